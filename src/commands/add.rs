@@ -7,7 +7,7 @@ use gix::progress; // For progress reporting
 use gix::interrupt; // For cancellation
 use gix::Repository as GixRepository; // Use alias
 
-use crate::core::{GitError, Result}; // ObjectId, ObjectType, operations not needed
+use crate::core::{GitError, Result, lock_index, write_index}; // ObjectId, ObjectType, operations not needed
 // use crate::repository::Repository; // Replaced by gix::Repository
 
 /// Implements the `add` command functionality
@@ -36,7 +36,8 @@ impl AddCommand {
         let repo = GixRepository::open(&self.repo_path)
             .map_err(|e| GitError::Repository(format!("Failed to open gitoxide repository: {}", e), Some(self.repo_path.clone())))?;
 
-        // Get mutable access to the index file
+        // Hold index.lock from reading the index until it is written back
+        let index_lock = lock_index(&repo)?;
         let mut index = repo.index_mut()?;
 
         // Define add options
@@ -63,8 +64,7 @@ impl AddCommand {
         }
 
         // Write the updated index back to disk
-        index.write(gix::index::write::Options::default())
-            .map_err(|e| GitError::Repository(format!("Failed to write index: {}", e), Some(self.repo_path.clone())))?;
+        write_index(index_lock, &index)?;
 
         println!("Changes staged successfully.");
         Ok(())
@@ -1,14 +1,14 @@
 use std::path::{Path, PathBuf};
 
-use crate::core::{GitError, Result}; // ObjectId not needed directly
+use crate::core::{GitError, Result, write_index_tree, ensure_tree_changed, lock_index, edit_references}; // ObjectId not needed directly
 // use crate::repository::Repository; // Replaced by gix
 // use crate::crypto::SignatureProvider; // Signing handled differently
+use crate::crypto::AnonymousIdentity;
+use crate::repository::{Config, Signature};
 use gix::Repository as GixRepository;
-use gix::actor;
 use gix::refs::transaction::{Change, LogChange, RefEdit, PreviousValue};
 use gix::refs::{Target, TargetRef};
 use gix::hash::ObjectId as GixObjectId;
-use std::time::SystemTime;
 
 /// Implements the `commit` command functionality with anonymous signing support
 pub struct CommitCommand<'a> {
@@ -16,58 +16,58 @@ pub struct CommitCommand<'a> {
     message: String,
     /// Whether to sign the commit
     sign: bool,
-    /// Optional custom onion address for signing (identity)
-    onion_address: Option<&'a str>,
+    /// Pseudonymous identity to commit as, instead of `user.name`/`user.email`
+    identity: Option<&'a AnonymousIdentity>,
     /// Repository path
     path: PathBuf,
+    /// Whether to commit even if the tree is unchanged from HEAD
+    allow_empty: bool,
 }
 
 impl<'a> CommitCommand<'a> {
     /// Create a new commit command
-    pub fn new(message: &str, sign: bool, onion_address: Option<&'a str>, path: &Path) -> Self {
+    pub fn new(message: &str, sign: bool, identity: Option<&'a AnonymousIdentity>, path: &Path) -> Self {
         Self {
             message: message.to_string(),
             sign,
-            onion_address,
+            identity,
             path: path.to_path_buf(),
+            allow_empty: false,
         }
     }
     
+    /// Allow a commit whose tree is identical to HEAD's
+    pub fn with_allow_empty(mut self, allow_empty: bool) -> Self {
+        self.allow_empty = allow_empty;
+        self
+    }
+    
     /// Execute the commit command
     pub fn execute(self) -> Result<GixObjectId> { // Return gix ObjectId
         // Open the gitoxide repository instance
         let repo = GixRepository::open(&self.path)
             .map_err(|e| GitError::Repository(format!("Failed to open gitoxide repository: {}", e), Some(self.path.clone())))?;
 
-        // TODO: Handle signing options (self.sign, self.onion_address) using gix mechanisms if possible.
+        // TODO: Handle signing (self.sign) using gix mechanisms if possible.
         if self.sign {
             log::warn!("Commit signing requested but not yet implemented with gitoxide.");
         }
 
-        // 1. Get the index
-        let index = repo.index()?;
-        // Optional: Check if index has changes compared to HEAD tree?
-        // if index.is_unchanged(&repo.objects)? { ... return Err("no changes added to commit") }
-
-        // 2. Write index to a tree
-        let tree_id = index.write_tree_to(&repo.objects)
-            .map_err(|e| GitError::ObjectStorage(format!("Failed to write index tree: {}", e)))?;
+        // 1. Write the index to a tree, keeping it locked until HEAD moved
+        let _index_lock = lock_index(&repo)?;
+        let tree_id = write_index_tree(&repo)?;
         println!("Written tree {}", tree_id);
 
-        // 3. Get Author and Committer Signatures from config
-        let config = repo.config_snapshot();
-        let author = match config.actor() {
-            Ok(actor) => actor,
-            Err(e) => {
-                // Try getting name/email individually or use defaults
-                log::warn!("Failed to get default actor from config ({}). Trying individual values or defaults.", e);
-                let name = config.string("user.name").unwrap_or_else(|| "Arti Git User".into());
-                let email = config.string("user.email").unwrap_or_else(|| "arti-git@example.com".into());
-                // TODO: Get timezone offset correctly
-                let time = actor::Time::new(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as u64, 0);
-                actor::Signature { name, email, time }
-            }
+        // 2. Refuse no-op commits unless they were asked for
+        ensure_tree_changed(&repo, tree_id, self.allow_empty)?;
+
+        // 3. Get Author and Committer Signatures from the identity or config,
+        // failing rather than committing under a made-up name
+        let signature = match self.identity {
+            Some(identity) => Signature::anonymous(identity),
+            None => Signature::from_config(&Config::load_from_repo(repo.path())?)?,
         };
+        let author = signature.to_actor();
         // Use author as committer for simplicity, gitoxide might default committer too
         let committer = author.clone();
 
@@ -123,7 +123,7 @@ impl<'a> CommitCommand<'a> {
                 name: head_ref_obj.name().to_owned(), // Use the actual ref name (e.g., refs/heads/main)
                 deref: true, // We want to update the ref HEAD points to, not HEAD itself if symbolic
             };
-            repo.edit_references(std::iter::once(edit))?;
+            edit_references(&repo, std::iter::once(edit))?;
             println!("Updated reference {}", head_ref_obj.name().as_bstr());
         } else {
             // If HEAD was detached or didn't exist, we don't update a ref automatically.
@@ -212,7 +212,7 @@ impl PullCommand {
                         name: dst_ref_str.try_into()?,
                         deref: false,
                     };
-                    gix_repo.edit_references(std::iter::once(edit))?;
+                    crate::core::edit_references(&gix_repo, std::iter::once(edit))?;
                     println!("Pull completed successfully. Working directory updated.");
                 } else if final_oid == local_oid {
                     // This case might happen if checkout determined no merge was needed,
@@ -0,0 +1,231 @@
+//! Borrowing objects from a local reference repository
+//!
+//! `clone --reference` points `objects/info/alternates` at the reference
+//! repository's object store, so objects already on disk there are never
+//! fetched again over Tor. The reference's refs are copied into the new
+//! repository under [`REFERENCE_REFS_PREFIX`] for the duration of the fetch,
+//! which makes the negotiation offer them as `have`s; they are removed once
+//! the clone is complete.
+
+use std::path::{Path, PathBuf};
+
+use gix::{Repository, oid};
+
+use crate::core::{GitError, Result, io_err, repo_err};
+use crate::protocol::PackBuilder;
+
+/// File listing the object directories a repository borrows from
+pub const ALTERNATES_FILE: &str = "objects/info/alternates";
+
+/// Namespace the reference's refs are copied to while cloning
+pub const REFERENCE_REFS_PREFIX: &str = "refs/reference/";
+
+/// A local repository to borrow objects from while cloning
+#[derive(Debug, Clone)]
+pub struct CloneReference {
+    /// Path of the reference repository (worktree or git directory)
+    pub path: PathBuf,
+    /// Copy the borrowed objects once the clone is done and stop using the
+    /// reference, so it can be deleted later
+    pub dissociate: bool,
+}
+
+impl CloneReference {
+    /// Borrow objects from the repository at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), dissociate: false }
+    }
+
+    /// Set whether to copy the borrowed objects after cloning
+    pub fn with_dissociate(mut self, dissociate: bool) -> Self {
+        self.dissociate = dissociate;
+        self
+    }
+}
+
+/// Read the object directories listed in a repository's alternates file
+pub fn read_alternates(git_dir: &Path) -> Result<Vec<PathBuf>> {
+    let path = git_dir.join(ALTERNATES_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_err(format!("Failed to read alternates: {}", e), &path)),
+    };
+    let objects_dir = git_dir.join("objects");
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| objects_dir.join(line))
+        .collect())
+}
+
+/// Make the repository at `git_dir` borrow objects from `reference`
+///
+/// Called between creating the repository and fetching into it. Returns
+/// the number of refs offered to the remote on the reference's behalf.
+pub fn borrow_objects(git_dir: &Path, reference: &CloneReference) -> Result<usize> {
+    let reference_repo = gix::open(&reference.path)
+        .map_err(|e| repo_err(format!("Failed to open reference repository: {}", e), &reference.path))?;
+    let reference_objects = reference_repo.objects.store_ref().path();
+    let reference_objects = reference_objects.canonicalize()
+        .map_err(|e| io_err(format!("Failed to resolve reference object directory: {}", e), reference_objects))?;
+
+    let mut alternates = read_alternates(git_dir)?;
+    if !alternates.contains(&reference_objects) {
+        alternates.push(reference_objects.clone());
+    }
+    write_alternates(git_dir, &alternates)?;
+
+    // Loose refs, since the new repository has no packed-refs to merge with
+    let refs = reference_repo.references()
+        .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &reference.path))?;
+    let all = refs.all()
+        .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &reference.path))?;
+    let mut borrowed = 0;
+    for reference_ref in all.flatten() {
+        let name = reference_ref.name().as_bstr().to_string();
+        let id = match (name.strip_prefix("refs/"), reference_ref.target().try_id()) {
+            (Some(_), Some(id)) => id.to_owned(),
+            _ => continue,
+        };
+        let file = git_dir.join(REFERENCE_REFS_PREFIX).join(&name["refs/".len()..]);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| io_err(format!("Failed to create ref directory: {}", e), parent))?;
+        }
+        std::fs::write(&file, format!("{}\n", id))
+            .map_err(|e| io_err(format!("Failed to write ref: {}", e), &file))?;
+        borrowed += 1;
+    }
+
+    log::info!("Borrowing objects from {} ({} refs offered as haves)", reference_objects.display(), borrowed);
+    Ok(borrowed)
+}
+
+/// Clean up after cloning with a reference
+///
+/// Drops the borrowed refs and, with `dissociate`, copies the objects the
+/// clone only reaches through the alternates into its own store before
+/// removing the alternates file.
+pub fn finish_reference(repo: &Repository, reference: &CloneReference) -> Result<()> {
+    let git_dir = repo.path();
+    let borrowed_refs = git_dir.join(REFERENCE_REFS_PREFIX);
+    if borrowed_refs.exists() {
+        std::fs::remove_dir_all(&borrowed_refs)
+            .map_err(|e| io_err(format!("Failed to remove borrowed refs: {}", e), &borrowed_refs))?;
+    }
+
+    if reference.dissociate {
+        dissociate(repo)?;
+    }
+    Ok(())
+}
+
+/// Copy the borrowed objects into the repository's own object store and stop
+/// using alternates
+///
+/// Only objects reachable from the repository's refs that are neither loose
+/// nor in one of its own packs are copied, into a single new pack; what the
+/// repository already stores is left alone. `objects/info/alternates` is
+/// removed afterwards.
+pub fn dissociate(repo: &Repository) -> Result<()> {
+    let git_dir = repo.path().to_path_buf();
+    if read_alternates(&git_dir)?.is_empty() {
+        return Ok(());
+    }
+
+    let refs = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &git_dir))?;
+    let all = refs.all()
+        .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &git_dir))?;
+    let mut tips = Vec::new();
+    for mut reference in all.flatten() {
+        if let Ok(id) = reference.peel_to_id_in_place() {
+            if !tips.contains(&id.detach()) {
+                tips.push(id.detach());
+            }
+        }
+    }
+
+    if !tips.is_empty() {
+        let own_objects = OwnObjects::open(repo)?;
+        let mut traversal = repo.objects.traverse(tips)
+            .map_err(|e| repo_err(format!("Failed to start object traversal: {}", e), &git_dir))?
+            .with_deepen(true)
+            .with_objects(true);
+        let mut pack_builder = PackBuilder::new(true);
+        let mut borrowed = 0;
+        while let Some(obj) = traversal.next() {
+            let obj = obj.map_err(|e| repo_err(format!("Failed to traverse object: {}", e), &git_dir))?;
+            if own_objects.contains(&obj.id) {
+                continue;
+            }
+            pack_builder.add_object(obj.data.into(), obj.kind);
+            borrowed += 1;
+        }
+
+        if borrowed > 0 {
+            let pack = pack_builder.finish(&|_| {})?;
+            let stats = crate::protocol::index_pack(repo, &pack, false)?;
+            log::info!("Copied {} borrowed objects into {}", stats.num_objects, stats.pack_path.display());
+        }
+    }
+
+    let alternates = git_dir.join(ALTERNATES_FILE);
+    std::fs::remove_file(&alternates)
+        .map_err(|e| io_err(format!("Failed to remove alternates: {}", e), &alternates))?;
+    Ok(())
+}
+
+/// The objects a repository stores itself, as opposed to borrowing them
+struct OwnObjects {
+    objects_dir: PathBuf,
+    indexes: Vec<gix::odb::pack::index::File>,
+}
+
+impl OwnObjects {
+    /// Open the indexes of the repository's own packs
+    fn open(repo: &Repository) -> Result<Self> {
+        let objects_dir = repo.path().join("objects");
+        let pack_dir = objects_dir.join("pack");
+        let mut indexes = Vec::new();
+        if pack_dir.is_dir() {
+            let entries = std::fs::read_dir(&pack_dir)
+                .map_err(|e| io_err(format!("Failed to read pack directory: {}", e), &pack_dir))?;
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().map_or(true, |ext| ext != "idx") || !path.with_extension("pack").is_file() {
+                    continue;
+                }
+                let index = gix::odb::pack::index::File::at(&path, repo.object_hash())
+                    .map_err(|e| repo_err(format!("Failed to read pack index: {}", e), &path))?;
+                indexes.push(index);
+            }
+        }
+        Ok(Self { objects_dir, indexes })
+    }
+
+    /// Check whether `id` is loose or packed in the repository itself
+    fn contains(&self, id: &oid) -> bool {
+        let hex = id.to_hex().to_string();
+        self.objects_dir.join(&hex[..2]).join(&hex[2..]).is_file()
+            || self.indexes.iter().any(|index| index.lookup(id).is_some())
+    }
+}
+
+fn write_alternates(git_dir: &Path, alternates: &[PathBuf]) -> Result<()> {
+    let path = git_dir.join(ALTERNATES_FILE);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| io_err(format!("Failed to create directory: {}", e), parent))?;
+    }
+    let mut content = String::new();
+    for alternate in alternates {
+        let line = alternate.to_str()
+            .ok_or_else(|| GitError::InvalidArgument(format!("Alternate path is not valid UTF-8: {}", alternate.display())))?;
+        content.push_str(line);
+        content.push('\n');
+    }
+    std::fs::write(&path, content)
+        .map_err(|e| io_err(format!("Failed to write alternates: {}", e), &path))
+}
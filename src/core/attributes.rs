@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+
+use crate::core::{Result, io_err};
+
+/// Name of the per-directory attributes file
+pub const GITATTRIBUTES_FILE: &str = ".gitattributes";
+
+/// Repository-local attributes file inside the git directory
+pub const INFO_ATTRIBUTES_FILE: &str = "info/attributes";
+
+/// State of a single attribute for a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    /// `attr`
+    Set,
+    /// `-attr`
+    Unset,
+    /// `attr=value`
+    Value(String),
+    /// No rule mentions the attribute, or `!attr` reset it
+    Unspecified,
+}
+
+/// One line of an attributes file
+#[derive(Debug, Clone)]
+struct AttributeRule {
+    /// Directory of the `.gitattributes` file the rule came from, relative to
+    /// the worktree and without a trailing slash; empty for the root and for
+    /// files outside the worktree
+    base: String,
+    pattern: String,
+    attributes: Vec<(String, AttributeValue)>,
+}
+
+/// Attribute rules from one or more attributes files
+///
+/// Rules are kept in increasing precedence; later rules override earlier
+/// ones, as in git.
+#[derive(Debug, Clone, Default)]
+pub struct GitAttributes {
+    rules: Vec<AttributeRule>,
+}
+
+impl GitAttributes {
+    /// Parse the contents of a top-level attributes file
+    pub fn parse(content: &str) -> Self {
+        let mut attributes = Self::default();
+        attributes.add_rules(content, "");
+        attributes
+    }
+
+    /// Read `<workdir>/.gitattributes`; a missing file has no rules
+    pub fn from_worktree(workdir: &Path) -> Result<Self> {
+        let mut attributes = Self::default();
+        attributes.add_file(&workdir.join(GITATTRIBUTES_FILE), "")?;
+        Ok(attributes)
+    }
+
+    /// Load every attributes file that applies to `path`
+    ///
+    /// In increasing precedence: the global file (`core.attributesFile`),
+    /// `.gitattributes` in the worktree root and each directory leading to
+    /// `path`, and `$GIT_DIR/info/attributes`. Bare repositories have no
+    /// worktree and only use the global and info files.
+    pub fn load_for(workdir: Option<&Path>, git_dir: &Path, global: Option<&Path>, path: &str) -> Result<Self> {
+        let mut attributes = Self::default();
+        if let Some(global) = global {
+            attributes.add_file(global, "")?;
+        }
+
+        if let Some(workdir) = workdir {
+            attributes.add_file(&workdir.join(GITATTRIBUTES_FILE), "")?;
+            let components: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+            for depth in 1..components.len() {
+                let base = components[..depth].join("/");
+                attributes.add_file(&workdir.join(&base).join(GITATTRIBUTES_FILE), &base)?;
+            }
+        }
+
+        attributes.add_file(&git_dir.join(INFO_ATTRIBUTES_FILE), "")?;
+        Ok(attributes)
+    }
+
+    /// Get the value of attribute `name` for a worktree-relative path
+    pub fn get(&self, path: &str, name: &str) -> AttributeValue {
+        self.resolve(path).get(name)
+    }
+
+    /// Collect the final value of every attribute set for `path`
+    pub fn resolve(&self, path: &str) -> PathAttributes {
+        let path = path.trim_start_matches('/');
+        let mut values = HashMap::new();
+        for rule in &self.rules {
+            if !rule_matches(rule, path) {
+                continue;
+            }
+            for (name, value) in &rule.attributes {
+                values.insert(name.clone(), value.clone());
+            }
+        }
+        PathAttributes { path: path.to_string(), values }
+    }
+
+    /// Append the rules of an attributes file, if it exists
+    fn add_file(&mut self, file: &Path, base: &str) -> Result<()> {
+        if !file.is_file() {
+            return Ok(());
+        }
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| io_err(format!("Failed to read attributes: {}", e), file))?;
+        self.add_rules(&content, base);
+        Ok(())
+    }
+
+    /// Append the rules in `content`, relative to directory `base`
+    fn add_rules(&mut self, content: &str, base: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let pattern = match fields.next() {
+                Some(pattern) => pattern.to_string(),
+                None => continue,
+            };
+            // Negative patterns are not allowed in attribute files
+            if pattern.starts_with('!') {
+                log::warn!("Ignoring negative attribute pattern '{}'", pattern);
+                continue;
+            }
+
+            let mut attributes = Vec::new();
+            for field in fields {
+                if let Some(name) = field.strip_prefix('-') {
+                    attributes.push((name.to_string(), AttributeValue::Unset));
+                } else if let Some(name) = field.strip_prefix('!') {
+                    attributes.push((name.to_string(), AttributeValue::Unspecified));
+                } else if let Some((name, value)) = field.split_once('=') {
+                    attributes.push((name.to_string(), AttributeValue::Value(value.to_string())));
+                } else {
+                    attributes.push((field.to_string(), AttributeValue::Set));
+                    // The built-in `binary` macro turns off diff, merge and text
+                    if field == "binary" {
+                        for name in ["diff", "merge", "text"] {
+                            attributes.push((name.to_string(), AttributeValue::Unset));
+                        }
+                    }
+                }
+            }
+
+            self.rules.push(AttributeRule { base: base.to_string(), pattern, attributes });
+        }
+    }
+}
+
+/// The attributes in effect for one path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathAttributes {
+    path: String,
+    values: HashMap<String, AttributeValue>,
+}
+
+impl PathAttributes {
+    /// Worktree-relative path the attributes were resolved for
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the value of attribute `name`
+    pub fn get(&self, name: &str) -> AttributeValue {
+        self.values.get(name).cloned().unwrap_or(AttributeValue::Unspecified)
+    }
+
+    /// Check whether `name` has the string value `value`
+    pub fn is_value(&self, name: &str, value: &str) -> bool {
+        matches!(self.values.get(name), Some(AttributeValue::Value(v)) if v == value)
+    }
+}
+
+/// Get the global attributes file of the current user
+///
+/// Uses `core.attributesFile` when given (expanding a leading `~/`), and
+/// `$XDG_CONFIG_HOME/git/attributes` otherwise.
+pub fn global_attributes_file(configured: Option<&str>) -> Option<PathBuf> {
+    match configured {
+        Some(file) => match file.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().map(|home| home.join(rest)),
+            None => Some(PathBuf::from(file)),
+        },
+        None => xdg_git_dir().map(|dir| dir.join("attributes")),
+    }
+}
+
+/// Git's directory below the XDG config home
+///
+/// Like git, this is `$XDG_CONFIG_HOME/git`, or `~/.config/git` when the
+/// variable is unset or empty, on every platform.
+pub(crate) fn xdg_git_dir() -> Option<PathBuf> {
+    xdg_git_dir_from(std::env::var_os("XDG_CONFIG_HOME"), dirs::home_dir())
+}
+
+fn xdg_git_dir_from(xdg_config_home: Option<std::ffi::OsString>, home: Option<PathBuf>) -> Option<PathBuf> {
+    match xdg_config_home.filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir).join("git")),
+        None => home.map(|home| home.join(".config").join("git")),
+    }
+}
+
+/// Resolve the attributes of a worktree-relative path in a repository
+///
+/// Consults `core.attributesFile`, the `.gitattributes` files from the root
+/// down to the path's directory, and `.git/info/attributes`, in that order of
+/// increasing precedence. This is the lookup shared by LFS, merge drivers and
+/// diff, so all of them agree on what a path's attributes are.
+pub fn attributes_for(repo: &Repository, path: &str) -> Result<PathAttributes> {
+    let configured = repo.config_snapshot()
+        .string("core.attributesFile")
+        .map(|file| file.to_string());
+    let global = global_attributes_file(configured.as_deref());
+
+    let attributes = GitAttributes::load_for(repo.work_dir(), repo.path(), global.as_deref(), path)?;
+    Ok(attributes.resolve(path))
+}
+
+/// Check whether a rule applies to a path
+fn rule_matches(rule: &AttributeRule, path: &str) -> bool {
+    if rule.base.is_empty() {
+        return pattern_matches(&rule.pattern, path);
+    }
+    match path.strip_prefix(rule.base.as_str()).and_then(|rest| rest.strip_prefix('/')) {
+        Some(relative) => pattern_matches(&rule.pattern, relative),
+        None => false,
+    }
+}
+
+/// Match an attribute pattern against a slash-separated path
+///
+/// Patterns without a slash match the file name at any depth; others are
+/// anchored at the directory holding the attributes file.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    use gix::glob::wildmatch;
+
+    if pattern.contains('/') {
+        let pattern = pattern.trim_start_matches('/');
+        wildmatch(pattern.into(), path.into(), wildmatch::Mode::NO_MATCH_SLASH_LITERAL)
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        wildmatch(pattern.into(), name.into(), wildmatch::Mode::empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    #[test]
+    fn test_later_rules_win() {
+        let attributes = GitAttributes::parse("*.txt merge=union\ngenerated/*.txt merge=theirs -diff\n");
+        assert_eq!(attributes.get("notes.txt", "merge"), AttributeValue::Value("union".to_string()));
+        assert_eq!(attributes.get("docs/notes.txt", "merge"), AttributeValue::Value("union".to_string()));
+        assert_eq!(attributes.get("generated/out.txt", "merge"), AttributeValue::Value("theirs".to_string()));
+        assert_eq!(attributes.get("generated/out.txt", "diff"), AttributeValue::Unset);
+        assert_eq!(attributes.get("main.rs", "merge"), AttributeValue::Unspecified);
+    }
+
+    #[test]
+    fn test_reset_comments_and_binary_macro() {
+        let attributes = GitAttributes::parse("# comment\n*.bin binary merge\n*.bin !merge\n");
+        assert_eq!(attributes.get("a.bin", "merge"), AttributeValue::Unspecified);
+        assert_eq!(attributes.get("a.bin", "binary"), AttributeValue::Set);
+        assert_eq!(attributes.get("a.bin", "diff"), AttributeValue::Unset);
+    }
+
+    #[test]
+    fn test_lookup_chain_precedence() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let workdir = dir.child("work");
+        let git_dir = workdir.child(".git");
+        dir.child("global").write_str("*.txt eol=crlf merge=union\n").unwrap();
+        workdir.child(".gitattributes").write_str("*.txt merge=ours\n").unwrap();
+        workdir.child("sub/.gitattributes").write_str("*.txt merge=theirs\nnested/*.txt diff=plain\n").unwrap();
+        git_dir.child("info/attributes").write_str("sub/locked.txt merge=binary\n").unwrap();
+
+        let load = |path: &str| GitAttributes::load_for(
+            Some(workdir.path()), git_dir.path(), Some(dir.path().join("global").as_path()), path,
+        ).unwrap().resolve(path);
+
+        let top = load("a.txt");
+        assert!(top.is_value("merge", "ours"));
+        assert!(top.is_value("eol", "crlf"));
+
+        let sub = load("sub/nested/b.txt");
+        assert!(sub.is_value("merge", "theirs"));
+        assert!(sub.is_value("diff", "plain"));
+
+        // info/attributes beats every .gitattributes file
+        assert!(load("sub/locked.txt").is_value("merge", "binary"));
+        // Rules in sub/ don't leak into other directories
+        assert!(load("other/c.txt").is_value("merge", "ours"));
+    }
+
+    #[test]
+    fn test_xdg_git_dir() {
+        let home = Some(PathBuf::from("/home/user"));
+        assert_eq!(xdg_git_dir_from(Some("/xdg".into()), home.clone()), Some(PathBuf::from("/xdg/git")));
+        assert_eq!(xdg_git_dir_from(Some("".into()), home.clone()), Some(PathBuf::from("/home/user/.config/git")));
+        assert_eq!(xdg_git_dir_from(None, home), Some(PathBuf::from("/home/user/.config/git")));
+        assert_eq!(xdg_git_dir_from(None, None), None);
+    }
+}
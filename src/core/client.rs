@@ -1,5 +1,11 @@
+use std::collections::HashSet;
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
 
 #[cfg(feature = "tor")]
 use arti_client::{TorClient, TorClientConfig};
@@ -10,9 +16,19 @@ use gix::{Repository, open};
 #[cfg(feature = "tor")]
 use gix_transport::client::{connect, capabilities};
 
-use crate::core::{ArtiGitConfig, GitError, Result, io_err, repo_err, transport_err};
+use crate::core::{ArtiGitConfig, RemoteTorSettings, TorBootstrap, GitError, Result, io_err, repo_err, transport_err};
+use crate::core::commit_graph::CommitGraph;
+use crate::core::merge::{HeadMerge, MergeDrivers};
+use crate::core::shallow::{FetchDepth, check_fetch_depth, read_shallow};
+use crate::core::alternates::CloneReference;
+use crate::core::submodule::{self, InitializedSubmodule};
+use crate::crypto::{AnonymousIdentity, Signer, SigningMode};
+use crate::repository::Signature;
+#[cfg(feature = "tor")]
+use crate::transport::{TorTransport, PooledTorTransport, KnownHosts, ArtiGitTransportRegistry, OnionDescriptorWait, create_transport_registry};
 #[cfg(feature = "tor")]
-use crate::transport::{TorTransport, ArtiGitTransportRegistry, create_transport_registry};
+use crate::transport::{AsyncRemoteConnection, FetchEstimate, TorConnection, UrlConfig};
+use crate::transport::{TransportEventHandler, TransportEvents};
 use crate::utils;
 #[cfg(feature = "ipfs")]
 use crate::ipfs::{IpfsClient, IpfsObjectStorage, IpfsObjectProvider};
@@ -33,6 +49,15 @@ fn init_logging() {
     });
 }
 
+/// Isolate `transport`'s connections to `identity`, if one is configured
+#[cfg(feature = "tor")]
+fn with_pooled_identity(transport: PooledTorTransport, identity: Option<&AnonymousIdentity>) -> PooledTorTransport {
+    match identity {
+        Some(identity) => transport.with_identity(identity),
+        None => transport,
+    }
+}
+
 /// Workaround for the gix-url canonicalization issue
 fn canonicalize_url_path(url_str: &str) -> Result<String> {
     // Only process file:// URLs
@@ -62,6 +87,9 @@ fn canonicalize_url_path(url_str: &str) -> Result<String> {
 pub struct ArtiGitClient {
     config: ArtiGitConfig,
     
+    /// Observers of connection and transfer events, shared with the transports
+    events: TransportEvents,
+    
     #[cfg(feature = "tor")]
     runtime: PreferredRuntime,
     #[cfg(feature = "tor")]
@@ -72,6 +100,12 @@ pub struct ArtiGitClient {
     transport_registry: Option<ArtiGitTransportRegistry>,
     #[cfg(feature = "tor")]
     transport_handle: Option<capabilities::TransportFactoryHandle>,
+    /// Pooled transports whose connections are closed on shutdown
+    #[cfg(feature = "tor")]
+    pooled_transports: std::sync::Mutex<Vec<Arc<PooledTorTransport>>>,
+    /// Transport for LFS requests to onion services
+    #[cfg(feature = "tor")]
+    lfs_transport: Option<Arc<PooledTorTransport>>,
     
     /// IPFS client for interacting with the IPFS network
     #[cfg(feature = "ipfs")]
@@ -80,6 +114,16 @@ pub struct ArtiGitClient {
     /// IPFS object storage for Git objects
     #[cfg(feature = "ipfs")]
     ipfs_storage: Option<Arc<IpfsObjectStorage>>,
+    
+    /// LFS storage, opened on first use and flushed on shutdown
+    lfs_storage: std::sync::Mutex<Option<Arc<crate::lfs::LfsStorage>>>,
+    
+    /// Merge drivers used when a pull merges, by `merge` attribute
+    merge_drivers: MergeDrivers,
+    
+    /// Pseudonymous identity from `git.identity_key`, whose Tor connections
+    /// are kept apart from those of other identities
+    identity: Option<Arc<AnonymousIdentity>>,
 }
 
 impl ArtiGitClient {
@@ -91,6 +135,8 @@ impl ArtiGitClient {
         // Log client creation with config summary
         log::info!("Creating new ArtiGit client: Tor={}, IPFS={}", 
             config.tor.use_tor, config.ipfs.enabled);
+        
+        let identity = config.git.load_identity()?.map(Arc::new);
             
         #[cfg(feature = "tor")]
         let runtime = PreferredRuntime::create()
@@ -98,21 +144,34 @@ impl ArtiGitClient {
             
         #[cfg(feature = "tor")]
         let tor_client = if config.tor.use_tor {
-            // Configure and bootstrap Tor client
-            log::info!("Bootstrapping Tor client...");
             let arti_config = config.to_arti_config()?;
-            
-            let client = TorClient::create_bootstrapped(runtime.clone(), arti_config)
-                .await
-                .map_err(|e| GitError::Transport(format!("Failed to bootstrap Tor: {}", e), None))?;
-                
-            log::info!("Tor client bootstrapped successfully");
+            let client = match config.tor.bootstrap {
+                TorBootstrap::Eager => {
+                    // Configure and bootstrap Tor client
+                    log::info!("Bootstrapping Tor client...");
+                    let client = TorClient::create_bootstrapped(runtime.clone(), arti_config)
+                        .await
+                        .map_err(|e| GitError::Transport(format!("Failed to bootstrap Tor: {}", e), None))?;
+                    log::info!("Tor client bootstrapped successfully");
+                    client
+                },
+                TorBootstrap::OnDemand => {
+                    log::debug!("Tor client will bootstrap on first use");
+                    TorClient::with_runtime(runtime.clone())
+                        .config(arti_config)
+                        .bootstrap_behavior(arti_client::BootstrapBehavior::OnDemand)
+                        .create_unbootstrapped()
+                        .map_err(|e| GitError::Transport(format!("Failed to create Tor client: {}", e), None))?
+                },
+            };
             Some(Arc::new(client))
         } else {
             log::debug!("Tor is disabled in configuration, skipping initialization");
             None
         };
         
+        let events = TransportEvents::new();
+        
         // Create transport and registry if Tor is enabled
         #[cfg(feature = "tor")]
         let (tor_transport, transport_registry, transport_handle) = if config.tor.use_tor {
@@ -121,7 +180,19 @@ impl ArtiGitClient {
                 log::info!("Creating Tor transport...");
                 let transport = TorTransport::new(tor_client.as_ref().cloned())
                     .await
-                    .map_err(|e| GitError::Transport(format!("Failed to create Tor transport: {}", e), None))?;
+                    .map_err(|e| GitError::Transport(format!("Failed to create Tor transport: {}", e), None))?
+                    .with_exit_connect_timeout(Duration::from_secs(config.tor.exit_connect_timeout))
+                    .with_descriptor_wait(OnionDescriptorWait::new(Duration::from_secs(config.tor.onion_descriptor_wait)))
+                    .with_exit_preferences(config.tor.exit.clone())
+                    .map_err(|e| GitError::Config(format!("Invalid exit preferences: {}", e)))?
+                    .with_url_config(crate::transport::UrlConfig::discover("."))
+                    .with_known_hosts(KnownHosts::open(&config.tor.known_hosts)?)
+                    .with_accept_new_fingerprint(config.tor.accept_new_fingerprint)
+                    .with_events(events.clone());
+                let transport = match &identity {
+                    Some(identity) => transport.with_identity(identity.as_ref()),
+                    None => transport,
+                };
                 let transport_arc = Arc::new(transport);
                 
                 // Create the transport registry
@@ -143,6 +214,23 @@ impl ArtiGitClient {
             (None, None, None)
         };
         
+        #[cfg(feature = "tor")]
+        let pooled_transports = std::sync::Mutex::new(Vec::new());
+        
+        // LFS servers and actions on onion services are reached through Tor
+        #[cfg(feature = "tor")]
+        let lfs_transport = match &tor_client {
+            Some(tor_client) if config.lfs.enabled => {
+                let transport = PooledTorTransport::new(Some(tor_client.clone())).await?;
+                let transport = Arc::new(with_pooled_identity(transport, identity.as_deref()));
+                pooled_transports.lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .push(transport.clone());
+                Some(transport)
+            },
+            _ => None,
+        };
+        
         // Initialize IPFS if enabled
         #[cfg(feature = "ipfs")]
         let (ipfs_client, ipfs_storage) = if config.ipfs.enabled {
@@ -150,12 +238,26 @@ impl ArtiGitClient {
             match IpfsClient::new(config.ipfs.clone()).await {
                 Ok(client) => {
                     log::info!("IPFS client initialized successfully");
+                    // With Tor enabled, gateways are read through it too
+                    #[cfg(feature = "tor")]
+                    let client = match &tor_client {
+                        Some(tor_client) if !config.ipfs.gateway_urls.is_empty() => {
+                            let transport = PooledTorTransport::new(Some(tor_client.clone())).await?;
+                            let transport = Arc::new(with_pooled_identity(transport, identity.as_deref()));
+                            pooled_transports.lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .push(transport.clone());
+                            client.with_tor_transport(transport)
+                        },
+                        _ => client,
+                    };
                     let client_arc = Arc::new(client);
                     
                     // Create the object storage
                     log::info!("Creating IPFS object storage...");
                     match IpfsObjectStorage::new(client_arc.clone()).await {
                         Ok(storage) => {
+                            let storage = storage.with_cache_layout(config.ipfs.cache_layout);
                             log::info!("IPFS object storage created successfully");
                             (Some(client_arc), Some(Arc::new(storage)))
                         },
@@ -181,30 +283,54 @@ impl ArtiGitClient {
         #[cfg(feature = "tor")]
         let client = Self {
             config,
+            events,
             runtime,
             tor_client,
             tor_transport,
             transport_registry,
             transport_handle,
+            pooled_transports,
+            lfs_transport,
             #[cfg(feature = "ipfs")]
             ipfs_client,
             #[cfg(feature = "ipfs")]
             ipfs_storage,
+            lfs_storage: std::sync::Mutex::new(None),
+            merge_drivers: MergeDrivers::new(),
+            identity,
         };
         
         #[cfg(not(feature = "tor"))]
         let client = Self {
             config,
+            events,
             #[cfg(feature = "ipfs")]
             ipfs_client,
             #[cfg(feature = "ipfs")]
             ipfs_storage,
+            lfs_storage: std::sync::Mutex::new(None),
+            merge_drivers: MergeDrivers::new(),
+            identity,
         };
         
         log::info!("ArtiGit client created successfully");
         Ok(client)
     }
     
+    /// Register a handler for transport events (connection attempts, retries,
+    /// transferred bytes, received packfiles)
+    ///
+    /// Handlers added after the client was created still see events from its
+    /// transports, since the handler list is shared.
+    pub fn on_transport_event(&self, handler: impl TransportEventHandler + 'static) {
+        self.events.subscribe(handler);
+    }
+    
+    /// Get the event handler list shared with this client's transports
+    pub fn transport_events(&self) -> &TransportEvents {
+        &self.events
+    }
+    
     /// Create a client with the default configuration
     pub async fn with_default_config() -> Result<Self> {
         let config = ArtiGitConfig::default();
@@ -221,22 +347,250 @@ impl ArtiGitClient {
     }
     
     /// Clone a repository using the appropriate transport based on the URL
-    pub async fn clone(&self, url: &str, path: impl AsRef<Path>) -> Result<Repository> {
-        let path_ref = path.as_ref();
+    ///
+    /// Cancelling `cancel` interrupts the transfer; the partially created
+    /// destination and any temporary pack files are removed.
+    pub async fn clone(&self, url: &str, path: impl AsRef<Path>, cancel: &CancellationToken) -> Result<Repository> {
+        self.clone_at(url, path.as_ref(), None, &CloneOptions::default(), cancel).await
+    }
+    
+    /// Clone a repository as `options` describe
+    ///
+    /// The other clone methods are shorthands for single options.
+    pub async fn clone_with_options(&self, url: &str, path: impl AsRef<Path>, options: &CloneOptions, cancel: &CancellationToken) -> Result<Repository> {
+        self.clone_at(url, path.as_ref(), None, options, cancel).await
+    }
+    
+    /// Clone into a bare repository, without a working tree
+    ///
+    /// Branches of the remote become local branches, as with
+    /// `git clone --bare`, so the result can be served as it is.
+    pub async fn clone_bare(&self, url: &str, path: impl AsRef<Path>, cancel: &CancellationToken) -> Result<Repository> {
+        self.clone_at(url, path.as_ref(), None, &CloneOptions { bare: true, ..Default::default() }, cancel).await
+    }
+    
+    /// Clone only the newest `depth` commits of each fetched branch
+    ///
+    /// The clone is shallow: `.git/shallow` records the commits whose
+    /// parents were left out, and `fetch --deepen` or `--unshallow` can
+    /// extend the history later.
+    pub async fn clone_shallow(&self, url: &str, path: impl AsRef<Path>, depth: NonZeroU32, cancel: &CancellationToken) -> Result<Repository> {
+        self.clone_at(url, path.as_ref(), None, &CloneOptions { depth: Some(depth), ..Default::default() }, cancel).await
+    }
+    
+    /// Clone a repository, borrowing objects from a local reference repository
+    ///
+    /// Only objects missing from the reference are fetched. Unless the
+    /// reference is dissociated, the clone keeps reading objects from it
+    /// through `objects/info/alternates` and breaks if it is deleted.
+    pub async fn clone_with_reference(&self, url: &str, path: impl AsRef<Path>, reference: &CloneReference, cancel: &CancellationToken) -> Result<Repository> {
+        let options = CloneOptions { reference: Some(reference.clone()), ..Default::default() };
+        self.clone_at(url, path.as_ref(), None, &options, cancel).await
+    }
+    
+    /// Clone a repository, optionally checking out a detached commit instead of the remote HEAD
+    ///
+    /// A bare clone has no checkout, so `commit` is ignored for it.
+    async fn clone_at(&self, url: &str, path_ref: &Path, commit: Option<gix::ObjectId>, options: &CloneOptions, cancel: &CancellationToken) -> Result<Repository> {
         log::info!("Cloning repository from '{}' to '{}'", url, path_ref.display());
+        let CloneOptions { bare, depth, reference, resume } = options;
+        let (bare, depth, reference) = (*bare, *depth, reference.as_ref());
+        
+        // Fetches from Tor URLs go through a pooled transport that keeps the
+        // pack next to the destination while this registration is alive
+        #[cfg(feature = "tor")]
+        let _resumable = match (&self.tor_transport, *resume) {
+            (Some(tor_transport), true) => {
+                let pooled = PooledTorTransport::new(self.tor_client.clone()).await?
+                    .with_resume_target(path_ref);
+                let registry = ArtiGitTransportRegistry::new(tor_transport.clone())
+                    .with_upload_pack(self.track_pooled_transport(pooled)?);
+                Some((registry.register(), registry))
+            },
+            _ => None,
+        };
+        #[cfg(not(feature = "tor"))]
+        let _ = resume;
+        
+        // Expand `url.<base>.insteadOf` aliases before anything else looks at the URL.
+        // Named remotes used by pull/push are rewritten by gitoxide with the same rules.
+        let url = &crate::transport::UrlRewriter::from_globals().apply(url, gix::remote::Direction::Fetch);
         
         // Process the URL to make file:// URLs absolute without using gix-url's problematic method
         let canonical_url = canonicalize_url_path(url)?;
         log::debug!("Canonical URL: {}", canonical_url);
-            
-        // Clone using gitoxide's standard API
-        let repo = Repository::clone(canonical_url.clone(), path_ref)
-            .map_err(|e| repo_err(format!("Clone failed: {}", e), path_ref))?;
-            
+        
+        let existed = path_ref.exists();
+        let interrupt = interrupt_on_cancel(cancel);
+        
+        // Clone using gitoxide's standard API. The prepared clone deletes the
+        // destination again if it is dropped before checkout completes.
+        let prepared = if bare {
+            gix::prepare_clone_bare(canonical_url.clone(), path_ref)
+        } else {
+            gix::prepare_clone(canonical_url.clone(), path_ref)
+        };
+        let result = prepared
+            .map_err(|e| repo_err(format!("Clone failed: {}", e), path_ref))
+            .and_then(|mut prepare| {
+                if let Some(depth) = depth {
+                    prepare = prepare.with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(depth));
+                }
+                if let Some(reference) = reference {
+                    let git_dir = if bare { path_ref.to_path_buf() } else { path_ref.join(".git") };
+                    crate::core::borrow_objects(&git_dir, reference)?;
+                }
+                if bare {
+                    let (repo, _) = prepare
+                        .fetch_only(gix::progress::Discard, &interrupt.flag)
+                        .map_err(|e| repo_err(format!("Clone failed: {}", e), path_ref))?;
+                    promote_tracking_refs(&repo)?;
+                    if let Some(reference) = reference {
+                        crate::core::finish_reference(&repo, reference)?;
+                    }
+                    return Ok(repo);
+                }
+                let (mut checkout, _) = prepare
+                    .fetch_then_checkout(gix::progress::Discard, &interrupt.flag)
+                    .map_err(|e| repo_err(format!("Clone failed: {}", e), path_ref))?;
+                if let Some(commit) = commit {
+                    submodule::detach_head(checkout.repo(), commit)?;
+                }
+                let (repo, _) = checkout
+                    .main_worktree(gix::progress::Discard, &interrupt.flag)
+                    .map_err(|e| repo_err(format!("Checkout failed: {}", e), path_ref))?;
+                if let Some(reference) = reference {
+                    crate::core::finish_reference(&repo, reference)?;
+                }
+                Ok(repo)
+            });
+        
+        if cancel.is_cancelled() {
+            // Don't leave a half-cloned repository behind
+            if !existed && path_ref.exists() {
+                let _ = std::fs::remove_dir_all(path_ref);
+            }
+            return Err(GitError::Cancelled(format!("Clone of {} was cancelled", url)));
+        }
+        
+        let repo = result?;
         log::info!("Repository cloned successfully to: {}", path_ref.display());
         Ok(repo)
     }
     
+    /// Clone the submodules of a freshly cloned repository, recursively
+    ///
+    /// Reads `.gitmodules`, resolves relative URLs against the URL of the
+    /// containing repository and checks each submodule out at the commit
+    /// recorded in the superproject's index. Onion URLs go through Tor like
+    /// any other clone. Each submodule is registered as `submodule.<name>.url`
+    /// in its superproject's config, as `git submodule init` would.
+    pub async fn clone_submodules(&self, repo: &Repository, superproject_url: &str, cancel: &CancellationToken) -> Result<Vec<InitializedSubmodule>> {
+        let root = repo.work_dir()
+            .ok_or_else(|| repo_err("Cannot clone submodules into a bare repository", repo.path()))?
+            .to_path_buf();
+        
+        // Walk nested submodules with an explicit stack of (worktree, path from root, URL)
+        let mut initialized = Vec::new();
+        let mut pending = vec![(root, PathBuf::new(), superproject_url.to_string())];
+        while let Some((workdir, prefix, base_url)) = pending.pop() {
+            let parent = self.open(&workdir)?;
+            
+            for module in submodule::read_gitmodules(&workdir)? {
+                if cancel.is_cancelled() {
+                    return Err(GitError::Cancelled("Submodule clone was cancelled".to_string()));
+                }
+                
+                let url = submodule::resolve_url(&base_url, &module.url);
+                let commit = submodule::recorded_commit(&parent, &module.path)?;
+                let dest = workdir.join(&module.path);
+                log::info!("Cloning submodule '{}' from {} at {}", module.name, url, commit);
+                
+                self.clone_at(&url, &dest, Some(commit), &CloneOptions::default(), cancel).await?;
+                submodule::register(&parent, &module.name, &url)?;
+                
+                let path = prefix.join(&module.path);
+                pending.push((dest, path.clone(), url.clone()));
+                initialized.push(InitializedSubmodule { path, url, commit });
+            }
+        }
+        
+        Ok(initialized)
+    }
+    
+    /// Bring the submodules of a repository to the commits they should be at
+    ///
+    /// Submodules not yet registered in `.git/config` are skipped unless
+    /// `init` is set, in which case their URL is resolved against `origin`
+    /// and registered first. Missing submodules are cloned and existing ones
+    /// fetched from their origin, then checked out at the commit recorded in
+    /// the superproject's index, or at the remote tip when `remote` is set.
+    /// The URL decides the transport, so onion submodules go through Tor.
+    pub async fn update_submodules(&self, repo: &Repository, init: bool, remote: bool, cancel: &CancellationToken) -> Result<Vec<InitializedSubmodule>> {
+        let repo_path = repo.path().to_path_buf();
+        let workdir = repo.work_dir()
+            .ok_or_else(|| repo_err("Cannot update submodules of a bare repository", &repo_path))?
+            .to_path_buf();
+        
+        // Relative submodule URLs are relative to origin, or to the superproject itself
+        let base_url = match repo.find_remote("origin") {
+            Ok(origin) => origin.url(gix::remote::Direction::Fetch)
+                .map(|url| url.to_bstring().to_string())
+                .unwrap_or_else(|| workdir.display().to_string()),
+            Err(_) => workdir.display().to_string(),
+        };
+        
+        let mut updated = Vec::new();
+        for module in submodule::read_gitmodules(&workdir)? {
+            if cancel.is_cancelled() {
+                return Err(GitError::Cancelled("Submodule update was cancelled".to_string()));
+            }
+            
+            let url = match submodule::registered_url(repo, &module.name) {
+                Some(url) => url,
+                None if init => {
+                    let url = submodule::resolve_url(&base_url, &module.url);
+                    submodule::register(repo, &module.name, &url)?;
+                    log::info!("Registered submodule '{}' with URL {}", module.name, url);
+                    url
+                },
+                None => {
+                    log::info!("Skipping uninitialized submodule '{}'", module.name);
+                    continue;
+                }
+            };
+            
+            let recorded = submodule::recorded_commit(repo, &module.path)?;
+            let dest = workdir.join(&module.path);
+            
+            let sub_repo = if dest.join(".git").exists() {
+                let mut sub_repo = self.open(&dest)?;
+                self.pull(&mut sub_repo, cancel).await?;
+                sub_repo
+            } else {
+                // A fresh clone already lands on the recorded commit unless following the remote
+                let commit = if remote { None } else { Some(recorded) };
+                self.clone_at(&url, &dest, commit, &CloneOptions::default(), cancel).await?
+            };
+            
+            let target = if remote {
+                submodule::remote_tip(&sub_repo, module.branch.as_deref())?
+            } else {
+                recorded
+            };
+            
+            let current = sub_repo.head_id().ok().map(|id| id.detach());
+            if current != Some(target) {
+                log::info!("Checking out submodule '{}' at {}", module.name, target);
+                submodule::checkout_detached(&sub_repo, target)?;
+            }
+            
+            updated.push(InitializedSubmodule { path: module.path, url, commit: target });
+        }
+        
+        Ok(updated)
+    }
+    
     /// Open an existing repository
     pub fn open(&self, path: impl AsRef<Path>) -> Result<Repository> {
         let path_ref = path.as_ref();
@@ -247,13 +601,72 @@ impl ArtiGitClient {
     }
     
     /// Pull updates for a repository
-    pub async fn pull(&self, repo: &mut Repository) -> Result<()> {
+    ///
+    /// Returns the remote-tracking refs that moved, flagging any that were
+    /// rewritten rather than fast-forwarded.
+    ///
+    /// Cancelling `cancel` interrupts the fetch before any refs are updated.
+    pub async fn pull(&self, repo: &mut Repository, cancel: &CancellationToken) -> Result<Vec<FetchedRefUpdate>> {
+        self.pull_with_tags(repo, false, cancel).await.map(|outcome| outcome.updates)
+    }
+    
+    /// Pull updates for a repository, choosing how tags are fetched
+    ///
+    /// Tags pointing into the fetched history are always followed, using the
+    /// remote's `include-tag` capability when it is advertised. With
+    /// `all_tags`, every tag on the remote is fetched as well (`--tags`).
+    ///
+    /// The remote-tracking branch of HEAD's branch is then merged into it,
+    /// with files changed on both sides merged by the drivers registered
+    /// through [`ArtiGitClient::merge_drivers_mut`].
+    pub async fn pull_with_tags(&self, repo: &mut Repository, all_tags: bool, cancel: &CancellationToken) -> Result<PullOutcome> {
+        log::info!("Pulling updates for repository: {}", repo.path().display());
+        let mut outcome = self.fetch_remote(repo, "origin", all_tags, cancel).await?;
+        outcome.merge = self.merge_upstream(repo, "origin")?;
+        Ok(outcome)
+    }
+    
+    /// Merge drivers used by [`ArtiGitClient::pull_with_tags`], for
+    /// registering custom ones
+    pub fn merge_drivers_mut(&mut self) -> &mut MergeDrivers {
+        &mut self.merge_drivers
+    }
+    
+    /// Merge the remote-tracking branch of HEAD's branch into it
+    ///
+    /// Returns `None` when HEAD is detached or its branch has no
+    /// remote-tracking branch on `remote_name`.
+    fn merge_upstream(&self, repo: &Repository, remote_name: &str) -> Result<Option<HeadMerge>> {
+        let branch = match repo.head_name() {
+            Ok(Some(name)) => name.shorten().to_string(),
+            _ => return Ok(None),
+        };
+        let tracking = format!("refs/remotes/{}/{}", remote_name, branch);
+        let theirs = match repo.try_find_reference(tracking.as_str()) {
+            Ok(Some(mut reference)) => reference.peel_to_id_in_place()
+                .map_err(|e| repo_err(format!("Failed to resolve {}: {}", tracking, e), repo.path()))?
+                .detach(),
+            _ => return Ok(None),
+        };
+        
+        let committer = self.commit_signature(repo)?.to_actor();
+        let message = format!("Merge remote-tracking branch '{}/{}'", remote_name, branch);
+        crate::core::merge_into_head(repo, theirs, &self.merge_drivers, committer.to_ref(), &message).map(Some)
+    }
+    
+    /// Fetch a remote into its remote-tracking refs
+    ///
+    /// Reports the refs that moved and the new tags like
+    /// [`ArtiGitClient::pull_with_tags`], which fetches `origin` this way.
+    pub async fn fetch_remote(&self, repo: &mut Repository, remote_name: &str, all_tags: bool, cancel: &CancellationToken) -> Result<PullOutcome> {
+        self.fetch_remote_refs(repo, remote_name, all_tags, cancel).await.map(|(outcome, _)| outcome)
+    }
+    
+    /// [`ArtiGitClient::fetch_remote`], also returning the full names of the
+    /// refs the remote advertised
+    async fn fetch_remote_refs(&self, repo: &mut Repository, remote_name: &str, all_tags: bool, cancel: &CancellationToken) -> Result<(PullOutcome, HashSet<String>)> {
         // Get repository path for better error reporting
         let repo_path = repo.path().to_path_buf();
-        log::info!("Pulling updates for repository: {}", repo_path.display());
-        
-        // Get the default remote
-        let remote_name = "origin"; // We could make this configurable
         log::debug!("Using remote: {}", remote_name);
         
         // Create a fetch operation
@@ -266,21 +679,346 @@ impl ArtiGitClient {
             .to_string();
         log::debug!("Remote URL: {}", remote_url);
         
+        // Mirror refs/replace as well, so history reads match upstream
+        if super::replace_objects_enabled(repo) {
+            remote = remote.with_refspecs(Some(super::REPLACE_REFSPEC), gix::remote::Direction::Fetch)
+                .map_err(|e| repo_err(format!("Invalid replacement refspec: {}", e), &repo_path))?;
+        }
+        
+        // Annotated tags are stored as fetched; their peeled targets come from the advertisement
+        let tags = if all_tags { gix::remote::fetch::Tags::All } else { gix::remote::fetch::Tags::Included };
+        remote = remote.with_fetch_tags(tags);
+        
+        // Remember where the remote-tracking refs and tags pointed before fetching
+        let before = tracking_ref_targets(repo, remote_name)?;
+        let tags_before = tag_names(repo)?;
+        
         // Fetch from remote - transport will be automatically selected based on URL
         log::info!("Fetching from remote: {}", remote_name);
-        let result = gix::interrupt::init_handler(|| {});
-        remote.fetch(&gix::fetch::Options::default(), &result)
-            .map_err(|e| transport_err(format!("Failed to fetch from remote: {}", e), remote_url))?;
+        let interrupt = interrupt_on_cancel(cancel);
+        let result = remote.connect(gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Failed to connect to remote: {}", e), remote_url.clone()))?
+            .prepare_fetch(gix::progress::Discard, Default::default())
+            .map_err(|e| transport_err(format!("Failed to list remote refs: {}", e), remote_url.clone()))?
+            .receive(gix::progress::Discard, &interrupt.flag);
+        if cancel.is_cancelled() {
+            return Err(GitError::Cancelled(format!("Fetch from {} was cancelled", remote_url)));
+        }
+        let fetched = result.map_err(|e| transport_err(format!("Failed to fetch from remote: {}", e), remote_url))?;
+        let advertised = advertised_ref_names(&fetched.ref_map);
             
         log::info!("Fetch completed successfully");
         
-        // For now, just perform the fetch. In a full implementation, we'd also handle merging.
-        log::debug!("Note: Pull operation currently only fetches updates, merge not implemented yet");
-        Ok(())
+        // Compare against the new targets to find fast-forwards and forced updates
+        let after = tracking_ref_targets(repo, remote_name)?;
+        let graph = CommitGraph::for_repo(repo);
+        let mut updates = Vec::new();
+        for (name, new) in after {
+            let old = before.get(&name).copied();
+            if old == Some(new) {
+                continue;
+            }
+            
+            let forced = match old {
+                Some(old) => !is_ancestor(repo, graph.as_ref(), old, new)?,
+                None => false,
+            };
+            
+            if let (true, Some(old)) = (forced, old) {
+                log::warn!("{} was force-updated on the remote: {} -> {}", name, old, new);
+                record_forced_update(repo, &name, old, new)?;
+            }
+            
+            updates.push(FetchedRefUpdate { name, old, new, forced });
+        }
+        
+        let new_tags: Vec<String> = tag_names(repo)?
+            .into_iter()
+            .filter(|name| !tags_before.contains(name))
+            .collect();
+        if !new_tags.is_empty() {
+            log::info!("Fetched {} new tags", new_tags.len());
+        }
+        
+        Ok((PullOutcome { updates, new_tags, pruned: Vec::new(), merge: None }, advertised))
+    }
+    
+    /// Fetch a remote without merging anything, like `git fetch`
+    ///
+    /// Only remote-tracking refs and tags change; local branches and the
+    /// working tree are left alone. Tags pointing into the fetched history
+    /// are followed, and with `all_tags` every tag on the remote is fetched
+    /// (`--tags`). With `prune`, remote-tracking refs whose branches were
+    /// deleted on the remote are removed as well and listed in
+    /// [`PullOutcome::pruned`].
+    ///
+    /// Pruning compares against the refs the fetch itself listed, so the
+    /// remote is contacted only once.
+    pub async fn fetch(&self, repo: &mut Repository, remote_name: &str, prune: bool, all_tags: bool, cancel: &CancellationToken) -> Result<PullOutcome> {
+        let (mut outcome, advertised) = self.fetch_remote_refs(repo, remote_name, all_tags, cancel).await?;
+        if prune {
+            outcome.pruned = prune_tracking_refs(repo, remote_name, &advertised, false)?;
+        }
+        Ok(outcome)
+    }
+    
+    #[cfg(feature = "tor")]
+    /// Work out what fetching `remote_name` would download, without downloading it
+    ///
+    /// The remote's tips matching its fetch refspecs, and every tag with
+    /// `all_tags`, are wanted unless they are already stored here; the local
+    /// ref tips are offered as haves. See
+    /// [`TorConnection::estimate_fetch_async`] for what the remote is asked.
+    pub async fn estimate_fetch(&self, repo: &Repository, remote_name: &str, all_tags: bool) -> Result<FetchEstimate> {
+        let repo_path = repo.path().to_path_buf();
+        let remote = repo.find_remote(remote_name)
+            .map_err(|e| repo_err(format!("Failed to get remote '{}': {}", remote_name, e), &repo_path))?;
+        let remote_url = remote.url(gix::remote::Direction::Fetch)
+            .map(|url| url.to_bstring().to_string())
+            .ok_or_else(|| repo_err(format!("Remote '{}' has no fetch URL", remote_name), &repo_path))?;
+        let sources: Vec<String> = remote.refspecs(gix::remote::Direction::Fetch)
+            .iter()
+            .filter_map(|spec| spec.to_ref().source().map(|source| source.to_string()))
+            .collect();
+        let fetched = |name: &str| {
+            (all_tags && name.starts_with("refs/tags/")) || sources.iter().any(|source| match source.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == source,
+            })
+        };
+        
+        let config = repo.config_snapshot();
+        let transport = self.track_pooled_transport(
+            PooledTorTransport::new(self.tor_client()).await?
+                .with_url_config(UrlConfig::from_config(config.plumbing()))
+                .with_remote_isolation(config.plumbing())
+                .with_max_haves(self.config.tor.negotiation_max_haves)
+                .with_max_negotiation_rounds(self.config.tor.negotiation_max_rounds),
+        )?;
+        let mut connection = TorConnection::with_transport(&remote_url, transport)?
+            .with_http_compression(self.config.tor.http_compression);
+        let advertised = connection.list_refs_async().await?;
+        
+        let mut haves = Vec::new();
+        let refs = repo.references()
+            .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &repo_path))?;
+        let all = refs.all()
+            .map_err(|e| repo_err(format!("Failed to read refs: {}", e), &repo_path))?;
+        for mut reference in all.flatten() {
+            if let Ok(id) = reference.peel_to_id_in_place() {
+                haves.push(super::ObjectId::from(id.detach()));
+            }
+        }
+        let mut wants = Vec::new();
+        for (name, id) in &advertised {
+            let present = matches!(repo.try_find_header(gix::ObjectId::from(id)), Ok(Some(_)));
+            if fetched(name) && !present && !wants.contains(id) {
+                wants.push(id.clone());
+            }
+        }
+        
+        connection.estimate_fetch_async(&wants, &haves).await
+    }
+    
+    /// Delete remote-tracking refs whose branches no longer exist on the remote
+    ///
+    /// Compares `refs/remotes/<remote>/*` against the refs the remote currently
+    /// advertises and returns the names of the stale refs. With `dry_run` the
+    /// refs are only reported, not deleted.
+    pub async fn prune(&self, repo: &Repository, remote_name: &str, dry_run: bool) -> Result<Vec<String>> {
+        let repo_path = repo.path().to_path_buf();
+        log::info!("Pruning stale refs for remote '{}' in {}", remote_name, repo_path.display());
+        
+        let remote = repo.find_remote(remote_name)
+            .map_err(|e| repo_err(format!("Failed to get remote '{}': {}", remote_name, e), &repo_path))?;
+        let remote_url = remote.url(gix::remote::Direction::Fetch)
+            .map(|url| url.to_bstring().to_string())
+            .unwrap_or_default();
+        
+        // Ask the remote which refs it currently has
+        let connection = remote.connect(gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Failed to connect to remote: {}", e), remote_url.clone()))?;
+        let ref_map = connection.ref_map(gix::progress::Discard, Default::default())
+            .map_err(|e| transport_err(format!("Failed to list remote refs: {}", e), remote_url.clone()))?;
+        prune_tracking_refs(repo, remote_name, &advertised_ref_names(&ref_map), dry_run)
+    }
+    
+    /// Fetch from a remote while changing the depth of a shallow history
+    ///
+    /// The remote reports which commits become shallow or regain their
+    /// parents, and `$GIT_DIR/shallow` is updated to match. Returns the
+    /// boundary before and after the fetch; an empty boundary means the
+    /// repository now has its complete history.
+    pub async fn fetch_depth(&self, repo: &Repository, remote_name: &str, depth: FetchDepth, cancel: &CancellationToken) -> Result<ShallowFetchOutcome> {
+        let repo_path = repo.path().to_path_buf();
+        check_fetch_depth(&repo_path, depth)?;
+        let previous = read_shallow(&repo_path)?;
+        log::info!("Fetching from '{}' with {:?} ({} shallow commits)", remote_name, depth, previous.len());
+        
+        let remote = repo.find_remote(remote_name)
+            .map_err(|e| repo_err(format!("Failed to get remote '{}': {}", remote_name, e), &repo_path))?;
+        let remote_url = remote.url(gix::remote::Direction::Fetch)
+            .map(|url| url.to_bstring().to_string())
+            .unwrap_or_default();
+        
+        let shallow = match depth {
+            FetchDepth::Depth(depth) => match std::num::NonZeroU32::new(depth) {
+                Some(depth) => gix::remote::fetch::Shallow::DepthAtRemote(depth),
+                None => return Err(GitError::InvalidArgument("depth must be a positive number".to_string())),
+            },
+            FetchDepth::Deepen(depth) => gix::remote::fetch::Shallow::Deepen(depth),
+            FetchDepth::Since(time) => gix::remote::fetch::Shallow::Since {
+                cutoff: gix::date::Time::new(time as _, 0),
+            },
+            FetchDepth::Unshallow => gix::remote::fetch::Shallow::undo(),
+        };
+        
+        let interrupt = interrupt_on_cancel(cancel);
+        let connection = remote.connect(gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Failed to connect to remote: {}", e), remote_url.clone()))?;
+        let result = connection.prepare_fetch(gix::progress::Discard, Default::default())
+            .map_err(|e| transport_err(format!("Failed to negotiate with remote: {}", e), remote_url.clone()))?
+            .with_shallow(shallow)
+            .receive(gix::progress::Discard, &interrupt.flag);
+        if cancel.is_cancelled() {
+            return Err(GitError::Cancelled(format!("Fetch from {} was cancelled", remote_url)));
+        }
+        result.map_err(|e| transport_err(format!("Failed to fetch from remote: {}", e), remote_url))?;
+        
+        let boundary = read_shallow(&repo_path)?;
+        log::info!("Shallow boundary changed from {} to {} commits", previous.len(), boundary.len());
+        Ok(ShallowFetchOutcome { previous, boundary })
+    }
+    
+    /// Describe a remote using only locally stored configuration and refs
+    pub fn remote_info(&self, repo: &Repository, remote_name: &str) -> Result<RemoteInfo> {
+        let repo_path = repo.path().to_path_buf();
+        let remote = repo.find_remote(remote_name)
+            .map_err(|e| repo_err(format!("Failed to get remote '{}': {}", remote_name, e), &repo_path))?;
+        
+        let url = |direction| remote.url(direction)
+            .map(|url| utils::format_repo_url_safe(&url.to_bstring().to_string()));
+        let refspecs = |direction| remote.refspecs(direction)
+            .iter()
+            .map(|spec| spec.to_ref().to_bstring().to_string())
+            .collect::<Vec<_>>();
+        
+        let tracking_refs = tracking_ref_targets(repo, remote_name)?
+            .into_keys()
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        
+        Ok(RemoteInfo {
+            name: remote_name.to_string(),
+            fetch_url: url(gix::remote::Direction::Fetch),
+            push_url: url(gix::remote::Direction::Push),
+            fetch_refspecs: refspecs(gix::remote::Direction::Fetch),
+            push_refspecs: refspecs(gix::remote::Direction::Push),
+            tracking_refs,
+        })
+    }
+    
+    /// Add a remote, storing its Tor settings for the remote's URL
+    ///
+    /// Malformed onion addresses are rejected before anything is written.
+    pub fn add_remote(&self, repo: &Repository, name: &str, url: &str, tor: &RemoteTorSettings) -> Result<()> {
+        super::remote::add_remote(repo, name, url, tor)
+    }
+    
+    /// Change the URL of an existing remote
+    pub fn set_remote_url(&self, repo: &Repository, name: &str, url: &str) -> Result<()> {
+        super::remote::set_remote_url(repo, name, url)
+    }
+    
+    /// Remove a remote and its remote-tracking refs, returning the deleted refs
+    pub fn remove_remote(&self, repo: &Repository, name: &str) -> Result<Vec<String>> {
+        super::remote::remove_remote(repo, name)
+    }
+    
+    /// Contact a remote and list what it currently advertises
+    pub async fn remote_state(&self, repo: &Repository, remote_name: &str) -> Result<RemoteState> {
+        let repo_path = repo.path().to_path_buf();
+        let remote = repo.find_remote(remote_name)
+            .map_err(|e| repo_err(format!("Failed to get remote '{}': {}", remote_name, e), &repo_path))?;
+        let remote_url = remote.url(gix::remote::Direction::Fetch)
+            .map(|url| utils::format_repo_url_safe(&url.to_bstring().to_string()))
+            .unwrap_or_default();
+        
+        let connection = remote.connect(gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Failed to connect to remote: {}", e), remote_url.clone()))?;
+        let ref_map = connection.ref_map(gix::progress::Discard, Default::default())
+            .map_err(|e| transport_err(format!("Failed to list remote refs: {}", e), remote_url.clone()))?;
+        
+        let mut head_branch = None;
+        let mut refs = Vec::new();
+        for remote_ref in &ref_map.remote_refs {
+            if let gix::protocol::handshake::Ref::Symbolic { full_ref_name, target, .. } = remote_ref {
+                if full_ref_name == "HEAD" {
+                    head_branch = Some(target.to_string());
+                    continue;
+                }
+            }
+            let (name, target, _) = remote_ref.unpack();
+            if let Some(target) = target {
+                refs.push((name.to_string(), target.to_owned()));
+            }
+        }
+        refs.sort();
+        
+        Ok(RemoteState { head_branch, refs })
+    }
+    
+    /// List the refs a remote advertises, without a local repository
+    ///
+    /// Goes through gitoxide's transports, so the routing registered at
+    /// startup applies. Peeled tags are listed as `<tag>^{}` like `git
+    /// ls-remote` does.
+    pub fn ls_remote(&self, url: &str) -> Result<RemoteListing> {
+        let safe_url = utils::format_repo_url_safe(url);
+        
+        // gitoxide needs a repository to hang the remote off; nothing is
+        // written to it, and it goes away with the temporary directory
+        let scratch = tempfile::tempdir()
+            .map_err(|e| GitError::IO(format!("Failed to create scratch repository: {}", e), None))?;
+        let repo = gix::init_bare(scratch.path())
+            .map_err(|e| repo_err(format!("Failed to create scratch repository: {}", e), scratch.path()))?;
+        let remote = repo.remote_at(url)
+            .map_err(|e| transport_err(format!("Invalid remote URL: {}", e), safe_url.clone()))?
+            .with_refspecs(Some("+refs/*:refs/*"), gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Invalid refspec: {}", e), safe_url.clone()))?;
+        
+        let connection = remote.connect(gix::remote::Direction::Fetch)
+            .map_err(|e| transport_err(format!("Failed to connect to remote: {}", e), safe_url.clone()))?;
+        let options = gix::remote::ref_map::Options {
+            prefix_from_spec_as_filter_on_remote: false,
+            ..Default::default()
+        };
+        let ref_map = connection.ref_map(gix::progress::Discard, options)
+            .map_err(|e| transport_err(format!("Failed to list remote refs: {}", e), safe_url.clone()))?;
+        
+        let mut listing = RemoteListing::default();
+        for remote_ref in &ref_map.remote_refs {
+            if let gix::protocol::handshake::Ref::Symbolic { full_ref_name, target, .. } = remote_ref {
+                listing.symrefs.push((full_ref_name.to_string(), target.to_string()));
+            }
+            let (name, target, peeled) = remote_ref.unpack();
+            if let Some(target) = target {
+                listing.refs.push((name.to_string(), target.to_owned()));
+            }
+            if let Some(peeled) = peeled {
+                listing.refs.push((format!("{}^{{}}", name), peeled.to_owned()));
+            }
+        }
+        
+        Ok(listing)
     }
     
     /// Push changes to a remote repository
-    pub async fn push(&self, repo: &Repository, remote: Option<&str>, refspec: Option<&str>) -> Result<()> {
+    ///
+    /// Cancellation is honoured until the pack starts uploading; after that the
+    /// push runs to completion so the remote never sees a half-applied update.
+    pub async fn push(&self, repo: &Repository, remote: Option<&str>, refspec: Option<&str>, cancel: &CancellationToken) -> Result<()> {
         // Get repository path for better error reporting
         let repo_path = repo.path().to_path_buf();
         
@@ -310,6 +1048,10 @@ impl ArtiGitClient {
             options.specs = vec![push_spec];
         }
         
+        if cancel.is_cancelled() {
+            return Err(GitError::Cancelled(format!("Push to {} was cancelled", remote_url)));
+        }
+        
         // Perform the push - transport will be automatically selected based on URL
         log::info!("Pushing to remote: {}", remote_name);
         let result = remote.push(&options)
@@ -330,6 +1072,7 @@ impl ArtiGitClient {
         let repo_path = repo.path().to_path_buf();
         log::info!("Adding files to index in repository: {}", repo_path.display());
         
+        let index_lock = crate::core::lock_index(repo)?;
         let mut index = repo.index()
             .map_err(|e| repo_err(format!("Failed to get repository index: {}", e), &repo_path))?;
         
@@ -373,16 +1116,43 @@ impl ArtiGitClient {
         
         // Write the updated index
         log::debug!("Writing updated index with {} added files", added_count);
-        index.write()
-            .map_err(|e| repo_err(format!("Failed to write index: {}", e), &repo_path))?;
+        crate::core::write_index(index_lock, &index)?;
         
         log::info!("Successfully added {} files to the index", added_count);
         Ok(())
     }
     
     /// Commit changes to the repository
-    pub async fn commit(&self, repo: &Repository, message: &str, sign: bool) -> Result<gix_hash::ObjectId> {
-        let committer = self.get_committer_from_config()?;
+    ///
+    /// Fails with [`GitError::NothingToCommit`] if the index has the same
+    /// tree as HEAD, unless `allow_empty` is set. With `signing` the commit
+    /// carries an SSH signature by the key it picks; an ephemeral key only
+    /// lets others check the commit against the key recorded in it.
+    pub async fn commit(&self, repo: &Repository, message: &str, signing: Option<SigningMode>, allow_empty: bool) -> Result<gix_hash::ObjectId> {
+        // Like git, keep the index locked so it can't change under the commit
+        let _index_lock = crate::core::lock_index(repo)?;
+        
+        // Refuse to record the same tree as HEAD unless asked to
+        let tree_id = crate::core::write_index_tree(repo)?;
+        crate::core::ensure_tree_changed(repo, tree_id, allow_empty)?;
+        
+        let signature = self.commit_signature(repo)?;
+        if let Some(mode) = signing {
+            let signer = mode.signer();
+            let parents: Vec<crate::core::ObjectId> = repo.head_id().ok()
+                .map(|id| crate::core::ObjectId::from(id.detach()))
+                .into_iter()
+                .collect();
+            let signed = crate::repository::Repository::open(repo.work_dir().unwrap_or(repo.path()))?
+                .create_commit_signed("HEAD", &signature, &signature, message, &parents, signer.as_ref())?;
+            if mode.is_ephemeral() {
+                log::info!("Signed {} with one-time key {}", signed, base64::Engine::encode(
+                    &base64::engine::general_purpose::STANDARD, signer.public_key()));
+            }
+            return Ok(gix_hash::ObjectId::from(&signed));
+        }
+        
+        let committer = signature.to_actor();
         let author = committer.clone();
         
         // Create commit builder
@@ -390,17 +1160,10 @@ impl ArtiGitClient {
             .map_err(|e| GitError::Repository(format!("Failed to create commit builder: {}", e)))?;
         
         // Set basic commit properties
-        commit_builder.author(author);
-        commit_builder.committer(committer);
+        commit_builder.author(author.to_ref());
+        commit_builder.committer(committer.to_ref());
         commit_builder.message(message);
         
-        // Sign the commit if requested
-        if sign {
-            let key = self.get_or_create_signing_key()?;
-            commit_builder.sign(&key)
-                .map_err(|e| GitError::Crypto(format!("Failed to sign commit: {}", e)))?;
-        }
-        
         // Create the commit
         let commit_id = commit_builder.commit()
             .map_err(|e| GitError::Repository(format!("Failed to create commit: {}", e)))?;
@@ -408,39 +1171,62 @@ impl ArtiGitClient {
         Ok(commit_id)
     }
     
-    /// Get committer information from configuration
-    fn get_committer_from_config(&self) -> Result<gix_actor::SignatureRef<'static>> {
-        // Get name and email from config, or use defaults
-        let name = self.config.git.user_name.clone()
-            .unwrap_or_else(|| "ArtiGit User".to_string());
-            
-        let email = self.config.git.user_email.clone()
-            .unwrap_or_else(|| "user@artigit.invalid".to_string());
-        
-        // Create the signature
-        Ok(gix_actor::SignatureRef {
-            name: name.into(),
-            email: email.into(),
-            time: gix_date::Time::now_utc(),
-        }.to_owned())
+    /// Apply one patch of a mailed series and commit it, like `git am`
+    ///
+    /// The commit keeps the mail's author and date; the committer comes from
+    /// the configuration. Nothing is changed if the patch doesn't apply.
+    pub fn apply_mail(&self, repo: &Repository, mail: &crate::core::MailPatch) -> Result<gix_hash::ObjectId> {
+        crate::core::apply_patch(repo, &mail.diff, true, false)?;
+        let tree_id = crate::core::write_index_tree(repo)?;
+
+        let parents: Vec<gix_hash::ObjectId> = match repo.head_id() {
+            Ok(id) => vec![id.detach()],
+            Err(_) => Vec::new(),
+        };
+        let author = gix_actor::Signature {
+            name: mail.author_name.as_str().into(),
+            email: mail.author_email.as_str().into(),
+            time: mail.date,
+        };
+        let committer = self.commit_signature(repo)?.to_actor();
+
+        let commit_id = repo.commit_as(committer.to_ref(), author.to_ref(), "HEAD", mail.message.as_str(), tree_id, parents)
+            .map_err(|e| repo_err(format!("Failed to commit patch '{}': {}", mail.subject, e), repo.path()))?;
+        Ok(commit_id.detach())
     }
     
-    /// Get or create an Ed25519 key for signing
-    fn get_or_create_signing_key(&self) -> Result<ed25519_dalek::Keypair> {
-        use rand::Rng;
-        
-        // TODO: In a real implementation, we should store and load this key
-        // For now, we'll just create a temporary one
-        
-        // Create a random seed
-        let mut csprng = rand::thread_rng();
-        let mut seed = [0u8; 32];
-        csprng.fill(&mut seed);
-        
-        // Create keypair from seed
-        let keypair = ed25519_dalek::Keypair::generate(&mut csprng);
-        
-        Ok(keypair)
+    /// Signature for the commits this client makes in `repo`
+    ///
+    /// With a configured identity the commits are pseudonymous (see
+    /// [`Signature::anonymous`]). Otherwise `git.user_name` and
+    /// `git.user_email` are used, or else `user.name` and `user.email` of
+    /// the repository; it fails if neither is set.
+    pub fn commit_signature(&self, repo: &Repository) -> Result<Signature> {
+        if let Some(identity) = &self.identity {
+            return Ok(Signature::anonymous(identity));
+        }
+        if let (Some(name), Some(email)) = (&self.config.git.user_name, &self.config.git.user_email) {
+            return Ok(Signature::now(name, email));
+        }
+        Signature::from_config(&crate::repository::Config::load_from_repo(repo.path())?)
+    }
+    
+    /// The long-term key for signed commits: the configured identity's key
+    ///
+    /// Fails without `git.identity_key`, since a key made up on the spot
+    /// could never be recognised again; sign with an ephemeral key instead.
+    pub fn signing_key(&self) -> Result<Arc<dyn Signer + Send + Sync>> {
+        match &self.identity {
+            Some(identity) => Ok(identity.clone()),
+            None => Err(GitError::Config(
+                "Signing with a long-term key needs git.identity_key; use --ephemeral-key to sign with a one-time key".to_string(),
+            )),
+        }
+    }
+    
+    /// The pseudonymous identity configured by `git.identity_key`, if any
+    pub fn identity(&self) -> Option<&AnonymousIdentity> {
+        self.identity.as_deref()
     }
     
     /// Get the configuration
@@ -459,6 +1245,23 @@ impl ArtiGitClient {
         Ok(())
     }
     
+    #[cfg(feature = "tor")]
+    /// Share `transport`, closing its pooled connections on [`ArtiGitClient::shutdown`]
+    ///
+    /// Hosts without a trusted fingerprint are checked against the
+    /// configured known hosts store, and recorded there on first use. With
+    /// a configured identity, the transport connects as that identity.
+    pub fn track_pooled_transport(&self, transport: PooledTorTransport) -> Result<Arc<PooledTorTransport>> {
+        let known_hosts = KnownHosts::open(&self.config.tor.known_hosts)?;
+        let transport = Arc::new(with_pooled_identity(transport, self.identity.as_deref())
+            .with_known_hosts(known_hosts)
+            .with_accept_new_fingerprint(self.config.tor.accept_new_fingerprint));
+        self.pooled_transports.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(transport.clone());
+        Ok(transport)
+    }
+    
     #[cfg(feature = "tor")]
     /// Get the Tor client instance, if available
     pub fn tor_client(&self) -> Option<Arc<TorClient<PreferredRuntime>>> {
@@ -482,7 +1285,8 @@ impl ArtiGitClient {
                 // Use the existing tor client if available
                 let transport = TorTransport::new(self.tor_client.clone())
                     .await
-                    .map_err(|e| GitError::Transport(format!("Failed to create Tor transport: {}", e)))?;
+                    .map_err(|e| GitError::Transport(format!("Failed to create Tor transport: {}", e)))?
+                    .with_events(self.events.clone());
                     
                 self.tor_transport = Some(Arc::new(transport));
             }
@@ -572,13 +1376,13 @@ impl ArtiGitClient {
                                 self.config.lfs.clone(),
                                 ipfs_client.clone()
                             ) {
-                                return Some(Arc::new(lfs_client));
+                                return Some(Arc::new(self.with_lfs_transport(lfs_client)));
                             }
                         }
                     }
                     
                     // Return the client without IPFS support
-                    Some(Arc::new(client))
+                    Some(Arc::new(self.with_lfs_transport(client)))
                 },
                 Err(e) => {
                     eprintln!("Warning: Failed to create LFS client: {}", e);
@@ -590,13 +1394,33 @@ impl ArtiGitClient {
         }
     }
     
+    /// Route `client`'s requests to onion services through Tor, if it's on
+    fn with_lfs_transport(&self, client: crate::lfs::LfsClient) -> crate::lfs::LfsClient {
+        #[cfg(feature = "tor")]
+        if let Some(transport) = &self.lfs_transport {
+            return client.with_tor_transport(transport.clone());
+        }
+        client
+    }
+    
     /// Get the LFS storage backend, if available
+    ///
+    /// The storage is opened on first use and shared afterwards, so
+    /// [`ArtiGitClient::shutdown`] can flush it.
     pub fn lfs_storage(&self) -> Option<Arc<crate::lfs::LfsStorage>> {
         // Check if LFS is enabled in the config
         if !self.config.lfs.enabled {
             return None;
         }
         
+        let mut cached = self.lfs_storage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if cached.is_none() {
+            *cached = self.open_lfs_storage();
+        }
+        cached.clone()
+    }
+    
+    fn open_lfs_storage(&self) -> Option<Arc<crate::lfs::LfsStorage>> {
         // Create the LFS storage on-demand using the configured directory
         let base_dir = if self.config.lfs.objects_dir.is_absolute() {
             self.config.lfs.objects_dir.clone()
@@ -638,6 +1462,54 @@ impl ArtiGitClient {
         }
     }
     
+    /// Write out all in-memory state and close connections before exiting
+    ///
+    /// Waits for background IPFS uploads and saves the IPFS mappings, chunks
+    /// and pending-upload journal, saves the LFS statistics, closes the
+    /// client's own Tor transport and the connections of the transports passed
+    /// to [`ArtiGitClient::track_pooled_transport`]. Every step runs even if an
+    /// earlier one fails; the first error is returned.
+    pub async fn shutdown(self) -> Result<()> {
+        let mut result = Ok(());
+        
+        #[cfg(feature = "ipfs")]
+        if let Some(storage) = &self.ipfs_storage {
+            if let Err(e) = storage.flush().await {
+                log::error!("Failed to flush IPFS storage: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+        
+        let lfs_storage = self.lfs_storage.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(storage) = lfs_storage {
+            if let Err(e) = storage.flush().await {
+                log::error!("Failed to flush LFS storage: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+        
+        #[cfg(feature = "tor")]
+        if let Some(transport) = &self.tor_transport {
+            transport.close();
+        }
+        #[cfg(feature = "tor")]
+        let pooled_transports = std::mem::take(
+            &mut *self.pooled_transports.lock().unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        #[cfg(feature = "tor")]
+        for transport in pooled_transports {
+            match transport.close_all_connections().await {
+                Ok(closed) => log::debug!("Closed {} pooled Tor connections", closed),
+                Err(e) => {
+                    log::error!("Failed to close Tor connections: {}", e);
+                    result = result.and(Err(e));
+                },
+            }
+        }
+        
+        result
+    }
+    
     /// Initialize Git LFS for a repository
     pub async fn init_lfs(&self, repo_path: impl AsRef<Path>) -> Result<()> {
         crate::lfs::configure_lfs(self, repo_path).await
@@ -652,4 +1524,332 @@ impl ArtiGitClient {
     pub async fn start_lfs_server(&self, addr: &str, base_url: &str, repo_dir: impl AsRef<Path>) -> Result<()> {
         crate::lfs::start_server(self, addr, base_url, repo_dir).await
     }
-}
\ No newline at end of file
+}
+
+/// A remote-tracking ref that moved during a fetch
+#[derive(Debug, Clone)]
+pub struct FetchedRefUpdate {
+    /// Full name of the remote-tracking ref
+    pub name: String,
+    /// Previous target, or `None` if the ref is new
+    pub old: Option<gix::ObjectId>,
+    /// New target after the fetch
+    pub new: gix::ObjectId,
+    /// Whether the ref moved to a commit that does not contain the old one
+    pub forced: bool,
+}
+
+/// How [`ArtiGitClient::clone_with_options`] sets up a clone
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// Clone into a bare repository, without a working tree
+    pub bare: bool,
+    /// Fetch only this many commits of each branch
+    pub depth: Option<NonZeroU32>,
+    /// Local repository to borrow objects from
+    pub reference: Option<CloneReference>,
+    /// Keep the pack received over Tor next to the destination if the
+    /// transfer breaks off, and continue from it on the next attempt
+    pub resume: bool,
+}
+
+/// What a pull changed locally
+#[derive(Debug, Clone, Default)]
+pub struct PullOutcome {
+    /// Remote-tracking refs that moved
+    pub updates: Vec<FetchedRefUpdate>,
+    /// Full names of tags that did not exist locally before the pull
+    pub new_tags: Vec<String>,
+    /// Remote-tracking refs deleted because their branch is gone upstream
+    pub pruned: Vec<String>,
+    /// What merging the fetched branch into HEAD did, for pulls
+    pub merge: Option<HeadMerge>,
+}
+
+impl PullOutcome {
+    /// Whether nothing changed locally
+    pub fn is_up_to_date(&self) -> bool {
+        self.updates.is_empty() && self.new_tags.is_empty() && self.pruned.is_empty()
+    }
+}
+
+/// The shallow boundary before and after a depth-changing fetch
+#[derive(Debug, Clone, Default)]
+pub struct ShallowFetchOutcome {
+    /// Shallow commits before the fetch
+    pub previous: std::collections::BTreeSet<gix::ObjectId>,
+    /// Shallow commits after the fetch; empty if the history is complete
+    pub boundary: std::collections::BTreeSet<gix::ObjectId>,
+}
+
+impl ShallowFetchOutcome {
+    /// Check whether the repository has its complete history now
+    pub fn is_complete(&self) -> bool {
+        self.boundary.is_empty()
+    }
+}
+
+/// Locally known details about a configured remote
+#[derive(Debug, Clone)]
+pub struct RemoteInfo {
+    /// Remote name
+    pub name: String,
+    /// URL used for fetching, with credentials removed
+    pub fetch_url: Option<String>,
+    /// URL used for pushing, with credentials removed
+    pub push_url: Option<String>,
+    /// Configured fetch refspecs
+    pub fetch_refspecs: Vec<String>,
+    /// Configured push refspecs
+    pub push_refspecs: Vec<String>,
+    /// Remote-tracking refs currently stored locally
+    pub tracking_refs: Vec<String>,
+}
+
+/// What a remote advertised when it was last contacted
+#[derive(Debug, Clone)]
+pub struct RemoteState {
+    /// Branch the remote HEAD points to, if advertised
+    pub head_branch: Option<String>,
+    /// Advertised refs and their targets
+    pub refs: Vec<(String, gix::ObjectId)>,
+}
+
+/// Refs a remote advertises, as listed by [`ArtiGitClient::ls_remote`]
+#[derive(Debug, Clone, Default)]
+pub struct RemoteListing {
+    /// Advertised refs and their targets, in the order the remote sent them
+    pub refs: Vec<(String, gix::ObjectId)>,
+    /// Symbolic refs and the ref each points to, such as `HEAD`
+    pub symrefs: Vec<(String, String)>,
+}
+
+/// Names of the refs in a remote's advertisement
+fn advertised_ref_names(ref_map: &gix::remote::fetch::RefMap) -> HashSet<String> {
+    ref_map.remote_refs
+        .iter()
+        .map(|r| r.unpack().0.to_string())
+        .collect()
+}
+
+/// Delete the remote-tracking refs of `remote_name` whose branch is not in `advertised`
+///
+/// Returns the names of the stale refs; with `dry_run` they are only reported.
+fn prune_tracking_refs(repo: &Repository, remote_name: &str, advertised: &HashSet<String>, dry_run: bool) -> Result<Vec<String>> {
+    let repo_path = repo.path().to_path_buf();
+    
+    // Map each remote-tracking ref back to the branch it mirrors
+    let prefix = format!("refs/remotes/{}/", remote_name);
+    let mut stale = Vec::new();
+    let references = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    let tracking = references.prefixed(prefix.as_str())
+        .map_err(|e| repo_err(format!("Failed to read remote-tracking refs: {}", e), &repo_path))?;
+    for reference in tracking {
+        let reference = reference
+            .map_err(|e| repo_err(format!("Failed to read reference: {}", e), &repo_path))?;
+        let name = reference.name().as_bstr().to_string();
+        let branch = &name[prefix.len()..];
+        
+        // The symbolic refs/remotes/<remote>/HEAD is not a branch
+        if branch == "HEAD" {
+            continue;
+        }
+        
+        if !advertised.contains(&format!("refs/heads/{}", branch)) {
+            stale.push(name);
+        }
+    }
+    
+    if stale.is_empty() || dry_run {
+        return Ok(stale);
+    }
+    
+    let edits = stale
+        .iter()
+        .map(|name| {
+            let name = gix::refs::FullName::try_from(name.as_str())
+                .map_err(|e| repo_err(format!("Invalid reference name '{}': {}", name, e), &repo_path))?;
+            Ok(gix::refs::transaction::RefEdit {
+                change: gix::refs::transaction::Change::Delete {
+                    expected: gix::refs::transaction::PreviousValue::MustExist,
+                    log: gix::refs::transaction::RefLog::AndReference,
+                },
+                name,
+                deref: false,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    crate::core::edit_references(repo, edits)?;
+    
+    log::info!("Pruned {} stale refs for remote '{}'", stale.len(), remote_name);
+    Ok(stale)
+}
+
+/// Collect the current targets of `refs/remotes/<remote>/*`
+fn tracking_ref_targets(repo: &Repository, remote_name: &str) -> Result<std::collections::HashMap<String, gix::ObjectId>> {
+    let repo_path = repo.path().to_path_buf();
+    let prefix = format!("refs/remotes/{}/", remote_name);
+    let mut targets = std::collections::HashMap::new();
+    
+    let references = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    let tracking = references.prefixed(prefix.as_str())
+        .map_err(|e| repo_err(format!("Failed to read remote-tracking refs: {}", e), &repo_path))?;
+    for reference in tracking {
+        let reference = reference
+            .map_err(|e| repo_err(format!("Failed to read reference: {}", e), &repo_path))?;
+        if let Some(id) = reference.try_id() {
+            targets.insert(reference.name().as_bstr().to_string(), id.detach());
+        }
+    }
+    
+    Ok(targets)
+}
+
+/// Lay out a fresh bare clone like `git clone --bare` does
+///
+/// The remote's branches become local branches in place of the
+/// `refs/remotes/origin/*` refs, and later fetches update them the same way.
+fn promote_tracking_refs(repo: &Repository) -> Result<()> {
+    use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+    
+    let repo_path = repo.path().to_path_buf();
+    let full_name = |name: String| gix::refs::FullName::try_from(name.as_str())
+        .map_err(|e| repo_err(format!("Invalid reference name {}: {}", name, e), &repo_path));
+    let delete = |name| RefEdit {
+        change: Change::Delete { expected: PreviousValue::Any, log: RefLog::AndReference },
+        name,
+        deref: false,
+    };
+    
+    let prefix = "refs/remotes/origin/";
+    let mut edits = Vec::new();
+    for (name, id) in tracking_ref_targets(repo, "origin")? {
+        if name == "refs/remotes/origin/HEAD" {
+            continue;
+        }
+        edits.push(RefEdit {
+            change: Change::Update {
+                log: LogChange::default(),
+                expected: PreviousValue::Any,
+                new: gix::refs::Target::Peeled(id),
+            },
+            name: full_name(format!("refs/heads/{}", &name[prefix.len()..]))?,
+            deref: false,
+        });
+        edits.push(delete(full_name(name)?));
+    }
+    let remote_head = repo.try_find_reference("refs/remotes/origin/HEAD")
+        .map_err(|e| repo_err(format!("Failed to read reference: {}", e), &repo_path))?;
+    if let Some(remote_head) = remote_head {
+        edits.push(delete(remote_head.name().to_owned()));
+    }
+    crate::core::edit_references(repo, edits)?;
+    
+    super::remote::update_local_config(repo, |config| {
+        super::remote::set_value(config, "remote", Some("origin"), "fetch", "+refs/heads/*:refs/heads/*")
+    })
+}
+
+/// Get the names of all local tags, sorted
+fn tag_names(repo: &Repository) -> Result<std::collections::BTreeSet<String>> {
+    let repo_path = repo.path().to_path_buf();
+    let references = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    let tags = references.tags()
+        .map_err(|e| repo_err(format!("Failed to read tags: {}", e), &repo_path))?;
+    
+    Ok(tags
+        .filter_map(|reference| reference.ok())
+        .map(|reference| reference.name().as_bstr().to_string())
+        .collect())
+}
+
+/// Check whether `ancestor` is reachable from `descendant`
+///
+/// `graph` is the repository's commit-graph, loaded once by the caller so
+/// checking many refs doesn't parse it again for each.
+fn is_ancestor(repo: &Repository, graph: Option<&CommitGraph>, ancestor: gix::ObjectId, descendant: gix::ObjectId) -> Result<bool> {
+    if let Some(reachable) = graph.and_then(|graph| graph.is_ancestor(&ancestor, &descendant)) {
+        return Ok(reachable);
+    }
+    
+    let walk = descendant.attach(repo).ancestors().all()
+        .map_err(|e| repo_err(format!("Failed to walk history of {}: {}", descendant, e), repo.path()))?;
+    for info in walk {
+        let info = info
+            .map_err(|e| repo_err(format!("Failed to walk history of {}: {}", descendant, e), repo.path()))?;
+        if info.id == ancestor {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Make sure the reflog of a force-updated ref records the value it replaced
+///
+/// gitoxide normally logs fetch updates itself; this only appends an entry when
+/// the newest one does not already describe this `old -> new` transition.
+fn record_forced_update(repo: &Repository, name: &str, old: gix::ObjectId, new: gix::ObjectId) -> Result<()> {
+    let log_path = super::reflog::reflog_path(repo, name);
+    let transition = format!("{} {} ", old, new);
+    
+    let existing = std::fs::read_to_string(&log_path).unwrap_or_default();
+    if existing.lines().last().map_or(false, |line| line.starts_with(&transition)) {
+        return Ok(());
+    }
+    
+    let committer = repo.committer()
+        .and_then(|c| c.ok())
+        .map(|c| format!("{} <{}>", c.name, c.email))
+        .unwrap_or_else(|| "arti-git <arti-git@localhost>".to_string());
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let entry = format!("{}{} {} +0000\tfetch: forced-update\n", transition, committer, timestamp);
+    
+    if let Some(parent) = log_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| io_err(format!("Failed to create reflog directory: {}", e), parent))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|e| io_err(format!("Failed to open reflog: {}", e), &log_path))?;
+    std::io::Write::write_all(&mut file, entry.as_bytes())
+        .map_err(|e| io_err(format!("Failed to write reflog: {}", e), &log_path))?;
+    
+    Ok(())
+}
+
+/// An interrupt flag that is raised when a cancellation token fires
+///
+/// gitoxide polls an `AtomicBool` rather than a token, so a small task mirrors
+/// the token into the flag for as long as the guard lives.
+struct CancelInterrupt {
+    flag: Arc<AtomicBool>,
+    watcher: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for CancelInterrupt {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+/// Start mirroring `cancel` into a fresh interrupt flag
+fn interrupt_on_cancel(cancel: &CancellationToken) -> CancelInterrupt {
+    let flag = Arc::new(AtomicBool::new(cancel.is_cancelled()));
+    let watcher = {
+        let flag = flag.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            cancel.cancelled().await;
+            flag.store(true, Ordering::SeqCst);
+        })
+    };
+    CancelInterrupt { flag, watcher }
+}
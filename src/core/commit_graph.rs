@@ -0,0 +1,626 @@
+//! Reading and writing `objects/info/commit-graph`
+//!
+//! The commit-graph file stores the parents, root tree, commit time and
+//! generation number of every commit reachable from the refs, so history
+//! walks don't have to inflate and parse each commit object. The format is
+//! the one git writes (version 1, SHA-1), so the file is shared with git
+//! and gitoxide. Repositories with another object format get no graph.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+use gix_hash::ObjectId;
+use sha1::{Digest, Sha1};
+
+use crate::core::{GitError, Result, io_err, repo_err};
+
+/// Location of the commit-graph file, relative to the objects directory
+pub const COMMIT_GRAPH_FILE: &str = "info/commit-graph";
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const VERSION: u8 = 1;
+const HASH_VERSION_SHA1: u8 = 1;
+const HASH_LEN: usize = 20;
+
+const CHUNK_OID_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8; 4] = b"OIDL";
+const CHUNK_COMMIT_DATA: &[u8; 4] = b"CDAT";
+const CHUNK_EXTRA_EDGES: &[u8; 4] = b"EDGE";
+
+const HEADER_LEN: usize = 8;
+const TOC_ENTRY_LEN: usize = 12;
+const COMMIT_DATA_LEN: usize = HASH_LEN + 16;
+
+const PARENT_NONE: u32 = 0x7000_0000;
+const PARENT_EXTRA_EDGES: u32 = 0x8000_0000;
+const LAST_EDGE: u32 = 0x8000_0000;
+
+/// Largest generation number the version 1 format can store
+pub const GENERATION_NUMBER_MAX: u32 = 0x3FFF_FFFF;
+
+/// A commit as recorded in the commit-graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphCommit {
+    /// Root tree of the commit
+    pub tree: ObjectId,
+    /// Parents in commit order
+    pub parents: Vec<ObjectId>,
+    /// Topological level: 1 for root commits, otherwise one more than the
+    /// highest parent
+    pub generation: u32,
+    /// Committer time in seconds since the epoch
+    pub commit_time: u64,
+}
+
+/// A commit-graph loaded into memory
+///
+/// Lookups by id are O(1); parents are stored as positions, so walking
+/// history through the graph never touches the object database.
+#[derive(Debug, Clone, Default)]
+pub struct CommitGraph {
+    ids: Vec<ObjectId>,
+    positions: HashMap<ObjectId, u32>,
+    entries: Vec<GraphEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct GraphEntry {
+    tree: ObjectId,
+    parents: Vec<u32>,
+    generation: u32,
+    commit_time: u64,
+}
+
+impl CommitGraph {
+    /// Read the commit-graph of an objects directory; `None` if there is none
+    pub fn open(objects_dir: &Path) -> Result<Option<Self>> {
+        let path = objects_dir.join(COMMIT_GRAPH_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&path)
+            .map_err(|e| io_err(format!("Failed to read commit-graph: {}", e), &path))?;
+        Self::from_bytes(&data)
+            .map(Some)
+            .map_err(|e| repo_err(format!("Invalid commit-graph: {}", e), &path))
+    }
+
+    /// Load the commit-graph of a repository for history walks
+    ///
+    /// Returns `None` when there is no graph, when it can't be read (with a
+    /// warning, since callers fall back to the object database), or when
+    /// refs/replace is in effect: the graph records the original parents,
+    /// which replacements would contradict. Only SHA-1 graphs are read, so
+    /// repositories of another object format get `None` as well.
+    pub fn for_repo(repo: &Repository) -> Option<Self> {
+        if repo.object_hash() != gix_hash::Kind::Sha1 {
+            log::debug!("Not using a commit-graph for a {:?} repository", repo.object_hash());
+            return None;
+        }
+        match crate::core::Replacements::load(repo) {
+            Ok(replacements) if replacements.is_empty() => {},
+            _ => return None,
+        }
+        match Self::open(&repo.path().join("objects")) {
+            Ok(graph) => graph,
+            Err(e) => {
+                log::warn!("Ignoring commit-graph: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Parse a commit-graph file, verifying its checksum
+    pub fn from_bytes(data: &[u8]) -> std::result::Result<Self, String> {
+        if data.len() < HEADER_LEN + TOC_ENTRY_LEN + HASH_LEN {
+            return Err("file is too short".to_string());
+        }
+        if &data[..4] != SIGNATURE {
+            return Err("bad signature".to_string());
+        }
+        if data[4] != VERSION {
+            return Err(format!("unsupported version {}", data[4]));
+        }
+        if data[5] != HASH_VERSION_SHA1 {
+            return Err(format!("unsupported hash version {}", data[5]));
+        }
+        if data[7] != 0 {
+            return Err("split commit-graphs are not supported".to_string());
+        }
+
+        let (content, checksum) = data.split_at(data.len() - HASH_LEN);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err("checksum mismatch".to_string());
+        }
+
+        let chunk_count = data[6] as usize;
+        let mut chunks = HashMap::new();
+        for index in 0..chunk_count {
+            let entry = HEADER_LEN + index * TOC_ENTRY_LEN;
+            let next = entry + TOC_ENTRY_LEN;
+            if next + TOC_ENTRY_LEN > content.len() {
+                return Err("truncated chunk table".to_string());
+            }
+            let id: [u8; 4] = data[entry..entry + 4].try_into().unwrap();
+            let start = read_u64(data, entry + 4) as usize;
+            let end = read_u64(data, next + 4) as usize;
+            if start > end || end > content.len() {
+                return Err(format!("chunk {} is out of bounds", String::from_utf8_lossy(&id)));
+            }
+            chunks.insert(id, &data[start..end]);
+        }
+
+        let chunk = |id: &[u8; 4]| chunks.get(id).copied()
+            .ok_or_else(|| format!("missing {} chunk", String::from_utf8_lossy(id)));
+        let fanout = chunk(CHUNK_OID_FANOUT)?;
+        let lookup = chunk(CHUNK_OID_LOOKUP)?;
+        let commit_data = chunk(CHUNK_COMMIT_DATA)?;
+        let edges = chunks.get(CHUNK_EXTRA_EDGES).copied().unwrap_or(&[]);
+
+        if fanout.len() != 256 * 4 {
+            return Err("bad fanout chunk".to_string());
+        }
+        let count = read_u32(fanout, 255 * 4) as usize;
+        if lookup.len() != count * HASH_LEN || commit_data.len() != count * COMMIT_DATA_LEN {
+            return Err("chunk sizes don't match the commit count".to_string());
+        }
+
+        let ids: Vec<ObjectId> = lookup.chunks(HASH_LEN)
+            .map(ObjectId::from_bytes_or_panic)
+            .collect();
+        let positions = ids.iter().enumerate()
+            .map(|(position, id)| (*id, position as u32))
+            .collect();
+
+        let parent = |position: u32| -> std::result::Result<u32, String> {
+            if (position as usize) < count {
+                Ok(position)
+            } else {
+                Err(format!("parent position {} is out of range", position))
+            }
+        };
+
+        let mut entries = Vec::with_capacity(count);
+        for record in commit_data.chunks(COMMIT_DATA_LEN) {
+            let tree = ObjectId::from_bytes_or_panic(&record[..HASH_LEN]);
+            let first = read_u32(record, HASH_LEN);
+            let second = read_u32(record, HASH_LEN + 4);
+            let generation_and_time = read_u32(record, HASH_LEN + 8);
+            let time_low = read_u32(record, HASH_LEN + 12);
+
+            let mut parents = Vec::new();
+            if first != PARENT_NONE {
+                parents.push(parent(first)?);
+            }
+            if second & PARENT_EXTRA_EDGES != 0 {
+                // Octopus merges list their remaining parents in the EDGE chunk
+                let mut edge = (second & !PARENT_EXTRA_EDGES) as usize;
+                loop {
+                    if (edge + 1) * 4 > edges.len() {
+                        return Err("extra edge is out of range".to_string());
+                    }
+                    let value = read_u32(edges, edge * 4);
+                    parents.push(parent(value & !LAST_EDGE)?);
+                    if value & LAST_EDGE != 0 {
+                        break;
+                    }
+                    edge += 1;
+                }
+            } else if second != PARENT_NONE {
+                parents.push(parent(second)?);
+            }
+
+            entries.push(GraphEntry {
+                tree,
+                parents,
+                generation: generation_and_time >> 2,
+                commit_time: (((generation_and_time & 0x3) as u64) << 32) | time_low as u64,
+            });
+        }
+
+        Ok(Self { ids, positions, entries })
+    }
+
+    /// Number of commits in the graph
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Check whether the graph has no commits
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Check whether a commit is in the graph
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.positions.contains_key(id)
+    }
+
+    /// Look up a commit
+    pub fn get(&self, id: &ObjectId) -> Option<GraphCommit> {
+        let entry = &self.entries[*self.positions.get(id)? as usize];
+        Some(GraphCommit {
+            tree: entry.tree,
+            parents: entry.parents.iter().map(|p| self.ids[*p as usize]).collect(),
+            generation: entry.generation,
+            commit_time: entry.commit_time,
+        })
+    }
+
+    /// Parents of a commit, if it is in the graph
+    pub fn parents(&self, id: &ObjectId) -> Option<Vec<ObjectId>> {
+        let entry = &self.entries[*self.positions.get(id)? as usize];
+        Some(entry.parents.iter().map(|p| self.ids[*p as usize]).collect())
+    }
+
+    /// Generation number of a commit, if it is in the graph
+    pub fn generation(&self, id: &ObjectId) -> Option<u32> {
+        self.positions.get(id).map(|p| self.entries[*p as usize].generation)
+    }
+
+    /// Committer time of a commit, if it is in the graph
+    pub fn commit_time(&self, id: &ObjectId) -> Option<u64> {
+        self.positions.get(id).map(|p| self.entries[*p as usize].commit_time)
+    }
+
+    /// Check whether `ancestor` is reachable from `descendant`
+    ///
+    /// Returns `None` when `descendant` isn't in the graph. The graph is
+    /// closed under parents, so an `ancestor` missing from it can't be
+    /// reachable. Commits with a lower generation than `ancestor` are never
+    /// expanded, which keeps the walk short for nearby commits.
+    pub fn is_ancestor(&self, ancestor: &ObjectId, descendant: &ObjectId) -> Option<bool> {
+        let start = *self.positions.get(descendant)?;
+        let target = match self.positions.get(ancestor) {
+            Some(target) => *target,
+            None => return Some(false),
+        };
+        let floor = self.entries[target as usize].generation;
+
+        let mut seen = HashSet::new();
+        let mut stack = vec![start];
+        while let Some(position) = stack.pop() {
+            if position == target {
+                return Some(true);
+            }
+            let entry = &self.entries[position as usize];
+            // Capped generations don't order commits, so keep walking past them
+            if entry.generation <= floor && entry.generation < GENERATION_NUMBER_MAX {
+                continue;
+            }
+            for parent in &entry.parents {
+                if seen.insert(*parent) {
+                    stack.push(*parent);
+                }
+            }
+        }
+        Some(false)
+    }
+}
+
+/// Collects commits and writes them as a commit-graph file
+///
+/// Commits can be added in any order; generation numbers are computed when
+/// the file is written. Every parent must be added as well.
+#[derive(Debug, Default)]
+pub struct CommitGraphBuilder {
+    commits: BTreeMap<ObjectId, PendingCommit>,
+}
+
+#[derive(Debug)]
+struct PendingCommit {
+    tree: ObjectId,
+    parents: Vec<ObjectId>,
+    commit_time: u64,
+}
+
+impl CommitGraphBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a commit
+    pub fn add(&mut self, id: ObjectId, tree: ObjectId, parents: Vec<ObjectId>, commit_time: u64) {
+        self.commits.insert(id, PendingCommit { tree, parents, commit_time });
+    }
+
+    /// Number of commits added so far
+    pub fn len(&self) -> usize {
+        self.commits.len()
+    }
+
+    /// Check whether no commits were added
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty()
+    }
+
+    /// Write the graph in git's format, returning the number of commits
+    pub fn write_to<W: Write>(&self, out: W) -> std::result::Result<usize, String> {
+        let ids: Vec<&ObjectId> = self.commits.keys().collect();
+        let positions: HashMap<&ObjectId, u32> = ids.iter().enumerate()
+            .map(|(position, id)| (*id, position as u32))
+            .collect();
+
+        let mut parent_positions = Vec::with_capacity(ids.len());
+        for (id, commit) in &self.commits {
+            let mut parents = Vec::with_capacity(commit.parents.len());
+            for parent in &commit.parents {
+                match positions.get(parent) {
+                    Some(position) => parents.push(*position),
+                    None => return Err(format!("parent {} of {} is not in the graph", parent, id)),
+                }
+            }
+            parent_positions.push(parents);
+        }
+        let generations = compute_generations(&parent_positions);
+
+        let mut fanout = Vec::with_capacity(256 * 4);
+        let mut count = 0u32;
+        for byte in 0..=255u8 {
+            while (count as usize) < ids.len() && ids[count as usize].as_bytes()[0] <= byte {
+                count += 1;
+            }
+            fanout.extend_from_slice(&count.to_be_bytes());
+        }
+
+        let mut commit_data = Vec::with_capacity(ids.len() * COMMIT_DATA_LEN);
+        let mut edges: Vec<u32> = Vec::new();
+        for ((commit, parents), generation) in self.commits.values().zip(&parent_positions).zip(&generations) {
+            commit_data.extend_from_slice(commit.tree.as_bytes());
+            let first = parents.first().copied().unwrap_or(PARENT_NONE);
+            let second = match parents.len() {
+                0 | 1 => PARENT_NONE,
+                2 => parents[1],
+                _ => {
+                    let start = edges.len() as u32;
+                    edges.extend_from_slice(&parents[1..]);
+                    if let Some(last) = edges.last_mut() {
+                        *last |= LAST_EDGE;
+                    }
+                    PARENT_EXTRA_EDGES | start
+                }
+            };
+            commit_data.extend_from_slice(&first.to_be_bytes());
+            commit_data.extend_from_slice(&second.to_be_bytes());
+            let time_high = ((commit.commit_time >> 32) & 0x3) as u32;
+            commit_data.extend_from_slice(&((generation << 2) | time_high).to_be_bytes());
+            commit_data.extend_from_slice(&(commit.commit_time as u32).to_be_bytes());
+        }
+
+        let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![
+            (CHUNK_OID_FANOUT, fanout),
+            (CHUNK_OID_LOOKUP, ids.iter().flat_map(|id| id.as_bytes().to_vec()).collect()),
+            (CHUNK_COMMIT_DATA, commit_data),
+        ];
+        if !edges.is_empty() {
+            chunks.push((CHUNK_EXTRA_EDGES, edges.iter().flat_map(|e| e.to_be_bytes()).collect()));
+        }
+
+        let mut out = HashingWriter { inner: out, hasher: Sha1::new() };
+        let write_err = |e: std::io::Error| format!("write failed: {}", e);
+
+        out.write_all(SIGNATURE).map_err(write_err)?;
+        out.write_all(&[VERSION, HASH_VERSION_SHA1, chunks.len() as u8, 0]).map_err(write_err)?;
+
+        // The table of contents ends with a terminating entry pointing past the last chunk
+        let mut offset = (HEADER_LEN + (chunks.len() + 1) * TOC_ENTRY_LEN) as u64;
+        for (id, data) in &chunks {
+            out.write_all(*id).map_err(write_err)?;
+            out.write_all(&offset.to_be_bytes()).map_err(write_err)?;
+            offset += data.len() as u64;
+        }
+        out.write_all(&[0; 4]).map_err(write_err)?;
+        out.write_all(&offset.to_be_bytes()).map_err(write_err)?;
+
+        for (_, data) in &chunks {
+            out.write_all(data).map_err(write_err)?;
+        }
+
+        let checksum = out.hasher.finalize();
+        out.inner.write_all(&checksum).map_err(write_err)?;
+        out.inner.flush().map_err(write_err)?;
+        Ok(ids.len())
+    }
+
+    /// Replace `<objects_dir>/info/commit-graph` with the collected commits
+    pub fn write(&self, objects_dir: &Path) -> Result<PathBuf> {
+        let path = objects_dir.join(COMMIT_GRAPH_FILE);
+        let info_dir = objects_dir.join("info");
+        std::fs::create_dir_all(&info_dir)
+            .map_err(|e| io_err(format!("Failed to create {}: {}", info_dir.display(), e), &info_dir))?;
+
+        // Write next to the final file and rename, so readers never see a partial graph
+        let temp = tempfile::NamedTempFile::new_in(&info_dir)
+            .map_err(|e| io_err(format!("Failed to create temporary commit-graph: {}", e), &info_dir))?;
+        self.write_to(std::io::BufWriter::new(temp.as_file()))
+            .map_err(|e| io_err(format!("Failed to write commit-graph: {}", e), temp.path()))?;
+        temp.persist(&path)
+            .map_err(|e| io_err(format!("Failed to install commit-graph: {}", e), &path))?;
+        Ok(path)
+    }
+}
+
+/// Summary of a `commit-graph write`
+#[derive(Debug, Clone)]
+pub struct CommitGraphStats {
+    /// Where the graph was written
+    pub path: PathBuf,
+    /// Number of commits in the graph
+    pub commits: usize,
+}
+
+/// Write a commit-graph covering every commit reachable from the refs and HEAD
+///
+/// Commits are parsed one at a time while walking, so memory use only grows
+/// with the number of commits, not their size.
+pub fn write_commit_graph(repo: &Repository) -> Result<CommitGraphStats> {
+    let repo_path = repo.path().to_path_buf();
+    if repo_path.join("shallow").exists() {
+        return Err(GitError::Repository(
+            "Cannot write a commit-graph for a shallow repository".to_string(),
+            Some(repo_path),
+        ));
+    }
+    if repo.object_hash() != gix_hash::Kind::Sha1 {
+        return Err(GitError::Repository(
+            format!("Cannot write a commit-graph for a {:?} repository, only SHA-1 is supported", repo.object_hash()),
+            Some(repo_path),
+        ));
+    }
+
+    let mut tips = Vec::new();
+    if let Ok(head) = repo.head_id() {
+        tips.push(head.detach());
+    }
+    let references = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    let all = references.all()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    for reference in all.filter_map(|reference| reference.ok()) {
+        let mut reference = reference;
+        if let Ok(id) = reference.peel_to_id_in_place() {
+            tips.push(id.detach());
+        }
+    }
+
+    let mut builder = CommitGraphBuilder::new();
+    let mut seen = HashSet::new();
+    let mut stack = tips;
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let object = repo.find_object(id)
+            .map_err(|e| repo_err(format!("Failed to find object {}: {}", id, e), &repo_path))?;
+        // Refs may point at trees or blobs; only commits go into the graph
+        if object.kind != gix::object::Kind::Commit {
+            continue;
+        }
+        let commit = object.into_commit();
+        let tree = commit.tree_id()
+            .map_err(|e| repo_err(format!("Invalid commit {}: {}", id, e), &repo_path))?
+            .detach();
+        let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+        let commit_time = commit.time()
+            .map_err(|e| repo_err(format!("Invalid commit {}: {}", id, e), &repo_path))?
+            .seconds
+            .max(0) as u64;
+
+        stack.extend(parents.iter().copied());
+        builder.add(id, tree, parents, commit_time);
+    }
+
+    let commits = builder.len();
+    let path = builder.write(&repo_path.join("objects"))?;
+    log::info!("Wrote commit-graph with {} commits to {}", commits, path.display());
+    Ok(CommitGraphStats { path, commits })
+}
+
+/// Compute generation numbers for commits given by parent positions
+fn compute_generations(parents: &[Vec<u32>]) -> Vec<u32> {
+    let mut generations = vec![0u32; parents.len()];
+    for start in 0..parents.len() {
+        if generations[start] != 0 {
+            continue;
+        }
+        // Iterative post-order walk; deep histories would overflow the stack
+        let mut stack = vec![start];
+        while let Some(&position) = stack.last() {
+            let pending: Vec<usize> = parents[position].iter()
+                .map(|p| *p as usize)
+                .filter(|p| generations[*p] == 0)
+                .collect();
+            if pending.is_empty() {
+                let highest = parents[position].iter()
+                    .map(|p| generations[*p as usize])
+                    .max()
+                    .unwrap_or(0);
+                generations[position] = (highest + 1).min(GENERATION_NUMBER_MAX);
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    generations
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Forwards writes while hashing them for the trailing checksum
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha1,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> ObjectId {
+        ObjectId::from([byte; 20])
+    }
+
+    #[test]
+    fn test_round_trip_with_octopus_merge() {
+        let tree = id(0xee);
+        let mut builder = CommitGraphBuilder::new();
+        builder.add(id(1), tree, vec![], 100);
+        builder.add(id(2), tree, vec![id(1)], 200);
+        builder.add(id(3), tree, vec![id(1)], 300);
+        builder.add(id(4), tree, vec![id(1)], 400);
+        builder.add(id(5), tree, vec![id(2), id(3), id(4)], (1u64 << 33) + 5);
+
+        let mut data = Vec::new();
+        assert_eq!(builder.write_to(&mut data).unwrap(), 5);
+        let graph = CommitGraph::from_bytes(&data).unwrap();
+
+        assert_eq!(graph.len(), 5);
+        let merge = graph.get(&id(5)).unwrap();
+        assert_eq!(merge.parents, vec![id(2), id(3), id(4)]);
+        assert_eq!(merge.generation, 3);
+        assert_eq!(merge.commit_time, (1u64 << 33) + 5);
+        assert_eq!(graph.generation(&id(1)), Some(1));
+        assert_eq!(graph.parents(&id(2)), Some(vec![id(1)]));
+
+        assert_eq!(graph.is_ancestor(&id(1), &id(5)), Some(true));
+        assert_eq!(graph.is_ancestor(&id(5), &id(1)), Some(false));
+        assert_eq!(graph.is_ancestor(&id(2), &id(3)), Some(false));
+        assert_eq!(graph.is_ancestor(&id(9), &id(5)), Some(false));
+        assert_eq!(graph.is_ancestor(&id(1), &id(9)), None);
+    }
+
+    #[test]
+    fn test_rejects_corruption_and_missing_parents() {
+        let mut builder = CommitGraphBuilder::new();
+        builder.add(id(1), id(0xee), vec![], 1);
+        let mut data = Vec::new();
+        builder.write_to(&mut data).unwrap();
+        data[HEADER_LEN + 40] ^= 0xff;
+        assert!(CommitGraph::from_bytes(&data).is_err());
+
+        builder.add(id(2), id(0xee), vec![id(3)], 2);
+        assert!(builder.write_to(Vec::new()).is_err());
+    }
+}
@@ -1,10 +1,14 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use arti_client::TorClientConfig;
+use crate::crypto::{AnonymousIdentity, KeyPair};
 use crate::ipfs::IpfsConfig;
 use crate::lfs::LfsConfig;
+use crate::transport::ExitPreferences;
 
 /// Configuration error type
 #[derive(Error, Debug)]
@@ -17,10 +21,67 @@ pub enum ConfigError {
     
     #[error("Invalid configuration: {0}")]
     Invalid(String),
+    
+    #[error("{}:{}:{}: {}{}", .path.display(), .line, .column,
+            .key.as_ref().map(|key| format!("`{}`: ", key)).unwrap_or_default(), .message)]
+    Parse {
+        /// File the error is in
+        path: PathBuf,
+        /// Line of the error, starting at 1
+        line: usize,
+        /// Column of the error, starting at 1
+        column: usize,
+        /// Dotted key the error is about, such as `tor.use_tor`, if known
+        key: Option<String>,
+        /// What is wrong
+        message: String,
+    },
 }
 
+/// Environment variables that override secrets, so they can stay out of the
+/// configuration file
+pub const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("ARTI_GIT_IPFS_API_TOKEN", "ipfs.api_token"),
+];
+
 /// ArtiGit configuration
+///
+/// Read from a TOML file, by default `~/.config/arti-git/config.toml`. Every
+/// key is optional; unknown keys are rejected so typos don't go unnoticed.
+///
+/// ```toml
+/// repo_dir = "/srv/git"
+///
+/// [tor]
+/// use_tor = true
+/// bootstrap = "eager"            # or "on-demand": connect on first use
+/// data_dir = "/var/lib/arti-git/tor"
+///
+/// [tor.onion_service]
+/// port = 9418
+/// key_dir = "/var/lib/arti-git/onion-keys"
+/// authorized_clients = ["descriptor:x25519:<base32>"]
+///
+/// [tor.onion_service.repo_policies."project.git"]
+/// allow_receive_pack = true
+///
+/// [ipfs]
+/// enabled = true
+/// api_endpoint = "http://127.0.0.1"
+/// api_port = 5001
+/// gateway_urls = ["https://ipfs.io"]
+/// pin_objects = true
+///
+/// [lfs]
+/// enabled = true
+/// url = "http://example.onion/repo.git/info/lfs"
+/// use_ipfs = false
+/// ```
+///
+/// Secrets such as the IPFS API token are best set through the variables in
+/// [`ENV_OVERRIDES`]; they are never written back to the file.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ArtiGitConfig {
     /// Path to local repositories
     #[serde(default = "default_repo_dir")]
@@ -41,15 +102,24 @@ pub struct ArtiGitConfig {
     /// LFS configuration
     #[serde(default)]
     pub lfs: LfsConfig,
+    
+    /// Remotes kept fresh by `mirror --watch`
+    #[serde(default)]
+    pub mirror: MirrorConfig,
 }
 
 /// Tor configuration settings
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TorConfig {
     /// Whether to use Tor for all connections
     #[serde(default = "default_use_tor")]
     pub use_tor: bool,
     
+    /// When to connect to the Tor network
+    #[serde(default)]
+    pub bootstrap: TorBootstrap,
+    
     /// Path to Tor data directory (defaults to ~/.arti-git/tor)
     #[serde(default = "default_tor_data_dir")]
     pub data_dir: PathBuf,
@@ -57,10 +127,77 @@ pub struct TorConfig {
     /// Onion service configuration for hosting repositories
     #[serde(default)]
     pub onion_service: Option<OnionServiceConfig>,
+    
+    /// Route clearnet Git URLs through Tor exit relays instead of connecting directly
+    #[serde(default)]
+    pub route_clearnet: bool,
+    
+    /// Timeout in seconds for connections made through exit relays
+    #[serde(default = "default_exit_connect_timeout")]
+    pub exit_connect_timeout: u64,
+    
+    /// Seconds to keep waiting for an onion service whose descriptor is not
+    /// published yet (0 fails immediately)
+    #[serde(default = "default_onion_descriptor_wait")]
+    pub onion_descriptor_wait: u64,
+    
+    /// Stream preferences for connections made through exit relays
+    #[serde(default)]
+    pub exit: ExitPreferences,
+    
+    /// Append a redacted log of connection attempts to this file, with
+    /// onion addresses hashed so it can be shared (off when unset)
+    #[serde(default)]
+    pub connection_log: Option<PathBuf>,
+    
+    /// How local paths appear in error messages: `full`, `relative` to the
+    /// working or home directory, or `hidden` (file names only)
+    #[serde(default)]
+    pub error_paths: crate::core::PathRedaction,
+    
+    /// Most `have` lines offered to a remote when fetching (0 for no limit);
+    /// fewer means less negotiation over slow circuits but possibly a
+    /// larger pack
+    #[serde(default = "default_negotiation_max_haves")]
+    pub negotiation_max_haves: usize,
+    
+    /// Most round trips the `have` lines are spread over when fetching (0
+    /// for no limit); more rounds let the remote stop the negotiation early,
+    /// but each one waits on the circuit
+    #[serde(default = "default_negotiation_max_rounds")]
+    pub negotiation_max_rounds: usize,
+    
+    /// JSON file recording the fingerprint each onion service presented on
+    /// first use; later connections must present the same one
+    #[serde(default = "default_known_hosts")]
+    pub known_hosts: PathBuf,
+    
+    /// Replace recorded fingerprints that changed instead of refusing to
+    /// connect (meant for a single run, through --accept-new-fingerprint)
+    #[serde(default)]
+    pub accept_new_fingerprint: bool,
+    
+    /// Ask smart HTTP remotes for gzip or deflate compressed responses;
+    /// compressed or chunked responses are decoded either way
+    #[serde(default = "default_http_compression")]
+    pub http_compression: bool,
+}
+
+/// When the Tor client connects to the network
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TorBootstrap {
+    /// Bootstrap before running any command
+    #[default]
+    Eager,
+    /// Bootstrap when the first connection needs Tor, so commands that stay
+    /// local start without waiting for the network
+    OnDemand,
 }
 
 /// Git configuration settings
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitConfig {
     /// Default remote settings
     #[serde(default)]
@@ -72,10 +209,20 @@ pub struct GitConfig {
     
     #[serde(default)]
     pub user_email: Option<String>,
+    
+    /// Seed file of the Ed25519 key of a pseudonymous identity, created on
+    /// first use. Tor connections are isolated per identity.
+    #[serde(default)]
+    pub identity_key: Option<PathBuf>,
+    
+    /// Onion address the pseudonymous identity's email is built from
+    #[serde(default)]
+    pub identity_onion: Option<String>,
 }
 
 /// Onion service configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct OnionServiceConfig {
     /// Port for the onion service
     #[serde(default = "default_onion_port")]
@@ -84,6 +231,224 @@ pub struct OnionServiceConfig {
     /// Directory for onion service keys
     #[serde(default = "default_key_dir")]
     pub key_dir: PathBuf,
+    
+    /// Reject pushes that are not signed with a trusted key (push-cert)
+    #[serde(default)]
+    pub require_signed_push: bool,
+    
+    /// Base64 Ed25519 public keys allowed to sign pushes
+    #[serde(default)]
+    pub trusted_push_keys: Vec<String>,
+    
+    /// Accept pushes signed by keys not listed in `trusted_push_keys`, such
+    /// as per-push ephemeral keys (trust-on-use, not identity)
+    #[serde(default)]
+    pub accept_ephemeral_push_keys: bool,
+    
+    /// Public keys of the clients allowed to connect, as
+    /// `descriptor:x25519:<base32>`. When set, only these clients can
+    /// reach the service; empty leaves it open to anyone with the address
+    #[serde(default)]
+    pub authorized_clients: Vec<String>,
+    
+    /// Repositories to serve, by their path under the served directory,
+    /// and what clients may do with each. Unlisted repositories are
+    /// reported as not found. When empty, every repository is served with
+    /// fetches and pushes allowed
+    #[serde(default)]
+    pub repo_policies: HashMap<String, RepoPolicy>,
+    
+    /// Most objects a single fetch may make the server enumerate (0 for no limit)
+    #[serde(default = "default_max_pack_objects")]
+    pub max_pack_objects: usize,
+    
+    /// Largest pack in bytes a single fetch may make the server build (0 for no limit)
+    #[serde(default = "default_max_pack_bytes")]
+    pub max_pack_bytes: u64,
+    
+    /// Check pack indexes and HEAD of every served repository at startup,
+    /// and refuse to serve repositories that fail
+    #[serde(default = "default_verify_on_start")]
+    pub verify_on_start: bool,
+    
+    /// Skip the startup check for repositories whose packs are larger than
+    /// this many bytes (0 checks every repository)
+    #[serde(default)]
+    pub verify_max_pack_bytes: u64,
+    
+    /// Also accept Git connections directly on this address, such as
+    /// `192.168.1.10:9418`, for clients on the local network. These
+    /// connections bypass Tor entirely; unset by default
+    #[serde(default)]
+    pub lan_bind: Option<SocketAddr>,
+    
+    /// Seconds between logged checks of the published descriptor's status
+    /// (0 turns the logging off; a failed publish is still detected)
+    #[serde(default = "default_descriptor_check_interval")]
+    pub descriptor_check_interval: u64,
+    
+    /// Restarts in a row the `--supervise` mode attempts before giving up
+    /// (0 restarts forever)
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    
+    /// Seconds to wait before the first restart; doubled after each
+    /// failed restart
+    #[serde(default = "default_restart_backoff")]
+    pub restart_backoff: u64,
+    
+    /// Longest wait between restarts, in seconds
+    #[serde(default = "default_max_restart_backoff")]
+    pub max_restart_backoff: u64,
+    
+    /// Further onion identities the repositories are also published under,
+    /// such as a new key while the old address stays reachable
+    #[serde(default)]
+    pub additional_identities: Vec<OnionIdentityConfig>,
+}
+
+/// Nickname of the onion service published with the key in `key_dir`
+pub const PRIMARY_ONION_NICKNAME: &str = "arti-git";
+
+/// What clients may do with a served repository
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoPolicy {
+    /// Allow clones and fetches (`git-upload-pack`)
+    #[serde(default = "default_allow_upload_pack")]
+    pub allow_upload_pack: bool,
+    
+    /// Allow pushes (`git-receive-pack`)
+    #[serde(default)]
+    pub allow_receive_pack: bool,
+}
+
+impl Default for RepoPolicy {
+    fn default() -> Self {
+        Self::read_only()
+    }
+}
+
+impl RepoPolicy {
+    /// Fetches only
+    pub fn read_only() -> Self {
+        Self { allow_upload_pack: true, allow_receive_pack: false }
+    }
+    
+    /// Fetches and pushes
+    pub fn read_write() -> Self {
+        Self { allow_upload_pack: true, allow_receive_pack: true }
+    }
+    
+    /// Check whether `service` (`git-upload-pack` or `git-receive-pack`) is allowed
+    pub fn allows(&self, service: &str) -> bool {
+        match service {
+            "git-upload-pack" => self.allow_upload_pack,
+            "git-receive-pack" => self.allow_receive_pack,
+            _ => false,
+        }
+    }
+}
+
+/// An additional onion identity of the Git service
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnionIdentityConfig {
+    /// Arti nickname of the service, unique among the identities
+    pub nickname: String,
+    
+    /// Secret key file (defaults to `<key_dir>/<nickname>/hs_ed25519_secret_key`)
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+}
+
+/// Remotes fetched on a schedule to keep mirrors up to date
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Seconds between fetches of a remote without its own interval
+    #[serde(default = "default_mirror_interval")]
+    pub interval: u64,
+    
+    /// Largest random shift of a fetch, as a fraction of the wait before it
+    #[serde(default = "default_mirror_jitter")]
+    pub jitter: f64,
+    
+    /// Longest wait in seconds before retrying a remote that keeps failing
+    #[serde(default = "default_mirror_max_backoff")]
+    pub max_backoff: u64,
+    
+    /// Seconds a single fetch may take before it is abandoned as failed
+    #[serde(default = "default_mirror_fetch_timeout")]
+    pub fetch_timeout: u64,
+    
+    /// The mirrored remotes
+    #[serde(default)]
+    pub remotes: Vec<MirrorRemoteConfig>,
+}
+
+/// A remote of a local repository kept up to date
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorRemoteConfig {
+    /// Repository the fetched refs are stored in
+    pub path: PathBuf,
+    
+    /// Remote to fetch
+    #[serde(default = "default_mirror_remote")]
+    pub remote: String,
+    
+    /// Seconds between fetches (defaults to the mirror interval)
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+impl OnionServiceConfig {
+    /// Nickname and key file of every identity to publish, the primary first
+    ///
+    /// Fails if two identities share a nickname or a key, which would
+    /// publish the same address twice.
+    pub fn identities(&self) -> Result<Vec<(String, PathBuf)>, ConfigError> {
+        let mut identities = vec![(
+            PRIMARY_ONION_NICKNAME.to_string(),
+            self.key_dir.join("hs_ed25519_secret_key"),
+        )];
+        for identity in &self.additional_identities {
+            if identity.nickname.is_empty() {
+                return Err(ConfigError::Invalid("Onion identity nickname must not be empty".to_string()));
+            }
+            let key_path = identity.key_path.clone()
+                .unwrap_or_else(|| self.key_dir.join(&identity.nickname).join("hs_ed25519_secret_key"));
+            if identities.iter().any(|(nickname, _)| *nickname == identity.nickname) {
+                return Err(ConfigError::Invalid(format!("Duplicate onion identity nickname '{}'", identity.nickname)));
+            }
+            if identities.iter().any(|(_, path)| *path == key_path) {
+                return Err(ConfigError::Invalid(format!(
+                    "Onion identity '{}' reuses the key {}", identity.nickname, key_path.display())));
+            }
+            identities.push((identity.nickname.clone(), key_path));
+        }
+        Ok(identities)
+    }
+}
+
+/// Dotted name of the key assigned on `line` (1-based), if the line assigns one
+fn key_at_line(content: &str, line: usize) -> Option<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let (name, _) = lines.get(line.checked_sub(1)?)?.split_once('=')?;
+    let name = name.trim().trim_matches('"');
+    
+    // The innermost table header above the line, `[a.b]` or `[[a.b]]`
+    let table = lines[..line - 1].iter().rev().find_map(|text| {
+        let text = text.trim();
+        text.strip_prefix('[')
+            .and_then(|text| text.strip_suffix(']'))
+            .map(|table| table.trim_matches(|c| c == '[' || c == ']').trim())
+    });
+    Some(match table {
+        Some(table) => format!("{}.{}", table, name),
+        None => name.to_string(),
+    })
 }
 
 // Default functions for serde
@@ -95,6 +460,30 @@ fn default_use_tor() -> bool {
     true
 }
 
+fn default_exit_connect_timeout() -> u64 {
+    120 // Exit circuits add noticeable latency to clearnet hosts
+}
+
+fn default_onion_descriptor_wait() -> u64 {
+    300 // Freshly started services can take minutes to appear on the HSDirs
+}
+
+fn default_http_compression() -> bool {
+    true
+}
+
+fn default_negotiation_max_haves() -> usize {
+    crate::protocol::DEFAULT_MAX_HAVES
+}
+
+fn default_negotiation_max_rounds() -> usize {
+    crate::protocol::DEFAULT_MAX_NEGOTIATION_ROUNDS
+}
+
+fn default_known_hosts() -> PathBuf {
+    crate::transport::KnownHosts::default_location()
+}
+
 fn default_tor_data_dir() -> PathBuf {
     let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("~/.local/share"));
     path.push("arti-git");
@@ -106,6 +495,58 @@ fn default_onion_port() -> u16 {
     9418 // Default Git port
 }
 
+fn default_max_pack_objects() -> usize {
+    5_000_000 // Well above most repositories, far below "enumerate everything" abuse
+}
+
+fn default_max_pack_bytes() -> u64 {
+    2 * 1024 * 1024 * 1024
+}
+
+fn default_verify_on_start() -> bool {
+    true
+}
+
+fn default_allow_upload_pack() -> bool {
+    true
+}
+
+fn default_descriptor_check_interval() -> u64 {
+    60
+}
+
+fn default_max_restarts() -> u32 {
+    10
+}
+
+fn default_restart_backoff() -> u64 {
+    5
+}
+
+fn default_max_restart_backoff() -> u64 {
+    600 // Descriptor publication can fail for a while when the network is down
+}
+
+fn default_mirror_interval() -> u64 {
+    3600
+}
+
+fn default_mirror_jitter() -> f64 {
+    0.2
+}
+
+fn default_mirror_max_backoff() -> u64 {
+    6 * 3600 // Onion services of small projects can be offline for hours
+}
+
+fn default_mirror_fetch_timeout() -> u64 {
+    1800
+}
+
+fn default_mirror_remote() -> String {
+    "origin".to_string()
+}
+
 fn default_key_dir() -> PathBuf {
     let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("~/.local/share"));
     path.push("arti-git");
@@ -121,6 +562,19 @@ impl Default for ArtiGitConfig {
             git: GitConfig::default(),
             ipfs: IpfsConfig::default(),
             lfs: LfsConfig::default(),
+            mirror: MirrorConfig::default(),
+        }
+    }
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_mirror_interval(),
+            jitter: default_mirror_jitter(),
+            max_backoff: default_mirror_max_backoff(),
+            fetch_timeout: default_mirror_fetch_timeout(),
+            remotes: Vec::new(),
         }
     }
 }
@@ -129,8 +583,20 @@ impl Default for TorConfig {
     fn default() -> Self {
         Self {
             use_tor: default_use_tor(),
+            bootstrap: TorBootstrap::default(),
             data_dir: default_tor_data_dir(),
             onion_service: None,
+            route_clearnet: false,
+            exit_connect_timeout: default_exit_connect_timeout(),
+            onion_descriptor_wait: default_onion_descriptor_wait(),
+            exit: ExitPreferences::default(),
+            connection_log: None,
+            error_paths: crate::core::PathRedaction::default(),
+            negotiation_max_haves: default_negotiation_max_haves(),
+            negotiation_max_rounds: default_negotiation_max_rounds(),
+            known_hosts: default_known_hosts(),
+            accept_new_fingerprint: false,
+            http_compression: default_http_compression(),
         }
     }
 }
@@ -141,15 +607,67 @@ impl Default for GitConfig {
             default_remote: None,
             user_name: None,
             user_email: None,
+            identity_key: None,
+            identity_onion: None,
         }
     }
 }
 
+impl GitConfig {
+    /// The pseudonymous identity configured by `identity_key`, if any
+    ///
+    /// A missing key file is created with a random seed, readable only by
+    /// the owner.
+    pub fn load_identity(&self) -> Result<Option<AnonymousIdentity>, ConfigError> {
+        let Some(key_path) = &self.identity_key else {
+            return Ok(None);
+        };
+        let onion = self.identity_onion.as_deref()
+            .ok_or_else(|| ConfigError::Invalid("git.identity_key needs git.identity_onion".to_string()))?;
+        let seed = match std::fs::read(key_path) {
+            Ok(seed) => seed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                use rand::RngCore;
+                let mut seed = vec![0u8; 32];
+                rand::rngs::OsRng.fill_bytes(&mut seed);
+                if let Some(parent) = key_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                std::io::Write::write_all(&mut options.open(key_path)?, &seed)?;
+                seed
+            },
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+        let key_pair = KeyPair::from_seed(&seed)
+            .map_err(|e| ConfigError::Invalid(format!("Invalid identity key {}: {}", key_path.display(), e)))?;
+        Ok(Some(AnonymousIdentity::new(key_pair, onion)))
+    }
+}
+
 impl Default for OnionServiceConfig {
     fn default() -> Self {
         Self {
             port: default_onion_port(),
             key_dir: default_key_dir(),
+            require_signed_push: false,
+            trusted_push_keys: Vec::new(),
+            accept_ephemeral_push_keys: false,
+            authorized_clients: Vec::new(),
+            repo_policies: HashMap::new(),
+            max_pack_objects: default_max_pack_objects(),
+            max_pack_bytes: default_max_pack_bytes(),
+            verify_on_start: default_verify_on_start(),
+            verify_max_pack_bytes: 0,
+            lan_bind: None,
+            descriptor_check_interval: default_descriptor_check_interval(),
+            max_restarts: default_max_restarts(),
+            restart_backoff: default_restart_backoff(),
+            max_restart_backoff: default_max_restart_backoff(),
+            additional_identities: Vec::new(),
         }
     }
 }
@@ -163,12 +681,8 @@ impl ArtiGitConfig {
         }
         
         // Try to read the file
-        match std::fs::read_to_string(path) {
-            Ok(content) => {
-                let config = toml::from_str(&content)
-                    .map_err(|e| ConfigError::Format(format!("Failed to parse config: {}", e)))?;
-                Ok(config)
-            }
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content, path)?,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 // File doesn't exist, create default config
                 let config = Self::default();
@@ -176,10 +690,47 @@ impl ArtiGitConfig {
                     .map_err(|e| ConfigError::Format(format!("Failed to serialize config: {}", e)))?;
                     
                 std::fs::write(path, toml)?;
-                Ok(config)
+                config
+            }
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+        
+        config.apply_env_overrides_from(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+    
+    /// Parse the TOML `content` of the configuration file at `path`
+    ///
+    /// Errors point at the line, column and dotted key of the problem.
+    pub fn parse(content: &str, path: &Path) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|e| {
+            let message = e.message().to_string();
+            match e.span() {
+                Some(span) => {
+                    let before = &content[..span.start.min(content.len())];
+                    let line = before.matches('\n').count() + 1;
+                    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+                    ConfigError::Parse { path: path.to_path_buf(), line, column, key: key_at_line(content, line), message }
+                },
+                None => ConfigError::Format(format!("Failed to parse {}: {}", path.display(), message)),
+            }
+        })
+    }
+    
+    /// Apply the [`ENV_OVERRIDES`] that `lookup` returns a value for
+    pub fn apply_env_overrides_from(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        for (variable, key) in ENV_OVERRIDES {
+            let value = match lookup(variable) {
+                Some(value) if !value.is_empty() => value,
+                _ => continue,
+            };
+            match *key {
+                "ipfs.api_token" => self.ipfs.api_token = Some(value),
+                _ => return Err(ConfigError::Invalid(format!("No override for {}", key))),
             }
-            Err(e) => Err(ConfigError::Io(e)),
+            log::debug!("Using {} from ${}", key, variable);
         }
+        Ok(())
     }
     
     /// Get the default configuration location
@@ -205,6 +756,53 @@ impl ArtiGitConfig {
         Ok(())
     }
     
+    /// Apply a `key=value` override, as given to `-c/--config`
+    ///
+    /// The key is a dotted path into the configuration (`tor.use_tor`). The
+    /// value is read as a TOML value, falling back to a plain string, and
+    /// must have the type the configuration file would need.
+    pub fn apply_override(&mut self, spec: &str) -> Result<(), ConfigError> {
+        let (key, raw) = spec.split_once('=')
+            .ok_or_else(|| ConfigError::Invalid(format!("Expected key=value, got '{}'", spec)))?;
+        let path: Vec<&str> = key.trim().split('.').collect();
+        if path.iter().any(|part| part.is_empty()) {
+            return Err(ConfigError::Invalid(format!("Invalid configuration key '{}'", key)));
+        }
+        let value = match toml::from_str::<toml::Table>(&format!("value = {}", raw)) {
+            Ok(mut table) => table.remove("value").unwrap_or_else(|| toml::Value::String(raw.to_string())),
+            Err(_) => toml::Value::String(raw.to_string()),
+        };
+        
+        let mut root = toml::Value::try_from(&*self)
+            .map_err(|e| ConfigError::Format(format!("Failed to serialize config: {}", e)))?;
+        let (leaf, parents) = path.split_last().expect("the key has at least one part");
+        let mut table = &mut root;
+        for part in parents {
+            table = table.as_table_mut()
+                .and_then(|table| table.get_mut(*part))
+                .filter(|value| value.is_table())
+                .ok_or_else(|| ConfigError::Invalid(format!("Unknown configuration key '{}'", key)))?;
+        }
+        table.as_table_mut()
+            .ok_or_else(|| ConfigError::Invalid(format!("Unknown configuration key '{}'", key)))?
+            .insert(leaf.to_string(), value);
+        
+        let updated: Self = root.try_into()
+            .map_err(|e| ConfigError::Invalid(format!("Invalid value for {}: {}", key, e)))?;
+        
+        // Unknown fields are dropped when deserializing, so a key that does
+        // not survive the round trip was misspelled
+        let check = toml::Value::try_from(&updated)
+            .map_err(|e| ConfigError::Format(format!("Failed to serialize config: {}", e)))?;
+        let known = path.iter().try_fold(&check, |value, part| value.get(*part)).is_some();
+        if !known {
+            return Err(ConfigError::Invalid(format!("Unknown configuration key '{}'", key)));
+        }
+        
+        *self = updated;
+        Ok(())
+    }
+    
     /// Convert our TorConfig to Arti's TorClientConfig
     pub fn to_arti_config(&self) -> Result<TorClientConfig, ConfigError> {
         // Start with a default configuration
@@ -216,4 +814,94 @@ impl ArtiGitConfig {
         // Return the configured Arti config
         Ok(arti_config)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Identity;
+
+    #[test]
+    fn test_load_identity_creates_and_reuses_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut git = GitConfig::default();
+        assert!(git.load_identity().unwrap().is_none());
+
+        git.identity_key = Some(dir.path().join("keys").join("identity"));
+        assert!(git.load_identity().is_err(), "an identity needs an onion address");
+
+        git.identity_onion = Some("example.onion".to_string());
+        let first = git.load_identity().unwrap().unwrap();
+        let second = git.load_identity().unwrap().unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.email(), "example@anonymous.onion");
+    }
+
+    #[test]
+    fn test_apply_override() {
+        let mut config = ArtiGitConfig::default();
+        config.apply_override("tor.use_tor=false").unwrap();
+        assert!(!config.tor.use_tor);
+        config.apply_override("repo_dir=/srv/git").unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/git"));
+
+        // Values are checked like the config file would be
+        assert!(config.apply_override("tor.use_tor=yes").is_err());
+        assert!(config.apply_override("tor.no_such_key=1").is_err());
+        assert!(config.apply_override("nosuchsection.key=1").is_err());
+        assert!(config.apply_override("tor.use_tor").is_err());
+        assert!(!config.tor.use_tor);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_key() {
+        let path = Path::new("config.toml");
+        let config = ArtiGitConfig::parse("[tor]\nuse_tor = false\nbootstrap = \"on-demand\"\n", path).unwrap();
+        assert!(!config.tor.use_tor);
+        assert_eq!(config.tor.bootstrap, TorBootstrap::OnDemand);
+
+        match ArtiGitConfig::parse("repo_dir = \"/srv\"\n\n[tor]\nuse_tor = \"yes\"\n", path).unwrap_err() {
+            ConfigError::Parse { line, key, .. } => {
+                assert_eq!(line, 4);
+                assert_eq!(key.as_deref(), Some("tor.use_tor"));
+            },
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        let err = ArtiGitConfig::parse("[tor.onion_service]\nprot = 9418\n", path).unwrap_err();
+        assert!(err.to_string().starts_with("config.toml:2:1: `tor.onion_service.prot`:"), "{}", err);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = ArtiGitConfig::default();
+        config.apply_env_overrides_from(|name| match name {
+            "ARTI_GIT_IPFS_API_TOKEN" => Some("secret".to_string()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.ipfs.api_token.as_deref(), Some("secret"));
+
+        // Secrets from the environment are not written back to the file
+        let saved = toml::to_string_pretty(&config).unwrap();
+        assert!(!saved.contains("secret"));
+    }
+
+    #[test]
+    fn test_onion_identities() {
+        let mut config = OnionServiceConfig { key_dir: PathBuf::from("/keys"), ..OnionServiceConfig::default() };
+        config.additional_identities.push(OnionIdentityConfig { nickname: "rotated".to_string(), key_path: None });
+        let identities = config.identities().unwrap();
+        assert_eq!(identities, vec![
+            (PRIMARY_ONION_NICKNAME.to_string(), PathBuf::from("/keys/hs_ed25519_secret_key")),
+            ("rotated".to_string(), PathBuf::from("/keys/rotated/hs_ed25519_secret_key")),
+        ]);
+
+        config.additional_identities.push(OnionIdentityConfig {
+            nickname: "copy".to_string(),
+            key_path: Some(PathBuf::from("/keys/hs_ed25519_secret_key")),
+        });
+        assert!(config.identities().is_err());
+        config.additional_identities[1] = OnionIdentityConfig { nickname: "rotated".to_string(), key_path: None };
+        assert!(config.identities().is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use std::fmt;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
 
 /// Result type used throughout the application
 pub type Result<T> = std::result::Result<T, GitError>;
@@ -40,14 +43,26 @@ pub enum GitError {
     MergeConflict(Vec<String>),
     /// General merge failure
     MergeFailure(String),
+    /// The operation was cancelled by the caller
+    Cancelled(String),
+    /// A commit would record the same tree as HEAD
+    NothingToCommit(String),
+    /// Another operation holds the lock file at this path
+    Locked(PathBuf),
+    /// A patch doesn't apply; one `<path>: <reason>` per failed file
+    PatchConflict(Vec<String>),
+    /// A host presented another fingerprint than the one trusted for it:
+    /// host, expected fingerprint, presented fingerprint
+    FingerprintMismatch(String, String, String),
 }
 
-impl fmt::Display for GitError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl GitError {
+    /// Write the message, rendering paths with `show_path`
+    fn write_message(&self, f: &mut impl fmt::Write, show_path: &dyn Fn(&Path) -> String) -> fmt::Result {
         match self {
-            GitError::IO(msg, Some(path)) => write!(f, "IO error for path '{}': {}", path.display(), msg),
+            GitError::IO(msg, Some(path)) => write!(f, "IO error for path '{}': {}", show_path(path), msg),
             GitError::IO(msg, None) => write!(f, "IO error: {}", msg),
-            GitError::Repository(msg, Some(path)) => write!(f, "Repository error for '{}': {}", path.display(), msg),
+            GitError::Repository(msg, Some(path)) => write!(f, "Repository error for '{}': {}", show_path(path), msg),
             GitError::Repository(msg, None) => write!(f, "Repository error: {}", msg),
             GitError::InvalidObjectId(msg) => write!(f, "Invalid object ID: {}", msg),
             GitError::Transport(msg, Some(url)) => write!(f, "Transport error for URL '{}': {}", url, msg),
@@ -64,6 +79,37 @@ impl fmt::Display for GitError {
             GitError::PackGeneration(msg) => write!(f, "Packfile generation error: {}", msg),
             GitError::MergeConflict(paths) => write!(f, "Merge conflict in files: {}", paths.join(", ")),
             GitError::MergeFailure(msg) => write!(f, "Merge failed: {}", msg),
+            GitError::Cancelled(msg) => write!(f, "Cancelled: {}", msg),
+            GitError::NothingToCommit(msg) => write!(f, "Nothing to commit: {}", msg),
+            GitError::Locked(path) => write!(f,
+                "Another operation is in progress: '{}' exists. If no other process is running, remove the file and retry",
+                show_path(path)),
+            GitError::PatchConflict(problems) => write!(f, "Patch does not apply:\n  {}", problems.join("\n  ")),
+            GitError::FingerprintMismatch(host, expected, presented) => write!(f,
+                "Fingerprint of {} changed (expected {}, got {}). Someone may be intercepting the connection; \
+                 if the service changed its keys, retry with --accept-new-fingerprint",
+                host, expected, presented),
+        }
+    }
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = path_redaction();
+        if mode == PathRedaction::Full {
+            return self.write_message(f, &|path| path.display().to_string());
+        }
+        
+        if log::log_enabled!(log::Level::Debug) {
+            let mut full = String::new();
+            self.write_message(&mut full, &|path| path.display().to_string())?;
+            log::debug!("Unredacted error: {}", full);
+        }
+        let mut message = String::new();
+        self.write_message(&mut message, &|path| redact_path(path, mode))?;
+        match mode {
+            PathRedaction::Hidden => f.write_str(&hide_absolute_paths(&message)),
+            _ => f.write_str(&redact_local_paths(&message)),
         }
     }
 }
@@ -77,6 +123,161 @@ impl From<std::io::Error> for GitError {
     }
 }
 
+/// How local paths appear in error messages
+///
+/// Paths can reveal the user's name through their home directory, which
+/// matters when errors from an anonymity tool end up in shared logs or bug
+/// reports. The unredacted message is still logged at debug level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathRedaction {
+    /// Show paths as they are
+    #[default]
+    Full,
+    /// Show paths relative to the working directory, or to `~`
+    Relative,
+    /// Show only the last component of each path
+    Hidden,
+}
+
+impl std::str::FromStr for PathRedaction {
+    type Err = GitError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(PathRedaction::Full),
+            "relative" => Ok(PathRedaction::Relative),
+            "hidden" => Ok(PathRedaction::Hidden),
+            _ => Err(GitError::InvalidArgument(format!(
+                "Unknown path redaction '{}' (expected full, relative or hidden)", s
+            ))),
+        }
+    }
+}
+
+static PATH_REDACTION: AtomicU8 = AtomicU8::new(0);
+
+/// Set how paths appear in error messages, for the whole process
+pub fn set_path_redaction(mode: PathRedaction) {
+    PATH_REDACTION.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Get how paths currently appear in error messages
+pub fn path_redaction() -> PathRedaction {
+    match PATH_REDACTION.load(Ordering::Relaxed) {
+        1 => PathRedaction::Relative,
+        2 => PathRedaction::Hidden,
+        _ => PathRedaction::Full,
+    }
+}
+
+/// Directories whose location gives the user away, most specific first
+fn local_prefixes() -> Vec<(PathBuf, &'static str)> {
+    let mut prefixes = Vec::new();
+    // Relativizing against `/` would turn every path into a relative one
+    let meaningful = |dir: &Path| dir.components().count() > 1;
+    if let Ok(cwd) = std::env::current_dir() {
+        if meaningful(&cwd) {
+            prefixes.push((cwd, "."));
+        }
+    }
+    if let Some(home) = dirs::home_dir() {
+        if meaningful(&home) {
+            prefixes.push((home, "~"));
+        }
+    }
+    // From `/home`, the working directory would show the user name in
+    // `./alice/repo`; the home directory has to win there
+    prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.components().count()));
+    prefixes
+}
+
+/// Render a path under the given redaction mode
+pub fn redact_path(path: &Path, mode: PathRedaction) -> String {
+    match mode {
+        PathRedaction::Full => path.display().to_string(),
+        PathRedaction::Relative => {
+            for (prefix, replacement) in local_prefixes() {
+                if let Ok(rest) = path.strip_prefix(&prefix) {
+                    return if rest.as_os_str().is_empty() {
+                        replacement.to_string()
+                    } else {
+                        format!("{}/{}", replacement, rest.display())
+                    };
+                }
+            }
+            path.display().to_string()
+        },
+        PathRedaction::Hidden => {
+            // The last component of the home directory is the user name
+            if let Some((_, replacement)) = local_prefixes().into_iter().find(|(prefix, _)| prefix == path) {
+                return replacement.to_string();
+            }
+            match path.file_name() {
+                Some(name) => format!("<redacted>/{}", name.to_string_lossy()),
+                None => "<redacted>".to_string(),
+            }
+        },
+    }
+}
+
+/// Replace the working and home directories wherever they appear in `text`
+///
+/// Catches paths that were formatted into a message rather than passed as
+/// its path.
+fn redact_local_paths(text: &str) -> String {
+    let mut redacted = text.to_string();
+    for (prefix, replacement) in local_prefixes() {
+        let prefix = match prefix.to_str() {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        let mut result = String::with_capacity(redacted.len());
+        let mut rest = redacted.as_str();
+        while let Some(pos) = rest.find(prefix) {
+            let after = &rest[pos + prefix.len()..];
+            // `/home/al` must not match inside `/home/alice`
+            let at_boundary = after.chars().next()
+                .map_or(true, |c| c == '/' || !(c.is_alphanumeric() || c == '.' || c == '_' || c == '-'));
+            result.push_str(&rest[..pos]);
+            result.push_str(if at_boundary { replacement } else { prefix });
+            rest = after;
+        }
+        result.push_str(rest);
+        redacted = result;
+    }
+    redacted
+}
+
+/// Hide every absolute path formatted into `text`, keeping its last component
+///
+/// Hidden mode can't stop at the working and home directories: any other
+/// path (a served repository, a mount point) can identify the user too.
+/// A path starts with `/` at the beginning of a word or after a quote or
+/// bracket, and runs until whitespace, a quote or a closing bracket.
+fn hide_absolute_paths(text: &str) -> String {
+    let starts_word = |c: char| c.is_whitespace() || matches!(c, '\'' | '"' | '(' | '[' | '=' | '`');
+    let ends_path = |c: char| c.is_whitespace() || matches!(c, '\'' | '"' | ')' | ']' | ',' | ';' | '`');
+    let mut hidden = String::with_capacity(text.len());
+    let mut previous = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '/' && previous.map_or(true, starts_word) {
+            let end = rest.find(ends_path).unwrap_or(rest.len());
+            // Punctuation that ends a sentence or precedes the message isn't part of the path
+            let path = rest[..end].trim_end_matches(|c| matches!(c, ':' | '.'));
+            hidden.push_str(&redact_path(Path::new(path), PathRedaction::Hidden));
+            rest = &rest[path.len()..];
+            previous = path.chars().last();
+            continue;
+        }
+        hidden.push(c);
+        previous = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    hidden
+}
+
 /// Create an IO error with path context
 pub fn io_err(err: impl ToString, path: impl Into<PathBuf>) -> GitError {
     GitError::IO(err.to_string(), Some(path.into()))
@@ -110,4 +311,40 @@ impl From<arti_client::Error> for GitError {
     fn from(err: arti_client::Error) -> Self {
         GitError::Transport(format!("Arti client error: {}", err), None)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_paths_hide_the_home_directory() {
+        let home = match dirs::home_dir() {
+            Some(home) if home.components().count() > 1 => home,
+            _ => return,
+        };
+        let file = home.join("secret-project/file.txt");
+
+        assert_eq!(redact_path(&file, PathRedaction::Hidden), "<redacted>/file.txt");
+        assert_eq!(redact_path(&home, PathRedaction::Hidden), "~");
+        assert!(!redact_path(&file, PathRedaction::Relative).contains(&*home.to_string_lossy()));
+
+        let message = format!("Failed to read {}", file.display());
+        let redacted = redact_local_paths(&message);
+        assert!(!redacted.contains(&*home.to_string_lossy()));
+        assert!(redacted.ends_with("/secret-project/file.txt"));
+    }
+
+    #[test]
+    fn test_hidden_mode_hides_every_absolute_path() {
+        let message = "Failed to open '/srv/git/alice-private/repo.git': denied (copying /mnt/backup/alice/x.pack: no space)";
+        assert_eq!(
+            hide_absolute_paths(message),
+            "Failed to open '<redacted>/repo.git': denied (copying <redacted>/x.pack: no space)",
+        );
+        // URLs and ref names are not local paths
+        let message = "Fetching refs/heads/main from https://example.com/repo.git";
+        assert_eq!(hide_absolute_paths(message), message);
+        assert_eq!(hide_absolute_paths("Not found: /"), "Not found: <redacted>");
+    }
+}
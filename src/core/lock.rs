@@ -0,0 +1,160 @@
+//! Lock files guarding the index and refs against concurrent writers
+//!
+//! Works like git: a writer creates `<file>.lock` exclusively, writes the
+//! new content into it and renames it over the original. Whoever finds the
+//! lock file already present gets [`GitError::Locked`] instead of waiting,
+//! so two operations (or an operation and the onion service) never
+//! interleave their writes.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+use gix::refs::transaction::RefEdit;
+
+use crate::core::{GitError, Result, io_err, repo_err};
+
+/// Suffix git appends to a file name for its lock file
+pub const LOCK_SUFFIX: &str = ".lock";
+
+/// An exclusively held `<path>.lock`
+///
+/// Dropping the lock without [`LockFile::commit`] removes the lock file and
+/// leaves the original untouched.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+
+impl LockFile {
+    /// Take the lock for `path`
+    ///
+    /// Fails with [`GitError::Locked`] if another operation holds it.
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let lock_path = lock_path(&path);
+        if let Some(parent) = lock_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| io_err(format!("Failed to create lock directory: {}", e), parent))?;
+        }
+        let file = match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(GitError::Locked(lock_path)),
+            Err(e) => return Err(io_err(format!("Failed to create lock file: {}", e), &lock_path)),
+        };
+        log::debug!("Locked {}", path.display());
+        Ok(Self { path, lock_path, file: Some(file) })
+    }
+
+    /// Path of the file this lock protects
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The lock file, which receives the new content
+    pub fn file_mut(&mut self) -> &mut File {
+        self.file.as_mut().expect("lock file is open until committed")
+    }
+
+    /// Replace the protected file with what was written to the lock
+    pub fn commit(mut self) -> Result<()> {
+        let mut file = self.file.take().expect("lock file is open until committed");
+        file.flush()
+            .and_then(|_| file.sync_all())
+            .map_err(|e| io_err(format!("Failed to write lock file: {}", e), &self.lock_path))?;
+        drop(file);
+        std::fs::rename(&self.lock_path, &self.path)
+            .map_err(|e| io_err(format!("Failed to replace file with its lock: {}", e), &self.path))?;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Still open means not committed; give up the lock
+        if self.file.take().is_some() {
+            if let Err(e) = std::fs::remove_file(&self.lock_path) {
+                log::warn!("Failed to remove lock file {}: {}", self.lock_path.display(), e);
+            }
+        }
+    }
+}
+
+/// The lock file path git uses for `path`
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(LOCK_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Take `index.lock` of a repository
+///
+/// Take it before reading the index, so no other writer's changes are lost
+/// between reading and writing.
+pub fn lock_index(repo: &Repository) -> Result<LockFile> {
+    LockFile::acquire(repo.index_path())
+}
+
+/// Write an index through its lock and release the lock
+pub fn write_index(mut lock: LockFile, index: &gix::index::File) -> Result<()> {
+    index.write_to(lock.file_mut(), gix::index::write::Options::default())
+        .map_err(|e| repo_err(format!("Failed to write index: {}", e), lock.path()))?;
+    lock.commit()
+}
+
+/// Apply ref edits, reporting a held ref lock as [`GitError::Locked`]
+///
+/// gitoxide locks each ref (and `packed-refs`) itself and fails at once if
+/// a lock is held; this turns that failure into the same error the index
+/// lock gives.
+pub fn edit_references(repo: &Repository, edits: impl IntoIterator<Item = RefEdit>) -> Result<Vec<RefEdit>> {
+    use gix::reference::edit::Error as EditError;
+    use gix::refs::file::transaction::prepare::Error as PrepareError;
+
+    let edits: Vec<RefEdit> = edits.into_iter().collect();
+    match repo.edit_references(edits.clone()) {
+        Ok(applied) => Ok(applied),
+        Err(EditError::FileTransactionPrepare(PrepareError::LockAcquire { full_name, .. })) => {
+            Err(GitError::Locked(lock_path(&repo.common_dir().join(full_name.as_bstr().to_string()))))
+        },
+        Err(EditError::FileTransactionPrepare(PrepareError::PackedTransactionAcquire(_))) => {
+            Err(GitError::Locked(lock_path(&repo.common_dir().join("packed-refs"))))
+        },
+        Err(e) => {
+            let names: Vec<String> = edits.iter().map(|edit| edit.name.as_bstr().to_string()).collect();
+            Err(repo_err(format!("Failed to update {}: {}", names.join(", "), e), repo.path()))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_writer_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        std::fs::write(&path, "old").unwrap();
+
+        let mut lock = LockFile::acquire(&path).unwrap();
+        match LockFile::acquire(&path) {
+            Err(GitError::Locked(held)) => assert_eq!(held, dir.path().join("index.lock")),
+            other => panic!("expected a lock error, got {:?}", other),
+        }
+        lock.file_mut().write_all(b"new").unwrap();
+        lock.commit().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.path().join("index.lock").exists());
+
+        // An abandoned lock leaves the file alone and frees the lock
+        let mut lock = LockFile::acquire(&path).unwrap();
+        lock.file_mut().write_all(b"discarded").unwrap();
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        LockFile::acquire(&path).unwrap();
+    }
+}
@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+
+use gix::Repository;
+use gix::index::entry::Mode;
+use gix_hash::ObjectId;
+
+use crate::core::{FileStatus, GitError, Result, repo_err};
+use crate::core::attributes::{AttributeValue, PathAttributes, attributes_for};
+
+/// Attribute naming the merge driver for a path
+pub const MERGE_ATTRIBUTE: &str = "merge";
+
+/// Driver used when no `merge` attribute applies
+pub const DEFAULT_MERGE_DRIVER: &str = "text";
+
+/// The three versions of a file taking part in a merge
+#[derive(Debug, Clone, Copy)]
+pub struct MergeInput<'a> {
+    /// Worktree-relative path of the file
+    pub path: &'a str,
+    /// Content in the merge base, `None` if both sides added the file
+    pub base: Option<&'a [u8]>,
+    /// Content on the current branch
+    pub ours: &'a [u8],
+    /// Content on the branch being merged in
+    pub theirs: &'a [u8],
+}
+
+/// Result of merging one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOutcome {
+    /// Merged content, with conflict markers if `conflicted` is set
+    pub content: Vec<u8>,
+    /// Whether the file still needs manual resolution
+    pub conflicted: bool,
+}
+
+impl MergeOutcome {
+    /// A clean merge result
+    pub fn clean(content: Vec<u8>) -> Self {
+        Self { content, conflicted: false }
+    }
+
+    /// A result that needs manual resolution
+    pub fn conflict(content: Vec<u8>) -> Self {
+        Self { content, conflicted: true }
+    }
+}
+
+/// Merges the three versions of a file
+///
+/// Drivers are selected per path through the `merge=<driver>` attribute in
+/// `.gitattributes` and registered with [`MergeDrivers::register`].
+pub trait MergeDriver: Send + Sync {
+    /// Merge `input.ours` and `input.theirs` against `input.base`
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome>;
+}
+
+impl<F> MergeDriver for F
+where
+    F: Fn(&MergeInput<'_>) -> Result<MergeOutcome> + Send + Sync,
+{
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        self(input)
+    }
+}
+
+/// How overlapping changes from both sides are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictStyle {
+    /// Write both sides between conflict markers
+    Markers,
+    /// Write our lines followed by theirs, without markers
+    Union,
+}
+
+/// Line-based three-way merge; the built-in `text` driver
+///
+/// Binary content can't be merged line by line and is handled like the
+/// `binary` driver.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextDriver;
+
+impl MergeDriver for TextDriver {
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        if is_binary(input) {
+            return BinaryDriver.merge(input);
+        }
+        Ok(merge_lines(input, ConflictStyle::Markers))
+    }
+}
+
+/// Keeps lines from both sides of a conflict; the built-in `union` driver
+///
+/// Useful for files like changelogs where both additions should survive.
+/// The result never has conflict markers, so it needs review.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnionDriver;
+
+impl MergeDriver for UnionDriver {
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        if is_binary(input) {
+            return BinaryDriver.merge(input);
+        }
+        Ok(merge_lines(input, ConflictStyle::Union))
+    }
+}
+
+/// Keeps our version and reports a conflict unless only one side changed;
+/// the built-in `binary` driver
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryDriver;
+
+impl MergeDriver for BinaryDriver {
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        if let Some(content) = trivial_merge(input) {
+            return Ok(MergeOutcome::clean(content.to_vec()));
+        }
+        Ok(MergeOutcome::conflict(input.ours.to_vec()))
+    }
+}
+
+/// Always resolves to our version; the built-in `ours` driver
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OursDriver;
+
+impl MergeDriver for OursDriver {
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        Ok(MergeOutcome::clean(input.ours.to_vec()))
+    }
+}
+
+/// Always resolves to their version; the built-in `theirs` driver
+///
+/// Meant for generated files that are rebuilt after the merge anyway.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TheirsDriver;
+
+impl MergeDriver for TheirsDriver {
+    fn merge(&self, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        Ok(MergeOutcome::clean(input.theirs.to_vec()))
+    }
+}
+
+/// Registry of merge drivers by name
+///
+/// Starts out with the built-in `text`, `binary`, `union`, `ours` and
+/// `theirs` drivers. Cloning is cheap; drivers are shared.
+#[derive(Clone)]
+pub struct MergeDrivers {
+    drivers: HashMap<String, Arc<dyn MergeDriver>>,
+}
+
+impl MergeDrivers {
+    /// Create a registry holding the built-in drivers
+    pub fn new() -> Self {
+        let mut drivers = Self { drivers: HashMap::new() };
+        drivers.register("text", TextDriver);
+        drivers.register("binary", BinaryDriver);
+        drivers.register("union", UnionDriver);
+        drivers.register("ours", OursDriver);
+        drivers.register("theirs", TheirsDriver);
+        drivers
+    }
+
+    /// Register a driver under `name`, replacing any driver of that name
+    pub fn register(&mut self, name: &str, driver: impl MergeDriver + 'static) {
+        self.drivers.insert(name.to_string(), Arc::new(driver));
+    }
+
+    /// Get the driver registered under `name`
+    pub fn get(&self, name: &str) -> Option<Arc<dyn MergeDriver>> {
+        self.drivers.get(name).cloned()
+    }
+
+    /// Pick the driver for a path from its `merge` attribute
+    ///
+    /// `merge` selects `text` and `-merge` selects `binary`. Drivers that
+    /// aren't registered fall back to `text`, as git does for drivers missing
+    /// from its config.
+    pub fn driver_for(&self, attributes: &PathAttributes) -> Arc<dyn MergeDriver> {
+        let name = match attributes.get(MERGE_ATTRIBUTE) {
+            AttributeValue::Value(name) => name,
+            AttributeValue::Unset => "binary".to_string(),
+            AttributeValue::Set | AttributeValue::Unspecified => DEFAULT_MERGE_DRIVER.to_string(),
+        };
+
+        match self.get(&name) {
+            Some(driver) => driver,
+            None => {
+                log::warn!("Merge driver '{}' for {} is not registered, using '{}'",
+                    name, attributes.path(), DEFAULT_MERGE_DRIVER);
+                Arc::new(TextDriver)
+            }
+        }
+    }
+
+    /// Merge one file with the driver its attributes select
+    ///
+    /// `attributes` should come from [`crate::core::attributes_for`] for
+    /// `input.path`.
+    pub fn merge(&self, attributes: &PathAttributes, input: &MergeInput<'_>) -> Result<MergeOutcome> {
+        // Identical sides never need a driver
+        if let Some(content) = trivial_merge(input) {
+            return Ok(MergeOutcome::clean(content.to_vec()));
+        }
+        self.driver_for(attributes).merge(input)
+    }
+}
+
+impl Default for MergeDrivers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Result of merging two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeMerge {
+    /// The merged tree
+    pub tree: ObjectId,
+    /// Paths that still need manual resolution
+    pub conflicts: Vec<String>,
+}
+
+/// What merging a commit into HEAD did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadMerge {
+    /// HEAD already contained the commit
+    UpToDate,
+    /// HEAD was behind and moved forward to the commit
+    FastForward(ObjectId),
+    /// A merge commit was created
+    Merged(ObjectId),
+}
+
+/// Three-way merge of the trees `ours` and `theirs` against `base`
+///
+/// A path changed on one side only takes that side's version. Files changed
+/// on both sides are merged by the driver their `merge` attribute selects
+/// from `drivers`. Anything else changed on both sides, like a file deleted
+/// on one side and edited on the other, is a conflict and keeps our version.
+pub fn merge_trees(
+    repo: &Repository,
+    base: Option<ObjectId>,
+    ours: ObjectId,
+    theirs: ObjectId,
+    drivers: &MergeDrivers,
+) -> Result<TreeMerge> {
+    let base = match base {
+        Some(tree) => tree_entries(repo, tree)?,
+        None => BTreeMap::new(),
+    };
+    let ours = tree_entries(repo, ours)?;
+    let theirs = tree_entries(repo, theirs)?;
+    let paths: BTreeSet<&String> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+
+    let mut merged = gix::index::State::new(repo.object_hash());
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (base_entry, our_entry, their_entry) = (base.get(path), ours.get(path), theirs.get(path));
+        let entry = if our_entry == their_entry || base_entry == their_entry {
+            our_entry.copied()
+        } else if base_entry == our_entry {
+            their_entry.copied()
+        } else {
+            match (our_entry, their_entry) {
+                (Some(&(our_mode, our_id)), Some(&(their_mode, their_id))) if is_file(our_mode) && is_file(their_mode) => {
+                    let base_data = match base_entry {
+                        Some(&(mode, id)) if is_file(mode) => Some(read_blob(repo, id)?),
+                        _ => None,
+                    };
+                    let (our_data, their_data) = (read_blob(repo, our_id)?, read_blob(repo, their_id)?);
+                    let input = MergeInput { path, base: base_data.as_deref(), ours: &our_data, theirs: &their_data };
+                    let outcome = drivers.merge(&attributes_for(repo, path)?, &input)?;
+                    if outcome.conflicted {
+                        conflicts.push(path.clone());
+                    }
+                    let id = repo.write_blob(&outcome.content)
+                        .map_err(|e| GitError::ObjectStorage(format!("Failed to write merged {}: {}", path, e)))?;
+                    Some((our_mode, id.detach()))
+                },
+                _ => {
+                    conflicts.push(path.clone());
+                    our_entry.or(their_entry).copied()
+                },
+            }
+        };
+        if let Some((mode, id)) = entry {
+            merged.dangerously_push_entry(Default::default(), id, gix::index::entry::Flags::empty(), mode, path.as_str().into());
+        }
+    }
+    merged.sort_entries();
+
+    let tree = gix::index::File::from_state(merged, repo.index_path())
+        .write_tree_to(&repo.objects)
+        .map_err(|e| GitError::ObjectStorage(format!("Failed to write merged tree: {}", e)))?;
+    Ok(TreeMerge { tree, conflicts })
+}
+
+/// Merge commit `theirs` into HEAD and check out the result, like `git merge`
+///
+/// Fast-forwards when HEAD is behind. Otherwise the trees are merged with
+/// [`merge_trees`] against the merge base and committed by `committer` with
+/// `message`. Nothing changes if the merge conflicts, which fails with
+/// [`GitError::MergeConflict`], or if tracked files have local changes.
+pub fn merge_into_head(
+    repo: &Repository,
+    theirs: ObjectId,
+    drivers: &MergeDrivers,
+    committer: gix_actor::SignatureRef<'_>,
+    message: &str,
+) -> Result<HeadMerge> {
+    let repo_path = repo.path().to_path_buf();
+    let ours = match repo.head_id() {
+        Ok(id) => Some(id.detach()),
+        Err(_) => None,
+    };
+    if ours == Some(theirs) {
+        return Ok(HeadMerge::UpToDate);
+    }
+
+    // Checking out the result would overwrite local changes
+    let dirty: Vec<String> = crate::core::status(repo)?.into_iter()
+        .filter(|change| change.status != FileStatus::Untracked)
+        .map(|change| change.path.display().to_string())
+        .collect();
+    if !dirty.is_empty() {
+        return Err(repo_err(format!("Local changes would be overwritten by merge: {}", dirty.join(", ")), &repo_path));
+    }
+
+    let base = match ours {
+        Some(ours) => merge_base(repo, ours, theirs)?,
+        None => None,
+    };
+    if base == Some(theirs) {
+        return Ok(HeadMerge::UpToDate);
+    }
+    if base.is_none() && ours.is_some() {
+        return Err(repo_err(format!("Refusing to merge unrelated history {}", theirs), &repo_path));
+    }
+    let ours = match ours {
+        Some(ours) if base != Some(ours) => ours,
+        // HEAD is unborn or behind
+        _ => {
+            crate::core::reset::checkout_commit(repo, theirs)?;
+            let edit = gix::refs::transaction::RefEdit {
+                change: gix::refs::transaction::Change::Update {
+                    log: gix::refs::transaction::LogChange {
+                        mode: gix::refs::transaction::RefLog::AndReference,
+                        force_create_reflog: false,
+                        message: format!("merge {}: Fast-forward", theirs).into(),
+                    },
+                    expected: gix::refs::transaction::PreviousValue::Any,
+                    new: gix::refs::Target::Peeled(theirs),
+                },
+                name: "HEAD".try_into().expect("HEAD is a valid reference name"),
+                deref: true,
+            };
+            crate::core::edit_references(repo, Some(edit))?;
+            return Ok(HeadMerge::FastForward(theirs));
+        },
+    };
+
+    let tree_of = |commit: ObjectId| -> Result<ObjectId> {
+        repo.find_object(commit)
+            .and_then(|object| object.peel_to_tree().map_err(Into::into))
+            .map(|tree| tree.id)
+            .map_err(|e| repo_err(format!("Failed to read tree of {}: {}", commit, e), &repo_path))
+    };
+    let base_tree = base.map(tree_of).transpose()?;
+    let merged = merge_trees(repo, base_tree, tree_of(ours)?, tree_of(theirs)?, drivers)?;
+    if !merged.conflicts.is_empty() {
+        return Err(GitError::MergeConflict(merged.conflicts));
+    }
+
+    let commit = repo.commit_as(committer, committer, "HEAD", message, merged.tree, [ours, theirs])
+        .map_err(|e| repo_err(format!("Failed to commit merge of {}: {}", theirs, e), &repo_path))?
+        .detach();
+    crate::core::reset::checkout_commit(repo, commit)?;
+    Ok(HeadMerge::Merged(commit))
+}
+
+/// A common ancestor of `ours` and `theirs`, found by walking back from theirs
+fn merge_base(repo: &Repository, ours: ObjectId, theirs: ObjectId) -> Result<Option<ObjectId>> {
+    let walk_error = |id: ObjectId, e: &dyn std::fmt::Display| {
+        repo_err(format!("Failed to walk history of {}: {}", id, e), repo.path())
+    };
+    let mut our_history = HashSet::new();
+    for info in ours.attach(repo).ancestors().all().map_err(|e| walk_error(ours, &e))? {
+        our_history.insert(info.map_err(|e| walk_error(ours, &e))?.id);
+    }
+    for info in theirs.attach(repo).ancestors().all().map_err(|e| walk_error(theirs, &e))? {
+        let id = info.map_err(|e| walk_error(theirs, &e))?.id;
+        if our_history.contains(&id) {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+/// The blobs and submodules of a tree by path, with their modes
+fn tree_entries(repo: &Repository, tree: ObjectId) -> Result<BTreeMap<String, (Mode, ObjectId)>> {
+    let state = gix::index::State::from_tree(&tree, |oid, buf| repo.objects.find_tree_iter(oid, buf).ok())
+        .map_err(|e| repo_err(format!("Failed to read tree {}: {}", tree, e), repo.path()))?;
+    Ok(state.entries().iter()
+        .map(|entry| (entry.path_in(state.path_backing()).to_string(), (entry.mode, entry.id)))
+        .collect())
+}
+
+/// Whether entries of `mode` hold file content a driver can merge
+fn is_file(mode: Mode) -> bool {
+    mode == Mode::FILE || mode == Mode::FILE_EXECUTABLE
+}
+
+/// Read the content of blob `id`
+fn read_blob(repo: &Repository, id: ObjectId) -> Result<Vec<u8>> {
+    repo.find_object(id)
+        .map(|object| object.detach().data)
+        .map_err(|e| GitError::ObjectStorage(format!("Failed to read blob {}: {}", id, e)))
+}
+
+/// Resolve merges where at most one side changed anything
+fn trivial_merge<'a>(input: &MergeInput<'a>) -> Option<&'a [u8]> {
+    if input.ours == input.theirs {
+        return Some(input.ours);
+    }
+    match input.base {
+        Some(base) if base == input.ours => Some(input.theirs),
+        Some(base) if base == input.theirs => Some(input.ours),
+        _ => None,
+    }
+}
+
+/// Check for NUL bytes the way git decides a file is binary
+fn is_binary(input: &MergeInput<'_>) -> bool {
+    [input.base.unwrap_or_default(), input.ours, input.theirs]
+        .iter()
+        .any(|data| data.iter().take(8000).any(|&b| b == 0))
+}
+
+/// A changed region: lines `before` of the base became lines `after`
+type Hunk = (Range<u32>, Range<u32>);
+
+/// Diff base against one side, returning the changed line ranges
+fn line_hunks(base: &str, side: &str) -> Vec<Hunk> {
+    let input = gix::diff::blob::intern::InternedInput::new(base, side);
+    let mut hunks = Vec::new();
+    gix::diff::blob::diff(
+        gix::diff::blob::Algorithm::Histogram,
+        &input,
+        |before: Range<u32>, after: Range<u32>| hunks.push((before, after)),
+    );
+    hunks
+}
+
+/// Three-way merge of text, line by line
+///
+/// Changes from one side are applied as they are; regions changed by both
+/// sides in different ways (including adjacent changes) conflict.
+fn merge_lines(input: &MergeInput<'_>, style: ConflictStyle) -> MergeOutcome {
+    let base = String::from_utf8_lossy(input.base.unwrap_or_default());
+    let ours = String::from_utf8_lossy(input.ours);
+    let theirs = String::from_utf8_lossy(input.theirs);
+
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let our_lines: Vec<&str> = ours.split_inclusive('\n').collect();
+    let their_lines: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    let sides = [line_hunks(&base, &ours), line_hunks(&base, &theirs)];
+    let mut next = [0usize; 2];
+    let mut delta = [0i64; 2];
+
+    let mut out = String::new();
+    let mut conflicted = false;
+    let mut cursor = 0u32;
+
+    loop {
+        // The next hunk on either side starts a region
+        let start = match (sides[0].get(next[0]), sides[1].get(next[1])) {
+            (None, None) => break,
+            (Some(a), None) => a.0.start,
+            (None, Some(b)) => b.0.start,
+            (Some(a), Some(b)) => a.0.start.min(b.0.start),
+        };
+
+        // Each side's position in the region is offset by its earlier changes
+        let side_start = [start as i64 + delta[0], start as i64 + delta[1]];
+
+        // Grow the region while hunks from either side overlap or touch it
+        let mut end = start;
+        let mut changed = [false; 2];
+        loop {
+            let mut grew = false;
+            for side in 0..2 {
+                while let Some((before, after)) = sides[side].get(next[side]) {
+                    if before.start > end {
+                        break;
+                    }
+                    end = end.max(before.end);
+                    delta[side] += after.len() as i64 - before.len() as i64;
+                    changed[side] = true;
+                    next[side] += 1;
+                    grew = true;
+                }
+            }
+            if !grew {
+                break;
+            }
+        }
+
+        for line in &base_lines[cursor as usize..start as usize] {
+            out.push_str(line);
+        }
+
+        let side_end = [end as i64 + delta[0], end as i64 + delta[1]];
+        let our_part = &our_lines[side_start[0] as usize..side_end[0] as usize];
+        let their_part = &their_lines[side_start[1] as usize..side_end[1] as usize];
+
+        if !changed[1] || our_part == their_part {
+            push_lines(&mut out, our_part);
+        } else if !changed[0] {
+            push_lines(&mut out, their_part);
+        } else {
+            match style {
+                ConflictStyle::Markers => {
+                    conflicted = true;
+                    out.push_str("<<<<<<< ours\n");
+                    push_lines(&mut out, our_part);
+                    terminate_line(&mut out);
+                    out.push_str("=======\n");
+                    push_lines(&mut out, their_part);
+                    terminate_line(&mut out);
+                    out.push_str(">>>>>>> theirs\n");
+                },
+                ConflictStyle::Union => {
+                    push_lines(&mut out, our_part);
+                    terminate_line(&mut out);
+                    push_lines(&mut out, their_part);
+                },
+            }
+        }
+
+        cursor = end;
+    }
+
+    for line in &base_lines[cursor as usize..] {
+        out.push_str(line);
+    }
+
+    MergeOutcome { content: out.into_bytes(), conflicted }
+}
+
+/// Append lines as they are
+fn push_lines(out: &mut String, lines: &[&str]) {
+    for line in lines {
+        out.push_str(line);
+    }
+}
+
+/// End an unterminated last line before more text is appended
+fn terminate_line(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::attributes::GitAttributes;
+
+    fn input<'a>(base: &'a str, ours: &'a str, theirs: &'a str) -> MergeInput<'a> {
+        MergeInput { path: "file.txt", base: Some(base.as_bytes()), ours: ours.as_bytes(), theirs: theirs.as_bytes() }
+    }
+
+    #[test]
+    fn test_text_merges_separate_changes() {
+        let merged = TextDriver.merge(&input("a\nb\nc\nd\ne\n", "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n")).unwrap();
+        assert_eq!(merged, MergeOutcome::clean(b"A\nb\nc\nd\nE\n".to_vec()));
+    }
+
+    #[test]
+    fn test_text_marks_conflicts() {
+        let merged = TextDriver.merge(&input("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n")).unwrap();
+        assert!(merged.conflicted);
+        assert_eq!(
+            String::from_utf8(merged.content).unwrap(),
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn test_union_keeps_both_sides() {
+        let merged = UnionDriver.merge(&input("# Changes\n", "# Changes\n- ours\n", "# Changes\n- theirs\n")).unwrap();
+        assert_eq!(merged, MergeOutcome::clean(b"# Changes\n- ours\n- theirs\n".to_vec()));
+    }
+
+    #[test]
+    fn test_attribute_selects_driver() {
+        let attributes = GitAttributes::parse("generated/* merge=theirs\n*.lock merge=ours\n");
+        let drivers = MergeDrivers::new();
+
+        let mut generated = input("v1\n", "v2\n", "v3\n");
+        generated.path = "generated/api.rs";
+        let merged = drivers.merge(&attributes.resolve(generated.path), &generated).unwrap();
+        assert_eq!(merged, MergeOutcome::clean(b"v3\n".to_vec()));
+
+        let mut lock = input("v1\n", "v2\n", "v3\n");
+        lock.path = "Cargo.lock";
+        let merged = drivers.merge(&attributes.resolve(lock.path), &lock).unwrap();
+        assert_eq!(merged, MergeOutcome::clean(b"v2\n".to_vec()));
+    }
+
+    #[test]
+    fn test_custom_driver() {
+        let attributes = GitAttributes::parse("*.txt merge=upper\n");
+        let mut drivers = MergeDrivers::new();
+        drivers.register("upper", |input: &MergeInput<'_>| {
+            Ok(MergeOutcome::clean(input.theirs.to_ascii_uppercase()))
+        });
+        let merged = drivers.merge(&attributes.resolve("file.txt"), &input("a\n", "b\n", "c\n")).unwrap();
+        assert_eq!(merged.content, b"C\n");
+    }
+
+    fn git(dir: &std::path::Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_merge_into_head_runs_custom_driver() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(dir.path().join(".gitattributes"), "*.gen merge=upper\n").unwrap();
+        std::fs::write(dir.path().join("api.gen"), "a\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "one\ntwo\nthree\n").unwrap();
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "-q", "-m", "base"]);
+
+        git(dir.path(), &["checkout", "-q", "-b", "theirs"]);
+        std::fs::write(dir.path().join("api.gen"), "c\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "one\ntwo\nthree!\n").unwrap();
+        git(dir.path(), &["commit", "-q", "-am", "theirs"]);
+        let theirs = ObjectId::from_hex(git(dir.path(), &["rev-parse", "HEAD"]).as_bytes()).unwrap();
+
+        git(dir.path(), &["checkout", "-q", "main"]);
+        std::fs::write(dir.path().join("api.gen"), "b\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "one!\ntwo\nthree\n").unwrap();
+        git(dir.path(), &["commit", "-q", "-am", "ours"]);
+
+        let mut drivers = MergeDrivers::new();
+        drivers.register("upper", |input: &MergeInput<'_>| {
+            Ok(MergeOutcome::clean(input.theirs.to_ascii_uppercase()))
+        });
+        let repo = gix::open(dir.path()).unwrap();
+        let committer = gix_actor::SignatureRef { name: "t".into(), email: "t@t".into(), time: gix_date::Time::now_utc() };
+        let merged = merge_into_head(&repo, theirs, &drivers, committer, "Merge theirs").unwrap();
+
+        let HeadMerge::Merged(commit) = merged else { panic!("expected a merge commit, got {:?}", merged) };
+        assert_eq!(git(dir.path(), &["rev-parse", "HEAD"]), commit.to_string());
+        assert_eq!(git(dir.path(), &["rev-parse", "HEAD^2"]), theirs.to_string());
+        // The driver picked by the attribute ran; other files used the text driver
+        assert_eq!(std::fs::read_to_string(dir.path().join("api.gen")).unwrap(), "C\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("notes.txt")).unwrap(), "one!\ntwo\nthree!\n");
+        assert!(git(dir.path(), &["status", "--porcelain"]).is_empty());
+
+        assert_eq!(merge_into_head(&repo, theirs, &drivers, committer, "again").unwrap(), HeadMerge::UpToDate);
+    }
+}
@@ -0,0 +1,229 @@
+//! Keeping mirrors fresh by fetching their remotes on a schedule
+//!
+//! Each mirrored remote is fetched every `interval`, shifted by a random
+//! jitter so an observer of the Tor traffic can't tell a mirror apart by its
+//! clockwork timing; even the first fetches after a start are spread out. A
+//! remote that keeps failing is retried with exponential backoff instead of
+//! hammering an onion service that is down, and a fetch that hangs is
+//! abandoned after `fetch_timeout` so it can't hold up the other remotes.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::core::{ArtiGitClient, GitError, MirrorConfig, PullOutcome, Result};
+
+/// A remote of a local repository to keep fetching
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorTarget {
+    /// Repository the fetched refs are stored in
+    pub path: PathBuf,
+    /// Remote to fetch from
+    pub remote: String,
+    /// Time between fetches while they succeed
+    pub interval: Duration,
+}
+
+/// Timing shared by all mirrored remotes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MirrorSchedule {
+    /// Largest shift of a fetch, as a fraction of the wait before it
+    pub jitter: f64,
+    /// Longest wait after repeated failures
+    pub max_backoff: Duration,
+    /// Longest a single fetch may take
+    pub fetch_timeout: Duration,
+}
+
+impl MirrorSchedule {
+    /// Read the timing settings of a mirror configuration
+    pub fn from_config(config: &MirrorConfig) -> Self {
+        Self {
+            jitter: config.jitter.clamp(0.0, 1.0),
+            max_backoff: Duration::from_secs(config.max_backoff),
+            fetch_timeout: Duration::from_secs(config.fetch_timeout.max(1)),
+        }
+    }
+    
+    /// Wait before the first fetch of a remote, at most `jitter` of `interval`
+    ///
+    /// Keeps all mirrors from fetching at the same moment when they start.
+    pub fn initial_delay(&self, interval: Duration, rng: &mut impl Rng) -> Duration {
+        if self.jitter <= 0.0 {
+            return Duration::ZERO;
+        }
+        interval.mul_f64(rng.gen_range(0.0, self.jitter))
+    }
+
+    /// Wait before the next fetch of a remote with `failures` failed fetches in a row
+    ///
+    /// The wait doubles with every failure up to `max_backoff`, but is never
+    /// shorter than the regular interval.
+    pub fn delay(&self, interval: Duration, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures).unwrap_or(u32::MAX);
+        interval.saturating_mul(factor).min(self.max_backoff.max(interval))
+    }
+
+    /// Shift `delay` by a random amount of at most `jitter` of it
+    pub fn jittered(&self, delay: Duration, rng: &mut impl Rng) -> Duration {
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        delay.mul_f64(rng.gen_range(1.0 - self.jitter, 1.0 + self.jitter))
+    }
+}
+
+/// Fetches a set of mirrored remotes, each on its own schedule
+pub struct MirrorScheduler {
+    targets: Vec<MirrorTarget>,
+    schedule: MirrorSchedule,
+}
+
+impl MirrorScheduler {
+    /// Schedule `targets` with the given timing
+    pub fn new(targets: Vec<MirrorTarget>, schedule: MirrorSchedule) -> Self {
+        Self { targets, schedule }
+    }
+
+    /// The remotes configured in `config`
+    pub fn from_config(config: &MirrorConfig) -> Self {
+        let targets = config.remotes.iter()
+            .map(|remote| MirrorTarget {
+                path: remote.path.clone(),
+                remote: remote.remote.clone(),
+                interval: Duration::from_secs(remote.interval.unwrap_or(config.interval).max(1)),
+            })
+            .collect();
+        Self::new(targets, MirrorSchedule::from_config(config))
+    }
+
+    /// The scheduled remotes
+    pub fn targets(&self) -> &[MirrorTarget] {
+        &self.targets
+    }
+
+    /// Fetch every remote once, in order
+    pub async fn fetch_all(&self, client: &ArtiGitClient, cancel: &CancellationToken) -> Vec<Result<PullOutcome>> {
+        let mut results = Vec::with_capacity(self.targets.len());
+        for target in &self.targets {
+            results.push(fetch_target(client, target, cancel).await);
+        }
+        results
+    }
+
+    /// Fetch the remotes on their schedules until `cancel` fires
+    ///
+    /// Every remote is fetched after a random share of its jitter, then
+    /// again whenever its jittered interval or backoff has passed. Failures
+    /// and fetches that time out are logged and retried; they never stop
+    /// the other remotes.
+    pub async fn run(&self, client: &ArtiGitClient, cancel: &CancellationToken) {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut next_fetch: Vec<Instant> = self.targets.iter()
+            .map(|target| now + self.schedule.initial_delay(target.interval, &mut rng))
+            .collect();
+        let mut failures = vec![0u32; self.targets.len()];
+
+        loop {
+            let (index, due) = match next_fetch.iter().enumerate().min_by_key(|(_, due)| **due) {
+                Some((index, due)) => (index, *due),
+                None => return,
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep_until(due) => {},
+            }
+
+            let target = &self.targets[index];
+            match self.fetch_with_timeout(client, target, cancel).await {
+                Ok(outcome) => {
+                    failures[index] = 0;
+                    log::info!("Mirror {} ({}): {} refs updated, {} new tags",
+                        target.path.display(), target.remote, outcome.updates.len(), outcome.new_tags.len());
+                },
+                Err(_) if cancel.is_cancelled() => return,
+                Err(e) => {
+                    failures[index] = failures[index].saturating_add(1);
+                    log::warn!("Mirror {} ({}) failed {} times in a row: {}",
+                        target.path.display(), target.remote, failures[index], e);
+                },
+            }
+
+            let delay = self.schedule.jittered(
+                self.schedule.delay(target.interval, failures[index]),
+                &mut rng,
+            );
+            log::debug!("Next fetch of {} ({}) in {}s", target.path.display(), target.remote, delay.as_secs());
+            next_fetch[index] = Instant::now() + delay;
+        }
+    }
+    
+    /// Fetch `target`, giving up after the schedule's fetch timeout
+    ///
+    /// The fetch is cancelled through a child of `cancel`, which also
+    /// interrupts gitoxide while it is blocked on the network.
+    async fn fetch_with_timeout(&self, client: &ArtiGitClient, target: &MirrorTarget, cancel: &CancellationToken) -> Result<PullOutcome> {
+        let fetch_cancel = cancel.child_token();
+        let timer = {
+            let fetch_cancel = fetch_cancel.clone();
+            let limit = self.schedule.fetch_timeout;
+            tokio::spawn(async move {
+                tokio::time::sleep(limit).await;
+                fetch_cancel.cancel();
+            })
+        };
+        let result = fetch_target(client, target, &fetch_cancel).await;
+        timer.abort();
+        match result {
+            Err(_) if fetch_cancel.is_cancelled() && !cancel.is_cancelled() => Err(GitError::Transport(format!(
+                "Fetch of {} ({}) took longer than {}s",
+                target.path.display(), target.remote, self.schedule.fetch_timeout.as_secs()), None)),
+            result => result,
+        }
+    }
+}
+
+async fn fetch_target(client: &ArtiGitClient, target: &MirrorTarget, cancel: &CancellationToken) -> Result<PullOutcome> {
+    let mut repo = client.open(&target.path)?;
+    client.fetch_remote(&mut repo, &target.remote, false, cancel).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_jitter() {
+        let schedule = MirrorSchedule {
+            jitter: 0.25,
+            max_backoff: Duration::from_secs(3600),
+            fetch_timeout: Duration::from_secs(60),
+        };
+        let interval = Duration::from_secs(600);
+
+        let waits: Vec<u64> = (0..5).map(|failures| schedule.delay(interval, failures).as_secs()).collect();
+        assert_eq!(waits, vec![600, 1200, 2400, 3600, 3600]);
+        assert_eq!(schedule.delay(interval, 100).as_secs(), 3600);
+
+        // A backoff limit below the interval never speeds fetches up
+        let long = Duration::from_secs(7200);
+        assert_eq!(schedule.delay(long, 3), long);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let wait = schedule.jittered(interval, &mut rng);
+            assert!(wait >= Duration::from_secs(450) && wait <= Duration::from_secs(750), "{:?}", wait);
+        }
+        for _ in 0..100 {
+            let wait = schedule.initial_delay(interval, &mut rng);
+            assert!(wait <= Duration::from_secs(150), "{:?}", wait);
+        }
+        let fixed = MirrorSchedule { jitter: 0.0, ..schedule };
+        assert_eq!(fixed.jittered(interval, &mut rng), interval);
+        assert_eq!(fixed.initial_delay(interval, &mut rng), Duration::ZERO);
+    }
+}
@@ -6,12 +6,72 @@ mod error;
 mod config;
 mod client;
 mod operations;
+mod replace;
+mod submodule;
+mod attributes;
+mod merge;
+mod stage;
+mod mv;
+mod rm;
+mod reflog;
+mod reset;
+mod mirror;
+mod remote;
+mod commit_graph;
+mod shallow;
+mod lock;
+mod patch;
+mod alternates;
+mod hash;
 
 pub use object::{ObjectId, ObjectType};
-pub use error::{GitError, Result};
-pub use config::{ArtiGitConfig, TorConfig, GitConfig, OnionServiceConfig, ConfigError};
-pub use client::ArtiGitClient;
+pub use hash::{HashKind, ObjectHasher, hash_object, hash_reader};
+pub use error::{GitError, Result, PathRedaction, set_path_redaction, path_redaction, redact_path};
+pub use config::{
+    ArtiGitConfig, TorConfig, GitConfig, OnionServiceConfig, OnionIdentityConfig, RepoPolicy, MirrorConfig, MirrorRemoteConfig,
+    TorBootstrap, ConfigError, ENV_OVERRIDES, PRIMARY_ONION_NICKNAME,
+};
+pub use replace::{
+    Replacements, ReplaceRef, replace_objects_enabled, list_replacements, add_replacement, delete_replacement,
+    NO_REPLACE_OBJECTS_ENV, REPLACE_REFSPEC,
+};
+pub use attributes::{
+    GitAttributes, PathAttributes, AttributeValue, attributes_for, global_attributes_file,
+    GITATTRIBUTES_FILE, INFO_ATTRIBUTES_FILE
+};
+pub(crate) use attributes::xdg_git_dir;
+pub use merge::{
+    MergeDriver, MergeDrivers, MergeInput, MergeOutcome, MERGE_ATTRIBUTE,
+    TextDriver, UnionDriver, BinaryDriver, OursDriver, TheirsDriver,
+    TreeMerge, HeadMerge, merge_trees, merge_into_head
+};
+pub use stage::{FilePatch, Hunk, HunkChoice, unstaged_patches, stage_content, add_patch};
+pub use mv::move_path;
+pub use rm::remove_paths;
+pub use reflog::{ReflogEntry, reflog_ref_name, reflog_path, read_reflog, resolve_revision};
+pub use reset::{ResetMode, reset};
+pub use mirror::{MirrorTarget, MirrorSchedule, MirrorScheduler};
+pub use remote::RemoteTorSettings;
+pub use commit_graph::{
+    CommitGraph, CommitGraphBuilder, CommitGraphStats, GraphCommit, write_commit_graph,
+    COMMIT_GRAPH_FILE, GENERATION_NUMBER_MAX
+};
+pub use alternates::{
+    CloneReference, read_alternates, borrow_objects, finish_reference, dissociate,
+    ALTERNATES_FILE, REFERENCE_REFS_PREFIX
+};
+pub use patch::{
+    PatchFile, PatchHunk, HunkLine, PatchAction, AppliedFile, MailPatch,
+    parse_patch, apply_hunks, apply_patch, parse_mbox
+};
+pub use lock::{LockFile, lock_index, write_index, edit_references, lock_path, LOCK_SUFFIX};
+pub use shallow::{
+    FetchDepth, ShallowUpdate, read_shallow, write_shallow, check_fetch_depth, SHALLOW_FILE, INFINITE_DEPTH
+};
+pub use submodule::{Submodule, InitializedSubmodule, read_gitmodules, resolve_url as resolve_submodule_url};
+pub use client::{ArtiGitClient, CloneOptions, FetchedRefUpdate, PullOutcome, RemoteInfo, RemoteListing, RemoteState, ShallowFetchOutcome};
 pub use operations::{
     FileStatus, FileChange, status, create_branch, list_branches, 
-    delete_branch, checkout, log, format_commit
+    delete_branch, checkout, log, format_commit, show, diff_cached, diff_worktree,
+    ObjectCounts, count_objects, write_index_tree, head_tree_id, ensure_tree_changed
 };
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! Moving tracked files and directories, like `git mv`
+
+use std::path::{Component, Path};
+
+use gix::Repository;
+
+use crate::core::{GitError, Result, io_err, repo_err};
+
+/// Turn a path relative to the top of the work tree into an index path
+///
+/// Index paths are `/`-separated. Absolute paths and paths with `..`
+/// components are refused, even if they would end up inside the work tree.
+pub(crate) fn index_path(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()
+                .ok_or_else(|| GitError::InvalidArgument(format!("'{}' is not valid UTF-8", path.display())))?),
+            Component::CurDir => {},
+            _ => return Err(GitError::InvalidArgument(format!(
+                "'{}' is outside the work tree", path.display(),
+            ))),
+        }
+    }
+    if parts.is_empty() {
+        return Err(GitError::InvalidArgument(format!("'{}' is the top of the work tree", path.display())));
+    }
+    Ok(parts.join("/"))
+}
+
+/// Move `source` to `destination` in the work tree and the index
+///
+/// Both paths are relative to the top of the work tree, not the current
+/// directory. A tracked file or a directory holding tracked files can be
+/// moved; untracked files inside a moved directory go with it but stay
+/// untracked. When `destination` is an existing directory, `source` moves
+/// into it; the directory `destination` lands in must already exist. With
+/// `force` an existing destination file is replaced by a source file;
+/// directories are never replaced. Returns the old and new path of every
+/// index entry that moved.
+pub fn move_path(repo: &Repository, source: &Path, destination: &Path, force: bool) -> Result<Vec<(String, String)>> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot move files in a bare repository", &repo_path))?
+        .to_path_buf();
+
+    let source = index_path(source)?;
+    let mut destination = index_path(destination)?;
+    if workdir.join(&destination).is_dir() {
+        let name = source.rsplit('/').next().expect("index paths are not empty");
+        destination = format!("{}/{}", destination, name);
+    }
+    if destination == source || destination.starts_with(&format!("{}/", source)) {
+        return Err(GitError::InvalidArgument(format!("Cannot move '{}' into itself", source)));
+    }
+
+    let from = workdir.join(&source);
+    let to = workdir.join(&destination);
+    if std::fs::symlink_metadata(&from).is_err() {
+        return Err(GitError::InvalidArgument(format!("'{}' does not exist", source)));
+    }
+    if !to.parent().map_or(false, Path::is_dir) {
+        return Err(GitError::InvalidArgument(format!("Directory of '{}' does not exist", destination)));
+    }
+
+    let index_lock = crate::core::lock_index(repo)?;
+    let mut index = repo.open_index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+
+    // Entries under the source, whether it is a file or a directory
+    let prefix = format!("{}/", source);
+    let moved: Vec<_> = index.entries().iter()
+        .filter(|entry| {
+            let path = entry.path(&index);
+            path == source.as_str() || path.starts_with(prefix.as_bytes())
+        })
+        .map(|entry| (entry.path(&index).to_string(), entry.clone()))
+        .collect();
+    if moved.is_empty() {
+        return Err(GitError::InvalidArgument(format!("'{}' is not under version control", source)));
+    }
+    if let Some((path, _)) = moved.iter().find(|(_, entry)| entry.stage() != 0) {
+        return Err(GitError::InvalidArgument(format!("'{}' has unresolved conflicts", path)));
+    }
+
+    if std::fs::symlink_metadata(&to).is_ok() {
+        if !force || from.is_dir() || to.is_dir() {
+            return Err(GitError::InvalidArgument(format!(
+                "'{}' already exists; use --force to overwrite it", destination,
+            )));
+        }
+        log::debug!("Overwriting {}", destination);
+    }
+
+    std::fs::rename(&from, &to)
+        .map_err(|e| io_err(format!("Failed to move {} to {}: {}", source, destination, e), &from))?;
+
+    let renames: Vec<(String, String)> = moved.iter()
+        .map(|(path, _)| (path.clone(), format!("{}{}", destination, &path[source.len()..])))
+        .collect();
+    index.remove_entries(|_, path, _| {
+        path == source.as_str() || path.starts_with(prefix.as_bytes()) || path == destination.as_str()
+    });
+    for ((_, entry), (_, new_path)) in moved.iter().zip(&renames) {
+        // The content is unchanged; the rename at most changes the ctime, which
+        // costs a re-hash on the next status rather than a false change
+        index.dangerously_push_entry(entry.stat, entry.id, entry.flags, entry.mode, new_path.as_str().into());
+    }
+    index.sort_entries();
+    crate::core::write_index(index_lock, &index)?;
+
+    Ok(renames)
+}
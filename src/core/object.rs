@@ -55,6 +55,13 @@ impl From<GixObjectId> for ObjectId {
     }
 }
 
+// Implement conversion into gitoxide's ObjectId
+impl From<&ObjectId> for GixObjectId {
+    fn from(oid: &ObjectId) -> Self {
+        GixObjectId::from(oid.id)
+    }
+}
+
 /// Git object types
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ObjectType {
@@ -91,4 +98,15 @@ impl ObjectType {
             )),
         }
     }
+}
+
+impl From<ObjectType> for gix::object::Kind {
+    fn from(kind: ObjectType) -> Self {
+        match kind {
+            ObjectType::Blob => gix::object::Kind::Blob,
+            ObjectType::Tree => gix::object::Kind::Tree,
+            ObjectType::Commit => gix::object::Kind::Commit,
+            ObjectType::Tag => gix::object::Kind::Tag,
+        }
+    }
 }
\ No newline at end of file
@@ -1,11 +1,14 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 
 use gix::{Repository, oid};
 use gix_hash::ObjectId;
 use gix_revision::spec::parse;
 
-use crate::core::{GitError, Result};
+use crate::core::{GitError, Result, io_err, repo_err};
+use crate::core::replace::Replacements;
+use crate::core::commit_graph::CommitGraph;
+use crate::core::attributes::{AttributeValue, PathAttributes, attributes_for};
 
 /// Represents a file status in the repository
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -350,35 +353,55 @@ pub fn checkout(repo: &Repository, target: &str, create: bool) -> Result<ObjectI
 /// Show a commit log
 pub fn log(repo: &Repository, limit: Option<usize>) -> Result<Vec<gix::Commit<'_>>> {
     // Get the HEAD commit
-    let head = repo.head_commit()
-        .map_err(|e| GitError::Repository(format!("Failed to get HEAD commit: {}", e)))?;
+    let head = repo.head_id()
+        .map_err(|e| GitError::Repository(format!("Failed to get HEAD commit: {}", e), None))?
+        .detach();
     
-    // Create a revwalk to traverse the commit history
-    let mut revwalk = repo.revwalk()
-        .map_err(|e| GitError::Repository(format!("Failed to create revwalk: {}", e)))?;
-        
-    // Push HEAD as the starting point
-    revwalk.push(head.id)
-        .map_err(|e| GitError::Repository(format!("Failed to push HEAD to revwalk: {}", e)))?;
+    // Parents are looked up through refs/replace, so grafted or rewritten
+    // history is traversed the same way git would
+    let replacements = Replacements::load(repo)?;
+    // Parents and times come from the commit-graph when one covers them
+    let graph = CommitGraph::for_repo(repo);
     
-    // Collect commits
+    // Walk newest-first by committer time
+    let mut queue = std::collections::BinaryHeap::new();
+    let mut seen = HashSet::new();
     let mut commits = Vec::new();
-    let mut count = 0;
     let max_count = limit.unwrap_or(std::usize::MAX);
     
-    for commit_id in revwalk {
-        let commit_id = commit_id
-            .map_err(|e| GitError::Repository(format!("Failed to get next commit: {}", e)))?;
-            
-        let commit = repo.find_commit(commit_id)
-            .map_err(|e| GitError::Repository(format!("Failed to find commit {}: {}", commit_id, e)))?;
-            
-        commits.push(commit);
-        
-        count += 1;
-        if count >= max_count {
+    seen.insert(head);
+    queue.push((0i64, head));
+    
+    while let Some((_, commit_id)) = queue.pop() {
+        if commits.len() >= max_count {
             break;
         }
+        
+        let commit = repo.find_object(replacements.resolve(commit_id)?)
+            .map_err(|e| GitError::Repository(format!("Failed to find commit {}: {}", commit_id, e), None))?
+            .try_into_commit()
+            .map_err(|e| GitError::Repository(format!("{} is not a commit: {}", commit_id, e), None))?;
+        
+        let parents = match graph.as_ref().and_then(|graph| graph.parents(&commit_id)) {
+            Some(parents) => parents,
+            None => commit.parent_ids().map(|parent| parent.detach()).collect(),
+        };
+        for parent in parents {
+            if seen.insert(parent) {
+                let time = match graph.as_ref().and_then(|graph| graph.commit_time(&parent)) {
+                    Some(time) => time as i64,
+                    None => repo.find_object(replacements.resolve(parent)?)
+                        .ok()
+                        .and_then(|object| object.try_into_commit().ok())
+                        .and_then(|parent_commit| parent_commit.time().ok())
+                        .map(|time| time.seconds as i64)
+                        .unwrap_or(0),
+                };
+                queue.push((time, parent));
+            }
+        }
+        
+        commits.push(commit);
     }
     
     Ok(commits)
@@ -392,4 +415,403 @@ pub fn format_commit(commit: &gix::Commit<'_>) -> Result<String> {
     let message = commit.message().unwrap_or_default().title().unwrap_or_default().to_string();
     
     Ok(format!("{} {} ({}) {}", id[0..7].to_string(), message, author, date))
-}
\ No newline at end of file
+}
+
+/// Show a commit's metadata followed by the diff it introduced
+///
+/// The diff is taken against the first parent (or the empty tree for a root
+/// commit). Merge commits are marked as such, and changes to Git LFS pointer
+/// files are summarized instead of printing the pointer text.
+pub fn show(repo: &Repository, rev: &str) -> Result<String> {
+    let id = crate::core::resolve_revision(repo, rev)?;
+    
+    // Read through refs/replace so the commit matches what git shows
+    let replacements = Replacements::load(repo)?;
+    let commit = repo.find_object(replacements.resolve(id)?)
+        .map_err(|e| GitError::Repository(format!("Failed to read object {}: {}", id, e), None))?
+        .try_into_commit()
+        .map_err(|e| GitError::InvalidArgument(format!("'{}' is not a commit: {}", rev, e)))?;
+    
+    let author = commit.author()
+        .map_err(|e| GitError::Repository(format!("Failed to read author of {}: {}", id, e), None))?;
+    let message = commit.message_raw()
+        .map_err(|e| GitError::Repository(format!("Failed to read message of {}: {}", id, e), None))?;
+    let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+    
+    // Commit header, under the id that was asked for even if it was replaced
+    let mut out = format!("commit {}\n", id);
+    if parents.len() > 1 {
+        let short: Vec<String> = parents.iter().map(|p| p.to_hex_with_len(7).to_string()).collect();
+        out.push_str(&format!("Merge: {}\n", short.join(" ")));
+    }
+    out.push_str(&format!("Author: {} <{}>\n", author.name, author.email));
+    out.push_str(&format!("Date:   {}\n\n", author.time.format(gix::date::time::format::DEFAULT)));
+    for line in message.to_string().lines() {
+        out.push_str(&format!("    {}\n", line));
+    }
+    out.push('\n');
+    
+    if parents.len() > 1 {
+        out.push_str(&format!("Merge commit: diff shown against first parent {}\n\n",
+            parents[0].to_hex_with_len(7)));
+    }
+    
+    // Trees to compare: first parent (or empty) against this commit
+    let new_tree = commit.tree()
+        .map_err(|e| GitError::Repository(format!("Failed to read tree of {}: {}", id, e), None))?;
+    let old_tree = match parents.first() {
+        Some(parent) => repo.find_object(replacements.resolve(*parent)?)
+            .map_err(|e| GitError::Repository(format!("Failed to read parent {}: {}", parent, e), None))?
+            .peel_to_tree()
+            .map_err(|e| GitError::Repository(format!("Failed to read tree of parent {}: {}", parent, e), None))?,
+        None => repo.empty_tree(),
+    };
+    
+    let mut changes = Vec::new();
+    old_tree.changes()
+        .map_err(|e| GitError::Repository(format!("Failed to diff {}: {}", id, e), None))?
+        .track_path()
+        .for_each_to_obtain_tree(&new_tree, |change| {
+            use gix::object::tree::diff::change::Event;
+            let (old_id, new_id) = match change.event {
+                Event::Addition { id, .. } => (None, Some(id.detach())),
+                Event::Deletion { id, .. } => (Some(id.detach()), None),
+                Event::Modification { previous_id, id, .. } => (Some(previous_id.detach()), Some(id.detach())),
+                Event::Rewrite { source_id, id, .. } => (Some(source_id.detach()), Some(id.detach())),
+            };
+            changes.push((change.location.to_string(), old_id, new_id));
+            Ok::<_, std::convert::Infallible>(gix::object::tree::diff::Action::Continue)
+        })
+        .map_err(|e| GitError::Repository(format!("Failed to diff {}: {}", id, e), None))?;
+    
+    for (path, old_id, new_id) in changes {
+        let old_data = read_blob(repo, old_id)?;
+        let new_data = read_blob(repo, new_id)?;
+        let attributes = attributes_for(repo, &path)?;
+        out.push_str(&format_file_diff(&path, old_id, new_id, &old_data, &new_data, &attributes));
+    }
+    
+    Ok(out)
+}
+
+/// Read a blob's contents, treating a missing side of a change as empty
+fn read_blob(repo: &Repository, id: Option<ObjectId>) -> Result<Vec<u8>> {
+    match id {
+        Some(id) => Ok(repo.find_object(id)
+            .map_err(|e| GitError::Repository(format!("Failed to read blob {}: {}", id, e), None))?
+            .detach()
+            .data),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Render the diff of a single file in unified format
+fn format_file_diff(
+    path: &str,
+    old_id: Option<ObjectId>,
+    new_id: Option<ObjectId>,
+    old: &[u8],
+    new: &[u8],
+    attributes: &PathAttributes,
+) -> String {
+    let mut out = format!("diff --git a/{} b/{}\n", path, path);
+    match (old_id, new_id) {
+        (None, Some(_)) => out.push_str("new file\n"),
+        (Some(_), None) => out.push_str("deleted file\n"),
+        _ => {}
+    }
+    
+    // Summarize LFS pointer changes rather than showing the pointer text
+    let old_pointer = parse_lfs_pointer(old);
+    let new_pointer = parse_lfs_pointer(new);
+    if old_pointer.is_some() || new_pointer.is_some() {
+        let describe = |pointer: &Option<crate::lfs::LfsPointer>| match pointer {
+            Some(p) => format!("{} ({} bytes)", p.oid, p.size),
+            None => "(none)".to_string(),
+        };
+        out.push_str(&format!("LFS object: {} -> {}\n", describe(&old_pointer), describe(&new_pointer)));
+        return out;
+    }
+    
+    // Binary content (or `-diff` in the attributes) gets a one-line note, like git does
+    let no_diff = attributes.get("diff") == AttributeValue::Unset;
+    if no_diff || old.contains(&0) || new.contains(&0) {
+        out.push_str("Binary files differ\n");
+        return out;
+    }
+    
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    let old_label = if old_id.is_some() { format!("a/{}", path) } else { "/dev/null".to_string() };
+    let new_label = if new_id.is_some() { format!("b/{}", path) } else { "/dev/null".to_string() };
+    out.push_str(&format!("--- {}\n+++ {}\n", old_label, new_label));
+    
+    let input = gix::diff::blob::intern::InternedInput::new(old_text.as_ref(), new_text.as_ref());
+    out.push_str(&gix::diff::blob::diff(
+        gix::diff::blob::Algorithm::Histogram,
+        &input,
+        gix::diff::blob::UnifiedDiffBuilder::new(&input),
+    ));
+    
+    out
+}
+
+/// Parse blob content as an LFS pointer, if it is one
+fn parse_lfs_pointer(data: &[u8]) -> Option<crate::lfs::LfsPointer> {
+    // Pointer files are tiny; skip parsing anything that could not be one
+    if data.len() > 1024 || !data.starts_with(b"version https://git-lfs") {
+        return None;
+    }
+    std::str::from_utf8(data).ok().and_then(|s| crate::lfs::LfsPointer::parse(s).ok())
+}
+
+/// Show the changes staged in the index relative to HEAD (`diff --cached`)
+///
+/// Before the first commit HEAD has no tree, so every staged file shows up
+/// as new. Conflicted paths are listed as unmerged rather than diffed.
+pub fn diff_cached(repo: &Repository) -> Result<String> {
+    let repo_path = repo.path().to_path_buf();
+    let head_tree = head_tree_id(repo)?;
+    // The empty tree of an unborn HEAD isn't in the object database
+    let head = if head_tree == ObjectId::empty_tree(repo.object_hash()) {
+        gix::index::State::new(repo.object_hash())
+    } else {
+        gix::index::State::from_tree(&head_tree, |oid, buf| repo.objects.find_tree_iter(oid, buf).ok())
+            .map_err(|e| repo_err(format!("Failed to read tree of HEAD: {}", e), &repo_path))?
+    };
+    let head_entries: BTreeMap<String, ObjectId> = head.entries().iter()
+        .filter(|entry| entry.mode != gix::index::entry::Mode::COMMIT)
+        .map(|entry| (entry.path_in(head.path_backing()).to_string(), entry.id))
+        .collect();
+
+    let index = repo.index_or_empty()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+    let mut staged = BTreeMap::new();
+    let mut unmerged = BTreeSet::new();
+    for entry in index.entries() {
+        let path = entry.path(&index).to_string();
+        if entry.stage() != 0 {
+            unmerged.insert(path);
+        } else if entry.mode != gix::index::entry::Mode::COMMIT {
+            staged.insert(path, entry.id);
+        }
+    }
+
+    let paths: BTreeSet<&String> = head_entries.keys().chain(staged.keys()).collect();
+    let mut out = String::new();
+    for path in &unmerged {
+        out.push_str(&format!("* Unmerged path {}\n", path));
+    }
+    for path in paths {
+        if unmerged.contains(path) {
+            continue;
+        }
+        let old_id = head_entries.get(path).copied();
+        let new_id = staged.get(path).copied();
+        if old_id == new_id {
+            continue;
+        }
+        let attributes = attributes_for(repo, path)?;
+        out.push_str(&format_file_diff(path, old_id, new_id, &read_blob(repo, old_id)?, &read_blob(repo, new_id)?, &attributes));
+    }
+    Ok(out)
+}
+
+/// Show the changes in the working tree not yet staged (plain `diff`)
+///
+/// Only tracked files are compared, and file contents are compared as they
+/// are on disk, without applying checkout filters.
+pub fn diff_worktree(repo: &Repository) -> Result<String> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot diff the working tree of a bare repository", &repo_path))?
+        .to_path_buf();
+    let index = repo.index_or_empty()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+
+    let mut out = String::new();
+    let mut unmerged = BTreeSet::new();
+    for entry in index.entries() {
+        let path = entry.path(&index).to_string();
+        if entry.stage() != 0 {
+            unmerged.insert(path);
+            continue;
+        }
+        if entry.mode == gix::index::entry::Mode::COMMIT {
+            continue;
+        }
+
+        // A symlink's content is its target, as git stores it
+        let file = workdir.join(gix::path::from_bstr(entry.path(&index)));
+        let read = if entry.mode == gix::index::entry::Mode::SYMLINK {
+            std::fs::read_link(&file).map(|target| gix::path::into_bstr(target).to_vec())
+        } else {
+            std::fs::read(&file)
+        };
+        let (new_id, new) = match read {
+            Ok(data) => (Some(gix::objs::compute_hash(repo.object_hash(), gix::object::Kind::Blob, &data)), data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, Vec::new()),
+            Err(e) => return Err(io_err(format!("Failed to read {}: {}", path, e), &file)),
+        };
+        if new_id == Some(entry.id) {
+            continue;
+        }
+        let attributes = attributes_for(repo, &path)?;
+        out.push_str(&format_file_diff(&path, Some(entry.id), new_id, &read_blob(repo, Some(entry.id))?, &new, &attributes));
+    }
+    
+    let mut unmerged_lines: String = unmerged.iter().map(|path| format!("* Unmerged path {}\n", path)).collect();
+    unmerged_lines.push_str(&out);
+    Ok(unmerged_lines)
+}
+
+/// Write the current index as a tree object and return its id
+///
+/// This is the tree a commit made now would record.
+pub fn write_index_tree(repo: &Repository) -> Result<ObjectId> {
+    let index = repo.index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), repo.path()))?;
+    index.write_tree_to(&repo.objects)
+        .map_err(|e| GitError::ObjectStorage(format!("Failed to write index tree: {}", e)))
+}
+
+/// Get the tree of the commit HEAD points to
+///
+/// An unborn HEAD has the empty tree, so a first commit with nothing staged
+/// counts as empty too.
+pub fn head_tree_id(repo: &Repository) -> Result<ObjectId> {
+    let head = repo.head()
+        .map_err(|e| repo_err(format!("Failed to read HEAD: {}", e), repo.path()))?;
+    if head.is_unborn() {
+        return Ok(ObjectId::empty_tree(repo.object_hash()));
+    }
+
+    let commit = repo.head_commit()
+        .map_err(|e| repo_err(format!("Failed to read HEAD commit: {}", e), repo.path()))?;
+    commit.tree_id()
+        .map(|id| id.detach())
+        .map_err(|e| repo_err(format!("Failed to read tree of HEAD: {}", e), repo.path()))
+}
+
+/// Refuse to commit `tree_id` if it is identical to HEAD's tree
+///
+/// Returns [`GitError::NothingToCommit`] unless `allow_empty` is set, like
+/// `git commit` without `--allow-empty`.
+pub fn ensure_tree_changed(repo: &Repository, tree_id: ObjectId, allow_empty: bool) -> Result<()> {
+    if allow_empty || tree_id != head_tree_id(repo)? {
+        return Ok(());
+    }
+    Err(GitError::NothingToCommit(
+        "the index matches HEAD (use --allow-empty to commit anyway)".to_string()
+    ))
+}
+
+/// Object database statistics, as reported by `git count-objects -v`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectCounts {
+    /// Number of loose objects
+    pub count: usize,
+    /// Disk space used by loose objects, in bytes
+    pub size: u64,
+    /// Number of objects in packs
+    pub in_pack: usize,
+    /// Number of packs
+    pub packs: usize,
+    /// Disk space used by packs and their indexes, in bytes
+    pub size_pack: u64,
+    /// Loose objects that are also present in a pack
+    pub prune_packable: usize,
+    /// Files in the pack directory that are not part of a valid pack
+    pub garbage: usize,
+    /// Disk space used by garbage files, in bytes
+    pub size_garbage: u64,
+}
+
+impl ObjectCounts {
+    /// Average disk space of a packed object, its index entry included
+    ///
+    /// `None` if nothing is packed.
+    pub fn average_packed_size(&self) -> Option<u64> {
+        (self.in_pack > 0).then(|| self.size_pack / self.in_pack as u64)
+    }
+}
+
+/// Count the loose and packed objects of a repository
+///
+/// Loose objects are found by scanning `objects/xx/` directories; packed
+/// objects are counted from the pack indexes. Files in `objects/pack` that
+/// don't belong to a complete pack are reported as garbage.
+pub fn count_objects(repo: &Repository) -> Result<ObjectCounts> {
+    let objects_dir = repo.path().join("objects");
+    let mut counts = ObjectCounts::default();
+
+    // Loose objects live in the 256 fan-out directories
+    let mut loose = Vec::new();
+    let entries = std::fs::read_dir(&objects_dir)
+        .map_err(|e| io_err(format!("Failed to read object directory: {}", e), &objects_dir))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.len() != 2 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        let fanout = match std::fs::read_dir(entry.path()) {
+            Ok(fanout) => fanout,
+            Err(e) => {
+                log::warn!("Skipping unreadable object directory {}: {}", entry.path().display(), e);
+                continue;
+            }
+        };
+        for object in fanout.flatten() {
+            let suffix = object.file_name().to_string_lossy().to_string();
+            let id = match ObjectId::from_hex(format!("{}{}", name, suffix).as_bytes()) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            counts.count += 1;
+            counts.size += object.metadata().map(|m| m.len()).unwrap_or(0);
+            loose.push(id);
+        }
+    }
+
+    // Packs are only counted when both the .pack and its .idx are present
+    let pack_dir = objects_dir.join("pack");
+    let mut indexes = Vec::new();
+    if pack_dir.is_dir() {
+        let entries = std::fs::read_dir(&pack_dir)
+            .map_err(|e| io_err(format!("Failed to read pack directory: {}", e), &pack_dir))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            let companion = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("pack") => Some(path.with_extension("idx")),
+                Some("idx") => Some(path.with_extension("pack")),
+                Some("keep") | Some("bitmap") | Some("rev") | Some("promisor") | Some("mtimes") => continue,
+                _ => None,
+            };
+
+            match companion {
+                Some(companion) if companion.is_file() => {
+                    counts.size_pack += len;
+                    if path.extension().map_or(false, |ext| ext == "idx") {
+                        let index = gix::odb::pack::index::File::at(&path, repo.object_hash())
+                            .map_err(|e| repo_err(format!("Failed to read pack index: {}", e), &path))?;
+                        counts.packs += 1;
+                        counts.in_pack += index.num_objects() as usize;
+                        indexes.push(index);
+                    }
+                },
+                _ => {
+                    log::debug!("Garbage found in pack directory: {}", path.display());
+                    counts.garbage += 1;
+                    counts.size_garbage += len;
+                }
+            }
+        }
+    }
+
+    counts.prune_packable = loose.iter()
+        .filter(|id| indexes.iter().any(|index| index.lookup(id.as_ref()).is_some()))
+        .count();
+
+    Ok(counts)
+}
@@ -0,0 +1,769 @@
+//! Parsing and applying unified diffs and mailed patch series
+//!
+//! Backs `apply` and `am`, for contributions that arrive outside the
+//! repository's own transport. Patches are applied all-or-nothing: every
+//! file is patched in memory first, and nothing is written unless all of
+//! them apply.
+
+use std::path::Path;
+
+use gix::Repository;
+
+use crate::core::{GitError, Result, io_err, repo_err};
+
+/// One line of a hunk, with its line ending unless the patch marks it as
+/// having none
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkLine {
+    /// Unchanged line, present on both sides
+    Context(String),
+    /// Line only in the old version
+    Remove(String),
+    /// Line only in the new version
+    Add(String),
+}
+
+impl HunkLine {
+    fn text_mut(&mut self) -> &mut String {
+        match self {
+            HunkLine::Context(text) | HunkLine::Remove(text) | HunkLine::Add(text) => text,
+        }
+    }
+}
+
+/// A `@@ -a,b +c,d @@` section of a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchHunk {
+    /// First line of the old version covered by the hunk (1-based)
+    pub old_start: usize,
+    /// Number of old lines covered
+    pub old_lines: usize,
+    /// First line of the new version covered by the hunk (1-based)
+    pub new_start: usize,
+    /// Number of new lines covered
+    pub new_lines: usize,
+    /// The hunk body
+    pub lines: Vec<HunkLine>,
+}
+
+impl PatchHunk {
+    fn old_side(&self) -> Vec<&str> {
+        self.lines.iter().filter_map(|line| match line {
+            HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+            HunkLine::Add(_) => None,
+        }).collect()
+    }
+
+    fn new_side(&self) -> Vec<&str> {
+        self.lines.iter().filter_map(|line| match line {
+            HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+            HunkLine::Remove(_) => None,
+        }).collect()
+    }
+}
+
+/// The changes a patch makes to one file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchFile {
+    /// Path before the change; `None` for a new file
+    pub old_path: Option<String>,
+    /// Path after the change; `None` for a deleted file
+    pub new_path: Option<String>,
+    /// Mode of a new file, or the new mode of a changed one
+    pub new_mode: Option<u32>,
+    /// Whether the patch carries binary data, which can't be applied
+    pub binary: bool,
+    /// Text changes, in file order
+    pub hunks: Vec<PatchHunk>,
+}
+
+impl PatchFile {
+    /// Path the change is reported under
+    pub fn path(&self) -> &str {
+        self.new_path.as_deref().or(self.old_path.as_deref()).unwrap_or("")
+    }
+}
+
+/// What applying a patch does to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchAction {
+    /// The file is created
+    Create,
+    /// The file's content changes
+    Modify,
+    /// The file is removed
+    Delete,
+}
+
+/// A file changed (or, when checking, that would be changed) by a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedFile {
+    /// Worktree-relative path
+    pub path: String,
+    /// The change made
+    pub action: PatchAction,
+    /// Lines added
+    pub added: usize,
+    /// Lines removed
+    pub removed: usize,
+}
+
+/// One message of a patch series, as written by `git format-patch`
+#[derive(Debug, Clone)]
+pub struct MailPatch {
+    /// Author name from the `From:` header
+    pub author_name: String,
+    /// Author email from the `From:` header
+    pub author_email: String,
+    /// Authoring time from the `Date:` header
+    pub date: gix_date::Time,
+    /// Subject without the `[PATCH n/m]` prefix
+    pub subject: String,
+    /// Full commit message: subject, blank line, body
+    pub message: String,
+    /// The diff part of the message
+    pub diff: String,
+}
+
+/// Parse a unified diff, with or without git's extended headers
+pub fn parse_patch(text: &str) -> Result<Vec<PatchFile>> {
+    let mut files: Vec<PatchFile> = Vec::new();
+    let mut current: Option<PatchFile> = None;
+    let mut lines = text.split_inclusive('\n').peekable();
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_end_matches(['\n', '\r']);
+        if let Some(rest) = trimmed.strip_prefix("diff --git ") {
+            files.extend(current.take());
+            let mut file = PatchFile::default();
+            if let Some((old, new)) = split_git_paths(rest) {
+                file.old_path = Some(old);
+                file.new_path = Some(new);
+            }
+            current = Some(file);
+        } else if let Some(mode) = trimmed.strip_prefix("new file mode ") {
+            if let Some(file) = current.as_mut() {
+                file.old_path = None;
+                file.new_mode = u32::from_str_radix(mode.trim(), 8).ok();
+            }
+        } else if trimmed.starts_with("deleted file mode ") {
+            if let Some(file) = current.as_mut() {
+                file.new_path = None;
+            }
+        } else if let Some(mode) = trimmed.strip_prefix("new mode ") {
+            if let Some(file) = current.as_mut() {
+                file.new_mode = u32::from_str_radix(mode.trim(), 8).ok();
+            }
+        } else if let Some(path) = trimmed.strip_prefix("rename from ") {
+            if let Some(file) = current.as_mut() {
+                file.old_path = Some(path.to_string());
+            }
+        } else if let Some(path) = trimmed.strip_prefix("rename to ") {
+            if let Some(file) = current.as_mut() {
+                file.new_path = Some(path.to_string());
+            }
+        } else if trimmed == "GIT binary patch" || (trimmed.starts_with("Binary files ") && trimmed.ends_with(" differ")) {
+            if let Some(file) = current.as_mut() {
+                file.binary = true;
+            }
+        } else if let Some(path) = trimmed.strip_prefix("--- ") {
+            // A plain diff starts its file here; so does a git diff whose
+            // previous file already had hunks
+            let starts_file = match &current {
+                Some(file) => !file.hunks.is_empty(),
+                None => true,
+            };
+            if starts_file {
+                files.extend(current.take());
+                current = Some(PatchFile::default());
+            }
+            if let Some(file) = current.as_mut() {
+                file.old_path = patch_path(path);
+            }
+        } else if let Some(path) = trimmed.strip_prefix("+++ ") {
+            if let Some(file) = current.as_mut() {
+                file.new_path = patch_path(path);
+            }
+        } else if trimmed.starts_with("@@ ") {
+            let file = match current.as_mut() {
+                Some(file) => file,
+                None => return Err(GitError::InvalidArgument(format!("Hunk without a file header: {}", trimmed))),
+            };
+            let mut hunk = parse_hunk_header(trimmed)?;
+            let (mut old_left, mut new_left) = (hunk.old_lines, hunk.new_lines);
+            while old_left > 0 || new_left > 0 {
+                let body = match lines.next() {
+                    Some(body) => body,
+                    None => break,
+                };
+                let (marker, text) = match body.char_indices().nth(1) {
+                    Some((index, _)) => body.split_at(index),
+                    // Mailers often strip the space of an empty context line
+                    None => (" ", "\n"),
+                };
+                match marker {
+                    " " => {
+                        hunk.lines.push(HunkLine::Context(text.to_string()));
+                        old_left = old_left.saturating_sub(1);
+                        new_left = new_left.saturating_sub(1);
+                    },
+                    "-" => {
+                        hunk.lines.push(HunkLine::Remove(text.to_string()));
+                        old_left = old_left.saturating_sub(1);
+                    },
+                    "+" => {
+                        hunk.lines.push(HunkLine::Add(text.to_string()));
+                        new_left = new_left.saturating_sub(1);
+                    },
+                    "\\" => strip_last_newline(&mut hunk),
+                    _ => return Err(GitError::InvalidArgument(format!(
+                        "Malformed line in hunk of {}: {}", file.path(), body.trim_end()
+                    ))),
+                }
+            }
+            if old_left > 0 || new_left > 0 {
+                return Err(GitError::InvalidArgument(format!("Truncated hunk in patch of {}", file.path())));
+            }
+            // "\ No newline at end of file" for the hunk's last line
+            if lines.peek().map_or(false, |next| next.starts_with('\\')) {
+                lines.next();
+                strip_last_newline(&mut hunk);
+            }
+            file.hunks.push(hunk);
+        }
+    }
+    files.extend(current.take());
+    files.retain(|file| file.old_path.is_some() || file.new_path.is_some());
+    Ok(files)
+}
+
+fn strip_last_newline(hunk: &mut PatchHunk) {
+    if let Some(line) = hunk.lines.last_mut() {
+        let text = line.text_mut();
+        if text.ends_with('\n') {
+            text.pop();
+            if text.ends_with('\r') {
+                text.pop();
+            }
+        }
+    }
+}
+
+/// Split `a/<old> b/<new>` from a `diff --git` line
+fn split_git_paths(rest: &str) -> Option<(String, String)> {
+    let rest = rest.strip_prefix("a/")?;
+    let split = rest.find(" b/")?;
+    Some((rest[..split].to_string(), rest[split + 3..].to_string()))
+}
+
+/// Path from a `---`/`+++` line, without the `a/`/`b/` prefix and any
+/// timestamp; `None` for `/dev/null`
+fn patch_path(raw: &str) -> Option<String> {
+    let path = raw.split('\t').next().unwrap_or(raw).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path.strip_prefix("a/").or_else(|| path.strip_prefix("b/")).unwrap_or(path);
+    Some(path.to_string())
+}
+
+/// Parse `@@ -a[,b] +c[,d] @@`
+fn parse_hunk_header(line: &str) -> Result<PatchHunk> {
+    let invalid = || GitError::InvalidArgument(format!("Invalid hunk header: {}", line));
+    let mut parts = line.split_whitespace().skip(1);
+    let range = |part: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let part = part?.strip_prefix(sign)?;
+        match part.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((part.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_lines) = range(parts.next(), '-').ok_or_else(invalid)?;
+    let (new_start, new_lines) = range(parts.next(), '+').ok_or_else(invalid)?;
+    Ok(PatchHunk { old_start, old_lines, new_start, new_lines, lines: Vec::new() })
+}
+
+/// Apply hunks to `original`
+///
+/// Each hunk is looked for at its recorded position first, then ever
+/// further away, like `git apply` without fuzz. Fails with the 1-based
+/// number of the first hunk whose old lines can't be found.
+pub fn apply_hunks(original: &str, hunks: &[PatchHunk]) -> std::result::Result<String, usize> {
+    let lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let mut result = String::with_capacity(original.len());
+    let mut next = 0;
+    let mut drift: isize = 0;
+
+    for (number, hunk) in hunks.iter().enumerate() {
+        let old = hunk.old_side();
+        // A pure insertion records the line it goes after
+        let recorded = if hunk.old_lines == 0 { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+        let expected = ((recorded as isize + drift).max(next as isize) as usize).min(lines.len());
+
+        let fits = |at: usize| at + old.len() <= lines.len() && lines[at..at + old.len()] == old[..];
+        let found = (0..=lines.len()).find_map(|distance| {
+            let after = expected + distance;
+            if after <= lines.len() && fits(after) {
+                return Some(after);
+            }
+            match expected.checked_sub(distance) {
+                Some(before) if before >= next && fits(before) => Some(before),
+                _ => None,
+            }
+        });
+        let at = match found {
+            Some(at) => at,
+            None => return Err(number + 1),
+        };
+
+        result.extend(lines[next..at].iter().copied());
+        result.extend(hunk.new_side());
+        next = at + old.len();
+        drift = at as isize - recorded as isize;
+    }
+    result.extend(lines[next..].iter().copied());
+    Ok(result)
+}
+
+/// Apply a patch to the working tree, and to the index with `update_index`
+///
+/// With `check`, nothing is written; the result says what would change.
+/// Fails with [`GitError::PatchConflict`] naming every file that doesn't
+/// apply, in which case nothing is written either.
+pub fn apply_patch(repo: &Repository, patch: &str, update_index: bool, check: bool) -> Result<Vec<AppliedFile>> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot apply patches in a bare repository", &repo_path))?
+        .to_path_buf();
+    let files = parse_patch(patch)?;
+    if files.is_empty() {
+        return Err(GitError::InvalidArgument("No changes found in patch".to_string()));
+    }
+    for path in files.iter().flat_map(|file| file.old_path.iter().chain(file.new_path.iter())) {
+        check_patch_path(&workdir, path)?;
+    }
+
+    // Patch everything in memory before touching the worktree
+    let mut results: Vec<(AppliedFile, Option<String>, Option<u32>)> = Vec::new();
+    let mut conflicts = Vec::new();
+    for file in &files {
+        let path = file.path().to_string();
+        if file.binary {
+            conflicts.push(format!("{}: binary patches are not supported", path));
+            continue;
+        }
+        let original = match &file.old_path {
+            Some(old_path) => match std::fs::read(workdir.join(old_path)) {
+                Ok(data) => match String::from_utf8(data) {
+                    Ok(text) => text,
+                    Err(_) => {
+                        conflicts.push(format!("{}: file is not UTF-8 text", old_path));
+                        continue;
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    conflicts.push(format!("{}: does not exist in the working tree", old_path));
+                    continue;
+                },
+                Err(e) => return Err(io_err(format!("Failed to read {}: {}", old_path, e), workdir.join(old_path))),
+            },
+            None => {
+                if workdir.join(&path).exists() {
+                    conflicts.push(format!("{}: already exists in the working tree", path));
+                    continue;
+                }
+                String::new()
+            },
+        };
+
+        let patched = match apply_hunks(&original, &file.hunks) {
+            Ok(patched) => patched,
+            Err(hunk) => {
+                conflicts.push(format!("{}: hunk #{} does not apply", path, hunk));
+                continue;
+            }
+        };
+        let (added, removed) = file.hunks.iter().flat_map(|hunk| &hunk.lines).fold((0, 0), |(added, removed), line| {
+            match line {
+                HunkLine::Add(_) => (added + 1, removed),
+                HunkLine::Remove(_) => (added, removed + 1),
+                HunkLine::Context(_) => (added, removed),
+            }
+        });
+
+        if file.new_path.is_none() {
+            if !patched.is_empty() {
+                conflicts.push(format!("{}: deleted file still has content after patching", path));
+                continue;
+            }
+            results.push((AppliedFile { path, action: PatchAction::Delete, added, removed }, None, None));
+            continue;
+        }
+        // A rename removes the old path and creates the new one
+        if let (Some(old_path), Some(new_path)) = (&file.old_path, &file.new_path) {
+            if old_path != new_path {
+                results.push((AppliedFile { path: old_path.clone(), action: PatchAction::Delete, added: 0, removed: 0 }, None, None));
+                results.push((AppliedFile { path: new_path.clone(), action: PatchAction::Create, added, removed }, Some(patched), file.new_mode));
+                continue;
+            }
+        }
+        let action = if file.old_path.is_none() { PatchAction::Create } else { PatchAction::Modify };
+        results.push((AppliedFile { path, action, added, removed }, Some(patched), file.new_mode));
+    }
+    if !conflicts.is_empty() {
+        return Err(GitError::PatchConflict(conflicts));
+    }
+    if check {
+        return Ok(results.into_iter().map(|(applied, _, _)| applied).collect());
+    }
+
+    // Take the index lock before writing anything, so a concurrent
+    // operation fails this apply rather than interleaving with it
+    let index_lock = if update_index { Some(crate::core::lock_index(repo)?) } else { None };
+
+    for (applied, content, mode) in &results {
+        let file = workdir.join(&applied.path);
+        match content {
+            Some(content) => write_worktree_file(&file, content.as_bytes(), *mode)?,
+            None => std::fs::remove_file(&file)
+                .map_err(|e| io_err(format!("Failed to remove {}: {}", applied.path, e), &file))?,
+        }
+    }
+
+    if let Some(index_lock) = index_lock {
+        let mut index = repo.open_index()
+            .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+        for (applied, content, mode) in &results {
+            let path = applied.path.as_str();
+            index.remove_entries(|_, entry_path, _| entry_path == path);
+            if let Some(content) = content {
+                let blob_id = repo.write_blob(content.as_bytes())
+                    .map_err(|e| GitError::ObjectStorage(format!("Failed to write blob for {}: {}", path, e)))?
+                    .detach();
+                let mode = if mode == &Some(0o100755) {
+                    gix::index::entry::Mode::FILE_EXECUTABLE
+                } else {
+                    gix::index::entry::Mode::FILE
+                };
+                index.dangerously_push_entry(Default::default(), blob_id, gix::index::entry::Flags::empty(), mode, path.into());
+            }
+        }
+        index.sort_entries();
+        crate::core::write_index(index_lock, &index)?;
+    }
+
+    Ok(results.into_iter().map(|(applied, _, _)| applied).collect())
+}
+
+/// Refuse paths that would leave the working tree or reach into `.git`
+///
+/// The same rule git applies to index paths: relative, with no empty, `.`,
+/// `..` or `.git` components. Like `git apply`, paths beyond a symbolic
+/// link in `workdir` are refused too, since writing them would follow it.
+fn check_patch_path(workdir: &Path, path: &str) -> Result<()> {
+    let valid = !path.is_empty()
+        && !Path::new(path).is_absolute()
+        && !path.contains(['\\', '\0'])
+        && path.split('/').all(|component| {
+            !component.is_empty() && component != "." && component != ".." && !component.eq_ignore_ascii_case(".git")
+        });
+    if !valid {
+        return Err(GitError::InvalidArgument(format!("Refusing to apply a patch to '{}', which is outside the working tree", path)));
+    }
+
+    // Directories that don't exist yet are created, so only existing
+    // leading components can be links
+    let mut parent = workdir.to_path_buf();
+    for component in path.split('/').take(path.split('/').count() - 1) {
+        parent.push(component);
+        match std::fs::symlink_metadata(&parent) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(GitError::InvalidArgument(format!("Refusing to apply a patch to '{}', which is beyond a symbolic link", path)));
+            },
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(io_err(format!("Failed to inspect directory: {}", e), &parent)),
+        }
+    }
+    Ok(())
+}
+
+fn write_worktree_file(file: &Path, content: &[u8], mode: Option<u32>) -> Result<()> {
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| io_err(format!("Failed to create directory: {}", e), parent))?;
+    }
+    std::fs::write(file, content)
+        .map_err(|e| io_err(format!("Failed to write file: {}", e), file))?;
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = if mode == 0o100755 { 0o755 } else { 0o644 };
+        std::fs::set_permissions(file, std::fs::Permissions::from_mode(permissions))
+            .map_err(|e| io_err(format!("Failed to set file mode: {}", e), file))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+/// Whether `line` starts a message, as `From <commit id> <date>`
+///
+/// Other lines starting with "From " are message text.
+fn is_mbox_separator(line: &str) -> bool {
+    match line.strip_prefix("From ").and_then(|rest| rest.split_once(' ')) {
+        Some((id, date)) => {
+            matches!(id.len(), 40 | 64) && id.bytes().all(|b| b.is_ascii_hexdigit()) && !date.trim().is_empty()
+        },
+        None => false,
+    }
+}
+
+/// Undo the `>From ` quoting of message lines, as `git mailsplit --mboxrd` does
+fn unescape_mbox_line(line: &str) -> &str {
+    if line.trim_start_matches('>').starts_with("From ") && line.starts_with('>') {
+        &line[1..]
+    } else {
+        line
+    }
+}
+
+/// Split an mbox written by `git format-patch` into its patches
+pub fn parse_mbox(text: &str) -> Result<Vec<MailPatch>> {
+    let mut messages: Vec<Vec<&str>> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        if is_mbox_separator(line) || messages.is_empty() {
+            messages.push(Vec::new());
+        }
+        if let Some(message) = messages.last_mut() {
+            message.push(line);
+        }
+    }
+
+    let mut patches = Vec::new();
+    for message in messages {
+        let mut lines = message.into_iter().skip_while(|line| is_mbox_separator(line));
+        let mut headers: Vec<String> = Vec::new();
+        for line in lines.by_ref() {
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                break;
+            }
+            // Folded header lines continue the previous header
+            match headers.last_mut() {
+                Some(last) if line.starts_with([' ', '\t']) => {
+                    last.push(' ');
+                    last.push_str(line.trim_start());
+                },
+                _ => headers.push(line.to_string()),
+            }
+        }
+        let header = |name: &str| headers.iter()
+            .find_map(|h| h.split_once(':').filter(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.trim().to_string()));
+
+        let from = match header("From") {
+            Some(from) => from,
+            // Text before the first message, such as a cover note
+            None => continue,
+        };
+        let (author_name, author_email) = parse_address(&from)
+            .ok_or_else(|| GitError::InvalidArgument(format!("Invalid From header: {}", from)))?;
+        let date = match header("Date") {
+            Some(date) => chrono::DateTime::parse_from_rfc2822(&date)
+                .map(|date| gix_date::Time::new(date.timestamp() as _, date.offset().local_minus_utc()))
+                .map_err(|e| GitError::InvalidArgument(format!("Invalid Date header '{}': {}", date, e)))?,
+            None => gix_date::Time::now_local_or_utc(),
+        };
+        let subject = strip_patch_prefix(&header("Subject").unwrap_or_default());
+
+        // The message body runs up to the `---` before the diffstat or the diff itself
+        let rest: Vec<&str> = lines.map(unescape_mbox_line).collect();
+        let split = rest.iter()
+            .position(|line| line.trim_end() == "---" || line.starts_with("diff --git ") || line.starts_with("Index: "))
+            .unwrap_or(rest.len());
+        let body = rest[..split].concat();
+        let body = body.trim();
+        let diff = rest[split..].concat();
+
+        let message = if body.is_empty() {
+            format!("{}\n", subject)
+        } else {
+            format!("{}\n\n{}\n", subject, body)
+        };
+        patches.push(MailPatch { author_name, author_email, date, subject, message, diff });
+    }
+    Ok(patches)
+}
+
+/// Split `Name <email>` (the name may be quoted or missing)
+fn parse_address(from: &str) -> Option<(String, String)> {
+    match (from.rfind('<'), from.rfind('>')) {
+        (Some(open), Some(close)) if open < close => {
+            let name = from[..open].trim().trim_matches('"').to_string();
+            let email = from[open + 1..close].trim().to_string();
+            let name = if name.is_empty() { email.clone() } else { name };
+            Some((name, email))
+        },
+        _ if from.contains('@') => Some((from.to_string(), from.to_string())),
+        _ => None,
+    }
+}
+
+/// Drop a leading `[PATCH ...]` tag from a subject
+fn strip_patch_prefix(subject: &str) -> String {
+    let subject = subject.trim();
+    match subject.strip_prefix('[') {
+        Some(rest) if rest.to_ascii_uppercase().starts_with("PATCH") => {
+            rest.split_once(']').map_or(subject, |(_, title)| title.trim()).to_string()
+        },
+        _ => subject.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "\
+diff --git a/file.txt b/file.txt
+index 1111111..2222222 100644
+--- a/file.txt
++++ b/file.txt
+@@ -2,3 +2,3 @@ one
+ two
+-three
++THREE
+ four
+@@ -8,2 +8,3 @@ seven
+ eight
+ nine
++ten
+\\ No newline at end of file
+diff --git a/new.txt b/new.txt
+new file mode 100644
+index 0000000..3333333
+--- /dev/null
++++ b/new.txt
+@@ -0,0 +1 @@
++hello
+";
+
+    #[test]
+    fn test_parse_and_apply_with_offset() {
+        let files = parse_patch(PATCH).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path(), "file.txt");
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[1].old_path, None);
+        assert_eq!(files[1].new_mode, Some(0o100644));
+
+        // Two extra lines at the top move every hunk down
+        let original = "zero\nzero\none\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine";
+        let patched = apply_hunks(original, &files[0].hunks).unwrap();
+        assert_eq!(patched, "zero\nzero\none\ntwo\nTHREE\nfour\nfive\nsix\nseven\neight\nnine\nten");
+        assert_eq!(apply_hunks("", &files[1].hunks).unwrap(), "hello\n");
+
+        assert_eq!(apply_hunks("one\ntwo\nchanged\nfour\n", &files[0].hunks), Err(1));
+    }
+
+    #[test]
+    fn test_multibyte_lines() {
+        let patch = "--- a/file.txt\n+++ b/file.txt\n@@ -1,2 +1,2 @@\n ä\n-ö\n+ü\n";
+        let files = parse_patch(patch).unwrap();
+        assert_eq!(files[0].hunks[0].lines, vec![
+            HunkLine::Context("ä\n".to_string()),
+            HunkLine::Remove("ö\n".to_string()),
+            HunkLine::Add("ü\n".to_string()),
+        ]);
+
+        // A line starting with a multibyte character is malformed, not a panic
+        let malformed = "--- a/file.txt\n+++ b/file.txt\n@@ -1 +1 @@\näb\n";
+        assert!(matches!(parse_patch(malformed), Err(GitError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_paths_outside_the_worktree_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("work");
+        let repo = gix::init(&workdir).unwrap();
+
+        for path in ["../outside.txt", "/tmp/arti-git-patch-outside.txt", ".git/hooks/pre-commit", "sub/.GIT/config", "dir//file.txt"] {
+            let patch = format!("--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+pwned\n", path);
+            match apply_patch(&repo, &patch, false, false) {
+                Err(GitError::InvalidArgument(message)) => assert!(message.contains(path), "{}", message),
+                other => panic!("Expected {} to be refused, got {:?}", path, other),
+            }
+        }
+        assert!(!dir.path().join("outside.txt").exists());
+        assert!(!workdir.join(".git/hooks/pre-commit").exists());
+
+        // Renames are checked on both sides
+        std::fs::write(workdir.join("file.txt"), "one\n").unwrap();
+        let rename = "diff --git a/file.txt b/../moved.txt\nrename from file.txt\nrename to ../moved.txt\n";
+        assert!(apply_patch(&repo, rename, false, true).is_err());
+        let rename = "diff --git a/../secret b/stolen.txt\nrename from ../secret\nrename to stolen.txt\n";
+        assert!(apply_patch(&repo, rename, false, true).is_err());
+        assert!(check_patch_path(&workdir, "dir/file.txt").is_ok());
+        assert!(check_patch_path(&workdir, ".gitignore").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_paths_beyond_symlinks_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("work");
+        let outside = dir.path().join("outside");
+        let repo = gix::init(&workdir).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::create_dir(workdir.join("real")).unwrap();
+        std::os::unix::fs::symlink(&outside, workdir.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, workdir.join("real/nested")).unwrap();
+
+        for path in ["link/file.txt", "real/nested/deeper/file.txt"] {
+            let patch = format!("--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+pwned\n", path);
+            match apply_patch(&repo, &patch, false, false) {
+                Err(GitError::InvalidArgument(message)) => assert!(message.contains("symbolic link"), "{}", message),
+                other => panic!("Expected {} to be refused, got {:?}", path, other),
+            }
+        }
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+        assert!(check_patch_path(&workdir, "real/new/file.txt").is_ok());
+    }
+
+    #[test]
+    fn test_parse_mbox() {
+        let mbox = format!("\
+From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001
+From: \"Jane Doe\" <jane@example.com>
+Date: Tue, 3 Oct 2023 12:00:00 +0200
+Subject: [PATCH 1/2] Fix the
+ thing
+
+Longer explanation.
+From the docs it looked right.
+>From here on it is.
+---
+ file.txt | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+{}--
+2.42.0
+", PATCH);
+        let patches = parse_mbox(&mbox).unwrap();
+        assert_eq!(patches.len(), 1);
+        let patch = &patches[0];
+        assert_eq!(patch.author_name, "Jane Doe");
+        assert_eq!(patch.author_email, "jane@example.com");
+        assert_eq!(patch.subject, "Fix the thing");
+        assert_eq!(patch.message, "Fix the thing\n\nLonger explanation.\nFrom the docs it looked right.\nFrom here on it is.\n");
+        assert_eq!(patch.date.offset, 7200);
+        assert_eq!(parse_patch(&patch.diff).unwrap().len(), 2);
+
+        // Only separator lines start another message
+        assert_eq!(parse_mbox(&mbox.repeat(2)).unwrap().len(), 2);
+        assert!(is_mbox_separator("From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001\n"));
+        assert!(!is_mbox_separator("From the docs it looked right.\n"));
+        assert_eq!(unescape_mbox_line(">>From quoted\n"), ">From quoted\n");
+        assert_eq!(unescape_mbox_line("> From a reply\n"), "> From a reply\n");
+    }
+}
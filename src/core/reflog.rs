@@ -0,0 +1,188 @@
+//! Reading reflogs and resolving `<ref>@{<n>}` revisions
+//!
+//! Every ref update made through a gitoxide transaction appends a line to
+//! `.git/logs/<ref>`, which makes the previous values of HEAD and branches
+//! recoverable after a mistaken reset or rebase.
+
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result, io_err};
+
+/// One movement of a ref, as recorded in its reflog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// Value before the update, null if the ref was created
+    pub old: ObjectId,
+    /// Value after the update
+    pub new: ObjectId,
+    /// Who made the update, as `Name <email>`
+    pub committer: String,
+    /// When the update was made, in seconds since the epoch
+    pub time: i64,
+    /// Why the ref moved, e.g. `commit: Add README`
+    pub message: String,
+}
+
+/// Full name of the ref whose reflog `reference` refers to
+///
+/// `HEAD` and full names are kept as they are; short names are looked up as
+/// branches, then remote-tracking branches, then tags.
+pub fn reflog_ref_name(repo: &Repository, reference: &str) -> String {
+    if reference == "HEAD" || reference.starts_with("refs/") {
+        return reference.to_string();
+    }
+    ["refs/heads/", "refs/remotes/", "refs/tags/"].iter()
+        .map(|prefix| format!("{}{}", prefix, reference))
+        .find(|name| reflog_path(repo, name).is_file())
+        .unwrap_or_else(|| format!("refs/heads/{}", reference))
+}
+
+/// Path of the reflog of the ref with full name `name`
+///
+/// Every linked worktree has its own HEAD and HEAD reflog; all other refs
+/// and their reflogs are shared through the common directory.
+pub fn reflog_path(repo: &Repository, name: &str) -> std::path::PathBuf {
+    let dir = if name == "HEAD" { repo.path() } else { repo.common_dir() };
+    dir.join("logs").join(name)
+}
+
+/// Entries of the reflog of `reference`, newest first
+///
+/// Entry `n` is what `<reference>@{n}` resolves to. A ref without a reflog
+/// has no entries.
+pub fn read_reflog(repo: &Repository, reference: &str) -> Result<Vec<ReflogEntry>> {
+    let name = reflog_ref_name(repo, reference);
+    let log_path = reflog_path(repo, &name);
+    let content = match std::fs::read_to_string(&log_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_err(format!("Failed to read reflog of {}: {}", name, e), &log_path)),
+    };
+
+    let mut entries = Vec::new();
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        match parse_reflog_line(line) {
+            Some(entry) => entries.push(entry),
+            None => log::warn!("Skipping malformed reflog line in {}: {}", log_path.display(), line),
+        }
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Parse `<old> <new> <name> <<email>> <time> <tz>\t<message>`
+fn parse_reflog_line(line: &str) -> Option<ReflogEntry> {
+    let (header, message) = match line.split_once('\t') {
+        Some((header, message)) => (header, message),
+        None => (line, ""),
+    };
+    let mut fields = header.splitn(3, ' ');
+    let old = ObjectId::from_hex(fields.next()?.as_bytes()).ok()?;
+    let new = ObjectId::from_hex(fields.next()?.as_bytes()).ok()?;
+    let mut signature = fields.next()?.rsplitn(3, ' ');
+    let _timezone = signature.next()?;
+    let time = signature.next()?.parse().ok()?;
+    let committer = signature.next()?.to_string();
+    Some(ReflogEntry { old, new, committer, time, message: message.to_string() })
+}
+
+/// Split `<ref>@{<n>}<rest>` into the ref, `n` and the rest
+///
+/// An empty ref means HEAD. Anything other than a plain number between the
+/// braces (dates, `@{-1}`, `@{upstream}`) is left to the regular rev parser.
+fn split_reflog_spec(spec: &str) -> Option<(&str, usize, &str)> {
+    let start = spec.find("@{")?;
+    let end = start + spec[start..].find('}')?;
+    let index = spec[start + 2..end].parse().ok()?;
+    let reference = match &spec[..start] {
+        "" | "@" => "HEAD",
+        reference => reference,
+    };
+    Some((reference, index, &spec[end + 1..]))
+}
+
+/// Resolve a revision, including `<ref>@{<n>}` reflog lookups
+///
+/// `HEAD@{1}` is where HEAD pointed before its last move, and suffixes such
+/// as `HEAD@{2}~1` apply to the value found in the reflog. Every other
+/// revision goes through gitoxide's rev parser.
+pub fn resolve_revision(repo: &Repository, spec: &str) -> Result<ObjectId> {
+    let (reference, index, rest) = match split_reflog_spec(spec) {
+        Some(parts) => parts,
+        None => return repo.rev_parse_single(spec)
+            .map(|id| id.detach())
+            .map_err(|e| GitError::InvalidArgument(format!("Invalid revision '{}': {}", spec, e))),
+    };
+
+    let entries = read_reflog(repo, reference)?;
+    let entry = entries.get(index).ok_or_else(|| GitError::InvalidArgument(match entries.len() {
+        0 => format!("No reflog for '{}'", reference),
+        len => format!("Log for '{}' only has {} entries", reference, len),
+    }))?;
+    if rest.is_empty() {
+        return Ok(entry.new);
+    }
+
+    let suffixed = format!("{}{}", entry.new, rest);
+    repo.rev_parse_single(suffixed.as_str())
+        .map(|id| id.detach())
+        .map_err(|e| GitError::InvalidArgument(format!("Invalid revision '{}': {}", spec, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reflog_line() {
+        let line = "0000000000000000000000000000000000000000 3b18e512dba79e4c8300dd08aeb37f8e728b8dad \
+                    A U Thor <author@example.com> 1700000000 +0100\tcommit (initial): First";
+        let entry = parse_reflog_line(line).unwrap();
+        assert!(entry.old.is_null());
+        assert_eq!(entry.new.to_string(), "3b18e512dba79e4c8300dd08aeb37f8e728b8dad");
+        assert_eq!(entry.committer, "A U Thor <author@example.com>");
+        assert_eq!(entry.time, 1700000000);
+        assert_eq!(entry.message, "commit (initial): First");
+
+        assert!(parse_reflog_line("not a reflog line").is_none());
+    }
+
+    #[test]
+    fn test_split_reflog_spec() {
+        assert_eq!(split_reflog_spec("HEAD@{1}"), Some(("HEAD", 1, "")));
+        assert_eq!(split_reflog_spec("@{2}~1"), Some(("HEAD", 2, "~1")));
+        assert_eq!(split_reflog_spec("main@{0}^{tree}"), Some(("main", 0, "^{tree}")));
+        assert_eq!(split_reflog_spec("main@{yesterday}"), None);
+        assert_eq!(split_reflog_spec("@{-1}"), None);
+        assert_eq!(split_reflog_spec("HEAD~2"), None);
+    }
+
+    #[test]
+    fn test_linked_worktree_shares_branch_reflogs() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main");
+        let git = |cwd: &std::path::Path, args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(cwd)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        std::fs::create_dir(&main).unwrap();
+        git(&main, &["init", "-q", "-b", "main"]);
+        git(&main, &["commit", "-q", "--allow-empty", "-m", "First"]);
+        git(&main, &["commit", "-q", "--allow-empty", "-m", "Second"]);
+        git(&main, &["worktree", "add", "-q", "-b", "topic", "../linked"]);
+
+        let linked = gix::open(dir.path().join("linked")).unwrap();
+        assert_ne!(linked.path(), linked.common_dir());
+        assert_eq!(read_reflog(&linked, "main").unwrap().len(), 2);
+        assert_eq!(read_reflog(&linked, "topic").unwrap().len(), 1);
+        // HEAD is the linked worktree's own
+        assert!(reflog_path(&linked, "HEAD").starts_with(linked.path()));
+        assert!(reflog_path(&linked, "refs/heads/topic").starts_with(linked.common_dir()));
+    }
+}
@@ -0,0 +1,230 @@
+//! Adding, removing and re-pointing remotes in `.git/config`
+//!
+//! Onion URLs are checked before anything is written, so a typo in a 56
+//! character address fails here instead of as a descriptor lookup timeout
+//! on the next fetch. Tor settings given with a remote are stored as
+//! `tor.<url>.*`, where the transport picks them up for that URL (see
+//! `transport::urlmatch`).
+
+use gix::Repository;
+
+use crate::core::{GitError, Result, io_err, repo_err};
+use crate::core::{LockFile, lock_path};
+use crate::utils;
+
+/// Tor settings to store along with a new remote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteTorSettings {
+    /// Open the remote's streams on circuits of their own (`tor.<url>.isolate`)
+    pub isolate: Option<bool>,
+    /// Hex fingerprint the remote must present (`tor.<url>.fingerprint`)
+    pub fingerprint: Option<String>,
+}
+
+/// Add remote `name` fetching all branches from `url`
+pub fn add_remote(repo: &Repository, name: &str, url: &str, tor: &RemoteTorSettings) -> Result<()> {
+    check_remote_name(name)?;
+    check_remote_url(url)?;
+    if repo.find_remote(name).is_ok() {
+        return Err(GitError::InvalidArgument(format!("Remote '{}' already exists", name)));
+    }
+    let fingerprint = tor.fingerprint.as_deref().map(normalize_fingerprint).transpose()?;
+
+    update_local_config(repo, |config| {
+        set_value(config, "remote", Some(name), "url", url)?;
+        set_value(config, "remote", Some(name), "fetch", &format!("+refs/heads/*:refs/remotes/{}/*", name))?;
+        if let Some(isolate) = tor.isolate {
+            set_value(config, "tor", Some(url), "isolate", if isolate { "true" } else { "false" })?;
+        }
+        if let Some(fingerprint) = &fingerprint {
+            set_value(config, "tor", Some(url), "fingerprint", fingerprint)?;
+        }
+        Ok(())
+    })
+}
+
+/// Point remote `name` at `url`
+pub fn set_remote_url(repo: &Repository, name: &str, url: &str) -> Result<()> {
+    check_remote_url(url)?;
+    if repo.find_remote(name).is_err() {
+        return Err(GitError::InvalidArgument(format!("No such remote: '{}'", name)));
+    }
+    update_local_config(repo, |config| set_value(config, "remote", Some(name), "url", url))
+}
+
+/// Remove remote `name` and its remote-tracking refs
+///
+/// Branches tracking the remote lose their upstream (`branch.<name>.remote`
+/// and `.merge`), like with `git remote remove`. `tor.<url>.*` settings are
+/// kept, since other remotes may share the URL. Returns the names of the
+/// deleted refs.
+pub fn remove_remote(repo: &Repository, name: &str) -> Result<Vec<String>> {
+    let repo_path = repo.path().to_path_buf();
+    if repo.find_remote(name).is_err() {
+        return Err(GitError::InvalidArgument(format!("No such remote: '{}'", name)));
+    }
+
+    let prefix = format!("refs/remotes/{}/", name);
+    let references = repo.references()
+        .map_err(|e| repo_err(format!("Failed to read references: {}", e), &repo_path))?;
+    let mut tracking = Vec::new();
+    for reference in references.prefixed(prefix.as_str())
+        .map_err(|e| repo_err(format!("Failed to read remote-tracking refs: {}", e), &repo_path))?
+    {
+        let reference = reference
+            .map_err(|e| repo_err(format!("Failed to read reference: {}", e), &repo_path))?;
+        tracking.push(reference.name().to_owned());
+    }
+    let deleted = tracking.iter().map(|name| name.as_bstr().to_string()).collect();
+    let edits = tracking.into_iter().map(|name| gix::refs::transaction::RefEdit {
+        change: gix::refs::transaction::Change::Delete {
+            expected: gix::refs::transaction::PreviousValue::Any,
+            log: gix::refs::transaction::RefLog::AndReference,
+        },
+        name,
+        deref: false,
+    });
+    crate::core::edit_references(repo, edits)?;
+
+    update_local_config(repo, |config| {
+        config.remove_section("remote", Some(gix::bstr::BStr::new(name)));
+        let names_remote = |value: Option<std::borrow::Cow<'_, gix::bstr::BStr>>| value.map_or(false, |value| *value == *name);
+        let branches: Vec<_> = config.sections_and_ids_by_name("branch")
+            .into_iter()
+            .flatten()
+            .map(|(section, id)| (id, names_remote(section.value("remote")), names_remote(section.value("pushRemote"))))
+            .filter(|(_, upstream, push)| *upstream || *push)
+            .collect();
+        for (id, upstream, push) in branches {
+            let mut section = config.section_mut_by_id(id)
+                .map_err(|e| GitError::Config(format!("Failed to update branch config: {}", e)))?;
+            if upstream {
+                section.remove("remote");
+                section.remove("merge");
+            }
+            if push {
+                section.remove("pushRemote");
+            }
+        }
+        Ok(())
+    })?;
+    Ok(deleted)
+}
+
+/// Remote names become ref path components, so they follow the same rules
+fn check_remote_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.contains('/')
+        && gix::refs::FullName::try_from(format!("refs/remotes/{}/HEAD", name)).is_ok();
+    if valid {
+        Ok(())
+    } else {
+        Err(GitError::InvalidArgument(format!("'{}' is not a valid remote name", name)))
+    }
+}
+
+/// Reject malformed onion addresses; other URLs are left to the transports
+///
+/// Only the host is checked, so `.onion` in a path doesn't count.
+fn check_remote_url(url: &str) -> Result<()> {
+    if url.trim().is_empty() {
+        return Err(GitError::InvalidArgument("Remote URL is empty".to_string()));
+    }
+    let parsed = gix::url::parse(url.into())
+        .map_err(|e| GitError::InvalidArgument(format!("Invalid remote URL '{}': {}", url, e)))?;
+    if let Some(host) = parsed.host() {
+        utils::check_onion_host(host)?;
+    }
+    Ok(())
+}
+
+/// Lower-case hex, with the `:` and space separators people paste removed
+fn normalize_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex: String = fingerprint.chars().filter(|c| !matches!(c, ':' | ' ')).collect::<String>().to_ascii_lowercase();
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(GitError::InvalidArgument(format!("Fingerprint '{}' is not hexadecimal", fingerprint)));
+    }
+    Ok(hex)
+}
+
+pub(crate) fn set_value(config: &mut gix_config::File<'static>, section: &str, subsection: Option<&str>, key: &str, value: &str) -> Result<()> {
+    config.set_raw_value(section, subsection.map(Into::into), key, value)
+        .map(|_| ())
+        .map_err(|e| GitError::Config(format!("Failed to set {}.{}: {}", section, key, e)))
+}
+
+/// Read `.git/config`, change it and write it back
+///
+/// The new content goes to `config.lock`, which is renamed over the config
+/// once complete, so a failed write never leaves a truncated config behind
+/// and concurrent writers fail with [`GitError::Locked`].
+pub(crate) fn update_local_config(repo: &Repository, change: impl FnOnce(&mut gix_config::File<'static>) -> Result<()>) -> Result<()> {
+    let config_path = repo.path().join("config");
+    let mut lock = LockFile::acquire(&config_path)?;
+    let mut config = gix_config::File::from_path_no_includes(config_path.clone(), gix_config::Source::Local)
+        .map_err(|e| GitError::Config(format!("Failed to read {}: {}", config_path.display(), e)))?;
+    change(&mut config)?;
+
+    config.write_to(lock.file_mut())
+        .map_err(|e| io_err(format!("Failed to write config: {}", e), lock_path(&config_path)))?;
+    lock.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_validation() {
+        assert!(check_remote_name("origin").is_ok());
+        assert!(check_remote_name("mirror-2").is_ok());
+        assert!(check_remote_name("").is_err());
+        assert!(check_remote_name("a/b").is_err());
+        assert!(check_remote_name("bad..name").is_err());
+
+        let onion = "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion";
+        assert!(check_remote_url(&format!("tor+git://{}/repo.git", onion)).is_ok());
+        assert!(check_remote_url("tor+git://tooshort.onion/repo.git").is_err());
+        assert!(check_remote_url("/srv/git/repo.git").is_ok());
+        assert!(check_remote_url(" ").is_err());
+        assert!(check_remote_url("https://example.com/notes.onion/repo.git").is_ok());
+        assert!(check_remote_url("git@TOOSHORT.ONION:repo.git").is_err());
+        assert!(check_remote_url(&format!("git@{}:repo.git", onion)).is_ok());
+
+        assert_eq!(normalize_fingerprint("AB:cd 01").unwrap(), "abcd01");
+        assert!(normalize_fingerprint("xyz").is_err());
+    }
+
+    fn git(dir: &std::path::Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_remove_remote_clears_upstreams() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["remote", "add", "origin", "https://example.com/repo.git"]);
+        git(dir.path(), &["remote", "add", "mirror", "https://example.com/mirror.git"]);
+        git(dir.path(), &["config", "branch.main.remote", "origin"]);
+        git(dir.path(), &["config", "branch.main.merge", "refs/heads/main"]);
+        git(dir.path(), &["config", "branch.main.pushRemote", "mirror"]);
+        git(dir.path(), &["config", "branch.dev.remote", "mirror"]);
+        git(dir.path(), &["config", "branch.dev.merge", "refs/heads/dev"]);
+
+        let repo = gix::open(dir.path()).unwrap();
+        remove_remote(&repo, "origin").unwrap();
+
+        assert_eq!(git(dir.path(), &["config", "--get", "branch.main.remote"]), "");
+        assert_eq!(git(dir.path(), &["config", "--get", "branch.main.merge"]), "");
+        assert_eq!(git(dir.path(), &["config", "--get", "branch.main.pushRemote"]), "mirror");
+        assert_eq!(git(dir.path(), &["config", "--get", "branch.dev.remote"]), "mirror");
+        assert_eq!(git(dir.path(), &["config", "--get", "branch.dev.merge"]), "refs/heads/dev");
+        assert_eq!(git(dir.path(), &["remote"]), "mirror");
+        assert!(!dir.path().join(".git/config.lock").exists());
+    }
+}
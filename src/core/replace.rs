@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use gix::Repository;
+use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result};
+
+/// Environment variable that disables replacement refs, as in git
+pub const NO_REPLACE_OBJECTS_ENV: &str = "GIT_NO_REPLACE_OBJECTS";
+
+/// Namespace holding replacement refs
+pub const REPLACE_REF_PREFIX: &str = "refs/replace/";
+
+/// Refspec used to mirror replacement refs along with branches
+pub const REPLACE_REFSPEC: &str = "+refs/replace/*:refs/replace/*";
+
+/// Replacement chains longer than this are treated as an error, like git
+const MAX_REPLACE_DEPTH: usize = 5;
+
+/// Check whether replacement refs should be honoured for a repository
+///
+/// They are ignored when `GIT_NO_REPLACE_OBJECTS` is set (which
+/// `--no-replace-objects` does) or `core.useReplaceRefs` is false.
+pub fn replace_objects_enabled(repo: &Repository) -> bool {
+    if std::env::var_os(NO_REPLACE_OBJECTS_ENV).is_some() {
+        return false;
+    }
+
+    repo.config_snapshot()
+        .boolean("core.useReplaceRefs")
+        .unwrap_or(true)
+}
+
+/// Object substitutions taken from `refs/replace/<original>` refs
+///
+/// Reading an object through [`Replacements::resolve`] returns the id of its
+/// replacement, so history views see the same commits git would show.
+#[derive(Debug, Clone, Default)]
+pub struct Replacements {
+    map: HashMap<ObjectId, ObjectId>,
+}
+
+impl Replacements {
+    /// A replacement set that never substitutes anything
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Load the replacement refs of a repository, unless replacement is disabled
+    pub fn load(repo: &Repository) -> Result<Self> {
+        if !replace_objects_enabled(repo) {
+            log::debug!("Replacement refs disabled for {}", repo.path().display());
+            return Ok(Self::disabled());
+        }
+
+        let repo_path = repo.path().to_path_buf();
+        let references = repo.references()
+            .map_err(|e| GitError::Repository(format!("Failed to read references: {}", e), Some(repo_path.clone())))?;
+        let replace_refs = references.prefixed(REPLACE_REF_PREFIX)
+            .map_err(|e| GitError::Repository(format!("Failed to read replacement refs: {}", e), Some(repo_path.clone())))?;
+
+        let mut map = HashMap::new();
+        for reference in replace_refs {
+            let reference = reference
+                .map_err(|e| GitError::Repository(format!("Failed to read reference: {}", e), Some(repo_path.clone())))?;
+            let name = reference.name().as_bstr().to_string();
+
+            // The ref name carries the id of the object being replaced
+            let original = match ObjectId::from_hex(name[REPLACE_REF_PREFIX.len()..].as_bytes()) {
+                Ok(id) => id,
+                Err(_) => {
+                    log::warn!("Ignoring malformed replacement ref {}", name);
+                    continue;
+                }
+            };
+
+            if let Some(replacement) = reference.try_id() {
+                map.insert(original, replacement.detach());
+            }
+        }
+
+        if !map.is_empty() {
+            log::debug!("Loaded {} replacement refs", map.len());
+        }
+        Ok(Self { map })
+    }
+
+    /// Get the object to read in place of `id`
+    ///
+    /// Follows chains of replacements and fails on cycles or chains deeper
+    /// than git allows.
+    pub fn resolve(&self, id: ObjectId) -> Result<ObjectId> {
+        let mut current = id;
+        for _ in 0..MAX_REPLACE_DEPTH {
+            match self.map.get(&current) {
+                Some(next) => current = *next,
+                None => return Ok(current),
+            }
+        }
+
+        if self.map.contains_key(&current) {
+            return Err(GitError::Repository(format!("Replace depth too high for object {}", id), None));
+        }
+        Ok(current)
+    }
+
+    /// Check whether any replacements are in effect
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// One `refs/replace/<original>` entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceRef {
+    /// The object being replaced
+    pub original: ObjectId,
+    /// The object read in its place
+    pub replacement: ObjectId,
+}
+
+/// List the replacement refs of a repository
+///
+/// Unlike [`Replacements::load`] this ignores whether replacement is
+/// enabled, so the refs can be managed while they are switched off.
+pub fn list_replacements(repo: &Repository) -> Result<Vec<ReplaceRef>> {
+    let repo_path = repo.path().to_path_buf();
+    let references = repo.references()
+        .map_err(|e| GitError::Repository(format!("Failed to read references: {}", e), Some(repo_path.clone())))?;
+    let replace_refs = references.prefixed(REPLACE_REF_PREFIX)
+        .map_err(|e| GitError::Repository(format!("Failed to read replacement refs: {}", e), Some(repo_path.clone())))?;
+
+    let mut list = Vec::new();
+    for reference in replace_refs {
+        let reference = reference
+            .map_err(|e| GitError::Repository(format!("Failed to read reference: {}", e), Some(repo_path.clone())))?;
+        let name = reference.name().as_bstr().to_string();
+        let original = match ObjectId::from_hex(name[REPLACE_REF_PREFIX.len()..].as_bytes()) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        if let Some(replacement) = reference.try_id() {
+            list.push(ReplaceRef { original, replacement: replacement.detach() });
+        }
+    }
+    list.sort_by_key(|entry| entry.original);
+    Ok(list)
+}
+
+/// Make `replacement` stand in for `original`, like `git replace`
+///
+/// Both objects must exist and be of the same type, since a commit can't
+/// be read as a tree. An existing replacement of `original` is only
+/// overwritten with `force`, which also allows differing types.
+pub fn add_replacement(repo: &Repository, original: ObjectId, replacement: ObjectId, force: bool) -> Result<()> {
+    if original == replacement {
+        return Err(GitError::InvalidArgument(format!("New object {} is the same as the old one", original)));
+    }
+
+    let original_kind = object_kind(repo, original)?;
+    let replacement_kind = object_kind(repo, replacement)?;
+    if original_kind != replacement_kind && !force {
+        return Err(GitError::InvalidArgument(format!(
+            "Objects must be of the same type: {} is a {} but {} is a {} (use --force to override)",
+            original, original_kind, replacement, replacement_kind,
+        )));
+    }
+
+    let name = replace_ref_name(original)?;
+    let expected = if force { PreviousValue::Any } else { PreviousValue::MustNotExist };
+    let edit = RefEdit {
+        change: Change::Update {
+            log: LogChange::default(),
+            expected,
+            new: gix::refs::Target::Peeled(replacement),
+        },
+        name,
+        deref: false,
+    };
+    crate::core::edit_references(repo, Some(edit)).map_err(|e| {
+        if !force && repo.find_reference(&format!("{}{}", REPLACE_REF_PREFIX, original)).is_ok() {
+            GitError::InvalidArgument(format!("Replace ref for {} already exists (use --force to overwrite)", original))
+        } else {
+            e
+        }
+    })?;
+    log::debug!("Replaced {} with {}", original, replacement);
+    Ok(())
+}
+
+/// Remove the replacement of `original`, returning what it was replaced by
+pub fn delete_replacement(repo: &Repository, original: ObjectId) -> Result<ObjectId> {
+    let replacement = list_replacements(repo)?
+        .into_iter()
+        .find(|entry| entry.original == original)
+        .map(|entry| entry.replacement)
+        .ok_or_else(|| GitError::InvalidArgument(format!("Replace ref for {} not found", original)))?;
+
+    let edit = RefEdit {
+        change: Change::Delete {
+            expected: PreviousValue::MustExistAndMatch(gix::refs::Target::Peeled(replacement)),
+            log: RefLog::AndReference,
+        },
+        name: replace_ref_name(original)?,
+        deref: false,
+    };
+    crate::core::edit_references(repo, Some(edit))?;
+    Ok(replacement)
+}
+
+fn replace_ref_name(original: ObjectId) -> Result<gix::refs::FullName> {
+    gix::refs::FullName::try_from(format!("{}{}", REPLACE_REF_PREFIX, original))
+        .map_err(|e| GitError::InvalidArgument(format!("Invalid replace ref name: {}", e)))
+}
+
+fn object_kind(repo: &Repository, id: ObjectId) -> Result<gix::object::Kind> {
+    match repo.try_find_header(id) {
+        Ok(Some(header)) => Ok(header.kind()),
+        Ok(None) => Err(GitError::InvalidArgument(format!("Object {} does not exist", id))),
+        Err(e) => Err(GitError::Repository(format!("Failed to read object {}: {}", id, e), None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_list_and_delete_replacement() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = gix::init(dir.path()).unwrap();
+        let old = repo.write_blob(b"old").unwrap().detach();
+        let new = repo.write_blob(b"new").unwrap().detach();
+        let tree = repo.write_object(&gix::objs::Tree::empty()).unwrap().detach();
+
+        add_replacement(&repo, old, new, false).unwrap();
+        assert_eq!(list_replacements(&repo).unwrap(), vec![ReplaceRef { original: old, replacement: new }]);
+        assert_eq!(Replacements::load(&repo).unwrap().resolve(old).unwrap(), new);
+
+        // A blob can't be read as a tree, and an existing entry needs --force
+        assert!(add_replacement(&repo, old, tree, false).is_err());
+        assert!(add_replacement(&repo, old, new, false).is_err());
+
+        assert_eq!(delete_replacement(&repo, old).unwrap(), new);
+        assert!(list_replacements(&repo).unwrap().is_empty());
+        assert!(delete_replacement(&repo, old).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Moving HEAD to another commit, like `git reset`
+
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+
+use gix::Repository;
+use gix::prelude::FindExt;
+use gix_hash::ObjectId;
+
+use crate::core::{Result, io_err, repo_err};
+
+/// How much of the repository `reset` rewrites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Only move HEAD
+    Soft,
+    /// Move HEAD and rewrite the index from the target commit
+    Mixed,
+    /// Move HEAD and rewrite both the index and the worktree
+    Hard,
+}
+
+/// Move HEAD, and the branch it points to, to `rev`
+///
+/// `rev` may be a reflog revision such as `HEAD@{1}`, which undoes a mistaken
+/// reset or rebase. The move is recorded in the reflog. Returns the commit
+/// HEAD now points to.
+pub fn reset(repo: &Repository, rev: &str, mode: ResetMode) -> Result<ObjectId> {
+    let repo_path = repo.path().to_path_buf();
+    let target = crate::core::resolve_revision(repo, rev)?;
+    let commit = repo.find_object(target)
+        .and_then(|object| object.peel_to_kind(gix::object::Kind::Commit).map_err(Into::into))
+        .map_err(|e| repo_err(format!("'{}' is not a commit: {}", rev, e), &repo_path))?
+        .id;
+
+    match mode {
+        ResetMode::Soft => {},
+        ResetMode::Mixed => {
+            let index_lock = crate::core::lock_index(repo)?;
+            let index = index_for_commit(repo, commit)?;
+            crate::core::write_index(index_lock, &index)?;
+        },
+        ResetMode::Hard => checkout_commit(repo, commit)?,
+    }
+
+    let edit = gix::refs::transaction::RefEdit {
+        change: gix::refs::transaction::Change::Update {
+            log: gix::refs::transaction::LogChange {
+                mode: gix::refs::transaction::RefLog::AndReference,
+                force_create_reflog: false,
+                message: format!("reset: moving to {}", rev).into(),
+            },
+            expected: gix::refs::transaction::PreviousValue::Any,
+            new: gix::refs::Target::Peeled(commit),
+        },
+        name: "HEAD".try_into().expect("HEAD is a valid reference name"),
+        // Move the branch HEAD points to, not HEAD itself
+        deref: true,
+    };
+    crate::core::edit_references(repo, Some(edit))?;
+
+    Ok(commit)
+}
+
+/// An index holding exactly the tree of `commit`
+fn index_for_commit(repo: &Repository, commit: ObjectId) -> Result<gix::index::File> {
+    let repo_path = repo.path();
+    let tree_id = repo.find_object(commit)
+        .and_then(|object| object.peel_to_tree().map_err(Into::into))
+        .map_err(|e| repo_err(format!("Failed to read tree of {}: {}", commit, e), repo_path))?
+        .id;
+    let state = gix::index::State::from_tree(&tree_id, |oid, buf| repo.objects.find_tree_iter(oid, buf).ok())
+        .map_err(|e| repo_err(format!("Failed to build index for {}: {}", commit, e), repo_path))?;
+    Ok(gix::index::File::from_state(state, repo.index_path()))
+}
+
+/// Rewrite the index and worktree to match `commit`, leaving HEAD alone
+///
+/// Tracked files are overwritten, and files that were tracked before but are
+/// not part of `commit` are removed. Untracked files are left alone.
+pub(crate) fn checkout_commit(repo: &Repository, commit: ObjectId) -> Result<()> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot check out a commit in a bare repository", &repo_path))?
+        .to_path_buf();
+
+    // Remember what was tracked before, so files that disappear get removed
+    let index_lock = crate::core::lock_index(repo)?;
+    let previously_tracked: HashSet<String> = match repo.index() {
+        Ok(index) => index.entries().iter().map(|entry| entry.path(&index).to_string()).collect(),
+        Err(_) => HashSet::new(),
+    };
+
+    let mut index = index_for_commit(repo, commit)?;
+    let objects = repo.objects.clone().into_arc()
+        .map_err(|e| repo_err(format!("Failed to share object database: {}", e), &repo_path))?;
+    let options = gix::worktree::state::checkout::Options {
+        overwrite_existing: true,
+        ..Default::default()
+    };
+    gix::worktree::state::checkout(
+        &mut index,
+        &workdir,
+        move |oid, buf| objects.find_blob(oid, buf),
+        &gix::progress::Discard,
+        &gix::progress::Discard,
+        &AtomicBool::new(false),
+        options,
+    )
+    .map_err(|e| repo_err(format!("Failed to check out {}: {}", commit, e), &repo_path))?;
+
+    let now_tracked: HashSet<String> = index.entries().iter().map(|entry| entry.path(&index).to_string()).collect();
+    for stale in previously_tracked.difference(&now_tracked) {
+        let stale_path = workdir.join(stale);
+        if stale_path.is_file() {
+            std::fs::remove_file(&stale_path)
+                .map_err(|e| io_err(format!("Failed to remove {}: {}", stale, e), &stale_path))?;
+        }
+    }
+
+    crate::core::write_index(index_lock, &index)
+}
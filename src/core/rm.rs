@@ -0,0 +1,122 @@
+//! Removing tracked files from the index and work tree, like `git rm`
+
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result, ObjectType, hash_object, io_err, repo_err};
+use super::mv::index_path;
+
+/// Whether the work tree file at `file` still holds what the index records
+///
+/// LFS-tracked files are checked out as their resolved content while the
+/// index holds the pointer, so those are compared against the pointer's
+/// digest rather than hashed as they are.
+fn matches_index(repo: &Repository, path: &str, file: &Path, id: ObjectId) -> Result<bool> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        // Already deleted (or a directory now): nothing to lose
+        Err(_) => return Ok(true),
+    };
+    if hash_object(ObjectType::Blob, &data) == id {
+        return Ok(true);
+    }
+    if !crate::lfs::is_lfs_tracked(repo, path)? {
+        return Ok(false);
+    }
+    let blob = repo.find_object(id)
+        .map_err(|e| repo_err(format!("Failed to read blob for {}: {}", path, e), repo.path()))?;
+    match std::str::from_utf8(&blob.data).ok().and_then(|text| crate::lfs::LfsPointer::parse(text).ok()) {
+        Some(pointer) => pointer.verify(&data),
+        None => Ok(false),
+    }
+}
+
+/// Remove `paths` from the index, and from the work tree unless `cached`
+///
+/// Paths are relative to the top of the work tree; directories need
+/// `recursive`. Without `force`, files whose staged content differs from
+/// HEAD are refused, as are files with unstaged changes when they would be
+/// deleted. Nothing is removed unless every path passes. Returns the index
+/// paths that were removed.
+pub fn remove_paths(repo: &Repository, paths: &[PathBuf], cached: bool, force: bool, recursive: bool) -> Result<Vec<String>> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot remove files in a bare repository", &repo_path))?
+        .to_path_buf();
+
+    let index_lock = crate::core::lock_index(repo)?;
+    let mut index = repo.open_index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+
+    let mut removed: Vec<(String, ObjectId)> = Vec::new();
+    for path in paths {
+        let path = index_path(path)?;
+        let prefix = format!("{}/", path);
+        let mut matched = 0;
+        for entry in index.entries() {
+            let entry_path = entry.path(&index);
+            let in_directory = entry_path.starts_with(prefix.as_bytes());
+            if entry_path != path.as_str() && !in_directory {
+                continue;
+            }
+            if in_directory && !recursive {
+                return Err(GitError::InvalidArgument(format!(
+                    "Not removing '{}' recursively without -r", path,
+                )));
+            }
+            matched += 1;
+            let entry_path = entry_path.to_string();
+            if !removed.iter().any(|(removed_path, _)| *removed_path == entry_path) {
+                removed.push((entry_path, entry.id));
+            }
+        }
+        if matched == 0 {
+            return Err(GitError::InvalidArgument(format!("'{}' did not match any tracked files", path)));
+        }
+    }
+
+    if !force {
+        for (path, id) in &removed {
+            let staged = match repo.rev_parse_single(format!("HEAD:{}", path).as_str()) {
+                Ok(head_id) => head_id.detach() != *id,
+                // Not in HEAD (or no HEAD yet): the whole file is staged
+                Err(_) => true,
+            };
+            let modified = !matches_index(repo, path, &workdir.join(path), *id)?;
+            let reason = match (staged, modified, cached) {
+                (true, true, _) => "has staged content different from both the file and HEAD",
+                (true, false, false) => "has changes staged in the index",
+                (false, true, false) => "has local modifications",
+                _ => continue,
+            };
+            let hint = if cached { "use --force to remove it anyway" } else { "use --cached to keep the file, or --force to remove it" };
+            return Err(GitError::InvalidArgument(format!("'{}' {} ({})", path, reason, hint)));
+        }
+    }
+
+    index.remove_entries(|_, path, _| removed.iter().any(|(removed_path, _)| path == removed_path.as_str()));
+    crate::core::write_index(index_lock, &index)?;
+
+    if !cached {
+        for (path, _) in &removed {
+            let file = workdir.join(path);
+            match std::fs::remove_file(&file) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(io_err(format!("Failed to remove {}: {}", path, e), &file)),
+            }
+            // Drop directories the removal left empty, like git does
+            let mut dir = file.parent();
+            while let Some(current) = dir.filter(|current| *current != workdir) {
+                if std::fs::remove_dir(current).is_err() {
+                    break;
+                }
+                dir = current.parent();
+            }
+        }
+    }
+
+    Ok(removed.into_iter().map(|(path, _)| path).collect())
+}
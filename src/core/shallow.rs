@@ -0,0 +1,154 @@
+//! Shallow repository state in `$GIT_DIR/shallow`
+//!
+//! A shallow repository lists the commits whose parents it doesn't have,
+//! one hex id per line. Fetches that change the history depth report
+//! `shallow` and `unshallow` lines, which are applied to this file.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result, io_err, repo_err};
+
+/// Name of the shallow file inside the git directory
+pub const SHALLOW_FILE: &str = "shallow";
+
+/// Depth git uses to ask for the complete history (`--unshallow`)
+pub const INFINITE_DEPTH: u32 = 0x7fff_ffff;
+
+/// How a fetch should change the depth of history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchDepth {
+    /// Limit history to this many commits from each remote tip (`--depth`)
+    Depth(u32),
+    /// Add this many commits below the current shallow boundary (`--deepen`)
+    Deepen(u32),
+    /// Keep commits newer than this Unix time (`--shallow-since`)
+    Since(i64),
+    /// Fetch all missing history (`--unshallow`)
+    Unshallow,
+}
+
+impl FetchDepth {
+    /// The `deepen*` request lines sent to the server for this depth
+    pub fn request_lines(&self) -> Vec<String> {
+        match self {
+            FetchDepth::Depth(depth) => vec![format!("deepen {}", depth)],
+            FetchDepth::Deepen(depth) => vec![format!("deepen {}", depth), "deepen-relative".to_string()],
+            FetchDepth::Since(time) => vec![format!("deepen-since {}", time)],
+            FetchDepth::Unshallow => vec![format!("deepen {}", INFINITE_DEPTH)],
+        }
+    }
+}
+
+/// Changes to the shallow boundary sent by a server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShallowUpdate {
+    /// Commits that are now shallow
+    pub shallow: Vec<ObjectId>,
+    /// Commits whose parents are now present
+    pub unshallow: Vec<ObjectId>,
+}
+
+impl ShallowUpdate {
+    /// Check whether the update changes nothing
+    pub fn is_empty(&self) -> bool {
+        self.shallow.is_empty() && self.unshallow.is_empty()
+    }
+
+    /// Apply the update to the shallow file of `git_dir`
+    ///
+    /// Returns the new shallow boundary; an empty boundary means the
+    /// repository is complete and the shallow file was removed.
+    pub fn apply(&self, git_dir: &Path) -> Result<BTreeSet<ObjectId>> {
+        let mut boundary = read_shallow(git_dir)?;
+        for id in &self.unshallow {
+            boundary.remove(id);
+        }
+        boundary.extend(self.shallow.iter().copied());
+        write_shallow(git_dir, &boundary)?;
+        Ok(boundary)
+    }
+}
+
+/// Read the shallow boundary of a repository; empty if it isn't shallow
+pub fn read_shallow(git_dir: &Path) -> Result<BTreeSet<ObjectId>> {
+    let path = git_dir.join(SHALLOW_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(io_err(format!("Failed to read shallow file: {}", e), &path)),
+    };
+
+    let mut boundary = BTreeSet::new();
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let id = ObjectId::from_hex(line.as_bytes())
+            .map_err(|e| repo_err(format!("Invalid shallow commit '{}': {}", line, e), &path))?;
+        boundary.insert(id);
+    }
+    Ok(boundary)
+}
+
+/// Replace the shallow boundary of a repository
+///
+/// An empty boundary removes the file, making the repository complete.
+pub fn write_shallow(git_dir: &Path, boundary: &BTreeSet<ObjectId>) -> Result<()> {
+    let path = git_dir.join(SHALLOW_FILE);
+    if boundary.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_err(format!("Failed to remove shallow file: {}", e), &path)),
+        };
+    }
+
+    // Readers must never see a partially written boundary
+    let mut temp = tempfile::NamedTempFile::new_in(git_dir)
+        .map_err(|e| io_err(format!("Failed to create temporary shallow file: {}", e), git_dir))?;
+    for id in boundary {
+        writeln!(temp, "{}", id)
+            .map_err(|e| io_err(format!("Failed to write shallow file: {}", e), temp.path()))?;
+    }
+    temp.persist(&path)
+        .map_err(|e| io_err(format!("Failed to install shallow file: {}", e), &path))?;
+    Ok(())
+}
+
+/// Check that a depth change makes sense for the current boundary
+pub fn check_fetch_depth(git_dir: &Path, depth: FetchDepth) -> Result<()> {
+    match depth {
+        FetchDepth::Depth(0) | FetchDepth::Deepen(0) => Err(GitError::InvalidArgument(
+            "depth must be a positive number".to_string(),
+        )),
+        FetchDepth::Unshallow if read_shallow(git_dir)?.is_empty() => Err(GitError::InvalidArgument(
+            "--unshallow on a complete repository does not make sense".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_update_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = ObjectId::from([1u8; 20]);
+        let b = ObjectId::from([2u8; 20]);
+
+        assert!(read_shallow(dir.path()).unwrap().is_empty());
+        let boundary = ShallowUpdate { shallow: vec![a], unshallow: vec![] }.apply(dir.path()).unwrap();
+        assert_eq!(boundary.into_iter().collect::<Vec<_>>(), vec![a]);
+
+        let boundary = ShallowUpdate { shallow: vec![b], unshallow: vec![a] }.apply(dir.path()).unwrap();
+        assert_eq!(read_shallow(dir.path()).unwrap(), boundary);
+        assert_eq!(boundary.into_iter().collect::<Vec<_>>(), vec![b]);
+
+        ShallowUpdate { shallow: vec![], unshallow: vec![b] }.apply(dir.path()).unwrap();
+        assert!(!dir.path().join(SHALLOW_FILE).exists());
+        assert!(check_fetch_depth(dir.path(), FetchDepth::Unshallow).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::core::{AttributeValue, GitError, Result, attributes_for, io_err, repo_err};
+
+/// Lines of unchanged context shown around each hunk
+const CONTEXT_LINES: u32 = 3;
+
+/// A single change: lines `before` of the index version became lines `after`
+/// of the worktree version
+type Change = (Range<u32>, Range<u32>);
+
+/// A group of nearby changes shown and staged together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// Indexes into [`FilePatch::changes`] covered by this hunk
+    changes: Vec<usize>,
+    /// The hunk rendered as unified diff, starting with its `@@` header
+    pub text: String,
+}
+
+/// Unstaged changes to one tracked file, split into hunks
+#[derive(Debug, Clone)]
+pub struct FilePatch {
+    /// Worktree-relative path
+    pub path: String,
+    /// Content currently in the index
+    old: String,
+    /// Content currently in the worktree
+    new: String,
+    /// Every change between `old` and `new`
+    changes: Vec<Change>,
+    /// Changes grouped into hunks with context
+    pub hunks: Vec<Hunk>,
+}
+
+/// Answer to "Stage this hunk?"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkChoice {
+    /// `y`: stage the hunk
+    Stage,
+    /// `n`: leave the hunk unstaged
+    Skip,
+    /// `s`: split the hunk into smaller hunks
+    Split,
+    /// `q`: stop, keeping what was staged so far
+    Quit,
+}
+
+impl HunkChoice {
+    /// Parse a prompt answer; only the first character counts
+    pub fn parse(answer: &str) -> Option<Self> {
+        match answer.trim().chars().next()?.to_ascii_lowercase() {
+            'y' => Some(HunkChoice::Stage),
+            'n' => Some(HunkChoice::Skip),
+            's' => Some(HunkChoice::Split),
+            'q' => Some(HunkChoice::Quit),
+            _ => None,
+        }
+    }
+}
+
+impl FilePatch {
+    /// Diff the index version of a file against its worktree version
+    pub fn new(path: &str, old: String, new: String) -> Self {
+        let input = gix::diff::blob::intern::InternedInput::new(old.as_str(), new.as_str());
+        let mut changes = Vec::new();
+        gix::diff::blob::diff(
+            gix::diff::blob::Algorithm::Histogram,
+            &input,
+            |before: Range<u32>, after: Range<u32>| changes.push((before, after)),
+        );
+
+        let mut patch = Self { path: path.to_string(), old, new, changes, hunks: Vec::new() };
+
+        // Changes whose context would overlap are shown as one hunk
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (index, (before, _)) in patch.changes.iter().enumerate() {
+            match groups.last_mut() {
+                Some(group) if before.start - patch.changes[group[group.len() - 1]].0.end <= 2 * CONTEXT_LINES => {
+                    group.push(index);
+                },
+                _ => groups.push(vec![index]),
+            }
+        }
+        patch.hunks = groups.into_iter().map(|group| patch.hunk(group)).collect();
+        patch
+    }
+
+    /// Split a hunk into one hunk per change, if it has more than one
+    pub fn split(&self, hunk: &Hunk) -> Option<Vec<Hunk>> {
+        if hunk.changes.len() < 2 {
+            return None;
+        }
+        Some(hunk.changes.iter().map(|&change| self.hunk(vec![change])).collect())
+    }
+
+    /// Content of the file with only the changes of `selected` applied
+    pub fn apply(&self, selected: &[&Hunk]) -> String {
+        let old_lines: Vec<&str> = self.old.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = self.new.split_inclusive('\n').collect();
+
+        let mut chosen: Vec<usize> = selected.iter().flat_map(|hunk| hunk.changes.iter().copied()).collect();
+        chosen.sort_unstable();
+        chosen.dedup();
+
+        let mut out = String::new();
+        let mut pos = 0usize;
+        for index in chosen {
+            let (before, after) = &self.changes[index];
+            out.extend(old_lines[pos..before.start as usize].iter().copied());
+            out.extend(new_lines[after.start as usize..after.end as usize].iter().copied());
+            pos = before.end as usize;
+        }
+        out.extend(old_lines[pos..].iter().copied());
+        out
+    }
+
+    /// Render the changes in `group` as a unified diff hunk
+    fn hunk(&self, group: Vec<usize>) -> Hunk {
+        let old_lines: Vec<&str> = self.old.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = self.new.split_inclusive('\n').collect();
+        let first = &self.changes[group[0]];
+        let last = &self.changes[group[group.len() - 1]];
+
+        let old_start = first.0.start.saturating_sub(CONTEXT_LINES);
+        let old_end = (last.0.end + CONTEXT_LINES).min(old_lines.len() as u32);
+        let new_start = first.1.start - (first.0.start - old_start);
+        let new_end = last.1.end + (old_end - last.0.end);
+
+        let mut text = format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + 1, old_end - old_start, new_start + 1, new_end - new_start
+        );
+        let mut push = |prefix: char, line: &str| {
+            text.push(prefix);
+            text.push_str(line);
+            if !line.ends_with('\n') {
+                text.push_str("\n\\ No newline at end of file\n");
+            }
+        };
+
+        let mut pos = old_start;
+        for &index in &group {
+            let (before, after) = &self.changes[index];
+            for line in &old_lines[pos as usize..before.start as usize] {
+                push(' ', line);
+            }
+            for line in &old_lines[before.start as usize..before.end as usize] {
+                push('-', line);
+            }
+            for line in &new_lines[after.start as usize..after.end as usize] {
+                push('+', line);
+            }
+            pos = before.end;
+        }
+        for line in &old_lines[pos as usize..old_end as usize] {
+            push(' ', line);
+        }
+
+        Hunk { changes: group, text }
+    }
+}
+
+/// Find the unstaged changes to tracked text files
+///
+/// Deleted, binary and conflicted files are skipped; they can only be
+/// staged as a whole.
+pub fn unstaged_patches(repo: &Repository) -> Result<Vec<FilePatch>> {
+    let repo_path = repo.path().to_path_buf();
+    let workdir = repo.work_dir()
+        .ok_or_else(|| repo_err("Cannot stage hunks in a bare repository", &repo_path))?
+        .to_path_buf();
+    let index = repo.index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+
+    let mut patches = Vec::new();
+    for entry in index.entries() {
+        let is_file = entry.mode == gix::index::entry::Mode::FILE
+            || entry.mode == gix::index::entry::Mode::FILE_EXECUTABLE;
+        if entry.stage() != 0 || !is_file {
+            continue;
+        }
+
+        let path = entry.path(&index).to_string();
+        let file = workdir.join(&path);
+        let new = match std::fs::read(&file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io_err(format!("Failed to read {}: {}", path, e), &file)),
+        };
+        // Compare what `git add` would stage, not the raw worktree file
+        let new = clean_content(repo, &workdir, &path, new)?;
+        let old = repo.find_object(entry.id)
+            .map_err(|e| repo_err(format!("Failed to read blob for {}: {}", path, e), &repo_path))?
+            .data
+            .clone();
+
+        if old == new || old.contains(&0) || new.contains(&0) {
+            continue;
+        }
+        let (old, new) = match (String::from_utf8(old), String::from_utf8(new)) {
+            (Ok(old), Ok(new)) => (old, new),
+            _ => continue,
+        };
+
+        let patch = FilePatch::new(&path, old, new);
+        if !patch.hunks.is_empty() {
+            patches.push(patch);
+        }
+    }
+    Ok(patches)
+}
+
+/// Run worktree content through the clean filter of its path
+///
+/// The path's `filter` attribute names a driver whose `filter.<driver>.clean`
+/// command gets the content on stdin, with `%f` replaced by the path. Without
+/// a filter or a configured command the content is staged as it is, like git.
+pub fn clean_content(repo: &Repository, workdir: &std::path::Path, path: &str, content: Vec<u8>) -> Result<Vec<u8>> {
+    let driver = match attributes_for(repo, path)?.get("filter") {
+        AttributeValue::Value(driver) => driver,
+        _ => return Ok(content),
+    };
+    let command = match repo.config_snapshot().string(format!("filter.{}.clean", driver).as_str()) {
+        Some(command) => command.to_string().replace("%f", &format!("'{}'", path.replace('\'', "'\\''"))),
+        None => return Ok(content),
+    };
+
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", &command])
+        .current_dir(workdir)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| GitError::External(format!("Failed to run clean filter '{}' for {}: {}", driver, path, e)))?;
+    // Feed the filter from another thread so a full stdout pipe can't stall it
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(&content));
+    let output = child.wait_with_output()
+        .map_err(|e| GitError::External(format!("Clean filter '{}' for {} failed: {}", driver, path, e)))?;
+    let written = writer.join().unwrap_or(Ok(()));
+    if !output.status.success() {
+        return Err(GitError::External(format!("Clean filter '{}' for {} exited with {}", driver, path, output.status)));
+    }
+    written.map_err(|e| GitError::External(format!("Failed to feed clean filter '{}' for {}: {}", driver, path, e)))?;
+    Ok(output.stdout)
+}
+
+/// Write `content` as the staged version of `path`
+pub fn stage_content(repo: &Repository, path: &str, content: &[u8]) -> Result<ObjectId> {
+    let repo_path = repo.path().to_path_buf();
+    let blob_id = repo.write_blob(content)
+        .map_err(|e| GitError::ObjectStorage(format!("Failed to write blob for {}: {}", path, e)))?
+        .detach();
+
+    let index_lock = crate::core::lock_index(repo)?;
+    let mut index = repo.open_index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+    let position = index.entries().iter()
+        .position(|entry| entry.stage() == 0 && entry.path(&index) == path)
+        .ok_or_else(|| repo_err(format!("'{}' is not in the index", path), &repo_path))?;
+
+    let entry = &mut index.entries_mut()[position];
+    entry.id = blob_id;
+    // Forget the cached stat so status compares contents again
+    entry.stat = Default::default();
+
+    crate::core::write_index(index_lock, &index)?;
+    Ok(blob_id)
+}
+
+/// Interactively stage hunks, like `git add --patch`
+///
+/// Each hunk is written to `output` followed by a prompt, and answered by a
+/// line from `input` (see [`HunkChoice`]). Selected hunks of a file are
+/// staged once all of its hunks have been answered, or on quit. Returns the
+/// number of hunks staged.
+pub fn add_patch(repo: &Repository, mut input: impl BufRead, mut output: impl Write) -> Result<usize> {
+    let write_err = |e: std::io::Error| GitError::IO(format!("Failed to write prompt: {}", e), None);
+    let mut staged = 0;
+
+    for patch in unstaged_patches(repo)? {
+        writeln!(output, "diff --git a/{0} b/{0}", patch.path).map_err(write_err)?;
+
+        let mut pending: VecDeque<Hunk> = patch.hunks.iter().cloned().collect();
+        let mut selected = Vec::new();
+        let mut quit = false;
+        while let Some(hunk) = pending.pop_front() {
+            write!(output, "{}Stage this hunk [y,n,s,q]? ", hunk.text).map_err(write_err)?;
+            output.flush().map_err(write_err)?;
+
+            let mut answer = String::new();
+            let read = input.read_line(&mut answer)
+                .map_err(|e| GitError::IO(format!("Failed to read answer: {}", e), None))?;
+            // End of input counts as quitting
+            let choice = if read == 0 { Some(HunkChoice::Quit) } else { HunkChoice::parse(&answer) };
+
+            match choice {
+                Some(HunkChoice::Stage) => selected.push(hunk),
+                Some(HunkChoice::Skip) => {},
+                Some(HunkChoice::Split) => match patch.split(&hunk) {
+                    Some(parts) => {
+                        writeln!(output, "Split into {} hunks.", parts.len()).map_err(write_err)?;
+                        for part in parts.into_iter().rev() {
+                            pending.push_front(part);
+                        }
+                    },
+                    None => {
+                        writeln!(output, "Sorry, cannot split this hunk").map_err(write_err)?;
+                        pending.push_front(hunk);
+                    }
+                },
+                Some(HunkChoice::Quit) => {
+                    quit = true;
+                    break;
+                },
+                None => {
+                    writeln!(output, "y - stage this hunk\nn - do not stage this hunk\ns - split the current hunk into smaller hunks\nq - quit; do not stage this hunk or any of the remaining ones").map_err(write_err)?;
+                    pending.push_front(hunk);
+                }
+            }
+        }
+
+        if !selected.is_empty() {
+            let refs: Vec<&Hunk> = selected.iter().collect();
+            stage_content(repo, &patch.path, patch.apply(&refs).as_bytes())?;
+            staged += selected.len();
+        }
+        if quit {
+            break;
+        }
+    }
+
+    Ok(staged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch() -> FilePatch {
+        let old = (1..=20).map(|n| format!("line {}\n", n)).collect::<String>();
+        let new = old.replace("line 2\n", "line two\n").replace("line 18\n", "line eighteen\n");
+        FilePatch::new("file.txt", old, new)
+    }
+
+    #[test]
+    fn test_distant_changes_are_separate_hunks() {
+        let patch = patch();
+        assert_eq!(patch.hunks.len(), 2);
+        assert!(patch.hunks[0].text.starts_with("@@ -1,5 +1,5 @@\n"));
+        assert!(patch.hunks[0].text.contains("-line 2\n+line two\n"));
+    }
+
+    #[test]
+    fn test_apply_only_selected_hunks() {
+        let patch = patch();
+        let staged = patch.apply(&[&patch.hunks[1]]);
+        assert!(staged.contains("line 2\n"));
+        assert!(staged.contains("line eighteen\n"));
+    }
+
+    #[test]
+    fn test_split_nearby_changes() {
+        let old = "a\nb\nc\nd\ne\n".to_string();
+        let new = "A\nb\nc\nd\nE\n".to_string();
+        let patch = FilePatch::new("file.txt", old, new);
+        assert_eq!(patch.hunks.len(), 1);
+
+        let parts = patch.split(&patch.hunks[0]).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(patch.apply(&[&parts[0]]), "A\nb\nc\nd\ne\n");
+        assert!(patch.split(&parts[0]).is_none());
+    }
+
+    fn git(dir: &std::path::Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn test_patches_compare_cleaned_content() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["config", "filter.redact.clean", "sed s/secret/xxx/"]);
+        std::fs::write(dir.path().join(".gitattributes"), "*.txt filter=redact\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "one\nsecret\n").unwrap();
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "-q", "-m", "base"]);
+
+        std::fs::write(dir.path().join("notes.txt"), "one\nsecret\ntwo secret\n").unwrap();
+        let repo = gix::open(dir.path()).unwrap();
+        let patches = unstaged_patches(&repo).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].hunks.len(), 1);
+        assert!(patches[0].hunks[0].text.ends_with(" one\n xxx\n+two xxx\n"), "{}", patches[0].hunks[0].text);
+
+        assert_eq!(patches[0].apply(&[&patches[0].hunks[0]]), "one\nxxx\ntwo xxx\n");
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result, repo_err};
+
+/// Name of the file declaring a repository's submodules
+pub const GITMODULES_FILE: &str = ".gitmodules";
+
+/// A submodule declared in `.gitmodules`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submodule {
+    /// Name of the `[submodule "<name>"]` section
+    pub name: String,
+    /// Path of the submodule relative to the superproject's worktree
+    pub path: PathBuf,
+    /// URL as written in `.gitmodules`, possibly relative
+    pub url: String,
+    /// Remote branch followed by `submodule update --remote`, if configured
+    pub branch: Option<String>,
+}
+
+/// A submodule that was cloned and checked out
+#[derive(Debug, Clone)]
+pub struct InitializedSubmodule {
+    /// Path relative to the top-level superproject's worktree
+    pub path: PathBuf,
+    /// Absolute URL the submodule was cloned from
+    pub url: String,
+    /// Commit the submodule was checked out at
+    pub commit: ObjectId,
+}
+
+/// Read the submodules declared in `<workdir>/.gitmodules`
+///
+/// A missing file means there are no submodules. Sections without a `path`
+/// or `url` are skipped, as git does.
+pub fn read_gitmodules(workdir: &Path) -> Result<Vec<Submodule>> {
+    let gitmodules = workdir.join(GITMODULES_FILE);
+    if !gitmodules.exists() {
+        return Ok(Vec::new());
+    }
+
+    let config = gix_config::File::from_path_no_includes(gitmodules.clone(), gix_config::Source::Worktree)
+        .map_err(|e| GitError::Config(format!("Failed to parse {}: {}", gitmodules.display(), e)))?;
+
+    let sections = match config.sections_by_name("submodule") {
+        Some(sections) => sections,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut submodules = Vec::new();
+    for section in sections {
+        let name = match section.header().subsection_name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let (path, url) = match (section.value("path"), section.value("url")) {
+            (Some(path), Some(url)) => (path.to_string(), url.to_string()),
+            _ => {
+                log::warn!("Ignoring submodule '{}' without path or url", name);
+                continue;
+            }
+        };
+
+        // Refuse paths that would place the submodule outside the worktree
+        let within = crate::utils::is_path_within(workdir.join(&path), workdir).unwrap_or(false);
+        if path.is_empty() || !within {
+            log::warn!("Ignoring submodule '{}' with unsafe path '{}'", name, path);
+            continue;
+        }
+
+        let branch = section.value("branch").map(|branch| branch.to_string());
+        submodules.push(Submodule { name, path: PathBuf::from(path), url, branch });
+    }
+
+    Ok(submodules)
+}
+
+/// Resolve a submodule URL against the URL of its superproject
+///
+/// URLs starting with `./` or `../` are relative to the superproject URL;
+/// each `../` removes one path component, like `git submodule` does.
+/// Absolute URLs are returned unchanged.
+pub fn resolve_url(superproject_url: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_string();
+    }
+
+    let mut base = superproject_url.trim_end_matches('/').to_string();
+    let mut rest = url;
+    loop {
+        if let Some(stripped) = rest.strip_prefix("./") {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("../") {
+            rest = stripped;
+            // Drop the last component, keeping the scheme and host intact
+            if let Some(index) = base.rfind('/') {
+                if !base[..index].ends_with('/') {
+                    base.truncate(index);
+                }
+            }
+        } else {
+            break;
+        }
+    }
+
+    if rest.is_empty() {
+        base
+    } else {
+        format!("{}/{}", base, rest)
+    }
+}
+
+/// Get the commit recorded for a submodule in the superproject's index
+pub fn recorded_commit(repo: &Repository, path: &Path) -> Result<ObjectId> {
+    let repo_path = repo.path().to_path_buf();
+    let index = repo.index()
+        .map_err(|e| repo_err(format!("Failed to read index: {}", e), &repo_path))?;
+
+    let path_str = path.to_string_lossy().replace('\\', "/");
+    let entry = index.entry_by_path(path_str.as_str().into())
+        .ok_or_else(|| repo_err(format!("Submodule path '{}' is not in the index", path_str), &repo_path))?;
+
+    if entry.mode != gix::index::entry::Mode::COMMIT {
+        return Err(repo_err(format!("Index entry '{}' is not a submodule commit", path_str), &repo_path));
+    }
+    Ok(entry.id)
+}
+
+/// Record `submodule.<name>.url` in the superproject's `.git/config`
+///
+/// This is what `git submodule init` does; it marks the submodule as active
+/// and pins the absolute URL it was cloned from.
+pub fn register(repo: &Repository, name: &str, url: &str) -> Result<()> {
+    super::remote::update_local_config(repo, |config| {
+        super::remote::set_value(config, "submodule", Some(name), "url", url)
+    })
+}
+
+/// Get the URL a submodule was registered with, if it was initialized
+pub fn registered_url(repo: &Repository, name: &str) -> Option<String> {
+    repo.config_snapshot()
+        .string(&format!("submodule.{}.url", name))
+        .map(|url| url.to_string())
+}
+
+/// Get the commit at the remote tip a submodule should follow
+///
+/// Uses `origin/<branch>` when `.gitmodules` names a branch and the remote's
+/// default branch otherwise, falling back to the current HEAD when the
+/// remote HEAD is unknown.
+pub fn remote_tip(repo: &Repository, branch: Option<&str>) -> Result<ObjectId> {
+    let repo_path = repo.path().to_path_buf();
+    let ref_name = match branch {
+        Some(branch) => format!("refs/remotes/origin/{}", branch),
+        None => "refs/remotes/origin/HEAD".to_string(),
+    };
+
+    match repo.try_find_reference(ref_name.as_str()) {
+        Ok(Some(mut reference)) => reference.peel_to_id_in_place()
+            .map(|id| id.detach())
+            .map_err(|e| repo_err(format!("Failed to resolve {}: {}", ref_name, e), &repo_path)),
+        Ok(None) if branch.is_none() => repo.head_id()
+            .map(|id| id.detach())
+            .map_err(|e| repo_err(format!("Failed to resolve HEAD: {}", e), &repo_path)),
+        Ok(None) => Err(repo_err(format!("Remote branch {} not found", ref_name), &repo_path)),
+        Err(e) => Err(repo_err(format!("Failed to read {}: {}", ref_name, e), &repo_path)),
+    }
+}
+
+/// Point HEAD at `commit` without touching the index or worktree
+pub(crate) fn detach_head(repo: &Repository, commit: ObjectId) -> Result<()> {
+    let edit = gix::refs::transaction::RefEdit {
+        change: gix::refs::transaction::Change::Update {
+            log: gix::refs::transaction::LogChange::default(),
+            expected: gix::refs::transaction::PreviousValue::Any,
+            new: gix::refs::Target::Peeled(commit),
+        },
+        name: "HEAD".try_into().expect("HEAD is a valid reference name"),
+        deref: false,
+    };
+    crate::core::edit_references(repo, Some(edit))?;
+    Ok(())
+}
+
+/// Check out `commit` in an existing submodule and detach HEAD there
+///
+/// Rewrites the index from the commit's tree, overwrites tracked files and
+/// removes files that were tracked before but are not part of `commit`.
+pub(crate) fn checkout_detached(repo: &Repository, commit: ObjectId) -> Result<()> {
+    crate::core::reset::checkout_commit(repo, commit)?;
+    detach_head(repo, commit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPER: &str = "tor+git://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/group/super.git";
+
+    #[test]
+    fn test_resolve_relative_url() {
+        assert_eq!(
+            resolve_url(SUPER, "../lib.git"),
+            "tor+git://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/group/lib.git"
+        );
+        assert_eq!(resolve_url(SUPER, "./nested.git"), format!("{}/nested.git", SUPER));
+        assert_eq!(resolve_url("https://example.com/a/b/", "../../c"), "https://example.com/c");
+    }
+
+    #[test]
+    fn test_resolve_stops_at_host() {
+        assert_eq!(resolve_url("https://example.com/repo", "../../../other"), "https://example.com/other");
+    }
+
+    #[test]
+    fn test_resolve_absolute_url() {
+        assert_eq!(resolve_url(SUPER, "https://example.com/lib.git"), "https://example.com/lib.git");
+    }
+
+    #[test]
+    fn test_register_writes_through_config_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = gix::init(dir.path()).unwrap();
+        register(&repo, "lib", "https://example.com/lib.git").unwrap();
+        let repo = gix::open(dir.path()).unwrap();
+        assert_eq!(registered_url(&repo, "lib").as_deref(), Some("https://example.com/lib.git"));
+
+        // A held lock refuses the write and leaves the config alone
+        let config_path = repo.path().join("config");
+        let before = std::fs::read(&config_path).unwrap();
+        let _lock = crate::core::LockFile::acquire(&config_path).unwrap();
+        assert!(matches!(register(&repo, "other", "https://example.com/other.git"), Err(GitError::Locked(_))));
+        assert_eq!(std::fs::read(&config_path).unwrap(), before);
+    }
+}
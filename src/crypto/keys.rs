@@ -1,4 +1,5 @@
 use std::fmt;
+use std::sync::Arc;
 use rand::rngs::OsRng;
 use ed25519_dalek::{Keypair, PublicKey as DalekPublicKey, SecretKey, Signature, Signer as DalekSigner, Verifier as DalekVerifier};
 use base64::{Engine as _, engine::general_purpose};
@@ -142,4 +143,51 @@ impl Verifier for KeyPair {
         let public_key = PublicKey(self.keypair.public);
         public_key.verify(data, signature)
     }
-}
\ No newline at end of file
+}
+
+/// Which key signs commits and pushes
+///
+/// A persistent key ties every signature to one long-term identity, and a
+/// verifier checks signatures against keys it already trusts. An ephemeral
+/// key is generated for a single operation and thrown away afterwards. Its
+/// public key is still recorded with the signature (inside the SSH signature
+/// of a commit, as the `pusher` of a push certificate), so the signature can
+/// be checked, but only trust-on-use: a valid signature shows the content was
+/// not changed after signing, not who signed it, and two ephemeral signatures
+/// can't be linked to the same signer.
+#[derive(Clone)]
+pub enum SigningMode {
+    /// Sign with this long-term key
+    Persistent(Arc<dyn Signer + Send + Sync>),
+    /// Sign each operation with a fresh one-time key
+    Ephemeral,
+}
+
+impl SigningMode {
+    /// The signer for one operation
+    ///
+    /// In ephemeral mode every call returns a new key pair, so callers ask
+    /// once per commit or push and use that signer throughout.
+    pub fn signer(&self) -> Arc<dyn Signer + Send + Sync> {
+        match self {
+            SigningMode::Persistent(signer) => signer.clone(),
+            SigningMode::Ephemeral => Arc::new(KeyPair::generate()),
+        }
+    }
+
+    /// Whether each operation gets its own key
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, SigningMode::Ephemeral)
+    }
+}
+
+impl fmt::Debug for SigningMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningMode::Persistent(signer) => {
+                write!(f, "Persistent({})", general_purpose::STANDARD.encode(signer.public_key()))
+            },
+            SigningMode::Ephemeral => write!(f, "Ephemeral"),
+        }
+    }
+}
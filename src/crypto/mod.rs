@@ -1,7 +1,8 @@
 mod signing;
 mod keys;
 mod identity;
+pub mod sshsig;
 
-pub use signing::{Signer, Verifier, SignatureError};
-pub use keys::{KeyPair, PublicKey, PrivateKey};
+pub use signing::{Signer, Verifier, SignatureError, SignatureProvider};
+pub use keys::{KeyPair, PublicKey, PrivateKey, SigningMode};
 pub use identity::{Identity, AnonymousIdentity};
\ No newline at end of file
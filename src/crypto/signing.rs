@@ -41,6 +41,9 @@ pub trait Signer {
     fn public_key(&self) -> Vec<u8>;
 }
 
+/// Anything that can sign commits, such as a [`KeyPair`](super::KeyPair) or an identity
+pub type SignatureProvider = dyn Signer;
+
 /// Generic trait for verifying signatures
 pub trait Verifier {
     /// Verify the signature on the given data
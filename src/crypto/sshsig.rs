@@ -0,0 +1,193 @@
+//! SSH signatures (`SSHSIG`), the format git writes with `gpg.format = ssh`
+//!
+//! The armored signature carries the signer's public key and binds the signed
+//! data to a namespace (`git` for commits and tags), so a signature made for
+//! one purpose can't be replayed for another. Only Ed25519 keys are supported.
+
+use base64::{Engine as _, engine::general_purpose};
+use sha2::{Digest, Sha256, Sha512};
+
+use super::keys::PublicKey;
+use super::signing::{Signer, Verifier, SignatureError};
+
+/// Namespace git uses for commit and tag signatures
+pub const GIT_NAMESPACE: &str = "git";
+
+const MAGIC: &[u8] = b"SSHSIG";
+const VERSION: u32 = 1;
+const KEY_TYPE: &str = "ssh-ed25519";
+const HASH_ALGORITHM: &str = "sha512";
+const BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const END: &str = "-----END SSH SIGNATURE-----";
+/// Width of the base64 lines between the armor lines, as `ssh-keygen` writes them
+const LINE_WIDTH: usize = 70;
+
+/// A decoded SSH signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshSignature {
+    /// Raw Ed25519 public key of the signer
+    pub public_key: Vec<u8>,
+    /// Namespace the signature is valid for
+    pub namespace: String,
+    /// Hash algorithm applied to the data before signing
+    pub hash_algorithm: String,
+    /// Raw Ed25519 signature
+    pub signature: Vec<u8>,
+}
+
+/// Sign `data` for `namespace` and armor the result
+pub fn sign(signer: &dyn Signer, namespace: &str, data: &[u8]) -> Result<String, SignatureError> {
+    let signature = signer.sign(&signed_data(namespace, HASH_ALGORITHM, data)?)?;
+
+    let mut public_key = Vec::new();
+    put_string(&mut public_key, KEY_TYPE.as_bytes());
+    put_string(&mut public_key, &signer.public_key());
+    let mut signature_blob = Vec::new();
+    put_string(&mut signature_blob, KEY_TYPE.as_bytes());
+    put_string(&mut signature_blob, &signature);
+
+    let mut blob = MAGIC.to_vec();
+    blob.extend_from_slice(&VERSION.to_be_bytes());
+    put_string(&mut blob, &public_key);
+    put_string(&mut blob, namespace.as_bytes());
+    put_string(&mut blob, b"");
+    put_string(&mut blob, HASH_ALGORITHM.as_bytes());
+    put_string(&mut blob, &signature_blob);
+
+    let encoded = general_purpose::STANDARD.encode(blob);
+    let mut armored = String::from(BEGIN);
+    for line in encoded.as_bytes().chunks(LINE_WIDTH) {
+        armored.push('\n');
+        armored.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+    }
+    armored.push('\n');
+    armored.push_str(END);
+    Ok(armored)
+}
+
+/// Check that `armored` is a valid signature of `data` by `public_key`
+///
+/// Returns `Ok(false)` when the signature was made by another key or for
+/// another namespace, or doesn't match the data.
+pub fn verify(armored: &str, public_key: &PublicKey, namespace: &str, data: &[u8]) -> Result<bool, SignatureError> {
+    let signature = parse(armored)?;
+    if signature.public_key != public_key.as_bytes() || signature.namespace != namespace {
+        return Ok(false);
+    }
+    public_key.verify(&signed_data(namespace, &signature.hash_algorithm, data)?, &signature.signature)
+}
+
+/// Decode an armored SSH signature
+pub fn parse(armored: &str) -> Result<SshSignature, SignatureError> {
+    let armored = armored.trim();
+    let body = armored.strip_prefix(BEGIN)
+        .and_then(|rest| rest.strip_suffix(END))
+        .ok_or_else(|| SignatureError::InvalidKeyFormat("Not an armored SSH signature".to_string()))?;
+    let encoded: String = body.split_whitespace().collect();
+    let blob = general_purpose::STANDARD.decode(encoded)
+        .map_err(|e| SignatureError::InvalidKeyFormat(format!("Invalid Base64 encoding: {}", e)))?;
+
+    let mut reader = Reader(&blob);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(SignatureError::InvalidKeyFormat("Missing SSHSIG magic".to_string()));
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(SignatureError::InvalidKeyFormat(format!("Unsupported SSH signature version {}", version)));
+    }
+
+    let mut key = Reader(reader.string()?);
+    expect_key_type(key.string()?)?;
+    let public_key = key.string()?.to_vec();
+    let namespace = text(reader.string()?)?;
+    reader.string()?; // reserved
+    let hash_algorithm = text(reader.string()?)?;
+    let mut signature = Reader(reader.string()?);
+    expect_key_type(signature.string()?)?;
+    let signature = signature.string()?.to_vec();
+
+    Ok(SshSignature { public_key, namespace, hash_algorithm, signature })
+}
+
+/// The bytes the key actually signs: the namespace and a hash of the data
+fn signed_data(namespace: &str, hash_algorithm: &str, data: &[u8]) -> Result<Vec<u8>, SignatureError> {
+    let digest = match hash_algorithm {
+        "sha512" => Sha512::digest(data).to_vec(),
+        "sha256" => Sha256::digest(data).to_vec(),
+        other => return Err(SignatureError::InvalidKeyFormat(format!("Unsupported hash algorithm {}", other))),
+    };
+    let mut blob = MAGIC.to_vec();
+    put_string(&mut blob, namespace.as_bytes());
+    put_string(&mut blob, b"");
+    put_string(&mut blob, hash_algorithm.as_bytes());
+    put_string(&mut blob, &digest);
+    Ok(blob)
+}
+
+fn expect_key_type(key_type: &[u8]) -> Result<(), SignatureError> {
+    if key_type != KEY_TYPE.as_bytes() {
+        return Err(SignatureError::InvalidKeyFormat(format!(
+            "Unsupported key type {}", String::from_utf8_lossy(key_type),
+        )));
+    }
+    Ok(())
+}
+
+fn text(bytes: &[u8]) -> Result<String, SignatureError> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| SignatureError::InvalidKeyFormat("SSH signature field is not UTF-8".to_string()))
+}
+
+/// Append an SSH wire-format string: a 32-bit length and the bytes
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Reads SSH wire-format fields from a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SignatureError> {
+        if self.0.len() < len {
+            return Err(SignatureError::InvalidKeyFormat("Truncated SSH signature".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, SignatureError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], SignatureError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = KeyPair::from_seed(&[3u8; 32]).unwrap();
+        let armored = sign(&key, GIT_NAMESPACE, b"payload").unwrap();
+        assert!(armored.starts_with(BEGIN) && armored.ends_with(END));
+        assert!(armored.lines().all(|line| line.len() <= LINE_WIDTH));
+
+        let parsed = parse(&armored).unwrap();
+        assert_eq!(parsed.public_key, key.public_key().as_bytes());
+        assert_eq!(parsed.namespace, GIT_NAMESPACE);
+
+        assert!(verify(&armored, &key.public_key(), GIT_NAMESPACE, b"payload").unwrap());
+        assert!(!verify(&armored, &key.public_key(), GIT_NAMESPACE, b"tampered").unwrap());
+        assert!(!verify(&armored, &key.public_key(), "file", b"payload").unwrap());
+        let other = KeyPair::from_seed(&[4u8; 32]).unwrap();
+        assert!(!verify(&armored, &other.public_key(), GIT_NAMESPACE, b"payload").unwrap());
+    }
+}
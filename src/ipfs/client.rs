@@ -4,6 +4,8 @@
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
 use bytes::Bytes;
 use reqwest::Client as HttpClient;
 use reqwest::multipart;
@@ -15,19 +17,29 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 use futures::StreamExt;
 
 use crate::core::{GitError, Result};
+use crate::transport::PooledTorTransport;
 use super::IpfsConfig;
 
 /// Standard chunk size for large files (1MB)
 const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 
+/// How long a read may take when the caller doesn't say
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(120);
+
 /// Client for interacting with IPFS nodes
 #[derive(Debug, Clone)]
 pub struct IpfsClient {
     /// Configuration for IPFS
     config: IpfsConfig,
     
-    /// HTTP client for API calls
+    /// HTTP client for API calls, sending the API token if one is set
     http: HttpClient,
+    
+    /// HTTP client for public gateways, which never see the API token
+    gateway_http: HttpClient,
+    
+    /// Tor transport for gateway reads, so gateways don't see our address
+    tor_transport: Option<Arc<PooledTorTransport>>,
 }
 
 /// Response from the IPFS add operation
@@ -122,7 +134,19 @@ impl IpfsClient {
     /// Create a new IPFS client
     pub async fn new(config: IpfsConfig) -> Result<Self> {
         // Create HTTP client
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(token) = &config.api_token {
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| GitError::Config("IPFS API token contains invalid characters".to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
         let http = HttpClient::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .default_headers(headers)
+            .build()
+            .map_err(|e| GitError::IpfsError(format!("Failed to create HTTP client: {}", e)))?;
+        let gateway_http = HttpClient::builder()
             .timeout(std::time::Duration::from_secs(30))
             .build()
             .map_err(|e| GitError::IpfsError(format!("Failed to create HTTP client: {}", e)))?;
@@ -131,10 +155,18 @@ impl IpfsClient {
         let client = Self {
             config,
             http,
+            gateway_http,
+            tor_transport: None,
         };
         
-        // Check if the IPFS node is available
-        client.is_available().await?;
+        // Check if the IPFS node is available; with gateways configured,
+        // reads can still succeed without it
+        if let Err(e) = client.is_available().await {
+            if client.config.gateway_urls.is_empty() {
+                return Err(e);
+            }
+            log::warn!("{}; reading through gateways only", e);
+        }
         
         Ok(client)
     }
@@ -434,26 +466,64 @@ impl IpfsClient {
         Ok(())
     }
     
+    /// Read from gateways through `transport` instead of connecting directly
+    ///
+    /// Only `http://` gateways, onion services among them, can be reached
+    /// this way; the others are skipped rather than contacted in the clear.
+    pub fn with_tor_transport(mut self, transport: Arc<PooledTorTransport>) -> Self {
+        self.tor_transport = Some(transport);
+        self
+    }
+    
     /// Get a file from IPFS by CID
     pub async fn get_file(&self, cid: &str) -> Result<Bytes> {
-        let url = format!("{}/api/v0/cat?arg={}", self.config.api_url, cid);
-        
-        let response = self.http.post(&url)
-            .send()
-            .await
-            .map_err(|e| GitError::IpfsError(format!("Failed to get file from IPFS: {}", e)))?;
-            
+        self.get_file_with_timeout(cid, DEFAULT_READ_TIMEOUT).await
+    }
+
+    /// Get a file from IPFS by CID, giving each endpoint `timeout` to answer
+    ///
+    /// The local API is asked first, then each configured gateway in order;
+    /// the first to return the file wins. If all of them fail, the error
+    /// lists what each endpoint reported. Gateways are not trusted: callers
+    /// must check the data against what they asked for.
+    pub async fn get_file_with_timeout(&self, cid: &str, timeout: Duration) -> Result<Bytes> {
+        let local = format!("{}/api/v0/cat?arg={}", self.config.api_url, cid);
+        let mut failures = Vec::new();
+        match self.fetch(self.http.post(&local).timeout(timeout)).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) => failures.push(format!("local API: {}", e)),
+        }
+
+        for url in self.config.gateway_file_urls(cid) {
+            log::debug!("Fetching {} from gateway {}", cid, url);
+            let fetched = match &self.tor_transport {
+                Some(transport) => fetch_over_tor(transport, &url, timeout).await,
+                None => self.fetch(self.gateway_http.get(&url).timeout(timeout)).await,
+            };
+            match fetched {
+                Ok(bytes) => {
+                    log::info!("Fetched {} from gateway {}", cid, url);
+                    return Ok(bytes);
+                },
+                Err(e) => failures.push(format!("{}: {}", url, e)),
+            }
+        }
+
+        Err(GitError::IpfsError(format!("Failed to get {} from IPFS ({})", cid, failures.join("; "))))
+    }
+
+    /// Send a read request and return the response body
+    async fn fetch(&self, request: reqwest::RequestBuilder) -> std::result::Result<Bytes, String> {
+        let response = request.send().await
+            .map_err(|e| e.to_string())?;
         if !response.status().is_success() {
+            let status = response.status();
             let error = response.text().await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-                
-            return Err(GitError::IpfsError(format!("IPFS cat failed: {}", error)));
+            return Err(format!("{} {}", status, error.trim()));
         }
-        
-        let bytes = response.bytes().await
-            .map_err(|e| GitError::IpfsError(format!("Failed to read response body: {}", e)))?;
-            
-        Ok(bytes)
+        response.bytes().await
+            .map_err(|e| format!("Failed to read response body: {}", e))
     }
     
     /// Get a file from IPFS and save it to a local path
@@ -749,4 +819,15 @@ impl IpfsClient {
     pub fn config_mut(&mut self) -> &mut IpfsConfig {
         &mut self.config
     }
-}
\ No newline at end of file
+}
+
+/// Read `url` from a gateway over Tor
+async fn fetch_over_tor(transport: &PooledTorTransport, url: &str, timeout: Duration) -> std::result::Result<Bytes, String> {
+    let (status, body) = tokio::time::timeout(timeout, transport.http_request("GET", url, &[], &[])).await
+        .map_err(|_| format!("no answer over Tor within {} seconds", timeout.as_secs()))?
+        .map_err(|e| e.to_string())?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{} {}", status, String::from_utf8_lossy(&body).trim()));
+    }
+    Ok(Bytes::from(body))
+}
@@ -2,9 +2,11 @@ use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
 use crate::core::{GitError, Result};
+use super::packed_cache::CacheLayout;
 
 /// Configuration for IPFS integration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct IpfsConfig {
     /// Whether to enable IPFS integration
     #[serde(default = "default_enabled")]
@@ -33,6 +35,22 @@ pub struct IpfsConfig {
     /// Whether to pin objects to the local IPFS node
     #[serde(default = "default_pin_objects")]
     pub pin_objects: bool,
+
+    /// How the local object cache is laid out on disk
+    #[serde(default)]
+    pub cache_layout: CacheLayout,
+
+    /// HTTP gateways to read from when the local daemon fails, in order
+    /// (e.g. `https://ipfs.io`); never used for writes or pins. With Tor
+    /// enabled they are read through it, which only works for `http://`
+    /// gateways such as onion services
+    #[serde(default)]
+    pub gateway_urls: Vec<String>,
+
+    /// Bearer token for the IPFS API, usually set through
+    /// `ARTI_GIT_IPFS_API_TOKEN`; never saved and never sent to gateways
+    #[serde(default, skip_serializing)]
+    pub api_token: Option<String>,
 }
 
 fn default_enabled() -> bool {
@@ -76,6 +94,9 @@ impl Default for IpfsConfig {
             use_local_daemon: default_use_local_daemon(),
             start_daemon_if_needed: default_start_daemon_if_needed(),
             pin_objects: default_pin_objects(),
+            cache_layout: CacheLayout::default(),
+            gateway_urls: Vec::new(),
+            api_token: None,
         }
     }
 }
@@ -91,6 +112,17 @@ impl IpfsConfig {
         format!("{}:{}", self.api_endpoint, self.api_port)
     }
     
+    /// URL of `cid` on each configured gateway
+    pub fn gateway_file_urls(&self, cid: &str) -> Vec<String> {
+        self.gateway_urls.iter()
+            .map(|gateway| {
+                let gateway = gateway.trim_end_matches('/');
+                let gateway = gateway.strip_suffix("/ipfs").unwrap_or(gateway);
+                format!("{}/ipfs/{}", gateway, cid)
+            })
+            .collect()
+    }
+    
     /// Load configuration from a file
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
@@ -4,10 +4,13 @@
 mod config;
 mod client;
 mod storage;
+mod packed_cache;
+mod rabin;
 
 pub use config::IpfsConfig;
 pub use client::IpfsClient;
-pub use storage::{IpfsObjectStorage, IpfsObjectProvider, IpfsStorageError};
+pub use storage::{IpfsObjectStorage, IpfsObjectProvider, IpfsStorageError, ChunkDedupReport, ChunkGcReport, CacheCompactionReport};
+pub use packed_cache::{CacheLayout, PackedCache};
 
 use crate::core::{GitError, Result};
 
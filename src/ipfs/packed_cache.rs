@@ -0,0 +1,464 @@
+//! Packed layout for the local IPFS cache
+//!
+//! Instead of one file per object or chunk, cached data is appended to a few
+//! large data files and located through an append-only index. Each index
+//! line is `<key> <file> <offset> <length>`, or `<key> -` once the entry is
+//! removed; the last line for a key wins. Data is flushed before its index
+//! line is written, so a crash leaves at most some unreferenced bytes that
+//! the next rewrite reclaims, and a torn last index line that the next open
+//! cuts off. An open cache holds `index.lock`, so two processes never append
+//! to the same files.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use serde::{Serialize, Deserialize};
+
+use crate::core::{GitError, LockFile, Result, io_err};
+
+/// Directory below the cache directory holding the packed layout
+pub const PACKED_CACHE_DIR: &str = "packed";
+
+/// Name of the index file inside the packed directory
+const INDEX_FILE: &str = "index";
+
+/// Data files are rotated once they reach this size
+const MAX_DATA_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// How the local IPFS cache stores objects and chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheLayout {
+    /// One file per object or chunk
+    Loose,
+    /// A few append-only data files with an index
+    Packed,
+}
+
+impl Default for CacheLayout {
+    fn default() -> Self {
+        CacheLayout::Loose
+    }
+}
+
+/// Where an entry lives in the data files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PackedEntry {
+    file: u32,
+    offset: u64,
+    len: u64,
+}
+
+/// A packed cache directory
+#[derive(Debug)]
+pub struct PackedCache {
+    dir: PathBuf,
+    entries: HashMap<String, PackedEntry>,
+    /// Live keys per data location; a renamed entry briefly has two
+    refs: HashMap<(u32, u64), usize>,
+    /// Data file receiving appends
+    current: u32,
+    current_len: u64,
+    /// Bytes in data files that no entry refers to any more
+    dead_bytes: u64,
+    index: File,
+    /// Held while the cache is open and released on drop
+    _lock: LockFile,
+}
+
+impl PackedCache {
+    /// Check whether `cache_dir` has a packed cache
+    pub fn exists(cache_dir: &Path) -> bool {
+        cache_dir.join(PACKED_CACHE_DIR).join(INDEX_FILE).is_file()
+    }
+
+    /// Open the packed cache below `cache_dir`, creating it if needed
+    ///
+    /// Fails with [`GitError::Locked`] while another process has it open.
+    pub fn open(cache_dir: &Path) -> Result<Self> {
+        let dir = cache_dir.join(PACKED_CACHE_DIR);
+        fs::create_dir_all(&dir)
+            .map_err(|e| io_err(format!("Failed to create packed cache directory: {}", e), &dir))?;
+
+        let index_path = dir.join(INDEX_FILE);
+        let lock = LockFile::acquire(&index_path)?;
+        let mut entries = HashMap::new();
+        let mut refs = HashMap::new();
+        let mut dead_bytes = 0;
+        if index_path.is_file() {
+            let file = File::open(&index_path)
+                .map_err(|e| io_err(format!("Failed to open cache index: {}", e), &index_path))?;
+            let mut reader = BufReader::new(file);
+            let mut line = Vec::new();
+            let mut valid_len = 0u64;
+            loop {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line)
+                    .map_err(|e| io_err(format!("Failed to read cache index: {}", e), &index_path))?;
+                if read == 0 {
+                    break;
+                }
+                let text = String::from_utf8_lossy(&line);
+                let parsed = text.strip_suffix('\n').and_then(parse_index_line);
+                let (key, entry) = match parsed {
+                    Some(parsed) => parsed,
+                    None if !line.ends_with(b"\n") => {
+                        // A torn last line from a crash; its data is simply
+                        // lost, and appends must not continue it
+                        log::warn!("Truncating torn cache index line: {}", text);
+                        truncate_index(&index_path, valid_len)?;
+                        break;
+                    },
+                    None => {
+                        log::warn!("Ignoring invalid cache index line: {}", text.trim_end());
+                        valid_len += read as u64;
+                        continue;
+                    },
+                };
+                valid_len += read as u64;
+                dead_bytes += update_entry(&mut entries, &mut refs, &key, entry);
+            }
+        }
+
+        let current = data_files(&dir)?.into_iter().max().unwrap_or(0);
+        let current_len = fs::metadata(data_path(&dir, current)).map(|m| m.len()).unwrap_or(0);
+        let index = OpenOptions::new().create(true).append(true).open(&index_path)
+            .map_err(|e| io_err(format!("Failed to open cache index: {}", e), &index_path))?;
+
+        log::debug!("Opened packed cache with {} entries", entries.len());
+        Ok(Self { dir, entries, refs, current, current_len, dead_bytes, index, _lock: lock })
+    }
+
+    /// Number of live entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check whether `key` is cached
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Bytes a rewrite would reclaim
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes
+    }
+
+    /// Read the data cached under `key`
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let entry = match self.entries.get(key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let path = data_path(&self.dir, entry.file);
+        let mut file = File::open(&path)
+            .map_err(|e| io_err(format!("Failed to open cache data file: {}", e), &path))?;
+        let mut data = vec![0u8; entry.len as usize];
+        file.seek(SeekFrom::Start(entry.offset))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|e| io_err(format!("Failed to read cached entry {}: {}", key, e), &path))?;
+        Ok(Some(Bytes::from(data)))
+    }
+
+    /// Cache `data` under `key`, replacing any previous entry
+    pub fn put(&mut self, key: &str, data: &[u8]) -> Result<()> {
+        check_key(key)?;
+        if self.current_len > 0 && self.current_len + data.len() as u64 > MAX_DATA_FILE_SIZE {
+            self.current += 1;
+            self.current_len = 0;
+        }
+
+        let path = data_path(&self.dir, self.current);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| io_err(format!("Failed to open cache data file: {}", e), &path))?;
+        file.write_all(data)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| io_err(format!("Failed to append to cache data file: {}", e), &path))?;
+
+        let entry = PackedEntry { file: self.current, offset: self.current_len, len: data.len() as u64 };
+        self.current_len += entry.len;
+        self.append_index(key, Some(entry))
+    }
+
+    /// Drop the entry for `key`; its bytes are reclaimed by [`PackedCache::rewrite`]
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        if !self.entries.contains_key(key) {
+            return Ok(false);
+        }
+        self.append_index(key, None)?;
+        Ok(true)
+    }
+
+    /// Move an entry to another key without copying its data
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+        check_key(to)?;
+        let entry = match self.entries.get(from) {
+            Some(entry) => *entry,
+            None => return Ok(false),
+        };
+        if from == to {
+            return Ok(true);
+        }
+        self.append_index(to, Some(entry))?;
+        self.append_index(from, None)?;
+        Ok(true)
+    }
+
+    fn append_index(&mut self, key: &str, entry: Option<PackedEntry>) -> Result<()> {
+        let line = match entry {
+            Some(entry) => format!("{} {} {} {}\n", key, entry.file, entry.offset, entry.len),
+            None => format!("{} -\n", key),
+        };
+        self.index.write_all(line.as_bytes())
+            .and_then(|_| self.index.flush())
+            .map_err(|e| io_err(format!("Failed to write cache index: {}", e), self.dir.join(INDEX_FILE)))?;
+
+        self.dead_bytes += update_entry(&mut self.entries, &mut self.refs, key, entry);
+        Ok(())
+    }
+
+    /// Copy the live entries into fresh data files and drop the old ones
+    ///
+    /// Returns the number of bytes reclaimed. The new index replaces the old
+    /// one in a single rename, so a crash leaves either the old or the new
+    /// cache, never a mix.
+    pub fn rewrite(&mut self) -> Result<u64> {
+        let old_files = data_files(&self.dir)?;
+        let old_bytes: u64 = old_files.iter()
+            .map(|file| fs::metadata(data_path(&self.dir, *file)).map(|m| m.len()).unwrap_or(0))
+            .sum();
+
+        // Copy in data file order so reads are mostly sequential
+        let mut keys: Vec<(String, PackedEntry)> = self.entries.iter().map(|(k, e)| (k.clone(), *e)).collect();
+        keys.sort_by_key(|(_, entry)| (entry.file, entry.offset));
+
+        let first = old_files.iter().max().map_or(0, |max| max + 1);
+        let mut current = first;
+        let mut current_len = 0u64;
+        let mut writer: Option<File> = None;
+        let mut entries = HashMap::with_capacity(keys.len());
+        let mut index = String::new();
+        for (key, entry) in keys {
+            let data = self.get(&key)?.expect("live entry");
+            if current_len > 0 && current_len + entry.len > MAX_DATA_FILE_SIZE {
+                current += 1;
+                current_len = 0;
+                writer = None;
+            }
+            let path = data_path(&self.dir, current);
+            if writer.is_none() {
+                writer = Some(File::create(&path)
+                    .map_err(|e| io_err(format!("Failed to create cache data file: {}", e), &path))?);
+            }
+            if let Some(file) = writer.as_mut() {
+                file.write_all(&data)
+                    .map_err(|e| io_err(format!("Failed to write cache data file: {}", e), &path))?;
+            }
+            let moved = PackedEntry { file: current, offset: current_len, len: entry.len };
+            current_len += entry.len;
+            index.push_str(&format!("{} {} {} {}\n", key, moved.file, moved.offset, moved.len));
+            entries.insert(key, moved);
+        }
+        if let Some(file) = writer.take() {
+            file.sync_all()
+                .map_err(|e| io_err(format!("Failed to sync cache data file: {}", e), data_path(&self.dir, current)))?;
+        }
+
+        let index_path = self.dir.join(INDEX_FILE);
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)
+            .map_err(|e| io_err(format!("Failed to create cache index: {}", e), &self.dir))?;
+        temp.write_all(index.as_bytes())
+            .and_then(|_| temp.as_file().sync_all())
+            .map_err(|e| io_err(format!("Failed to write cache index: {}", e), temp.path()))?;
+        temp.persist(&index_path)
+            .map_err(|e| io_err(format!("Failed to replace cache index: {}", e), &index_path))?;
+        self.index = OpenOptions::new().append(true).open(&index_path)
+            .map_err(|e| io_err(format!("Failed to open cache index: {}", e), &index_path))?;
+
+        for file in &old_files {
+            let path = data_path(&self.dir, *file);
+            if let Err(e) = fs::remove_file(&path) {
+                log::warn!("Failed to remove old cache data file {}: {}", path.display(), e);
+            }
+        }
+
+        let new_bytes: u64 = entries.values().map(|entry| entry.len).sum();
+        self.refs = entries.values().map(|entry| ((entry.file, entry.offset), 1)).collect();
+        self.entries = entries;
+        self.current = current;
+        self.current_len = current_len;
+        self.dead_bytes = 0;
+        Ok(old_bytes.saturating_sub(new_bytes))
+    }
+
+    /// Number of data files in use
+    pub fn data_file_count(&self) -> usize {
+        self.entries.values().map(|entry| entry.file).collect::<BTreeSet<_>>().len()
+    }
+}
+
+/// Point `key` at `entry`, or drop it for `None`
+///
+/// Returns the bytes that no key refers to any more. Data moved by a
+/// rename stays referenced by its new key, so it is not counted.
+fn update_entry(
+    entries: &mut HashMap<String, PackedEntry>,
+    refs: &mut HashMap<(u32, u64), usize>,
+    key: &str,
+    entry: Option<PackedEntry>,
+) -> u64 {
+    let previous = match entry {
+        Some(entry) => {
+            *refs.entry((entry.file, entry.offset)).or_insert(0) += 1;
+            entries.insert(key.to_string(), entry)
+        },
+        None => entries.remove(key),
+    };
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return 0,
+    };
+    let location = (previous.file, previous.offset);
+    match refs.get_mut(&location) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            0
+        },
+        _ => {
+            refs.remove(&location);
+            previous.len
+        },
+    }
+}
+
+/// Keys end up in a line-based index, so they can't contain whitespace
+fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(GitError::InvalidArgument(format!("Invalid cache key: {:?}", key)));
+    }
+    Ok(())
+}
+
+fn parse_index_line(line: &str) -> Option<(String, Option<PackedEntry>)> {
+    let mut parts = line.split(' ');
+    let key = parts.next().filter(|key| !key.is_empty())?.to_string();
+    let rest: Vec<&str> = parts.collect();
+    match rest.as_slice() {
+        ["-"] => Some((key, None)),
+        [file, offset, len] => Some((key, Some(PackedEntry {
+            file: file.parse().ok()?,
+            offset: offset.parse().ok()?,
+            len: len.parse().ok()?,
+        }))),
+        _ => None,
+    }
+}
+
+/// Cut the index back to its first `len` bytes
+fn truncate_index(index_path: &Path, len: u64) -> Result<()> {
+    OpenOptions::new().write(true).open(index_path)
+        .and_then(|file| {
+            file.set_len(len)?;
+            file.sync_all()
+        })
+        .map_err(|e| io_err(format!("Failed to truncate cache index: {}", e), index_path))
+}
+
+fn data_path(dir: &Path, file: u32) -> PathBuf {
+    dir.join(format!("data-{:06}.pack", file))
+}
+
+/// Numbers of the data files present in `dir`
+fn data_files(dir: &Path) -> Result<Vec<u32>> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| io_err(format!("Failed to read packed cache directory: {}", e), dir))?;
+    Ok(entries.flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_prefix("data-")?.strip_suffix(".pack")?.parse().ok()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_remove_rewrite_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!PackedCache::exists(dir.path()));
+
+        let mut cache = PackedCache::open(dir.path()).unwrap();
+        cache.put("object/aa", b"first").unwrap();
+        cache.put("chunk/bb", b"second").unwrap();
+        cache.put("object/aa", b"replaced").unwrap();
+        cache.remove("chunk/bb").unwrap();
+        assert!(cache.rename("object/aa", "object/cc").unwrap());
+        assert_eq!(cache.dead_bytes(), 11);
+        assert!(cache.rename("object/cc", "object/cc").unwrap());
+        assert_eq!(cache.dead_bytes(), 11);
+        assert!(cache.put("bad key", b"x").is_err());
+        drop(cache);
+
+        let mut cache = PackedCache::open(dir.path()).unwrap();
+        assert!(PackedCache::exists(dir.path()));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.dead_bytes(), 11);
+        assert_eq!(cache.get("object/cc").unwrap().unwrap(), Bytes::from_static(b"replaced"));
+        assert!(cache.get("object/aa").unwrap().is_none());
+
+        assert_eq!(cache.rewrite().unwrap(), 11);
+        assert_eq!(cache.get("object/cc").unwrap().unwrap(), Bytes::from_static(b"replaced"));
+        cache.put("chunk/dd", b"after").unwrap();
+        drop(cache);
+
+        let cache = PackedCache::open(dir.path()).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.dead_bytes(), 0);
+        assert_eq!(cache.data_file_count(), 1);
+        assert_eq!(cache.get("chunk/dd").unwrap().unwrap(), Bytes::from_static(b"after"));
+    }
+
+    #[test]
+    fn test_open_cache_is_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PackedCache::open(dir.path()).unwrap();
+        assert!(matches!(PackedCache::open(dir.path()), Err(GitError::Locked(_))));
+        drop(cache);
+        assert!(PackedCache::open(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn test_torn_index_line_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = PackedCache::open(dir.path()).unwrap();
+        cache.put("object/aa", b"first").unwrap();
+        drop(cache);
+
+        // A crash in the middle of writing the next index line
+        let index_path = dir.path().join(PACKED_CACHE_DIR).join(INDEX_FILE);
+        let intact = fs::read(&index_path).unwrap();
+        let mut index = OpenOptions::new().append(true).open(&index_path).unwrap();
+        index.write_all(b"object/bb 0 5").unwrap();
+        drop(index);
+
+        let mut cache = PackedCache::open(dir.path()).unwrap();
+        assert_eq!(fs::read(&index_path).unwrap(), intact);
+        assert_eq!(cache.len(), 1);
+        cache.put("object/cc", b"second").unwrap();
+        drop(cache);
+
+        let cache = PackedCache::open(dir.path()).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("object/cc").unwrap().unwrap(), Bytes::from_static(b"second"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Content-defined chunking with a Rabin fingerprint
+//!
+//! A window of the last `window_size` bytes is hashed as a polynomial over
+//! GF(2), reduced modulo a fixed irreducible polynomial, and a chunk ends
+//! where the low bits of the fingerprint are all zero. The fingerprint only
+//! depends on the bytes in the window, so an insertion or deletion moves the
+//! boundaries around it and leaves the ones further on where they were.
+
+use super::storage::ChunkingStrategy;
+
+/// Irreducible polynomial of degree 53 the fingerprints are reduced by
+const POLYNOMIAL: u64 = 0x003D_A335_8B4D_C173;
+const POLYNOMIAL_DEGREE: u32 = 53;
+/// Shift that brings the top byte of a fingerprint down to the low bits
+const TOP_BYTE_SHIFT: u32 = POLYNOMIAL_DEGREE - 8;
+
+/// Degree of a polynomial over GF(2), or -1 for zero
+fn degree(p: u64) -> i32 {
+    63 - p.leading_zeros() as i32
+}
+
+/// Remainder of dividing `x` by `p` over GF(2)
+fn poly_mod(mut x: u64, p: u64) -> u64 {
+    while degree(x) >= degree(p) {
+        x ^= p << (degree(x) - degree(p));
+    }
+    x
+}
+
+/// Append a byte to a fingerprint, without sliding the window
+fn append_byte(fingerprint: u64, byte: u8) -> u64 {
+    poly_mod((fingerprint << 8) | byte as u64, POLYNOMIAL)
+}
+
+/// Rolling Rabin fingerprint over a fixed-size window
+struct RollingHash {
+    window: Vec<u8>,
+    pos: usize,
+    fingerprint: u64,
+    /// What each byte contributes once it is about to leave the window
+    out_table: [u64; 256],
+    /// Reduction of each possible top byte after shifting the fingerprint
+    mod_table: [u64; 256],
+}
+
+impl RollingHash {
+    fn new(window_size: usize) -> Self {
+        let window_size = window_size.max(1);
+        let mut out_table = [0u64; 256];
+        let mut mod_table = [0u64; 256];
+        for byte in 0..256u64 {
+            let mut hash = append_byte(0, byte as u8);
+            for _ in 1..window_size {
+                hash = append_byte(hash, 0);
+            }
+            out_table[byte as usize] = hash;
+            mod_table[byte as usize] = poly_mod(byte << POLYNOMIAL_DEGREE, POLYNOMIAL) | (byte << POLYNOMIAL_DEGREE);
+        }
+        Self { window: vec![0; window_size], pos: 0, fingerprint: 0, out_table, mod_table }
+    }
+
+    /// Push `byte` into the window, dropping the oldest one
+    fn slide(&mut self, byte: u8) -> u64 {
+        let out = std::mem::replace(&mut self.window[self.pos], byte);
+        self.pos = (self.pos + 1) % self.window.len();
+        self.fingerprint ^= self.out_table[out as usize];
+
+        let top = (self.fingerprint >> TOP_BYTE_SHIFT) as usize;
+        self.fingerprint = ((self.fingerprint << 8) | byte as u64) ^ self.mod_table[top];
+        self.fingerprint
+    }
+}
+
+/// Find where the chunks of `data` end
+///
+/// Returns the exclusive end offset of every chunk; the last one is
+/// `data.len()`. Chunks are at least `min_chunk_size` bytes (except the
+/// last), at most `max_chunk_size`, and `target_chunk_size` on average.
+pub(crate) fn chunk_boundaries(data: &[u8], strategy: &ChunkingStrategy) -> Vec<usize> {
+    let min_size = strategy.min_chunk_size.max(1);
+    let max_size = strategy.max_chunk_size.max(min_size);
+    // A boundary is a fingerprint with this many low zero bits, which occurs
+    // once every `target_chunk_size` bytes on average
+    let bits = usize::BITS - 1 - strategy.target_chunk_size.max(1).leading_zeros();
+    let mask = (1u64 << bits) - 1;
+
+    let mut hash = RollingHash::new(strategy.window_size);
+    let mut boundaries = Vec::new();
+    let mut start = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        // Keep rolling inside short chunks so the fingerprint at every
+        // position depends only on the window, not on where the chunk began
+        let fingerprint = hash.slide(byte);
+        let len = i + 1 - start;
+        if (len >= min_size && fingerprint & mask == 0) || len >= max_size {
+            boundaries.push(i + 1);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        boundaries.push(data.len());
+    }
+    boundaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs::storage::ChunkingAlgorithm;
+
+    fn strategy() -> ChunkingStrategy {
+        ChunkingStrategy {
+            min_chunk_size: 256,
+            max_chunk_size: 8192,
+            target_chunk_size: 1024,
+            window_size: 48,
+            algorithm: ChunkingAlgorithm::Rabin,
+        }
+    }
+
+    /// Pseudo-random bytes from a xorshift generator
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.max(1);
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn chunks<'a>(data: &'a [u8], boundaries: &[usize]) -> Vec<&'a [u8]> {
+        let mut start = 0;
+        boundaries.iter().map(|&end| {
+            let chunk = &data[start..end];
+            start = end;
+            chunk
+        }).collect()
+    }
+
+    #[test]
+    fn test_identical_streams_chunk_identically() {
+        let strategy = strategy();
+        for seed in 1..=8 {
+            let data = random_bytes(seed, 64 * 1024);
+            let boundaries = chunk_boundaries(&data, &strategy);
+            assert_eq!(boundaries, chunk_boundaries(&data.clone(), &strategy));
+            assert_eq!(boundaries.last(), Some(&data.len()));
+
+            let sizes: Vec<usize> = chunks(&data, &boundaries).iter().map(|chunk| chunk.len()).collect();
+            assert!(sizes.len() > 8, "seed {} gave only {} chunks", seed, sizes.len());
+            assert!(sizes.iter().all(|&size| size <= strategy.max_chunk_size));
+            assert!(sizes[..sizes.len() - 1].iter().all(|&size| size >= strategy.min_chunk_size));
+        }
+    }
+
+    #[test]
+    fn test_insertion_only_moves_nearby_boundaries() {
+        let strategy = strategy();
+        for seed in 1..=8 {
+            let data = random_bytes(seed, 64 * 1024);
+            let mut edited = data.clone();
+            edited.insert(100, 0xAB);
+
+            let original = chunk_boundaries(&data, &strategy);
+            let shifted = chunk_boundaries(&edited, &strategy);
+            let original_chunks = chunks(&data, &original);
+            let edited_chunks = chunks(&edited, &shifted);
+
+            // Every boundary after the edit is found again, one byte later
+            let unchanged = edited_chunks.iter().filter(|chunk| original_chunks.contains(chunk)).count();
+            assert!(unchanged + 2 >= edited_chunks.len(), "seed {}: only {} of {} chunks survived", seed, unchanged, edited_chunks.len());
+            assert_eq!(&original[2..].iter().map(|end| end + 1).collect::<Vec<_>>(), &shifted[2..]);
+        }
+    }
+}
@@ -11,9 +11,11 @@ use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use rayon::prelude::*;
 
-use crate::core::{GitError, Result, ObjectType, io_err};
+use crate::core::{GitError, Result, ObjectType, io_err, hash_object};
 use super::client::IpfsClient;
 use super::config::IpfsConfig;
+use super::packed_cache::{CacheLayout, PackedCache};
+use super::rabin;
 
 /// IPFS object storage error
 #[derive(Debug)]
@@ -184,6 +186,55 @@ struct ObjectChunk {
     ref_count: usize,
 }
 
+/// Result of consolidating chunks that store the same content
+#[derive(Debug, Default, Clone)]
+pub struct ChunkDedupReport {
+    /// Chunks whose content was checked
+    pub chunks_scanned: usize,
+    /// Chunks that could be read neither from the cache nor from IPFS
+    pub unreadable_chunks: usize,
+    /// Chunks folded into a canonical chunk with the same content
+    pub duplicate_chunks: usize,
+    /// Object mappings rewritten to point at canonical chunks
+    pub mappings_updated: usize,
+    /// Bytes no longer pinned once the duplicates are unpinned
+    pub bytes_reclaimed: usize,
+    /// Duplicate CIDs that were (or, in a dry run, would be) unpinned
+    pub unpinned: Vec<String>,
+}
+
+/// Result of sweeping chunks no object references
+#[derive(Debug, Default, Clone)]
+pub struct ChunkGcReport {
+    /// Chunks whose references were checked
+    pub chunks_scanned: usize,
+    /// Chunks dropped because no object mapping lists them
+    pub orphaned_chunks: usize,
+    /// Chunks kept with a reference count corrected to the mappings
+    pub counts_repaired: usize,
+    /// Bytes no longer pinned once the orphans are unpinned
+    pub bytes_reclaimed: usize,
+    /// Orphaned CIDs that were unpinned
+    pub unpinned: Vec<String>,
+}
+
+/// Result of compacting the local cache
+#[derive(Debug, Default, Clone)]
+pub struct CacheCompactionReport {
+    /// Cached objects moved from their own file into the packed cache
+    pub migrated_objects: usize,
+    /// Cached chunks moved from their own file into the packed cache
+    pub migrated_chunks: usize,
+    /// Files and directories removed from the per-file layout
+    pub files_removed: usize,
+    /// Entries in the packed cache afterwards
+    pub packed_entries: usize,
+    /// Data files backing the packed cache afterwards
+    pub data_files: usize,
+    /// Bytes of replaced or removed entries dropped from the data files
+    pub bytes_reclaimed: u64,
+}
+
 /// Cache statistics for monitoring
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct CacheStats {
@@ -203,6 +254,12 @@ pub struct CacheStats {
     pub unique_chunks: usize,
     /// Number of total chunks (including duplicates)
     pub total_chunks: usize,
+    /// Objects removed from storage
+    #[serde(default)]
+    pub objects_removed: usize,
+    /// Chunks unpinned after their last reference went away
+    #[serde(default)]
+    pub chunks_unpinned: usize,
 }
 
 impl CacheStats {
@@ -255,6 +312,9 @@ pub struct IpfsStorageSettings {
     pub use_background_uploads: bool,
     /// Maximum size of the local cache (in bytes, 0 = unlimited)
     pub max_cache_size: usize,
+    /// Where newly cached objects and chunks are written
+    #[serde(default)]
+    pub cache_layout: CacheLayout,
 }
 
 impl Default for IpfsStorageSettings {
@@ -269,6 +329,7 @@ impl Default for IpfsStorageSettings {
             timeout_seconds: 120,
             use_background_uploads: true,
             max_cache_size: 1024 * 1024 * 1024, // 1 GB
+            cache_layout: CacheLayout::default(),
         }
     }
 }
@@ -309,8 +370,22 @@ struct BackgroundUploadTask {
     status: UploadStatus,
 }
 
+/// A background upload as recorded in `pending_uploads.json`
+///
+/// The data itself waits in the local cache under the Git object ID until
+/// the upload completes, so the record only needs to point at it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingUpload {
+    /// The Git object ID, which is also the key of the cached data
+    git_id: String,
+    /// The object type
+    object_type: String,
+    /// Status of the upload when last saved
+    status: UploadStatus,
+}
+
 /// Upload status for background tasks
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 enum UploadStatus {
     /// Upload is pending
     Pending,
@@ -338,12 +413,18 @@ pub struct IpfsObjectStorage {
     
     /// Local cache directory
     cache_dir: PathBuf,
+
+    /// Packed layout of the local cache, opened when in use
+    packed: Arc<std::sync::Mutex<Option<PackedCache>>>,
     
     /// Mappings file path
     mappings_file: PathBuf,
     
     /// Chunks file path
     chunks_file: PathBuf,
+
+    /// Pending background uploads file path
+    pending_file: PathBuf,
     
     /// Enable local caching of objects
     cache_enabled: bool,
@@ -356,6 +437,9 @@ pub struct IpfsObjectStorage {
 
     /// Background upload tasks
     background_tasks: Arc<Mutex<HashMap<String, BackgroundUploadTask>>>,
+
+    /// Handles of spawned background uploads not yet waited for
+    upload_handles: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
 }
 
 impl IpfsObjectStorage {
@@ -385,13 +469,23 @@ impl IpfsObjectStorage {
         fs::create_dir_all(&cache_dir)
             .map_err(|e| io_err(format!("Failed to create cache directory: {}", e), &cache_dir))?;
         
-        let objects_dir = cache_dir.join("objects");
-        fs::create_dir_all(&objects_dir)
-            .map_err(|e| io_err(format!("Failed to create objects directory: {}", e), &objects_dir))?;
-        
-        let chunks_dir = cache_dir.join("chunks");
-        fs::create_dir_all(&chunks_dir)
-            .map_err(|e| io_err(format!("Failed to create chunks directory: {}", e), &chunks_dir))?;
+        if settings.cache_layout == CacheLayout::Loose {
+            let objects_dir = cache_dir.join("objects");
+            fs::create_dir_all(&objects_dir)
+                .map_err(|e| io_err(format!("Failed to create objects directory: {}", e), &objects_dir))?;
+
+            let chunks_dir = cache_dir.join("chunks");
+            fs::create_dir_all(&chunks_dir)
+                .map_err(|e| io_err(format!("Failed to create chunks directory: {}", e), &chunks_dir))?;
+        }
+
+        // A packed cache is read whatever the layout, so entries stay
+        // reachable after switching back to loose files
+        let packed = if settings.cache_layout == CacheLayout::Packed || PackedCache::exists(&cache_dir) {
+            Some(PackedCache::open(&cache_dir)?)
+        } else {
+            None
+        };
         
         let mappings_file = cache_dir.join("mappings.json");
         let chunks_file = cache_dir.join("chunks.json");
@@ -477,19 +571,25 @@ impl IpfsObjectStorage {
         log::info!("IPFS object storage initialized with {} existing mappings and {} chunks",
                   mappings.len(), chunks.len());
         
-        Ok(Self {
+        let pending_file = cache_dir.join("pending_uploads.json");
+        let storage = Self {
             client,
             mappings: Arc::new(RwLock::new(mappings)),
             chunks: Arc::new(RwLock::new(chunks)),
             content_to_git: Arc::new(RwLock::new(content_to_git)),
             cache_dir,
+            packed: Arc::new(std::sync::Mutex::new(packed)),
             mappings_file,
             chunks_file,
             cache_enabled: true,
+            pending_file,
             stats: Arc::new(RwLock::new(stats)),
             settings,
             background_tasks: Arc::new(Mutex::new(HashMap::new())),
-        })
+            upload_handles: Arc::new(Mutex::new(Vec::new())),
+        };
+        storage.resume_background_uploads().await;
+        Ok(storage)
     }
 
     /// Set advanced storage settings
@@ -497,6 +597,12 @@ impl IpfsObjectStorage {
         self.settings = settings;
         self
     }
+
+    /// Choose the on-disk layout for newly cached objects and chunks
+    pub fn with_cache_layout(mut self, layout: CacheLayout) -> Self {
+        self.settings.cache_layout = layout;
+        self
+    }
     
     /// Enable or disable local caching
     pub fn set_caching(&mut self, enabled: bool) {
@@ -543,6 +649,312 @@ impl IpfsObjectStorage {
         Ok(())
     }
     
+    /// Save mappings and chunks together
+    ///
+    /// Both files are written out before either replaces its previous
+    /// version, so a failed write leaves the old pair in place. The two
+    /// renames are not one atomic step: chunks are replaced first, so a
+    /// crash in between leaves old mappings with new chunks, whose extra
+    /// chunks and stale counts [`IpfsObjectStorage::gc`] repairs.
+    async fn save_index(&self) -> Result<()> {
+        let mappings_json = {
+            let mappings = self.mappings.read().await;
+            let mappings_list: Vec<&ObjectMapping> = mappings.values().collect();
+            serde_json::to_string_pretty(&mappings_list)
+                .map_err(|e| GitError::IpfsError(format!("Failed to serialize mappings: {}", e)))?
+        };
+        let chunks_json = {
+            let chunks = self.chunks.read().await;
+            let chunks_list: Vec<&ObjectChunk> = chunks.values().collect();
+            serde_json::to_string_pretty(&chunks_list)
+                .map_err(|e| GitError::IpfsError(format!("Failed to serialize chunks: {}", e)))?
+        };
+
+        let mappings_temp = self.mappings_file.with_extension("tmp");
+        let chunks_temp = self.chunks_file.with_extension("tmp");
+        fs::write(&mappings_temp, mappings_json)
+            .map_err(|e| io_err(format!("Failed to write mappings file: {}", e), &mappings_temp))?;
+        fs::write(&chunks_temp, chunks_json)
+            .map_err(|e| io_err(format!("Failed to write chunks file: {}", e), &chunks_temp))?;
+
+        fs::rename(&chunks_temp, &self.chunks_file)
+            .map_err(|e| io_err(format!("Failed to rename chunks file: {}", e), &self.chunks_file))?;
+        fs::rename(&mappings_temp, &self.mappings_file)
+            .map_err(|e| io_err(format!("Failed to rename mappings file: {}", e), &self.mappings_file))?;
+
+        Ok(())
+    }
+    
+    /// Time each IPFS endpoint gets to answer a read
+    fn read_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.settings.timeout_seconds)
+    }
+    
+    /// Directory holding the local cache of IPFS objects and chunks
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+    
+    /// Fold chunks with identical content under different CIDs into one
+    ///
+    /// Chunks stored before a chunking or hashing change can hold the same
+    /// bytes under another key and CID. Every chunk's content is re-hashed
+    /// with the current algorithm (read from the local cache, or IPFS when
+    /// not cached); for each group of equal chunks one canonical chunk is
+    /// kept, object mappings are rewritten to it and the duplicates are
+    /// unpinned. With `dry_run`, nothing is changed and the report says what
+    /// would be reclaimed.
+    pub async fn consolidate_chunks(&self, dry_run: bool) -> Result<ChunkDedupReport> {
+        let mut report = ChunkDedupReport::default();
+        let snapshot: Vec<ObjectChunk> = self.chunks.read().await.values().cloned().collect();
+
+        // Group chunks by the hash of their actual content
+        let mut by_content: HashMap<String, Vec<ObjectChunk>> = HashMap::new();
+        for chunk in snapshot {
+            report.chunks_scanned += 1;
+            let data = if self.is_chunk_in_cache(&chunk.content_hash) {
+                self.get_chunk_from_cache(&chunk.content_hash)
+            } else {
+                self.client.get_file_with_timeout(&chunk.ipfs_cid, self.read_timeout()).await
+            };
+            match data {
+                Ok(data) => by_content.entry(self.calculate_content_hash(&data)).or_default().push(chunk),
+                Err(e) => {
+                    log::warn!("Skipping unreadable chunk {}: {}", chunk.ipfs_cid, e);
+                    report.unreadable_chunks += 1;
+                }
+            }
+        }
+
+        // An unchunked object can have the same CID as a duplicate chunk;
+        // that pin must stay
+        let object_cids: HashSet<String> = self.mappings.read().await.values()
+            .map(|m| m.ipfs_cid.clone())
+            .collect();
+
+        // Pick a canonical chunk per group: one already keyed by the current
+        // hash if there is one, else the most referenced
+        let mut replacements: HashMap<String, String> = HashMap::new();
+        let mut merged = Vec::new();
+        for (hash, mut group) in by_content {
+            if group.len() < 2 {
+                continue;
+            }
+            group.sort_by(|a, b| {
+                (b.content_hash == hash).cmp(&(a.content_hash == hash))
+                    .then(b.ref_count.cmp(&a.ref_count))
+                    .then(a.ipfs_cid.cmp(&b.ipfs_cid))
+            });
+            let canonical = group.remove(0);
+            for duplicate in &group {
+                report.duplicate_chunks += 1;
+                if duplicate.ipfs_cid != canonical.ipfs_cid {
+                    replacements.insert(duplicate.ipfs_cid.clone(), canonical.ipfs_cid.clone());
+                    if !object_cids.contains(&duplicate.ipfs_cid) && !report.unpinned.contains(&duplicate.ipfs_cid) {
+                        report.unpinned.push(duplicate.ipfs_cid.clone());
+                        report.bytes_reclaimed += duplicate.size;
+                    }
+                }
+            }
+            merged.push((hash, canonical, group));
+        }
+
+        let mappings_to_update = self.mappings.read().await.values()
+            .filter(|m| m.is_chunked && m.chunk_cids.iter().any(|cid| replacements.contains_key(cid)))
+            .count();
+        report.mappings_updated = mappings_to_update;
+        if dry_run || merged.is_empty() {
+            return Ok(report);
+        }
+
+        {
+            let mut chunks = self.chunks.write().await;
+            for (hash, canonical, duplicates) in &merged {
+                let mut canonical = canonical.clone();
+                chunks.remove(&canonical.content_hash);
+                for duplicate in duplicates {
+                    chunks.remove(&duplicate.content_hash);
+                    canonical.ref_count += duplicate.ref_count;
+                }
+                // Re-key under the current hash so new stores dedup against it
+                if canonical.content_hash != *hash && self.is_chunk_in_cache(&canonical.content_hash) {
+                    self.rename_cached_chunk(&canonical.content_hash, hash);
+                }
+                canonical.content_hash = hash.clone();
+                chunks.insert(hash.clone(), canonical);
+            }
+        }
+        {
+            let mut mappings = self.mappings.write().await;
+            for mapping in mappings.values_mut().filter(|m| m.is_chunked) {
+                for cid in mapping.chunk_cids.iter_mut() {
+                    if let Some(canonical) = replacements.get(cid) {
+                        *cid = canonical.clone();
+                    }
+                }
+            }
+        }
+        self.save_mappings().await?;
+        self.save_chunks().await?;
+        self.stats.write().await.unique_chunks = self.chunks.read().await.len();
+
+        // Mappings are safe on disk; now drop the duplicates themselves
+        for (_, _, duplicates) in &merged {
+            for duplicate in duplicates {
+                self.remove_cached_chunk(&duplicate.content_hash);
+            }
+        }
+        for cid in &report.unpinned {
+            if let Err(e) = self.client.unpin(cid).await {
+                log::warn!("Failed to unpin duplicate chunk {}: {}", cid, e);
+            }
+        }
+
+        log::info!("Consolidated {} duplicate chunks, reclaiming {} bytes",
+                  report.duplicate_chunks, report.bytes_reclaimed);
+        Ok(report)
+    }
+    
+    /// Remove a Git object and release the chunks only it referenced
+    ///
+    /// The object's mapping is dropped and every chunk it lists loses a
+    /// reference. Chunks left without references are unpinned and evicted
+    /// from the local cache, as is the object's own CID when no other object
+    /// shares it. Returns `false` if the object isn't stored here.
+    pub async fn remove_object(&self, id: &ObjectId) -> Result<bool> {
+        let key = id.to_string();
+        let mut released_chunks = Vec::new();
+        let mut unpin = Vec::new();
+        let mapping = {
+            let mut mappings = self.mappings.write().await;
+            let mapping = match mappings.remove(&key) {
+                Some(mapping) => mapping,
+                None => return Ok(false),
+            };
+            // Deduplicated objects share CIDs, so only release what no
+            // remaining mapping still points at
+            let still_referenced: HashSet<&String> = mappings.values()
+                .flat_map(|m| m.chunk_cids.iter().chain(std::iter::once(&m.ipfs_cid)))
+                .collect();
+
+            let mut chunks = self.chunks.write().await;
+            for cid in &mapping.chunk_cids {
+                let content_hash = match chunks.values().find(|chunk| &chunk.ipfs_cid == cid) {
+                    Some(chunk) => chunk.content_hash.clone(),
+                    None => continue,
+                };
+                if let Some(chunk) = chunks.get_mut(&content_hash) {
+                    chunk.ref_count = chunk.ref_count.saturating_sub(1);
+                    if chunk.ref_count == 0 && !still_referenced.contains(cid) {
+                        chunks.remove(&content_hash);
+                        released_chunks.push(content_hash);
+                        unpin.push(cid.clone());
+                    }
+                }
+            }
+            if !mapping.chunk_cids.contains(&mapping.ipfs_cid) && !still_referenced.contains(&mapping.ipfs_cid) {
+                unpin.push(mapping.ipfs_cid.clone());
+            }
+            mapping
+        };
+        self.content_to_git.write().await.retain(|_, git_id| *git_id != key);
+
+        {
+            let mut stats = self.stats.write().await;
+            stats.objects_stored = stats.objects_stored.saturating_sub(1);
+            stats.total_bytes_stored = stats.total_bytes_stored.saturating_sub(mapping.size);
+            if mapping.is_chunked {
+                stats.chunked_objects = stats.chunked_objects.saturating_sub(1);
+                stats.total_chunks = stats.total_chunks.saturating_sub(mapping.chunk_cids.len());
+            }
+            stats.unique_chunks = self.chunks.read().await.len();
+            stats.objects_removed += 1;
+            stats.chunks_unpinned += released_chunks.len();
+        }
+        self.save_index().await?;
+
+        // The index no longer lists them; now drop the data itself
+        self.remove_cached_object(id);
+        for content_hash in &released_chunks {
+            self.remove_cached_chunk(content_hash);
+        }
+        for cid in &unpin {
+            if let Err(e) = self.client.unpin(cid).await {
+                log::warn!("Failed to unpin {}: {}", cid, e);
+            }
+        }
+
+        log::debug!("Removed object {}, releasing {} chunks", id, released_chunks.len());
+        Ok(true)
+    }
+
+    /// Sweep chunks that no object mapping references
+    ///
+    /// Reference counts of the chunks that stay are recomputed from the
+    /// mappings, which repairs counts left behind by an interrupted store or
+    /// removal. Orphans are unpinned and evicted from the local cache once
+    /// the updated mappings and chunks are saved.
+    pub async fn gc(&self) -> Result<ChunkGcReport> {
+        let mut report = ChunkGcReport::default();
+        let mut orphans = Vec::new();
+        {
+            let mappings = self.mappings.read().await;
+            let mut references: HashMap<&str, usize> = HashMap::new();
+            for mapping in mappings.values() {
+                for cid in &mapping.chunk_cids {
+                    *references.entry(cid.as_str()).or_default() += 1;
+                }
+            }
+            // An unchunked object can have the same CID as a chunk with the
+            // same bytes; that pin must stay
+            let object_cids: HashSet<&str> = mappings.values().map(|m| m.ipfs_cid.as_str()).collect();
+
+            let mut chunks = self.chunks.write().await;
+            report.chunks_scanned = chunks.len();
+            chunks.retain(|content_hash, chunk| match references.get(chunk.ipfs_cid.as_str()) {
+                Some(&count) => {
+                    if chunk.ref_count != count {
+                        chunk.ref_count = count;
+                        report.counts_repaired += 1;
+                    }
+                    true
+                }
+                None => {
+                    report.orphaned_chunks += 1;
+                    orphans.push(content_hash.clone());
+                    if !object_cids.contains(chunk.ipfs_cid.as_str()) && !report.unpinned.contains(&chunk.ipfs_cid) {
+                        report.unpinned.push(chunk.ipfs_cid.clone());
+                        report.bytes_reclaimed += chunk.size;
+                    }
+                    false
+                }
+            });
+        }
+        if report.orphaned_chunks == 0 && report.counts_repaired == 0 {
+            return Ok(report);
+        }
+
+        {
+            let mut stats = self.stats.write().await;
+            stats.unique_chunks = self.chunks.read().await.len();
+            stats.chunks_unpinned += report.unpinned.len();
+        }
+        self.save_index().await?;
+
+        for content_hash in &orphans {
+            self.remove_cached_chunk(content_hash);
+        }
+        for cid in &report.unpinned {
+            if let Err(e) = self.client.unpin(cid).await {
+                log::warn!("Failed to unpin orphaned chunk {}: {}", cid, e);
+            }
+        }
+
+        log::info!("Swept {} orphaned chunks, reclaiming {} bytes",
+                  report.orphaned_chunks, report.bytes_reclaimed);
+        Ok(report)
+    }
+    
     /// Get path for a cached object
     fn get_object_path(&self, id: &ObjectId) -> PathBuf {
         let id_str = id.to_string();
@@ -560,40 +972,57 @@ impl IpfsObjectStorage {
         self.cache_dir.join("chunks").join(prefix).join(suffix)
     }
     
+    /// Key of an object in the packed cache
+    fn object_key(id: &ObjectId) -> String {
+        format!("object/{}", id)
+    }
+
+    /// Key of a chunk in the packed cache
+    fn chunk_key(content_hash: &str) -> String {
+        format!("chunk/{}", content_hash)
+    }
+
+    /// Lock the packed cache, opening it first when `create` is set
+    fn packed_cache(&self, create: bool) -> Result<std::sync::MutexGuard<'_, Option<PackedCache>>> {
+        let mut packed = self.packed.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if create && packed.is_none() {
+            *packed = Some(PackedCache::open(&self.cache_dir)?);
+        }
+        Ok(packed)
+    }
+
+    /// Check whether `key` is in the packed cache, without opening it
+    fn is_packed(&self, key: &str) -> bool {
+        match self.packed_cache(false) {
+            Ok(packed) => packed.as_ref().map_or(false, |cache| cache.contains(key)),
+            Err(_) => false,
+        }
+    }
+
+    /// Read `key` from the packed cache, if it is there
+    fn get_packed(&self, key: &str) -> Result<Option<Bytes>> {
+        match self.packed_cache(false)?.as_ref() {
+            Some(cache) => cache.get(key),
+            None => Ok(None),
+        }
+    }
+    
     /// Check if an object is in the local cache
     fn is_in_cache(&self, id: &ObjectId) -> bool {
-        self.get_object_path(id).exists()
+        self.is_packed(&Self::object_key(id)) || self.get_object_path(id).exists()
     }
 
     /// Check if a chunk is in the local cache
     fn is_chunk_in_cache(&self, content_hash: &str) -> bool {
-        self.get_chunk_path(content_hash).exists()
+        self.is_packed(&Self::chunk_key(content_hash)) || self.get_chunk_path(content_hash).exists()
     }
     
     /// Store an object in the local cache
-    async fn store_in_cache(&self, id: &ObjectId, object_type: ObjectType, data: &[u8]) -> Result<()> {
+    async fn store_in_cache(&self, id: &ObjectId, _object_type: ObjectType, data: &[u8]) -> Result<()> {
         if !self.cache_enabled {
             return Ok(());
         }
-        
-        let object_path = self.get_object_path(id);
-        
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = object_path.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| io_err(format!("Failed to create directory: {}", e), parent))?;
-        }
-        
-        // Write the object to disk
-        let temp_path = object_path.with_extension("tmp");
-        fs::write(&temp_path, data)
-            .map_err(|e| io_err(format!("Failed to write cached object: {}", e), &temp_path))?;
-        
-        // Rename for atomic replacement
-        fs::rename(&temp_path, &object_path)
-            .map_err(|e| io_err(format!("Failed to rename cached object: {}", e), &object_path))?;
-        
-        Ok(())
+        self.store_cached_file(&Self::object_key(id), &self.get_object_path(id), data)
     }
 
     /// Store a chunk in the local cache
@@ -601,29 +1030,42 @@ impl IpfsObjectStorage {
         if !self.cache_enabled {
             return Ok(());
         }
-        
-        let chunk_path = self.get_chunk_path(content_hash);
-        
+        self.store_cached_file(&Self::chunk_key(content_hash), &self.get_chunk_path(content_hash), data)
+    }
+
+    /// Write cached data in the configured layout
+    fn store_cached_file(&self, key: &str, path: &Path, data: &[u8]) -> Result<()> {
+        if self.settings.cache_layout == CacheLayout::Packed {
+            let mut packed = self.packed_cache(true)?;
+            return match packed.as_mut() {
+                Some(cache) => cache.put(key, data),
+                None => Ok(()),
+            };
+        }
+
         // Create parent directory if it doesn't exist
-        if let Some(parent) = chunk_path.parent() {
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)
                 .map_err(|e| io_err(format!("Failed to create directory: {}", e), parent))?;
         }
         
-        // Write the chunk to disk
-        let temp_path = chunk_path.with_extension("tmp");
+        // Write the data to disk
+        let temp_path = path.with_extension("tmp");
         fs::write(&temp_path, data)
-            .map_err(|e| io_err(format!("Failed to write cached chunk: {}", e), &temp_path))?;
+            .map_err(|e| io_err(format!("Failed to write cache file: {}", e), &temp_path))?;
         
         // Rename for atomic replacement
-        fs::rename(&temp_path, &chunk_path)
-            .map_err(|e| io_err(format!("Failed to rename cached chunk: {}", e), &chunk_path))?;
+        fs::rename(&temp_path, path)
+            .map_err(|e| io_err(format!("Failed to rename cache file: {}", e), path))?;
         
         Ok(())
     }
     
     /// Get an object from the local cache
     fn get_from_cache(&self, id: &ObjectId) -> Result<Bytes> {
+        if let Some(data) = self.get_packed(&Self::object_key(id))? {
+            return Ok(data);
+        }
         let object_path = self.get_object_path(id);
         
         fs::read(&object_path)
@@ -633,6 +1075,9 @@ impl IpfsObjectStorage {
 
     /// Get a chunk from the local cache
     fn get_chunk_from_cache(&self, content_hash: &str) -> Result<Bytes> {
+        if let Some(data) = self.get_packed(&Self::chunk_key(content_hash))? {
+            return Ok(data);
+        }
         let chunk_path = self.get_chunk_path(content_hash);
         
         fs::read(&chunk_path)
@@ -640,6 +1085,89 @@ impl IpfsObjectStorage {
             .map_err(|e| io_err(format!("Failed to read cached chunk: {}", e), &chunk_path).into())
     }
 
+    /// Move a cached chunk to another content hash, in whichever layout holds it
+    fn rename_cached_chunk(&self, from: &str, to: &str) {
+        if let Ok(mut packed) = self.packed_cache(false) {
+            if let Some(cache) = packed.as_mut() {
+                match cache.rename(&Self::chunk_key(from), &Self::chunk_key(to)) {
+                    Ok(true) => return,
+                    Ok(false) => {},
+                    Err(e) => log::warn!("Failed to re-key packed chunk {}: {}", from, e),
+                }
+            }
+        }
+        let new_path = self.get_chunk_path(to);
+        if let Some(parent) = new_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::rename(self.get_chunk_path(from), new_path);
+    }
+
+    /// Drop a cached chunk from both layouts
+    fn remove_cached_chunk(&self, content_hash: &str) {
+        if let Ok(mut packed) = self.packed_cache(false) {
+            if let Some(cache) = packed.as_mut() {
+                if let Err(e) = cache.remove(&Self::chunk_key(content_hash)) {
+                    log::warn!("Failed to remove packed chunk {}: {}", content_hash, e);
+                }
+            }
+        }
+        let _ = fs::remove_file(self.get_chunk_path(content_hash));
+    }
+
+    /// Drop a cached object from both layouts
+    fn remove_cached_object(&self, id: &ObjectId) {
+        if let Ok(mut packed) = self.packed_cache(false) {
+            if let Some(cache) = packed.as_mut() {
+                if let Err(e) = cache.remove(&Self::object_key(id)) {
+                    log::warn!("Failed to remove packed object {}: {}", id, e);
+                }
+            }
+        }
+        let _ = fs::remove_file(self.get_object_path(id));
+    }
+
+    /// Compact the local cache
+    ///
+    /// Rewrites the packed cache without replaced and removed entries. With
+    /// `aggressive`, every object and chunk still cached in its own file is
+    /// first moved into the packed cache and the per-file tree is removed,
+    /// which cuts inode use and makes opening a large cache faster. Newly
+    /// cached data keeps following the configured layout, so set it to
+    /// packed to stay compacted.
+    pub async fn compact_cache(&self, aggressive: bool) -> Result<CacheCompactionReport> {
+        let mut report = CacheCompactionReport::default();
+        if !aggressive && !PackedCache::exists(&self.cache_dir) {
+            return Ok(report);
+        }
+
+        let mut packed = self.packed_cache(true)?;
+        let cache = match packed.as_mut() {
+            Some(cache) => cache,
+            None => return Ok(report),
+        };
+
+        if aggressive {
+            for (dir_name, key_prefix) in [("objects", "object/"), ("chunks", "chunk/")] {
+                let dir = self.cache_dir.join(dir_name);
+                let migrated = migrate_loose_files(cache, &dir, key_prefix, &mut report.files_removed)?;
+                if dir_name == "objects" {
+                    report.migrated_objects = migrated;
+                } else {
+                    report.migrated_chunks = migrated;
+                }
+            }
+        }
+
+        report.bytes_reclaimed = cache.rewrite()?;
+        report.packed_entries = cache.len();
+        report.data_files = cache.data_file_count();
+
+        log::info!("Compacted IPFS cache: {} objects and {} chunks migrated, {} bytes reclaimed",
+                  report.migrated_objects, report.migrated_chunks, report.bytes_reclaimed);
+        Ok(report)
+    }
+
     /// Calculate content hash for deduplication
     fn calculate_content_hash(&self, data: &[u8]) -> String {
         match self.settings.content_hash_algorithm {
@@ -683,20 +1211,16 @@ impl IpfsObjectStorage {
                 self.fast_cdc_chunking(data)
             },
             ChunkingAlgorithm::Rabin => {
-                // Content-defined chunking using Rabin algorithm
-                // For now, we'll just use fixed-size chunking as a fallback
-                // In a real implementation, we would implement proper Rabin chunking
-                let chunk_size = self.settings.chunking_strategy.target_chunk_size;
-                let mut chunks = Vec::new();
-                let mut pos = 0;
-                
-                while pos < data.len() {
-                    let end = std::cmp::min(pos + chunk_size, data.len());
-                    chunks.push(Bytes::copy_from_slice(&data[pos..end]));
-                    pos = end;
-                }
-                
-                chunks
+                // Content-defined chunking using a Rabin fingerprint
+                let mut start = 0;
+                rabin::chunk_boundaries(data, &self.settings.chunking_strategy)
+                    .into_iter()
+                    .map(|end| {
+                        let chunk = Bytes::copy_from_slice(&data[start..end]);
+                        start = end;
+                        chunk
+                    })
+                    .collect()
             },
         }
     }
@@ -964,8 +1488,15 @@ impl IpfsObjectStorage {
             }
             
             // Get the chunk from IPFS
-            match self.client.get_file(cid).await {
+            match self.client.get_file_with_timeout(cid, self.read_timeout()).await {
                 Ok(data) => {
+                    // A chunk from a gateway is only trusted if it hashes as recorded
+                    if let Some(hash) = &content_hash {
+                        if self.calculate_content_hash(&data) != *hash {
+                            return Err(GitError::IpfsError(format!("Chunk {} from IPFS doesn't match its content hash", cid)));
+                        }
+                    }
+                    
                     // Cache the chunk if we have its content hash
                     if self.cache_enabled && content_hash.is_some() {
                         if let Err(e) = self.store_chunk_in_cache(&content_hash.unwrap(), &data).await {
@@ -1083,13 +1614,7 @@ impl IpfsObjectStorage {
         data: Bytes
     ) -> Result<ObjectId> {
         // Calculate Git object ID
-        let header = format!("{} {}\0", object_type.to_string(), data.len());
-        let mut content = Vec::with_capacity(header.len() + data.len());
-        content.extend_from_slice(header.as_bytes());
-        content.extend_from_slice(&data);
-        
-        let hash = gix_hash::Kind::Sha1.hash(&content);
-        let object_id = ObjectId::from_hash(hash);
+        let object_id = hash_object(object_type, &data);
         
         // Check if we already have this object
         if self.has_object(&object_id).await {
@@ -1097,65 +1622,215 @@ impl IpfsObjectStorage {
             return Ok(object_id);
         }
         
+        // Stage the data in the local cache, whether or not caching is
+        // enabled, so the upload can resume after a restart
+        if let Err(e) = self.store_cached_file(&Self::object_key(&object_id), &self.get_object_path(&object_id), &data) {
+            log::warn!("Failed to stage object {} for background upload, it won't survive a restart: {}", object_id, e);
+        }
+        
         // Create a background task
         {
             let mut tasks = self.background_tasks.lock().await;
             tasks.insert(object_id.to_string(), BackgroundUploadTask {
                 git_id: object_id.clone(),
                 object_type,
-                data: data.clone(),
+                data,
                 status: UploadStatus::Pending,
             });
         }
+        if let Err(e) = self.save_pending_uploads().await {
+            log::warn!("Failed to record background upload of {}: {}", object_id, e);
+        }
         
-        // Start a background task to upload the object
+        self.spawn_upload(object_id.clone()).await;
+        Ok(object_id)
+    }
+
+    /// Start uploading a registered background task
+    async fn spawn_upload(&self, object_id: ObjectId) {
         let object_storage = self.clone();
-        let object_id_clone = object_id.clone();
         
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
+            let key = object_id.to_string();
+            
             // Mark as in progress
-            {
+            let (object_type, data) = {
                 let mut tasks = object_storage.background_tasks.lock().await;
-                if let Some(task) = tasks.get_mut(&object_id_clone.to_string()) {
-                    task.status = UploadStatus::InProgress;
+                match tasks.get_mut(&key) {
+                    Some(task) => {
+                        task.status = UploadStatus::InProgress;
+                        (task.object_type, task.data.clone())
+                    },
+                    None => return,
                 }
-            }
+            };
             
-            // Attempt to store the object
-            let result = object_storage.store_object_internal(object_type, &data).await;
+            // The task itself makes `has_object` report the object, so
+            // upload without the existence check
+            let status = match object_storage.upload_object(object_id.clone(), object_type, &data).await {
+                Ok(_) => UploadStatus::Completed,
+                Err(e) => {
+                    log::error!("Background upload failed for object {}: {}", object_id, e);
+                    UploadStatus::Failed
+                }
+            };
             
             // Update task status
-            {
-                let mut tasks = object_storage.background_tasks.lock().await;
-                match result {
-                    Ok(_) => {
-                        if let Some(task) = tasks.get_mut(&object_id_clone.to_string()) {
-                            task.status = UploadStatus::Completed;
-                        }
-                    },
-                    Err(e) => {
-                        log::error!("Background upload failed for object {}: {}", object_id_clone, e);
-                        if let Some(task) = tasks.get_mut(&object_id_clone.to_string()) {
-                            task.status = UploadStatus::Failed;
-                        }
-                    }
-                }
+            if let Some(task) = object_storage.background_tasks.lock().await.get_mut(&key) {
+                task.status = status;
+            }
+            if let Err(e) = object_storage.save_pending_uploads().await {
+                log::warn!("Failed to update pending uploads after {}: {}", object_id, e);
+            }
+            if status == UploadStatus::Completed && !object_storage.cache_enabled {
+                object_storage.remove_cached_object(&object_id);
             }
         });
         
-        Ok(object_id)
+        self.upload_handles.lock().await.push(handle);
+    }
+
+    /// Save the background uploads that haven't completed
+    async fn save_pending_uploads(&self) -> Result<()> {
+        // Hold the task lock while writing, so concurrent saves don't race
+        // on the temporary file
+        let tasks = self.background_tasks.lock().await;
+        let pending: Vec<PendingUpload> = tasks.values()
+            .filter(|task| task.status != UploadStatus::Completed)
+            .map(|task| PendingUpload {
+                git_id: task.git_id.to_string(),
+                object_type: task.object_type.to_str().to_string(),
+                status: task.status,
+            })
+            .collect();
+        
+        let json = serde_json::to_string_pretty(&pending)
+            .map_err(|e| GitError::IpfsError(format!("Failed to serialize pending uploads: {}", e)))?;
+        
+        // Write to a temporary file first, then rename for atomicity
+        let temp_file = self.pending_file.with_extension("tmp");
+        fs::write(&temp_file, json)
+            .map_err(|e| io_err(format!("Failed to write pending uploads file: {}", e), &temp_file))?;
+        fs::rename(&temp_file, &self.pending_file)
+            .map_err(|e| io_err(format!("Failed to rename pending uploads file: {}", e), &self.pending_file))?;
+        
+        Ok(())
+    }
+
+    /// Re-enqueue the background uploads a previous run didn't finish
+    ///
+    /// Pending, in-progress and failed uploads are all retried, with the
+    /// data read back from the local cache. Uploads whose staged data is
+    /// gone or no longer matches the object ID are dropped.
+    async fn resume_background_uploads(&self) {
+        let content = match fs::read_to_string(&self.pending_file) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+        let pending: Vec<PendingUpload> = match serde_json::from_str(&content) {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::warn!("Failed to parse pending uploads file, not resuming uploads: {}", e);
+                return;
+            }
+        };
+        
+        let mut resumed = Vec::new();
+        for upload in pending.into_iter().filter(|upload| upload.status != UploadStatus::Completed) {
+            let object_id = match ObjectId::from_hex(upload.git_id.as_bytes()) {
+                Ok(id) => id,
+                Err(e) => {
+                    log::warn!("Dropping background upload with invalid id {}: {}", upload.git_id, e);
+                    continue;
+                }
+            };
+            let object_type = match ObjectType::from_str(&upload.object_type) {
+                Ok(object_type) => object_type,
+                Err(e) => {
+                    log::warn!("Dropping background upload of {}: {}", object_id, e);
+                    continue;
+                }
+            };
+            let data = match self.get_from_cache(&object_id) {
+                Ok(data) if hash_object(object_type, &data) == object_id => data,
+                Ok(_) => {
+                    log::warn!("Dropping background upload of {}: staged data doesn't match", object_id);
+                    continue;
+                },
+                Err(e) => {
+                    log::warn!("Dropping background upload of {}: staged data is gone: {}", object_id, e);
+                    continue;
+                }
+            };
+            
+            self.background_tasks.lock().await.insert(object_id.to_string(), BackgroundUploadTask {
+                git_id: object_id.clone(),
+                object_type,
+                data,
+                status: UploadStatus::Pending,
+            });
+            resumed.push(object_id);
+        }
+        
+        // Rewrite the file so dropped uploads don't linger in it
+        if let Err(e) = self.save_pending_uploads().await {
+            log::warn!("Failed to update pending uploads file: {}", e);
+        }
+        if !resumed.is_empty() {
+            log::info!("Resuming {} background uploads from a previous run", resumed.len());
+        }
+        for object_id in resumed {
+            self.spawn_upload(object_id).await;
+        }
+    }
+
+    /// Wait until every background upload has finished
+    ///
+    /// Call this before exiting to be sure the objects `store_object`
+    /// returned have reached IPFS. Fails if any upload failed; failed
+    /// uploads stay recorded and are retried the next time the storage is
+    /// opened.
+    pub async fn flush_background_uploads(&self) -> Result<()> {
+        loop {
+            let handles: Vec<_> = self.upload_handles.lock().await.drain(..).collect();
+            if handles.is_empty() {
+                break;
+            }
+            for handle in handles {
+                if let Err(e) = handle.await {
+                    log::error!("Background upload task ended abnormally: {}", e);
+                }
+            }
+        }
+        
+        let unfinished = self.background_tasks.lock().await.values()
+            .filter(|task| task.status != UploadStatus::Completed)
+            .count();
+        if unfinished > 0 {
+            return Err(GitError::IpfsError(format!(
+                "{} background uploads did not complete; they will be retried when the storage is next opened",
+                unfinished,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wait for background uploads, then write all state to disk
+    ///
+    /// Mappings, chunks and the pending-upload journal are all written even
+    /// if the uploads failed, so nothing `store_object` accepted is lost on
+    /// exit. Returns the first error.
+    pub async fn flush(&self) -> Result<()> {
+        let uploads = self.flush_background_uploads().await;
+        let index = self.save_index().await;
+        let pending = self.save_pending_uploads().await;
+        uploads.and(index).and(pending)
     }
 
     /// Internal method to actually store an object
     async fn store_object_internal(&self, object_type: ObjectType, data: &[u8]) -> Result<ObjectId> {
         // Calculate Git object ID
-        let header = format!("{} {}\0", object_type.to_string(), data.len());
-        let mut content = Vec::with_capacity(header.len() + data.len());
-        content.extend_from_slice(header.as_bytes());
-        content.extend_from_slice(data);
-        
-        let hash = gix_hash::Kind::Sha1.hash(&content);
-        let object_id = ObjectId::from_hash(hash);
+        let object_id = hash_object(object_type, data);
         
         // Check if we already have this object
         if self.has_object(&object_id).await {
@@ -1163,6 +1838,11 @@ impl IpfsObjectStorage {
             return Ok(object_id);
         }
         
+        self.upload_object(object_id, object_type, data).await
+    }
+
+    /// Upload an object to IPFS and record its mapping
+    async fn upload_object(&self, object_id: ObjectId, object_type: ObjectType, data: &[u8]) -> Result<ObjectId> {
         // Try deduplication by content if enabled
         if self.settings.use_deduplication {
             let content_hash = self.calculate_content_hash(data);
@@ -1183,6 +1863,15 @@ impl IpfsObjectStorage {
                     
                     // Create a new mapping with the same IPFS CID
                     if mapping.is_chunked {
+                        // The new object references the same chunks
+                        {
+                            let mut chunks = self.chunks.write().await;
+                            for cid in &mapping.chunk_cids {
+                                if let Some(chunk) = chunks.values_mut().find(|chunk| &chunk.ipfs_cid == cid) {
+                                    chunk.ref_count += 1;
+                                }
+                            }
+                        }
                         self.add_chunked_mapping(&object_id, mapping.ipfs_cid.clone(), object_type, data.len(), mapping.chunk_cids.clone()).await?;
                     } else {
                         self.add_mapping_with_content_hash(&object_id, mapping.ipfs_cid.clone(), object_type, data.len(), content_hash).await?;
@@ -1282,16 +1971,28 @@ impl IpfsObjectStorage {
             chunks: self.chunks.clone(),
             content_to_git: self.content_to_git.clone(),
             cache_dir: self.cache_dir.clone(),
+            packed: self.packed.clone(),
             mappings_file: self.mappings_file.clone(),
             chunks_file: self.chunks_file.clone(),
+            pending_file: self.pending_file.clone(),
             cache_enabled: self.cache_enabled,
             stats: self.stats.clone(),
             settings: self.settings.clone(),
             background_tasks: self.background_tasks.clone(),
+            upload_handles: self.upload_handles.clone(),
         }
     }
 }
 
+/// Fail unless `data` is the object `id` names
+fn check_object_data(id: &ObjectId, object_type: ObjectType, data: &[u8]) -> Result<()> {
+    let actual = hash_object(object_type, data);
+    if actual != *id {
+        return Err(GitError::IpfsError(format!("Data from IPFS for {} hashes to {} instead", id, actual)));
+    }
+    Ok(())
+}
+
 impl IpfsObjectProvider for IpfsObjectStorage {
     async fn get_object(&self, id: &ObjectId) -> Result<(ObjectType, Bytes)> {
         // Check if we have a mapping for this object
@@ -1302,38 +2003,30 @@ impl IpfsObjectProvider for IpfsObjectStorage {
         
         match mapping {
             Some(mapping) => {
+                // Convert object type string back to enum
+                let object_type = match mapping.object_type.as_str() {
+                    "blob" => ObjectType::Blob,
+                    "tree" => ObjectType::Tree,
+                    "commit" => ObjectType::Commit,
+                    "tag" => ObjectType::Tag,
+                    _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
+                };
+                
                 // Check if object is chunked
                 if mapping.is_chunked {
                     log::debug!("Getting chunked object {} from IPFS", id);
                     
                     // Reassemble from chunks
                     let data = self.reassemble_from_chunks(&mapping.chunk_cids).await?;
+                    check_object_data(id, object_type, &data)?;
                     
                     // Store in local cache if enabled
                     if self.cache_enabled {
-                        // Convert object type string back to enum
-                        let object_type = match mapping.object_type.as_str() {
-                            "blob" => ObjectType::Blob,
-                            "tree" => ObjectType::Tree,
-                            "commit" => ObjectType::Commit,
-                            "tag" => ObjectType::Tag,
-                            _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
-                        };
-                        
                         if let Err(e) = self.store_in_cache(id, object_type, &data).await {
                             log::warn!("Failed to cache object: {}", e);
                         }
                     }
                     
-                    // Convert object type string back to enum
-                    let object_type = match mapping.object_type.as_str() {
-                        "blob" => ObjectType::Blob,
-                        "tree" => ObjectType::Tree,
-                        "commit" => ObjectType::Commit,
-                        "tag" => ObjectType::Tag,
-                        _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
-                    };
-                    
                     return Ok((object_type, data));
                 }
                 
@@ -1347,15 +2040,6 @@ impl IpfsObjectProvider for IpfsObjectStorage {
                                 stats.hits += 1;
                             }
                             
-                            // Convert object type string back to enum
-                            let object_type = match mapping.object_type.as_str() {
-                                "blob" => ObjectType::Blob,
-                                "tree" => ObjectType::Tree,
-                                "commit" => ObjectType::Commit,
-                                "tag" => ObjectType::Tag,
-                                _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
-                            };
-                            
                             return Ok((object_type, data));
                         }
                         Err(e) => {
@@ -1364,21 +2048,15 @@ impl IpfsObjectProvider for IpfsObjectStorage {
                     }
                 }
                 
-                // Get the data from IPFS
+                // Get the data from IPFS; gateways may return anything, so
+                // the data must hash to the requested ID before it is used
                 log::debug!("Fetching object {} from IPFS with CID {}", id, mapping.ipfs_cid);
-                match self.client.get_file(&mapping.ipfs_cid).await {
+                match self.client.get_file_with_timeout(&mapping.ipfs_cid, self.read_timeout()).await {
                     Ok(data) => {
+                        check_object_data(id, object_type, &data)?;
+                        
                         // Cache the object if caching is enabled
                         if self.cache_enabled {
-                            // Convert object type string back to enum
-                            let object_type = match mapping.object_type.as_str() {
-                                "blob" => ObjectType::Blob,
-                                "tree" => ObjectType::Tree,
-                                "commit" => ObjectType::Commit,
-                                "tag" => ObjectType::Tag,
-                                _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
-                            };
-                            
                             if let Err(e) = self.store_in_cache(id, object_type, &data).await {
                                 log::warn!("Failed to cache object: {}", e);
                             }
@@ -1390,15 +2068,6 @@ impl IpfsObjectProvider for IpfsObjectStorage {
                             stats.misses += 1;
                         }
                         
-                        // Convert object type string back to enum
-                        let object_type = match mapping.object_type.as_str() {
-                            "blob" => ObjectType::Blob,
-                            "tree" => ObjectType::Tree,
-                            "commit" => ObjectType::Commit,
-                            "tag" => ObjectType::Tag,
-                            _ => return Err(GitError::IpfsError(format!("Invalid object type: {}", mapping.object_type)))
-                        };
-                        
                         Ok((object_type, data))
                     },
                     Err(e) => Err(GitError::IpfsError(format!("Failed to get object from IPFS: {}", e)))
@@ -1589,4 +2258,264 @@ impl ObjectTypeExt for ObjectType {
             ObjectType::Tag => "tag",
         }
     }
-}
\ No newline at end of file
+}
+
+/// Move every file of a per-file cache tree (`<dir>/xx/rest`) into the packed
+/// cache and remove the tree, returning how many entries were moved
+fn migrate_loose_files(cache: &mut PackedCache, dir: &Path, key_prefix: &str, files_removed: &mut usize) -> Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut migrated = 0;
+    let prefixes = fs::read_dir(dir)
+        .map_err(|e| io_err(format!("Failed to read cache directory: {}", e), dir))?;
+    for prefix in prefixes.flatten() {
+        let prefix_path = prefix.path();
+        if !prefix_path.is_dir() {
+            continue;
+        }
+        let prefix_name = prefix.file_name().to_string_lossy().to_string();
+        let files = fs::read_dir(&prefix_path)
+            .map_err(|e| io_err(format!("Failed to read cache directory: {}", e), &prefix_path))?;
+        for file in files.flatten() {
+            let path = file.path();
+            let name = file.file_name().to_string_lossy().to_string();
+            // Leftovers of an interrupted write are dropped, not migrated
+            if !name.ends_with(".tmp") {
+                let key = format!("{}{}{}", key_prefix, prefix_name, name);
+                if !cache.contains(&key) {
+                    let data = fs::read(&path)
+                        .map_err(|e| io_err(format!("Failed to read cached file: {}", e), &path))?;
+                    cache.put(&key, &data)?;
+                    migrated += 1;
+                }
+            }
+            fs::remove_file(&path)
+                .map_err(|e| io_err(format!("Failed to remove cached file: {}", e), &path))?;
+            *files_removed += 1;
+        }
+        if fs::remove_dir(&prefix_path).is_ok() {
+            *files_removed += 1;
+        }
+    }
+    if fs::remove_dir(dir).is_ok() {
+        *files_removed += 1;
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// An HTTP server answering every request with `body`
+    async fn gateway(body: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request).await;
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    /// Storage without a reachable daemon, reading from `gateway` only
+    async fn storage_with_gateway(dir: &Path, gateway: String) -> IpfsObjectStorage {
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = unused.local_addr().unwrap().port();
+        drop(unused);
+        let config = IpfsConfig {
+            api_endpoint: "http://127.0.0.1".to_string(),
+            api_port: port,
+            gateway_urls: vec![gateway],
+            ..IpfsConfig::default()
+        };
+        let client = Arc::new(IpfsClient::new(config).await.unwrap());
+        IpfsObjectStorage::with_cache(client, dir.to_path_buf()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gateway_data_must_match_the_object_id() {
+        let data = b"hello from a gateway\n".to_vec();
+        let id = hash_object(ObjectType::Blob, &data);
+        let mapping = ObjectMapping::new(&id, "bafkreigatewaytest".to_string(), ObjectType::Blob, data.len());
+
+        // Other bytes from the gateway are refused and not cached
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage_with_gateway(dir.path(), gateway(b"something else\n".to_vec()).await).await;
+        storage.mappings.write().await.insert(id.to_string(), mapping.clone());
+        assert!(matches!(storage.get_object(&id).await, Err(GitError::IpfsError(_))));
+        assert!(!storage.is_in_cache(&id));
+
+        // The right bytes are returned and cached
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage_with_gateway(dir.path(), gateway(data.clone()).await).await;
+        storage.mappings.write().await.insert(id.to_string(), mapping);
+        let (object_type, fetched) = storage.get_object(&id).await.unwrap();
+        assert_eq!(object_type, ObjectType::Blob);
+        assert_eq!(&fetched[..], &data[..]);
+        assert!(storage.is_in_cache(&id));
+    }
+
+    /// A chunk index entry for tests
+    fn chunk(content_hash: &str, ipfs_cid: &str, ref_count: usize) -> ObjectChunk {
+        ObjectChunk {
+            content_hash: content_hash.to_string(),
+            ipfs_cid: ipfs_cid.to_string(),
+            size: 10,
+            ref_count,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_object_releases_only_unshared_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        let first = hash_object(ObjectType::Blob, b"first");
+        let second = hash_object(ObjectType::Blob, b"second");
+        {
+            let mut mappings = storage.mappings.write().await;
+            mappings.insert(first.to_string(), ObjectMapping::chunked(&first, "bafyfirst".to_string(), ObjectType::Blob, 20,
+                vec!["bafyshared".to_string(), "bafyonly".to_string()]));
+            mappings.insert(second.to_string(), ObjectMapping::chunked(&second, "bafysecond".to_string(), ObjectType::Blob, 10,
+                vec!["bafyshared".to_string()]));
+            let mut chunks = storage.chunks.write().await;
+            chunks.insert("aa01".to_string(), chunk("aa01", "bafyshared", 2));
+            chunks.insert("bb02".to_string(), chunk("bb02", "bafyonly", 1));
+        }
+
+        assert!(storage.remove_object(&first).await.unwrap());
+        assert!(!storage.remove_object(&first).await.unwrap());
+        {
+            let chunks = storage.chunks.read().await;
+            assert_eq!(chunks.len(), 1);
+            assert_eq!(chunks["aa01"].ref_count, 1);
+        }
+
+        // The removal is saved: a fresh storage sees only the second object
+        let reopened = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        let mappings = reopened.mappings.read().await;
+        assert_eq!(mappings.keys().collect::<Vec<_>>(), vec![&second.to_string()]);
+        assert_eq!(reopened.chunks.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gc_sweeps_orphans_and_repairs_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        let id = hash_object(ObjectType::Blob, b"kept");
+        {
+            let mut mappings = storage.mappings.write().await;
+            mappings.insert(id.to_string(), ObjectMapping::chunked(&id, "bafykept".to_string(), ObjectType::Blob, 10,
+                vec!["bafyused".to_string()]));
+            let mut chunks = storage.chunks.write().await;
+            chunks.insert("aa01".to_string(), chunk("aa01", "bafyused", 3));
+            chunks.insert("bb02".to_string(), chunk("bb02", "bafyorphan", 1));
+            // Same bytes as an unchunked object: swept from the index but still pinned
+            chunks.insert("cc03".to_string(), chunk("cc03", "bafykept", 1));
+        }
+
+        let report = storage.gc().await.unwrap();
+        assert_eq!(report.chunks_scanned, 3);
+        assert_eq!(report.orphaned_chunks, 2);
+        assert_eq!(report.counts_repaired, 1);
+        assert_eq!(report.unpinned, vec!["bafyorphan".to_string()]);
+        assert_eq!(report.bytes_reclaimed, 10);
+        {
+            let chunks = storage.chunks.read().await;
+            assert_eq!(chunks.keys().collect::<Vec<_>>(), vec!["aa01"]);
+            assert_eq!(chunks["aa01"].ref_count, 1);
+        }
+
+        // Nothing is left to do the second time
+        let report = storage.gc().await.unwrap();
+        assert_eq!((report.orphaned_chunks, report.counts_repaired), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_pending_uploads_resume_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let pending_file = dir.path().join("pending_uploads.json");
+        let recorded = || {
+            let pending: Vec<PendingUpload> = serde_json::from_str(&fs::read_to_string(&pending_file).unwrap()).unwrap();
+            let mut ids: Vec<String> = pending.into_iter().map(|upload| upload.git_id).collect();
+            ids.sort();
+            ids
+        };
+
+        let storage = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        let kept = storage.submit_background_upload(ObjectType::Blob, Bytes::from_static(b"kept")).await.unwrap();
+        let gone = storage.submit_background_upload(ObjectType::Blob, Bytes::from_static(b"gone")).await.unwrap();
+        let changed = storage.submit_background_upload(ObjectType::Blob, Bytes::from_static(b"changed")).await.unwrap();
+        // Nothing listens on the API port, so every upload fails and stays recorded
+        assert!(storage.flush_background_uploads().await.is_err());
+        let mut expected = vec![kept.to_string(), gone.to_string(), changed.to_string()];
+        expected.sort();
+        assert_eq!(recorded(), expected);
+
+        // Lose the staged data of one upload and replace that of another
+        storage.remove_cached_object(&gone);
+        storage.store_cached_file(&IpfsObjectStorage::object_key(&changed), &storage.get_object_path(&changed), b"other").unwrap();
+        drop(storage);
+
+        // A fresh storage re-enqueues only the upload it can still verify
+        let reopened = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        {
+            let tasks = reopened.background_tasks.lock().await;
+            assert_eq!(tasks.keys().collect::<Vec<_>>(), vec![&kept.to_string()]);
+            assert_eq!(&tasks[&kept.to_string()].data[..], b"kept");
+        }
+        assert!(reopened.flush_background_uploads().await.is_err());
+        assert_eq!(recorded(), vec![kept.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_consolidate_chunks_folds_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage_with_gateway(dir.path(), gateway(Vec::new()).await).await;
+        let current = storage.calculate_content_hash(b"same bytes");
+        let object = hash_object(ObjectType::Blob, b"object");
+        let unchunked = hash_object(ObjectType::Blob, b"same bytes");
+        {
+            let mut mappings = storage.mappings.write().await;
+            mappings.insert(object.to_string(), ObjectMapping::chunked(&object, "bafyobject".to_string(), ObjectType::Blob, 20,
+                vec!["bafyold".to_string(), "bafyshared".to_string()]));
+            // Stored whole under the same CID as one of the duplicates
+            mappings.insert(unchunked.to_string(), ObjectMapping::new(&unchunked, "bafyshared".to_string(), ObjectType::Blob, 10));
+            let mut chunks = storage.chunks.write().await;
+            chunks.insert(current.clone(), chunk(&current, "bafycurrent", 1));
+            chunks.insert("aa01".to_string(), chunk("aa01", "bafyold", 1));
+            chunks.insert("bb02".to_string(), chunk("bb02", "bafyshared", 1));
+        }
+        for content_hash in [current.as_str(), "aa01", "bb02"] {
+            storage.store_chunk_in_cache(content_hash, b"same bytes").await.unwrap();
+        }
+
+        // A dry run reports without changing anything
+        let report = storage.consolidate_chunks(true).await.unwrap();
+        assert_eq!(report.chunks_scanned, 3);
+        assert_eq!(report.duplicate_chunks, 2);
+        assert_eq!(report.mappings_updated, 1);
+        assert_eq!(report.unpinned, vec!["bafyold".to_string()]);
+        assert_eq!(report.bytes_reclaimed, 10);
+        assert_eq!(storage.chunks.read().await.len(), 3);
+
+        let report = storage.consolidate_chunks(false).await.unwrap();
+        assert_eq!(report.unpinned, vec!["bafyold".to_string()]);
+        {
+            let chunks = storage.chunks.read().await;
+            assert_eq!(chunks.keys().collect::<Vec<_>>(), vec![&current]);
+            assert_eq!(chunks[&current].ipfs_cid, "bafycurrent");
+            assert_eq!(chunks[&current].ref_count, 3);
+        }
+        let mappings = storage.mappings.read().await;
+        assert_eq!(mappings[&object.to_string()].chunk_cids, vec!["bafycurrent".to_string(), "bafycurrent".to_string()]);
+        assert_eq!(mappings[&unchunked.to_string()].ipfs_cid, "bafyshared");
+    }
+}
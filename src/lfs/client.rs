@@ -1,11 +1,14 @@
 /// Git LFS client implementation
 ///
 /// This module provides a client for interacting with Git LFS servers and IPFS
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use reqwest::{Client as HttpClient, Response};
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, ACCEPT};
+use chrono::{DateTime, Utc};
+
+use reqwest::Client as HttpClient;
+use reqwest::header::{CONTENT_TYPE, ACCEPT, AUTHORIZATION};
 use serde::{Serialize, Deserialize};
 use bytes::Bytes;
 use tokio::fs as tokio_fs;
@@ -13,7 +16,9 @@ use tokio::io::AsyncWriteExt;
 
 use crate::core::{GitError, Result};
 use crate::ipfs::IpfsClient;
-use super::{LfsConfig, LfsPointer};
+use crate::transport::PooledTorTransport;
+use super::{LfsConfig, LfsPointer, LfsLock};
+use super::transfer::{TransferTransport, TransferResponse, HttpTransferTransport, TorTransferTransport};
 
 /// LFS batch request object
 #[derive(Debug, Serialize)]
@@ -51,7 +56,7 @@ struct BatchResponseObject {
     oid: String,
     size: u64,
     authenticated: Option<bool>,
-    actions: Option<std::collections::HashMap<String, BatchObjectAction>>,
+    actions: Option<HashMap<String, BatchObjectAction>>,
     error: Option<BatchObjectError>,
 }
 
@@ -59,8 +64,76 @@ struct BatchResponseObject {
 #[derive(Debug, Deserialize)]
 struct BatchObjectAction {
     href: String,
-    header: Option<std::collections::HashMap<String, String>>,
-    expires_in: Option<u64>,
+    header: Option<HashMap<String, String>>,
+    expires_in: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Error body of a failed batch request
+#[derive(Debug, Deserialize)]
+struct BatchErrorResponse {
+    message: String,
+}
+
+/// Transfer adapter the client speaks
+const BASIC_TRANSFER: &str = "basic";
+
+/// Actions expiring sooner than this are treated as already expired, so a
+/// transfer doesn't start with a URL that lapses halfway through
+const ACTION_EXPIRY_MARGIN_SECONDS: i64 = 5;
+
+/// Operation a batch request negotiates transfers for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOperation {
+    /// Send objects to the server
+    Upload,
+    /// Fetch objects from the server
+    Download,
+}
+
+impl BatchOperation {
+    /// Name of the operation in the batch API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchOperation::Upload => "upload",
+            BatchOperation::Download => "download",
+        }
+    }
+}
+
+/// Where and how to transfer one object, as the server negotiated it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchAction {
+    /// URL to send the transfer request to
+    pub href: String,
+    /// Headers to send with the transfer request
+    pub header: HashMap<String, String>,
+    /// When the action stops being valid, if the server said
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl BatchAction {
+    /// Whether the action has expired, or is about to
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| {
+            expires_at <= Utc::now() + chrono::Duration::seconds(ACTION_EXPIRY_MARGIN_SECONDS)
+        })
+    }
+}
+
+/// Outcome of a batch request for one object
+#[derive(Debug)]
+pub struct BatchObject {
+    /// Object ID, as requested
+    pub oid: String,
+    /// Object size, as requested
+    pub size: u64,
+    /// Actions by name (`upload`, `download`, `verify`), or why the server
+    /// refused the object
+    ///
+    /// No actions means there is nothing to do, e.g. the server already has
+    /// an object being uploaded.
+    pub actions: Result<HashMap<String, BatchAction>>,
 }
 
 /// LFS batch object error
@@ -70,13 +143,48 @@ struct BatchObjectError {
     message: String,
 }
 
+/// A single lock, or the conflicting one with an explanation
+#[derive(Debug, Deserialize)]
+struct LockResponse {
+    lock: Option<LfsLock>,
+    message: Option<String>,
+}
+
+/// A page of locks
+#[derive(Debug, Deserialize)]
+struct LockListResponse {
+    locks: Vec<LfsLock>,
+    next_cursor: Option<String>,
+}
+
+/// A page of locks, split by owner
+#[derive(Debug, Deserialize)]
+struct VerifyLocksResponse {
+    ours: Vec<LfsLock>,
+    theirs: Vec<LfsLock>,
+    next_cursor: Option<String>,
+}
+
+/// Locks that matter for a push, split by who holds them
+#[derive(Debug, Default, Clone)]
+pub struct LockVerification {
+    /// Locks held by the configured user
+    pub ours: Vec<LfsLock>,
+    /// Locks held by anyone else
+    pub theirs: Vec<LfsLock>,
+}
+
 /// Client for interacting with Git LFS servers
 pub struct LfsClient {
     /// LFS configuration
     config: LfsConfig,
     
-    /// HTTP client for API calls
-    http: HttpClient,
+    /// Transport for API calls and actions on clearnet hosts
+    http_transport: HttpTransferTransport,
+    
+    /// Transport for API calls and actions on onion services, if Tor is
+    /// available
+    onion_transport: Option<Arc<dyn TransferTransport>>,
     
     /// IPFS client for IPFS-based operations (optional)
     ipfs_client: Option<Arc<IpfsClient>>,
@@ -94,7 +202,8 @@ impl LfsClient {
         // Create client
         let client = Self {
             config,
-            http,
+            http_transport: HttpTransferTransport::new(http),
+            onion_transport: None,
             ipfs_client: None,
         };
         
@@ -112,13 +221,26 @@ impl LfsClient {
         // Create client
         let client = Self {
             config,
-            http,
+            http_transport: HttpTransferTransport::new(http),
+            onion_transport: None,
             ipfs_client: Some(ipfs_client),
         };
         
         Ok(client)
     }
     
+    /// Send transfers to onion services through `transport`, reusing its
+    /// connection pool
+    pub fn with_tor_transport(self, transport: Arc<PooledTorTransport>) -> Self {
+        self.with_onion_transport(Arc::new(TorTransferTransport::new(transport)))
+    }
+    
+    /// Send transfers to onion services through `transport`
+    pub fn with_onion_transport(mut self, transport: Arc<dyn TransferTransport>) -> Self {
+        self.onion_transport = Some(transport);
+        self
+    }
+    
     /// Initialize Git LFS in a repository
     pub async fn initialize(&self, repo_path: impl AsRef<Path>) -> Result<()> {
         let repo_path = repo_path.as_ref();
@@ -230,90 +352,318 @@ impl LfsClient {
     
     /// Upload a file to an LFS server
     pub async fn upload_to_server(&self, pointer: &LfsPointer, data: &[u8]) -> Result<()> {
+        let objects = vec![(pointer.clone(), Bytes::copy_from_slice(data))];
+        let (_, result) = self.upload_objects(&objects).await?
+            .pop()
+            .ok_or_else(|| GitError::LfsError("Object not found in LFS batch response".to_string()))?;
+        result?;
+        
+        println!("Successfully uploaded {} to LFS server", pointer.oid);
+        Ok(())
+    }
+    
+    /// Negotiate transfers for `objects` with the batch API
+    ///
+    /// Posts `objects` (ID and size) to `/objects/batch`, offering the basic
+    /// transfer adapter. An object the server refuses, whether individually or
+    /// by rejecting the request with 422, gets an error of its own instead of
+    /// failing the whole batch. Objects come back in the order requested.
+    pub async fn batch(&self, operation: BatchOperation, objects: Vec<(String, u64)>) -> Result<Vec<BatchObject>> {
         let server_url = self.config.url.as_ref()
             .ok_or_else(|| GitError::LfsError("LFS server URL not configured".to_string()))?;
-            
-        // First, make a batch request to check if the server already has the object
-        let batch_url = format!("{}/objects/batch", server_url);
+        let batch_url = format!("{}/objects/batch", server_url.trim_end_matches('/'));
         
         let request = BatchRequest {
-            operation: "upload",
+            operation: operation.as_str(),
             reference: None,
-            objects: vec![BatchRequestObject {
-                oid: pointer.oid.clone(),
-                size: pointer.size,
-            }],
-            transfers: Some(vec!["basic"]),
+            objects: objects.iter()
+                .map(|(oid, size)| BatchRequestObject { oid: oid.clone(), size: *size })
+                .collect(),
+            transfers: Some(vec![BASIC_TRANSFER]),
         };
         
-        let response = self.http.post(&batch_url)
-            .header(CONTENT_TYPE, "application/vnd.git-lfs+json")
-            .header(ACCEPT, "application/vnd.git-lfs+json")
-            .json(&request)
-            .send()
+        let body = serde_json::to_vec(&request)
+            .map_err(|e| GitError::LfsError(format!("Failed to serialize LFS batch request: {}", e)))?;
+        let response = self.transport_for(&batch_url)?
+            .send(reqwest::Method::POST, &batch_url, &self.api_headers(), Some(body))
             .await
             .map_err(|e| GitError::LfsError(format!("LFS batch request failed: {}", e)))?;
-            
-        if !response.status().is_success() {
-            let error = response.text().await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-                
-            return Err(GitError::LfsError(format!("LFS server error: {}", error)));
+        let received_at = Utc::now();
+        
+        if response.status == reqwest::StatusCode::UNPROCESSABLE_ENTITY.as_u16() {
+            // The server validated the objects and refused them
+            let body = response.text();
+            let message = serde_json::from_str::<BatchErrorResponse>(&body)
+                .map(|error| error.message)
+                .unwrap_or(body);
+            return Ok(objects.into_iter()
+                .map(|(oid, size)| BatchObject {
+                    actions: Err(GitError::LfsError(format!("LFS object {} rejected: {}", oid, message))),
+                    oid,
+                    size,
+                })
+                .collect());
+        }
+        if !response.is_success() {
+            return Err(GitError::LfsError(format!("LFS server error: {}", response.text())));
         }
         
-        // Parse the batch response
-        let batch_response: BatchResponse = response.json().await
+        let batch_response: BatchResponse = serde_json::from_slice(&response.body)
             .map_err(|e| GitError::LfsError(format!("Failed to parse LFS response: {}", e)))?;
+        parse_batch_response(objects, batch_response, received_at)
+    }
+    
+    /// Upload `objects` through the batch API
+    ///
+    /// Objects the server already has are skipped. After an upload, the
+    /// object is verified if the server asked for it. Returns the outcome for
+    /// each object in the order given.
+    pub async fn upload_objects(&self, objects: &[(LfsPointer, Bytes)]) -> Result<Vec<(String, Result<()>)>> {
+        let requested = objects.iter()
+            .map(|(pointer, _)| (pointer.oid.clone(), pointer.size))
+            .collect();
+        let negotiated = self.batch(BatchOperation::Upload, requested).await?;
+        
+        let mut results = Vec::with_capacity(negotiated.len());
+        for (object, (_, data)) in negotiated.into_iter().zip(objects) {
+            let result = match object.actions {
+                Ok(actions) => self.basic_upload(&object.oid, object.size, &actions, data).await,
+                Err(e) => Err(e),
+            };
+            results.push((object.oid, result));
+        }
+        Ok(results)
+    }
+    
+    /// Download `pointers` through the batch API
+    ///
+    /// Returns the content, or why it couldn't be fetched, for each object in
+    /// the order given.
+    pub async fn download_objects(&self, pointers: &[LfsPointer]) -> Result<Vec<(String, Result<Bytes>)>> {
+        let requested = pointers.iter()
+            .map(|pointer| (pointer.oid.clone(), pointer.size))
+            .collect();
+        let negotiated = self.batch(BatchOperation::Download, requested).await?;
+        
+        let mut results = Vec::with_capacity(negotiated.len());
+        for object in negotiated {
+            let result = match object.actions {
+                Ok(actions) => self.basic_download(&object.oid, object.size, &actions).await,
+                Err(e) => Err(e),
+            };
+            results.push((object.oid, result));
+        }
+        Ok(results)
+    }
+    
+    /// Run the `upload` and `verify` actions of the basic transfer adapter
+    async fn basic_upload(&self, oid: &str, size: u64, actions: &HashMap<String, BatchAction>, data: &[u8]) -> Result<()> {
+        let upload = match actions.get("upload") {
+            Some(upload) => upload,
+            // The server already has the object
+            None => return Ok(()),
+        };
+        
+        let headers = [(CONTENT_TYPE.as_str().to_string(), "application/octet-stream".to_string())];
+        let response = self.run_action(reqwest::Method::PUT, oid, "upload", upload, &headers, Some(data.to_vec())).await?;
+        if !response.is_success() {
+            return Err(GitError::LfsError(format!("LFS upload error: {}", response.text())));
+        }
+        
+        if let Some(verify) = actions.get("verify") {
+            let headers = [
+                (CONTENT_TYPE.as_str().to_string(), "application/vnd.git-lfs+json".to_string()),
+                (ACCEPT.as_str().to_string(), "application/vnd.git-lfs+json".to_string()),
+            ];
+            let body = serde_json::to_vec(&BatchRequestObject { oid: oid.to_string(), size })
+                .map_err(|e| GitError::LfsError(format!("Failed to serialize verify request: {}", e)))?;
+            let response = self.run_action(reqwest::Method::POST, oid, "verify", verify, &headers, Some(body)).await?;
+            if !response.is_success() {
+                return Err(GitError::LfsError(format!("LFS verify error for {}: {}", oid, response.text())));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Run the `download` action of the basic transfer adapter
+    ///
+    /// The content must hash to `oid` and be `size` bytes long; anything
+    /// else the server sends is refused before it can be stored.
+    async fn basic_download(&self, oid: &str, size: u64, actions: &HashMap<String, BatchAction>) -> Result<Bytes> {
+        let download = actions.get("download")
+            .ok_or_else(|| GitError::LfsError(format!("Could not find LFS object {}", oid)))?;
+        
+        let response = self.run_action(reqwest::Method::GET, oid, "download", download, &[], None).await?;
+        if !response.is_success() {
+            return Err(GitError::LfsError(format!("LFS download error: {}", response.text())));
+        }
+        verify_download(oid, size, &response.body)?;
+        Ok(response.body)
+    }
+    
+    /// Send the request for a negotiated action, refusing stale ones
+    ///
+    /// The action's own headers are sent along with `headers`.
+    async fn run_action(
+        &self,
+        method: reqwest::Method,
+        oid: &str,
+        name: &str,
+        action: &BatchAction,
+        headers: &[(String, String)],
+        body: Option<Vec<u8>>,
+    ) -> Result<TransferResponse> {
+        if action.is_expired() {
+            return Err(GitError::LfsError(format!(
+                "LFS {} action for {} expired at {}",
+                name, oid, action.expires_at.map(|at| at.to_rfc3339()).unwrap_or_default(),
+            )));
+        }
+        
+        let transport = self.transport_for(&action.href)?;
+        log::debug!("Sending LFS {} action for {} over {}", name, oid, transport.name());
+        let headers: Vec<(String, String)> = action.header.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(headers.iter().cloned())
+            .collect();
+        transport.send(method, &action.href, &headers, body).await
+    }
+    
+    /// The transport for requests to `href`
+    ///
+    /// Onion services are only reached through Tor, never over clearnet HTTP.
+    /// This holds for the batch and locking APIs as much as for actions.
+    fn transport_for(&self, href: &str) -> Result<&dyn TransferTransport> {
+        if !PooledTorTransport::handles_url(href) {
+            return Ok(&self.http_transport);
+        }
+        self.onion_transport.as_deref()
+            .ok_or_else(|| GitError::LfsError(format!("LFS request to {} needs Tor, but no Tor transport is configured", href)))
+    }
+    
+    /// Headers for batch and locking API requests, identifying as the
+    /// configured user
+    fn api_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            (CONTENT_TYPE.as_str().to_string(), "application/vnd.git-lfs+json".to_string()),
+            (ACCEPT.as_str().to_string(), "application/vnd.git-lfs+json".to_string()),
+        ];
+        if let Some(user) = &self.config.user {
+            let credentials = format!("{}:{}", user, self.config.password.as_deref().unwrap_or_default());
+            headers.push((AUTHORIZATION.as_str().to_string(), format!("Basic {}", base64::Engine::encode(
+                &base64::engine::general_purpose::STANDARD,
+                credentials,
+            ))));
+        }
+        headers
+    }
+    
+    /// Lock `path` on the LFS server
+    ///
+    /// Fails if someone already holds a lock on it, naming the owner.
+    pub async fn lock(&self, path: &str) -> Result<LfsLock> {
+        let body = serde_json::json!({ "path": path });
+        let response = self.lock_request(reqwest::Method::POST, "locks", Some(body))
+            .await
+            .map_err(|e| GitError::LfsError(format!("LFS lock request failed: {}", e)))?;
+        
+        let status = response.status;
+        let lock_response = parse_lock_response(&response);
+        match (status, lock_response.lock) {
+            (200 | 201, Some(lock)) => Ok(lock),
+            (409, Some(existing)) => Err(GitError::LfsError(format!(
+                "'{}' is already locked by {} (lock {})", existing.path, existing.owner.name, existing.id,
+            ))),
+            _ => Err(GitError::LfsError(format!("LFS lock failed ({}): {}",
+                status, lock_response.message.unwrap_or_else(|| "Unknown error".to_string())))),
+        }
+    }
+    
+    /// Release lock `id` on the LFS server
+    ///
+    /// A lock held by someone else is only released with `force`; without
+    /// it the error names the owner.
+    pub async fn unlock(&self, id: &str, force: bool) -> Result<LfsLock> {
+        let body = serde_json::json!({ "force": force });
+        let response = self.lock_request(reqwest::Method::POST, &format!("locks/{}/unlock", id), Some(body))
+            .await
+            .map_err(|e| GitError::LfsError(format!("LFS unlock request failed: {}", e)))?;
+        
+        let status = response.status;
+        let lock_response = parse_lock_response(&response);
+        match (status, lock_response.lock) {
+            (200, Some(lock)) => Ok(lock),
+            (403, Some(existing)) => Err(GitError::LfsError(format!(
+                "Lock {} on '{}' is owned by {}; use force to unlock it", existing.id, existing.path, existing.owner.name,
+            ))),
+            _ => Err(GitError::LfsError(format!("LFS unlock failed ({}): {}",
+                status, lock_response.message.unwrap_or_else(|| "Unknown error".to_string())))),
+        }
+    }
+    
+    /// List every lock on the LFS server
+    pub async fn list_locks(&self) -> Result<Vec<LfsLock>> {
+        let mut locks = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let endpoint = match &cursor {
+                Some(cursor) => format!("locks?{}", url::form_urlencoded::Serializer::new(String::new())
+                    .append_pair("cursor", cursor)
+                    .finish()),
+                None => "locks".to_string(),
+            };
+            let response = self.lock_request(reqwest::Method::GET, &endpoint, None)
+                .await
+                .map_err(|e| GitError::LfsError(format!("LFS lock list request failed: {}", e)))?;
+            if !response.is_success() {
+                return Err(GitError::LfsError(format!("LFS lock list failed: {}", response.text())));
+            }
             
-        // Find our object in the response
-        if let Some(obj) = batch_response.objects.iter().find(|o| o.oid == pointer.oid) {
-            // Check for errors
-            if let Some(error) = &obj.error {
-                return Err(GitError::LfsError(format!("LFS error: {} - {}", error.code, error.message)));
+            let page: LockListResponse = serde_json::from_slice(&response.body)
+                .map_err(|e| GitError::LfsError(format!("Failed to parse LFS lock list: {}", e)))?;
+            locks.extend(page.locks);
+            match page.next_cursor {
+                Some(next) if !next.is_empty() => cursor = Some(next),
+                _ => return Ok(locks),
+            }
+        }
+    }
+    
+    /// Ask the LFS server which locks are ours and which are someone else's
+    pub async fn verify_locks(&self) -> Result<LockVerification> {
+        let mut verification = LockVerification::default();
+        let mut cursor: Option<String> = None;
+        loop {
+            let body = serde_json::json!({ "cursor": cursor });
+            let response = self.lock_request(reqwest::Method::POST, "locks/verify", Some(body))
+                .await
+                .map_err(|e| GitError::LfsError(format!("LFS lock verify request failed: {}", e)))?;
+            if !response.is_success() {
+                return Err(GitError::LfsError(format!("LFS lock verify failed: {}", response.text())));
             }
             
-            // Check if we need to upload the object
-            if let Some(actions) = &obj.actions {
-                if let Some(upload_action) = actions.get("upload") {
-                    // We need to upload the object
-                    let upload_url = &upload_action.href;
-                    
-                    // Create headers from the action if any
-                    let mut headers = HeaderMap::new();
-                    if let Some(action_headers) = &upload_action.header {
-                        for (key, value) in action_headers {
-                            headers.insert(
-                                reqwest::header::HeaderName::from_bytes(key.as_bytes())
-                                    .map_err(|_| GitError::LfsError(format!("Invalid header name: {}", key)))?,
-                                HeaderValue::from_str(value)
-                                    .map_err(|_| GitError::LfsError(format!("Invalid header value: {}", value)))?,
-                            );
-                        }
-                    }
-                    
-                    // Make the upload request
-                    let response = self.http.put(upload_url)
-                        .headers(headers)
-                        .body(data.to_vec())
-                        .send()
-                        .await
-                        .map_err(|e| GitError::LfsError(format!("LFS upload failed: {}", e)))?;
-                        
-                    if !response.status().is_success() {
-                        let error = response.text().await
-                            .unwrap_or_else(|_| "Unknown error".to_string());
-                            
-                        return Err(GitError::LfsError(format!("LFS upload error: {}", error)));
-                    }
-                    
-                    println!("Successfully uploaded {} to LFS server", pointer.oid);
-                }
+            let page: VerifyLocksResponse = serde_json::from_slice(&response.body)
+                .map_err(|e| GitError::LfsError(format!("Failed to parse LFS lock verify response: {}", e)))?;
+            verification.ours.extend(page.ours);
+            verification.theirs.extend(page.theirs);
+            match page.next_cursor {
+                Some(next) if !next.is_empty() => cursor = Some(next),
+                _ => return Ok(verification),
             }
-        } else {
-            return Err(GitError::LfsError("Object not found in LFS batch response".to_string()));
         }
+    }
+    
+    /// Send a locking API request to `endpoint` with an optional JSON body
+    async fn lock_request(&self, method: reqwest::Method, endpoint: &str, body: Option<serde_json::Value>) -> Result<TransferResponse> {
+        let server_url = self.config.url.as_ref()
+            .ok_or_else(|| GitError::LfsError("LFS server URL not configured".to_string()))?;
+        let url = format!("{}/{}", server_url.trim_end_matches('/'), endpoint);
         
-        Ok(())
+        let body = body.map(|body| body.to_string().into_bytes());
+        self.transport_for(&url)?
+            .send(method, &url, &self.api_headers(), body)
+            .await
     }
     
     /// Upload data to IPFS and return the CID
@@ -358,108 +708,30 @@ impl LfsClient {
     
     /// Download an object from an LFS server
     pub async fn get_from_server(&self, pointer: &LfsPointer, dest_path: impl AsRef<Path>) -> Result<()> {
-        let server_url = self.config.url.as_ref()
-            .ok_or_else(|| GitError::LfsError("LFS server URL not configured".to_string()))?;
-            
-        // First, make a batch request to get the download URL
-        let batch_url = format!("{}/objects/batch", server_url);
-        
-        let request = BatchRequest {
-            operation: "download",
-            reference: None,
-            objects: vec![BatchRequestObject {
-                oid: pointer.oid.clone(),
-                size: pointer.size,
-            }],
-            transfers: Some(vec!["basic"]),
-        };
-        
-        let response = self.http.post(&batch_url)
-            .header(CONTENT_TYPE, "application/vnd.git-lfs+json")
-            .header(ACCEPT, "application/vnd.git-lfs+json")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| GitError::LfsError(format!("LFS batch request failed: {}", e)))?;
-            
-        if !response.status().is_success() {
-            let error = response.text().await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-                
-            return Err(GitError::LfsError(format!("LFS server error: {}", error)));
-        }
+        let dest_path = dest_path.as_ref();
+        let (_, result) = self.download_objects(std::slice::from_ref(pointer)).await?
+            .pop()
+            .ok_or_else(|| GitError::LfsError(format!("Could not find LFS object {}", pointer.oid)))?;
+        let content = result?;
         
-        // Parse the batch response
-        let batch_response: BatchResponse = response.json().await
-            .map_err(|e| GitError::LfsError(format!("Failed to parse LFS response: {}", e)))?;
+        tokio_fs::write(dest_path, content).await
+            .map_err(|e| GitError::LfsError(format!("Failed to write file: {}", e)))?;
             
-        // Find our object in the response
-        if let Some(obj) = batch_response.objects.iter().find(|o| o.oid == pointer.oid) {
-            // Check for errors
-            if let Some(error) = &obj.error {
-                return Err(GitError::LfsError(format!("LFS error: {} - {}", error.code, error.message)));
-            }
-            
-            // Check if we need to download the object
-            if let Some(actions) = &obj.actions {
-                if let Some(download_action) = actions.get("download") {
-                    // We need to download the object
-                    let download_url = &download_action.href;
-                    
-                    // Create headers from the action if any
-                    let mut headers = HeaderMap::new();
-                    if let Some(action_headers) = &download_action.header {
-                        for (key, value) in action_headers {
-                            headers.insert(
-                                reqwest::header::HeaderName::from_bytes(key.as_bytes())
-                                    .map_err(|_| GitError::LfsError(format!("Invalid header name: {}", key)))?,
-                                HeaderValue::from_str(value)
-                                    .map_err(|_| GitError::LfsError(format!("Invalid header value: {}", value)))?,
-                            );
-                        }
-                    }
-                    
-                    // Make the download request
-                    let response = self.http.get(download_url)
-                        .headers(headers)
-                        .send()
-                        .await
-                        .map_err(|e| GitError::LfsError(format!("LFS download failed: {}", e)))?;
-                        
-                    if !response.status().is_success() {
-                        let error = response.text().await
-                            .unwrap_or_else(|_| "Unknown error".to_string());
-                            
-                        return Err(GitError::LfsError(format!("LFS download error: {}", error)));
-                    }
-                    
-                    // Get the content and write to file
-                    let content = response.bytes().await
-                        .map_err(|e| GitError::LfsError(format!("Failed to read LFS response: {}", e)))?;
-                        
-                    tokio_fs::write(dest_path, content).await
-                        .map_err(|e| GitError::LfsError(format!("Failed to write file: {}", e)))?;
-                        
-                    // If IPFS is enabled, also store in IPFS for future use
-                    if self.config.use_ipfs {
-                        if let Some(ipfs_client) = &self.ipfs_client {
-                            match ipfs_client.add_file(dest_path).await {
-                                Ok(cid) => {
-                                    println!("Stored LFS object {} in IPFS with CID: {}", pointer.oid, cid);
-                                    // In a real-world implementation, we would save this CID mapping
-                                },
-                                Err(e) => eprintln!("Warning: Failed to upload to IPFS: {}", e),
-                            }
-                        }
-                    }
-                    
-                    println!("Successfully downloaded {} from LFS server", pointer.oid);
-                    return Ok(());
+        // If IPFS is enabled, also store in IPFS for future use
+        if self.config.use_ipfs {
+            if let Some(ipfs_client) = &self.ipfs_client {
+                match ipfs_client.add_file(dest_path).await {
+                    Ok(cid) => {
+                        println!("Stored LFS object {} in IPFS with CID: {}", pointer.oid, cid);
+                        // In a real-world implementation, we would save this CID mapping
+                    },
+                    Err(e) => eprintln!("Warning: Failed to upload to IPFS: {}", e),
                 }
             }
         }
         
-        Err(GitError::LfsError(format!("Could not find LFS object {}", pointer.oid)))
+        println!("Successfully downloaded {} from LFS server", pointer.oid);
+        Ok(())
     }
     
     /// Track a pattern with Git LFS
@@ -512,4 +784,174 @@ impl LfsClient {
     pub fn config_mut(&mut self) -> &mut LfsConfig {
         &mut self.config
     }
-}
\ No newline at end of file
+}
+
+/// Parse a locking API response, which carries JSON for errors too
+fn parse_lock_response(response: &TransferResponse) -> LockResponse {
+    serde_json::from_slice(&response.body).unwrap_or_else(|_| LockResponse {
+        lock: None,
+        message: Some(format!("{} {}", response.status, response.text())),
+    })
+}
+
+/// Match a batch response up with the requested objects
+///
+/// Relative expiry times are resolved against `received_at`, when the
+/// response arrived.
+/// Check downloaded content against the object ID and size it was asked for
+fn verify_download(oid: &str, size: u64, data: &[u8]) -> Result<()> {
+    use sha2::Digest;
+    
+    if data.len() as u64 != size {
+        return Err(GitError::LfsError(format!(
+            "LFS object {} is {} bytes, but {} were expected", oid, data.len(), size,
+        )));
+    }
+    let expected = oid.strip_prefix("sha256:").unwrap_or(oid);
+    let actual = hex::encode(sha2::Sha256::digest(data));
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(GitError::LfsError(format!(
+            "LFS object {} does not match its content (SHA-256 {})", oid, actual,
+        )));
+    }
+    Ok(())
+}
+
+fn parse_batch_response(requested: Vec<(String, u64)>, response: BatchResponse, received_at: DateTime<Utc>) -> Result<Vec<BatchObject>> {
+    if let Some(transfer) = response.transfer.as_deref().filter(|&transfer| transfer != BASIC_TRANSFER) {
+        return Err(GitError::LfsError(format!("LFS server chose unsupported transfer adapter '{}'", transfer)));
+    }
+    
+    let mut returned: HashMap<String, BatchResponseObject> = response.objects.into_iter()
+        .map(|object| (object.oid.clone(), object))
+        .collect();
+    Ok(requested.into_iter()
+        .map(|(oid, size)| {
+            let actions = match returned.remove(&oid) {
+                None => Err(GitError::LfsError(format!("Object {} not found in LFS batch response", oid))),
+                Some(BatchResponseObject { error: Some(error), .. }) => Err(GitError::LfsError(
+                    format!("LFS error for {}: {} - {}", oid, error.code, error.message))),
+                Some(object) => Ok(object.actions.unwrap_or_default().into_iter()
+                    .map(|(name, action)| {
+                        let expires_at = action.expires_at.or_else(|| {
+                            action.expires_in.map(|seconds| received_at + chrono::Duration::seconds(seconds))
+                        });
+                        (name, BatchAction { href: action.href, header: action.header.unwrap_or_default(), expires_at })
+                    })
+                    .collect()),
+            };
+            BatchObject { oid, size, actions }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch_response() {
+        let response: BatchResponse = serde_json::from_str(r#"{
+            "transfer": "basic",
+            "objects": [
+                {"oid": "aaaa", "size": 10, "actions": {
+                    "upload": {"href": "https://lfs.example/aaaa", "header": {"Authorization": "Basic xyz"}, "expires_in": 3600},
+                    "verify": {"href": "https://lfs.example/verify", "expires_at": "2001-01-01T00:00:00Z"}
+                }},
+                {"oid": "bbbb", "size": 20},
+                {"oid": "cccc", "size": 30, "error": {"code": 422, "message": "Invalid object size"}}
+            ]
+        }"#).unwrap();
+        let requested = vec![
+            ("aaaa".to_string(), 10), ("bbbb".to_string(), 20), ("cccc".to_string(), 30), ("dddd".to_string(), 40),
+        ];
+        let objects = parse_batch_response(requested, response, Utc::now()).unwrap();
+        assert_eq!(objects.len(), 4);
+
+        let actions = objects[0].actions.as_ref().unwrap();
+        let upload = &actions["upload"];
+        assert_eq!(upload.href, "https://lfs.example/aaaa");
+        assert_eq!(upload.header["Authorization"], "Basic xyz");
+        assert!(!upload.is_expired());
+        assert!(actions["verify"].is_expired());
+
+        // Nothing to do for an object the server already has
+        assert!(objects[1].actions.as_ref().unwrap().is_empty());
+        // Refused and missing objects fail alone
+        let err = objects[2].actions.as_ref().unwrap_err();
+        assert!(err.to_string().contains("Invalid object size"), "{}", err);
+        assert!(objects[3].actions.is_err());
+
+        let response: BatchResponse = serde_json::from_str(r#"{"transfer": "tus", "objects": []}"#).unwrap();
+        assert!(parse_batch_response(Vec::new(), response, Utc::now()).is_err());
+    }
+
+    /// Records the requests it is asked to send
+    struct RecordingTransport {
+        hrefs: std::sync::Mutex<Vec<String>>,
+        response: &'static [u8],
+    }
+
+    #[async_trait::async_trait]
+    impl TransferTransport for RecordingTransport {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        async fn send(&self, _method: reqwest::Method, href: &str, _headers: &[(String, String)], _body: Option<Vec<u8>>) -> Result<TransferResponse> {
+            self.hrefs.lock().unwrap().push(href.to_string());
+            Ok(TransferResponse { status: 200, body: Bytes::from_static(self.response) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_onion_actions_use_tor_transport() {
+        let onion_href = "http://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/objects/aaaa";
+        let download = |href: &str| {
+            let action = BatchAction { href: href.to_string(), header: HashMap::new(), expires_at: None };
+            HashMap::from([("download".to_string(), action)])
+        };
+
+        let oid = LfsPointer::from_data(b"object", None::<&Path>).oid;
+        
+        // Without a Tor transport, onion hrefs are refused rather than sent in the clear
+        let client = LfsClient::new(LfsConfig::default()).unwrap();
+        assert!(client.basic_download(&oid, 6, &download(onion_href)).await.is_err());
+
+        let recording = Arc::new(RecordingTransport { hrefs: std::sync::Mutex::new(Vec::new()), response: b"object" });
+        let client = LfsClient::new(LfsConfig::default()).unwrap().with_onion_transport(recording.clone());
+        let content = client.basic_download(&oid, 6, &download(onion_href)).await.unwrap();
+        assert_eq!(content, Bytes::from_static(b"object"));
+        assert_eq!(*recording.hrefs.lock().unwrap(), vec![onion_href.to_string()]);
+        
+        // Content that doesn't match the object ID or size is refused
+        let other = LfsPointer::from_data(b"another", None::<&Path>).oid;
+        assert!(client.basic_download(&other, 6, &download(onion_href)).await.is_err());
+        assert!(client.basic_download(&oid, 7, &download(onion_href)).await.is_err());
+
+        assert_eq!(client.transport_for("https://lfs.example.com/objects/aaaa").unwrap().name(), "http");
+    }
+
+    #[tokio::test]
+    async fn test_onion_server_api_uses_tor_transport() {
+        let server = "http://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/repo.git/info/lfs";
+        let config = LfsConfig { url: Some(server.to_string()), ..LfsConfig::default() };
+
+        // Without a Tor transport, the batch API isn't called in the clear
+        let client = LfsClient::new(config.clone()).unwrap();
+        assert!(client.batch(BatchOperation::Download, vec![("aaaa".to_string(), 6)]).await.is_err());
+
+        let recording = Arc::new(RecordingTransport {
+            hrefs: std::sync::Mutex::new(Vec::new()),
+            response: br#"{"objects": [{"oid": "aaaa", "size": 6}], "locks": []}"#,
+        });
+        let client = LfsClient::new(config).unwrap().with_onion_transport(recording.clone());
+        let objects = client.batch(BatchOperation::Download, vec![("aaaa".to_string(), 6)]).await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert!(client.list_locks().await.unwrap().is_empty());
+        assert_eq!(*recording.hrefs.lock().unwrap(), vec![
+            format!("{}/objects/batch", server),
+            format!("{}/locks", server),
+        ]);
+    }
+}
@@ -1,9 +1,11 @@
 /// LFS configuration settings
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use serde::{Serialize, Deserialize};
 
 /// Configuration for Git LFS functionality
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LfsConfig {
     /// Whether LFS is enabled
     #[serde(default = "default_lfs_enabled")]
@@ -40,6 +42,23 @@ pub struct LfsConfig {
     /// Whether to automatically upload objects to IPFS when downloaded from LFS server
     #[serde(default = "default_auto_upload")]
     pub auto_upload_to_ipfs: bool,
+
+    /// Name to identify as to the LFS server; locks are owned by it
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Password sent with `user`
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Users who may take locks when serving, mapped to the hex SHA-256
+    /// of their password. Locking is refused while this is empty
+    #[serde(default)]
+    pub lock_users: HashMap<String, String>,
+
+    /// Users among `lock_users` who may force-release others' locks
+    #[serde(default)]
+    pub lock_admins: Vec<String>,
 }
 
 fn default_lfs_enabled() -> bool {
@@ -79,6 +98,10 @@ impl Default for LfsConfig {
             pin_objects: default_pin_objects(),
             ipfs_primary: false,
             auto_upload_to_ipfs: default_auto_upload(),
+            user: None,
+            password: None,
+            lock_users: HashMap::new(),
+            lock_admins: Vec::new(),
         }
     }
 }
@@ -143,6 +166,18 @@ impl LfsConfig {
         self
     }
     
+    /// Set the name to identify as to the LFS server
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+    
+    /// Set the password sent with the user
+    pub fn with_password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+    
     /// Get the absolute path to the LFS objects directory
     pub fn get_absolute_objects_dir(&self, repo_path: impl AsRef<Path>) -> PathBuf {
         let repo_path = repo_path.as_ref();
@@ -8,7 +8,7 @@ use tokio::fs as tokio_fs;
 use tokio::io::AsyncWriteExt;
 use sha2::{Sha256, Digest};
 
-use crate::core::{GitError, Result};
+use crate::core::{GitError, Result, attributes_for};
 use super::{LfsClient, LfsPointer, LfsStorage, LfsConfig, LfsObjectId};
 
 /// LFS filter for Git
@@ -306,6 +306,14 @@ pub fn install_filter_in_repo(repo_path: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// Check whether a worktree-relative path is tracked by LFS (`filter=lfs`)
+///
+/// Uses the same attribute lookup as merges and diffs, so patterns in
+/// nested `.gitattributes` files and `.git/info/attributes` count too.
+pub fn is_lfs_tracked(repo: &gix::Repository, path: &str) -> Result<bool> {
+    Ok(attributes_for(repo, path)?.is_value("filter", "lfs"))
+}
+
 /// Check if the content appears to be an LFS pointer
 fn is_lfs_pointer(content: &str) -> bool {
     // LFS pointers typically start with "version https://git-lfs.github.com/spec/"
@@ -0,0 +1,192 @@
+/// Git LFS file locks
+///
+/// Locks follow the Git LFS locking API
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md
+/// and are kept in a JSON file next to the object metadata.
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::RwLock;
+
+use crate::core::{GitError, Result, io_err};
+
+/// Owner of a lock, as the locking API reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    /// Name the owner authenticated as
+    pub name: String,
+}
+
+/// A lock on a path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LfsLock {
+    /// Server-assigned lock ID
+    pub id: String,
+    /// Locked path, relative to the repository root
+    pub path: String,
+    /// When the lock was taken
+    pub locked_at: chrono::DateTime<chrono::Utc>,
+    /// Who holds the lock
+    pub owner: LockOwner,
+}
+
+/// Result of asking for a lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockRequest {
+    /// The path was free and is now locked
+    Created(LfsLock),
+    /// The path is already locked; this is the existing lock
+    Conflict(LfsLock),
+}
+
+/// Result of releasing a lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnlockRequest {
+    /// The lock was released
+    Released(LfsLock),
+    /// The lock belongs to someone else and `force` wasn't given
+    NotOwner(LfsLock),
+    /// No lock has this ID
+    NotFound,
+}
+
+/// Persistent set of locks
+pub struct LockStore {
+    /// File the locks are saved to
+    path: PathBuf,
+    /// Current locks, oldest first
+    locks: RwLock<Vec<LfsLock>>,
+}
+
+impl LockStore {
+    /// Open the lock file at `path`, starting empty if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let locks = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| GitError::LfsError(format!("Failed to parse lock file {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(io_err(format!("Failed to read lock file: {}", e), &path)),
+        };
+        Ok(Self { path, locks: RwLock::new(locks) })
+    }
+
+    /// Lock `path` for `owner`, unless someone already holds it
+    pub async fn create(&self, path: &str, owner: &str) -> Result<LockRequest> {
+        let mut locks = self.locks.write().await;
+        if let Some(existing) = locks.iter().find(|lock| lock.path == path) {
+            return Ok(LockRequest::Conflict(existing.clone()));
+        }
+
+        let lock = LfsLock {
+            id: format!("{:032x}", rand::random::<u128>()),
+            path: path.to_string(),
+            locked_at: chrono::Utc::now(),
+            owner: LockOwner { name: owner.to_string() },
+        };
+        locks.push(lock.clone());
+        self.save(&locks)?;
+        Ok(LockRequest::Created(lock))
+    }
+
+    /// Release lock `id` on behalf of `owner`
+    ///
+    /// Only the owner may release a lock, unless `force` is set. Callers
+    /// decide who may force it.
+    pub async fn unlock(&self, id: &str, owner: &str, force: bool) -> Result<UnlockRequest> {
+        let mut locks = self.locks.write().await;
+        let position = match locks.iter().position(|lock| lock.id == id) {
+            Some(position) => position,
+            None => return Ok(UnlockRequest::NotFound),
+        };
+        if locks[position].owner.name != owner && !force {
+            return Ok(UnlockRequest::NotOwner(locks[position].clone()));
+        }
+
+        let lock = locks.remove(position);
+        self.save(&locks)?;
+        Ok(UnlockRequest::Released(lock))
+    }
+
+    /// Locks matching `path` and `id`, where given
+    pub async fn list(&self, path: Option<&str>, id: Option<&str>) -> Vec<LfsLock> {
+        self.locks.read().await.iter()
+            .filter(|lock| path.map_or(true, |path| lock.path == path))
+            .filter(|lock| id.map_or(true, |id| lock.id == id))
+            .cloned()
+            .collect()
+    }
+
+    /// Split the locks into those held by `owner` and those held by others
+    pub async fn verify(&self, owner: &str) -> (Vec<LfsLock>, Vec<LfsLock>) {
+        self.locks.read().await.iter()
+            .cloned()
+            .partition(|lock| lock.owner.name == owner)
+    }
+
+    fn save(&self, locks: &[LfsLock]) -> Result<()> {
+        let json = serde_json::to_string_pretty(locks)
+            .map_err(|e| GitError::LfsError(format!("Failed to serialize locks: {}", e)))?;
+
+        // Write to a temporary file first, then rename for atomicity
+        let temp_file = self.path.with_extension("tmp");
+        fs::write(&temp_file, json)
+            .map_err(|e| io_err(format!("Failed to write lock file: {}", e), &temp_file))?;
+        fs::rename(&temp_file, &self.path)
+            .map_err(|e| io_err(format!("Failed to rename lock file: {}", e), &self.path))?;
+        Ok(())
+    }
+}
+
+/// One page of `items`, starting at `cursor`, and the cursor of the next page
+///
+/// Cursors are offsets into the list; an unparsable cursor starts over.
+pub fn paginate<T>(items: Vec<T>, cursor: Option<&str>, limit: Option<usize>) -> (Vec<T>, Option<String>) {
+    let start = cursor.and_then(|cursor| cursor.parse().ok()).unwrap_or(0);
+    let limit = limit.filter(|&limit| limit > 0).unwrap_or(usize::MAX);
+    let total = items.len();
+    let page: Vec<T> = items.into_iter().skip(start).take(limit).collect();
+    let end = start + page.len();
+    let next_cursor = if end < total { Some(end.to_string()) } else { None };
+    (page, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("locks.json");
+        let store = LockStore::open(&file).unwrap();
+
+        let lock = match store.create("assets/model.bin", "alice").await.unwrap() {
+            LockRequest::Created(lock) => lock,
+            other => panic!("expected a new lock, got {:?}", other),
+        };
+        assert_eq!(store.create("assets/model.bin", "bob").await.unwrap(), LockRequest::Conflict(lock.clone()));
+
+        let (ours, theirs) = store.verify("bob").await;
+        assert!(ours.is_empty());
+        assert_eq!(theirs, vec![lock.clone()]);
+
+        // Locks survive reopening the store
+        let store = LockStore::open(&file).unwrap();
+        assert_eq!(store.list(Some("assets/model.bin"), None).await, vec![lock.clone()]);
+
+        assert_eq!(store.unlock(&lock.id, "bob", false).await.unwrap(), UnlockRequest::NotOwner(lock.clone()));
+        assert_eq!(store.unlock(&lock.id, "bob", true).await.unwrap(), UnlockRequest::Released(lock.clone()));
+        assert_eq!(store.unlock(&lock.id, "alice", false).await.unwrap(), UnlockRequest::NotFound);
+        assert!(store.list(None, None).await.is_empty());
+    }
+
+    #[test]
+    fn test_paginate() {
+        let (page, next) = paginate(vec![1, 2, 3, 4, 5], None, Some(2));
+        assert_eq!((page, next.as_deref()), (vec![1, 2], Some("2")));
+        let (page, next) = paginate(vec![1, 2, 3, 4, 5], Some("4"), Some(2));
+        assert_eq!((page, next), (vec![5], None));
+    }
+}
@@ -10,15 +10,19 @@ mod server;
 mod filter;
 mod pointer;
 mod storage;
+mod locks;
+mod transfer;
 mod commands;
 
 // Public exports
 pub use config::LfsConfig;
-pub use client::LfsClient;
+pub use client::{LfsClient, BatchOperation, BatchAction, BatchObject};
 pub use server::LfsServer;
-pub use filter::LfsFilter;
+pub use filter::{LfsFilter, is_lfs_tracked};
 pub use pointer::LfsPointer;
 pub use storage::{LfsStorage, LfsObjectProvider, LfsObjectId};
+pub use locks::{LfsLock, LockOwner, LockStore, LockRequest, UnlockRequest};
+pub use transfer::{TransferTransport, TransferResponse, HttpTransferTransport, TorTransferTransport};
 
 use crate::core::{ArtiGitClient, Result};
 use std::path::Path;
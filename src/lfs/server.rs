@@ -9,11 +9,14 @@ use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use tokio::io::AsyncWriteExt;
 use hyper::{Body, Request, Response, StatusCode};
-use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
 use url::Url;
+use base64::{Engine as _, engine::general_purpose};
+use sha2::{Digest, Sha256};
 
 use crate::core::{GitError, Result};
-use super::{LfsClient, LfsStorage, LfsObjectId, LfsPointer};
+use super::{LfsClient, LfsStorage, LfsObjectId, LfsPointer, LfsLock, LockRequest, UnlockRequest};
+use super::locks::paginate;
 
 /// The LFS batch request
 #[derive(Debug, Deserialize)]
@@ -71,6 +74,63 @@ struct BatchObjectError {
     message: String,
 }
 
+/// A request to create a lock
+#[derive(Debug, Default, Deserialize)]
+struct CreateLockRequest {
+    path: String,
+    #[serde(rename = "ref")]
+    reference: Option<BatchReference>,
+}
+
+/// A request to list the locks relevant to a push
+#[derive(Debug, Default, Deserialize)]
+struct VerifyLocksRequest {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    #[serde(rename = "ref")]
+    reference: Option<BatchReference>,
+}
+
+/// A request to release a lock
+#[derive(Debug, Default, Deserialize)]
+struct DeleteLockRequest {
+    #[serde(default)]
+    force: bool,
+    #[serde(rename = "ref")]
+    reference: Option<BatchReference>,
+}
+
+/// A single lock, with an explanation when the request failed
+#[derive(Debug, Serialize)]
+struct LockResponse {
+    lock: LfsLock,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// A page of locks
+#[derive(Debug, Serialize)]
+struct LockListResponse {
+    locks: Vec<LfsLock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// A page of locks, split by owner
+#[derive(Debug, Serialize)]
+struct VerifyLocksResponse {
+    ours: Vec<LfsLock>,
+    theirs: Vec<LfsLock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// An error from the locking API
+#[derive(Debug, Serialize)]
+struct LockErrorResponse {
+    message: String,
+}
+
 pub struct LfsServer {
     /// The LFS client
     client: Arc<LfsClient>,
@@ -94,10 +154,19 @@ impl LfsServer {
     
     /// Handle an LFS API request
     pub async fn handle_request(&self, req: Request<Body>) -> Result<Response<Body>> {
-        let path = req.uri().path();
+        let path = req.uri().path().to_string();
+        let method = req.method().as_str().to_string();
         
-        match (req.method().as_str(), path) {
+        match (method.as_str(), path.as_str()) {
             ("POST", "/objects/batch") => self.handle_batch(req).await,
+            ("POST", "/locks") => self.handle_create_lock(req).await,
+            ("GET", "/locks") => self.handle_list_locks(req).await,
+            ("POST", "/locks/verify") => self.handle_verify_locks(req).await,
+            ("POST", path) if path.starts_with("/locks/") && path.ends_with("/unlock") => {
+                let id = path.strip_prefix("/locks/").and_then(|rest| rest.strip_suffix("/unlock")).unwrap_or("");
+                let id = id.to_string();
+                self.handle_delete_lock(req, &id).await
+            },
             ("GET", path) if path.starts_with("/objects/") => {
                 let oid = path.strip_prefix("/objects/").unwrap_or("");
                 self.handle_download(oid).await
@@ -287,6 +356,107 @@ impl LfsServer {
             .unwrap())
     }
     
+    /// Handle a request to lock a path
+    async fn handle_create_lock(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let owner = match self.authenticate(&req) {
+            Some(owner) => owner,
+            None => return unauthorized(),
+        };
+        let request: CreateLockRequest = read_json(req).await?;
+        if request.path.is_empty() {
+            return lock_json(StatusCode::UNPROCESSABLE_ENTITY, &LockErrorResponse { message: "Missing path".to_string() });
+        }
+        
+        match self.storage.locks().create(&request.path, &owner).await? {
+            LockRequest::Created(lock) => {
+                log::info!("{} locked {}", owner, lock.path);
+                lock_json(StatusCode::CREATED, &LockResponse { lock, message: None })
+            },
+            LockRequest::Conflict(lock) => {
+                let message = format!("'{}' is already locked by {}", lock.path, lock.owner.name);
+                lock_json(StatusCode::CONFLICT, &LockResponse { lock, message: Some(message) })
+            },
+        }
+    }
+    
+    /// Handle a request to list locks, optionally filtered by path or ID
+    async fn handle_list_locks(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query: HashMap<String, String> = req.uri().query()
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+        let limit = query.get("limit").and_then(|limit| limit.parse().ok());
+        
+        let locks = self.storage.locks()
+            .list(query.get("path").map(String::as_str), query.get("id").map(String::as_str))
+            .await;
+        let (locks, next_cursor) = paginate(locks, query.get("cursor").map(String::as_str), limit);
+        lock_json(StatusCode::OK, &LockListResponse { locks, next_cursor })
+    }
+    
+    /// Handle a request to list locks split into the caller's and others'
+    async fn handle_verify_locks(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let owner = match self.authenticate(&req) {
+            Some(owner) => owner,
+            None => return unauthorized(),
+        };
+        let request: VerifyLocksRequest = read_json(req).await?;
+        
+        // Paginate over all locks, then split each page by owner
+        let (ours, theirs) = self.storage.locks().verify(&owner).await;
+        let all: Vec<(bool, LfsLock)> = ours.into_iter().map(|lock| (true, lock))
+            .chain(theirs.into_iter().map(|lock| (false, lock)))
+            .collect();
+        let (page, next_cursor) = paginate(all, request.cursor.as_deref(), request.limit);
+        let (ours, theirs): (Vec<_>, Vec<_>) = page.into_iter().partition(|(ours, _)| *ours);
+        lock_json(StatusCode::OK, &VerifyLocksResponse {
+            ours: ours.into_iter().map(|(_, lock)| lock).collect(),
+            theirs: theirs.into_iter().map(|(_, lock)| lock).collect(),
+            next_cursor,
+        })
+    }
+    
+    /// Handle a request to release a lock
+    async fn handle_delete_lock(&self, req: Request<Body>, id: &str) -> Result<Response<Body>> {
+        let owner = match self.authenticate(&req) {
+            Some(owner) => owner,
+            None => return unauthorized(),
+        };
+        let request: DeleteLockRequest = read_json(req).await?;
+        if request.force && !self.client.config().lock_admins.contains(&owner) {
+            let message = format!("{} is not allowed to force-unlock locks", owner);
+            return lock_json(StatusCode::FORBIDDEN, &LockErrorResponse { message });
+        }
+        
+        match self.storage.locks().unlock(id, &owner, request.force).await? {
+            UnlockRequest::Released(lock) => {
+                if lock.owner.name != owner {
+                    log::warn!("{} force-unlocked {} held by {}", owner, lock.path, lock.owner.name);
+                }
+                lock_json(StatusCode::OK, &LockResponse { lock, message: None })
+            },
+            UnlockRequest::NotOwner(lock) => {
+                let message = format!("Lock {} on '{}' is owned by {}; use force to unlock it", lock.id, lock.path, lock.owner.name);
+                lock_json(StatusCode::FORBIDDEN, &LockResponse { lock, message: Some(message) })
+            },
+            UnlockRequest::NotFound => {
+                lock_json(StatusCode::NOT_FOUND, &LockErrorResponse { message: format!("Lock {} not found", id) })
+            },
+        }
+    }
+    
+    /// The lock user a request authenticated as, if its credentials match
+    /// a configured one
+    fn authenticate(&self, req: &Request<Body>) -> Option<String> {
+        let (user, password) = basic_credentials(req)?;
+        let expected = self.client.config().lock_users.get(&user)?;
+        let presented = hex::encode(Sha256::digest(password.as_bytes()));
+        if !presented.eq_ignore_ascii_case(expected.trim()) {
+            log::warn!("Rejected locking request with a wrong password for {}", user);
+            return None;
+        }
+        Some(user)
+    }
+    
     /// Start the LFS server on a given address
     pub async fn start(&self, addr: &str) -> Result<()> {
         println!("Starting LFS server on {}", addr);
@@ -326,4 +496,119 @@ impl LfsServer {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// User name and password from HTTP basic credentials
+fn basic_credentials(req: &Request<Body>) -> Option<(String, String)> {
+    let encoded = req.headers().get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(general_purpose::STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    if user.is_empty() {
+        return None;
+    }
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Ask for lock user credentials
+fn unauthorized() -> Result<Response<Body>> {
+    let mut response = lock_json(StatusCode::UNAUTHORIZED, &LockErrorResponse {
+        message: "Locking requires the credentials of a lock user".to_string(),
+    })?;
+    response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"Git LFS\""));
+    Ok(response)
+}
+
+/// Parse a JSON request body; an empty body is the type's default
+async fn read_json<T: serde::de::DeserializeOwned + Default>(req: Request<Body>) -> Result<T> {
+    let body_bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| GitError::LfsError(format!("Failed to read request body: {}", e)))?;
+    if body_bytes.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(&body_bytes)
+        .map_err(|e| GitError::LfsError(format!("Failed to parse lock request: {}", e)))
+}
+
+/// A locking API response with a JSON body
+fn lock_json(status: StatusCode, body: &impl Serialize) -> Result<Response<Body>> {
+    let json = serde_json::to_string(body)
+        .map_err(|e| GitError::LfsError(format!("Failed to serialize lock response: {}", e)))?;
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/vnd.git-lfs+json"))
+        .body(Body::from(json))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::LfsConfig;
+
+    fn server(dir: &Path) -> LfsServer {
+        let mut config = LfsConfig::default();
+        for (user, password) in [("alice", "wonderland"), ("root", "hunter2")] {
+            config.lock_users.insert(user.to_string(), hex::encode(Sha256::digest(password.as_bytes())));
+        }
+        config.lock_admins.push("root".to_string());
+        let client = Arc::new(LfsClient::new(config).unwrap());
+        let storage = Arc::new(LfsStorage::new(dir).unwrap());
+        LfsServer::new(client, storage, "http://example.onion/repo.git/info/lfs")
+    }
+
+    fn request(uri: &str, credentials: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method("POST").uri(uri);
+        if let Some(credentials) = credentials {
+            builder = builder.header(AUTHORIZATION, format!("Basic {}", general_purpose::STANDARD.encode(credentials)));
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn lock_id(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        json["lock"]["id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_locking_requires_a_lock_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let body = r#"{"path": "assets/model.bin"}"#;
+
+        for credentials in [None, Some("alice:"), Some("alice:wrong"), Some("mallory:wonderland")] {
+            let response = server.handle_request(request("/locks", credentials, body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", credentials);
+            assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        }
+        assert!(server.storage.locks().list(None, None).await.is_empty());
+
+        let response = server.handle_request(request("/locks", Some("alice:wonderland"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(server.storage.locks().list(None, None).await[0].owner.name, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_only_admins_force_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let created = server.handle_request(request("/locks", Some("alice:wonderland"), r#"{"path": "a.bin"}"#)).await.unwrap();
+        let unlock = format!("/locks/{}/unlock", lock_id(created).await);
+
+        // Claiming to be the owner takes her password
+        let response = server.handle_request(request(&unlock, Some("alice:guess"), "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        server.storage.locks().create("b.bin", "root").await.unwrap();
+        let theirs = server.storage.locks().list(Some("b.bin"), None).await.remove(0);
+        let response = server.handle_request(
+            request(&format!("/locks/{}/unlock", theirs.id), Some("alice:wonderland"), r#"{"force": true}"#),
+        ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = server.handle_request(request(&unlock, Some("root:hunter2"), r#"{"force": true}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.storage.locks().list(Some("a.bin"), None).await, Vec::new());
+    }
+}
@@ -13,6 +13,7 @@ use serde::{Serialize, Deserialize};
 use crate::core::{GitError, Result, io_err};
 use crate::ipfs::IpfsClient;
 use crate::lfs::pointer::LfsPointer;
+use crate::lfs::locks::LockStore;
 
 /// An LFS object ID, which is a SHA-256 hash
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -207,6 +208,9 @@ pub struct LfsStorage {
     
     /// Cache of object metadata
     metadata_cache: RwLock<HashMap<String, StoredObjectMetadata>>,
+
+    /// File locks, saved next to the metadata
+    locks: LockStore,
     
     /// Storage statistics
     stats: RwLock<LfsStorageStats>,
@@ -230,12 +234,15 @@ impl LfsStorage {
         fs::create_dir_all(&metadata_dir)
             .map_err(|e| io_err(format!("Failed to create metadata directory: {}", e), &metadata_dir))?;
         
+        let locks = LockStore::open(base_dir.join("locks.json"))?;
+        
         let storage = Self {
             base_dir,
             ipfs_client: None,
             ipfs_primary: false,
             ipfs_pin: true,
             metadata_cache: RwLock::new(HashMap::new()),
+            locks,
             stats: RwLock::new(LfsStorageStats::default()),
             upload_throttle: RwLock::new(0),
             download_throttle: RwLock::new(0),
@@ -259,6 +266,11 @@ impl LfsStorage {
         Ok(storage)
     }
     
+    /// File locks held on this storage
+    pub fn locks(&self) -> &LockStore {
+        &self.locks
+    }
+    
     /// Set bandwidth throttling for uploads (bytes/sec, 0 = unlimited)
     pub async fn set_upload_throttle(&self, bytes_per_sec: u64) {
         let mut throttle = self.upload_throttle.write().await;
@@ -273,6 +285,25 @@ impl LfsStorage {
         log::info!("LFS download throttle set to {} bytes/sec", bytes_per_sec);
     }
     
+    /// Write the storage statistics to `stats.json`
+    ///
+    /// Object metadata and locks are saved as they change; the statistics
+    /// only live in memory until this is called.
+    pub async fn flush(&self) -> Result<()> {
+        let stats = *self.stats.read().await;
+        let json = serde_json::to_string_pretty(&stats)
+            .map_err(|e| GitError::LfsError(format!("Failed to serialize LFS stats: {}", e)))?;
+        
+        // Write to a temporary file first, then rename for atomicity
+        let stats_file = self.base_dir.join("stats.json");
+        let temp_file = stats_file.with_extension("tmp");
+        fs::write(&temp_file, json)
+            .map_err(|e| io_err(format!("Failed to write LFS stats: {}", e), &temp_file))?;
+        fs::rename(&temp_file, &stats_file)
+            .map_err(|e| io_err(format!("Failed to rename LFS stats: {}", e), &stats_file))?;
+        Ok(())
+    }
+    
     /// Load metadata from disk
     async fn load_metadata(&self) -> Result<()> {
         log::debug!("Loading LFS metadata...");
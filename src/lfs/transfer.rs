@@ -0,0 +1,113 @@
+/// Transports for the requests of the basic transfer adapter
+///
+/// Batch actions may point at clearnet servers or at onion services. Actions
+/// on onion hosts go through the pooled Tor transport, the same one Git
+/// traffic uses; everything else goes through the regular HTTP client.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use reqwest::{Client as HttpClient, Method};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::core::{GitError, Result};
+use crate::transport::PooledTorTransport;
+
+/// Response to a transfer request
+#[derive(Debug, Clone)]
+pub struct TransferResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// Response body, with any transfer encoding undone
+    pub body: Bytes,
+}
+
+impl TransferResponse {
+    /// Whether the status is 2xx
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The body as text, for error messages
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).trim().to_string()
+    }
+}
+
+/// Sends the upload, download and verify requests of batch actions
+#[async_trait]
+pub trait TransferTransport: Send + Sync {
+    /// Short name of the transport, for logs
+    fn name(&self) -> &'static str;
+
+    /// Send `method` to `href` with `headers` and an optional body
+    async fn send(&self, method: Method, href: &str, headers: &[(String, String)], body: Option<Vec<u8>>) -> Result<TransferResponse>;
+}
+
+/// Sends transfer requests with the regular HTTP client
+pub struct HttpTransferTransport {
+    http: HttpClient,
+}
+
+impl HttpTransferTransport {
+    /// Send requests through `http`
+    pub fn new(http: HttpClient) -> Self {
+        Self { http }
+    }
+}
+
+#[async_trait]
+impl TransferTransport for HttpTransferTransport {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn send(&self, method: Method, href: &str, headers: &[(String, String)], body: Option<Vec<u8>>) -> Result<TransferResponse> {
+        let mut header_map = HeaderMap::new();
+        for (key, value) in headers {
+            header_map.insert(
+                HeaderName::from_bytes(key.as_bytes())
+                    .map_err(|_| GitError::LfsError(format!("Invalid header name: {}", key)))?,
+                HeaderValue::from_str(value)
+                    .map_err(|_| GitError::LfsError(format!("Invalid header value: {}", value)))?,
+            );
+        }
+
+        let mut request = self.http.request(method, href).headers(header_map);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request.send().await
+            .map_err(|e| GitError::LfsError(format!("LFS request to {} failed: {}", href, e)))?;
+        let status = response.status().as_u16();
+        let body = response.bytes().await
+            .map_err(|e| GitError::LfsError(format!("Failed to read LFS response: {}", e)))?;
+        Ok(TransferResponse { status, body })
+    }
+}
+
+/// Sends transfer requests to onion services over pooled Tor connections
+pub struct TorTransferTransport {
+    transport: Arc<PooledTorTransport>,
+}
+
+impl TorTransferTransport {
+    /// Send requests through `transport`, sharing its connection pool
+    pub fn new(transport: Arc<PooledTorTransport>) -> Self {
+        Self { transport }
+    }
+}
+
+#[async_trait]
+impl TransferTransport for TorTransferTransport {
+    fn name(&self) -> &'static str {
+        "tor"
+    }
+
+    async fn send(&self, method: Method, href: &str, headers: &[(String, String)], body: Option<Vec<u8>>) -> Result<TransferResponse> {
+        let (status, body) = self.transport
+            .http_request(method.as_str(), href, headers, body.as_deref().unwrap_or_default())
+            .await?;
+        Ok(TransferResponse { status, body: Bytes::from(body) })
+    }
+}
@@ -17,7 +17,7 @@ pub use core::{
     ArtiGitClient, ArtiGitConfig, GitError, Result, ObjectId, ObjectType,
     TorConfig, GitConfig, OnionServiceConfig, ConfigError,
     FileStatus, FileChange, status, create_branch, list_branches, 
-    delete_branch, checkout, log, format_commit
+    delete_branch, checkout, log, format_commit, show
 };
 pub use service::GitOnionService;
 pub use transport::TorTransport;
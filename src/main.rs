@@ -14,8 +14,10 @@ mod ipfs;
 
 use clap::{Parser, Subcommand, Args};
 use tokio::signal;
-use crate::core::{ArtiGitClient, ArtiGitConfig, OnionServiceConfig, GitError, Result};
-use crate::service::GitOnionService;
+use tokio_util::sync::CancellationToken;
+use crate::core::{ArtiGitClient, ArtiGitConfig, OnionServiceConfig, RemoteTorSettings, GitError, Result};
+use crate::service::{GitOnionService, RestartPolicy, ServiceSupervisor};
+use crate::transport::{ConnectionLog, RoutingMode, TransportEvent, UrlConfig};
 
 #[derive(Parser)]
 #[command(name = "arti-git")]
@@ -25,8 +27,32 @@ struct Cli {
     command: Commands,
     
     /// Path to config file
-    #[arg(short, long, value_name = "FILE")]
-    config: Option<PathBuf>,
+    #[arg(long, value_name = "FILE")]
+    config_file: Option<PathBuf>,
+    
+    /// Override a config value for this run, like git's -c (repeatable)
+    ///
+    /// A value without `=` is a config file path, the old meaning of
+    /// `-c/--config` kept for existing scripts.
+    #[arg(short = 'c', long = "config", global = true, value_name = "KEY=VALUE")]
+    config_overrides: Vec<String>,
+    
+    /// Ignore refs/replace when reading objects (for debugging)
+    #[arg(long, global = true)]
+    no_replace_objects: bool,
+    
+    /// Append a connection log with onion addresses redacted to FILE
+    #[arg(long, global = true, value_name = "FILE")]
+    connection_log: Option<PathBuf>,
+    
+    /// How local paths appear in errors: full, relative or hidden
+    #[arg(long, global = true, value_name = "MODE")]
+    error_paths: Option<core::PathRedaction>,
+    
+    /// Accept onion services whose fingerprint changed since first use,
+    /// recording the new one
+    #[arg(long, global = true)]
+    accept_new_fingerprint: bool,
 }
 
 #[derive(Subcommand)]
@@ -35,6 +61,10 @@ enum Commands {
     Clone(CloneArgs),
     /// Pull updates from a remote
     Pull(PullArgs),
+    /// Update remote-tracking refs without merging, optionally changing the depth of a shallow history
+    Fetch(FetchArgs),
+    /// Fetch mirrored remotes, once or on a schedule with --watch
+    Mirror(MirrorArgs),
     /// Push changes to a remote
     Push(PushArgs),
     /// Initialize a repository
@@ -43,8 +73,48 @@ enum Commands {
     Status(StatusArgs),
     /// Add files to the index
     Add(AddArgs),
+    /// Move or rename a tracked file or directory
+    Mv(MvArgs),
+    /// Remove files from the working tree and the index
+    Rm(RmArgs),
     /// Commit changes to the repository
     Commit(CommitArgs),
+    /// Move HEAD to another commit
+    Reset(ResetArgs),
+    /// Show a commit and the changes it introduced
+    Show(ShowArgs),
+    /// Show where HEAD or a branch pointed before
+    Reflog(ReflogArgs),
+    /// Show unstaged changes, or staged changes with --cached
+    Diff(DiffArgs),
+    /// Apply a patch to the working tree
+    Apply(ApplyArgs),
+    /// Apply a series of patches from a mailbox as commits
+    Am(AmArgs),
+    /// Count objects and the disk space they use
+    CountObjects(CountObjectsArgs),
+    /// Check a pack and its index for corruption
+    VerifyPack(VerifyPackArgs),
+    /// Serve a fetch over stdin/stdout, for use as git's `--upload-pack`
+    UploadPack(UploadPackArgs),
+    /// Fetch a pack for the given refs over Tor without updating any refs
+    FetchPack(FetchPackArgs),
+    /// List the refs of a remote repository without cloning it
+    LsRemote(LsRemoteArgs),
+    /// Remove remote-tracking refs deleted on the remote
+    Prune(PruneArgs),
+    /// Create, list and delete replacement refs (refs/replace)
+    Replace(ReplaceArgs),
+    /// Write and inspect the commit-graph file
+    CommitGraph(CommitGraphArgs),
+    /// Manage and inspect configured remotes
+    Remote(RemoteArgs),
+    /// Query git configuration
+    Config(ConfigArgs),
+    /// Initialize and update submodules
+    Submodule(SubmoduleArgs),
+    /// Probe remotes through a pooled Tor transport and report connection statistics
+    Stats(StatsArgs),
     /// Start an onion service for hosting repositories
     Serve(ServeArgs),
     /// IPFS related commands
@@ -61,6 +131,37 @@ struct CloneArgs {
     /// Use Tor for anonymous cloning
     #[arg(short, long)]
     anonymous: bool,
+    /// Connect directly for this operation, even if Tor is enabled
+    #[arg(long, conflicts_with = "tor")]
+    no_tor: bool,
+    /// Force Tor for this operation, using exit relays for clearnet URLs
+    #[arg(long)]
+    tor: bool,
+    /// Only use Tor exits in this country (repeatable; ISO code like "de")
+    #[arg(long = "exit-country", value_name = "CC")]
+    exit_countries: Vec<String>,
+    /// Never use Tor exits in this country (repeatable; ISO code like "us")
+    #[arg(long = "exclude-exit-country", value_name = "CC")]
+    exclude_exit_countries: Vec<String>,
+    /// Also clone and check out all submodules, recursively
+    #[arg(long)]
+    recurse_submodules: bool,
+    /// Borrow objects from this local repository instead of fetching them
+    #[arg(long, value_name = "PATH")]
+    reference: Option<PathBuf>,
+    /// Copy the borrowed objects after cloning and stop using the reference
+    #[arg(long, requires = "reference")]
+    dissociate: bool,
+    /// Create a shallow clone with this many commits from each remote tip
+    #[arg(long, value_name = "N", conflicts_with = "reference")]
+    depth: Option<std::num::NonZeroU32>,
+    /// Create a bare repository, with the remote's branches as local branches
+    #[arg(long, conflicts_with_all = ["reference", "depth", "recurse_submodules"])]
+    bare: bool,
+    /// Keep a checkpoint of the received pack next to the destination, and
+    /// continue from it when an interrupted clone is run again
+    #[arg(long)]
+    resume: bool,
 }
 
 #[derive(Args)]
@@ -74,6 +175,27 @@ struct PullArgs {
     /// Use Tor for anonymous pulling
     #[arg(short, long)]
     anonymous: bool,
+    /// Remove remote-tracking refs that no longer exist on the remote
+    #[arg(short, long)]
+    prune: bool,
+    /// Fetch all tags, not only those pointing into the fetched history
+    #[arg(long)]
+    tags: bool,
+    /// Negotiate and estimate the download, then stop before fetching or merging
+    #[arg(long, conflicts_with = "prune")]
+    dry_run: bool,
+    /// Connect directly for this operation, even if Tor is enabled
+    #[arg(long, conflicts_with = "tor")]
+    no_tor: bool,
+    /// Force Tor for this operation, using exit relays for clearnet URLs
+    #[arg(long)]
+    tor: bool,
+    /// Only use Tor exits in this country (repeatable; ISO code like "de")
+    #[arg(long = "exit-country", value_name = "CC")]
+    exit_countries: Vec<String>,
+    /// Never use Tor exits in this country (repeatable; ISO code like "us")
+    #[arg(long = "exclude-exit-country", value_name = "CC")]
+    exclude_exit_countries: Vec<String>,
 }
 
 #[derive(Args)]
@@ -87,6 +209,18 @@ struct PushArgs {
     /// Use Tor for anonymous pushing
     #[arg(short, long)]
     anonymous: bool,
+    /// Connect directly for this operation, even if Tor is enabled
+    #[arg(long, conflicts_with = "tor")]
+    no_tor: bool,
+    /// Force Tor for this operation, using exit relays for clearnet URLs
+    #[arg(long)]
+    tor: bool,
+    /// Only use Tor exits in this country (repeatable; ISO code like "de")
+    #[arg(long = "exit-country", value_name = "CC")]
+    exit_countries: Vec<String>,
+    /// Never use Tor exits in this country (repeatable; ISO code like "us")
+    #[arg(long = "exclude-exit-country", value_name = "CC")]
+    exclude_exit_countries: Vec<String>,
 }
 
 #[derive(Args)]
@@ -122,6 +256,43 @@ struct AddArgs {
     /// Add all changes
     #[arg(short = 'A', long)]
     all: bool,
+    /// Interactively choose hunks to stage
+    #[arg(short, long, conflicts_with = "all")]
+    patch: bool,
+}
+
+#[derive(Args)]
+struct MvArgs {
+    /// File or directory to move, relative to the top of the work tree
+    source: PathBuf,
+    /// New path, or an existing directory to move it into, relative to the
+    /// top of the work tree
+    destination: PathBuf,
+    /// Overwrite an existing destination file with a source file
+    #[arg(short, long)]
+    force: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct RmArgs {
+    /// Files to remove, relative to the top of the work tree
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Only remove from the index, keeping the files
+    #[arg(long)]
+    cached: bool,
+    /// Remove files even if they have staged or local changes
+    #[arg(short, long)]
+    force: bool,
+    /// Remove directories and everything tracked in them
+    #[arg(short)]
+    recursive: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
 }
 
 #[derive(Args)]
@@ -132,9 +303,386 @@ struct CommitArgs {
     /// Commit message
     #[arg(short, long)]
     message: String,
-    /// Sign commit with Ed25519 key
+    /// Sign commit with the Ed25519 key of `git.identity_key`
     #[arg(short, long)]
     sign: bool,
+    /// Sign with a fresh one-time key instead of the long-term one
+    ///
+    /// The key is recorded in the signature, so the commit can be checked
+    /// against it, but that shows only that it wasn't altered after
+    /// signing, not who signed it.
+    #[arg(long, requires = "sign")]
+    ephemeral_key: bool,
+    /// Create the commit even if nothing changed since HEAD
+    #[arg(long)]
+    allow_empty: bool,
+}
+
+#[derive(Args)]
+struct ShowArgs {
+    /// Revision to show
+    #[arg(default_value = "HEAD")]
+    rev: String,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct ResetArgs {
+    /// Commit to move to, e.g. HEAD~1 or HEAD@{1}
+    #[arg(default_value = "HEAD")]
+    rev: String,
+    /// Only move HEAD, keeping the index and worktree
+    #[arg(long, conflicts_with_all = ["mixed", "hard"])]
+    soft: bool,
+    /// Also reset the index (the default)
+    #[arg(long, conflicts_with = "hard")]
+    mixed: bool,
+    /// Also reset the index and worktree, discarding changes to tracked files
+    #[arg(long)]
+    hard: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct ReflogArgs {
+    /// Ref whose history to show
+    #[arg(default_value = "HEAD")]
+    reference: String,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Show changes staged in the index relative to HEAD
+    #[arg(long, visible_alias = "staged")]
+    cached: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct ApplyArgs {
+    /// Patch file, as written by `diff -u` or `git diff`
+    patch: PathBuf,
+    /// Only check whether the patch applies, without changing anything
+    #[arg(long)]
+    check: bool,
+    /// Apply the patch to the index as well as the working tree
+    #[arg(long)]
+    index: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct AmArgs {
+    /// Mailbox file, as written by `git format-patch --stdout`
+    mbox: PathBuf,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct CountObjectsArgs {
+    /// Report packs, garbage and IPFS storage in detail
+    #[arg(short, long)]
+    verbose: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct UploadPackArgs {
+    /// Repository to serve
+    repo: PathBuf,
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("depth_change")
+    .args(["depth", "deepen", "shallow_since", "unshallow"])))]
+struct FetchPackArgs {
+    /// URL of the remote repository
+    url: String,
+    /// Refs to fetch, by full name or relative to refs/heads/ or refs/tags/
+    refs: Vec<String>,
+    /// Fetch every advertised ref
+    #[arg(long)]
+    all: bool,
+    /// Repository to store the pack in
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+    /// Negotiate and estimate the download, then stop before fetching
+    #[arg(long)]
+    dry_run: bool,
+    /// Limit history to this many commits from each remote tip
+    #[arg(long, value_name = "N")]
+    depth: Option<u32>,
+    /// Fetch this many more commits below the current shallow boundary
+    #[arg(long, value_name = "N")]
+    deepen: Option<u32>,
+    /// Keep commits newer than this date
+    #[arg(long, value_name = "DATE")]
+    shallow_since: Option<String>,
+    /// Fetch the complete history of a shallow repository
+    #[arg(long)]
+    unshallow: bool,
+}
+
+#[derive(Args)]
+struct LsRemoteArgs {
+    /// URL of the remote repository
+    url: String,
+    /// Only show branches (refs/heads/)
+    #[arg(long)]
+    heads: bool,
+    /// Only show tags (refs/tags/)
+    #[arg(long)]
+    tags: bool,
+    /// Also show the ref each symbolic ref, such as HEAD, points to
+    #[arg(long)]
+    symref: bool,
+}
+
+#[derive(Args)]
+struct ReplaceArgs {
+    /// The object to replace and its replacement, or the objects to delete
+    objects: Vec<String>,
+    /// List replacement refs
+    #[arg(short = 'l', long, conflicts_with = "delete")]
+    list: bool,
+    /// Delete the replacement refs of the given objects
+    #[arg(short = 'd', long)]
+    delete: bool,
+    /// Overwrite an existing replacement, even with an object of another type
+    #[arg(short = 'f', long)]
+    force: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifyPackArgs {
+    /// Pack files to verify; each needs its .idx alongside
+    #[arg(required = true)]
+    packs: Vec<PathBuf>,
+    /// List every object with its type, size and delta chain
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Args)]
+struct CommitGraphArgs {
+    /// Commit-graph subcommand
+    #[command(subcommand)]
+    command: CommitGraphCommands,
+}
+
+#[derive(Subcommand)]
+enum CommitGraphCommands {
+    /// Write a commit-graph covering all commits reachable from refs
+    Write {
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// Check that the commit-graph can be read and report its size
+    Verify {
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("depth_change")
+    .args(["depth", "deepen", "shallow_since", "unshallow"])))]
+struct FetchArgs {
+    /// Remote name
+    #[arg(short, long, default_value = "origin", conflicts_with = "all")]
+    remote: String,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+    /// Fetch every configured remote
+    #[arg(long, conflicts_with = "depth_change")]
+    all: bool,
+    /// Remove remote-tracking refs that no longer exist on the remote
+    #[arg(short, long, conflicts_with = "depth_change")]
+    prune: bool,
+    /// Fetch all tags, not only those pointing into the fetched history
+    #[arg(long, conflicts_with = "depth_change")]
+    tags: bool,
+    /// Use Tor for anonymous fetching
+    #[arg(short, long)]
+    anonymous: bool,
+    /// Limit history to this many commits from each remote tip
+    #[arg(long, value_name = "N")]
+    depth: Option<u32>,
+    /// Fetch this many more commits below the current shallow boundary
+    #[arg(long, value_name = "N")]
+    deepen: Option<u32>,
+    /// Keep commits newer than this date
+    #[arg(long, value_name = "DATE")]
+    shallow_since: Option<String>,
+    /// Fetch the complete history of a shallow repository
+    #[arg(long)]
+    unshallow: bool,
+    /// Negotiate and estimate the download, then stop before fetching
+    #[arg(long, conflicts_with_all = ["depth_change", "prune"])]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct MirrorArgs {
+    /// Mirror this repository instead of the configured mirrors
+    #[arg(short = 'C', long)]
+    path: Option<PathBuf>,
+    /// With -C, remote to fetch; repeatable
+    #[arg(short, long = "remote", default_value = "origin")]
+    remotes: Vec<String>,
+    /// Seconds between fetches of each remote
+    #[arg(long, value_name = "SECS")]
+    interval: Option<u64>,
+    /// Keep fetching on a schedule until interrupted
+    #[arg(long)]
+    watch: bool,
+}
+
+#[derive(Args)]
+struct PruneArgs {
+    /// Remote name
+    #[arg(default_value = "origin")]
+    remote: String,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+    /// Only report the refs that would be pruned
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct RemoteArgs {
+    /// Remote subcommand
+    #[command(subcommand)]
+    command: RemoteCommands,
+}
+
+#[derive(Subcommand)]
+enum RemoteCommands {
+    /// Add a remote, checking onion addresses before it is written
+    Add {
+        /// Remote name
+        name: String,
+        /// Remote URL
+        url: String,
+        /// Open this remote's streams on circuits of their own
+        #[arg(long)]
+        isolate: bool,
+        /// Fingerprint the remote must present
+        #[arg(long, value_name = "HEX")]
+        fingerprint: Option<String>,
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// Remove a remote and its remote-tracking refs
+    #[command(alias = "rm")]
+    Remove {
+        /// Remote name
+        name: String,
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// List remotes with their URLs
+    List {
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// Change the URL of a remote
+    SetUrl {
+        /// Remote name
+        name: String,
+        /// New remote URL
+        url: String,
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// Show the configuration of a remote and the refs it advertises
+    Show {
+        /// Remote name
+        name: String,
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    /// Show the value of a `tor.<url>.*` setting that applies to a URL,
+    /// taken from the most specific matching section
+    #[arg(long = "get-urlmatch", num_args = 2, value_names = ["NAME", "URL"], required = true)]
+    get_urlmatch: Vec<String>,
+    /// Repository path (outside a repository only global config is read)
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    /// Remotes to probe over Tor
+    #[arg(required_unless_present = "all", conflicts_with = "all")]
+    remotes: Vec<String>,
+    /// Probe every configured remote
+    #[arg(long)]
+    all: bool,
+    /// How many times to list each remote's refs
+    #[arg(long, default_value_t = 2)]
+    rounds: usize,
+    /// Show pool occupancy and reuse rate per host
+    #[arg(long)]
+    per_host: bool,
+    /// Repository path
+    #[arg(short = 'C', long, default_value = ".")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct SubmoduleArgs {
+    /// Submodule subcommand
+    #[command(subcommand)]
+    command: SubmoduleCommands,
+}
+
+#[derive(Subcommand)]
+enum SubmoduleCommands {
+    /// Check out registered submodules at the commits recorded in the index
+    Update {
+        /// Register submodules from .gitmodules that are not initialized yet
+        #[arg(long)]
+        init: bool,
+        /// Check out the tip of each submodule's remote branch instead
+        #[arg(long)]
+        remote: bool,
+        /// Repository path
+        #[arg(short = 'C', long, default_value = ".")]
+        path: PathBuf,
+    },
 }
 
 #[derive(Args)]
@@ -145,6 +693,27 @@ struct ServeArgs {
     /// Port for the onion service
     #[arg(short, long, default_value = "9418")]
     port: u16,
+    /// Serve repositories without checking their packs and HEAD first
+    #[arg(long)]
+    skip_integrity_check: bool,
+    /// Also serve directly on this address for LAN clients, without Tor
+    #[arg(long, value_name = "ADDR")]
+    lan_bind: Option<std::net::SocketAddr>,
+    /// Keep running as a daemon, restarting the service whenever it fails
+    #[arg(long)]
+    supervise: bool,
+    /// With --supervise, restarts in a row before giving up (0 for no limit)
+    #[arg(long, value_name = "N")]
+    max_restarts: Option<u32>,
+    /// With --supervise, seconds to wait before the first restart
+    #[arg(long, value_name = "SECS")]
+    restart_backoff: Option<u64>,
+    /// Only let the client with this key connect (descriptor:x25519:<base32>); repeatable
+    #[arg(long = "authorized-client", value_name = "KEY")]
+    authorized_clients: Vec<String>,
+    /// Accept pushes signed by untrusted or one-time keys, trust-on-use
+    #[arg(long)]
+    accept_ephemeral_keys: bool,
 }
 
 #[derive(Args)]
@@ -187,32 +756,253 @@ enum IpfsCommands {
         /// Object ID
         object_id: String,
     },
+    /// Merge chunks that store identical content under different CIDs
+    Dedup {
+        /// Only report what would be reclaimed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Compact the local object cache
+    Gc {
+        /// Also move objects cached as individual files into packed cache files
+        #[arg(long)]
+        aggressive: bool,
+    },
+}
+
+/// Print what a fetch would download, for `--dry-run`
+///
+/// The pack size is estimated from the local repository's average packed
+/// object where it has one.
+fn print_fetch_estimate(repo: &gix::Repository, estimate: &transport::FetchEstimate) {
+    println!("Remote has {} of {} offered local commits", estimate.common.len(), estimate.offered);
+    let local_object_size = core::count_objects(repo).ok().and_then(|counts| counts.average_packed_size());
+    match (estimate.objects, estimate.approximate_pack_bytes(local_object_size)) {
+        (Some(objects), Some(bytes)) => println!("Would receive {} objects, about {:.2} MiB",
+            objects, bytes as f64 / (1024.0 * 1024.0)),
+        (Some(objects), None) => println!("Would receive {} objects; download size unknown", objects),
+        (None, _) => println!("Remote sent no pack; download size unknown"),
+    }
+}
+
+/// Run a `--dry-run` fetch of `remote` and report it
+async fn estimate_remote_fetch(client: &ArtiGitClient, repo: &gix::Repository, remote: &str, all_tags: bool) -> Result<()> {
+    let estimate = client.estimate_fetch(repo, remote, all_tags).await?;
+    if estimate.is_up_to_date() {
+        println!("{}: already up to date", remote);
+    } else {
+        println!("{}: would fetch {} ref tips", remote, estimate.wants.len());
+        print_fetch_estimate(repo, &estimate);
+    }
+    Ok(())
+}
+
+/// Print the refs a fetch changed the way `git fetch` does
+fn print_fetch_outcome(remote: &str, outcome: &core::PullOutcome) {
+    let short = |id: &gix::ObjectId| id.to_hex_with_len(7).to_string();
+    let branch_prefix = format!("refs/remotes/{}/", remote);
+    let tracking = |name: &str| name.strip_prefix("refs/remotes/").unwrap_or(name).to_string();
+    
+    for update in &outcome.updates {
+        let (source, new_kind) = match update.name.strip_prefix(&branch_prefix) {
+            Some(branch) => (branch, "[new branch]"),
+            None => (update.name.as_str(), "[new ref]"),
+        };
+        let destination = tracking(&update.name);
+        match update.old {
+            Some(old) if update.forced => println!(" + {:<17} {} -> {}  (forced update)",
+                format!("{}...{}", short(&old), short(&update.new)), source, destination),
+            Some(old) => println!("   {:<17} {} -> {}",
+                format!("{}..{}", short(&old), short(&update.new)), source, destination),
+            None => println!(" * {:<17} {} -> {}", new_kind, source, destination),
+        }
+    }
+    for tag in &outcome.new_tags {
+        let tag = tag.trim_start_matches("refs/tags/");
+        println!(" * {:<17} {} -> {}", "[new tag]", tag, tag);
+    }
+    for name in &outcome.pruned {
+        println!(" - {:<17} {} -> {}", "[deleted]", "(none)", tracking(name));
+    }
+}
+
+/// The depth change asked for by `--depth`, `--deepen`, `--shallow-since` or `--unshallow`
+fn requested_depth(
+    depth: Option<u32>,
+    deepen: Option<u32>,
+    shallow_since: Option<&str>,
+    unshallow: bool,
+) -> std::result::Result<Option<core::FetchDepth>, String> {
+    Ok(if let Some(depth) = depth {
+        Some(core::FetchDepth::Depth(depth))
+    } else if let Some(deepen) = deepen {
+        Some(core::FetchDepth::Deepen(deepen))
+    } else if let Some(since) = shallow_since {
+        let time = gix::date::parse(since, Some(std::time::SystemTime::now()))
+            .map_err(|e| format!("Invalid --shallow-since date '{}': {}", since, e))?;
+        Some(core::FetchDepth::Since(time.seconds as i64))
+    } else if unshallow {
+        Some(core::FetchDepth::Unshallow)
+    } else {
+        None
+    })
+}
+
+/// Print a shallow boundary after a fetch changed it
+fn print_shallow_boundary(outcome: &core::ShallowFetchOutcome) {
+    if outcome.is_complete() {
+        println!("Repository is no longer shallow");
+    } else {
+        println!("Shallow boundary ({} commits, was {}):", outcome.boundary.len(), outcome.previous.len());
+        for id in &outcome.boundary {
+            println!("{}", id);
+        }
+    }
+}
+
+/// Shut `client` down, then exit with `code`
+///
+/// Every exit once the client exists goes through here, so storage is
+/// flushed and Tor connections are closed whether the command succeeded or
+/// not. A failed shutdown turns a successful exit into a failing one.
+fn exit_after_shutdown(client: ArtiGitClient, code: i32) -> ! {
+    let shutdown = tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(client.shutdown()));
+    if let Err(e) = shutdown {
+        eprintln!("Failed to shut down cleanly: {}", e);
+        process::exit(code.max(1));
+    }
+    process::exit(code)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse the command line arguments
     let cli = Cli::parse();
-
-    // --- Initialize Transports ---
-    // Register custom transports (like Tor) with gitoxide
-    if let Err(e) = transport::register_transports().await {
-        eprintln!("Failed to register custom transports: {}", e);
-        // Decide if this is a fatal error
-        // process::exit(1);
+    
+    // Same mechanism as git: the environment variable also reaches gitoxide
+    if cli.no_replace_objects {
+        env::set_var(crate::core::NO_REPLACE_OBJECTS_ENV, "1");
+    }
+    
+    // `-c FILE` is the old spelling of --config-file; everything else is an override
+    let mut config_file = cli.config_file.clone();
+    let mut overrides = Vec::new();
+    for spec in &cli.config_overrides {
+        if spec.contains('=') {
+            overrides.push(spec);
+        } else if config_file.is_none() {
+            config_file = Some(PathBuf::from(spec));
+        } else {
+            eprintln!("Only one config file can be given, got '{}' as well", spec);
+            process::exit(1);
+        }
     }
-    // --- End Initialize Transports ---
     
     // Load config
-    let config_path = cli.config
+    let config_path = config_file
         .unwrap_or_else(|| ArtiGitConfig::default_location());
     
-    let config = if config_path.exists() {
+    let mut config = if config_path.exists() {
         ArtiGitConfig::from_file(&config_path)?
     } else {
         ArtiGitConfig::default()
     };
     
+    // Inline overrides win over the file, in the order given
+    for spec in overrides {
+        if let Err(e) = config.apply_override(spec) {
+            eprintln!("Invalid --config {}: {}", spec, e);
+            process::exit(1);
+        }
+    }
+    
+    if cli.connection_log.is_some() {
+        config.tor.connection_log = cli.connection_log.clone();
+    }
+    if let Some(mode) = cli.error_paths {
+        config.tor.error_paths = mode;
+    }
+    core::set_path_redaction(config.tor.error_paths);
+    if cli.accept_new_fingerprint {
+        config.tor.accept_new_fingerprint = true;
+    }
+    
+    // upload-pack speaks the protocol on stdout, so it has to run before
+    // anything below prints to it
+    if let Commands::UploadPack(args) = &cli.command {
+        let repo = match gix::open(&args.repo) {
+            Ok(repo) => repo,
+            Err(e) => {
+                eprintln!("Failed to open repository: {}", e);
+                process::exit(1);
+            }
+        };
+        let command = protocol::GitCommand::new("git-upload-pack".to_string(), args.repo.clone());
+        let mut stdio = transport::StdioStream::new();
+        let limits = protocol::PackLimits::unlimited();
+        // Like git's upload-pack, the client asks for protocol v2 through GIT_PROTOCOL
+        let v2 = env::var("GIT_PROTOCOL").map_or(false, |value| value.split(':').any(|param| param == "version=2"));
+        let served = if v2 {
+            protocol::serve_v2(&mut stdio, &repo, &limits).await
+        } else {
+            protocol::handle_upload_pack(&mut stdio, &repo, &command, &limits).await
+        };
+        if let Err(e) = served {
+            eprintln!("upload-pack failed: {}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    
+    // Per-command routing override (--tor / --no-tor); --anonymous or the
+    // `route_clearnet` setting send clearnet URLs through Tor exit relays
+    let routing = match &cli.command {
+        Commands::Clone(args) => RoutingMode::from_flags(args.tor, args.no_tor)
+            .with_clearnet_over_tor(args.anonymous || config.tor.route_clearnet),
+        Commands::Pull(args) => RoutingMode::from_flags(args.tor, args.no_tor)
+            .with_clearnet_over_tor(args.anonymous || config.tor.route_clearnet),
+        Commands::Fetch(args) => RoutingMode::Auto
+            .with_clearnet_over_tor(args.anonymous || config.tor.route_clearnet),
+        Commands::Push(args) => RoutingMode::from_flags(args.tor, args.no_tor)
+            .with_clearnet_over_tor(args.anonymous || config.tor.route_clearnet),
+        _ => RoutingMode::Auto,
+    };
+    
+    // Per-command exit preferences extend the configured ones
+    let (exit_countries, exclude_exit_countries) = match &cli.command {
+        Commands::Clone(args) => (args.exit_countries.clone(), args.exclude_exit_countries.clone()),
+        Commands::Pull(args) => (args.exit_countries.clone(), args.exclude_exit_countries.clone()),
+        Commands::Push(args) => (args.exit_countries.clone(), args.exclude_exit_countries.clone()),
+        _ => (Vec::new(), Vec::new()),
+    };
+    config.tor.exit.exit_countries.extend(exit_countries);
+    config.tor.exit.exclude_countries.extend(exclude_exit_countries);
+    
+    // Refuse to run rather than ignore an exit restriction we cannot enforce
+    if let Err(e) = config.tor.exit.validate() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    
+    match routing {
+        // Skip bootstrapping Tor entirely when the operation bypasses it
+        RoutingMode::Direct => config.tor.use_tor = false,
+        RoutingMode::Tor if !config.tor.use_tor => {
+            eprintln!("Tor routing requested but Tor is not enabled in the configuration");
+            process::exit(1);
+        }
+        _ => {}
+    }
+    
+    // --- Initialize Transports ---
+    // Register custom transports (like Tor) with gitoxide
+    if let Err(e) = transport::register_transports(routing, &config.tor).await {
+        eprintln!("Failed to register custom transports: {}", e);
+        // Decide if this is a fatal error
+        // process::exit(1);
+    }
+    // --- End Initialize Transports ---
+    
     // Initialize ArtiGit client
     let client = match ArtiGitClient::new(config).await {
         Ok(client) => client,
@@ -222,27 +1012,99 @@ async fn main() -> Result<()> {
         }
     };
     
-    // Execute command
-    match cli.command {
-        Commands::Clone(args) => {
-            println!("Cloning {} to {}", args.url, args.path.display());
-            
-            // If anonymous flag is set, ensure Tor is enabled in the config
-            if args.anonymous {
+    // Explain long pauses while a freshly started onion service gets published
+    client.on_transport_event(|event: &TransportEvent| {
+        if let TransportEvent::WaitingForOnionService { target, delay, .. } = event {
+            eprintln!("Waiting for onion service {} to come online (retrying in {}s)...", target, delay.as_secs());
+        }
+    });
+    
+    // Opt-in diagnostics that are safe to share
+    if let Some(path) = &client.config().tor.connection_log {
+        match ConnectionLog::create(path) {
+            Ok(connection_log) => client.on_transport_event(connection_log),
+            Err(e) => {
+                eprintln!("Failed to open connection log: {}", e);
+                exit_after_shutdown(client, 1);
+            }
+        }
+    }
+    
+    // Ctrl-C cancels the running operation cleanly; a second Ctrl-C exits at once
+    let cancel = CancellationToken::new();
+    {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if signal::ctrl_c().await.is_ok() {
+                eprintln!("Interrupted, cancelling...");
+                cancel.cancel();
+            }
+            if signal::ctrl_c().await.is_ok() {
+                process::exit(130);
+            }
+        });
+    }
+    
+    // Execute command
+    match cli.command {
+        Commands::Clone(args) => {
+            println!("Cloning {} to {}", args.url, args.path.display());
+            
+            if args.no_tor && utils::is_onion_address(&args.url) {
+                eprintln!("Cannot clone an onion service with --no-tor");
+                exit_after_shutdown(client, 1);
+            }
+            
+            // If anonymous flag is set, ensure Tor is enabled in the config
+            if args.anonymous {
                 // Check if Tor is enabled
                 if !client.config().tor.use_tor {
                     eprintln!("Anonymous clone requested but Tor is not enabled in the configuration");
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             }
             
-            match client.clone(&args.url, &args.path).await {
-                Ok(_) => println!("Clone completed successfully"),
+            let options = core::CloneOptions {
+                bare: args.bare,
+                depth: args.depth,
+                reference: args.reference.as_ref()
+                    .map(|reference| core::CloneReference::new(reference).with_dissociate(args.dissociate)),
+                resume: args.resume,
+            };
+            let repo = match client.clone_with_options(&args.url, &args.path, &options, &cancel).await {
+                Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Clone failed: {}", e);
-                    process::exit(1);
+                    if args.resume && transport::ResumeState::location(&args.path).exists() {
+                        eprintln!("The data received so far was kept; run the same command again to continue");
+                    }
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            if args.resume {
+                if let Err(e) = transport::discard_resume_state(&args.path) {
+                    eprintln!("Warning: failed to remove the clone checkpoint: {}", e);
+                }
+            }
+            
+            if args.recurse_submodules {
+                match client.clone_submodules(&repo, &args.url, &cancel).await {
+                    Ok(submodules) => {
+                        for submodule in &submodules {
+                            println!("Submodule '{}' ({}) checked out at {}",
+                                submodule.path.display(), submodule.url, submodule.commit.to_hex_with_len(7));
+                        }
+                        if submodules.is_empty() {
+                            println!("No submodules to initialize");
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Failed to clone submodules: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
                 }
             }
+            println!("Clone completed successfully");
         },
         Commands::Pull(args) => {
             println!("Pulling from remote {} in {}", args.remote, args.path.display());
@@ -252,18 +1114,170 @@ async fn main() -> Result<()> {
                 Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Failed to open repository: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
-            match client.pull(&mut repo).await {
-                Ok(_) => println!("Pull completed successfully"),
+            if args.dry_run {
+                if let Err(e) = estimate_remote_fetch(&client, &repo, &args.remote, args.tags).await {
+                    eprintln!("Failed to estimate the pull: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+                exit_after_shutdown(client, 0);
+            }
+            
+            match client.pull_with_tags(&mut repo, args.tags, &cancel).await {
+                Ok(outcome) => {
+                    for update in &outcome.updates {
+                        let short = |id: &gix::ObjectId| id.to_hex_with_len(7).to_string();
+                        match (update.old, update.forced) {
+                            (Some(old), true) => println!(" + {}...{} {} (forced update)",
+                                short(&old), short(&update.new), update.name),
+                            (Some(old), false) => println!("   {}..{} {}",
+                                short(&old), short(&update.new), update.name),
+                            (None, _) => println!(" * [new ref] {}", update.name),
+                        }
+                    }
+                    for tag in &outcome.new_tags {
+                        println!(" * [new tag] {}", tag.trim_start_matches("refs/tags/"));
+                    }
+                    if !outcome.new_tags.is_empty() {
+                        println!("Fetched {} new tags", outcome.new_tags.len());
+                    }
+                    match outcome.merge {
+                        Some(core::HeadMerge::UpToDate) => println!("Already up to date."),
+                        Some(core::HeadMerge::FastForward(id)) => println!("Fast-forward to {}", id.to_hex_with_len(7)),
+                        Some(core::HeadMerge::Merged(id)) => println!("Merge made, commit {}", id.to_hex_with_len(7)),
+                        None => {},
+                    }
+                    println!("Pull completed successfully");
+                },
                 Err(e) => {
                     eprintln!("Pull failed: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+            
+            if args.prune {
+                match client.prune(&repo, &args.remote, false).await {
+                    Ok(pruned) => {
+                        for name in pruned {
+                            println!(" - [pruned] {}", name);
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Prune failed: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            }
+        },
+        Commands::Fetch(args) => {
+            let mut repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            if args.anonymous && !client.config().tor.use_tor {
+                eprintln!("Anonymous fetch requested but Tor is not enabled in the configuration");
+                exit_after_shutdown(client, 1);
+            }
+            
+            let depth = match requested_depth(args.depth, args.deepen, args.shallow_since.as_deref(), args.unshallow) {
+                Ok(depth) => depth,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit_after_shutdown(client, 2);
+                }
+            };
+            let depth = match depth {
+                Some(depth) => depth,
+                None => {
+                    let remotes: Vec<String> = if args.all {
+                        repo.remote_names().iter().map(|name| name.to_string()).collect()
+                    } else {
+                        vec![args.remote.clone()]
+                    };
+                    if args.dry_run {
+                        for remote in remotes {
+                            if let Err(e) = estimate_remote_fetch(&client, &repo, &remote, args.tags).await {
+                                eprintln!("Failed to estimate the fetch from {}: {}", remote, e);
+                                exit_after_shutdown(client, 1);
+                            }
+                        }
+                        exit_after_shutdown(client, 0);
+                    }
+                    for remote in remotes {
+                        match client.fetch(&mut repo, &remote, args.prune, args.tags, &cancel).await {
+                            Ok(outcome) if outcome.is_up_to_date() => {},
+                            Ok(outcome) => {
+                                let url = client.remote_info(&repo, &remote).ok().and_then(|info| info.fetch_url);
+                                println!("From {}", url.as_deref().unwrap_or(&remote));
+                                print_fetch_outcome(&remote, &outcome);
+                            },
+                            Err(e) => {
+                                eprintln!("Fetch from {} failed: {}", remote, e);
+                                exit_after_shutdown(client, 1);
+                            }
+                        }
+                    }
+                    exit_after_shutdown(client, 0);
+                },
+            };
+            
+            match client.fetch_depth(&repo, &args.remote, depth, &cancel).await {
+                Ok(outcome) => print_shallow_boundary(&outcome),
+                Err(e) => {
+                    eprintln!("Fetch failed: {}", e);
+                    exit_after_shutdown(client, 1);
                 }
             }
         },
+        Commands::Mirror(args) => {
+            let mut mirror_config = client.config().mirror.clone();
+            if let Some(path) = &args.path {
+                mirror_config.remotes = args.remotes.iter()
+                    .map(|remote| core::MirrorRemoteConfig {
+                        path: path.clone(),
+                        remote: remote.clone(),
+                        interval: args.interval,
+                    })
+                    .collect();
+            } else if let Some(interval) = args.interval {
+                mirror_config.interval = interval;
+            }
+            
+            let scheduler = core::MirrorScheduler::from_config(&mirror_config);
+            if scheduler.targets().is_empty() {
+                eprintln!("No mirrors configured; pass -C <repository> or add mirror.remotes to the configuration");
+                exit_after_shutdown(client, 2);
+            }
+            
+            if args.watch {
+                println!("Watching {} mirrored remotes (Ctrl-C to stop)", scheduler.targets().len());
+                scheduler.run(&client, &cancel).await;
+                exit_after_shutdown(client, 0);
+            }
+            
+            let mut failed = false;
+            let results = scheduler.fetch_all(&client, &cancel).await;
+            for (target, result) in scheduler.targets().iter().zip(results) {
+                match result {
+                    Ok(outcome) => println!("{} ({}): {} refs updated, {} new tags",
+                        target.path.display(), target.remote, outcome.updates.len(), outcome.new_tags.len()),
+                    Err(e) => {
+                        eprintln!("{} ({}): {}", target.path.display(), target.remote, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                exit_after_shutdown(client, 1);
+            }
+        },
         Commands::Push(args) => {
             println!("Pushing to remote {} from {}", args.remote, args.path.display());
             
@@ -272,15 +1286,15 @@ async fn main() -> Result<()> {
                 Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Failed to open repository: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
-            match client.push(&repo, Some(&args.remote), None).await {
+            match client.push(&repo, Some(&args.remote), None, &cancel).await {
                 Ok(_) => println!("Push completed successfully"),
                 Err(e) => {
                     eprintln!("Push failed: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             }
         },
@@ -302,7 +1316,7 @@ async fn main() -> Result<()> {
                 Ok(_) => println!("Repository initialized successfully"),
                 Err(e) => {
                     eprintln!("Initialization failed: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             }
         },
@@ -314,7 +1328,7 @@ async fn main() -> Result<()> {
                 Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Failed to open repository: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
@@ -323,7 +1337,7 @@ async fn main() -> Result<()> {
                 Ok(statuses) => statuses,
                 Err(e) => {
                     eprintln!("Failed to get repository status: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
@@ -347,17 +1361,27 @@ async fn main() -> Result<()> {
                 Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Failed to open repository: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
-            if args.all {
+            if args.patch {
+                let stdin = std::io::stdin();
+                match core::add_patch(&repo, stdin.lock(), std::io::stdout()) {
+                    Ok(0) => println!("No hunks staged"),
+                    Ok(count) => println!("Staged {} hunk(s)", count),
+                    Err(e) => {
+                        eprintln!("Failed to stage hunks: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            } else if args.all {
                 // Add all changes
                 let workdir = match repo.work_dir() {
                     Ok(dir) => dir,
                     Err(e) => {
                         eprintln!("Failed to get work directory: {}", e);
-                        process::exit(1);
+                        exit_after_shutdown(client, 1);
                     }
                 };
                 
@@ -365,7 +1389,7 @@ async fn main() -> Result<()> {
                     Ok(_) => println!("Added all changes to index"),
                     Err(e) => {
                         eprintln!("Failed to add changes: {}", e);
-                        process::exit(1);
+                        exit_after_shutdown(client, 1);
                     }
                 }
             } else if !args.files.is_empty() {
@@ -374,12 +1398,12 @@ async fn main() -> Result<()> {
                     Ok(_) => println!("Added files to index"),
                     Err(e) => {
                         eprintln!("Failed to add files: {}", e);
-                        process::exit(1);
+                        exit_after_shutdown(client, 1);
                     }
                 }
             } else {
                 eprintln!("No files specified");
-                process::exit(1);
+                exit_after_shutdown(client, 1);
             }
         },
         Commands::Commit(args) => {
@@ -390,16 +1414,1027 @@ async fn main() -> Result<()> {
                 Ok(repo) => repo,
                 Err(e) => {
                     eprintln!("Failed to open repository: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
             // Commit changes
-            match client.commit(&repo, &args.message, args.sign).await {
+            let signing = if args.ephemeral_key {
+                Some(crypto::SigningMode::Ephemeral)
+            } else if args.sign {
+                match client.signing_key() {
+                    Ok(key) => Some(crypto::SigningMode::Persistent(key)),
+                    Err(e) => {
+                        eprintln!("Failed to commit: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            } else {
+                None
+            };
+            match client.commit(&repo, &args.message, signing, args.allow_empty).await {
                 Ok(commit_id) => println!("Created commit: {}", commit_id),
+                Err(GitError::NothingToCommit(msg)) => {
+                    println!("nothing to commit, working tree clean ({})", msg);
+                    exit_after_shutdown(client, 1);
+                },
                 Err(e) => {
                     eprintln!("Failed to commit: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Show(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match core::show(&repo, &args.rev) {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    eprintln!("Failed to show {}: {}", args.rev, e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Reset(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let mode = if args.soft {
+                core::ResetMode::Soft
+            } else if args.hard {
+                core::ResetMode::Hard
+            } else {
+                core::ResetMode::Mixed
+            };
+            match core::reset(&repo, &args.rev, mode) {
+                Ok(id) => {
+                    if mode == core::ResetMode::Hard {
+                        println!("HEAD is now at {}", &id.to_string()[..7]);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Reflog(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match core::read_reflog(&repo, &args.reference) {
+                Ok(entries) => {
+                    for (index, entry) in entries.iter().enumerate() {
+                        println!("{}..{} {}@{{{}}}: {}",
+                            &entry.old.to_string()[..7], &entry.new.to_string()[..7],
+                            args.reference, index, entry.message);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Diff(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let diff = if args.cached {
+                core::diff_cached(&repo)
+            } else {
+                core::diff_worktree(&repo)
+            };
+            match diff {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    eprintln!("Failed to diff: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Mv(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match core::move_path(&repo, &args.source, &args.destination, args.force) {
+                Ok(renames) => {
+                    for (from, to) in renames {
+                        println!("Renamed {} -> {}", from, to);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Rm(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match core::remove_paths(&repo, &args.files, args.cached, args.force, args.recursive) {
+                Ok(removed) => {
+                    for path in removed {
+                        println!("rm '{}'", path);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Apply(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            let patch = match std::fs::read_to_string(&args.patch) {
+                Ok(patch) => patch,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", args.patch.display(), e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match core::apply_patch(&repo, &patch, args.index, args.check) {
+                Ok(files) => {
+                    for file in files {
+                        let action = match file.action {
+                            core::PatchAction::Create => "create",
+                            core::PatchAction::Modify => "modify",
+                            core::PatchAction::Delete => "delete",
+                        };
+                        let verb = if args.check { "Would" } else { "Applied:" };
+                        println!("{} {} {} (+{} -{})", verb, action, file.path, file.added, file.removed);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Am(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            let mbox = match std::fs::read_to_string(&args.mbox) {
+                Ok(mbox) => mbox,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", args.mbox.display(), e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            let patches = match core::parse_mbox(&mbox) {
+                Ok(patches) if !patches.is_empty() => patches,
+                Ok(_) => {
+                    eprintln!("No patches found in {}", args.mbox.display());
+                    exit_after_shutdown(client, 1);
+                },
+                Err(e) => {
+                    eprintln!("Failed to parse {}: {}", args.mbox.display(), e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            for (number, mail) in patches.iter().enumerate() {
+                println!("Applying: {}", mail.subject);
+                if let Err(e) = client.apply_mail(&repo, mail) {
+                    eprintln!("error: {}", e);
+                    eprintln!("Patch failed at {:04} {}", number + 1, mail.subject);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::UploadPack(_) => unreachable!("upload-pack is served before the client starts"),
+        Commands::FetchPack(args) => {
+            use crate::transport::{AsyncRemoteConnection, PooledTorTransport, TorConnection, extract_pack_data};
+            
+            if args.refs.is_empty() && !args.all {
+                eprintln!("No refs to fetch; name some or pass --all");
+                exit_after_shutdown(client, 1);
+            }
+            let depth = match requested_depth(args.depth, args.deepen, args.shallow_since.as_deref(), args.unshallow) {
+                Ok(depth) => depth,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit_after_shutdown(client, 2);
+                }
+            };
+            
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let transport = match PooledTorTransport::new(client.tor_client()).await
+                .and_then(|transport| client.track_pooled_transport(
+                    transport.with_url_config(UrlConfig::from_config(repo.config_snapshot().plumbing()))
+                        .with_remote_isolation(repo.config_snapshot().plumbing())
+                        .with_max_haves(client.config().tor.negotiation_max_haves)
+                        .with_max_negotiation_rounds(client.config().tor.negotiation_max_rounds)
+                ))
+            {
+                Ok(transport) => transport,
+                Err(e) => {
+                    eprintln!("Failed to create Tor transport: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            let mut connection = match TorConnection::with_transport(&args.url, transport)
+                .map(|connection| connection.with_http_compression(client.config().tor.http_compression))
+            {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Failed to connect to {}: {}", args.url, e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            // A depth change sends our shallow boundary along with the deepen request
+            let previous_shallow = match depth {
+                Some(depth) => match core::check_fetch_depth(repo.path(), depth).and_then(|_| core::read_shallow(repo.path())) {
+                    Ok(previous) => {
+                        connection = connection.with_shallow(previous.iter().copied().map(core::ObjectId::from).collect(), depth);
+                        Some(previous)
+                    },
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        exit_after_shutdown(client, 2);
+                    }
+                },
+                None => None,
+            };
+            let advertised = match connection.list_refs_async().await {
+                Ok(refs) => refs,
+                Err(e) => {
+                    eprintln!("Failed to list refs of {}: {}", args.url, e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            // Match refs the way git fetch-pack does: exactly, or under refs/heads/ and refs/tags/
+            let mut selected = Vec::new();
+            if args.all {
+                selected = advertised.iter().filter(|(name, _)| name != "HEAD").cloned().collect();
+            }
+            for wanted in &args.refs {
+                let candidates = [wanted.clone(), format!("refs/heads/{}", wanted), format!("refs/tags/{}", wanted)];
+                match candidates.iter().find_map(|candidate| advertised.iter().find(|(name, _)| name == candidate)) {
+                    Some(found) if !selected.contains(found) => selected.push(found.clone()),
+                    Some(_) => {},
+                    None => {
+                        eprintln!("no such remote ref {}", wanted);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            }
+            
+            // Local ref tips are our haves; only tips we don't have yet are wanted
+            let mut haves = Vec::new();
+            if let Ok(refs) = repo.references() {
+                if let Ok(all) = refs.all() {
+                    for mut reference in all.flatten() {
+                        if let Ok(id) = reference.peel_to_id_in_place() {
+                            haves.push(core::ObjectId::from(id.detach()));
+                        }
+                    }
+                }
+            }
+            // Deepening needs the tips even when we have them, as git does
+            let mut wants = Vec::new();
+            for (_, id) in &selected {
+                let present = depth.is_none() && matches!(repo.try_find_header(gix::ObjectId::from(id)), Ok(Some(_)));
+                if !present && !wants.contains(id) {
+                    wants.push(id.clone());
+                }
+            }
+            
+            if args.dry_run {
+                let estimate = match connection.estimate_fetch_async(&wants, &haves).await {
+                    Ok(estimate) => estimate,
+                    Err(e) => {
+                        eprintln!("Failed to negotiate with {}: {}", args.url, e);
+                        exit_after_shutdown(client, 1);
+                    }
+                };
+                let present = selected.len() - selected.iter().filter(|(_, id)| wants.contains(id)).count();
+                if estimate.is_up_to_date() {
+                    println!("Already up to date ({} refs present locally)", present);
+                    exit_after_shutdown(client, 0);
+                }
+                println!("Would fetch {} ref tips ({} refs already present locally)", estimate.wants.len(), present);
+                print_fetch_estimate(&repo, &estimate);
+                exit_after_shutdown(client, 0);
+            }
+            
+            if !wants.is_empty() {
+                let pack = match connection.fetch_pack_async(&wants, &haves).await.and_then(|response| extract_pack_data(&response)) {
+                    Ok(pack) => pack,
+                    Err(e) => {
+                        eprintln!("Failed to fetch pack: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                };
+                match protocol::index_pack(&repo, &pack, true) {
+                    Ok(stats) => log::info!("Stored {} objects in {}", stats.num_objects, stats.pack_path.display()),
+                    Err(e) => {
+                        eprintln!("Failed to store pack: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            }
+            
+            // Record the boundary the remote reported once the pack is stored
+            if let Some(previous) = previous_shallow {
+                let update = connection.last_negotiation()
+                    .map(|negotiation| core::ShallowUpdate {
+                        shallow: negotiation.shallow.iter().map(gix::ObjectId::from).collect(),
+                        unshallow: negotiation.unshallow.iter().map(gix::ObjectId::from).collect(),
+                    })
+                    .unwrap_or_default();
+                match update.apply(repo.path()) {
+                    Ok(boundary) => print_shallow_boundary(&core::ShallowFetchOutcome { previous, boundary }),
+                    Err(e) => {
+                        eprintln!("Failed to update the shallow file: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            }
+            
+            for (name, id) in &selected {
+                println!("{} {}", id, name);
+            }
+        },
+        Commands::LsRemote(args) => {
+            use crate::transport::{PooledTorTransport, TorConnection};
+            
+            let (over_tor, scheme) = match gix::url::parse(args.url.as_str().into()) {
+                Ok(url) => (routing.routes_over_tor(&url), url.scheme),
+                Err(e) => {
+                    eprintln!("Invalid URL {}: {}", args.url, e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            // Tor routed remotes, and git:// and http:// ones reached directly,
+            // use the same reference discovery as fetch-pack; the rest goes
+            // through gitoxide's transports
+            let connection = if over_tor {
+                let transport = match PooledTorTransport::new(client.tor_client()).await
+                    .and_then(|transport| client.track_pooled_transport(transport.with_url_config(UrlConfig::discover("."))))
+                {
+                    Ok(transport) => transport,
+                    Err(e) => {
+                        eprintln!("Failed to create Tor transport: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                };
+                Some(TorConnection::with_transport(&args.url, transport))
+            } else if matches!(scheme, gix::url::Scheme::Git | gix::url::Scheme::Http) {
+                Some(TorConnection::direct(&args.url))
+            } else {
+                None
+            };
+            let listing = match connection {
+                Some(connection) => {
+                    let mut connection = match connection
+                        .map(|connection| connection.with_http_compression(client.config().tor.http_compression))
+                    {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            eprintln!("Failed to connect to {}: {}", args.url, e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    connection.ls_refs().await.map(|refs| core::RemoteListing {
+                        refs: refs.iter().map(|(name, id)| (name.clone(), gix::ObjectId::from(id))).collect(),
+                        symrefs: connection.symrefs(),
+                    })
+                },
+                None => client.ls_remote(&args.url),
+            };
+            let listing = match listing {
+                Ok(listing) => listing,
+                Err(e) => {
+                    eprintln!("Failed to list refs of {}: {}", args.url, e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            // Like git, --heads and --tags together show both
+            let shown = |name: &str| {
+                (!args.heads && !args.tags)
+                    || (args.heads && name.starts_with("refs/heads/"))
+                    || (args.tags && name.starts_with("refs/tags/"))
+            };
+            for (name, id) in &listing.refs {
+                if !shown(name) {
+                    continue;
+                }
+                if args.symref {
+                    if let Some((_, target)) = listing.symrefs.iter().find(|(symref, _)| symref == name) {
+                        println!("ref: {}\t{}", target, name);
+                    }
+                }
+                println!("{}\t{}", id, name);
+            }
+        },
+        Commands::VerifyPack(args) => {
+            let mut failed = false;
+            for pack in &args.packs {
+                let pack_path = if pack.extension().map_or(false, |ext| ext == "idx") {
+                    pack.with_extension("pack")
+                } else {
+                    pack.clone()
+                };
+                let verification = match protocol::verify_pack(&pack_path, gix_hash::Kind::Sha1, args.verbose) {
+                    Ok(verification) => verification,
+                    Err(e) => {
+                        eprintln!("{}: bad ({})", pack_path.display(), e);
+                        failed = true;
+                        continue;
+                    }
+                };
+                
+                // Same layout as `git verify-pack -v`
+                for object in &verification.objects {
+                    let line = format!("{} {:<6} {} {} {}", object.id, object.kind, object.size, object.size_in_pack, object.offset);
+                    match object.base {
+                        Some(base) => println!("{} {} {}", line, object.depth, base),
+                        None => println!("{}", line),
+                    }
+                }
+                for (depth, count) in &verification.chain_lengths {
+                    if *depth == 0 {
+                        println!("non delta: {} objects", count);
+                    } else {
+                        println!("chain length = {}: {} objects", depth, count);
+                    }
+                }
+                println!("{}: ok ({} objects)", pack_path.display(), verification.num_objects);
+            }
+            if failed {
+                exit_after_shutdown(client, 1);
+            }
+        },
+        Commands::CountObjects(args) => {
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let counts = match core::count_objects(&repo) {
+                Ok(counts) => counts,
+                Err(e) => {
+                    eprintln!("Failed to count objects: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            // Sizes are shown in KiB, like git
+            let kib = |bytes: u64| (bytes + 1023) / 1024;
+            if !args.verbose {
+                println!("{} objects, {} kilobytes", counts.count, kib(counts.size));
+                exit_after_shutdown(client, 0);
+            }
+            
+            println!("count: {}", counts.count);
+            println!("size: {}", kib(counts.size));
+            println!("in-pack: {}", counts.in_pack);
+            println!("packs: {}", counts.packs);
+            println!("size-pack: {}", kib(counts.size_pack));
+            println!("prune-packable: {}", counts.prune_packable);
+            println!("garbage: {}", counts.garbage);
+            println!("size-garbage: {}", kib(counts.size_garbage));
+            
+            #[cfg(feature = "ipfs")]
+            if let Some(storage) = client.ipfs_storage() {
+                use crate::ipfs::IpfsObjectProvider;
+                
+                let stats = storage.get_stats();
+                println!("ipfs-objects: {}", stats.objects_stored);
+                println!("ipfs-size: {}", kib(stats.total_bytes_stored as u64));
+                println!("ipfs-dedup-savings: {}", kib(stats.dedup_savings as u64));
+                match utils::dir_size(storage.cache_dir()) {
+                    Ok(size) => println!("ipfs-cache-size: {}", kib(size)),
+                    Err(e) => eprintln!("Failed to measure IPFS cache: {}", e),
+                }
+            }
+        },
+        Commands::Prune(args) => {
+            println!("Pruning {} in {}", args.remote, args.path.display());
+            
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match client.prune(&repo, &args.remote, args.dry_run).await {
+                Ok(pruned) if pruned.is_empty() => println!("Nothing to prune"),
+                Ok(pruned) => {
+                    let marker = if args.dry_run { "would prune" } else { "pruned" };
+                    for name in pruned {
+                        println!(" - [{}] {}", marker, name);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Prune failed: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::Replace(args) => {
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let resolve = |spec: &str| match core::resolve_revision(&repo, spec) {
+                Ok(id) => id,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            if args.list || (args.objects.is_empty() && !args.delete) {
+                match core::list_replacements(&repo) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            println!("{}", entry.original);
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Failed to list replace refs: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            } else if args.delete {
+                let mut failed = false;
+                for spec in &args.objects {
+                    // The replaced object may be gone, so accept a full id as is
+                    let original = match gix_hash::ObjectId::from_hex(spec.as_bytes()) {
+                        Ok(id) => id,
+                        Err(_) => resolve(spec),
+                    };
+                    match core::delete_replacement(&repo, original) {
+                        Ok(_) => println!("Deleted replace ref '{}'", original),
+                        Err(e) => {
+                            eprintln!("error: {}", e);
+                            failed = true;
+                        }
+                    }
+                }
+                if failed {
+                    exit_after_shutdown(client, 1);
+                }
+            } else {
+                let (original, replacement) = match args.objects.as_slice() {
+                    [original, replacement] => (resolve(original), resolve(replacement)),
+                    _ => {
+                        eprintln!("Expected an object and its replacement");
+                        exit_after_shutdown(client, 1);
+                    }
+                };
+                if let Err(e) = core::add_replacement(&repo, original, replacement, args.force) {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        Commands::CommitGraph(args) => {
+            match args.command {
+                CommitGraphCommands::Write { path } => {
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    match core::write_commit_graph(&repo) {
+                        Ok(stats) => println!("Wrote commit-graph with {} commits to {}", stats.commits, stats.path.display()),
+                        Err(e) => {
+                            eprintln!("Failed to write commit-graph: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    }
+                },
+                CommitGraphCommands::Verify { path } => {
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    match core::CommitGraph::open(&repo.path().join("objects")) {
+                        Ok(Some(graph)) => println!("commit-graph is valid: {} commits", graph.len()),
+                        Ok(None) => {
+                            eprintln!("No commit-graph found; run `arti-git commit-graph write`");
+                            exit_after_shutdown(client, 1);
+                        },
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    }
+                },
+            }
+        },
+        Commands::Remote(args) => {
+            match args.command {
+                RemoteCommands::Show { name, path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    let info = match client.remote_info(&repo, &name) {
+                        Ok(info) => info,
+                        Err(e) => {
+                            eprintln!("Failed to read remote: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    println!("* remote {}", info.name);
+                    println!("  Fetch URL: {}", info.fetch_url.as_deref().unwrap_or("(none)"));
+                    println!("  Push  URL: {}", info.push_url.as_deref().unwrap_or("(none)"));
+                    for spec in &info.fetch_refspecs {
+                        println!("  Fetch refspec: {}", spec);
+                    }
+                    for spec in &info.push_refspecs {
+                        println!("  Push  refspec: {}", spec);
+                    }
+                    
+                    // Locally known state, available without contacting the remote
+                    println!("  Local tracking refs:");
+                    if info.tracking_refs.is_empty() {
+                        println!("    (none)");
+                    }
+                    for tracking in &info.tracking_refs {
+                        println!("    {}", tracking);
+                    }
+                    
+                    // Freshly fetched state; the remote may well be unreachable
+                    let tracking_prefix = format!("refs/remotes/{}/", info.name);
+                    match client.remote_state(&repo, &name).await {
+                        Ok(state) => {
+                            println!("  Remote state (fetched now):");
+                            let head = state.head_branch
+                                .as_deref()
+                                .map(|target| target.trim_start_matches("refs/heads/"))
+                                .unwrap_or("(unknown)");
+                            println!("    HEAD branch: {}", head);
+                            println!("    Remote branches:");
+                            let mut advertised = std::collections::HashSet::new();
+                            for (ref_name, _) in &state.refs {
+                                let branch = match ref_name.strip_prefix("refs/heads/") {
+                                    Some(branch) => branch,
+                                    None => continue,
+                                };
+                                advertised.insert(branch.to_string());
+                                let tracking = format!("{}{}", tracking_prefix, branch);
+                                let status = if info.tracking_refs.contains(&tracking) { "tracked" } else { "new (next pull will fetch)" };
+                                println!("      {:<24} {}", branch, status);
+                            }
+                            for tracking in &info.tracking_refs {
+                                let branch = &tracking[tracking_prefix.len()..];
+                                if branch != "HEAD" && !advertised.contains(branch) {
+                                    println!("      {:<24} stale (use 'arti-git prune {}' to remove)", branch, info.name);
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            println!("  Remote state: unavailable ({})", e);
+                        }
+                    }
+                },
+                RemoteCommands::Add { name, url, isolate, fingerprint, path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    let tor = RemoteTorSettings {
+                        isolate: isolate.then_some(true),
+                        fingerprint,
+                    };
+                    if let Err(e) = client.add_remote(&repo, &name, &url, &tor) {
+                        eprintln!("Failed to add remote: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                },
+                RemoteCommands::Remove { name, path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    match client.remove_remote(&repo, &name) {
+                        Ok(deleted) => {
+                            for ref_name in deleted {
+                                println!("Deleted {}", ref_name);
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("Failed to remove remote: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    }
+                },
+                RemoteCommands::List { path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    let names: Vec<String> = repo.remote_names().iter().map(|name| name.to_string()).collect();
+                    for name in names {
+                        match client.remote_info(&repo, &name) {
+                            Ok(info) => {
+                                println!("{}\t{} (fetch)", info.name, info.fetch_url.as_deref().unwrap_or(""));
+                                println!("{}\t{} (push)", info.name, info.push_url.as_deref().unwrap_or(""));
+                            },
+                            Err(e) => {
+                                eprintln!("Failed to read remote {}: {}", name, e);
+                                exit_after_shutdown(client, 1);
+                            }
+                        }
+                    }
+                },
+                RemoteCommands::SetUrl { name, url, path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    if let Err(e) = client.set_remote_url(&repo, &name, &url) {
+                        eprintln!("Failed to set remote URL: {}", e);
+                        exit_after_shutdown(client, 1);
+                    }
+                }
+            }
+        },
+        Commands::Submodule(args) => {
+            match args.command {
+                SubmoduleCommands::Update { init, remote, path } => {
+                    // Open the repository
+                    let repo = match client.open(&path) {
+                        Ok(repo) => repo,
+                        Err(e) => {
+                            eprintln!("Failed to open repository: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    };
+                    
+                    match client.update_submodules(&repo, init, remote, &cancel).await {
+                        Ok(submodules) => {
+                            for submodule in &submodules {
+                                println!("Submodule path '{}': checked out '{}'",
+                                    submodule.path.display(), submodule.commit);
+                            }
+                            if submodules.is_empty() {
+                                println!("No initialized submodules to update");
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("Submodule update failed: {}", e);
+                            exit_after_shutdown(client, 1);
+                        }
+                    }
+                }
+            }
+        },
+        Commands::Config(args) => {
+            let (name, url) = (&args.get_urlmatch[0], &args.get_urlmatch[1]);
+            let key = match name.split_once('.') {
+                Some((section, key)) if section.eq_ignore_ascii_case(transport::URL_SECTION) && !key.is_empty() => key,
+                _ => {
+                    eprintln!("Only tor.<key> settings can be matched by URL, got '{}'", name);
+                    exit_after_shutdown(client, 2);
+                }
+            };
+            
+            // Repository config includes the global files; without a repository use those alone
+            let url_config = match client.open(&args.path) {
+                Ok(repo) => UrlConfig::from_config(repo.config_snapshot().plumbing()),
+                Err(_) => UrlConfig::from_globals(),
+            };
+            
+            match url_config.get(key, url) {
+                Some(value) => println!("{}", value),
+                // Like git, a missing value is reported through the exit code only
+                None => exit_after_shutdown(client, 1),
+            }
+        },
+        Commands::Stats(args) => {
+            use crate::transport::{AsyncRemoteConnection, PooledTorTransport, TorConnection};
+            
+            // Open the repository
+            let repo = match client.open(&args.path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    eprintln!("Failed to open repository: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            let names: Vec<String> = if args.all {
+                repo.remote_names().iter().map(|name| name.to_string()).collect()
+            } else {
+                args.remotes.clone()
+            };
+            let mut urls = Vec::new();
+            for name in &names {
+                match client.remote_info(&repo, name) {
+                    Ok(info) => match info.fetch_url {
+                        Some(url) => urls.push(url),
+                        None => eprintln!("Remote '{}' has no fetch URL", name),
+                    },
+                    Err(e) => eprintln!("Skipping remote '{}': {}", name, e),
+                }
+            }
+            
+            // One transport for every probe, so repeated connections can be pooled
+            let transport = match PooledTorTransport::new(client.tor_client()).await
+                .and_then(|transport| client.track_pooled_transport(
+                    transport.with_url_config(UrlConfig::from_config(repo.config_snapshot().plumbing()))
+                        .with_remote_isolation(repo.config_snapshot().plumbing())
+                ))
+            {
+                Ok(transport) => transport,
+                Err(e) => {
+                    eprintln!("Failed to create Tor transport: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            for _ in 0..args.rounds {
+                for url in &urls {
+                    let result = match TorConnection::with_transport(url, transport.clone())
+                        .map(|connection| connection.with_http_compression(client.config().tor.http_compression))
+                    {
+                        Ok(mut connection) => connection.list_refs_async().await.map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("Probe of {} failed: {}", url, e);
+                    }
+                }
+            }
+            
+            let stats = transport.get_stats().await;
+            println!("Connections: {} attempted, {} succeeded, {} failed, {} reused, {} closed, {} evicted idle",
+                stats.total_connections, stats.successful_connections, stats.failed_connections,
+                stats.reused_connections, stats.closed_connections, stats.evicted_connections);
+            println!("Average connect time: {} ms", stats.avg_connection_time_ms);
+            
+            if args.per_host {
+                println!("{:<40} {:>6} {:>6} {:>6} {:>7} {:>6}", "HOST", "POOLED", "REUSES", "OPENED", "EVICTED", "REUSE");
+                for (host, pool) in transport.pool_snapshot().await {
+                    println!("{:<40} {:>6} {:>6} {:>6} {:>7} {:>5.0}%",
+                        host, pool.pooled, pool.reuses, pool.opened, pool.evicted, pool.reuse_rate() * 100.0);
+                }
+            }
+        },
+        #[cfg(feature = "ipfs")]
+        Commands::Ipfs(IpfsArgs { command: IpfsCommands::Dedup { dry_run } }) => {
+            let storage = match client.ipfs_storage() {
+                Some(storage) => storage,
+                None => {
+                    eprintln!("IPFS storage is not enabled in the configuration");
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            
+            match storage.consolidate_chunks(dry_run).await {
+                Ok(report) => {
+                    let verb = if dry_run { "Would merge" } else { "Merged" };
+                    println!("Scanned {} chunks ({} unreadable)", report.chunks_scanned, report.unreadable_chunks);
+                    println!("{} {} duplicate chunks into canonical chunks, updating {} object mappings",
+                        verb, report.duplicate_chunks, report.mappings_updated);
+                    for cid in &report.unpinned {
+                        println!("  unpin {}", cid);
+                    }
+                    println!("Space reclaimed: {} bytes", report.bytes_reclaimed);
+                },
+                Err(e) => {
+                    eprintln!("Chunk consolidation failed: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+        },
+        #[cfg(feature = "ipfs")]
+        Commands::Ipfs(IpfsArgs { command: IpfsCommands::Gc { aggressive } }) => {
+            let storage = match client.ipfs_storage() {
+                Some(storage) => storage,
+                None => {
+                    eprintln!("IPFS storage is not enabled in the configuration");
+                    exit_after_shutdown(client, 1);
+                }
+            };
+
+            match storage.compact_cache(aggressive).await {
+                Ok(report) => {
+                    if aggressive {
+                        println!("Moved {} objects and {} chunks into packed cache files, removing {} files",
+                            report.migrated_objects, report.migrated_chunks, report.files_removed);
+                    }
+                    println!("Packed cache: {} entries in {} data files", report.packed_entries, report.data_files);
+                    println!("Space reclaimed: {} bytes", report.bytes_reclaimed);
+                    if aggressive && client.config().ipfs.cache_layout == crate::ipfs::CacheLayout::Loose {
+                        println!("hint: set ipfs.cache_layout = \"packed\" to keep newly cached objects packed");
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Cache compaction failed: {}", e);
+                    exit_after_shutdown(client, 1);
                 }
             }
         },
@@ -409,7 +2444,7 @@ async fn main() -> Result<()> {
             // Ensure Tor is enabled
             if !client.config().tor.use_tor {
                 eprintln!("Cannot create onion service: Tor is not enabled in configuration");
-                process::exit(1);
+                exit_after_shutdown(client, 1);
             }
             
             // Get tor client from our ArtiGit client
@@ -417,7 +2452,7 @@ async fn main() -> Result<()> {
                 Some(client) => client,
                 None => {
                     eprintln!("Tor client not available");
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
             
@@ -436,21 +2471,59 @@ async fn main() -> Result<()> {
             if args.port != 9418 {
                 onion_config.port = args.port;
             }
+            if args.skip_integrity_check {
+                onion_config.verify_on_start = false;
+            }
+            if args.lan_bind.is_some() {
+                onion_config.lan_bind = args.lan_bind;
+            }
+            if let Some(max_restarts) = args.max_restarts {
+                onion_config.max_restarts = max_restarts;
+            }
+            if let Some(restart_backoff) = args.restart_backoff {
+                onion_config.restart_backoff = restart_backoff;
+            }
+            if args.accept_ephemeral_keys {
+                onion_config.accept_ephemeral_push_keys = true;
+            }
+            let restart_policy = RestartPolicy::from_config(&onion_config);
             
             // Create and start the onion service
             let runtime = tokio::runtime::Handle::current();
-            let mut service = GitOnionService::new(
+            let mut service = match GitOnionService::new(
                 tor_client.clone(),
                 &args.path,
                 onion_config,
                 runtime.clone(),
-            )?;
+            ) {
+                Ok(service) => service,
+                Err(e) => {
+                    eprintln!("Failed to create onion service: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            };
+            for key in &args.authorized_clients {
+                if let Err(e) = service.add_authorized_client(key) {
+                    eprintln!("error: {}", e);
+                    exit_after_shutdown(client, 1);
+                }
+            }
+            
+            // In daemon mode the supervisor owns the service until Ctrl-C
+            if args.supervise {
+                println!("Supervising onion service (max restarts: {})", restart_policy.max_restarts);
+                if let Err(e) = ServiceSupervisor::new(service, restart_policy).run(cancel.clone()).await {
+                    eprintln!("{}", e);
+                    exit_after_shutdown(client, 1);
+                }
+                exit_after_shutdown(client, 0);
+            }
             
             // Start the service and get the onion address
-            let onion_address = match service.start().await {
-                Ok(addr) => addr,
+            let onion_addresses = match service.start().await {
+                Ok(addrs) => addrs,
                 Err(e) => {
                     eprintln!("Failed to start onion service: {}", e);
-                    process::exit(1);
+                    exit_after_shutdown(client, 1);
                 }
             };
\ No newline at end of file
@@ -0,0 +1,455 @@
+//! Delta compression for generated packs
+//!
+//! Successive revisions of a file or directory differ in a few lines, so
+//! sending each of them whole wastes most of a fetch on slow Tor circuits.
+//! [`PackBuilder`] stores blobs and trees as `OFS_DELTA` entries against a
+//! similar object earlier in the same pack, found like `git pack-objects`
+//! does: objects of a kind are sorted by size and each one is tried against
+//! a window of its predecessors.
+//!
+//! Deltas use Git's format: the base and result sizes, then instructions
+//! that either copy a range of the base or insert literal bytes.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use gix::object::Kind;
+use sha1::{Digest, Sha1};
+
+use crate::core::{Result, protocol_err};
+
+/// How many preceding objects each object is tried against
+pub const DELTA_WINDOW: usize = 10;
+
+/// Longest chain of deltas on top of deltas, bounding the receiver's work
+pub const MAX_DELTA_DEPTH: usize = 50;
+
+/// Objects larger than this are always sent whole
+pub const MAX_DELTA_OBJECT_SIZE: usize = 16 << 20;
+
+/// Length of the base chunks matches are looked up by
+const BLOCK_SIZE: usize = 16;
+
+/// Most base offsets remembered per chunk, against repetitive content
+const MAX_BLOCK_CANDIDATES: usize = 64;
+
+/// Longest insert instruction
+const MAX_INSERT: usize = 0x7f;
+
+/// Longest copy instruction
+const MAX_COPY: usize = 0xff_ffff;
+
+/// Pack entry type of a delta against an object at a relative offset
+const OFS_DELTA: u8 = 6;
+
+/// Encode `target` as a delta against `base`
+pub fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, target.len());
+
+    // Where each aligned chunk of the base starts
+    let mut blocks: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+        let starts = blocks.entry(&base[start..start + BLOCK_SIZE]).or_default();
+        if starts.len() < MAX_BLOCK_CANDIDATES {
+            starts.push(start);
+        }
+    }
+
+    let mut insert_from = 0;
+    let mut position = 0;
+    while position + BLOCK_SIZE <= target.len() {
+        let best = blocks.get(&target[position..position + BLOCK_SIZE]).and_then(|starts| {
+            starts.iter()
+                .map(|&start| (start, common_prefix(&base[start..], &target[position..])))
+                .max_by_key(|&(_, len)| len)
+        });
+        let (mut start, mut len) = match best {
+            Some(found) => found,
+            None => {
+                position += 1;
+                continue;
+            },
+        };
+        // The match may begin before the chunk it was found by
+        while position > insert_from && start > 0 && base[start - 1] == target[position - 1] {
+            position -= 1;
+            start -= 1;
+            len += 1;
+        }
+        push_inserts(&mut delta, &target[insert_from..position]);
+        push_copies(&mut delta, start, len);
+        position += len;
+        insert_from = position;
+    }
+    push_inserts(&mut delta, &target[insert_from..]);
+    delta
+}
+
+/// Rebuild an object from its delta and base
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut input = delta;
+    let base_size = read_size(&mut input)?;
+    let result_size = read_size(&mut input)?;
+    if base_size != base.len() {
+        return Err(protocol_err(format!("Delta expects a base of {} bytes, got {}", base_size, base.len()), None));
+    }
+
+    let mut result = Vec::with_capacity(result_size);
+    while let Some((&op, rest)) = input.split_first() {
+        input = rest;
+        if op & 0x80 != 0 {
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for (bit, shift) in (0..4).map(|byte| (1 << byte, byte * 8)) {
+                if op & bit != 0 {
+                    offset |= (next_byte(&mut input)? as usize) << shift;
+                }
+            }
+            for (bit, shift) in (0..3).map(|byte| (0x10 << byte, byte * 8)) {
+                if op & bit != 0 {
+                    size |= (next_byte(&mut input)? as usize) << shift;
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let end = offset.checked_add(size).filter(|&end| end <= base.len())
+                .ok_or_else(|| protocol_err("Delta copies past the end of its base", None))?;
+            result.extend_from_slice(&base[offset..end]);
+        } else if op != 0 {
+            let len = op as usize;
+            if input.len() < len {
+                return Err(protocol_err("Delta inserts past its end", None));
+            }
+            result.extend_from_slice(&input[..len]);
+            input = &input[len..];
+        } else {
+            return Err(protocol_err("Invalid delta instruction 0", None));
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(protocol_err(format!("Delta produced {} bytes instead of {}", result.len(), result_size), None));
+    }
+    Ok(result)
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn push_inserts(delta: &mut Vec<u8>, mut data: &[u8]) {
+    while !data.is_empty() {
+        let len = data.len().min(MAX_INSERT);
+        delta.push(len as u8);
+        delta.extend_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+fn push_copies(delta: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        let op_index = delta.len();
+        let mut op = 0x80u8;
+        delta.push(0);
+        for byte in 0..4 {
+            let value = (offset >> (byte * 8)) as u8;
+            if value != 0 {
+                op |= 1 << byte;
+                delta.push(value);
+            }
+        }
+        for byte in 0..3 {
+            let value = (size >> (byte * 8)) as u8;
+            if value != 0 {
+                op |= 0x10 << byte;
+                delta.push(value);
+            }
+        }
+        delta[op_index] = op;
+        offset += size;
+        len -= size;
+    }
+}
+
+fn write_size(out: &mut Vec<u8>, mut size: usize) {
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_size(input: &mut &[u8]) -> Result<usize> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = next_byte(input)?;
+        if shift > 56 {
+            return Err(protocol_err("Delta size is too large", None));
+        }
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn next_byte(input: &mut &[u8]) -> Result<u8> {
+    let (&byte, rest) = input.split_first()
+        .ok_or_else(|| protocol_err("Truncated delta", None))?;
+    *input = rest;
+    Ok(byte)
+}
+
+/// Pack entry type of an object kind
+fn type_code(kind: Kind) -> u8 {
+    match kind {
+        Kind::Commit => 1,
+        Kind::Tree => 2,
+        Kind::Blob => 3,
+        Kind::Tag => 4,
+    }
+}
+
+/// How an object ends up in the pack
+enum Entry {
+    Whole,
+    /// Delta against the object at this position of the pack order
+    Delta { base: usize, delta: Vec<u8> },
+}
+
+/// Collects objects and writes them as a version 2 pack
+pub struct PackBuilder {
+    objects: Vec<(Kind, Vec<u8>)>,
+    deltas: bool,
+}
+
+impl PackBuilder {
+    /// Build a pack, storing similar blobs and trees as deltas if `deltas` is set
+    ///
+    /// Without `deltas` every object is stored whole, for clients that didn't
+    /// announce `ofs-delta`.
+    pub fn new(deltas: bool) -> Self {
+        Self { objects: Vec::new(), deltas }
+    }
+
+    /// Add an object to the pack
+    pub fn add_object(&mut self, data: Vec<u8>, kind: Kind) {
+        self.objects.push((kind, data));
+    }
+
+    /// Number of objects added
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Whether no objects were added
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Write the pack, reporting how many objects were compressed so far
+    pub fn finish(self, progress: &dyn Fn(usize)) -> Result<Vec<u8>> {
+        self.finish_checked(&|_| Ok(()), progress)
+    }
+
+    /// Write the pack like [`PackBuilder::finish`], passing its compressed
+    /// size to `check_size` after every entry
+    ///
+    /// An error from `check_size` stops writing, so an oversized pack is
+    /// refused without compressing the rest of it.
+    pub fn finish_checked(self, check_size: &dyn Fn(u64) -> Result<()>, progress: &dyn Fn(usize)) -> Result<Vec<u8>> {
+        let objects = self.objects;
+        let mut order: Vec<usize> = (0..objects.len()).collect();
+        if self.deltas {
+            // Similar objects end up next to each other, larger ones first so
+            // they become the bases
+            order.sort_by_key(|&index| (type_code(objects[index].0), std::cmp::Reverse(objects[index].1.len())));
+        }
+
+        let mut entries = Vec::with_capacity(order.len());
+        let mut depths = vec![0usize; order.len()];
+        for position in 0..order.len() {
+            let entry = if self.deltas {
+                find_delta(&objects, &order, &depths, position)
+            } else {
+                None
+            };
+            if let Some((base, _)) = &entry {
+                depths[position] = depths[*base] + 1;
+            }
+            entries.push(match entry {
+                Some((base, delta)) => Entry::Delta { base, delta },
+                None => Entry::Whole,
+            });
+            if (position + 1) % 100 == 0 {
+                progress(position + 1);
+            }
+        }
+        progress(order.len());
+
+        let mut pack = Vec::new();
+        pack.extend_from_slice(b"PACK");
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        let count = u32::try_from(order.len())
+            .map_err(|_| protocol_err("Too many objects for one pack", None))?;
+        pack.extend_from_slice(&count.to_be_bytes());
+
+        let mut offsets = Vec::with_capacity(order.len());
+        for (position, (&index, entry)) in order.iter().zip(&entries).enumerate() {
+            offsets.push(pack.len());
+            let (kind, data) = &objects[index];
+            let body = match entry {
+                Entry::Whole => {
+                    write_entry_header(&mut pack, type_code(*kind), data.len());
+                    data.as_slice()
+                },
+                Entry::Delta { base, delta } => {
+                    write_entry_header(&mut pack, OFS_DELTA, delta.len());
+                    write_base_offset(&mut pack, offsets[position] - offsets[*base]);
+                    delta.as_slice()
+                },
+            };
+            let mut encoder = ZlibEncoder::new(&mut pack, Compression::default());
+            encoder.write_all(body)
+                .and_then(|_| encoder.finish().map(|_| ()))
+                .map_err(|e| protocol_err(format!("Failed to compress pack entry: {}", e), None))?;
+            check_size(pack.len() as u64)?;
+        }
+
+        let checksum = Sha1::digest(&pack);
+        pack.extend_from_slice(&checksum);
+        Ok(pack)
+    }
+}
+
+/// Add whole objects to the end of a finished pack
+///
+/// Offsets of the entries already in the pack stay valid, so their deltas
+/// still resolve. The object count and the trailer are rewritten.
+pub fn append_objects(pack: &[u8], objects: impl IntoIterator<Item = (Kind, Vec<u8>)>) -> Result<Vec<u8>> {
+    if pack.len() < 32 || !pack.starts_with(b"PACK") {
+        return Err(protocol_err("Not a pack", None));
+    }
+    let mut count = u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]);
+    let mut out = pack[..pack.len() - 20].to_vec();
+    for (kind, data) in objects {
+        count = count.checked_add(1)
+            .ok_or_else(|| protocol_err("Too many objects for one pack", None))?;
+        write_entry_header(&mut out, type_code(kind), data.len());
+        let mut encoder = ZlibEncoder::new(&mut out, Compression::default());
+        encoder.write_all(&data)
+            .and_then(|_| encoder.finish().map(|_| ()))
+            .map_err(|e| protocol_err(format!("Failed to compress pack entry: {}", e), None))?;
+    }
+    out[8..12].copy_from_slice(&count.to_be_bytes());
+    let checksum = Sha1::digest(&out);
+    out.extend_from_slice(&checksum);
+    Ok(out)
+}
+
+/// The smallest worthwhile delta for the object at `position` of `order`,
+/// against one of the objects before it
+fn find_delta(objects: &[(Kind, Vec<u8>)], order: &[usize], depths: &[usize], position: usize) -> Option<(usize, Vec<u8>)> {
+    let (kind, target) = &objects[order[position]];
+    if !matches!(kind, Kind::Blob | Kind::Tree) || target.len() > MAX_DELTA_OBJECT_SIZE || target.len() < BLOCK_SIZE {
+        return None;
+    }
+
+    let mut best: Option<(usize, Vec<u8>)> = None;
+    for base_position in (position.saturating_sub(DELTA_WINDOW)..position).rev() {
+        let (base_kind, base) = &objects[order[base_position]];
+        if base_kind != kind || depths[base_position] >= MAX_DELTA_DEPTH || base.len() > MAX_DELTA_OBJECT_SIZE {
+            continue;
+        }
+        // Objects this different in size don't make useful deltas
+        if base.len().abs_diff(target.len()) >= target.len() / 2 {
+            continue;
+        }
+        let delta = encode_delta(base, target);
+        let limit = best.as_ref().map_or(target.len() / 2, |(_, best)| best.len());
+        if delta.len() < limit {
+            best = Some((base_position, delta));
+        }
+    }
+    best
+}
+
+/// Type and size of an entry: 3 bits of type, then the size 4 and 7 bits at a time
+fn write_entry_header(pack: &mut Vec<u8>, type_code: u8, size: usize) {
+    let mut byte = (type_code << 4) | (size & 0x0f) as u8;
+    let mut rest = size >> 4;
+    while rest != 0 {
+        pack.push(byte | 0x80);
+        byte = (rest & 0x7f) as u8;
+        rest >>= 7;
+    }
+    pack.push(byte);
+}
+
+/// Distance back to the base of an `OFS_DELTA`, most significant bits first
+fn write_base_offset(pack: &mut Vec<u8>, mut distance: usize) {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    pack.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base: Vec<u8> = (0..2000).flat_map(|line| format!("line {}\n", line).into_bytes()).collect();
+        let mut target = base.clone();
+        target.splice(500..510, b"changed".iter().copied());
+        target.extend_from_slice(b"appended\n");
+        target.drain(..40);
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 100, "delta of {} bytes", delta.len());
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+
+        // Nothing in common, nothing at all, and long copies
+        for (base, target) in [
+            (b"abc".to_vec(), b"completely different content".to_vec()),
+            (Vec::new(), Vec::new()),
+            (vec![7u8; 200_000], vec![7u8; 200_000]),
+        ] {
+            assert_eq!(apply_delta(&base, &encode_delta(&base, &target)).unwrap(), target);
+        }
+
+        assert!(apply_delta(b"short", &delta).is_err());
+        assert!(apply_delta(&base, &delta[..delta.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_base_offset_encoding() {
+        let encode = |distance| {
+            let mut out = Vec::new();
+            write_base_offset(&mut out, distance);
+            out
+        };
+        assert_eq!(encode(0x7f), [0x7f]);
+        // Git's encoding adds one per continuation byte, so 128 is [0x80, 0x00]
+        assert_eq!(encode(0x80), [0x80, 0x00]);
+        assert_eq!(encode(0x4080), [0x80, 0x80, 0x00]);
+    }
+}
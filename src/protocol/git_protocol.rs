@@ -10,8 +10,13 @@ use gix_packetline::{self as pkt, PacketLine, WriteMode};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
 use futures::StreamExt;
+use sha1::{Digest, Sha1};
 
 use crate::core::{GitError, Result, io_err, protocol_err};
+use super::push_cert::{CertifiedUpdate, PushCertificate, PushCertPolicy, PUSH_CERT_BEGIN, PUSH_CERT_END};
+use super::shallow::{ShallowPlan, ShallowRequest, plan_shallow, shallow_pack_objects};
+use super::pkt_line::{parse_pkt_length, read_pkt_line, MAX_PKT_LINE_LENGTH};
+use super::delta::PackBuilder;
 
 /// A parsed Git command
 #[derive(Debug, Clone)]
@@ -118,10 +123,13 @@ impl ServerCapabilities {
             "thin-pack".to_string(),
             "ofs-delta".to_string(),
             "shallow".to_string(),
+            "deepen-since".to_string(),
+            "deepen-relative".to_string(),
             "no-progress".to_string(),
             "include-tag".to_string(),
             "allow-tip-sha1-in-want".to_string(),
             "allow-reachable-sha1-in-want".to_string(),
+            RESUME_CAPABILITY.to_string(),
         ]);
         
         // Receive pack capabilities
@@ -201,22 +209,150 @@ impl From<u8> for PackProtocolChannel {
     }
 }
 
+/// Progress of pack generation and transfer, reported to fetching clients
+///
+/// Each update is rendered the way git prints its own progress and sent on
+/// side-band channel 2, so clients draw a normal progress meter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackProgress {
+    /// Walking the object graph to find what to send
+    Enumerating { done: usize, total: usize },
+    /// Deltifying and compressing the objects
+    Compressing { done: usize, total: usize },
+    /// Pack bytes written to the client so far, out of `total`
+    Writing { bytes: usize, total: usize },
+}
+
+impl PackProgress {
+    /// Whether this is the final update of its phase
+    pub fn is_done(&self) -> bool {
+        match *self {
+            PackProgress::Enumerating { done, total } | PackProgress::Compressing { done, total } => done >= total,
+            PackProgress::Writing { bytes, total } => bytes >= total,
+        }
+    }
+}
+
+impl std::fmt::Display for PackProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (label, done, total) = match *self {
+            PackProgress::Enumerating { done, total } => ("Enumerating objects", done, total),
+            PackProgress::Compressing { done, total } => ("Compressing objects", done, total),
+            PackProgress::Writing { bytes, total } => {
+                let percent = if total == 0 { 100 } else { bytes * 100 / total };
+                let end = if self.is_done() { ", done.\n" } else { "\r" };
+                return write!(f, "Writing objects: {}% ({:.2} KiB){}", percent, bytes as f64 / 1024.0, end);
+            }
+        };
+        
+        let percent = if total == 0 { 100 } else { done * 100 / total };
+        // Git overwrites the line with '\r' until the phase finishes with '\n'
+        if self.is_done() {
+            write!(f, "{}: {}% ({}/{}), done.\n", label, percent, done, total)
+        } else {
+            write!(f, "{}: {}% ({}/{})\r", label, percent, done, total)
+        }
+    }
+}
+
+/// Per-request caps on the work a fetch can make the server do
+///
+/// A `want` naming the root of a huge history makes the server walk and pack
+/// all of it. Publicly reachable services should bound that, and requests
+/// over a limit are refused with a side-band error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PackLimits {
+    /// Most objects to enumerate for one pack
+    pub max_objects: Option<usize>,
+    /// Largest pack to build, in bytes
+    pub max_bytes: Option<u64>,
+}
+
+impl PackLimits {
+    /// No limits, for trusted callers
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+    
+    /// Build limits from configuration values, where 0 means unlimited
+    pub fn from_config(max_objects: usize, max_bytes: u64) -> Self {
+        Self {
+            max_objects: Some(max_objects).filter(|&max| max > 0),
+            max_bytes: Some(max_bytes).filter(|&max| max > 0),
+        }
+    }
+    
+    /// Fail once more than the allowed number of objects were enumerated
+    fn check_objects(&self, count: usize) -> Result<()> {
+        match self.max_objects {
+            Some(max) if count > max => Err(protocol_err(
+                format!("Request exceeds the server limit of {} objects per fetch", max), None)),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Fail once the pack grows beyond the allowed size
+    fn check_bytes(&self, bytes: u64) -> Result<()> {
+        match self.max_bytes {
+            Some(max) if bytes > max => Err(protocol_err(
+                format!("Request exceeds the server limit of {} bytes per fetch", max), None)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Longest request a client may open a connection with
+pub const MAX_GIT_REQUEST_LENGTH: usize = 4096;
+
+/// Bytes needed to tell a Git request from other traffic
+pub const GIT_REQUEST_PREFIX_LENGTH: usize = 8;
+
+/// Length of the Git request starting with `prefix`, or `None` if it isn't one
+///
+/// Requests are `git-<service> <path>\0...`, normally framed as a pkt-line,
+/// so the first [`GIT_REQUEST_PREFIX_LENGTH`] bytes are enough to turn away
+/// HTTP requests, TLS handshakes and scanner garbage. A framed request has
+/// its declared length; an unframed one is at least as long as the prefix.
+pub fn git_request_length(prefix: &[u8]) -> Option<usize> {
+    let prefix = prefix.get(..GIT_REQUEST_PREFIX_LENGTH)?;
+    if prefix.starts_with(b"git-") {
+        return Some(GIT_REQUEST_PREFIX_LENGTH);
+    }
+    let length = parse_pkt_length(&prefix[..4], MAX_GIT_REQUEST_LENGTH).ok()?;
+    if length > GIT_REQUEST_PREFIX_LENGTH && &prefix[4..] == b"git-" {
+        Some(length)
+    } else {
+        None
+    }
+}
+
 /// Parse a Git smart protocol command from a stream
 pub async fn parse_git_command<S>(stream: &mut S) -> Result<GitCommand>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut buf = [0u8; 4096];
+    let mut buf = [0u8; MAX_GIT_REQUEST_LENGTH];
     let bytes_read = stream.read(&mut buf).await
         .map_err(|e| io_err(format!("Failed to read Git command: {}", e)))?;
     
-    if bytes_read == 0 {
+    parse_git_request(&buf[..bytes_read])
+}
+
+/// Parse the request a Git client opens a connection with
+pub fn parse_git_request(request: &[u8]) -> Result<GitCommand> {
+    if request.is_empty() {
         return Err(protocol_err("Empty request", None));
     }
     
-    let request = std::str::from_utf8(&buf[..bytes_read])
+    let request = std::str::from_utf8(request)
         .map_err(|_| protocol_err("Invalid UTF-8 in request", None))?;
     
+    // git daemon clients frame the request as a packet line; strip its length
+    let request = match request.get(..4).and_then(|len| usize::from_str_radix(len, 16).ok()) {
+        Some(len) if len > 4 => request.get(4..len).unwrap_or(&request[4..]),
+        _ => request,
+    };
+    
     // Check for protocol version marker
     let mut version = GitProtocolVersion::V0;
     if request.starts_with("version=") {
@@ -258,6 +394,15 @@ where
         }
     }
     
+    // git daemon clients ask for a version in an extra parameter after the host
+    if command.version == GitProtocolVersion::V0 {
+        match command.params.get("version").map(String::as_str) {
+            Some("2") => command = command.with_version(GitProtocolVersion::V2),
+            Some("1") => command = command.with_version(GitProtocolVersion::V1),
+            _ => {},
+        }
+    }
+    
     log::debug!("Parsed Git command: {:?} for repository: {:?}", command.service, command.repo_path);
     
     Ok(command)
@@ -342,6 +487,15 @@ where
         let packet = format!("{:04x}{}", line.len() + 4, line);
         stream.write_all(packet.as_bytes()).await
             .map_err(|e| io_err(format!("Failed to write reference {}: {}", name, e)))?;
+        
+        // Annotated tags are followed by the object they point to, so clients
+        // can resolve them without fetching the tag object first
+        if let Some(peeled) = peeled_tag_target(repo, git_ref.id().detach()) {
+            let line = format!("{} {}^{{}}", peeled.to_hex(), name);
+            let packet = format!("{:04x}{}", line.len() + 4, line);
+            stream.write_all(packet.as_bytes()).await
+                .map_err(|e| io_err(format!("Failed to write peeled reference {}: {}", name, e)))?;
+        }
     }
     
     // Send a flush packet
@@ -353,11 +507,102 @@ where
     Ok(())
 }
 
+/// Get the object an annotated tag ultimately points to
+///
+/// Returns `None` for anything that is not a tag object, including
+/// lightweight tags which already point at their commit.
+pub(crate) fn peeled_tag_target(repo: &Repository, id: ObjectId) -> Option<ObjectId> {
+    let object = repo.find_object(id).ok()?;
+    if object.kind != gix::object::Kind::Tag {
+        return None;
+    }
+    
+    match object.peel_tags_to_end() {
+        Ok(target) => Some(target.id),
+        Err(e) => {
+            log::warn!("Failed to peel tag {}: {}", id, e);
+            None
+        }
+    }
+}
+
+/// Capability for continuing an interrupted pack download
+///
+/// Advertised by arti-git servers. A client that kept the start of a pack
+/// asks for the rest with `resume-pack=<offset>:<sha1>` after its first
+/// want; see [`PackResume`].
+pub const RESUME_CAPABILITY: &str = "resume-pack";
+
+/// A client's request to skip the part of a pack it already has
+///
+/// The server builds the pack as usual and skips the first `offset` bytes
+/// if their SHA-1 matches, which holds as long as the repository and the
+/// request haven't changed. Otherwise the whole pack is sent, and the
+/// client sees it start over with the `PACK` signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackResume {
+    /// Bytes of the pack the client kept
+    pub offset: u64,
+    /// Hex SHA-1 of those bytes
+    pub prefix_sha1: String,
+}
+
+impl PackResume {
+    /// Describe the first `prefix.len()` bytes of a pack
+    pub fn for_prefix(prefix: &[u8]) -> Self {
+        Self {
+            offset: prefix.len() as u64,
+            prefix_sha1: hex::encode(Sha1::digest(prefix)),
+        }
+    }
+
+    /// Parse a `resume-pack=<offset>:<sha1>` capability
+    pub fn parse_capability(capability: &str) -> Option<Self> {
+        let value = capability.strip_prefix(RESUME_CAPABILITY)?.strip_prefix('=')?;
+        let (offset, prefix_sha1) = value.split_once(':')?;
+        Some(Self {
+            offset: offset.parse().ok()?,
+            prefix_sha1: prefix_sha1.to_ascii_lowercase(),
+        })
+    }
+
+    /// The capability to send after the first want
+    pub fn to_capability(&self) -> String {
+        format!("{}={}:{}", RESUME_CAPABILITY, self.offset, self.prefix_sha1)
+    }
+
+    /// Bytes at the start of `pack` the client already has, 0 if they differ
+    pub fn skip_in(&self, pack: &[u8]) -> usize {
+        match usize::try_from(self.offset) {
+            Ok(offset) if offset > 0 && offset < pack.len() && Self::for_prefix(&pack[..offset]) == *self => offset,
+            _ => 0,
+        }
+    }
+}
+
+/// What a client asked for during upload-pack negotiation
+#[derive(Debug, Clone, Default)]
+pub struct UploadRequest {
+    /// Objects the client wants
+    pub wants: Vec<ObjectId>,
+    /// Objects the client already has
+    pub haves: Vec<ObjectId>,
+    /// The new shallow boundary and the commits above it, for deepening requests
+    pub shallow: Option<ShallowPlan>,
+    /// The client announced `ofs-delta`, so the pack may contain deltas
+    pub ofs_delta: bool,
+    /// The client kept the start of this pack from an interrupted download
+    pub resume: Option<PackResume>,
+}
+
 /// Process Git upload-pack (fetch/clone) negotiation
+///
+/// Shallow clients get their `shallow`/`unshallow` lines after the wants,
+/// before the haves are negotiated.
 pub async fn process_wants<S>(
     stream: &mut S,
     repo: &Repository
-) -> Result<(Vec<ObjectId>, Vec<ObjectId>)>
+) -> Result<UploadRequest>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -365,22 +610,19 @@ where
     
     let mut wanted_objects = Vec::new();
     let mut have_objects = Vec::new();
-    let mut shallow_objects = Vec::new();
+    let mut shallow_request = ShallowRequest::default();
+    let mut shallow_update = None;
+    let mut ofs_delta = false;
+    let mut resume = None;
     let mut client_done = false;
-    let mut length_buf = [0u8; 4];
-    let mut data_buf = Vec::new();
     
     // Read the client's wants and haves
     while !client_done {
-        // Read packet length
-        stream.read_exact(&mut length_buf).await
-            .map_err(|e| io_err(format!("Failed to read packet length: {}", e)))?;
-            
-        let length_str = std::str::from_utf8(&length_buf)
-            .map_err(|_| protocol_err("Invalid packet length encoding", None))?;
-            
+        let data_buf = read_pkt_line(stream, MAX_PKT_LINE_LENGTH).await?
+            .ok_or_else(|| protocol_err("Unexpected end of stream during negotiation", None))?;
+        
         // Check for flush packet
-        if length_str == "0000" {
+        if data_buf.is_empty() {
             // Flush packet - end of current section
             if !wanted_objects.is_empty() && !have_objects.is_empty() {
                 // If we've seen wants and haves, this flush marks the end of haves
@@ -389,6 +631,16 @@ where
             } else if !wanted_objects.is_empty() {
                 // If we've only seen wants, this flush marks the end of wants
                 log::debug!("Client sent flush packet after wants");
+                if shallow_update.is_none() && shallow_request.is_deepening() {
+                    let plan = plan_shallow(repo, &wanted_objects, &[], &shallow_request)?;
+                    send_shallow_info(stream, &plan).await?;
+                    shallow_update = Some(plan.update);
+                } else if shallow_update.is_none() && !shallow_request.client_shallow.is_empty() {
+                    // A shallow client that keeps its depth gets an empty section
+                    stream.write_all(b"0000").await
+                        .map_err(|e| io_err(format!("Failed to write flush packet: {}", e)))?;
+                    shallow_update = Some(Default::default());
+                }
                 // Wait for haves or done
             } else {
                 // No wants yet - unexpected flush
@@ -398,20 +650,6 @@ where
             continue;
         }
         
-        // Parse length
-        let length = u16::from_str_radix(length_str, 16)
-            .map_err(|_| protocol_err("Invalid packet length", None))?;
-            
-        if length < 4 {
-            return Err(protocol_err("Invalid packet length", None));
-        }
-        
-        // Read packet data
-        let data_length = length as usize - 4; // Subtract the 4 bytes of the length header
-        data_buf.resize(data_length, 0);
-        stream.read_exact(&mut data_buf).await
-            .map_err(|e| io_err(format!("Failed to read packet data: {}", e)))?;
-            
         // Parse command
         let line = std::str::from_utf8(&data_buf)
             .map_err(|_| protocol_err("Invalid UTF-8 in packet", None))?;
@@ -424,6 +662,11 @@ where
             }
             
             let oid_hex = &line[5..45];
+            // Capabilities follow the first want
+            if wanted_objects.is_empty() {
+                ofs_delta = line[45..].split_whitespace().any(|cap| cap == "ofs-delta");
+                resume = line[45..].split_whitespace().find_map(PackResume::parse_capability);
+            }
             match ObjectId::from_hex(oid_hex.as_bytes()) {
                 Ok(oid) => {
                     log::debug!("Client wants object: {}", oid_hex);
@@ -446,21 +689,8 @@ where
                 },
                 Err(_) => return Err(protocol_err(format!("Invalid object ID: {}", oid_hex), None)),
             }
-        } else if line.starts_with("shallow ") {
-            // Parse shallow object
-            if line.len() < 48 {
-                log::warn!("Invalid shallow line: {}", line);
-                continue;
-            }
-            
-            let oid_hex = &line[8..48];
-            match ObjectId::from_hex(oid_hex.as_bytes()) {
-                Ok(oid) => {
-                    log::debug!("Client shallow object: {}", oid_hex);
-                    shallow_objects.push(oid);
-                },
-                Err(_) => return Err(protocol_err(format!("Invalid object ID: {}", oid_hex), None)),
-            }
+        } else if shallow_request.parse_line(line)? {
+            log::debug!("Client shallow request: {}", line.trim_end());
         } else if line.trim() == "done" {
             // Client is done sending commands
             log::debug!("Client sent done");
@@ -469,12 +699,41 @@ where
     }
     
     log::info!("Object negotiation complete: {} wants, {} haves, {} shallows", 
-             wanted_objects.len(), have_objects.len(), shallow_objects.len());
+             wanted_objects.len(), have_objects.len(), shallow_request.client_shallow.len());
     
     // Send acknowledgement before packfile
     send_ack_response(stream, &have_objects, true).await?;
     
-    Ok((wanted_objects, have_objects))
+    // The boundary was announced before the haves; now that they are known,
+    // leave out the commits the client already has
+    let shallow = match shallow_update {
+        Some(update) if shallow_request.is_deepening() => {
+            let plan = plan_shallow(repo, &wanted_objects, &have_objects, &shallow_request)?;
+            Some(ShallowPlan { update, commits: plan.commits })
+        },
+        _ => None,
+    };
+    
+    Ok(UploadRequest { wants: wanted_objects, haves: have_objects, shallow, ofs_delta, resume })
+}
+
+/// Send the `shallow`/`unshallow` section for a deepening request
+async fn send_shallow_info<S>(stream: &mut S, plan: &ShallowPlan) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let lines = plan.update.shallow.iter().map(|id| format!("shallow {}\n", id.to_hex()))
+        .chain(plan.update.unshallow.iter().map(|id| format!("unshallow {}\n", id.to_hex())));
+    for line in lines {
+        let packet = format!("{:04x}{}", line.len() + 4, line);
+        stream.write_all(packet.as_bytes()).await
+            .map_err(|e| io_err(format!("Failed to write shallow packet: {}", e)))?;
+    }
+    stream.write_all(b"0000").await
+        .map_err(|e| io_err(format!("Failed to write flush packet: {}", e)))?;
+    log::debug!("Sent {} shallow and {} unshallow lines",
+             plan.update.shallow.len(), plan.update.unshallow.len());
+    Ok(())
 }
 
 /// Send an acknowledgement response for object negotiation
@@ -512,21 +771,413 @@ where
     Ok(())
 }
 
+/// Generate a packfile holding `wanted_objects` and everything reachable from
+/// them, excluding what is reachable from `have_objects`
+///
+/// This is the pack generation half of `send_packfile`, usable by callers that
+/// want to move the pack over their own transport.
+pub fn pack_objects(repo: &Repository, wanted_objects: &[ObjectId], have_objects: &[ObjectId]) -> Result<Bytes> {
+    let (pack_data, stats) = build_packfile(repo, wanted_objects, have_objects, None, true, &PackLimits::unlimited(), &|progress| {
+        log::debug!("{}", progress.to_string().trim_end());
+    })?;
+    log::info!("Packed {} objects into {} bytes", stats.objects, pack_data.len());
+    Ok(Bytes::from(pack_data))
+}
+
+/// Build a packfile, reporting progress through `progress_reporter`
+///
+/// Returns the pack bytes and statistics about their contents. Fails as
+/// soon as the pack would exceed `limits`. With `shallow_commits` the pack
+/// holds exactly those commits and their trees instead of full history.
+/// Similar blobs and trees are stored as `OFS_DELTA` entries if `ofs_delta`
+/// is set, otherwise every object is stored whole.
+fn build_packfile(
+    repo: &Repository,
+    wanted_objects: &[ObjectId],
+    have_objects: &[ObjectId],
+    shallow_commits: Option<&[ObjectId]>,
+    ofs_delta: bool,
+    limits: &PackLimits,
+    progress_reporter: &dyn Fn(PackProgress),
+) -> Result<(Vec<u8>, SentPackStats)> {
+    let mut pack_builder = PackBuilder::new(ofs_delta);
+    
+    // How objects are stored locally, to compare with what gets sent
+    let local_packs = LocalPacks::open(repo);
+    let mut local_deltas = 0;
+    
+    // Shallow fetches were planned during negotiation and must not walk
+    // past the boundary
+    if let Some(commits) = shallow_commits {
+        let objects = shallow_pack_objects(repo, commits)?;
+        let total = objects.len();
+        progress_reporter(PackProgress::Enumerating { done: 0, total });
+        limits.check_objects(total)?;
+        
+        for (done, id) in objects.iter().enumerate() {
+            let obj = repo.find_object(*id)
+                .map_err(|e| protocol_err(format!("Object not found: {}", e), None))?;
+            if local_packs.is_delta(id) {
+                local_deltas += 1;
+            }
+            
+            pack_builder.add_object(obj.data.clone(), obj.kind);
+            if (done + 1) % 1000 == 0 {
+                progress_reporter(PackProgress::Enumerating { done: done + 1, total });
+            }
+        }
+        progress_reporter(PackProgress::Enumerating { done: total, total });
+        return finish_packfile(pack_builder, total, local_deltas, limits, progress_reporter, repo.object_hash());
+    }
+    
+    // Find the commits that the client doesn't have
+    let mut objects_to_send = Vec::new();
+    let graph = crate::core::CommitGraph::for_repo(repo);
+    
+    for wanted in wanted_objects {
+        // Check if client already has this object
+        if have_objects.contains(wanted) {
+            continue;
+        }
+        
+        // A want the client already has through history needs no traversal;
+        // generation numbers make this check cheap
+        if let Some(graph) = &graph {
+            if have_objects.iter().any(|have| graph.is_ancestor(wanted, have) == Some(true)) {
+                continue;
+            }
+        }
+        
+        // Check if object exists in the repository
+        let object = repo.find_object(*wanted)
+            .map_err(|e| protocol_err(format!("Object not found: {}", e), None))?;
+        objects_to_send.push(object.id);
+    }
+    
+    // Set up traversal, using what the client has as the boundary
+    let mut traversal = repo.objects.traverse(objects_to_send)
+        .map_err(|e| protocol_err(format!("Failed to start object traversal: {}", e), None))?
+        .with_deepen(true)  // Include all tree entries for tree objects
+        .with_objects(true);  // Include all reachable objects
+    
+    if !have_objects.is_empty() {
+        traversal = traversal.with_boundary(have_objects.to_vec());
+    }
+    
+    let total = traversal.total_objects();
+    progress_reporter(PackProgress::Enumerating { done: 0, total });
+    
+    // Refuse oversized requests up front when the traversal knows its size
+    limits.check_objects(total)?;
+    
+    let mut object_count = 0;
+    while let Some(obj_result) = traversal.next() {
+        let obj = obj_result
+            .map_err(|e| protocol_err(format!("Failed to traverse object: {}", e), None))?;
+        
+        object_count += 1;
+        limits.check_objects(object_count)?;
+        if local_packs.is_delta(&obj.id) {
+            local_deltas += 1;
+        }
+        
+        pack_builder.add_object(obj.data.into(), obj.kind);
+        
+        // Report progress
+        if object_count % 1000 == 0 {
+            progress_reporter(PackProgress::Enumerating { done: object_count, total: total.max(object_count) });
+        }
+    }
+    progress_reporter(PackProgress::Enumerating { done: object_count, total: object_count });
+    
+    finish_packfile(pack_builder, object_count, local_deltas, limits, progress_reporter, repo.object_hash())
+}
+
+/// Compress the collected objects into the final pack
+fn finish_packfile(
+    pack_builder: PackBuilder,
+    object_count: usize,
+    local_deltas: usize,
+    limits: &PackLimits,
+    progress_reporter: &dyn Fn(PackProgress),
+    object_hash: gix_hash::Kind,
+) -> Result<(Vec<u8>, SentPackStats)> {
+    // Finalize packfile data
+    progress_reporter(PackProgress::Compressing { done: 0, total: object_count });
+    // The byte limit is on the pack as sent, so it is checked as entries are
+    // compressed rather than against the objects' uncompressed sizes
+    let pack_data = pack_builder.finish_checked(
+        &|bytes| limits.check_bytes(bytes),
+        &|done| progress_reporter(PackProgress::Compressing { done, total: object_count }),
+    )?;
+    
+    log::debug!("Generated packfile: {} bytes", pack_data.len());
+    let mut stats = SentPackStats::from_pack(&pack_data, object_hash)?;
+    stats.local_deltas = local_deltas;
+    Ok((pack_data, stats))
+}
+
+/// What a pack sent to a client is made of
+///
+/// A pack that is much larger than expected usually has too few deltas:
+/// compare `deltas` with `local_deltas`, the objects this repository
+/// itself stores as deltas. Not to be confused with [`PackStats`], which
+/// describes a received pack after indexing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SentPackStats {
+    /// Objects in the pack
+    pub objects: usize,
+    /// Objects stored whole
+    pub bases: usize,
+    /// Objects stored as a delta against another object
+    pub deltas: usize,
+    /// Objects sent that the local packs store as deltas; their deltas
+    /// are computed afresh for the sent pack, not copied
+    pub local_deltas: usize,
+    /// Size of the objects after inflating their entries (of the delta, for deltas)
+    pub uncompressed_bytes: u64,
+    /// Size of the compressed entries, headers included
+    pub compressed_bytes: u64,
+    /// Size of the whole pack
+    pub pack_bytes: u64,
+}
+
+impl SentPackStats {
+    /// Count the entries of a complete pack
+    pub fn from_pack(pack_data: &[u8], object_hash: gix_hash::Kind) -> Result<Self> {
+        use gix::odb::pack::data::input;
+        
+        let entries = input::BytesToEntriesIter::new_from_header(
+            std::io::BufReader::new(pack_data),
+            input::Mode::AsIs,
+            input::EntryDataMode::Ignore,
+            object_hash,
+        ).map_err(|e| protocol_err(format!("Failed to read generated pack: {}", e), None))?;
+        
+        let mut stats = SentPackStats { pack_bytes: pack_data.len() as u64, ..Default::default() };
+        for entry in entries {
+            let entry = entry
+                .map_err(|e| protocol_err(format!("Failed to read generated pack: {}", e), None))?;
+            stats.objects += 1;
+            if entry.header.is_delta() {
+                stats.deltas += 1;
+            } else {
+                stats.bases += 1;
+            }
+            stats.uncompressed_bytes += entry.decompressed_size;
+            stats.compressed_bytes += entry.header_size as u64 + entry.compressed_size;
+        }
+        Ok(stats)
+    }
+}
+
+impl std::fmt::Display for SentPackStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} objects ({} whole, {} deltas; {} stored as deltas locally), {} bytes inflated, {} bytes compressed, {} byte pack",
+            self.objects, self.bases, self.deltas, self.local_deltas,
+            self.uncompressed_bytes, self.compressed_bytes, self.pack_bytes
+        )
+    }
+}
+
+/// The repository's pack indexes, for looking up how objects are stored
+struct LocalPacks {
+    packs: Vec<(gix::odb::pack::index::File, gix::odb::pack::data::File)>,
+}
+
+impl LocalPacks {
+    /// Open every pack of the repository; unreadable packs are skipped
+    fn open(repo: &Repository) -> Self {
+        let object_hash = repo.object_hash();
+        let pack_dir = repo.objects.store_ref().path().join("pack");
+        let mut packs = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(&pack_dir) {
+            for entry in entries.flatten() {
+                let index_path = entry.path();
+                if index_path.extension().map_or(true, |ext| ext != "idx") {
+                    continue;
+                }
+                let index = gix::odb::pack::index::File::at(&index_path, object_hash);
+                let data = gix::odb::pack::data::File::at(index_path.with_extension("pack"), object_hash);
+                if let (Ok(index), Ok(data)) = (index, data) {
+                    packs.push((index, data));
+                }
+            }
+        }
+        Self { packs }
+    }
+    
+    /// Check whether `id` is stored as a delta in one of the packs
+    fn is_delta(&self, id: &oid) -> bool {
+        self.packs.iter().any(|(index, data)| {
+            index.lookup(id)
+                .map_or(false, |position| data.entry(index.pack_offset_at_index(position)).header.is_delta())
+        })
+    }
+}
+
+/// Counts and locations for a pack written by `index_pack`
+#[derive(Debug, Clone)]
+pub struct PackStats {
+    /// Total number of objects in the pack
+    pub num_objects: usize,
+    /// Number of commits
+    pub commits: usize,
+    /// Number of trees
+    pub trees: usize,
+    /// Number of blobs
+    pub blobs: usize,
+    /// Number of annotated tags
+    pub tags: usize,
+    /// Path of the written `.pack` file
+    pub pack_path: PathBuf,
+    /// Path of the written `.idx` file
+    pub index_path: PathBuf,
+}
+
+/// Write a packfile and its index into the repository's object store
+///
+/// Every object referenced by a commit or tree in the pack must exist either
+/// in the pack or in the repository, otherwise the pack is rejected and
+/// removed again. With `complete_thin` set, REF-deltas against objects the
+/// repository already has are resolved, so thin packs from a fetch can be
+/// imported directly.
+pub fn index_pack(repo: &Repository, pack_data: &[u8], complete_thin: bool) -> Result<PackStats> {
+    let pack_dir = repo.objects.store_ref().path().join("pack");
+    
+    // Resolve thin-pack bases from the objects we already have
+    let thin_lookup = if complete_thin {
+        let odb = repo.objects.clone();
+        Some(move |id: ObjectId, buf: &mut Vec<u8>| {
+            use gix::odb::Find;
+            odb.try_find(&id, buf).ok().flatten().map(|(data, _)| data)
+        })
+    } else {
+        None
+    };
+    
+    let outcome = gix::odb::pack::Bundle::write_to_directory(
+        &mut std::io::BufReader::new(pack_data),
+        Some(&pack_dir),
+        gix::progress::Discard,
+        &gix::interrupt::IS_INTERRUPTED,
+        thin_lookup,
+        gix::odb::pack::bundle::write::Options {
+            thread_limit: None,
+            iteration_mode: gix::odb::pack::data::input::Mode::Verify,
+            index_version: gix::odb::pack::index::Version::V2,
+            object_hash: repo.object_hash(),
+        },
+    ).map_err(|e| protocol_err(format!("Failed to index pack: {}", e), None))?;
+    
+    let (pack_path, index_path) = match (outcome.data_path, outcome.index_path) {
+        (Some(pack_path), Some(index_path)) => (pack_path, index_path),
+        _ => return Err(protocol_err("Pack was indexed but not written to disk", None)),
+    };
+    
+    // Check that everything the new objects point at is present
+    match verify_pack_connectivity(repo, &index_path) {
+        Ok(mut stats) => {
+            stats.pack_path = pack_path;
+            stats.index_path = index_path;
+            log::info!("Indexed pack with {} objects", stats.num_objects);
+            Ok(stats)
+        }
+        Err(e) => {
+            // Don't leave a pack with dangling references in the object store
+            let _ = std::fs::remove_file(&index_path);
+            let _ = std::fs::remove_file(&pack_path);
+            Err(e)
+        }
+    }
+}
+
+/// Walk the objects of a freshly written pack and check their references
+fn verify_pack_connectivity(repo: &Repository, index_path: &Path) -> Result<PackStats> {
+    let index = gix::odb::pack::index::File::at(index_path, repo.object_hash())
+        .map_err(|e| protocol_err(format!("Failed to open pack index: {}", e), None))?;
+    
+    let mut stats = PackStats {
+        num_objects: 0,
+        commits: 0,
+        trees: 0,
+        blobs: 0,
+        tags: 0,
+        pack_path: PathBuf::new(),
+        index_path: PathBuf::new(),
+    };
+    
+    let require = |id: ObjectId, from: ObjectId| -> Result<()> {
+        if repo.objects.contains(&id) {
+            Ok(())
+        } else {
+            Err(protocol_err(format!("Pack is missing object {} referenced by {}", id, from), None))
+        }
+    };
+    
+    for entry in index.iter() {
+        let object = repo.find_object(entry.oid)
+            .map_err(|e| protocol_err(format!("Object {} from pack is unreadable: {}", entry.oid, e), None))?;
+        stats.num_objects += 1;
+        
+        match object.kind {
+            gix::object::Kind::Commit => {
+                stats.commits += 1;
+                let commit = object.into_commit();
+                let tree_id = commit.tree_id()
+                    .map_err(|e| protocol_err(format!("Invalid commit {}: {}", entry.oid, e), None))?;
+                require(tree_id.detach(), entry.oid)?;
+                for parent in commit.parent_ids() {
+                    require(parent.detach(), entry.oid)?;
+                }
+            }
+            gix::object::Kind::Tree => {
+                stats.trees += 1;
+                let tree = object.into_tree();
+                let decoded = tree.decode()
+                    .map_err(|e| protocol_err(format!("Invalid tree {}: {}", entry.oid, e), None))?;
+                for child in &decoded.entries {
+                    // Submodule commits live in other repositories
+                    if child.mode.is_commit() {
+                        continue;
+                    }
+                    require(child.oid.to_owned(), entry.oid)?;
+                }
+            }
+            gix::object::Kind::Blob => stats.blobs += 1,
+            gix::object::Kind::Tag => {
+                stats.tags += 1;
+                let target = object.into_tag().target_id()
+                    .map_err(|e| protocol_err(format!("Invalid tag {}: {}", entry.oid, e), None))?;
+                require(target.detach(), entry.oid)?;
+            }
+        }
+    }
+    
+    Ok(stats)
+}
+
 /// Send a packfile containing the requested objects
+///
+/// Requests that would exceed `limits` are answered with a side-band error
+/// instead of a pack. Returns what the pack was made of.
 pub async fn send_packfile<S>(
     stream: &mut S,
     repo: &Repository, 
-    wanted_objects: &[ObjectId],
-    have_objects: &[ObjectId],
-) -> Result<()>
+    request: &UploadRequest,
+    limits: &PackLimits,
+) -> Result<SentPackStats>
 where
     S: AsyncWrite + Unpin,
 {
+    let wanted_objects = &request.wants;
     if wanted_objects.is_empty() {
         // No objects requested, send an empty flush packet
         stream.write_all(b"0000").await
             .map_err(|e| io_err(format!("Failed to write flush packet: {}", e)))?;
-        return Ok(());
+        return Ok(SentPackStats::default());
     }
 
     log::info!("Sending packfile with {} requested objects", wanted_objects.len());
@@ -537,171 +1188,60 @@ where
     // Channel 2: progress messages
     // Channel 3: error messages
 
-    // Create a packfile writer
-    let mut pack_builder = gix_pack::data::output::Builder::default();
-    
-    // Set up pack builder options
-    pack_builder
-        .set_thread_limit(num_cpus::get().min(4))
-        .use_reflog(true);
-    
-    // Send initial progress message
-    send_progress(stream, "Preparing packfile...").await?;
-
     // Start processing objects in a background task to avoid blocking
     let (tx, mut rx) = mpsc::channel::<Result<Vec<u8>>>(2);  // Buffer up to 2 chunks
-    let (progress_tx, mut progress_rx) = mpsc::channel::<String>(10); // Buffer for progress messages
+    let (progress_tx, mut progress_rx) = mpsc::channel::<PackProgress>(10); // Buffer for progress updates
     
     // Clone objects for the task
     let wanted_objects_clone = wanted_objects.to_vec();
-    let have_objects_clone = have_objects.to_vec();
+    let have_objects_clone = request.haves.clone();
+    let shallow_commits = request.shallow.as_ref().map(|plan| plan.commits.clone());
+    let ofs_delta = request.ofs_delta;
+    let resume = request.resume.clone();
+    let limits = *limits;
     let repo_path = repo.path().to_path_buf();
     
     // Spawn a task to build the packfile
     let pack_task = tokio::spawn(async move {
-        // Create a progress reporter
+        // Create a progress reporter; updates are dropped rather than stalling the pack
         let progress_tx_clone = progress_tx.clone();
-        let progress_reporter = move |msg: String| {
-            let tx = progress_tx_clone.clone();
-            let _ = tx.try_send(msg); // Ignore errors if channel is full
+        let progress_reporter = move |progress: PackProgress| {
+            let _ = progress_tx_clone.try_send(progress);
         };
         
-        let progress_clone = progress_reporter.clone();
-        
         // Open repository in the background task
         let repo = match Repository::open(repo_path) {
             Ok(r) => r,
             Err(e) => {
                 let _ = tx.send(Err(protocol_err(format!("Failed to open repository: {}", e), None))).await;
-                return;
-            }
-        };
-        
-        // Report progress
-        progress_reporter("Analyzing object graph...".to_string());
-        
-        // Find the commits that the client doesn't have
-        let mut objects_to_send = Vec::new();
-        
-        for wanted in &wanted_objects_clone {
-            // Check if client already has this object
-            if have_objects_clone.contains(wanted) {
-                continue;
+                return None;
             }
-            
-            // Check if object exists in the repository
-            match repo.find_object(*wanted) {
-                Ok(object) => {
-                    // Add object to the list
-                    objects_to_send.push(object.id);
-                    
-                    // Report progress
-                    progress_reporter(format!("Processing object {}", object.id));
-                },
-                Err(e) => {
-                    let err_msg = format!("Object not found: {}", e);
-                    let _ = tx.send(Err(protocol_err(err_msg, None))).await;
-                    return;
-                }
-            }
-        }
-        
-        // Create shallow boundary if client has any objects
-        let boundary = if !have_objects_clone.is_empty() {
-            Some(have_objects_clone)
-        } else {
-            None
         };
         
-        // Set up traversal with boundary
-        progress_reporter("Building object graph traversal...".to_string());
-        
-        let mut traversal_builder = repo.objects.traverse(objects_to_send)?
-            .with_deepen(true)  // Include all tree entries for tree objects
-            .with_objects(true);  // Include all reachable objects
-            
-        // Apply boundary if one exists
-        if let Some(ref boundary_objects) = boundary {
-            traversal_builder = traversal_builder.with_boundary(boundary_objects.clone());
-        }
-        
-        // Traverse object graph
-        let mut traversal = traversal_builder;
-        let mut object_count = 0;
-        
-        // Report total objects count if known
-        progress_reporter(format!("Traversing {} objects...", traversal.total_objects()));
-        
-        // Process objects in chunks
-        let mut objects_chunk = Vec::new();
-        let chunk_size = 1000;  // Process 1000 objects per chunk
-        
-        while let Some(obj_result) = traversal.next() {
-            let obj = match obj_result {
-                Ok(obj) => obj,
-                Err(e) => {
-                    let err_msg = format!("Failed to traverse object: {}", e);
-                    let _ = tx.send(Err(protocol_err(err_msg, None))).await;
-                    return;
-                }
-            };
-            
-            // Add to current chunk
-            objects_chunk.push(obj);
-            object_count += 1;
-            
-            // Process chunk when it reaches the desired size
-            if objects_chunk.len() >= chunk_size {
-                // Report progress
-                if object_count % 1000 == 0 {
-                    progress_reporter(format!("Processed {}/{} objects", 
-                                             object_count, traversal.total_objects()));
-                }
-                
-                // Add objects to the pack
-                for obj in &objects_chunk {
-                    if let Err(e) = pack_builder.add_object(obj.data.into(), obj.kind) {
-                        let err_msg = format!("Failed to add object to pack: {}", e);
-                        let _ = tx.send(Err(protocol_err(err_msg, None))).await;
-                        return;
-                    }
-                }
-                
-                // Clear the chunk for the next iteration
-                objects_chunk.clear();
-            }
-        }
-        
-        // Process any remaining objects
-        for obj in objects_chunk {
-            if let Err(e) = pack_builder.add_object(obj.data.into(), obj.kind) {
-                let err_msg = format!("Failed to add object to pack: {}", e);
-                let _ = tx.send(Err(protocol_err(err_msg, None))).await;
-                return;
-            }
-        }
-        
-        // Report final object count
-        progress_reporter(format!("Processed {} objects in total", object_count));
-        progress_reporter("Finalizing packfile...".to_string());
-        
-        // Finalize packfile data
-        let pack_data = match pack_builder.finish() {
-            Ok(data) => data,
+        // Build the pack outside of the network code
+        let (pack_data, stats) = match build_packfile(
+            &repo,
+            &wanted_objects_clone,
+            &have_objects_clone,
+            shallow_commits.as_deref(),
+            ofs_delta,
+            &limits,
+            &progress_reporter,
+        ) {
+            Ok(result) => result,
             Err(e) => {
-                let err_msg = format!("Failed to create packfile: {}", e);
-                let _ = tx.send(Err(protocol_err(err_msg, None))).await;
-                return;
+                let _ = tx.send(Err(e)).await;
+                return None;
             }
         };
         
-        // Report packfile size
-        progress_reporter(format!("Generated packfile: {} bytes", pack_data.len()));
-        
         // Send the packfile data in chunks that fit into the side-band-64k protocol
         // Max 65519 bytes per packet (65535 - 4 bytes for length prefix - 1 byte for channel - 11 bytes for overhead)
         const MAX_CHUNK_SIZE: usize = 65000;
-        let mut offset = 0;
+        let mut offset = resume.map_or(0, |resume| resume.skip_in(&pack_data));
+        if offset > 0 {
+            log::info!("Resuming pack at byte {} of {}", offset, pack_data.len());
+        }
         
         while offset < pack_data.len() {
             let chunk_size = std::cmp::min(MAX_CHUNK_SIZE, pack_data.len() - offset);
@@ -714,65 +1254,61 @@ where
             }
             
             offset += chunk_size;
-            
-            // Report progress periodically
-            if offset % (1024 * 1024) == 0 {  // Every 1MB
-                progress_reporter(format!("Sending packfile: {}/{} bytes ({:.1}%)", 
-                                         offset, pack_data.len(), 
-                                         (offset as f64 / pack_data.len() as f64) * 100.0));
+            if offset < pack_data.len() {
+                progress_reporter(PackProgress::Writing { bytes: offset, total: pack_data.len() });
             }
         }
         
-        // Report completion
-        progress_reporter(format!("Packfile transmission complete: {} objects, {} bytes", 
-                                 object_count, pack_data.len()));
+        // Unlike the updates in between, the line that ends the meter must not be dropped
+        if offset >= pack_data.len() {
+            let _ = progress_tx.send(PackProgress::Writing { bytes: offset, total: pack_data.len() }).await;
+        }
+        
+        log::debug!("Packfile transmission complete: {} objects, {} bytes", stats.objects, pack_data.len());
         
-        // Close the channel to signal completion
+        // Close the channels to signal completion
         drop(tx);
         drop(progress_tx);
+        Some(stats)
     });
     
-    // Process progress messages
-    let progress_handler = tokio::spawn(async move {
-        while let Some(msg) = progress_rx.recv().await {
-            // Forward progress to the client
-            if let Err(e) = send_progress(stream, &msg).await {
-                log::error!("Failed to send progress message: {}", e);
-                break;
-            }
-        }
-    });
-    
-    // Process packfile chunks as they become available
-    while let Some(chunk_result) = rx.recv().await {
-        match chunk_result {
-            Ok(chunk) => {
-                // Send the chunk with the data channel prefix
-                send_packet_on_channel(stream, PackProtocolChannel::Data, &chunk).await?;
+    // Interleave progress updates with packfile chunks as they become available
+    let mut chunks_open = true;
+    let mut progress_open = true;
+    while chunks_open || progress_open {
+        tokio::select! {
+            chunk_result = rx.recv(), if chunks_open => match chunk_result {
+                Some(Ok(chunk)) => {
+                    // Send the chunk with the data channel prefix
+                    send_packet_on_channel(stream, PackProtocolChannel::Data, &chunk).await?;
+                },
+                Some(Err(e)) => {
+                    // Send error message
+                    send_error(stream, &format!("Packfile generation error: {}", e)).await?;
+                    return Err(e);
+                },
+                None => chunks_open = false,
+            },
+            progress = progress_rx.recv(), if progress_open => match progress {
+                Some(progress) => send_progress(stream, &progress.to_string()).await?,
+                None => progress_open = false,
             },
-            Err(e) => {
-                // Send error message
-                send_error(stream, &format!("Packfile generation error: {}", e)).await?;
-                return Err(e);
-            }
         }
     }
     
-    // Wait for progress handler to complete
-    let _ = progress_handler.await;
-    
     // Wait for pack task to complete (it should be done by now)
-    let _ = pack_task.await;
-    
-    // Send completion message
-    send_progress(stream, "Pack transfer complete").await?;
+    let stats = match pack_task.await {
+        Ok(Some(stats)) => stats,
+        Ok(None) => SentPackStats::default(),
+        Err(e) => return Err(protocol_err(format!("Pack generation task failed: {}", e), None)),
+    };
     
     // Send flush packet to indicate end of packfile
     stream.write_all(b"0000").await
         .map_err(|e| io_err(format!("Failed to write final flush packet: {}", e)))?;
     
-    log::info!("Packfile sent successfully");
-    Ok(())
+    log::info!("Packfile sent: {}", stats);
+    Ok(stats)
 }
 
 /// Send a message on the progress channel
@@ -817,55 +1353,16 @@ where
     Ok(())
 }
 
-/// Parse a pkt-line from a stream
-async fn read_pkt_line<S>(stream: &mut S) -> Result<Option<Vec<u8>>>
-where
-    S: AsyncRead + Unpin,
-{
-    let mut length_buf = [0u8; 4];
-    
-    // Read packet length
-    match stream.read_exact(&mut length_buf).await {
-        Ok(_) => {},
-        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-            // End of stream reached
-            return Ok(None);
-        },
-        Err(e) => {
-            return Err(io_err(format!("Failed to read packet length: {}", e)));
-        }
-    }
-    
-    let length_str = std::str::from_utf8(&length_buf)
-        .map_err(|_| protocol_err("Invalid packet length encoding", None))?;
-        
-    // Check for flush packet
-    if length_str == "0000" {
-        return Ok(Some(Vec::new()));  // Empty vec represents flush packet
-    }
-    
-    // Parse length
-    let length = u16::from_str_radix(length_str, 16)
-        .map_err(|_| protocol_err("Invalid packet length", None))?;
-        
-    if length < 4 {
-        return Err(protocol_err("Invalid packet length", None));
-    }
-    
-    // Read packet data
-    let data_length = length as usize - 4;  // Subtract the 4 bytes of the length header
-    let mut data = vec![0; data_length];
-    
-    stream.read_exact(&mut data).await
-        .map_err(|e| io_err(format!("Failed to read packet data: {}", e)))?;
-        
-    Ok(Some(data))
-}
-
 /// Process Git receive-pack (push) requests
+///
+/// `repo_path` is the path the client asked for; it binds push certificate
+/// nonces to the repository. Ref updates are refused when `push_cert`
+/// requires a signed push and no valid certificate was sent.
 pub async fn receive_packfile<S>(
     stream: &mut S, 
-    repo: &Repository
+    repo: &Repository,
+    repo_path: &str,
+    push_cert: &PushCertPolicy,
 ) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -874,10 +1371,13 @@ where
     
     // First, read the client's reference updates
     let mut ref_updates = HashMap::new();
+    let mut commands = Vec::new();
+    let mut certificate = None;
+    let mut atomic = false;
     
     // Read reference update commands
     loop {
-        let line = match read_pkt_line(stream).await? {
+        let line = match read_pkt_line(stream, MAX_PKT_LINE_LENGTH).await? {
             Some(data) if data.is_empty() => {
                 // Flush packet - end of reference updates
                 break;
@@ -891,7 +1391,40 @@ where
         // Parse reference update command
         let line_str = std::str::from_utf8(&line)
             .map_err(|_| protocol_err("Invalid UTF-8 in packet", None))?;
+        
+        // The first line, a command or the start of a certificate, carries the
+        // client's capabilities after a NUL
+        let line_str = match line_str.split_once('\0') {
+            Some((command, capabilities)) => {
+                atomic |= capabilities.split_whitespace().any(|cap| cap == "atomic");
+                command
+            },
+            None => line_str,
+        };
+        
+        // A signed push carries its commands inside a certificate
+        if line_str.trim_end_matches('\n') == PUSH_CERT_BEGIN {
+            let mut cert_lines = Vec::new();
+            loop {
+                let cert_line = read_pkt_line(stream, MAX_PKT_LINE_LENGTH).await?
+                    .ok_or_else(|| protocol_err("Unexpected end of stream in push certificate", None))?;
+                let cert_line = String::from_utf8(cert_line)
+                    .map_err(|_| protocol_err("Invalid UTF-8 in push certificate", None))?;
+                if cert_line.trim_end_matches('\n') == PUSH_CERT_END {
+                    break;
+                }
+                cert_lines.push(cert_line);
+            }
             
+            let cert = PushCertificate::parse(&cert_lines)?;
+            for update in &cert.updates {
+                let zero = |id: &ObjectId| if id.is_null() { None } else { Some(*id) };
+                ref_updates.insert(update.name.clone(), (zero(&update.old), zero(&update.new)));
+            }
+            certificate = Some(cert);
+            continue;
+        }
+        
         // Reference update format: <old-oid> <new-oid> <ref-name>
         let parts: Vec<&str> = line_str.split_whitespace().collect();
         if parts.len() >= 3 {
@@ -924,6 +1457,11 @@ where
                       old_oid.map_or("null".to_string(), |o| o.to_hex().to_string()),
                       new_oid.map_or("null".to_string(), |o| o.to_hex().to_string()));
             
+            commands.push(CertifiedUpdate {
+                old: old_oid.unwrap_or_else(|| ObjectId::null(gix_hash::Kind::Sha1)),
+                new: new_oid.unwrap_or_else(|| ObjectId::null(gix_hash::Kind::Sha1)),
+                name: ref_name.to_string(),
+            });
             ref_updates.insert(ref_name.to_string(), (old_oid, new_oid));
         }
     }
@@ -942,7 +1480,7 @@ where
     
     // Read packfile data
     loop {
-        let data = match read_pkt_line(stream).await? {
+        let data = match read_pkt_line(stream, MAX_PKT_LINE_LENGTH).await? {
             Some(data) if data.is_empty() => {
                 // Flush packet - end of packfile
                 break;
@@ -963,67 +1501,67 @@ where
     stream.write_all(b"0010unpack ok\n").await
         .map_err(|e| io_err(format!("Failed to write unpack status: {}", e)))?;
     
+    // Check the push certificate before touching any refs
+    let authorization = match &certificate {
+        Some(cert) => {
+            let covered = if commands.is_empty() { &cert.updates } else { &commands };
+            push_cert.verify(repo_path, cert, covered)
+        },
+        None if push_cert.required => {
+            Err(GitError::Authentication("this repository requires signed pushes (push-cert)".to_string()))
+        },
+        None => Ok(()),
+    };
+    if let Err(e) = &authorization {
+        log::warn!("Rejecting push to {}: {}", repo_path, e);
+    }
+    
     // Apply the reference updates
     let mut results = Vec::new();
     
+    // An atomic push updates every ref in one transaction or none of them
+    if atomic && authorization.is_ok() {
+        let outcome = ref_updates.iter()
+            .map(|(ref_name, (old_oid, new_oid))| ref_edit(ref_name, *old_oid, *new_oid))
+            .collect::<Result<Vec<_>>>()
+            .and_then(|edits| crate::core::edit_references(repo, edits));
+        let reason = match &outcome {
+            Ok(_) => None,
+            Err(GitError::Locked(_)) => Some("another operation is in progress"),
+            Err(e) => {
+                log::error!("Atomic push to {} failed: {}", repo_path, e);
+                Some("atomic push failed")
+            }
+        };
+        for ref_name in ref_updates.keys() {
+            results.push(match reason {
+                None => format!("ok {}", ref_name),
+                Some(reason) => format!("ng {} {}", ref_name, reason),
+            });
+        }
+        ref_updates.clear();
+    }
+    
     for (ref_name, (old_oid, new_oid)) in ref_updates {
-        let result = match (old_oid, new_oid) {
-            (_, None) => {
-                // Delete reference
-                match repo.references.delete(&ref_name) {
-                    Ok(_) => {
-                        log::info!("Deleted reference: {}", ref_name);
-                        format!("ok {}", ref_name)
-                    },
-                    Err(e) => {
-                        log::error!("Failed to delete reference {}: {}", ref_name, e);
-                        format!("ng {} deletion failed", ref_name)
-                    }
-                }
+        if let Err(e) = &authorization {
+            results.push(format!("ng {} {}", ref_name, e));
+            continue;
+        }
+        
+        let result = match apply_ref_update(repo, &ref_name, old_oid, new_oid) {
+            Ok(()) => {
+                log::info!("Updated reference {}: {} -> {}", ref_name,
+                          old_oid.map_or("null".to_string(), |o| o.to_hex().to_string()),
+                          new_oid.map_or("null".to_string(), |o| o.to_hex().to_string()));
+                format!("ok {}", ref_name)
             },
-            (None, Some(new_id)) => {
-                // Create new reference
-                match repo.references.create(&ref_name, new_id, false, &format!("push: created {}", ref_name)) {
-                    Ok(_) => {
-                        log::info!("Created reference: {} -> {}", ref_name, new_id);
-                        format!("ok {}", ref_name)
-                    },
-                    Err(e) => {
-                        log::error!("Failed to create reference {}: {}", ref_name, e);
-                        format!("ng {} creation failed", ref_name)
-                    }
-                }
+            Err(GitError::Locked(_)) => {
+                log::warn!("Reference {} is locked by another operation", ref_name);
+                format!("ng {} another operation is in progress", ref_name)
             },
-            (Some(old_id), Some(new_id)) => {
-                // Update existing reference
-                // First verify that the old value matches what we expect
-                match repo.references.find(&ref_name) {
-                    Ok(existing_ref) => {
-                        if existing_ref.id() != old_id {
-                            log::error!("Reference update failed: {} expected {}, found {}", 
-                                      ref_name, old_id, existing_ref.id());
-                            format!("ng {} expected old value {} was {}", 
-                                   ref_name, old_id, existing_ref.id())
-                        } else {
-                            // Update the reference
-                            match repo.references.create_matching(&ref_name, new_id, false, old_id, 
-                                                                &format!("push: update {}", ref_name)) {
-                                Ok(_) => {
-                                    log::info!("Updated reference: {} {} -> {}", ref_name, old_id, new_id);
-                                    format!("ok {}", ref_name)
-                                },
-                                Err(e) => {
-                                    log::error!("Failed to update reference {}: {}", ref_name, e);
-                                    format!("ng {} update failed", ref_name)
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        log::error!("Failed to find reference {}: {}", ref_name, e);
-                        format!("ng {} not found", ref_name)
-                    }
-                }
+            Err(e) => {
+                log::error!("Failed to update reference {}: {}", ref_name, e);
+                format!("ng {} failed to update ref", ref_name)
             }
         };
         
@@ -1045,11 +1583,46 @@ where
     Ok(())
 }
 
+/// Apply one pushed ref update under the ref's lock
+fn apply_ref_update(repo: &Repository, ref_name: &str, old: Option<ObjectId>, new: Option<ObjectId>) -> Result<()> {
+    crate::core::edit_references(repo, Some(ref_edit(ref_name, old, new)?))?;
+    Ok(())
+}
+
+/// Describe a pushed ref update as a ref edit
+///
+/// The ref must still have the value the client based its push on, so a
+/// concurrent push or local operation can't be silently overwritten.
+fn ref_edit(ref_name: &str, old: Option<ObjectId>, new: Option<ObjectId>) -> Result<gix::refs::transaction::RefEdit> {
+    use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+    
+    let name = gix::refs::FullName::try_from(ref_name)
+        .map_err(|e| protocol_err(format!("Invalid reference name '{}': {}", ref_name, e), None))?;
+    let expected = match old {
+        Some(old) => PreviousValue::MustExistAndMatch(gix::refs::Target::Peeled(old)),
+        None => PreviousValue::MustNotExist,
+    };
+    let change = match new {
+        Some(new) => Change::Update {
+            log: LogChange {
+                mode: RefLog::AndReference,
+                force_create_reflog: false,
+                message: "push".into(),
+            },
+            expected,
+            new: gix::refs::Target::Peeled(new),
+        },
+        None => Change::Delete { expected, log: RefLog::AndReference },
+    };
+    Ok(RefEdit { change, name, deref: false })
+}
+
 /// Run the Git upload-pack service
 pub async fn handle_upload_pack<S>(
     stream: &mut S, 
     repo: &Repository,
-    command: &GitCommand
+    command: &GitCommand,
+    limits: &PackLimits,
 ) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -1063,10 +1636,10 @@ where
     send_refs_advertisement(stream, repo, command, &capabilities).await?;
     
     // Process wants/haves (negotiation)
-    let (wants, haves) = process_wants(stream, repo).await?;
+    let request = process_wants(stream, repo).await?;
     
     // Send packfile with requested objects
-    send_packfile(stream, repo, &wants, &haves).await?;
+    send_packfile(stream, repo, &request, limits).await?;
     
     log::info!("git-upload-pack command completed successfully");
     Ok(())
@@ -1076,21 +1649,24 @@ where
 pub async fn handle_receive_pack<S>(
     stream: &mut S, 
     repo: &Repository,
-    command: &GitCommand
+    command: &GitCommand,
+    push_cert: &PushCertPolicy,
 ) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     log::info!("Handling git-receive-pack command for {:?}", command.repo_path);
     
-    // Create capabilities object
-    let capabilities = ServerCapabilities::new();
+    // Advertise a fresh nonce so clients can sign their push
+    let repo_path = command.repo_path.to_string_lossy().to_string();
+    let mut capabilities = ServerCapabilities::new();
+    capabilities.add(&format!("push-cert={}", push_cert.nonce(&repo_path)), Some("git-receive-pack"));
     
     // Send references advertisement
     send_refs_advertisement(stream, repo, command, &capabilities).await?;
     
     // Process receive-pack request (push)
-    receive_packfile(stream, repo).await?;
+    receive_packfile(stream, repo, &repo_path, push_cert).await?;
     
     log::info!("git-receive-pack command completed successfully");
     Ok(())
@@ -1099,7 +1675,9 @@ where
 /// Handle a Git smart protocol connection
 pub async fn handle_connection<S>(
     stream: &mut S,
-    repo: &Repository
+    repo: &Repository,
+    push_cert: &PushCertPolicy,
+    limits: &PackLimits,
 ) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -1110,10 +1688,10 @@ where
     // Handle the command based on service type
     match command.service.as_str() {
         "git-upload-pack" => {
-            handle_upload_pack(stream, repo, &command).await?;
+            handle_upload_pack(stream, repo, &command, limits).await?;
         },
         "git-receive-pack" => {
-            handle_receive_pack(stream, repo, &command).await?;
+            handle_receive_pack(stream, repo, &command, push_cert).await?;
         },
         _ => {
             return Err(protocol_err(format!("Unsupported Git service: {}", command.service), None));
@@ -1121,4 +1699,270 @@ where
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(args: &[&str], cwd: &Path) {
+        let status = Command::new("git").args(args).current_dir(cwd).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn test_pack_progress_renders_like_git() {
+        assert_eq!(PackProgress::Enumerating { done: 5, total: 10 }.to_string(), "Enumerating objects: 50% (5/10)\r");
+        assert_eq!(PackProgress::Compressing { done: 10, total: 10 }.to_string(), "Compressing objects: 100% (10/10), done.\n");
+        assert_eq!(PackProgress::Writing { bytes: 2048, total: 4096 }.to_string(), "Writing objects: 50% (2.00 KiB)\r");
+        assert_eq!(PackProgress::Writing { bytes: 4096, total: 4096 }.to_string(), "Writing objects: 100% (4.00 KiB), done.\n");
+    }
+
+    #[test]
+    fn test_git_request_length() {
+        assert_eq!(git_request_length(b"002egit-upload-pack /project.git\0host=example\0"), Some(0x2e));
+        assert_eq!(git_request_length(b"git-receive-pack /project.git\0"), Some(GIT_REQUEST_PREFIX_LENGTH));
+        assert_eq!(git_request_length(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(git_request_length(b"\x16\x03\x01\x02\x00\x01\x00\x01"), None);
+        assert_eq!(git_request_length(b"ffffgit-upload-pack"), None);
+        assert_eq!(git_request_length(b"0032git"), None);
+        
+        let command = parse_git_request(b"002egit-upload-pack /project.git\0host=example\0").unwrap();
+        assert_eq!(command.service, "git-upload-pack");
+        assert_eq!(command.repo_path, PathBuf::from("project.git"));
+    }
+
+    #[test]
+    fn test_pack_limits() {
+        let limits = PackLimits::from_config(10, 0);
+        assert_eq!(limits.max_bytes, None);
+        assert!(limits.check_objects(10).is_ok());
+        assert!(limits.check_objects(11).is_err());
+        assert!(limits.check_bytes(u64::MAX).is_ok());
+        assert_eq!(PackLimits::from_config(0, 0), PackLimits::unlimited());
+    }
+
+    #[test]
+    fn test_pack_resume() {
+        let pack = b"PACK\0\0\0\x02\0\0\0\x01 some object data and a trailer".to_vec();
+        let resume = PackResume::for_prefix(&pack[..20]);
+        assert_eq!(PackResume::parse_capability(&resume.to_capability()), Some(resume.clone()));
+        assert_eq!(PackResume::parse_capability("resume-pack=abc:00"), None);
+        assert_eq!(PackResume::parse_capability("ofs-delta"), None);
+
+        assert_eq!(resume.skip_in(&pack), 20);
+        // A pack that changed in between is sent from the start
+        let mut changed = pack.clone();
+        changed[15] = b'X';
+        assert_eq!(resume.skip_in(&changed), 0);
+        assert_eq!(resume.skip_in(&pack[..10]), 0);
+    }
+
+    #[test]
+    fn test_pack_objects_from_alternate() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let shared = dir.path().join("shared");
+        let fork = dir.path().join("fork");
+        std::fs::create_dir_all(&shared).unwrap();
+        git(&["init", "-q"], &shared);
+        std::fs::write(shared.join("file.txt"), "content").unwrap();
+        git(&["add", "file.txt"], &shared);
+        git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Initial"], &shared);
+
+        // The fork stores no objects of its own
+        git(&["clone", "-q", "--shared", "shared", "fork"], dir.path());
+        assert!(fork.join(".git/objects/info/alternates").exists());
+
+        let repo = gix::open(&fork).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let pack = pack_objects(&repo, &[head], &[]).unwrap();
+        // Commit, tree and blob all come from the alternate
+        assert_eq!(u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]), 3);
+    }
+
+    #[test]
+    fn test_deltas_shrink_similar_revisions() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        git(&["init", "-q"], dir.path());
+        let mut lines: Vec<String> = (0..2000).map(|i| format!("line {} of {}\n", i, i * 7919 % 10007)).collect();
+        for revision in 0..20 {
+            lines[revision * 97] = format!("revision {}\n", revision);
+            std::fs::write(dir.path().join("file.txt"), lines.concat()).unwrap();
+            git(&["add", "file.txt"], dir.path());
+            git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Edit"], dir.path());
+        }
+
+        let repo = gix::open(dir.path()).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let build = |ofs_delta| {
+            build_packfile(&repo, &[head], &[], None, ofs_delta, &PackLimits::unlimited(), &|_| {}).unwrap()
+        };
+        let (whole, whole_stats) = build(false);
+        let (deltified, delta_stats) = build(true);
+
+        assert_eq!(whole_stats.objects, 60);
+        assert_eq!(whole_stats.deltas, 0);
+        assert_eq!(delta_stats.objects, 60);
+        // Every blob but the first, and most trees, are deltas
+        assert!(delta_stats.deltas >= 19, "{}", delta_stats);
+        assert!(deltified.len() * 4 < whole.len(), "{} vs {} bytes", deltified.len(), whole.len());
+
+        // Git accepts the pack and resolves every delta
+        let pack_path = dir.path().join("deltified.pack");
+        std::fs::write(&pack_path, &deltified).unwrap();
+        git(&["index-pack", "--strict", "deltified.pack"], dir.path());
+
+        // The byte limit applies to the compressed pack, not the objects
+        let sizes = Command::new("git")
+            .args(["cat-file", "--batch-all-objects", "--batch-check=%(objectsize)"])
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        let uncompressed: u64 = String::from_utf8(sizes.stdout).unwrap()
+            .lines()
+            .map(|size| size.parse::<u64>().unwrap())
+            .sum();
+        let fits = PackLimits::from_config(0, deltified.len() as u64);
+        assert!(uncompressed > deltified.len() as u64);
+        assert!(build_packfile(&repo, &[head], &[], None, true, &fits, &|_| {}).is_ok());
+        let too_small = PackLimits::from_config(0, deltified.len() as u64 / 2);
+        assert!(build_packfile(&repo, &[head], &[], None, true, &too_small, &|_| {}).is_err());
+    }
+
+    #[test]
+    fn test_sent_pack_stats_count_local_deltas() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        git(&["init", "-q"], dir.path());
+        let mut lines: Vec<String> = (0..500).map(|i| format!("line {}\n", i)).collect();
+        for revision in 0..5 {
+            lines[revision * 50] = format!("revision {}\n", revision);
+            std::fs::write(dir.path().join("file.txt"), lines.concat()).unwrap();
+            git(&["add", "file.txt"], dir.path());
+            git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Edit"], dir.path());
+        }
+        let stats = |repo: &Repository| {
+            let head = repo.head_id().unwrap().detach();
+            let (pack, stats) = build_packfile(repo, &[head], &[], None, true, &PackLimits::unlimited(), &|_| {}).unwrap();
+            assert_eq!(stats.objects, 15);
+            assert_eq!(stats.bases + stats.deltas, stats.objects);
+            assert_eq!(stats.pack_bytes, pack.len() as u64);
+            assert!(stats.compressed_bytes < stats.pack_bytes);
+            assert!(stats.uncompressed_bytes > 0);
+            stats
+        };
+
+        // Loose objects are never deltas locally
+        assert_eq!(stats(&gix::open(dir.path()).unwrap()).local_deltas, 0);
+
+        git(&["gc", "-q", "--aggressive"], dir.path());
+        let packed = stats(&gix::open(dir.path()).unwrap());
+        assert!(packed.local_deltas > 0, "{}", packed);
+        assert!(packed.local_deltas < packed.objects);
+    }
+
+    #[tokio::test]
+    async fn test_advertisement_peels_annotated_tags() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        git(&["init", "-q"], dir.path());
+        std::fs::write(dir.path().join("file.txt"), "content").unwrap();
+        git(&["add", "file.txt"], dir.path());
+        git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Initial"], dir.path());
+        git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "tag", "-a", "v1.0", "-m", "Release"], dir.path());
+        git(&["tag", "light"], dir.path());
+
+        let repo = gix::open(dir.path()).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let command = GitCommand::new("git-upload-pack".to_string(), PathBuf::from("repo"));
+
+        let mut output = Vec::new();
+        send_refs_advertisement(&mut output, &repo, &command, &ServerCapabilities::new()).await.unwrap();
+        let advertisement = String::from_utf8_lossy(&output);
+
+        // The peeled line follows the tag ref and names the commit
+        let peeled_line = format!("{} refs/tags/v1.0^{{}}", head.to_hex());
+        let tag_pos = advertisement.find(" refs/tags/v1.0").expect("tag advertised");
+        let peeled_pos = advertisement.find(&peeled_line).expect("peeled tag advertised");
+        assert!(peeled_pos > tag_pos);
+
+        // Lightweight tags already point at the commit and are not peeled
+        assert!(!advertisement.contains("refs/tags/light^{}"));
+    }
+
+    #[tokio::test]
+    async fn test_replacements_are_sent_only_when_wanted() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let commit = ["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-q"];
+        git(&["init", "-q", "-b", "main"], dir.path());
+        std::fs::write(dir.path().join("file.txt"), "content").unwrap();
+        git(&["add", "file.txt"], dir.path());
+        git(&[&commit[..], &["-m", "Initial"]].concat(), dir.path());
+
+        // Replace a side commit that main never reaches
+        git(&["checkout", "-q", "-b", "side"], dir.path());
+        std::fs::write(dir.path().join("other.txt"), "original").unwrap();
+        git(&["add", "other.txt"], dir.path());
+        git(&[&commit[..], &["-m", "Original"]].concat(), dir.path());
+        git(&["tag", "original"], dir.path());
+        std::fs::write(dir.path().join("other.txt"), "replacement").unwrap();
+        git(&["add", "other.txt"], dir.path());
+        git(&[&commit[..], &["--amend", "-m", "Replacement"]].concat(), dir.path());
+        git(&["replace", "original", "side"], dir.path());
+        git(&["checkout", "-q", "main"], dir.path());
+        git(&["branch", "-q", "-D", "side"], dir.path());
+
+        let repo = gix::open(dir.path()).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let command = GitCommand::new("git-upload-pack".to_string(), PathBuf::from("repo"));
+        let mut output = Vec::new();
+        send_refs_advertisement(&mut output, &repo, &command, &ServerCapabilities::new()).await.unwrap();
+        let advertisement = String::from_utf8_lossy(&output);
+
+        // The replace ref is advertised so clients can ask for it
+        let replace_line = advertisement.split("refs/replace/").next().unwrap();
+        let replacement = ObjectId::from_hex(replace_line[replace_line.len() - 41..][..40].as_bytes()).unwrap();
+
+        let object_count = |pack: &[u8]| u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]);
+        // Commit, tree and blob of main only
+        assert_eq!(object_count(&pack_objects(&repo, &[head], &[]).unwrap()), 3);
+        // Plus the replacement commit, its tree and new blob once wanted
+        assert_eq!(object_count(&pack_objects(&repo, &[head, replacement], &[]).unwrap()), 6);
+        assert_eq!(object_count(&pack_objects(&repo, &[replacement], &[head]).unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn test_signed_atomic_push_is_all_or_nothing() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        git(&["init", "-q", "-b", "main"], dir.path());
+        std::fs::write(dir.path().join("file.txt"), "content").unwrap();
+        git(&["add", "file.txt"], dir.path());
+        git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Initial"], dir.path());
+        let repo = gix::open(dir.path()).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let stale = ObjectId::from_hex(b"e69de29bb2d1d6434b8b29ae775ad8c2e48c5391").unwrap();
+
+        let keys = crate::crypto::KeyPair::generate();
+        let policy = PushCertPolicy::required(vec![keys.public_key()]);
+        // The new branch could be created, but main has moved on from `stale`
+        let updates = vec![
+            CertifiedUpdate { old: ObjectId::null(gix_hash::Kind::Sha1), new: head, name: "refs/heads/topic".to_string() },
+            CertifiedUpdate { old: stale, new: head, name: "refs/heads/main".to_string() },
+        ];
+        let cert = PushCertificate::sign(&keys, "repo", &policy.nonce("repo"), updates).unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut request = cert.to_pkt_lines(&["report-status", "atomic"]);
+        // End of commands, then an empty pack
+        request.extend_from_slice(b"00000000");
+        client.write_all(&request).await.unwrap();
+
+        receive_packfile(&mut server, &repo, "repo", &policy).await.unwrap();
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.contains("ng refs/heads/topic"), "{}", response);
+        assert!(response.contains("ng refs/heads/main"), "{}", response);
+        assert!(repo.try_find_reference("refs/heads/topic").unwrap().is_none());
+    }
+}
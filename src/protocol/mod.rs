@@ -1,16 +1,38 @@
 mod pack;
+mod delta;
 mod refs;
 mod negotiate;
 mod upload_pack;
 mod receive_pack;
 mod git_protocol;
+mod push_cert;
+mod shallow;
+mod verify;
+mod v2;
+mod pkt_line;
 
 pub use pack::{Pack, PackEntry, PackHeader};
+pub use delta::{PackBuilder, append_objects, encode_delta, apply_delta, DELTA_WINDOW, MAX_DELTA_DEPTH, MAX_DELTA_OBJECT_SIZE};
 pub use refs::Reference;
-pub use negotiate::{Negotiator, NegotiationResult};
+pub use negotiate::{
+    Negotiator, NegotiationResult, limit_haves, have_rounds, DEFAULT_MAX_HAVES, DEFAULT_MAX_NEGOTIATION_ROUNDS,
+    INITIAL_HAVES_PER_ROUND
+};
+pub use push_cert::{PushCertificate, PushCertPolicy, CertifiedUpdate, advertised_nonce};
+pub use shallow::{Deepen, ShallowRequest, ShallowPlan, plan_shallow};
+pub use v2::{
+    V2Request, ObjectInfoRequest, ObjectInfo, read_v2_request, lookup_object_info,
+    process_v2_object_info, OBJECT_INFO_COMMAND, FetchRequest, send_v2_capabilities,
+    process_v2_ls_refs, process_v2_fetch, serve_v2, LS_REFS_COMMAND, FETCH_COMMAND
+};
+pub use pkt_line::{read_pkt_line, parse_pkt_length, write_err_packet, MAX_PKT_LINE_LENGTH};
+pub use verify::{verify_pack, PackVerification, PackObjectInfo};
+pub(crate) use verify::check_index_checksum;
 pub use upload_pack::UploadPack;
 pub use receive_pack::ReceivePack;
 pub use git_protocol::{
-    GitCommand, parse_git_command, send_refs_advertisement, 
-    process_wants, send_packfile, receive_packfile, update_references
+    GitCommand, GitProtocolVersion, ServerCapabilities, parse_git_command, parse_git_request, git_request_length,
+    MAX_GIT_REQUEST_LENGTH, GIT_REQUEST_PREFIX_LENGTH, send_refs_advertisement,
+    process_wants, send_packfile, UploadRequest, receive_packfile, update_references, pack_objects,
+    index_pack, PackStats, SentPackStats, PackProgress, PackLimits, handle_upload_pack, handle_receive_pack, PackResume, RESUME_CAPABILITY
 };
\ No newline at end of file
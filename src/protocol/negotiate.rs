@@ -4,6 +4,64 @@ use bytes::Bytes;
 use crate::core::{Result, ObjectId, ObjectType};
 use crate::protocol::Reference;
 
+/// Capabilities this client knows how to use when fetching
+pub const CLIENT_FETCH_CAPABILITIES: &[&str] = &[
+    "multi_ack_detailed",
+    "side-band-64k",
+    "side-band",
+    "thin-pack",
+    "ofs-delta",
+    "shallow",
+    "deepen-since",
+    "deepen-relative",
+    "include-tag",
+];
+
+/// Default number of `have` lines offered before settling for a larger pack
+pub const DEFAULT_MAX_HAVES: usize = 256;
+
+/// Default number of rounds `have` lines are spread over: all in one
+pub const DEFAULT_MAX_NEGOTIATION_ROUNDS: usize = 1;
+
+/// `have` lines in the first of several negotiation rounds; each later
+/// round sends twice as many
+pub const INITIAL_HAVES_PER_ROUND: usize = 32;
+
+/// The `have` lines to send, given local commits newest first
+///
+/// Every `have` makes the remote walk more history, and over Tor that time
+/// adds up to more than a slightly bigger pack would take to download. Only
+/// the newest `max_haves` are offered (all of them when `max_haves` is 0); if
+/// the common history is older than that, the remote sends it again.
+pub fn limit_haves(haves: &[ObjectId], max_haves: usize) -> &[ObjectId] {
+    if max_haves == 0 || haves.len() <= max_haves {
+        haves
+    } else {
+        &haves[..max_haves]
+    }
+}
+
+/// Split `count` haves into negotiation rounds, returning each round's size
+///
+/// Rounds start at [`INITIAL_HAVES_PER_ROUND`] and double, so the remote
+/// can report that it has found enough common history before all haves are
+/// sent. Every round but the last is a round trip, which is what costs over
+/// Tor: with `max_rounds` set (0 for no limit), the last allowed round
+/// carries every remaining have.
+pub fn have_rounds(count: usize, max_rounds: usize) -> Vec<usize> {
+    let mut rounds = Vec::new();
+    let mut remaining = count;
+    let mut size = INITIAL_HAVES_PER_ROUND;
+    while remaining > 0 {
+        let last = max_rounds != 0 && rounds.len() + 1 >= max_rounds;
+        let round = if last { remaining } else { size.min(remaining) };
+        rounds.push(round);
+        remaining -= round;
+        size = size.saturating_mul(2);
+    }
+    rounds
+}
+
 /// The result of a negotiation with a remote repository
 #[derive(Debug, Clone)]
 pub struct NegotiationResult {
@@ -13,6 +71,32 @@ pub struct NegotiationResult {
     pub common_objects: Vec<ObjectId>,
     /// References to update after fetch
     pub refs_to_update: Vec<(String, ObjectId)>,
+    /// Git protocol version spoken with the remote (0, 1 or 2)
+    pub protocol_version: u8,
+    /// Capabilities supported by both sides and requested for this fetch
+    pub capabilities: Vec<String>,
+    /// Whether the remote may send a thin pack
+    pub thin_pack: bool,
+    /// Whether the pack is multiplexed over side-band channels
+    pub side_band: bool,
+    /// Shallow boundary commits reported by the remote
+    pub shallow: Vec<ObjectId>,
+    /// Formerly shallow commits whose parents the remote is now sending
+    pub unshallow: Vec<ObjectId>,
+    /// True when we offered local history but none of it is known to the remote,
+    /// meaning the remote will send its entire history
+    pub no_common_history: bool,
+    /// Local commits not offered because of the `have` limit
+    pub haves_skipped: usize,
+}
+
+impl NegotiationResult {
+    /// Check whether a capability was agreed on, ignoring any `=value` suffix
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|cap| cap.split('=').next() == Some(name))
+    }
 }
 
 /// Negotiator for determining which objects to fetch from a remote
@@ -23,6 +107,18 @@ pub struct Negotiator {
     wants: HashSet<ObjectId>,
     /// Objects that we already have
     haves: HashSet<ObjectId>,
+    /// Objects the remote acknowledged as common
+    acked: HashSet<ObjectId>,
+    /// Capabilities advertised by the remote
+    remote_capabilities: Vec<String>,
+    /// Protocol version announced by the remote
+    protocol_version: u8,
+    /// Shallow boundary commits sent by the remote
+    shallow: HashSet<ObjectId>,
+    /// Commits the remote unshallowed
+    unshallow: HashSet<ObjectId>,
+    /// Local commits left out by the `have` limit
+    haves_skipped: usize,
 }
 
 impl Negotiator {
@@ -32,9 +128,65 @@ impl Negotiator {
             remote_refs: HashMap::new(),
             wants: HashSet::new(),
             haves: HashSet::new(),
+            acked: HashSet::new(),
+            remote_capabilities: Vec::new(),
+            protocol_version: 0,
+            shallow: HashSet::new(),
+            unshallow: HashSet::new(),
+            haves_skipped: 0,
         }
     }
     
+    /// Set the capabilities advertised by the remote
+    pub fn set_remote_capabilities(&mut self, capabilities: &[String]) {
+        self.remote_capabilities = capabilities.to_vec();
+    }
+    
+    /// Set the protocol version announced by the remote
+    pub fn set_protocol_version(&mut self, version: u8) {
+        self.protocol_version = version;
+    }
+    
+    /// Record objects the remote acknowledged with `ACK`
+    pub fn add_acks(&mut self, ids: &[ObjectId]) {
+        for id in ids {
+            self.acked.insert(id.clone());
+        }
+    }
+    
+    /// Record shallow boundary commits sent by the remote
+    pub fn add_shallow(&mut self, ids: &[ObjectId]) {
+        for id in ids {
+            self.shallow.insert(id.clone());
+        }
+    }
+    
+    /// Record commits the remote no longer considers shallow
+    pub fn add_unshallow(&mut self, ids: &[ObjectId]) {
+        for id in ids {
+            self.unshallow.insert(id.clone());
+        }
+    }
+    
+    /// Capabilities that both the remote and this client support
+    pub fn agreed_capabilities(&self) -> Vec<String> {
+        let mut agreed: Vec<String> = self.remote_capabilities
+            .iter()
+            .filter(|cap| {
+                let name = cap.split('=').next().unwrap_or("");
+                CLIENT_FETCH_CAPABILITIES.contains(&name)
+            })
+            .cloned()
+            .collect();
+        
+        // side-band-64k supersedes side-band; requesting both is a protocol error
+        if agreed.iter().any(|cap| cap == "side-band-64k") {
+            agreed.retain(|cap| cap != "side-band");
+        }
+        
+        agreed
+    }
+    
     /// Add remote references
     pub fn add_remote_refs(&mut self, refs: &[Reference]) {
         for r in refs {
@@ -49,6 +201,11 @@ impl Negotiator {
         }
     }
     
+    /// Record how many local commits were left out by the `have` limit
+    pub fn set_haves_skipped(&mut self, skipped: usize) {
+        self.haves_skipped = skipped;
+    }
+    
     /// Add objects we want to fetch
     pub fn add_wants(&mut self, ids: &[ObjectId]) {
         for id in ids {
@@ -75,21 +232,82 @@ impl Negotiator {
             .cloned()
             .collect();
             
-        let common_objects: Vec<ObjectId> = self.wants
+        let mut common_objects: Vec<ObjectId> = self.wants
             .iter()
             .filter(|id| self.haves.contains(id))
             .cloned()
             .collect();
+        
+        // Objects the remote acknowledged are common as well
+        for id in &self.acked {
+            if !common_objects.contains(id) {
+                common_objects.push(id.clone());
+            }
+        }
             
         let refs_to_update: Vec<(String, ObjectId)> = self.remote_refs
             .iter()
             .map(|(name, id)| (name.clone(), id.clone()))
             .collect();
         
+        // Having local objects yet sharing none of them with the remote usually
+        // means the wrong remote was picked; flag it before a full download
+        let no_common_history = !self.haves.is_empty() && common_objects.is_empty();
+        if no_common_history && self.haves_skipped > 0 {
+            log::info!(
+                "No common history within the {} newest local commits ({} not offered); \
+                 the remote will send a larger pack",
+                self.haves.len(), self.haves_skipped
+            );
+        } else if no_common_history {
+            log::warn!(
+                "No common history with the remote: none of {} local commits are known to it, \
+                 so the full history will be fetched. Check that this is the intended remote.",
+                self.haves.len()
+            );
+        }
+        
+        let capabilities = self.agreed_capabilities();
+        let thin_pack = capabilities.iter().any(|cap| cap == "thin-pack");
+        let side_band = capabilities.iter().any(|cap| cap.starts_with("side-band"));
+        
         NegotiationResult {
             objects_to_fetch,
             common_objects,
             refs_to_update,
+            protocol_version: self.protocol_version,
+            capabilities,
+            thin_pack,
+            side_band,
+            shallow: self.shallow.iter().cloned().collect(),
+            unshallow: self.unshallow.iter().cloned().collect(),
+            no_common_history,
+            haves_skipped: self.haves_skipped,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_haves_keeps_newest() {
+        let haves: Vec<ObjectId> = (1..=5u8)
+            .map(|n| ObjectId::from_hex(&format!("{:040x}", n)).unwrap())
+            .collect();
+        assert_eq!(limit_haves(&haves, 2), &haves[..2]);
+        assert_eq!(limit_haves(&haves, 0).len(), 5);
+        assert_eq!(limit_haves(&haves, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_have_rounds_are_capped() {
+        assert_eq!(have_rounds(0, 3), Vec::<usize>::new());
+        assert_eq!(have_rounds(200, 1), vec![200]);
+        assert_eq!(have_rounds(200, 3), vec![32, 64, 104]);
+        assert_eq!(have_rounds(50, 3), vec![32, 18]);
+        assert_eq!(have_rounds(200, 0), vec![32, 64, 104]);
+        assert_eq!(have_rounds(500, 0), vec![32, 64, 128, 256, 20]);
+    }
+}
@@ -0,0 +1,119 @@
+//! Bounded pkt-line reading shared by the client and the server
+//!
+//! A pkt-line starts with four hex digits giving its length, prefix
+//! included. The protocol caps lines at 65520 bytes; a declared length over
+//! the cap is rejected before any buffer is allocated, so a peer can't make
+//! us reserve memory for data it never sends.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::core::{GitError, Result, protocol_err};
+
+/// Longest pkt-line the protocol allows, length prefix included
+pub const MAX_PKT_LINE_LENGTH: usize = 65520;
+
+/// Parse a length prefix, rejecting lengths over `max_length`
+///
+/// Lengths below 4 are the special packets (flush, delimiter, response end)
+/// and are returned as they are for the caller to interpret.
+pub fn parse_pkt_length(prefix: &[u8], max_length: usize) -> Result<usize> {
+    let length = std::str::from_utf8(prefix).ok()
+        .filter(|hex| hex.len() == 4)
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| protocol_err(format!("Invalid packet length {:?}", String::from_utf8_lossy(prefix)), None))?;
+    if length > max_length {
+        return Err(protocol_err(
+            format!("Packet length {} exceeds the maximum of {} bytes", length, max_length), None));
+    }
+    Ok(length)
+}
+
+/// Read one pkt-line of at most `max_length` bytes
+///
+/// Returns `None` if the stream ends before a new line starts and an empty
+/// line for a flush packet. Delimiter and response-end packets are errors;
+/// protocol v2 readers handle those themselves.
+pub async fn read_pkt_line<S>(stream: &mut S, max_length: usize) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 4];
+    match stream.read_exact(&mut prefix).await {
+        Ok(_) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(GitError::IO(format!("Failed to read packet length: {}", e), None)),
+    }
+
+    let length = parse_pkt_length(&prefix, max_length)?;
+    match length {
+        0 => return Ok(Some(Vec::new())),
+        1..=3 => return Err(protocol_err(format!("Unexpected special packet {:04x}", length), None)),
+        _ => {},
+    }
+
+    let mut data = vec![0u8; length - 4];
+    stream.read_exact(&mut data).await
+        .map_err(|e| GitError::IO(format!("Failed to read packet data: {}", e), None))?;
+    Ok(Some(data))
+}
+
+/// Send an `ERR` packet, which clients show as `remote error: <message>`
+///
+/// Only valid before the ref advertisement; once the side-band is up,
+/// errors go on its error channel instead.
+pub async fn write_err_packet<S>(stream: &mut S, message: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut line = format!("ERR {}", message);
+    // Keep a multi-byte character whole when cutting an overlong message
+    let mut end = line.len().min(MAX_PKT_LINE_LENGTH - 5);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line.truncate(end);
+    line.push('\n');
+
+    let packet = format!("{:04x}{}", line.len() + 4, line);
+    stream.write_all(packet.as_bytes()).await
+        .map_err(|e| GitError::IO(format!("Failed to send error packet: {}", e), None))?;
+    stream.flush().await
+        .map_err(|e| GitError::IO(format!("Failed to send error packet: {}", e), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_pkt_line_bounds() {
+        let mut input: &[u8] = b"0009hello0000";
+        assert_eq!(read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.unwrap(), Some(Vec::new()));
+        assert_eq!(read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.unwrap(), None);
+
+        // Rejected from the prefix alone, without waiting for the data
+        let mut input: &[u8] = b"fff1";
+        let err = read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum"), "{}", err);
+
+        let mut input: &[u8] = b"0009hello";
+        assert!(read_pkt_line(&mut input, 8).await.is_err());
+        let mut input: &[u8] = b"00zz";
+        assert!(read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.is_err());
+        let mut input: &[u8] = b"0001";
+        assert!(read_pkt_line(&mut input, MAX_PKT_LINE_LENGTH).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_err_packet() {
+        let mut output = Vec::new();
+        write_err_packet(&mut output, "access denied").await.unwrap();
+        assert_eq!(output, b"0016ERR access denied\n");
+
+        let mut output = Vec::new();
+        write_err_packet(&mut output, &"x".repeat(MAX_PKT_LINE_LENGTH)).await.unwrap();
+        assert_eq!(output.len(), MAX_PKT_LINE_LENGTH);
+        assert_eq!(parse_pkt_length(&output[..4], MAX_PKT_LINE_LENGTH).unwrap(), MAX_PKT_LINE_LENGTH);
+    }
+}
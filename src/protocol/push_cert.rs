@@ -0,0 +1,451 @@
+//! Signed pushes (`push-cert` capability)
+//!
+//! The server advertises `push-cert=<nonce>`. A signing client then sends its
+//! ref updates inside a certificate instead of as plain commands:
+//!
+//! ```text
+//! push-cert\0<capabilities>
+//! certificate version 0.1
+//! pusher <base64 ed25519 public key> <timestamp> +0000
+//! pushee <repository url>
+//! nonce <nonce>
+//!
+//! <old-oid> <new-oid> <ref-name>
+//! ...
+//! -----BEGIN ARTI-GIT SIGNATURE-----
+//! <base64 signature over everything above the signature>
+//! -----END ARTI-GIT SIGNATURE-----
+//! push-cert-end
+//! ```
+//!
+//! Nonces are stateless like git's: `<timestamp>-<HMAC-SHA1(secret, path:timestamp)>`,
+//! so the advertisement and the push may arrive on different Tor streams.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine as _, engine::general_purpose};
+use gix_hash::ObjectId;
+use sha1::{Digest, Sha1};
+
+use crate::core::{GitError, Result};
+use crate::crypto::{PublicKey, Signer, Verifier};
+
+/// Certificate format version
+const CERT_VERSION: &str = "0.1";
+
+/// First line of a certificate in the command list
+pub const PUSH_CERT_BEGIN: &str = "push-cert";
+
+/// Last line of a certificate in the command list
+pub const PUSH_CERT_END: &str = "push-cert-end";
+
+const SIGNATURE_BEGIN: &str = "-----BEGIN ARTI-GIT SIGNATURE-----";
+const SIGNATURE_END: &str = "-----END ARTI-GIT SIGNATURE-----";
+
+/// Default window in which a nonce is accepted
+const DEFAULT_NONCE_SLOP: Duration = Duration::from_secs(300);
+
+/// A single ref update covered by a certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertifiedUpdate {
+    /// Previous value (all zeros for a create)
+    pub old: ObjectId,
+    /// New value (all zeros for a delete)
+    pub new: ObjectId,
+    /// Full ref name
+    pub name: String,
+}
+
+/// A push certificate covering a set of ref updates
+#[derive(Debug, Clone)]
+pub struct PushCertificate {
+    /// Base64 public key of the pusher
+    pub pusher: String,
+    /// Seconds since the epoch when the certificate was made
+    pub timestamp: u64,
+    /// Repository the push is meant for
+    pub pushee: String,
+    /// Nonce advertised by the server
+    pub nonce: String,
+    /// Ref updates covered by the signature
+    pub updates: Vec<CertifiedUpdate>,
+    /// Signature over [`PushCertificate::payload`]
+    pub signature: Vec<u8>,
+}
+
+impl PushCertificate {
+    /// Create and sign a certificate for `updates`
+    pub fn sign(
+        signer: &dyn Signer,
+        pushee: &str,
+        nonce: &str,
+        updates: Vec<CertifiedUpdate>,
+    ) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut cert = Self {
+            pusher: general_purpose::STANDARD.encode(signer.public_key()),
+            timestamp,
+            pushee: pushee.to_string(),
+            nonce: nonce.to_string(),
+            updates,
+            signature: Vec::new(),
+        };
+
+        cert.signature = signer.sign(cert.payload().as_bytes())
+            .map_err(|e| GitError::Crypto(format!("Failed to sign push certificate: {}", e)))?;
+        Ok(cert)
+    }
+
+    /// The signed portion of the certificate
+    pub fn payload(&self) -> String {
+        let mut payload = format!(
+            "certificate version {}\npusher {} {} +0000\npushee {}\nnonce {}\n\n",
+            CERT_VERSION, self.pusher, self.timestamp, self.pushee, self.nonce
+        );
+        for update in &self.updates {
+            payload.push_str(&format!("{} {} {}\n", update.old, update.new, update.name));
+        }
+        payload
+    }
+
+    /// Encode the certificate as the pkt-lines sent in place of plain commands
+    ///
+    /// `capabilities` are attached to the first line, as they would be to the
+    /// first command. The caller sends the terminating flush.
+    pub fn to_pkt_lines(&self, capabilities: &[&str]) -> Vec<u8> {
+        let mut lines = vec![format!("{}\0{}\n", PUSH_CERT_BEGIN, capabilities.join(" "))];
+        lines.extend(self.payload().lines().map(|line| format!("{}\n", line)));
+        lines.push(format!("{}\n", SIGNATURE_BEGIN));
+        lines.push(format!("{}\n", general_purpose::STANDARD.encode(&self.signature)));
+        lines.push(format!("{}\n", SIGNATURE_END));
+        lines.push(format!("{}\n", PUSH_CERT_END));
+
+        let mut out = Vec::new();
+        for line in lines {
+            out.extend_from_slice(format!("{:04x}", line.len() + 4).as_bytes());
+            out.extend_from_slice(line.as_bytes());
+        }
+        out
+    }
+
+    /// Parse the certificate lines between `push-cert` and `push-cert-end`
+    pub fn parse(lines: &[String]) -> Result<Self> {
+        let invalid = |msg: &str| GitError::Protocol(format!("Invalid push certificate: {}", msg));
+        let mut lines = lines.iter().map(|line| line.trim_end_matches('\n'));
+
+        if lines.next() != Some(&*format!("certificate version {}", CERT_VERSION)) {
+            return Err(invalid("unsupported version"));
+        }
+
+        let pusher_line = lines.next()
+            .and_then(|line| line.strip_prefix("pusher "))
+            .ok_or_else(|| invalid("missing pusher"))?;
+        let mut pusher_parts = pusher_line.split(' ');
+        let pusher = pusher_parts.next().unwrap_or_default().to_string();
+        let timestamp = pusher_parts.next()
+            .and_then(|ts| ts.parse().ok())
+            .ok_or_else(|| invalid("missing timestamp"))?;
+
+        let pushee = lines.next()
+            .and_then(|line| line.strip_prefix("pushee "))
+            .ok_or_else(|| invalid("missing pushee"))?
+            .to_string();
+        let nonce = lines.next()
+            .and_then(|line| line.strip_prefix("nonce "))
+            .ok_or_else(|| invalid("missing nonce"))?
+            .to_string();
+        if lines.next() != Some("") {
+            return Err(invalid("missing blank line after header"));
+        }
+
+        let mut updates = Vec::new();
+        let mut signature = None;
+        while let Some(line) = lines.next() {
+            if line == SIGNATURE_BEGIN {
+                let encoded = lines.next().ok_or_else(|| invalid("missing signature"))?;
+                signature = Some(general_purpose::STANDARD.decode(encoded)
+                    .map_err(|_| invalid("signature is not valid base64"))?);
+                if lines.next() != Some(SIGNATURE_END) {
+                    return Err(invalid("unterminated signature"));
+                }
+                break;
+            }
+
+            let parts: Vec<&str> = line.splitn(3, ' ').collect();
+            if parts.len() != 3 {
+                return Err(invalid("malformed ref update"));
+            }
+            let old = ObjectId::from_hex(parts[0].as_bytes()).map_err(|_| invalid("malformed old id"))?;
+            let new = ObjectId::from_hex(parts[1].as_bytes()).map_err(|_| invalid("malformed new id"))?;
+            updates.push(CertifiedUpdate { old, new, name: parts[2].to_string() });
+        }
+
+        Ok(Self {
+            pusher,
+            timestamp,
+            pushee,
+            nonce,
+            updates,
+            signature: signature.ok_or_else(|| invalid("unsigned"))?,
+        })
+    }
+}
+
+/// Server-side rules for signed pushes
+#[derive(Clone)]
+pub struct PushCertPolicy {
+    /// Reject pushes that don't carry a valid certificate
+    pub required: bool,
+    /// Keys allowed to sign pushes
+    pub trusted_keys: Vec<PublicKey>,
+    /// Also accept certificates signed by other keys, such as per-push
+    /// ephemeral keys, checking them against the key they carry
+    ///
+    /// This is trust-on-use: the signature still binds the updates to the
+    /// nonce, but says nothing about who pushed.
+    pub trust_on_use: bool,
+    /// How long an advertised nonce stays valid
+    pub nonce_slop: Duration,
+    /// Secret used to make nonces unforgeable
+    nonce_secret: [u8; 32],
+}
+
+impl Default for PushCertPolicy {
+    fn default() -> Self {
+        Self {
+            required: false,
+            trusted_keys: Vec::new(),
+            trust_on_use: false,
+            nonce_slop: DEFAULT_NONCE_SLOP,
+            nonce_secret: rand::random(),
+        }
+    }
+}
+
+impl PushCertPolicy {
+    /// Create a policy that requires pushes to be signed by one of `trusted_keys`
+    pub fn required(trusted_keys: Vec<PublicKey>) -> Self {
+        Self {
+            required: true,
+            trusted_keys,
+            ..Self::default()
+        }
+    }
+
+    /// Build a policy from base64-encoded public keys
+    pub fn from_config(required: bool, trusted_keys: &[String]) -> Result<Self> {
+        let trusted_keys = trusted_keys
+            .iter()
+            .map(|key| PublicKey::from_base64(key)
+                .map_err(|e| GitError::Config(format!("Invalid trusted push key '{}': {}", key, e))))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            required,
+            trusted_keys,
+            ..Self::default()
+        })
+    }
+
+    /// Accept certificates from keys outside `trusted_keys`, trust-on-use
+    pub fn with_trust_on_use(mut self, enabled: bool) -> Self {
+        self.trust_on_use = enabled;
+        self
+    }
+
+    /// Generate the nonce to advertise for a repository
+    pub fn nonce(&self, repo_path: &str) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.nonce_at(repo_path, timestamp)
+    }
+
+    /// Nonce for a repository at a given time
+    fn nonce_at(&self, repo_path: &str, timestamp: u64) -> String {
+        let mac = hmac_sha1(&self.nonce_secret, format!("{}:{}", repo_path, timestamp).as_bytes());
+        format!("{}-{}", timestamp, hex::encode(mac))
+    }
+
+    /// Check that a nonce was issued by this server, for this repository, recently
+    fn check_nonce(&self, repo_path: &str, nonce: &str) -> Result<()> {
+        let timestamp: u64 = nonce.split('-').next()
+            .and_then(|ts| ts.parse().ok())
+            .ok_or_else(|| GitError::Authentication("Malformed push certificate nonce".to_string()))?;
+
+        if self.nonce_at(repo_path, timestamp) != nonce {
+            return Err(GitError::Authentication("Push certificate nonce was not issued by this server".to_string()));
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if now.saturating_sub(timestamp) > self.nonce_slop.as_secs() {
+            return Err(GitError::Authentication("Push certificate nonce has expired".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Verify a certificate against this policy
+    ///
+    /// `commands` are the ref updates the server is about to apply; they must
+    /// be exactly the updates the certificate covers.
+    pub fn verify(&self, repo_path: &str, cert: &PushCertificate, commands: &[CertifiedUpdate]) -> Result<()> {
+        self.check_nonce(repo_path, &cert.nonce)?;
+        if repository_name(pushee_path(&cert.pushee)) != repository_name(repo_path) {
+            return Err(GitError::Authentication(format!("Push certificate is for {}, not this repository", cert.pushee)));
+        }
+
+        let key = match self.trusted_keys.iter().find(|key| key.to_base64() == cert.pusher) {
+            Some(key) => key.clone(),
+            None if self.trust_on_use => {
+                log::info!("Accepting push signed by untrusted key {} (trust on use)", cert.pusher);
+                PublicKey::from_base64(&cert.pusher)
+                    .map_err(|e| GitError::Authentication(format!("Invalid pusher key {}: {}", cert.pusher, e)))?
+            },
+            None => return Err(GitError::Authentication(format!("Push signed by untrusted key {}", cert.pusher))),
+        };
+
+        let valid = key.verify(cert.payload().as_bytes(), &cert.signature)
+            .map_err(|e| GitError::Authentication(format!("Invalid push certificate signature: {}", e)))?;
+        if !valid {
+            return Err(GitError::Authentication("Push certificate signature does not match".to_string()));
+        }
+
+        if cert.updates != commands {
+            return Err(GitError::Authentication("Push certificate does not match the pushed ref updates".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// The path part of a `pushee` URL, or the whole value if it has no scheme
+fn pushee_path(pushee: &str) -> &str {
+    match pushee.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |start| &rest[start..]),
+        None => pushee,
+    }
+}
+
+/// A repository path without the slashes and `.git` suffix that don't change
+/// which repository it names
+fn repository_name(path: &str) -> &str {
+    let path = path.trim_matches('/');
+    path.strip_suffix(".git").unwrap_or(path).trim_end_matches('/')
+}
+
+/// Extract the nonce from an advertised `push-cert=<nonce>` capability
+pub fn advertised_nonce(capabilities: &[String]) -> Option<&str> {
+    capabilities.iter().find_map(|cap| cap.strip_prefix("push-cert="))
+}
+
+/// HMAC-SHA1 as used for git's push-cert nonces
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.update(message);
+    let inner = inner.finalize();
+
+    let mut outer = Sha1::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(inner);
+    outer.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{KeyPair, SigningMode};
+
+    fn update() -> CertifiedUpdate {
+        CertifiedUpdate {
+            old: ObjectId::null(gix_hash::Kind::Sha1),
+            new: ObjectId::from_hex(b"e69de29bb2d1d6434b8b29ae775ad8c2e48c5391").unwrap(),
+            name: "refs/heads/main".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keys = KeyPair::generate();
+        let policy = PushCertPolicy::required(vec![keys.public_key()]);
+        let nonce = policy.nonce("repo");
+
+        let cert = PushCertificate::sign(&keys, "tor+git://example.onion/repo", &nonce, vec![update()]).unwrap();
+        let lines: Vec<String> = cert.payload().lines()
+            .map(String::from)
+            .chain([SIGNATURE_BEGIN.to_string(), general_purpose::STANDARD.encode(&cert.signature), SIGNATURE_END.to_string()])
+            .collect();
+        let parsed = PushCertificate::parse(&lines).unwrap();
+
+        assert!(policy.verify("repo", &parsed, &[update()]).is_ok());
+        assert!(policy.verify("other-repo", &parsed, &[update()]).is_err());
+        assert!(policy.verify("repo", &parsed, &[]).is_err());
+    }
+
+    #[test]
+    fn test_pushee_must_name_the_repository() {
+        let keys = KeyPair::generate();
+        let policy = PushCertPolicy::required(vec![keys.public_key()]);
+        let nonce = policy.nonce("/repo.git");
+
+        let cert = PushCertificate::sign(&keys, "tor+git://example.onion/repo", &nonce, vec![update()]).unwrap();
+        assert!(policy.verify("/repo.git", &cert, &[update()]).is_ok());
+        let cert = PushCertificate::sign(&keys, "tor+git://example.onion/other.git", &nonce, vec![update()]).unwrap();
+        assert!(policy.verify("/repo.git", &cert, &[update()]).is_err());
+    }
+
+    #[test]
+    fn test_untrusted_key_rejected() {
+        let policy = PushCertPolicy::required(vec![KeyPair::generate().public_key()]);
+        let nonce = policy.nonce("repo");
+
+        let cert = PushCertificate::sign(&KeyPair::generate(), "repo", &nonce, vec![update()]).unwrap();
+        assert!(policy.verify("repo", &cert, &[update()]).is_err());
+    }
+
+    #[test]
+    fn test_ephemeral_key_trusted_on_use() {
+        let policy = PushCertPolicy::required(vec![KeyPair::generate().public_key()]);
+        let nonce = policy.nonce("repo");
+
+        // Each operation signs with a new key, recorded as the pusher
+        let mode = SigningMode::Ephemeral;
+        let first = PushCertificate::sign(mode.signer().as_ref(), "repo", &nonce, vec![update()]).unwrap();
+        let second = PushCertificate::sign(mode.signer().as_ref(), "repo", &nonce, vec![update()]).unwrap();
+        assert_ne!(first.pusher, second.pusher);
+
+        assert!(policy.verify("repo", &first, &[update()]).is_err());
+        let policy = policy.with_trust_on_use(true);
+        assert!(policy.verify("repo", &first, &[update()]).is_ok());
+
+        // The signature must still match the key it carries
+        let mut forged = first.clone();
+        forged.pusher = second.pusher.clone();
+        assert!(policy.verify("repo", &forged, &[update()]).is_err());
+    }
+
+    #[test]
+    fn test_advertised_nonce() {
+        let caps = vec!["report-status".to_string(), "push-cert=123-abc".to_string()];
+        assert_eq!(advertised_nonce(&caps), Some("123-abc"));
+        assert_eq!(advertised_nonce(&caps[..1]), None);
+    }
+}
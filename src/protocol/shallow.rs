@@ -0,0 +1,375 @@
+//! Server side of shallow fetches: `deepen`, `deepen-since`,
+//! `deepen-relative` and the client's existing `shallow` boundary
+//!
+//! Before the packfile the server tells the client which commits become
+//! shallow and which are no longer shallow, and only sends commits above the
+//! new boundary.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::core::{GitError, Result, ShallowUpdate, INFINITE_DEPTH};
+
+/// How far a client asked to deepen its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deepen {
+    /// `deepen <n>`: commits up to `n` levels below each want, or below the
+    /// current boundary with `deepen-relative`
+    Depth(u32),
+    /// `deepen-since <time>`: commits newer than a Unix time
+    Since(i64),
+}
+
+/// The shallow-related lines of an upload-pack request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShallowRequest {
+    /// Commits the client currently has without their parents
+    pub client_shallow: Vec<ObjectId>,
+    /// The requested depth, if any
+    pub deepen: Option<Deepen>,
+    /// Count `deepen` from the client's boundary instead of the wants
+    pub relative: bool,
+}
+
+impl ShallowRequest {
+    /// Record a request line if it is shallow-related
+    ///
+    /// Returns `false` for lines that belong to someone else.
+    pub fn parse_line(&mut self, line: &str) -> Result<bool> {
+        let line = line.trim_end();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "shallow" => {
+                let id = ObjectId::from_hex(argument.as_bytes())
+                    .map_err(|_| GitError::Protocol(format!("Invalid shallow line: {}", line)))?;
+                self.client_shallow.push(id);
+            },
+            "deepen" => {
+                let depth = argument.parse::<u32>()
+                    .ok()
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(|| GitError::Protocol(format!("Invalid deepen line: {}", line)))?;
+                self.set_deepen(Deepen::Depth(depth))?;
+            },
+            "deepen-since" => {
+                let time = argument.parse::<i64>()
+                    .map_err(|_| GitError::Protocol(format!("Invalid deepen-since line: {}", line)))?;
+                self.set_deepen(Deepen::Since(time))?;
+            },
+            "deepen-relative" => self.relative = true,
+            "deepen-not" => {
+                return Err(GitError::Protocol("deepen-not is not supported".to_string()));
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn set_deepen(&mut self, deepen: Deepen) -> Result<()> {
+        if self.deepen.is_some() {
+            return Err(GitError::Protocol("deepen and deepen-since cannot be combined".to_string()));
+        }
+        self.deepen = Some(deepen);
+        Ok(())
+    }
+
+    /// Check whether the client asked to change its history depth
+    pub fn is_deepening(&self) -> bool {
+        self.deepen.is_some()
+    }
+}
+
+/// What a shallow fetch sends
+#[derive(Debug, Clone, Default)]
+pub struct ShallowPlan {
+    /// `shallow`/`unshallow` lines for the client
+    pub update: ShallowUpdate,
+    /// Commits to pack, newest first; the client has none of them
+    pub commits: Vec<ObjectId>,
+}
+
+/// Work out the new boundary and the commits to send for a deepening request
+pub fn plan_shallow(
+    repo: &Repository,
+    wants: &[ObjectId],
+    haves: &[ObjectId],
+    request: &ShallowRequest,
+) -> Result<ShallowPlan> {
+    let deepen = match request.deepen {
+        Some(deepen) => deepen,
+        None => return Err(GitError::Protocol("Shallow plan requested without deepen".to_string())),
+    };
+    let client_shallow: HashSet<ObjectId> = request.client_shallow.iter().copied().collect();
+    let client_has = client_commits(repo, haves, &client_shallow)?;
+
+    let mut commits = CommitCache::new(repo);
+    let mut boundary = Vec::new();
+    let mut expanded = HashSet::new();
+    let mut order = Vec::new();
+
+    // `None` means above the boundary the depth is counted from
+    let mut depths: HashMap<ObjectId, Option<u32>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    let counted_from_wants = !request.relative || matches!(deepen, Deepen::Since(_));
+    for want in wants {
+        let depth = if counted_from_wants { Some(1) } else { None };
+        if depths.insert(*want, depth).is_none() {
+            queue.push_back((*want, depth));
+        }
+    }
+    if !counted_from_wants {
+        // Relative depth is counted from the commits the client stopped at
+        for id in &request.client_shallow {
+            depths.insert(*id, Some(0));
+            queue.push_back((*id, Some(0)));
+        }
+    }
+
+    while let Some((id, depth)) = queue.pop_front() {
+        let relative_seed = depth == Some(0);
+        // Above the boundary, history the client has needs no walking
+        if depth.is_none() && client_has.contains(&id) {
+            continue;
+        }
+        if !relative_seed && !client_has.contains(&id) {
+            order.push(id);
+        }
+
+        let (parents, _) = commits.get(id)?;
+        if parents.is_empty() {
+            continue;
+        }
+
+        let stop = match (deepen, depth) {
+            (Deepen::Depth(limit), Some(depth)) => limit < INFINITE_DEPTH && depth >= limit,
+            (Deepen::Depth(_), None) => false,
+            (Deepen::Since(cutoff), _) => {
+                let mut older = false;
+                for parent in parents.clone() {
+                    older |= commits.get(parent)?.1 < cutoff;
+                }
+                older
+            },
+        };
+        if stop {
+            boundary.push(id);
+            continue;
+        }
+
+        expanded.insert(id);
+        for parent in parents {
+            let parent_depth = match depth {
+                Some(depth) => Some(depth + 1),
+                None if client_shallow.contains(&id) => Some(1),
+                None => None,
+            };
+            // Breadth-first order reaches each commit at its smallest depth first
+            if !depths.contains_key(&parent) {
+                depths.insert(parent, parent_depth);
+                queue.push_back((parent, parent_depth));
+            }
+        }
+    }
+
+    let update = ShallowUpdate {
+        shallow: boundary.into_iter().filter(|id| !client_shallow.contains(id)).collect(),
+        unshallow: request.client_shallow.iter().copied().filter(|id| expanded.contains(id)).collect(),
+    };
+    Ok(ShallowPlan { update, commits: order })
+}
+
+/// Commits the client has: everything reachable from its haves, down to its
+/// shallow boundary
+fn client_commits(repo: &Repository, haves: &[ObjectId], client_shallow: &HashSet<ObjectId>) -> Result<HashSet<ObjectId>> {
+    let mut commits = CommitCache::new(repo);
+    let mut seen = HashSet::new();
+    let mut stack: Vec<ObjectId> = haves.iter()
+        .copied()
+        .filter(|id| repo.objects.contains(id))
+        .collect();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) || client_shallow.contains(&id) {
+            continue;
+        }
+        match commits.get(id) {
+            Ok((parents, _)) => stack.extend(parents),
+            // A have may name a tag or tree; only commits carry history
+            Err(_) => continue,
+        }
+    }
+    Ok(seen)
+}
+
+/// All objects needed for `commits`: the commits, their trees and blobs
+pub fn shallow_pack_objects(repo: &Repository, commits: &[ObjectId]) -> Result<Vec<ObjectId>> {
+    let mut objects = Vec::new();
+    let mut seen = HashSet::new();
+    for id in commits {
+        let commit = repo.find_object(*id)
+            .map_err(|e| GitError::Protocol(format!("Object not found: {}", e)))?
+            .try_into_commit()
+            .map_err(|e| GitError::Protocol(format!("{} is not a commit: {}", id, e)))?;
+        let tree = commit.tree_id()
+            .map_err(|e| GitError::Protocol(format!("Invalid commit {}: {}", id, e)))?
+            .detach();
+        objects.push(*id);
+
+        let mut trees = vec![tree];
+        while let Some(tree_id) = trees.pop() {
+            if !seen.insert(tree_id) {
+                continue;
+            }
+            objects.push(tree_id);
+            let tree = repo.find_object(tree_id)
+                .map_err(|e| GitError::Protocol(format!("Object not found: {}", e)))?
+                .into_tree();
+            let decoded = tree.decode()
+                .map_err(|e| GitError::Protocol(format!("Invalid tree {}: {}", tree_id, e)))?;
+            for entry in &decoded.entries {
+                let child = entry.oid.to_owned();
+                if entry.mode.is_tree() {
+                    trees.push(child);
+                } else if entry.mode.is_commit() {
+                    // Submodule commits live in other repositories
+                    continue;
+                } else if seen.insert(child) {
+                    objects.push(child);
+                }
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// Parents and commit times, each commit decoded once
+struct CommitCache<'r> {
+    repo: &'r Repository,
+    commits: HashMap<ObjectId, (Vec<ObjectId>, i64)>,
+}
+
+impl<'r> CommitCache<'r> {
+    fn new(repo: &'r Repository) -> Self {
+        Self { repo, commits: HashMap::new() }
+    }
+
+    fn get(&mut self, id: ObjectId) -> Result<(Vec<ObjectId>, i64)> {
+        if let Some(info) = self.commits.get(&id) {
+            return Ok(info.clone());
+        }
+        let commit = self.repo.find_object(id)
+            .map_err(|e| GitError::Protocol(format!("Commit {} not found: {}", id, e)))?
+            .try_into_commit()
+            .map_err(|e| GitError::Protocol(format!("{} is not a commit: {}", id, e)))?;
+        let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+        let time = commit.time()
+            .map(|time| time.seconds as i64)
+            .map_err(|e| GitError::Protocol(format!("Invalid commit {}: {}", id, e)))?;
+        self.commits.insert(id, (parents.clone(), time));
+        Ok((parents, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git").args(args).current_dir(dir).output().unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    fn id(hex: &str) -> ObjectId {
+        ObjectId::from_hex(hex.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_depth_relative_and_unshallow() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        let mut history = Vec::new();
+        for i in 0..5 {
+            std::fs::write(dir.path().join("file.txt"), format!("{}\n", i)).unwrap();
+            git(dir.path(), &["add", "file.txt"]);
+            git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", &i.to_string()]);
+            history.push(id(&git(dir.path(), &["rev-parse", "HEAD"])));
+        }
+        let repo = gix::open(dir.path()).unwrap();
+        let tip = history[4];
+
+        // Depth 2 from the tip: the second commit down is the boundary
+        let mut request = ShallowRequest::default();
+        assert!(request.parse_line("deepen 2").unwrap());
+        let plan = plan_shallow(&repo, &[tip], &[], &request).unwrap();
+        assert_eq!(plan.update.shallow, vec![history[3]]);
+        assert_eq!(plan.commits, vec![history[4], history[3]]);
+
+        // Deepen that clone by one more commit
+        let mut request = ShallowRequest::default();
+        for line in [format!("shallow {}", history[3]), "deepen 1".to_string(), "deepen-relative".to_string()] {
+            request.parse_line(&line).unwrap();
+        }
+        let plan = plan_shallow(&repo, &[tip], &[tip], &request).unwrap();
+        assert_eq!(plan.update.shallow, vec![history[2]]);
+        assert_eq!(plan.update.unshallow, vec![history[3]]);
+        assert_eq!(plan.commits, vec![history[2]]);
+
+        // Unshallow: everything below the old boundary, no new boundary
+        let mut request = ShallowRequest::default();
+        request.parse_line(&format!("shallow {}", history[2])).unwrap();
+        request.parse_line(&format!("deepen {}", INFINITE_DEPTH)).unwrap();
+        let plan = plan_shallow(&repo, &[tip], &[tip], &request).unwrap();
+        assert!(plan.update.shallow.is_empty());
+        assert_eq!(plan.update.unshallow, vec![history[2]]);
+        assert_eq!(plan.commits, vec![history[1], history[0]]);
+
+        // Depth beyond the history length has no boundary
+        let mut request = ShallowRequest::default();
+        request.parse_line("deepen 50").unwrap();
+        assert!(plan_shallow(&repo, &[tip], &[], &request).unwrap().update.shallow.is_empty());
+    }
+
+    #[test]
+    fn test_depth_through_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let commit = |message: &str| {
+            std::fs::write(dir.path().join(format!("{}.txt", message)), message).unwrap();
+            git(dir.path(), &["add", "."]);
+            git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", message]);
+            id(&git(dir.path(), &["rev-parse", "HEAD"]))
+        };
+        git(dir.path(), &["init", "-q"]);
+        let base = commit("base");
+        let trunk = git(dir.path(), &["rev-parse", "--abbrev-ref", "HEAD"]);
+        git(dir.path(), &["checkout", "-qb", "side"]);
+        let side = commit("side");
+        git(dir.path(), &["checkout", "-q", &trunk]);
+        let main = commit("main");
+        git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "merge", "-q", "--no-edit", "side"]);
+        let merge = id(&git(dir.path(), &["rev-parse", "HEAD"]));
+        let repo = gix::open(dir.path()).unwrap();
+
+        // Both parents of the merge are one level down and become the boundary
+        let mut request = ShallowRequest::default();
+        request.parse_line("deepen 2").unwrap();
+        let plan = plan_shallow(&repo, &[merge], &[], &request).unwrap();
+        assert_eq!(plan.commits, vec![merge, main, side]);
+        let mut boundary = plan.update.shallow.clone();
+        boundary.sort();
+        let mut expected = vec![main, side];
+        expected.sort();
+        assert_eq!(boundary, expected);
+
+        // The shared root is reached through both parents but sent once, and
+        // as a root it never becomes shallow
+        let mut request = ShallowRequest::default();
+        request.parse_line("deepen 3").unwrap();
+        let plan = plan_shallow(&repo, &[merge], &[], &request).unwrap();
+        assert_eq!(plan.commits, vec![merge, main, side, base]);
+        assert!(plan.update.shallow.is_empty());
+    }
+}
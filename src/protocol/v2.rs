@@ -0,0 +1,622 @@
+//! Git protocol v2 commands
+//!
+//! A v2 request is a single command: `command=<name>`, optional capability
+//! lines, a delimiter packet (`0001`), the command's arguments and a flush.
+//! Each command is answered on its own, without any shared state.
+
+use gix::Repository;
+use gix_hash::ObjectId;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::core::{GitError, Result, protocol_err};
+use super::git_protocol::{PackLimits, PackResume, UploadRequest, RESUME_CAPABILITY, peeled_tag_target, send_packfile};
+use super::shallow::{ShallowRequest, plan_shallow};
+use super::pkt_line::{parse_pkt_length, MAX_PKT_LINE_LENGTH};
+
+/// Name of the command reporting object sizes without sending content
+pub const OBJECT_INFO_COMMAND: &str = "object-info";
+
+/// Name of the command listing references
+pub const LS_REFS_COMMAND: &str = "ls-refs";
+
+/// Name of the command negotiating and sending a pack
+pub const FETCH_COMMAND: &str = "fetch";
+
+/// Most object IDs answered in one `object-info` request
+const MAX_OBJECT_INFO_IDS: usize = 100_000;
+
+/// One v2 command request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct V2Request {
+    /// The command, from `command=<name>`
+    pub command: String,
+    /// Capability lines sent with the command
+    pub capabilities: Vec<String>,
+    /// Argument lines after the delimiter
+    pub args: Vec<String>,
+}
+
+/// A packet of the v2 framing
+enum Packet {
+    Flush,
+    Delimiter,
+    Data(String),
+}
+
+async fn read_packet<S>(stream: &mut S) -> Result<Option<Packet>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(GitError::IO(format!("Failed to read packet length: {}", e), None)),
+    }
+    let length = parse_pkt_length(&length, MAX_PKT_LINE_LENGTH)?;
+    match length {
+        0 => return Ok(Some(Packet::Flush)),
+        1 => return Ok(Some(Packet::Delimiter)),
+        2..=4 => return Err(protocol_err("Invalid packet length", None)),
+        _ => {},
+    }
+
+    let mut data = vec![0u8; length - 4];
+    stream.read_exact(&mut data).await
+        .map_err(|e| GitError::IO(format!("Failed to read packet data: {}", e), None))?;
+    let line = String::from_utf8(data)
+        .map_err(|_| protocol_err("Invalid UTF-8 in request", None))?;
+    Ok(Some(Packet::Data(line.trim_end_matches('\n').to_string())))
+}
+
+/// Read the next command request from a client
+///
+/// Returns `None` once the client closes the connection or sends a lone
+/// flush, which ends a v2 session.
+pub async fn read_v2_request<S>(stream: &mut S) -> Result<Option<V2Request>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut request = V2Request::default();
+    let mut in_args = false;
+    loop {
+        match read_packet(stream).await? {
+            None if request.command.is_empty() && !in_args => return Ok(None),
+            None => return Err(protocol_err("Connection closed in the middle of a request", None)),
+            Some(Packet::Flush) if request.command.is_empty() => return Ok(None),
+            Some(Packet::Flush) => break,
+            Some(Packet::Delimiter) => in_args = true,
+            Some(Packet::Data(line)) if in_args => request.args.push(line),
+            Some(Packet::Data(line)) => match line.strip_prefix("command=") {
+                Some(command) => request.command = command.to_string(),
+                None => request.capabilities.push(line),
+            },
+        }
+    }
+    if request.command.is_empty() {
+        return Err(protocol_err("Request names no command", None));
+    }
+    Ok(Some(request))
+}
+
+/// What an `object-info` request asks for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectInfoRequest {
+    /// Report object sizes
+    pub size: bool,
+    /// Report object types (an extension; git only defines `size`)
+    pub kind: bool,
+    /// The objects to describe
+    pub ids: Vec<ObjectId>,
+}
+
+impl ObjectInfoRequest {
+    /// Parse the arguments of an `object-info` command
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut request = Self::default();
+        for arg in args {
+            if arg == "size" {
+                request.size = true;
+            } else if arg == "type" {
+                request.kind = true;
+            } else if let Some(hex) = arg.strip_prefix("oid ") {
+                let id = ObjectId::from_hex(hex.trim().as_bytes())
+                    .map_err(|_| protocol_err(format!("Invalid object ID in object-info: {}", hex), None))?;
+                request.ids.push(id);
+            } else {
+                return Err(protocol_err(format!("Unexpected object-info argument: {}", arg), None));
+            }
+        }
+        if request.ids.len() > MAX_OBJECT_INFO_IDS {
+            return Err(protocol_err(
+                format!("object-info requests are limited to {} objects", MAX_OBJECT_INFO_IDS), None,
+            ));
+        }
+        Ok(request)
+    }
+}
+
+/// Type and size of one object; both are `None` when it doesn't exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The object asked about
+    pub id: ObjectId,
+    /// Its type
+    pub kind: Option<gix::object::Kind>,
+    /// Its uncompressed size
+    pub size: Option<u64>,
+}
+
+/// Look up type and size of objects from their headers alone
+///
+/// Only the object header is decoded, so even huge blobs cost no more than
+/// a pack index lookup.
+pub fn lookup_object_info(repo: &Repository, ids: &[ObjectId]) -> Result<Vec<ObjectInfo>> {
+    ids.iter().map(|id| {
+        let header = repo.try_find_header(*id)
+            .map_err(|e| protocol_err(format!("Failed to read header of {}: {}", id, e), None))?;
+        Ok(ObjectInfo {
+            id: *id,
+            kind: header.as_ref().map(|header| header.kind()),
+            size: header.as_ref().map(|header| header.size()),
+        })
+    }).collect()
+}
+
+/// Answer an `object-info` command
+///
+/// The first line names the requested attributes; each object follows on
+/// its own line with the values in the same order. Missing objects are
+/// listed with empty values, as git does.
+pub async fn process_v2_object_info<S>(stream: &mut S, repo: &Repository, args: &[String]) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let request = ObjectInfoRequest::parse(args)?;
+    let infos = lookup_object_info(repo, &request.ids)?;
+    log::debug!("Answering object-info for {} objects", infos.len());
+
+    let mut attributes = Vec::new();
+    if request.size {
+        attributes.push("size");
+    }
+    if request.kind {
+        attributes.push("type");
+    }
+
+    let mut response = String::new();
+    if !attributes.is_empty() {
+        push_packet(&mut response, &attributes.join(" "));
+        for info in &infos {
+            let mut line = info.id.to_string();
+            if request.size {
+                line.push(' ');
+                if let Some(size) = info.size {
+                    line.push_str(&size.to_string());
+                }
+            }
+            if request.kind {
+                line.push(' ');
+                if let Some(kind) = info.kind {
+                    line.push_str(&kind.to_string());
+                }
+            }
+            push_packet(&mut response, &line);
+        }
+    }
+    response.push_str("0000");
+
+    stream.write_all(response.as_bytes()).await
+        .map_err(|e| GitError::IO(format!("Failed to write object-info response: {}", e), None))?;
+    stream.flush().await
+        .map_err(|e| GitError::IO(format!("Failed to write object-info response: {}", e), None))
+}
+
+/// Advertise the v2 capabilities of upload-pack
+///
+/// This replaces the reference advertisement of v0; references are only
+/// listed when the client asks with `ls-refs`.
+pub async fn send_v2_capabilities<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut response = String::new();
+    push_packet(&mut response, "version 2");
+    push_packet(&mut response, &format!("agent=arti-git/{}", env!("CARGO_PKG_VERSION")));
+    push_packet(&mut response, &format!("{}=unborn", LS_REFS_COMMAND));
+    push_packet(&mut response, &format!("{}=shallow {}", FETCH_COMMAND, RESUME_CAPABILITY));
+    push_packet(&mut response, "object-format=sha1");
+    push_packet(&mut response, OBJECT_INFO_COMMAND);
+    response.push_str("0000");
+    write_response(stream, &response, "capability advertisement").await
+}
+
+/// Answer an `ls-refs` command
+///
+/// Supports `ref-prefix` filtering, `symrefs` (a `symref-target:` attribute
+/// on symbolic refs), `peel` (a `peeled:` attribute on annotated tags) and
+/// `unborn` (HEAD is listed even before the first commit).
+pub async fn process_v2_ls_refs<S>(stream: &mut S, repo: &Repository, args: &[String]) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut unborn = false;
+    let mut prefixes = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "symrefs" => symrefs = true,
+            "peel" => peel = true,
+            "unborn" => unborn = true,
+            _ => match arg.strip_prefix("ref-prefix ") {
+                Some(prefix) => prefixes.push(prefix.to_string()),
+                None => return Err(protocol_err(format!("Unexpected ls-refs argument: {}", arg), None)),
+            },
+        }
+    }
+    let wanted = |name: &str| prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()));
+
+    let mut response = String::new();
+    let mut count = 0;
+
+    if wanted("HEAD") {
+        let target = repo.head_name()
+            .map_err(|e| protocol_err(format!("Failed to read HEAD: {}", e), None))?
+            .map(|name| name.as_bstr().to_string());
+        let head = repo.head_id().ok().map(|id| id.detach());
+        let line = match (head, &target) {
+            (Some(id), _) => Some(ref_line(repo, id, "HEAD", target.as_deref().filter(|_| symrefs), peel)),
+            (None, Some(target)) if unborn && symrefs => Some(format!("unborn HEAD symref-target:{}", target)),
+            (None, Some(_)) if unborn => Some("unborn HEAD".to_string()),
+            _ => None,
+        };
+        if let Some(line) = line {
+            push_packet(&mut response, &line);
+            count += 1;
+        }
+    }
+
+    let references = repo.references()
+        .map_err(|e| protocol_err(format!("Failed to get refs: {}", e), None))?;
+    let all = references.all()
+        .map_err(|e| protocol_err(format!("Failed to list refs: {}", e), None))?;
+    for reference in all.filter_map(std::result::Result::ok) {
+        let name = reference.name().as_bstr().to_string();
+        if !wanted(&name) {
+            continue;
+        }
+        // Symbolic refs are listed with the object their target points to
+        let (id, target) = match reference.try_id() {
+            Some(id) => (id.detach(), None),
+            None => {
+                let target = match reference.target().try_name() {
+                    Some(target) => target.as_bstr().to_string(),
+                    None => continue,
+                };
+                match repo.find_reference(target.as_str()).ok().and_then(|r| r.try_id().map(|id| id.detach())) {
+                    Some(id) => (id, Some(target)),
+                    None => continue,
+                }
+            },
+        };
+        push_packet(&mut response, &ref_line(repo, id, &name, target.as_deref().filter(|_| symrefs), peel));
+        count += 1;
+    }
+    response.push_str("0000");
+
+    log::debug!("Listed {} references for ls-refs", count);
+    write_response(stream, &response, "ls-refs response").await
+}
+
+/// Format one `ls-refs` line with its optional attributes
+fn ref_line(repo: &Repository, id: ObjectId, name: &str, symref_target: Option<&str>, peel: bool) -> String {
+    let mut line = format!("{} {}", id, name);
+    if let Some(target) = symref_target {
+        line.push_str(&format!(" symref-target:{}", target));
+    }
+    if peel {
+        if let Some(peeled) = peeled_tag_target(repo, id) {
+            line.push_str(&format!(" peeled:{}", peeled));
+        }
+    }
+    line
+}
+
+/// What a `fetch` request asks for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchRequest {
+    /// Objects the client wants
+    pub wants: Vec<ObjectId>,
+    /// Objects the client already has
+    pub haves: Vec<ObjectId>,
+    /// The client is done negotiating and wants the pack now
+    pub done: bool,
+    /// The client's shallow boundary and requested depth
+    pub shallow: ShallowRequest,
+    /// The client accepts deltas against objects earlier in the pack
+    pub ofs_delta: bool,
+    /// The client kept the start of this pack from an interrupted download
+    pub resume: Option<PackResume>,
+}
+
+impl FetchRequest {
+    /// Parse the arguments of a `fetch` command
+    ///
+    /// Pack options we always honour (`thin-pack`, `no-progress`,
+    /// `include-tag`) are accepted and ignored.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut request = Self::default();
+        for arg in args {
+            if let Some(resume) = PackResume::parse_capability(arg) {
+                request.resume = Some(resume);
+                continue;
+            }
+            match arg.as_str() {
+                "done" => request.done = true,
+                "ofs-delta" => request.ofs_delta = true,
+                "thin-pack" | "no-progress" | "include-tag" => {},
+                _ if request.shallow.parse_line(arg)? => {},
+                _ => {
+                    let (list, hex) = match (arg.strip_prefix("want "), arg.strip_prefix("have ")) {
+                        (Some(hex), _) => (&mut request.wants, hex),
+                        (_, Some(hex)) => (&mut request.haves, hex),
+                        _ => return Err(protocol_err(format!("Unexpected fetch argument: {}", arg), None)),
+                    };
+                    let id = ObjectId::from_hex(hex.trim().as_bytes())
+                        .map_err(|_| protocol_err(format!("Invalid object ID in fetch: {}", hex), None))?;
+                    list.push(id);
+                },
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// Answer a `fetch` command
+///
+/// Until the client says `done`, the haves we know are acknowledged; as
+/// soon as there is a common object we are `ready` and send the pack in
+/// the same response. A deepening request gets a `shallow-info` section
+/// with the new boundary, and the pack stops there. The pack is always
+/// multiplexed on side-band channel 1.
+pub async fn process_v2_fetch<S>(stream: &mut S, repo: &Repository, args: &[String], limits: &PackLimits) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let request = FetchRequest::parse(args)?;
+    if request.wants.is_empty() {
+        return Err(protocol_err("fetch request wants no objects", None));
+    }
+    let common: Vec<ObjectId> = request.haves.iter()
+        .filter(|id| repo.objects.contains(*id))
+        .copied()
+        .collect();
+
+    let mut response = String::new();
+    if !request.done {
+        push_packet(&mut response, "acknowledgments");
+        if common.is_empty() {
+            push_packet(&mut response, "NAK");
+            // Let the client send more haves, or `done`
+            response.push_str("0000");
+            return write_response(stream, &response, "acknowledgments").await;
+        }
+        for id in &common {
+            push_packet(&mut response, &format!("ACK {}", id));
+        }
+        push_packet(&mut response, "ready");
+        response.push_str("0001");
+    }
+    let shallow = if request.shallow.is_deepening() {
+        Some(plan_shallow(repo, &request.wants, &common, &request.shallow)?)
+    } else {
+        None
+    };
+    if let Some(plan) = &shallow {
+        push_packet(&mut response, "shallow-info");
+        for id in &plan.update.shallow {
+            push_packet(&mut response, &format!("shallow {}", id));
+        }
+        for id in &plan.update.unshallow {
+            push_packet(&mut response, &format!("unshallow {}", id));
+        }
+        response.push_str("0001");
+    }
+    push_packet(&mut response, "packfile");
+    write_response(stream, &response, "fetch response").await?;
+
+    let upload = UploadRequest { wants: request.wants, haves: common, shallow, ofs_delta: request.ofs_delta, resume: request.resume };
+    let stats = send_packfile(stream, repo, &upload, limits).await?;
+    log::info!("Served v2 fetch: {}", stats);
+    stream.flush().await
+        .map_err(|e| GitError::IO(format!("Failed to write fetch response: {}", e), None))
+}
+
+/// Serve v2 commands on a connection until the client ends the session
+///
+/// The capability advertisement is sent first; every request after that is
+/// answered on its own.
+pub async fn serve_v2<S>(stream: &mut S, repo: &Repository, limits: &PackLimits) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    send_v2_capabilities(stream).await?;
+    while let Some(request) = read_v2_request(stream).await? {
+        log::debug!("Processing v2 command {}", request.command);
+        match request.command.as_str() {
+            LS_REFS_COMMAND => process_v2_ls_refs(stream, repo, &request.args).await?,
+            FETCH_COMMAND => process_v2_fetch(stream, repo, &request.args, limits).await?,
+            OBJECT_INFO_COMMAND => process_v2_object_info(stream, repo, &request.args).await?,
+            command => return Err(protocol_err(format!("Unsupported v2 command: {}", command), None)),
+        }
+    }
+    Ok(())
+}
+
+async fn write_response<S>(stream: &mut S, response: &str, what: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(response.as_bytes()).await
+        .map_err(|e| GitError::IO(format!("Failed to write {}: {}", what, e), None))?;
+    stream.flush().await
+        .map_err(|e| GitError::IO(format!("Failed to write {}: {}", what, e), None))
+}
+
+fn push_packet(out: &mut String, line: &str) {
+    out.push_str(&format!("{:04x}{}\n", line.len() + 5, line));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_object_info_reports_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = gix::init(dir.path()).unwrap();
+        let blob = repo.write_blob(b"hello world").unwrap().detach();
+        let missing = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567").unwrap();
+
+        let mut request = Vec::new();
+        for line in ["command=object-info", "agent=git/2.42.0"] {
+            push_request_packet(&mut request, line);
+        }
+        request.extend_from_slice(b"0001");
+        for line in ["size".to_string(), "type".to_string(), format!("oid {}", blob), format!("oid {}", missing)] {
+            push_request_packet(&mut request, &line);
+        }
+        request.extend_from_slice(b"0000");
+
+        let mut input = request.as_slice();
+        let parsed = read_v2_request(&mut input).await.unwrap().unwrap();
+        assert_eq!(parsed.command, OBJECT_INFO_COMMAND);
+        assert_eq!(parsed.capabilities, vec!["agent=git/2.42.0"]);
+        assert!(read_v2_request(&mut input).await.unwrap().is_none());
+
+        let mut output = Vec::new();
+        process_v2_object_info(&mut output, &repo, &parsed.args).await.unwrap();
+        let expected = format!(
+            "000esize type\n{:04x}{} 11 blob\n{:04x}{}  \n0000",
+            blob.to_string().len() + 13, blob,
+            missing.to_string().len() + 7, missing,
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_v2_session_over_duplex() {
+        let dir = tempfile::tempdir().unwrap();
+        git(&["init", "-q"], dir.path());
+        std::fs::write(dir.path().join("file.txt"), "content").unwrap();
+        git(&["add", "file.txt"], dir.path());
+        git(&["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-qm", "Initial"], dir.path());
+        git(&["tag", "v1.0"], dir.path());
+
+        let repo = gix::open(dir.path()).unwrap();
+        let head = repo.head_id().unwrap().detach();
+        let branch = repo.head_name().unwrap().unwrap().as_bstr().to_string();
+
+        let (mut client, mut server) = tokio::io::duplex(1 << 16);
+        let serve = async {
+            let command = crate::protocol::parse_git_command(&mut server).await.unwrap();
+            assert_eq!(command.version, crate::protocol::GitProtocolVersion::V2);
+            serve_v2(&mut server, &repo, &PackLimits::unlimited()).await.unwrap();
+        };
+        let fetch = async {
+            let mut request = Vec::new();
+            push_request_packet(&mut request, "git-upload-pack /repo\0host=example.onion\0\0version=2\0");
+            client.write_all(&request).await.unwrap();
+
+            let capabilities = read_until_flush(&mut client).await;
+            assert_eq!(capabilities[0], b"version 2\n");
+            assert!(capabilities.contains(&b"ls-refs=unborn\n".to_vec()));
+            assert!(capabilities.contains(&b"fetch=shallow resume-pack\n".to_vec()));
+
+            let mut request = Vec::new();
+            push_request_packet(&mut request, "command=ls-refs");
+            request.extend_from_slice(b"0001");
+            for line in ["symrefs", "ref-prefix HEAD", "ref-prefix refs/heads/"] {
+                push_request_packet(&mut request, line);
+            }
+            request.extend_from_slice(b"0000");
+            client.write_all(&request).await.unwrap();
+
+            // The tag is filtered out by the prefixes
+            let refs = read_until_flush(&mut client).await;
+            assert_eq!(refs, vec![
+                format!("{} HEAD symref-target:{}\n", head, branch).into_bytes(),
+                format!("{} {}\n", head, branch).into_bytes(),
+            ]);
+
+            let mut request = Vec::new();
+            push_request_packet(&mut request, "command=fetch");
+            request.extend_from_slice(b"0001");
+            for line in [format!("want {}", head), "done".to_string()] {
+                push_request_packet(&mut request, &line);
+            }
+            request.extend_from_slice(b"0000");
+            client.write_all(&request).await.unwrap();
+
+            let response = read_until_flush(&mut client).await;
+            assert_eq!(response[0], b"packfile\n");
+            let pack: Vec<u8> = response[1..].iter()
+                .filter(|packet| packet.first() == Some(&1))
+                .flat_map(|packet| packet[1..].to_vec())
+                .collect();
+            assert_eq!(&pack[..4], b"PACK");
+            // Commit, tree and blob
+            assert_eq!(u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]), 3);
+
+            // The same fetch again, continuing after the first 20 bytes
+            let mut request = Vec::new();
+            push_request_packet(&mut request, "command=fetch");
+            request.extend_from_slice(b"0001");
+            let resume = PackResume::for_prefix(&pack[..20]).to_capability();
+            for line in [format!("want {}", head), resume, "done".to_string()] {
+                push_request_packet(&mut request, &line);
+            }
+            request.extend_from_slice(b"0000");
+            client.write_all(&request).await.unwrap();
+
+            let response = read_until_flush(&mut client).await;
+            let rest: Vec<u8> = response[1..].iter()
+                .filter(|packet| packet.first() == Some(&1))
+                .flat_map(|packet| packet[1..].to_vec())
+                .collect();
+            assert_eq!(rest, pack[20..]);
+
+            client.write_all(b"0000").await.unwrap();
+        };
+        tokio::join!(serve, fetch);
+    }
+
+    /// Read packets up to the next flush; delimiters are returned as empty packets
+    async fn read_until_flush(stream: &mut tokio::io::DuplexStream) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        loop {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).await.unwrap();
+            let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+            match length {
+                0 => return packets,
+                1 => packets.push(Vec::new()),
+                _ => {
+                    let mut data = vec![0u8; length - 4];
+                    stream.read_exact(&mut data).await.unwrap();
+                    packets.push(data);
+                },
+            }
+        }
+    }
+
+    fn git(args: &[&str], cwd: &std::path::Path) {
+        let status = std::process::Command::new("git").args(args).current_dir(cwd).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn push_request_packet(out: &mut Vec<u8>, line: &str) {
+        out.extend_from_slice(format!("{:04x}{}\n", line.len() + 5, line).as_bytes());
+    }
+}
@@ -0,0 +1,229 @@
+//! Independent verification of a pack and its index
+//!
+//! The pack is indexed again from scratch with the same code `index_pack`
+//! uses for fetched packs, which checks the trailing checksum, every entry's
+//! compression and every delta. The fresh index must then agree with the
+//! `.idx` next to the pack. A pack that fails here was damaged in transfer
+//! or on disk; one that passes but still breaks a fetch points at a
+//! protocol problem instead.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use gix_hash::ObjectId;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::core::{Result, io_err, repo_err};
+
+/// Longest delta chain followed before assuming the pack is corrupt
+const MAX_DELTA_DEPTH: usize = 10_000;
+
+/// One object as stored in a pack
+#[derive(Debug, Clone)]
+pub struct PackObjectInfo {
+    /// Object ID
+    pub id: ObjectId,
+    /// Type of the object, after resolving deltas
+    pub kind: gix::object::Kind,
+    /// Uncompressed size of the entry (of the delta, for deltified objects)
+    pub size: u64,
+    /// Bytes the entry takes in the pack
+    pub size_in_pack: u64,
+    /// Offset of the entry in the pack
+    pub offset: u64,
+    /// Number of deltas to apply to reach the object (0 for a full object)
+    pub depth: usize,
+    /// Object the delta applies to
+    pub base: Option<ObjectId>,
+}
+
+/// Result of verifying a pack
+#[derive(Debug, Clone)]
+pub struct PackVerification {
+    /// The verified pack
+    pub pack_path: PathBuf,
+    /// Its index
+    pub index_path: PathBuf,
+    /// Number of objects in the pack
+    pub num_objects: usize,
+    /// Every object in pack order; empty unless objects were requested
+    pub objects: Vec<PackObjectInfo>,
+    /// Number of objects per delta chain length; empty unless objects were requested
+    pub chain_lengths: BTreeMap<usize, usize>,
+}
+
+/// Verify `pack_path` and the `.idx` beside it
+///
+/// With `list_objects`, also reports the type, sizes and delta chain of
+/// every object.
+pub fn verify_pack(pack_path: &Path, object_hash: gix_hash::Kind, list_objects: bool) -> Result<PackVerification> {
+    let index_path = pack_path.with_extension("idx");
+    if !pack_path.is_file() {
+        return Err(repo_err("Pack file not found", pack_path));
+    }
+    if !index_path.is_file() {
+        return Err(repo_err("Pack has no index file", &index_path));
+    }
+    check_index_checksum(&index_path, object_hash)?;
+
+    // Index the pack again, fully verifying its contents
+    let scratch = tempfile::tempdir()
+        .map_err(|e| io_err(format!("Failed to create temporary directory: {}", e), pack_path))?;
+    let pack_file = File::open(pack_path)
+        .map_err(|e| io_err(format!("Failed to open pack: {}", e), pack_path))?;
+    let outcome = gix::odb::pack::Bundle::write_to_directory(
+        &mut BufReader::new(pack_file),
+        Some(scratch.path()),
+        gix::progress::Discard,
+        &gix::interrupt::IS_INTERRUPTED,
+        None::<fn(ObjectId, &mut Vec<u8>) -> Option<gix::objs::Data<'_>>>,
+        gix::odb::pack::bundle::write::Options {
+            thread_limit: None,
+            iteration_mode: gix::odb::pack::data::input::Mode::Verify,
+            index_version: gix::odb::pack::index::Version::V2,
+            object_hash,
+        },
+    ).map_err(|e| repo_err(format!("Pack is corrupt: {}", e), pack_path))?;
+    let fresh_index_path = match outcome.index_path {
+        Some(path) => path,
+        None => return Err(repo_err("Pack was verified but no index was written", pack_path)),
+    };
+
+    let index = gix::odb::pack::index::File::at(&index_path, object_hash)
+        .map_err(|e| repo_err(format!("Invalid pack index: {}", e), &index_path))?;
+    let fresh = gix::odb::pack::index::File::at(&fresh_index_path, object_hash)
+        .map_err(|e| repo_err(format!("Invalid pack index: {}", e), &fresh_index_path))?;
+    compare_indexes(&index, &fresh, &index_path)?;
+
+    let mut verification = PackVerification {
+        pack_path: pack_path.to_path_buf(),
+        index_path,
+        num_objects: index.num_objects() as usize,
+        objects: Vec::new(),
+        chain_lengths: BTreeMap::new(),
+    };
+    if list_objects {
+        verification.objects = list_pack_objects(pack_path, &index, object_hash)?;
+        for object in &verification.objects {
+            *verification.chain_lengths.entry(object.depth).or_default() += 1;
+        }
+    }
+    Ok(verification)
+}
+
+/// The index ends with the pack's checksum and a checksum over everything
+/// before it, both made with the repository's hash function
+///
+/// Returns the pack checksum the index records.
+pub(crate) fn check_index_checksum(index_path: &Path, object_hash: gix_hash::Kind) -> Result<Vec<u8>> {
+    let hash_len = object_hash.len_in_bytes();
+    let data = std::fs::read(index_path)
+        .map_err(|e| io_err(format!("Failed to read pack index: {}", e), index_path))?;
+    if data.len() < 2 * hash_len {
+        return Err(repo_err("Pack index is truncated", index_path));
+    }
+    let (content, checksum) = data.split_at(data.len() - hash_len);
+    // Go by digest length, which tells SHA-1 and SHA-256 apart in every gix version
+    let digest = match hash_len {
+        20 => Sha1::digest(content).to_vec(),
+        _ => Sha256::digest(content).to_vec(),
+    };
+    if digest != checksum {
+        return Err(repo_err("Pack index checksum mismatch", index_path));
+    }
+    Ok(content[content.len() - hash_len..].to_vec())
+}
+
+/// Check that an index lists the same objects at the same offsets as one
+/// freshly built from the pack
+fn compare_indexes(
+    index: &gix::odb::pack::index::File,
+    fresh: &gix::odb::pack::index::File,
+    index_path: &Path,
+) -> Result<()> {
+    if index.num_objects() != fresh.num_objects() {
+        return Err(repo_err(
+            format!("Index lists {} objects but the pack has {}", index.num_objects(), fresh.num_objects()),
+            index_path,
+        ));
+    }
+    for (listed, actual) in index.iter().zip(fresh.iter()) {
+        if listed.oid != actual.oid {
+            return Err(repo_err(format!("Index lists {} which is not in the pack", listed.oid), index_path));
+        }
+        if listed.pack_offset != actual.pack_offset {
+            return Err(repo_err(
+                format!("Index places {} at offset {}, but it is at {}", listed.oid, listed.pack_offset, actual.pack_offset),
+                index_path,
+            ));
+        }
+        // Version 1 indexes carry no CRCs
+        if let (Some(listed_crc), Some(actual_crc)) = (listed.crc32, actual.crc32) {
+            if listed_crc != actual_crc {
+                return Err(repo_err(format!("Index has a wrong CRC for {}", listed.oid), index_path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Describe every object of a pack, following delta chains to their base
+fn list_pack_objects(
+    pack_path: &Path,
+    index: &gix::odb::pack::index::File,
+    object_hash: gix_hash::Kind,
+) -> Result<Vec<PackObjectInfo>> {
+    use gix::odb::pack::data::entry::Header;
+
+    let pack = gix::odb::pack::data::File::at(pack_path, object_hash)
+        .map_err(|e| repo_err(format!("Failed to open pack: {}", e), pack_path))?;
+
+    let mut entries: Vec<(u64, ObjectId)> = index.iter().map(|entry| (entry.pack_offset, entry.oid)).collect();
+    entries.sort();
+    let id_at: HashMap<u64, ObjectId> = entries.iter().map(|(offset, id)| (*offset, *id)).collect();
+    let offset_of: HashMap<ObjectId, u64> = entries.iter().map(|(offset, id)| (*id, *offset)).collect();
+    let pack_end = pack.data_len() as u64 - object_hash.len_in_bytes() as u64;
+
+    let mut objects = Vec::with_capacity(entries.len());
+    for (position, (offset, id)) in entries.iter().enumerate() {
+        let entry = pack.entry(*offset);
+        let next_offset = entries.get(position + 1).map_or(pack_end, |(next, _)| *next);
+
+        // Walk down to the full object to learn the type and chain length
+        let mut depth = 0;
+        let mut base = None;
+        let mut header = entry.header;
+        let mut current = *offset;
+        let kind = loop {
+            let base_offset = match header {
+                Header::OfsDelta { base_distance } => current.checked_sub(base_distance),
+                Header::RefDelta { base_id } => offset_of.get(&base_id).copied(),
+                _ => break header.as_kind().expect("non-delta entries have a kind"),
+            };
+            let base_offset = match base_offset {
+                Some(base_offset) if depth < MAX_DELTA_DEPTH => base_offset,
+                _ => return Err(repo_err(format!("Delta chain of {} is broken", id), pack_path)),
+            };
+            if depth == 0 {
+                base = id_at.get(&base_offset).copied();
+            }
+            depth += 1;
+            current = base_offset;
+            header = pack.entry(current).header;
+        };
+
+        objects.push(PackObjectInfo {
+            id: *id,
+            kind,
+            size: entry.decompressed_size,
+            size_in_pack: next_offset - offset,
+            offset: *offset,
+            depth,
+            base,
+        });
+    }
+    Ok(objects)
+}
@@ -370,4 +370,57 @@ impl Tag {
         
         content
     }
-}
\ No newline at end of file
+}
+
+/// Header that carries a commit's signature
+const SIGNATURE_HEADER: &[u8] = b"gpgsig";
+
+/// Add `signature` to raw commit content as a `gpgsig` header
+///
+/// The header goes last, right before the blank line that ends the headers;
+/// its continuation lines start with a space.
+pub(crate) fn insert_signature(content: &[u8], signature: &str) -> Result<Vec<u8>> {
+    let headers_end = content.windows(2).position(|pair| pair == b"\n\n")
+        .ok_or_else(|| GitError::ObjectStorage("Commit has no message separator".to_string()))? + 1;
+    let mut signed = content[..headers_end].to_vec();
+    signed.extend_from_slice(SIGNATURE_HEADER);
+    signed.push(b' ');
+    signed.extend_from_slice(signature.trim_end().replace('\n', "\n ").as_bytes());
+    signed.push(b'\n');
+    signed.extend_from_slice(&content[headers_end..]);
+    Ok(signed)
+}
+
+/// Split raw commit content into the signed payload and its signature
+///
+/// The payload is the commit without the `gpgsig` header, which is what was
+/// signed. Returns `None` for unsigned commits.
+pub(crate) fn split_signature(raw: &[u8]) -> Option<(Vec<u8>, String)> {
+    let mut payload = Vec::with_capacity(raw.len());
+    let mut signature: Option<Vec<u8>> = None;
+    let mut in_signature = false;
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let line_len = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+        let (line, tail) = rest.split_at(line_len);
+        if line == b"\n" {
+            payload.extend_from_slice(rest);
+            break;
+        }
+        if in_signature && line[0] == b' ' {
+            let signature = signature.as_mut().expect("inside a signature header");
+            signature.push(b'\n');
+            signature.extend_from_slice(line[1..].strip_suffix(b"\n").unwrap_or(&line[1..]));
+        } else if signature.is_none() && line.starts_with(SIGNATURE_HEADER) && line.get(SIGNATURE_HEADER.len()) == Some(&b' ') {
+            let value = &line[SIGNATURE_HEADER.len() + 1..];
+            signature = Some(value.strip_suffix(b"\n").unwrap_or(value).to_vec());
+            in_signature = true;
+        } else {
+            in_signature = false;
+            payload.extend_from_slice(line);
+        }
+        rest = tail;
+    }
+    let signature = String::from_utf8(signature?).ok()?;
+    Some((payload, signature))
+}
@@ -1,95 +1,235 @@
+//! Reading Git configuration files
+//!
+//! Parsing is left to `gix-config`, which handles Git's INI dialect:
+//! `[section "subsection"]` headers, quoted values with backslash escapes,
+//! comments, line continuations, keys without a value (meaning `true`), keys
+//! that occur more than once, and `[include] path = ...` pulling in further
+//! files. This module flattens the result into canonical keys.
+
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::fs;
-use std::collections::HashMap;
 
-use crate::core::{GitError, Result};
+use crate::core::{GitError, Result, xdg_git_dir};
+
+/// How deep `include.path` files may include further files, like Git
+pub const MAX_INCLUDE_DEPTH: usize = 10;
 
-/// Represents a Git repository configuration
+/// Repository configuration
+///
+/// Keys are canonical `section.key` or `section.subsection.key` names:
+/// section and key are case-insensitive and stored in lower case, the
+/// subsection keeps its case. Lookups accept any case for the parts Git
+/// treats case-insensitively (`core.attributesFile`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Config {
-    sections: HashMap<String, HashMap<String, String>>,
+    /// Values of each key, in the order they were read
+    values: HashMap<String, Vec<String>>,
 }
 
 impl Config {
-    /// Create a new empty configuration
+    /// Create a new configuration
     pub fn new() -> Self {
-        Self {
-            sections: HashMap::new(),
-        }
+        Self::default()
     }
-    
-    /// Load configuration from a file
-    pub fn load_from_file(path: &Path) -> Result<Self> {
-        if !path.exists() {
-            return Ok(Self::new());
+
+    /// Get a configuration value; the last one wins if the key is repeated
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.values.get(&canonical_key(key)).and_then(|values| values.last()).cloned()
+    }
+
+    /// Get every value of a multi-valued key, such as `remote.origin.fetch`
+    pub fn get_all(&self, key: &str) -> &[String] {
+        self.values.get(&canonical_key(key)).map_or(&[], Vec::as_slice)
+    }
+
+    /// Set a configuration value, replacing all previous values
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(canonical_key(key), vec![value.to_string()]);
+    }
+
+    /// Add a value to a key, keeping the previous ones
+    pub fn add(&mut self, key: &str, value: &str) {
+        self.values.entry(canonical_key(key)).or_default().push(value.to_string());
+    }
+
+    /// Load configuration from a Git repository
+    ///
+    /// Reads the user's global configuration (`$XDG_CONFIG_HOME/git/config`,
+    /// then `~/.gitconfig`) followed by `<git_dir>/config`, so repository
+    /// settings override global ones. Missing files are skipped.
+    pub fn load_from_repo(git_dir: &Path) -> Result<Self> {
+        let mut config = Self::new();
+        let global = [
+            xdg_git_dir().map(|dir| dir.join("config")),
+            dirs::home_dir().map(|home| home.join(".gitconfig")),
+        ];
+        for path in global.iter().flatten() {
+            config.read_file(path, gix_config::Source::User, Some(git_dir))?;
         }
-        
-        let content = fs::read_to_string(path)
-            .map_err(|e| GitError::Io(e))?;
-            
-        Self::parse(&content)
+        config.read_file(&git_dir.join("config"), gix_config::Source::Local, Some(git_dir))?;
+        Ok(config)
     }
-    
-    /// Parse configuration content
-    fn parse(content: &str) -> Result<Self> {
+
+    /// Load configuration from a single file and the files it includes
+    pub fn load_from_file(path: &Path) -> Result<Self> {
         let mut config = Self::new();
-        let mut current_section = String::new();
-        
-        for line in content.lines() {
-            let line = line.trim();
-            
-            // Skip comments and empty lines
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            
-            // Parse section header: [section] or [section "subsection"]
-            if line.starts_with('[') && line.ends_with(']') {
-                let section_name = &line[1..line.len() - 1].trim();
-                current_section = section_name.to_string();
-                config.sections.entry(current_section.clone()).or_insert_with(HashMap::new);
-            }
-            // Parse key-value pair
-            else if let Some(pos) = line.find('=') {
-                let key = line[..pos].trim();
-                let value = line[pos + 1..].trim();
-                
-                if !current_section.is_empty() {
-                    if let Some(section) = config.sections.get_mut(&current_section) {
-                        section.insert(key.to_string(), value.to_string());
-                    }
-                }
-            }
+        config.read_file(path, gix_config::Source::Local, None)?;
+        Ok(config)
+    }
+
+    /// Parse configuration text; `include.path` is resolved against `base_dir`
+    pub fn parse(content: &str, base_dir: Option<&Path>) -> Result<Self> {
+        let mut meta = gix_config::file::Metadata::api();
+        if let Some(base_dir) = base_dir {
+            // Includes are relative to the directory of the including file
+            meta = meta.at(base_dir.join("config"));
         }
-        
+        let home = dirs::home_dir();
+        let file = gix_config::File::from_bytes_owned(&mut content.as_bytes().to_vec(), meta, init_options(home.as_deref(), None))
+            .map_err(|e| GitError::Config(e.to_string()))?;
+
+        let mut config = Self::new();
+        config.extend_from(&file);
         Ok(config)
     }
-    
-    /// Save configuration to a file
-    pub fn save_to_file(&self, path: &Path) -> Result<()> {
-        let mut content = String::new();
-        
-        for (section, values) in &self.sections {
-            content.push_str(&format!("[{}]\n", section));
-            
-            for (key, value) in values {
-                content.push_str(&format!("\t{} = {}\n", key, value));
-            }
-            
-            content.push('\n');
+
+    fn read_file(&mut self, path: &Path, source: gix_config::Source, git_dir: Option<&Path>) -> Result<()> {
+        let meta = gix_config::file::Metadata::try_from_path(path, source)
+            .unwrap_or_else(|_| gix_config::file::Metadata::from(source).at(path));
+        let home = dirs::home_dir();
+        let mut buf = Vec::new();
+        let file = gix_config::File::from_paths_metadata_buf(
+            &mut std::iter::once(meta), &mut buf, false, init_options(home.as_deref(), git_dir),
+        ).map_err(|e| GitError::Config(format!("{}: {}", path.display(), e)))?;
+
+        if let Some(file) = file {
+            self.extend_from(&file);
         }
-        
-        fs::write(path, content).map_err(GitError::Io)?;
         Ok(())
     }
-    
-    /// Get a configuration value
-    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
-        self.sections.get(section).and_then(|s| s.get(key).map(|s| s.as_str()))
+
+    /// Add every value of `file`, in the order of its sections
+    fn extend_from(&mut self, file: &gix_config::File<'_>) {
+        for section in file.sections() {
+            let header = section.header();
+            let name = header.name().to_string().to_lowercase();
+            let prefix = match header.subsection_name() {
+                // The legacy dotted form lower-cases the subsection too
+                Some(subsection) if header.is_legacy() => format!("{}.{}", name, subsection.to_string().to_lowercase()),
+                Some(subsection) => format!("{}.{}", name, subsection),
+                None => name,
+            };
+
+            let body = section.body();
+            let mut seen = HashSet::new();
+            for key in body.keys() {
+                let key = key.as_ref().to_lowercase();
+                if !seen.insert(key.clone()) {
+                    continue;
+                }
+                let mut values: Vec<String> = body.values(&key).iter().map(|value| value.to_string()).collect();
+                // A key without a value is a boolean set to true
+                if let (Some(None), Some(last)) = (body.value_implicit(&key), values.last_mut()) {
+                    *last = "true".to_string();
+                }
+                self.values.entry(format!("{}.{}", prefix, key)).or_default().extend(values);
+            }
+        }
+    }
+}
+
+/// Follow includes like Git: `include.path` always, `includeIf` conditions
+/// on `gitdir` when the repository is known
+fn init_options<'a>(home_dir: Option<&'a Path>, git_dir: Option<&'a Path>) -> gix_config::file::init::Options<'a> {
+    let mut includes = gix_config::file::includes::Options::follow_without_conditional(home_dir);
+    includes.max_depth = MAX_INCLUDE_DEPTH as u8;
+    includes.err_on_missing_config_path = false;
+    includes.conditional.git_dir = git_dir;
+    gix_config::file::init::Options { includes, ..Default::default() }
+}
+
+/// Canonical form of a key: lower-case section and name, subsection as is
+fn canonical_key(key: &str) -> String {
+    let (section, rest) = key.split_once('.').unwrap_or((key, ""));
+    match rest.rsplit_once('.') {
+        Some((subsection, name)) => format!("{}.{}.{}", section.to_lowercase(), subsection, name.to_lowercase()),
+        None => format!("{}.{}", section.to_lowercase(), rest.to_lowercase()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_repository_config() {
+        let config = Config::parse(r#"
+# A representative .git/config
+[core]
+	repositoryformatversion = 0
+	bare = false   ; trailing comment
+	attributesFile = ~/.gitattributes
+[user]
+	name = "A. U. Thor"  # quoted, with a comment after
+	email = author@example.com
+[remote "origin"]
+	url = tor+git://example.onion/repo.git
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+[branch "Main"]
+	remote = origin
+[alias]
+	lg = log --graph \
+	     --oneline
+	quote = "say \"hi\"\tnow"
+[http]
+	sslVerify
+"#, None).unwrap();
+
+        assert_eq!(config.get("user.name").as_deref(), Some("A. U. Thor"));
+        assert_eq!(config.get("user.email").as_deref(), Some("author@example.com"));
+        assert_eq!(config.get("core.bare").as_deref(), Some("false"));
+        assert_eq!(config.get("core.attributesFile").as_deref(), Some("~/.gitattributes"));
+        assert_eq!(config.get("CORE.ATTRIBUTESFILE"), config.get("core.attributesfile"));
+        assert_eq!(config.get("remote.origin.url").as_deref(), Some("tor+git://example.onion/repo.git"));
+        assert_eq!(config.get_all("remote.origin.fetch"), [
+            "+refs/heads/*:refs/remotes/origin/*".to_string(),
+            "+refs/tags/*:refs/tags/*".to_string(),
+        ]);
+        assert_eq!(config.get("remote.origin.fetch").as_deref(), Some("+refs/tags/*:refs/tags/*"));
+
+        // Subsections keep their case
+        assert_eq!(config.get("branch.Main.remote").as_deref(), Some("origin"));
+        assert_eq!(config.get("branch.main.remote"), None);
+
+        assert_eq!(config.get("alias.lg").as_deref(), Some("log --graph \t     --oneline"));
+        assert_eq!(config.get("alias.quote").as_deref(), Some("say \"hi\"\tnow"));
+        assert_eq!(config.get("http.sslverify").as_deref(), Some("true"));
     }
-    
-    /// Set a configuration value
-    pub fn set(&mut self, section: &str, key: &str, value: &str) {
-        let section_entry = self.sections.entry(section.to_string()).or_insert_with(HashMap::new);
-        section_entry.insert(key.to_string(), value.to_string());
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Config::parse("name = outside\n", None).is_err());
+        assert!(Config::parse("[user\nname = x\n", None).is_err());
+        assert!(Config::parse("[user]\nname = \"unterminated\n", None).is_err());
+        assert!(Config::parse("[user]\nname = bad\\qescape\n", None).is_err());
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(dir.path().join("nested/identity"), "[user]\n\temail = included@example.com\n").unwrap();
+        std::fs::write(dir.path().join("shared"), "[include]\n\tpath = nested/identity\n[user]\n\tname = Included\n").unwrap();
+        std::fs::write(dir.path().join("config"), "[user]\n\tname = Before\n[include]\n\tpath = shared\n").unwrap();
+
+        let config = Config::load_from_file(&dir.path().join("config")).unwrap();
+        // Included values count as if they appeared where the include is
+        assert_eq!(config.get_all("user.name"), ["Before".to_string(), "Included".to_string()]);
+        assert_eq!(config.get("user.email").as_deref(), Some("included@example.com"));
+
+        // Missing includes are skipped; include loops stop at the depth limit
+        std::fs::write(dir.path().join("loop"), "[include]\n\tpath = loop\n\tpath = missing\n").unwrap();
+        assert!(Config::load_from_file(&dir.path().join("loop")).is_err());
     }
-}
\ No newline at end of file
+}
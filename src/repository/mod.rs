@@ -4,43 +4,14 @@ mod config;
 mod commit;
 
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, Local, Utc};
 use gix::index::File as IndexFile; // <-- Add use statement
-use crate::core::{Result, GitError, ObjectId};
-use crate::crypto::SignatureProvider;
+use crate::core::{Result, GitError, ObjectId, PathAttributes, io_err};
+use crate::crypto::{sshsig, AnonymousIdentity, Identity, PublicKey, SignatureProvider};
 
-/// Repository configuration
-pub struct Config {
-    /// Configuration values
-    values: HashMap<String, String>,
-}
-
-impl Config {
-    /// Create a new configuration
-    pub fn new() -> Self {
-        Self {
-            values: HashMap::new(),
-        }
-    }
-
-    /// Get a configuration value
-    pub fn get(&self, key: &str) -> Option<String> {
-        self.values.get(key).cloned()
-    }
-
-    /// Set a configuration value
-    pub fn set(&mut self, key: &str, value: &str) {
-        self.values.insert(key.to_string(), value.to_string());
-    }
-
-    /// Load configuration from a Git repository
-    pub fn load_from_repo(_path: &Path) -> Result<Self> {
-        // In a real implementation, this would read the .git/config file
-        // For now, we'll return an empty configuration
-        Ok(Self::new())
-    }
-}
+pub use storage::{FileSystemObjectStore, read_alternates, ALTERNATES_FILE};
+pub use refs::{RefStorage, RefTransaction, PackedRef, PACKED_REFS_FILE};
+pub use config::Config;
 
 /// Git signature (author/committer)
 #[derive(Clone)]
@@ -49,27 +20,86 @@ pub struct Signature {
     name: String,
     /// Email
     email: String,
-    /// Timestamp
-    time: DateTime<Utc>,
+    /// Timestamp, with the timezone offset it was made in
+    time: DateTime<FixedOffset>,
 }
 
 impl Signature {
     /// Create a new signature
-    pub fn new(name: &str, email: &str, time: DateTime<Utc>) -> Self {
+    pub fn new(name: &str, email: &str, time: impl Into<DateTime<FixedOffset>>) -> Self {
         Self {
             name: name.to_string(),
             email: email.to_string(),
-            time,
+            time: time.into(),
+        }
+    }
+
+    /// Create a signature stamped with the current time in the local timezone
+    pub fn now(name: &str, email: &str) -> Self {
+        Self::new(name, email, Local::now())
+    }
+
+    /// Signature from `user.name` and `user.email` of a repository
+    ///
+    /// Fails if either is unset, rather than committing with an empty identity.
+    pub fn from_repo_config(repo: &Repository) -> Result<Self> {
+        Self::from_config(repo.get_config())
+    }
+
+    /// Signature from `user.name` and `user.email` of a loaded configuration
+    ///
+    /// Like [`Signature::from_repo_config`], for callers that don't open the
+    /// repository through [`Repository`].
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let name = config.get("user.name")
+            .ok_or_else(|| GitError::Config("user.name is not set".to_string()))?;
+        let email = config.get("user.email")
+            .ok_or_else(|| GitError::Config("user.email is not set".to_string()))?;
+        Ok(Self::now(&name, &email))
+    }
+
+    /// Pseudonymous signature for commits made over Tor
+    ///
+    /// The name is derived from the identity's key fingerprint and the email
+    /// from its onion address, so both stay the same across commits made with
+    /// the same identity. The time is recorded in UTC so the commit doesn't
+    /// reveal the author's timezone.
+    pub fn anonymous(identity: &AnonymousIdentity) -> Self {
+        Self::new(identity.name(), identity.email(), Utc::now())
+    }
+
+    /// Name of the signer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Email of the signer
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// When the signature was made
+    pub fn time(&self) -> DateTime<FixedOffset> {
+        self.time
+    }
+
+    /// The same signature as gitoxide represents it
+    pub fn to_actor(&self) -> gix::actor::Signature {
+        gix::actor::Signature {
+            name: self.name.as_str().into(),
+            email: self.email.as_str().into(),
+            time: gix::date::Time::new(self.time.timestamp() as _, self.time.offset().local_minus_utc()),
         }
     }
 
     /// Convert to Git format: "Name <email> timestamp timezone"
     pub fn to_string(&self) -> String {
         format!(
-            "{} <{}> {} +0000",
+            "{} <{}> {} {}",
             self.name,
             self.email,
-            self.time.timestamp()
+            self.time.timestamp(),
+            self.time.format("%z")
         )
     }
 }
@@ -156,12 +186,92 @@ impl Repository {
         Ok(ObjectId::from_bytes(gix_oid.as_bytes())?)
     }
     
+    /// Generate a packfile of `wants` and their history, minus what `haves` reach
+    ///
+    /// Uses the same pack generation as the onion service's upload-pack, so the
+    /// result can be shipped over any transport.
+    pub fn pack_objects(&self, wants: &[ObjectId], haves: &[ObjectId]) -> Result<bytes::Bytes> {
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        
+        let wants: Vec<gix::ObjectId> = wants.iter().map(Into::into).collect();
+        let haves: Vec<gix::ObjectId> = haves.iter().map(Into::into).collect();
+        
+        crate::protocol::pack_objects(&repo, &wants, &haves)
+    }
+    
+    /// Write a packfile obtained elsewhere into the object store, with its index
+    ///
+    /// Set `complete_thin` to accept thin packs whose deltas refer to objects
+    /// that are already in this repository.
+    pub fn index_pack(&self, pack_data: &[u8], complete_thin: bool) -> Result<crate::protocol::PackStats> {
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        
+        crate::protocol::index_pack(&repo, pack_data, complete_thin)
+    }
+    
+    /// Get the alternate object directories this repository borrows objects from
+    pub fn alternates(&self) -> Result<Vec<PathBuf>> {
+        read_alternates(&self.git_dir.join("objects"))
+    }
+    
+    /// Borrow objects from another repository's object directory
+    ///
+    /// Appends `objects_dir` to `objects/info/alternates`, after which object
+    /// lookups here, including packs served to clients, also find the objects
+    /// stored there. Used to let many forks share one object store.
+    pub fn add_alternate(&self, objects_dir: &Path) -> Result<()> {
+        let objects_dir = objects_dir.canonicalize()
+            .map_err(|e| io_err(format!("Alternate object directory not found: {}", e), objects_dir))?;
+        if !objects_dir.is_dir() {
+            return Err(io_err("Alternate object store is not a directory", &objects_dir));
+        }
+        
+        if self.alternates()?.contains(&objects_dir) {
+            return Ok(());
+        }
+        
+        let file = self.git_dir.join("objects").join(ALTERNATES_FILE);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| io_err(format!("Failed to create directory: {}", e), parent))?;
+        }
+        let mut alternates = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)
+            .map_err(|e| io_err(format!("Failed to open alternates: {}", e), &file))?;
+        std::io::Write::write_all(&mut alternates, format!("{}\n", objects_dir.display()).as_bytes())
+            .map_err(|e| io_err(format!("Failed to write alternates: {}", e), &file))?;
+        
+        Ok(())
+    }
+    
+    /// Get the attributes in effect for a worktree-relative path
+    ///
+    /// The same lookup as [`crate::core::attributes_for`], which LFS, merge
+    /// drivers and diff use.
+    pub fn attributes_for(&self, path: &str) -> Result<PathAttributes> {
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        
+        crate::core::attributes_for(&repo, path)
+    }
+    
+    /// Get the references of this repository, loose and packed
+    pub fn refs(&self) -> RefStorage {
+        RefStorage::new(&self.git_dir)
+    }
+    
+    /// Resolve a reference to the object it points to
+    pub fn resolve_ref(&self, name: &str) -> Result<Option<ObjectId>> {
+        self.refs().resolve(name)
+    }
+    
     /// Set the HEAD reference
     pub fn set_head(&self, object_id: &ObjectId) -> Result<()> {
-        let head_path = self.git_dir.join("HEAD");
-        std::fs::write(&head_path, object_id.to_string())
-            .map_err(|e| GitError::IO(format!("Failed to write HEAD: {}", e)))?;
-        Ok(())
+        self.refs().set_head(&object_id.to_hex())
     }
     
     /// Create a commit
@@ -184,6 +294,11 @@ impl Repository {
     }
     
     /// Create a signed commit
+    ///
+    /// Records the index as the commit's tree, signs the commit with
+    /// `signature_provider` in the SSH signature format git uses with
+    /// `gpg.format = ssh`, and points `ref_name` at it. Like `git commit`,
+    /// the ref only moves if it still points at the first parent.
     pub fn create_commit_signed(
         &self,
         ref_name: &str,
@@ -193,19 +308,85 @@ impl Repository {
         parents: &[ObjectId],
         signature_provider: &SignatureProvider,
     ) -> Result<ObjectId> {
-        // In a real implementation, this would:
-        // 1. Create a tree from the index
-        // 2. Build the commit object content
-        // 3. Sign the commit using the signature provider
-        // 4. Write the signed commit object
-        // 5. Update the reference
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        let tree = ObjectId::from(crate::core::write_index_tree(&repo)?);
         
-        println!("Creating signed commit with anonymous identity from Tor");
+        let mut content = format!("tree {}\n", tree.to_hex());
+        for parent in parents {
+            content.push_str(&format!("parent {}\n", parent.to_hex()));
+        }
+        content.push_str(&format!("author {}\n", author.to_string()));
+        content.push_str(&format!("committer {}\n", committer.to_string()));
+        content.push('\n');
+        content.push_str(message);
+        if !message.ends_with('\n') {
+            content.push('\n');
+        }
+        
+        let signature = sshsig::sign(signature_provider, sshsig::GIT_NAMESPACE, content.as_bytes())
+            .map_err(|e| GitError::Crypto(format!("Failed to sign commit: {}", e)))?;
+        let signed = commit::insert_signature(content.as_bytes(), &signature)?;
+        
+        let mut odb = repo.objects;
+        let gix_oid = odb.write_buf(gix::objs::Kind::Commit, &signed)
+            .map_err(|e| GitError::ObjectStorage(format!("Failed to write commit: {}", e)))?;
+        let object_id = ObjectId::from(gix_oid);
+        
+        // Move the branch HEAD points to rather than detaching HEAD
+        let refs = self.refs();
+        let target = match refs.get_ref(ref_name)? {
+            Some(value) => match value.strip_prefix("ref:") {
+                Some(target) => target.trim().to_string(),
+                None => ref_name.to_string(),
+            },
+            None => ref_name.to_string(),
+        };
+        let mut transaction = refs.transaction();
+        transaction.set(&target, &object_id.to_hex(), Some(parents.first().cloned()));
+        transaction.commit()?;
         
-        // For now, just return a dummy object ID
-        let object_id = ObjectId::from_hex("0000000000000000000000000000000000000000")?;
         Ok(object_id)
     }
+    
+    /// Check the signature of a commit made by [`Repository::create_commit_signed`]
+    ///
+    /// Returns `Ok(false)` if the commit is signed by another key or its
+    /// content no longer matches the signature, and an error if it isn't
+    /// signed at all.
+    pub fn verify_commit_signature(&self, commit_id: &ObjectId, public_key: &PublicKey) -> Result<bool> {
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        let object = repo.find_object(gix::ObjectId::from(commit_id))
+            .map_err(|e| GitError::ObjectStorage(format!("Failed to read commit {}: {}", commit_id, e)))?;
+        if object.kind != gix::objs::Kind::Commit {
+            return Err(GitError::ObjectStorage(format!("{} is not a commit", commit_id)));
+        }
+        
+        let (payload, signature) = commit::split_signature(&object.data)
+            .ok_or_else(|| GitError::Crypto(format!("Commit {} is not signed", commit_id)))?;
+        sshsig::verify(&signature, public_key, sshsig::GIT_NAMESPACE, &payload)
+            .map_err(|e| GitError::Crypto(format!("Invalid signature on commit {}: {}", commit_id, e)))
+    }
+    
+    /// The public key recorded in the signature of a commit
+    ///
+    /// Commits signed with an ephemeral key can only be checked against this
+    /// key, which shows the commit wasn't altered after signing but not who
+    /// signed it.
+    pub fn commit_signing_key(&self, commit_id: &ObjectId) -> Result<PublicKey> {
+        let repo = gix::open(&self.git_dir)
+            .map_err(|e| GitError::Repository(format!("Failed to open repository: {}", e), Some(self.path.clone())))?;
+        let object = repo.find_object(gix::ObjectId::from(commit_id))
+            .map_err(|e| GitError::ObjectStorage(format!("Failed to read commit {}: {}", commit_id, e)))?;
+        
+        let (_, signature) = commit::split_signature(&object.data)
+            .ok_or_else(|| GitError::Crypto(format!("Commit {} is not signed", commit_id)))?;
+        let signature = sshsig::parse(&signature)
+            .map_err(|e| GitError::Crypto(format!("Invalid signature on commit {}: {}", commit_id, e)))?;
+        PublicKey::from_bytes(&signature.public_key)
+            .map_err(|e| GitError::Crypto(format!("Invalid key in signature of commit {}: {}", commit_id, e)))
+    }
 }
 
 /// Find the .git directory for a repository
@@ -224,4 +405,104 @@ fn find_git_dir(path: &Path) -> Result<PathBuf> {
             )));
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::crypto::{KeyPair, SigningMode};
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git").args(args).current_dir(dir).output().unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_signatures() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["config", "user.name", "Config User"]);
+        git(dir.path(), &["config", "user.email", "config@example.com"]);
+        let repo = Repository::open(dir.path()).unwrap();
+        let signature = Signature::from_repo_config(&repo).unwrap();
+        assert_eq!((signature.name(), signature.email()), ("Config User", "config@example.com"));
+
+        // The offset the time was taken in is kept
+        let ist = FixedOffset::east_opt(5 * 3600 + 1800).unwrap();
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap().with_timezone(&ist);
+        assert_eq!(Signature::new("A", "a@b", time).to_string(), "A <a@b> 1700000000 +0530");
+        let west = FixedOffset::west_opt(7 * 3600).unwrap();
+        assert!(Signature::new("A", "a@b", time.with_timezone(&west)).to_string().ends_with(" -0700"));
+        let actor = Signature::new("A", "a@b", time).to_actor();
+        assert_eq!((actor.time.seconds as i64, actor.time.offset), (1_700_000_000, 5 * 3600 + 1800));
+
+        // Without user.name and user.email there is no signature to fall back to
+        assert!(Signature::from_config(&Config::parse("[user]\n\tname = Only Name\n", None).unwrap()).is_err());
+
+        // Anonymous signatures are stable per key and don't reveal the timezone
+        let key = || KeyPair::from_seed(&[3u8; 32]).unwrap();
+        let first = Signature::anonymous(&AnonymousIdentity::new(key(), "example.onion"));
+        let second = Signature::anonymous(&AnonymousIdentity::new(key(), "example.onion"));
+        assert_eq!((first.name(), first.email()), (second.name(), second.email()));
+        assert_eq!(first.email(), "example@anonymous.onion");
+        assert!(first.to_string().ends_with(" +0000"));
+    }
+
+    #[test]
+    fn test_signed_commit_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(dir.path().join("file.txt"), "content\n").unwrap();
+        git(dir.path(), &["add", "file.txt"]);
+
+        let repo = Repository::open(dir.path()).unwrap();
+        let key = KeyPair::from_seed(&[9u8; 32]).unwrap();
+        let signer = Signature::new("Signer", "signer@example.onion", Utc::now());
+        let first = repo.create_commit_signed("HEAD", &signer, &signer, "Signed commit", &[], &key).unwrap();
+        assert_eq!(git(dir.path(), &["rev-parse", "refs/heads/main"]), first.to_hex());
+        assert_eq!(git(dir.path(), &["log", "-1", "--format=%s"]), "Signed commit");
+        assert!(git(dir.path(), &["cat-file", "commit", "HEAD"]).contains("gpgsig -----BEGIN SSH SIGNATURE-----"));
+
+        assert!(repo.verify_commit_signature(&first, &key.public_key()).unwrap());
+        let other = KeyPair::from_seed(&[8u8; 32]).unwrap();
+        assert!(!repo.verify_commit_signature(&first, &other.public_key()).unwrap());
+
+        // The branch only moves forward from the commit it points to
+        let second = repo.create_commit_signed("HEAD", &signer, &signer, "Second", &[first.clone()], &key).unwrap();
+        assert_eq!(git(dir.path(), &["rev-parse", "HEAD~1"]), first.to_hex());
+        assert!(repo.create_commit_signed("HEAD", &signer, &signer, "Stale", &[first.clone()], &key).is_err());
+        assert_eq!(git(dir.path(), &["rev-parse", "HEAD"]), second.to_hex());
+
+        // Keeping the signature but changing the message breaks it
+        let raw = git(dir.path(), &["cat-file", "commit", &second.to_hex()]);
+        let forged_path = dir.path().join("forged");
+        std::fs::write(&forged_path, format!("{}\n", raw.replace("Second", "Forged"))).unwrap();
+        let forged = git(dir.path(), &["hash-object", "-t", "commit", "-w", forged_path.to_str().unwrap()]);
+        let forged = ObjectId::from_hex(&forged).unwrap();
+        assert!(!repo.verify_commit_signature(&forged, &key.public_key()).unwrap());
+
+        git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-q", "--allow-empty", "-m", "unsigned"]);
+        let unsigned = ObjectId::from_hex(&git(dir.path(), &["rev-parse", "HEAD"])).unwrap();
+        assert!(repo.verify_commit_signature(&unsigned, &key.public_key()).is_err());
+    }
+
+    #[test]
+    fn test_ephemeral_signed_commit_records_key() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(dir.path().join("file.txt"), "content\n").unwrap();
+        git(dir.path(), &["add", "file.txt"]);
+
+        let repo = Repository::open(dir.path()).unwrap();
+        let signer = Signature::new("Signer", "signer@example.onion", Utc::now());
+        let ephemeral = SigningMode::Ephemeral.signer();
+        let commit = repo.create_commit_signed("HEAD", &signer, &signer, "Ephemeral", &[], ephemeral.as_ref()).unwrap();
+
+        // Verifying against the recorded key is all an ephemeral signature allows
+        let recorded = repo.commit_signing_key(&commit).unwrap();
+        assert_eq!(recorded.as_bytes(), ephemeral.public_key().as_slice());
+        assert!(repo.verify_commit_signature(&commit, &recorded).unwrap());
+    }
+}
@@ -1,13 +1,31 @@
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::io::Write;
 
-use crate::core::{GitError, Result, ObjectId};
+use crate::core::{GitError, Result, ObjectId, LockFile, io_err, repo_err};
+
+/// Name of the file holding packed references
+pub const PACKED_REFS_FILE: &str = "packed-refs";
+
+/// How many symbolic refs are followed before giving up, like git
+const MAX_SYMREF_DEPTH: usize = 5;
 
 /// Storage for Git references
+///
+/// Reads loose refs first and falls back to `packed-refs`. Writes go through
+/// [`RefTransaction`], which locks every ref it touches.
 pub struct RefStorage {
     path: PathBuf,
-    refs: HashMap<String, String>,
+}
+
+/// A reference in `packed-refs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedRef {
+    /// The object the ref points to
+    pub target: ObjectId,
+    /// For annotated tags, the object the tag points to (`^` line)
+    pub peeled: Option<ObjectId>,
 }
 
 impl RefStorage {
@@ -15,98 +33,132 @@ impl RefStorage {
     pub fn new(repo_path: &Path) -> Self {
         Self {
             path: repo_path.to_path_buf(),
-            refs: HashMap::new(),
         }
     }
     
     /// Get a reference value
+    ///
+    /// Returns the raw value: an object id, or `ref: <name>` for symbolic refs.
     pub fn get_ref(&self, name: &str) -> Result<Option<String>> {
         let ref_path = self.path.join(name);
         
-        if ref_path.exists() {
+        if ref_path.is_file() {
             let content = fs::read_to_string(&ref_path)
-                .map_err(GitError::Io)?
+                .map_err(|e| io_err(format!("Failed to read reference: {}", e), &ref_path))?
                 .trim()
                 .to_string();
                 
             return Ok(Some(content));
         }
         
-        // Check packed-refs
-        let packed_refs_path = self.path.join("packed-refs");
-        if packed_refs_path.exists() {
-            let content = fs::read_to_string(&packed_refs_path)
-                .map_err(GitError::Io)?;
-                
-            for line in content.lines() {
-                let line = line.trim();
-                if line.is_empty() || line.starts_with('#') {
-                    continue;
-                }
+        Ok(self.packed_refs()?.remove(name).map(|packed| packed.target.to_hex()))
+    }
                 
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() == 2 && parts[1] == name {
-                    return Ok(Some(parts[0].to_string()));
-                }
+    /// Resolve a reference to an object id, following symbolic refs
+    ///
+    /// Returns `None` for missing refs and unborn branches.
+    pub fn resolve(&self, name: &str) -> Result<Option<ObjectId>> {
+        let mut name = name.to_string();
+        for _ in 0..MAX_SYMREF_DEPTH {
+            let value = match self.get_ref(&name)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            match value.strip_prefix("ref:") {
+                Some(target) => name = target.trim().to_string(),
+                None => return ObjectId::from_hex(&value).map(Some),
             }
         }
-        
-        Ok(None)
+        Err(repo_err(format!("Too many levels of symbolic refs at {}", name), &self.path))
     }
     
-    /// Set a reference value
-    pub fn update_ref(&mut self, name: &str, value: &str) -> Result<()> {
-        let ref_path = self.path.join(name);
+    /// Read `packed-refs`; empty if there is none
+    pub fn packed_refs(&self) -> Result<BTreeMap<String, PackedRef>> {
+        let packed_refs_path = self.path.join(PACKED_REFS_FILE);
+        let content = match fs::read_to_string(&packed_refs_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(io_err(format!("Failed to read packed refs: {}", e), &packed_refs_path)),
+        };
         
-        // Ensure the directory exists
-        if let Some(parent) = ref_path.parent() {
-            fs::create_dir_all(parent).map_err(GitError::Io)?;
-        }
+        let mut refs: BTreeMap<String, PackedRef> = BTreeMap::new();
+        let mut last: Option<String> = None;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
         
-        fs::write(&ref_path, format!("{}\n", value))
-            .map_err(GitError::Io)?;
+            // A peeled line belongs to the tag right above it
+            if let Some(peeled) = line.strip_prefix('^') {
+                let peeled = ObjectId::from_hex(peeled)?;
+                match last.as_ref().and_then(|name| refs.get_mut(name)) {
+                    Some(packed) => packed.peeled = Some(peeled),
+                    None => return Err(repo_err("Peeled line without a reference in packed-refs", &packed_refs_path)),
+                }
+                continue;
+            }
             
-        self.refs.insert(name.to_string(), value.to_string());
+            let (target, name) = line.split_once(' ')
+                .ok_or_else(|| repo_err(format!("Invalid packed-refs line: {}", line), &packed_refs_path))?;
+            refs.insert(name.to_string(), PackedRef { target: ObjectId::from_hex(target)?, peeled: None });
+            last = Some(name.to_string());
+        }
         
-        Ok(())
+        Ok(refs)
     }
     
-    /// Delete a reference
+    /// Set a reference value
+    pub fn update_ref(&mut self, name: &str, value: &str) -> Result<()> {
+        let mut transaction = self.transaction();
+        transaction.set(name, value, None);
+        transaction.commit()
+    }
+
+    /// Delete a reference, loose or packed
     pub fn delete_ref(&mut self, name: &str) -> Result<()> {
-        let ref_path = self.path.join(name);
-        
-        if ref_path.exists() {
-            fs::remove_file(&ref_path).map_err(GitError::Io)?;
-            self.refs.remove(name);
-        }
-        
-        Ok(())
+        let mut transaction = self.transaction();
+        transaction.delete(name, None);
+        transaction.commit()
     }
-    
-    /// Get all references
+
+    /// Start a transaction that updates several refs at once
+    pub fn transaction(&self) -> RefTransaction<'_> {
+        RefTransaction { storage: self, updates: Vec::new() }
+    }
+
+    /// Get all references under `prefix`, loose and packed
     pub fn list_refs(&self, prefix: &str) -> Result<Vec<String>> {
-        let mut refs = Vec::new();
+        let mut refs: Vec<String> = self.packed_refs()?
+            .into_keys()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
         let ref_dir = self.path.join(prefix);
-        
-        if ref_dir.exists() {
+
+        if ref_dir.is_dir() {
             Self::list_refs_recursive(&ref_dir, &self.path, &mut refs)?;
         }
-        
+
+        refs.sort();
+        refs.dedup();
         Ok(refs)
     }
     
     /// Recursively list references
     fn list_refs_recursive(dir: &Path, base: &Path, result: &mut Vec<String>) -> Result<()> {
-        for entry in fs::read_dir(dir).map_err(GitError::Io)? {
-            let entry = entry.map_err(GitError::Io)?;
+        let entries = fs::read_dir(dir)
+            .map_err(|e| io_err(format!("Failed to read reference directory: {}", e), dir))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| io_err(format!("Failed to read reference directory: {}", e), dir))?;
             let path = entry.path();
             
             if path.is_dir() {
                 Self::list_refs_recursive(&path, base, result)?;
-            } else {
-                if let Ok(relative) = path.strip_prefix(base) {
-                    result.push(relative.to_string_lossy().into_owned());
-                }
+            } else if path.extension().map_or(false, |ext| ext == "lock") {
+                // Another writer's pending update, not a ref
+                continue;
+            } else if let Ok(relative) = path.strip_prefix(base) {
+                result.push(relative.to_string_lossy().replace('\\', "/"));
             }
         }
         
@@ -122,4 +174,212 @@ impl RefStorage {
     pub fn set_head(&mut self, target: &str) -> Result<()> {
         self.update_ref("HEAD", target)
     }
-}
\ No newline at end of file
+}
+
+/// One change in a [`RefTransaction`]
+#[derive(Debug, Clone)]
+struct RefUpdate {
+    name: String,
+    /// New raw value, or `None` to delete
+    new: Option<String>,
+    /// Required current target: `Some(None)` means the ref must not exist
+    expected: Option<Option<ObjectId>>,
+}
+
+/// Updates to several refs that are applied all together or not at all
+///
+/// Every ref is locked and checked against its expected value before any of
+/// them changes. `packed-refs` is locked and rewritten only when a deleted
+/// ref is packed.
+pub struct RefTransaction<'a> {
+    storage: &'a RefStorage,
+    updates: Vec<RefUpdate>,
+}
+
+impl<'a> RefTransaction<'a> {
+    /// Point `name` at `value`, an object id or `ref: <name>`
+    ///
+    /// With `expected`, the ref must currently resolve to `Some(id)`, or not
+    /// exist for `None`.
+    pub fn set(&mut self, name: &str, value: &str, expected: Option<Option<ObjectId>>) -> &mut Self {
+        self.updates.push(RefUpdate { name: name.to_string(), new: Some(value.to_string()), expected });
+        self
+    }
+
+    /// Delete `name`, optionally only if it resolves to `expected`
+    pub fn delete(&mut self, name: &str, expected: Option<ObjectId>) -> &mut Self {
+        self.updates.push(RefUpdate { name: name.to_string(), new: None, expected: expected.map(Some) });
+        self
+    }
+
+    /// Number of queued updates
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    /// Check whether nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Apply all updates
+    ///
+    /// Fails without changing any ref if a lock is held
+    /// ([`GitError::Locked`]) or a ref doesn't have its expected value.
+    pub fn commit(self) -> Result<()> {
+        let storage = self.storage;
+        let mut updates = self.updates;
+        updates.sort_by(|a, b| a.name.cmp(&b.name));
+        for pair in updates.windows(2) {
+            if pair[0].name == pair[1].name {
+                return Err(GitError::InvalidArgument(format!("Reference {} is updated twice", pair[0].name)));
+            }
+        }
+        for update in &updates {
+            check_ref_name(&update.name)?;
+        }
+
+        // Lock everything first, in a stable order
+        let mut locks = Vec::with_capacity(updates.len());
+        for update in &updates {
+            locks.push(LockFile::acquire(storage.path.join(&update.name))?);
+        }
+        let mut packed = storage.packed_refs()?;
+        let packed_lock = if updates.iter().any(|u| u.new.is_none() && packed.contains_key(&u.name)) {
+            Some(LockFile::acquire(storage.path.join(PACKED_REFS_FILE))?)
+        } else {
+            None
+        };
+        // Re-read under the lock so a concurrent repack can't slip in between
+        if packed_lock.is_some() {
+            packed = storage.packed_refs()?;
+        }
+
+        for update in &updates {
+            if let Some(expected) = &update.expected {
+                let current = storage.resolve(&update.name)?;
+                if &current != expected {
+                    let show = |id: &Option<ObjectId>| id.as_ref().map_or("nothing".to_string(), |id| id.to_hex());
+                    return Err(repo_err(
+                        format!("Reference {} is at {}, expected {}", update.name, show(&current), show(expected)),
+                        &storage.path,
+                    ));
+                }
+            }
+        }
+
+        // Nothing has changed yet; from here on only renames and removals remain
+        for (update, lock) in updates.iter().zip(locks.iter_mut()) {
+            if let Some(value) = &update.new {
+                writeln!(lock.file_mut(), "{}", value)
+                    .map_err(|e| io_err(format!("Failed to write reference: {}", e), lock.path()))?;
+            }
+        }
+
+        if let Some(mut lock) = packed_lock {
+            for update in updates.iter().filter(|u| u.new.is_none()) {
+                packed.remove(&update.name);
+            }
+            write_packed_refs(lock.file_mut(), &packed)
+                .map_err(|e| io_err(format!("Failed to write packed refs: {}", e), lock.path()))?;
+            lock.commit()?;
+        }
+
+        for (update, lock) in updates.iter().zip(locks) {
+            if update.new.is_some() {
+                lock.commit()?;
+                continue;
+            }
+            let path = storage.path.join(&update.name);
+            match fs::remove_file(&path) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(io_err(format!("Failed to delete reference: {}", e), &path)),
+            }
+            // Dropping the lock removes it
+            drop(lock);
+        }
+        
+        Ok(())
+    }
+}
+
+/// Reject names that could escape the git directory or clash with lock files
+fn check_ref_name(name: &str) -> Result<()> {
+    let valid = (name == "HEAD" || name.starts_with("refs/"))
+        && !name.ends_with('/')
+        && !name.ends_with(".lock")
+        && name.split('/').all(|component| !component.is_empty() && component != "." && component != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(GitError::InvalidArgument(format!("Invalid reference name: {}", name)))
+    }
+}
+
+/// Write `packed-refs` in git's format
+fn write_packed_refs(out: &mut impl Write, refs: &BTreeMap<String, PackedRef>) -> std::io::Result<()> {
+    writeln!(out, "# pack-refs with: peeled fully-peeled sorted ")?;
+    for (name, packed) in refs {
+        writeln!(out, "{} {}", packed.target, name)?;
+        if let Some(peeled) = &packed.peeled {
+            writeln!(out, "^{}", peeled)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git").args(args).current_dir(dir).output().unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_transaction_with_packed_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-q", "--allow-empty", "-m", "one"]);
+        git(dir.path(), &["branch", "packed"]);
+        git(dir.path(), &["tag", "-a", "-m", "tag", "v1"]);
+        git(dir.path(), &["pack-refs", "--all"]);
+        let head = ObjectId::from_hex(&git(dir.path(), &["rev-parse", "HEAD"])).unwrap();
+
+        let storage = RefStorage::new(&dir.path().join(".git"));
+        assert_eq!(storage.resolve("HEAD").unwrap(), Some(head.clone()));
+        assert_eq!(storage.resolve("refs/heads/packed").unwrap(), Some(head.clone()));
+        assert!(storage.packed_refs().unwrap()["refs/tags/v1"].peeled.is_some());
+        assert_eq!(storage.list_refs("refs/heads/").unwrap(), vec!["refs/heads/main", "refs/heads/packed"]);
+
+        // A failed expectation leaves every ref alone
+        let mut transaction = storage.transaction();
+        transaction.delete("refs/heads/packed", Some(head.clone()));
+        transaction.set("refs/heads/new", &head.to_hex(), Some(Some(head.clone())));
+        assert!(transaction.commit().is_err());
+        assert!(storage.resolve("refs/heads/packed").unwrap().is_some());
+        assert!(storage.resolve("refs/heads/new").unwrap().is_none());
+        assert!(!dir.path().join(".git/packed-refs.lock").exists());
+
+        let mut transaction = storage.transaction();
+        transaction.delete("refs/heads/packed", Some(head.clone()));
+        transaction.set("refs/heads/new", &head.to_hex(), Some(None));
+        transaction.commit().unwrap();
+        assert!(storage.resolve("refs/heads/packed").unwrap().is_none());
+        assert_eq!(git(dir.path(), &["rev-parse", "refs/heads/new"]), head.to_hex());
+        assert_eq!(git(dir.path(), &["rev-parse", "v1^{}"]), head.to_hex());
+        git(dir.path(), &["fsck", "--no-progress"]);
+
+        // A held lock refuses the whole transaction
+        fs::write(dir.path().join(".git/refs/heads/main.lock"), "").unwrap();
+        let mut transaction = storage.transaction();
+        transaction.set("refs/heads/other", &head.to_hex(), None);
+        transaction.set("refs/heads/main", &head.to_hex(), None);
+        assert!(matches!(transaction.commit(), Err(GitError::Locked(_))));
+        assert!(storage.resolve("refs/heads/other").unwrap().is_none());
+    }
+}
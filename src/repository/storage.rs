@@ -5,35 +5,113 @@ use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 
-use crate::core::{GitError, Result, ObjectId, ObjectType, ObjectStorage};
+use crate::core::{GitError, Result, ObjectId, ObjectType, ObjectStorage, io_err};
+
+/// Location of the alternates file, relative to an objects directory
+pub const ALTERNATES_FILE: &str = "info/alternates";
+
+/// Alternates may themselves have alternates; git stops following after this many levels
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+/// Read the object directories listed in `<objects_dir>/info/alternates`
+///
+/// Follows nested alternates, resolves relative entries against the objects
+/// directory that lists them and skips blank lines, comments and entries
+/// that do not exist. A missing file means there are no alternates.
+pub fn read_alternates(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut alternates = Vec::new();
+    collect_alternates(objects_dir, 0, &mut alternates)?;
+    Ok(alternates)
+}
+
+fn collect_alternates(objects_dir: &Path, depth: usize, alternates: &mut Vec<PathBuf>) -> Result<()> {
+    let file = objects_dir.join(ALTERNATES_FILE);
+    let contents = match fs::read_to_string(&file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_err(format!("Failed to read alternates: {}", e), &file)),
+    };
+    
+    if depth >= MAX_ALTERNATE_DEPTH {
+        log::warn!("Ignoring alternates of {}: nested too deeply", objects_dir.display());
+        return Ok(());
+    }
+    
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        
+        let dir = objects_dir.join(line);
+        let dir = dir.canonicalize().unwrap_or(dir);
+        if !dir.is_dir() {
+            log::warn!("Ignoring missing alternate object directory {}", dir.display());
+            continue;
+        }
+        if alternates.contains(&dir) {
+            continue;
+        }
+        
+        alternates.push(dir.clone());
+        collect_alternates(&dir, depth + 1, alternates)?;
+    }
+    
+    Ok(())
+}
 
 /// File system implementation of Git object storage
+///
+/// Objects missing from `path` are looked up in the alternate object
+/// directories listed in `path/info/alternates`, so repositories can share
+/// one object store. New objects are always written to `path`.
 pub struct FileSystemObjectStore {
     path: PathBuf,
+    alternates: Vec<PathBuf>,
 }
 
 impl FileSystemObjectStore {
     /// Create a new file system object store
     pub fn new(path: PathBuf) -> Self {
-        Self { path }
+        let alternates = read_alternates(&path).unwrap_or_else(|e| {
+            log::warn!("Failed to load alternates for {}: {}", path.display(), e);
+            Vec::new()
+        });
+        Self { path, alternates }
+    }
+    
+    /// Get the alternate object directories consulted by this store
+    pub fn alternates(&self) -> &[PathBuf] {
+        &self.alternates
     }
     
     /// Get the path for an object file
     fn object_path(&self, id: &ObjectId) -> PathBuf {
+        Self::object_path_in(&self.path, id)
+    }
+    
+    /// Get the path for an object file below `objects_dir`
+    fn object_path_in(objects_dir: &Path, id: &ObjectId) -> PathBuf {
         let id_hex = id.to_hex();
         let dir = &id_hex[0..2];
         let file = &id_hex[2..];
-        self.path.join(dir).join(file)
+        objects_dir.join(dir).join(file)
+    }
+    
+    /// Find the file holding an object, in this store or one of its alternates
+    fn find_object_path(&self, id: &ObjectId) -> Option<PathBuf> {
+        std::iter::once(&self.path)
+            .chain(self.alternates.iter())
+            .map(|dir| Self::object_path_in(dir, id))
+            .find(|path| path.exists())
     }
 }
 
 impl ObjectStorage for FileSystemObjectStore {
     fn read_object(&self, id: &ObjectId) -> Result<(ObjectType, Vec<u8>)> {
-        let path = self.object_path(id);
-        
-        if !path.exists() {
-            return Err(GitError::NotFound(id.clone()));
-        }
+        let path = match self.find_object_path(id) {
+            Some(path) => path,
+            None => return Err(GitError::NotFound(id.clone())),
+        };
         
         // Open file and create zlib decoder
         let file = fs::File::open(path).map_err(GitError::Io)?;
@@ -87,8 +165,8 @@ impl ObjectStorage for FileSystemObjectStore {
             fs::create_dir_all(parent).map_err(GitError::Io)?;
         }
         
-        // Check if object already exists
-        if path.exists() {
+        // Check if object already exists, including in a shared alternate store
+        if self.find_object_path(&id).is_some() {
             return Ok(id);
         }
         
@@ -116,6 +194,6 @@ impl ObjectStorage for FileSystemObjectStore {
     }
     
     fn has_object(&self, id: &ObjectId) -> Result<bool> {
-        Ok(self.object_path(id).exists())
+        Ok(self.find_object_path(id).is_some())
     }
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+//! Tor v3 client authorization for private repositories
+//!
+//! With authorized clients configured, the service descriptor is encrypted
+//! to their keys (arti calls this restricted discovery). Tor clients without
+//! one of the matching private keys can't decrypt the descriptor, so they
+//! can't even find the introduction points; nothing reaches the Git handler.
+//!
+//! Each client generates its own x25519 keypair and sends only the public
+//! half to the operator. With arti, for example:
+//!
+//! ```text
+//! arti hsc key get --onion-address <address>.onion
+//! ```
+//!
+//! stores the private key in the client's keystore and prints the public key
+//! as `descriptor:x25519:<base32>`, the same format C Tor uses in its
+//! `authorized_clients/*.auth` files. That string goes into
+//! `authorized_clients` of the onion service config, or is passed to
+//! `serve --authorized-client`.
+
+use tor_hscrypto::pk::HsClientDescEncKey;
+use tor_hsservice::config::restricted_discovery::HsClientNickname;
+
+use crate::core::{GitError, Result};
+
+/// Prefix of the public key of an authorized client
+pub const CLIENT_KEY_PREFIX: &str = "descriptor:x25519:";
+
+/// Parse an authorized client key in `descriptor:x25519:<base32>` form
+pub fn parse_client_key(key: &str) -> Result<HsClientDescEncKey> {
+    let key = key.trim();
+    if !key.starts_with(CLIENT_KEY_PREFIX) {
+        return Err(GitError::Config(format!(
+            "Authorized client key must look like {}<base32>, got '{}'", CLIENT_KEY_PREFIX, key,
+        )));
+    }
+    key.parse()
+        .map_err(|e| GitError::Config(format!("Invalid authorized client key '{}': {}", key, e)))
+}
+
+/// Parse every configured client key, naming the clients by position
+///
+/// The nicknames only label the keys in arti's logs.
+pub fn authorized_clients(keys: &[String]) -> Result<Vec<(HsClientNickname, HsClientDescEncKey)>> {
+    keys.iter()
+        .enumerate()
+        .map(|(index, key)| {
+            let nickname = format!("client-{}", index)
+                .parse()
+                .map_err(|e| GitError::Config(format!("Invalid client nickname: {}", e)))?;
+            Ok((nickname, parse_client_key(key)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_key() {
+        let key = format!("{}{}", CLIENT_KEY_PREFIX, "A".repeat(52));
+        assert!(parse_client_key(&key).is_ok());
+        assert_eq!(authorized_clients(&[key.clone(), key]).unwrap().len(), 2);
+
+        assert!(parse_client_key(&"A".repeat(52)).is_err());
+        assert!(parse_client_key("descriptor:x25519:not-base32").is_err());
+        assert!(parse_client_key("descriptor:ed25519:AAAA").is_err());
+    }
+}
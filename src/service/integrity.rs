@@ -0,0 +1,217 @@
+//! Startup integrity checks for served repositories
+//!
+//! The checks are deliberately cheap: each pack index is hashed and matched
+//! against its pack's header and trailer, and HEAD must resolve to a commit.
+//! Packs are never inflated, so a corrupt object inside an otherwise
+//! well-formed pack is not detected.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use gix::Repository;
+
+use crate::core::{Result, io_err, repo_err};
+use crate::protocol::check_index_checksum;
+
+/// How deep below the served directory to look for repositories
+const MAX_SEARCH_DEPTH: usize = 4;
+
+/// Outcome of checking one repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityStatus {
+    /// All checks passed
+    Ok { packs: usize },
+    /// The repository is too large to check and is served unchecked
+    Skipped { pack_bytes: u64 },
+}
+
+/// Check the pack indexes and HEAD of a repository
+///
+/// Repositories with more than `max_pack_bytes` of packs are skipped when
+/// the limit is non-zero. Returns the first problem found as an error.
+pub fn check_repository(repo: &Repository, max_pack_bytes: u64) -> Result<IntegrityStatus> {
+    let pack_dir = repo.path().join("objects").join("pack");
+    let mut indexes = Vec::new();
+    let mut pack_bytes = 0;
+    if pack_dir.is_dir() {
+        let entries = std::fs::read_dir(&pack_dir)
+            .map_err(|e| io_err(format!("Failed to read pack directory: {}", e), &pack_dir))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("idx") => indexes.push(path),
+                Some("pack") => pack_bytes += entry.metadata().map(|m| m.len()).unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+
+    if max_pack_bytes > 0 && pack_bytes > max_pack_bytes {
+        return Ok(IntegrityStatus::Skipped { pack_bytes });
+    }
+
+    indexes.sort();
+    for index in &indexes {
+        check_pack(repo, index)?;
+    }
+    check_head(repo)?;
+
+    Ok(IntegrityStatus::Ok { packs: indexes.len() })
+}
+
+/// Validate a pack index and the pack it describes
+fn check_pack(repo: &Repository, index_path: &Path) -> Result<()> {
+    let pack_path = index_path.with_extension("pack");
+    if !pack_path.is_file() {
+        return Err(repo_err("Pack index has no pack file", index_path));
+    }
+
+    // The index ends with the pack checksum followed by its own checksum
+    let pack_checksum = check_index_checksum(index_path, repo.object_hash())?;
+
+    let index = gix::odb::pack::index::File::at(index_path, repo.object_hash())
+        .map_err(|e| repo_err(format!("Invalid pack index: {}", e), index_path))?;
+
+    let mut pack = File::open(&pack_path)
+        .map_err(|e| io_err(format!("Failed to open pack: {}", e), &pack_path))?;
+    let mut header = [0u8; 12];
+    pack.read_exact(&mut header)
+        .map_err(|e| repo_err(format!("Pack header is unreadable: {}", e), &pack_path))?;
+    if &header[..4] != b"PACK" || !matches!(header[7], 2 | 3) || header[4..7] != [0, 0, 0] {
+        return Err(repo_err("Pack has an invalid header", &pack_path));
+    }
+    let object_count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    if object_count != index.num_objects() {
+        return Err(repo_err(
+            format!("Pack has {} objects but its index lists {}", object_count, index.num_objects()),
+            &pack_path,
+        ));
+    }
+
+    let mut trailer = vec![0u8; pack_checksum.len()];
+    pack.seek(SeekFrom::End(-(pack_checksum.len() as i64)))
+        .and_then(|_| pack.read_exact(&mut trailer))
+        .map_err(|e| repo_err(format!("Pack trailer is unreadable: {}", e), &pack_path))?;
+    if trailer != pack_checksum {
+        return Err(repo_err("Pack checksum doesn't match its index", &pack_path));
+    }
+
+    Ok(())
+}
+
+/// Make sure HEAD is unborn or points at a readable commit
+fn check_head(repo: &Repository) -> Result<()> {
+    let head = repo.head()
+        .map_err(|e| repo_err(format!("HEAD is unreadable: {}", e), repo.path()))?;
+    // A freshly created repository has nothing to serve yet, which is fine
+    if head.is_unborn() {
+        return Ok(());
+    }
+    repo.head_commit()
+        .map(|_| ())
+        .map_err(|e| repo_err(format!("HEAD does not resolve to a commit: {}", e), repo.path()))
+}
+
+/// Check every repository below `repo_dir`
+///
+/// Returns the repositories that failed, keyed by canonical path, with the
+/// problem found. Each result is logged.
+pub fn check_served_repositories(repo_dir: &Path, max_pack_bytes: u64) -> HashMap<PathBuf, String> {
+    let mut failed = HashMap::new();
+    for path in find_repositories(repo_dir) {
+        let repo = match gix::open(&path) {
+            Ok(repo) => repo,
+            Err(e) => {
+                failed.insert(canonical(&path), format!("Failed to open repository: {}", e));
+                continue;
+            }
+        };
+        match check_repository(&repo, max_pack_bytes) {
+            Ok(IntegrityStatus::Ok { packs }) => {
+                log::info!("Integrity check passed for {} ({} packs)", path.display(), packs);
+            },
+            Ok(IntegrityStatus::Skipped { pack_bytes }) => {
+                log::warn!("Skipping integrity check for {}: {} bytes of packs", path.display(), pack_bytes);
+            },
+            Err(e) => {
+                log::error!("Refusing to serve {}: {}", path.display(), e);
+                failed.insert(canonical(&path), e.to_string());
+            }
+        }
+    }
+    failed
+}
+
+/// Canonical form of a repository path, for comparing against requests
+pub fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Find repositories at or below `root`, without descending into them
+fn find_repositories(root: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        if is_repository(&dir) {
+            found.push(dir);
+            continue;
+        }
+        if depth >= MAX_SEARCH_DEPTH {
+            continue;
+        }
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if path.is_dir() && !hidden {
+                    pending.push((path, depth + 1));
+                }
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Check for a worktree with `.git` or a bare repository
+pub fn is_repository(dir: &Path) -> bool {
+    dir.join(".git").exists() || (dir.join("HEAD").is_file() && dir.join("objects").is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").args(args).current_dir(dir).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn test_detects_truncated_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        std::fs::write(dir.path().join("file.txt"), "content\n").unwrap();
+        git(dir.path(), &["add", "file.txt"]);
+        git(dir.path(), &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", "init"]);
+        git(dir.path(), &["repack", "-qad"]);
+
+        let repo = gix::open(dir.path()).unwrap();
+        assert_eq!(check_repository(&repo, 0).unwrap(), IntegrityStatus::Ok { packs: 1 });
+        assert!(matches!(check_repository(&repo, 1).unwrap(), IntegrityStatus::Skipped { .. }));
+
+        let pack = std::fs::read_dir(dir.path().join(".git/objects/pack")).unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|path| path.extension().map_or(false, |ext| ext == "pack"))
+            .unwrap();
+        let data = std::fs::read(&pack).unwrap();
+        std::fs::write(&pack, &data[..data.len() - 1]).unwrap();
+
+        assert!(check_repository(&repo, 0).is_err());
+        assert_eq!(check_served_repositories(dir.path(), 0).len(), 1);
+    }
+}
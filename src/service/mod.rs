@@ -1,19 +1,32 @@
+mod client_auth;
+mod integrity;
+mod supervisor;
+
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::net::SocketAddr;
 use std::io;
+use std::time::Duration;
 
 use arti_client::{TorClient, OnionServiceConfig};
 use tor_rtcompat::{Runtime, PreferredRuntime};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tor_hsservice::status::State as DescriptorState;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
 use gix::Repository;
 
-use crate::core::{GitError, Result, OnionServiceConfig as ArtiGitOnionConfig};
-use crate::protocol::{GitCommand, parse_git_command, send_refs_advertisement, 
-                     process_wants, send_packfile, receive_packfile, update_references};
+use crate::core::{GitError, Result, OnionServiceConfig as ArtiGitOnionConfig, RepoPolicy};
+use crate::protocol::{GitCommand, parse_git_request, git_request_length, send_refs_advertisement, 
+                     process_wants, send_packfile, receive_packfile, update_references, PushCertPolicy, PackLimits,
+                     GitProtocolVersion, serve_v2, write_err_packet, MAX_GIT_REQUEST_LENGTH, GIT_REQUEST_PREFIX_LENGTH};
 use crate::utils;
 
+pub use client_auth::{CLIENT_KEY_PREFIX, parse_client_key};
+pub use integrity::{IntegrityStatus, check_repository, check_served_repositories};
+pub use supervisor::{RestartPolicy, ServiceSupervisor};
+
 /// Git repository onion service
 pub struct GitOnionService<R: Runtime> {
     /// The directory containing Git repositories to serve
@@ -28,8 +41,24 @@ pub struct GitOnionService<R: Runtime> {
     /// Runtime for async operations
     runtime: R,
     
-    /// The onion address (once created)
-    onion_address: Option<String>,
+    /// The onion addresses (once published), the primary identity first
+    onion_addresses: Vec<String>,
+    
+    /// The LAN address actually bound (once started, if enabled)
+    lan_address: Option<SocketAddr>,
+    
+    /// Accept loops of the running service; each ends with the reason it stopped
+    tasks: Vec<JoinHandle<String>>,
+}
+
+/// Everything a connection handler needs, shared by all listeners
+#[derive(Clone)]
+struct ConnectionContext {
+    repo_dir: PathBuf,
+    push_cert: Arc<PushCertPolicy>,
+    limits: PackLimits,
+    rejected: Arc<HashMap<PathBuf, String>>,
+    policies: Arc<HashMap<String, RepoPolicy>>,
 }
 
 impl<R: Runtime> GitOnionService<R> {
@@ -53,12 +82,17 @@ impl<R: Runtime> GitOnionService<R> {
             tor_client,
             config,
             runtime,
-            onion_address: None,
+            onion_addresses: Vec::new(),
+            lan_address: None,
+            tasks: Vec::new(),
         })
     }
     
     /// Start the onion service
-    pub async fn start(&mut self) -> Result<String> {
+    ///
+    /// The service is published under every configured identity, and the
+    /// addresses are returned with the primary one first.
+    pub async fn start(&mut self) -> Result<Vec<String>> {
         // Bind to localhost on the configured port for local service
         let addr = SocketAddr::from(([127, 0, 0, 1], self.config.port));
         let listener = TcpListener::bind(addr)
@@ -67,67 +101,450 @@ impl<R: Runtime> GitOnionService<R> {
             
         println!("Local Git service listening on {}", addr);
         
-        // Configure the onion service
-        let onion_config = OnionServiceConfig::builder()
-            .nickname("arti-git")
-            .key_path(self.config.key_dir.join("hs_ed25519_secret_key"))
-            .build()
-            .map_err(|e| GitError::Config(format!("Failed to build onion service config: {}", e)))?;
+        // Bind the optional LAN listener before publishing anything, so a
+        // bad address fails the whole start
+        let lan_listener = match self.config.lan_bind {
+            Some(lan_addr) => Some(bind_lan_listener(lan_addr, addr, !self.config.authorized_clients.is_empty()).await?),
+            None => None,
+        };
+        if let Some(listener) = &lan_listener {
+            let lan_addr = listener.local_addr()
+                .map_err(|e| GitError::IO(format!("Failed to get LAN listener address: {}", e), None))?;
+            println!("LAN Git service listening on {} (connections do not go through Tor)", lan_addr);
+            self.lan_address = Some(lan_addr);
+        }
+        
+        // Clients without an authorized key can't decrypt the descriptors,
+        // so they are turned away by Tor before reaching the listener
+        let authorized_clients = client_auth::authorized_clients(&self.config.authorized_clients)?;
+        if !authorized_clients.is_empty() {
+            println!("Onion service restricted to {} authorized clients", authorized_clients.len());
+        }
+        
+        // Publish the same local service under every identity. A failure
+        // drops the handles published so far, withdrawing them again
+        let mut published = Vec::new();
+        for (nickname, key_path) in self.config.identities()? {
+            if let Some(key_dir) = key_path.parent() {
+                utils::ensure_dir_exists(key_dir)?;
+            }
+            let mut builder = OnionServiceConfig::builder();
+            builder.nickname(nickname.as_str()).key_path(key_path);
+            if !authorized_clients.is_empty() {
+                let discovery = builder.restricted_discovery();
+                discovery.enabled(true);
+                for (client, key) in &authorized_clients {
+                    discovery.static_keys().access().push((client.clone(), key.clone()));
+                }
+            }
+            let onion_config = builder
+                .build()
+                .map_err(|e| GitError::Config(format!("Failed to build onion service config for {}: {}", nickname, e)))?;
             
-        // Create and publish the onion service
-        let publish_handle = self.tor_client.publish_onion_service(
-            onion_config, 
-            [(self.config.port, addr)].into_iter()
-        )
-        .await
-        .map_err(|e| GitError::Transport(format!("Failed to publish onion service: {}", e)))?;
+            let publish_handle = self.tor_client.publish_onion_service(
+                onion_config, 
+                [(self.config.port, addr)].into_iter()
+            )
+            .await
+            .map_err(|e| GitError::Transport(format!("Failed to publish onion service {}: {}", nickname, e)))?;
+            
+            let onion_addr = publish_handle.onion_name().to_string();
+            println!("Onion service {} published at: {}", nickname, onion_addr);
+            published.push((nickname, onion_addr, Arc::new(publish_handle)));
+        }
+        self.onion_addresses = published.iter().map(|(_, onion_addr, _)| onion_addr.clone()).collect();
         
-        // Get the onion address
-        let onion_addr = publish_handle.onion_name().to_string();
-        println!("Onion service published at: {}", onion_addr);
-        self.onion_address = Some(onion_addr.clone());
+        // Arti refreshes a descriptor (on expiry and when the responsible
+        // HSDirs change) only while its handle is alive. Every handle is
+        // watched so a failed publish is reported, and republished under
+        // --supervise, instead of leaving the service silently unreachable;
+        // the check interval only controls the status logging
+        let (check_interval, log_changes) = match self.config.descriptor_check_interval {
+            0 => (PUBLISH_FAILURE_CHECK_INTERVAL, false),
+            secs => (Duration::from_secs(secs), true),
+        };
+        for (nickname, _, publish_handle) in &published {
+            let monitored = publish_handle.clone();
+            self.tasks.push(spawn_descriptor_monitor(
+                nickname.clone(),
+                move || {
+                    let status = monitored.status();
+                    (status.state(), status.current_problem().map(|problem| format!("{:?}", problem)))
+                },
+                check_interval,
+                log_changes,
+            ));
+        }
+        let publish_handles: Vec<_> = published.into_iter().map(|(_, _, handle)| handle).collect();
         
         // Start the local server that handles Git protocols
         let repo_dir = self.repo_dir.clone();
+        let push_cert = Arc::new(PushCertPolicy::from_config(
+            self.config.require_signed_push,
+            &self.config.trusted_push_keys,
+        )?.with_trust_on_use(self.config.accept_ephemeral_push_keys));
+        if push_cert.required {
+            println!("Signed pushes required ({} trusted keys)", push_cert.trusted_keys.len());
+        }
+        if push_cert.trust_on_use {
+            println!("Accepting pushes signed by untrusted and ephemeral keys");
+        }
+        let limits = PackLimits::from_config(self.config.max_pack_objects, self.config.max_pack_bytes);
         
-        // Spawn a task to handle incoming connections
-        tokio::spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((stream, addr)) => {
-                        println!("New connection from {}", addr);
-                        let repo_path = repo_dir.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = handle_git_connection(stream, &repo_path).await {
-                                eprintln!("Error handling connection: {}", e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("Error accepting connection: {}", e);
-                        break;
-                    }
-                }
-            }
-        });
+        // Find corrupt repositories before any client can clone them
+        let rejected = if self.config.verify_on_start {
+            integrity::check_served_repositories(&repo_dir, self.config.verify_max_pack_bytes)
+        } else {
+            HashMap::new()
+        };
+        for (path, problem) in &rejected {
+            eprintln!("Not serving {}: {}", path.display(), problem);
+        }
+        if !self.config.repo_policies.is_empty() {
+            println!("Serving only the {} repositories with an access policy", self.config.repo_policies.len());
+        }
+        let context = ConnectionContext {
+            repo_dir,
+            push_cert,
+            limits,
+            rejected: Arc::new(rejected),
+            policies: Arc::new(self.config.repo_policies.clone()),
+        };
+        
+        // Both listeners share the same handler and access policies. The
+        // onion loop owns the publish handles, so the service stays published
+        // exactly as long as it accepts connections
+        self.tasks.push(spawn_accept_loop(listener, context.clone(), "onion", publish_handles));
+        if let Some(listener) = lan_listener {
+            self.tasks.push(spawn_accept_loop(listener, context, "LAN", ()));
+        }
         
-        Ok(onion_addr)
+        Ok(self.onion_addresses.clone())
     }
     
-    /// Get the onion address of this service
+    /// Allow the client holding the private half of `pubkey` to connect
+    ///
+    /// `pubkey` is the client's x25519 key as `descriptor:x25519:<base32>`;
+    /// the client generates it with e.g. `arti hsc key get`. Once any client is
+    /// authorized, the service only accepts authorized clients. The list is
+    /// applied when the descriptors are published, so a running service
+    /// picks up the new key the next time it is started.
+    pub fn add_authorized_client(&mut self, pubkey: &str) -> Result<()> {
+        client_auth::parse_client_key(pubkey)?;
+        let pubkey = pubkey.trim().to_string();
+        if !self.config.authorized_clients.contains(&pubkey) {
+            self.config.authorized_clients.push(pubkey);
+        }
+        if !self.tasks.is_empty() {
+            log::warn!("Authorized client added while running; restart the service to apply it");
+        }
+        Ok(())
+    }
+    
+    /// Public keys of the clients allowed to connect; empty if anyone may
+    pub fn authorized_clients(&self) -> &[String] {
+        &self.config.authorized_clients
+    }
+    
+    /// Get the onion address of the primary identity
     pub fn onion_address(&self) -> Option<&str> {
-        self.onion_address.as_deref()
+        self.onion_addresses.first().map(String::as_str)
+    }
+    
+    /// Get every onion address the service is published at, the primary first
+    pub fn onion_addresses(&self) -> &[String] {
+        &self.onion_addresses
+    }
+    
+    /// Get the LAN address this service also listens on, if enabled
+    pub fn lan_address(&self) -> Option<SocketAddr> {
+        self.lan_address
+    }
+    
+    /// Wait until any listener of the started service stops, returning why
+    ///
+    /// Returns at once if the service isn't running.
+    pub async fn stopped(&mut self) -> String {
+        if self.tasks.is_empty() {
+            return "service is not running".to_string();
+        }
+        let (result, index, _) = futures::future::select_all(self.tasks.iter_mut()).await;
+        self.tasks.remove(index);
+        match result {
+            Ok(reason) => reason,
+            Err(e) => format!("accept task failed: {}", e),
+        }
+    }
+    
+    /// Stop all listeners and withdraw the onion service
+    ///
+    /// The service can be started again afterwards; it republishes with the
+    /// same key and therefore the same onion address.
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.onion_addresses.clear();
+        self.lan_address = None;
     }
 }
 
+/// Bind the LAN listener, refusing addresses that clash with the onion
+/// service's own loopback listener
+///
+/// A service `restricted` to authorized clients has no LAN listener at all:
+/// its connections never go through Tor, so client authorization can't
+/// keep anyone out of it.
+async fn bind_lan_listener(lan_addr: SocketAddr, onion_addr: SocketAddr, restricted: bool) -> Result<TcpListener> {
+    if restricted {
+        return Err(GitError::Config(format!(
+            "LAN bind address {} can't be combined with authorized clients; LAN connections bypass Tor's client authorization",
+            lan_addr)));
+    }
+    if lan_addr.ip().is_loopback() {
+        return Err(GitError::Config(format!(
+            "LAN bind address {} is a loopback address; loopback clients can already use {}",
+            lan_addr, onion_addr)));
+    }
+    if lan_addr.ip().is_unspecified() && lan_addr.port() == onion_addr.port() {
+        return Err(GitError::Config(format!(
+            "LAN bind address {} overlaps the onion service listener on {}; use another port",
+            lan_addr, onion_addr)));
+    }
+    TcpListener::bind(lan_addr)
+        .await
+        .map_err(|e| GitError::IO(format!("Failed to bind to {}: {}", lan_addr, e), None))
+}
+
+/// How often a publish handle is checked for failure when the descriptor
+/// status checks are turned off
+const PUBLISH_FAILURE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watch the published descriptor, logging its state changes if `log_changes`
+///
+/// The task ends once the service is broken or shut down, printing why.
+/// Under `--supervise` that triggers a fresh publish; otherwise the listeners
+/// keep running, but clients can't reach the service until it is restarted.
+/// Other state changes only go to the log.
+fn spawn_descriptor_monitor<F>(nickname: String, status: F, interval: Duration, log_changes: bool) -> JoinHandle<String>
+where
+    F: Fn() -> (DescriptorState, Option<String>) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_state = None;
+        loop {
+            let (state, problem) = status();
+            if matches!(state, DescriptorState::Broken | DescriptorState::Shutdown) {
+                let problem = problem.unwrap_or_else(|| "no details".to_string());
+                let reason = match state {
+                    DescriptorState::Broken => format!("descriptor publication of {} failed: {}", nickname, problem),
+                    _ => format!("onion service {} shut down", nickname),
+                };
+                // Printed like listener failures, as a service without a
+                // supervisor has no one else to report this
+                eprintln!("Onion service unavailable: {}", reason);
+                return reason;
+            }
+            if log_changes && last_state != Some(state) {
+                let problem = problem.unwrap_or_else(|| "no details".to_string());
+                match state {
+                    DescriptorState::Running => log::info!("Onion service {} descriptor is published", nickname),
+                    DescriptorState::Bootstrapping => log::info!("Onion service {} descriptor is being published", nickname),
+                    DescriptorState::DegradedReachable => {
+                        log::warn!("Onion service {} is reachable but degraded: {}", nickname, problem)
+                    },
+                    DescriptorState::DegradedUnreachable | DescriptorState::Recovering => {
+                        log::warn!("Onion service {} may be unreachable, republishing: {}", nickname, problem)
+                    },
+                    _ => log::info!("Onion service {} descriptor state: {:?}", nickname, state),
+                }
+                last_state = Some(state);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
+
+/// Accept connections on `listener` until it fails, handling each in its own task
+///
+/// `keep_alive` is dropped only when the loop ends.
+fn spawn_accept_loop<K>(listener: TcpListener, context: ConnectionContext, label: &'static str, keep_alive: K) -> JoinHandle<String>
+where
+    K: Send + 'static,
+{
+    tokio::spawn(async move {
+        let _keep_alive = keep_alive;
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    // Most connections to a public service are probes; only
+                    // the ones that turn out to be Git requests are reported
+                    log::debug!("New {} connection from {}", label, addr);
+                    let context = context.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_git_connection(stream, &context.repo_dir, &context.push_cert,
+                                                              &context.limits, &context.rejected,
+                                                              &context.policies).await {
+                            eprintln!("Error handling connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Error accepting {} connection: {}", label, e);
+                    return format!("{} listener failed: {}", label, e);
+                }
+            }
+        }
+    })
+}
+
+/// How long a new connection has to send its Git request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the request a client opens with, or `None` for non-Git traffic
+///
+/// Scanners probe public onion services with HTTP requests, TLS handshakes
+/// and garbage. Unless the first bytes look like a Git request and the
+/// request arrives within [`HANDSHAKE_TIMEOUT`], the connection is dropped
+/// before anything is parsed or reported.
+async fn read_git_request<S>(stream: &mut S) -> Option<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut request = vec![0u8; MAX_GIT_REQUEST_LENGTH];
+    let read = async {
+        let filled = read_at_least(stream, &mut request, 0, GIT_REQUEST_PREFIX_LENGTH).await?;
+        let length = match git_request_length(&request[..filled]) {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+        // A framed request is read up to its declared length
+        read_at_least(stream, &mut request, filled, length).await.map(Some)
+    };
+    let outcome = tokio::time::timeout(HANDSHAKE_TIMEOUT, read).await;
+    match outcome {
+        Ok(Ok(Some(filled))) => {
+            request.truncate(filled);
+            Some(request)
+        },
+        Ok(Ok(None)) => {
+            log::debug!("Dropping connection that did not open with a Git request");
+            None
+        },
+        Ok(Err(e)) => {
+            log::debug!("Dropping connection during the handshake: {}", e);
+            None
+        },
+        Err(_) => {
+            log::debug!("Dropping connection that sent no Git request within {:?}", HANDSHAKE_TIMEOUT);
+            None
+        },
+    }
+}
+
+/// Read into `buffer[filled..]` until at least `min` bytes are filled
+async fn read_at_least<S>(stream: &mut S, buffer: &mut [u8], mut filled: usize, min: usize) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    while filled < min {
+        match stream.read(&mut buffer[filled..]).await? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")),
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Check `service` on the repository `repo` resolved under `repo_dir`
+/// against the access policies
+///
+/// A policy applies when its name resolves to `repo` the same way a
+/// request does. The policy named by the exact path under `repo_dir` wins;
+/// otherwise every policy resolving to `repo` has to allow `service`.
+///
+/// Without policies everything is allowed. Otherwise repositories without a
+/// policy are reported exactly like repositories that don't exist, so
+/// clients can't probe for private ones.
+fn check_access(
+    policies: &HashMap<String, RepoPolicy>,
+    repo_dir: &Path,
+    repo: &Path,
+    requested: &str,
+    service: &str,
+) -> io::Result<()> {
+    if policies.is_empty() {
+        return Ok(());
+    }
+    let exact = repo.strip_prefix(repo_dir).ok()
+        .and_then(|relative| relative.to_str())
+        .and_then(|relative| policies.get(relative));
+    let matching: Vec<&RepoPolicy> = match exact {
+        Some(policy) => vec![policy],
+        None => policies.iter()
+            .filter(|(name, _)| utils::normalize_repo_path(name).ok()
+                .and_then(|name| resolve_repo_path(repo_dir, &name))
+                .map_or(false, |path| path == repo))
+            .map(|(_, policy)| policy)
+            .collect(),
+    };
+    if matching.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Repository not found: {}", requested)));
+    }
+    if !matching.iter().all(|policy| policy.allows(service)) {
+        let action = if service == "git-receive-pack" { "Pushing to" } else { "Fetching from" };
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} {} is not allowed", action, requested)));
+    }
+    Ok(())
+}
+
+/// Find the repository a client asked for, as `git daemon` does
+///
+/// `project` also finds a bare `project.git`, and `project.git` a
+/// repository with a working tree at `project`.
+fn resolve_repo_path(repo_dir: &Path, requested: &str) -> Option<PathBuf> {
+    let mut candidates = vec![requested.to_string(), format!("{}.git", requested)];
+    if let Some(name) = requested.strip_suffix(".git") {
+        candidates.push(name.to_string());
+    }
+    candidates.into_iter()
+        .map(|candidate| repo_dir.join(candidate))
+        .find(|path| integrity::is_repository(path))
+}
+
+/// Tell the client why its request was refused, then fail with `error`
+async fn refuse<S>(stream: &mut S, error: io::Error) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    eprintln!("{}", error);
+    if let Err(e) = write_err_packet(stream, &error.to_string()).await {
+        log::debug!("Failed to send the refusal to the client: {}", e);
+    }
+    Err(error)
+}
+
 /// Handle a Git client connection using our full Git protocol implementation
-async fn handle_git_connection<S, P>(mut stream: S, repo_dir: &P) -> io::Result<()>
+async fn handle_git_connection<S, P>(
+    mut stream: S,
+    repo_dir: &P,
+    push_cert: &PushCertPolicy,
+    limits: &PackLimits,
+    rejected: &HashMap<PathBuf, String>,
+    policies: &HashMap<String, RepoPolicy>,
+) -> io::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
     P: AsRef<Path>,
 {
+    // Turn away non-Git traffic before spending anything on it
+    let request = match read_git_request(&mut stream).await {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    
     // Parse the Git command from the client
-    let command = match parse_git_command(&mut stream).await {
+    let command = match parse_git_request(&request) {
         Ok(cmd) => {
             println!("Received Git command: {} for path: {}", 
                    cmd.service, cmd.repo_path.display());
@@ -139,15 +556,24 @@ where
         }
     };
     
-    // Determine the full repository path
-    let full_repo_path = repo_dir.as_ref().join(&command.repo_path);
+    // Decode and normalize the requested path before it touches the filesystem
+    let requested_path = match utils::normalize_repo_path(&command.repo_path.to_string_lossy()) {
+        Ok(path) => path,
+        Err(e) => {
+            let error_msg = format!("Rejected repository path {}: {}", command.repo_path.display(), e);
+            eprintln!("{}", error_msg);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, error_msg));
+        }
+    };
     
-    // Verify that the requested repository exists and is within our repos directory
-    if !full_repo_path.exists() {
-        let error_msg = format!("Repository not found: {}", command.repo_path.display());
-        eprintln!("{}", error_msg);
-        return Err(io::Error::new(io::ErrorKind::NotFound, error_msg));
-    }
+    // Determine the full repository path, bare or not
+    let full_repo_path = match resolve_repo_path(repo_dir.as_ref(), &requested_path) {
+        Some(path) => path,
+        None => {
+            let error_msg = format!("Repository not found: {}", requested_path);
+            return refuse(&mut stream, io::Error::new(io::ErrorKind::NotFound, error_msg)).await;
+        }
+    };
     
     // Ensure the repository path is within our served directory (security check)
     match utils::is_path_within(&full_repo_path, repo_dir) {
@@ -166,6 +592,21 @@ where
         }
     }
     
+    // Policies are looked up by the repository actually resolved, so no
+    // spelling of the request picks up another repository's policy.
+    // Unlisted repositories are refused exactly like missing ones
+    if let Err(e) = check_access(policies, repo_dir.as_ref(), &full_repo_path, &requested_path, &command.service) {
+        return refuse(&mut stream, e).await;
+    }
+    
+    // Repositories that failed the startup integrity check are never served
+    // The client learns that, but the details stay in the service's log
+    if let Some(problem) = rejected.get(&integrity::canonical(&full_repo_path)) {
+        log::warn!("Refusing {}, which failed its integrity check: {}", full_repo_path.display(), problem);
+        let error_msg = format!("Repository {} failed its integrity check", requested_path);
+        return refuse(&mut stream, io::Error::new(io::ErrorKind::InvalidData, error_msg)).await;
+    }
+    
     // Try to open the repository with gitoxide
     let repo = match gix::open(&full_repo_path) {
         Ok(repo) => repo,
@@ -181,6 +622,16 @@ where
         "git-upload-pack" => {
             println!("Processing git-upload-pack request (clone/fetch operation)");
             
+            // Protocol v2 clients get a capability advertisement and send commands
+            if command.version == GitProtocolVersion::V2 {
+                if let Err(e) = serve_v2(&mut stream, &repo, limits).await {
+                    eprintln!("Failed to serve protocol v2: {}", e);
+                    return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+                }
+                println!("Upload-pack v2 session completed for {}", command.repo_path.display());
+                return Ok(());
+            }
+            
             // Send capabilities and references
             if let Err(e) = send_refs_advertisement(&mut stream, &repo, "git-upload-pack", true).await {
                 eprintln!("Failed to send refs advertisement: {}", e);
@@ -188,19 +639,19 @@ where
             }
             
             // Process the client's wants and haves
-            let wanted_objects = match process_wants(&mut stream, &repo).await {
-                Ok(objects) => objects,
+            let request = match process_wants(&mut stream, &repo).await {
+                Ok(request) => request,
                 Err(e) => {
                     eprintln!("Failed to process wants: {}", e);
                     return Err(e);
                 }
             };
             
-            println!("Client wants {} objects", wanted_objects.len());
+            println!("Client wants {} objects", request.wants.len());
             
-            if !wanted_objects.is_empty() {
-                // Send the requested objects as a packfile
-                if let Err(e) = send_packfile(&mut stream, &repo, &wanted_objects).await {
+            if !request.wants.is_empty() {
+                // Send the requested objects as a packfile, within the configured limits
+                if let Err(e) = send_packfile(&mut stream, &repo, &request, limits).await {
                     eprintln!("Failed to send packfile: {}", e);
                     return Err(e);
                 }
@@ -211,14 +662,16 @@ where
         "git-receive-pack" => {
             println!("Processing git-receive-pack request (push operation)");
             
-            // Send initial reference advertisement
-            if let Err(e) = send_refs_advertisement(&mut stream, &repo, "git-receive-pack", true).await {
+            // Send initial reference advertisement, with a nonce for signed pushes
+            let mut capabilities = crate::protocol::ServerCapabilities::new();
+            capabilities.add(&format!("push-cert={}", push_cert.nonce(&requested_path)), Some("git-receive-pack"));
+            if let Err(e) = send_refs_advertisement(&mut stream, &repo, &command, &capabilities).await {
                 eprintln!("Failed to send refs advertisement: {}", e);
                 return Err(e);
             }
             
             // Receive packfile with new objects
-            if let Err(e) = receive_packfile(&mut stream, &repo).await {
+            if let Err(e) = receive_packfile(&mut stream, &repo, &requested_path, push_cert).await {
                 eprintln!("Failed to receive packfile: {}", e);
                 return Err(e);
             }
@@ -235,4 +688,136 @@ where
     
     println!("Git operation completed successfully for {}", command.repo_path.display());
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_lan_listener_refused_with_client_auth() {
+        let onion_addr = SocketAddr::from(([127, 0, 0, 1], 9418));
+        let lan_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+        assert!(matches!(bind_lan_listener(lan_addr, onion_addr, true).await, Err(GitError::Config(_))));
+        assert!(bind_lan_listener(lan_addr, onion_addr, false).await.is_ok());
+    }
+
+    fn policies() -> HashMap<String, RepoPolicy> {
+        let mut policies = HashMap::new();
+        policies.insert("public.git".to_string(), RepoPolicy::read_only());
+        policies.insert("team".to_string(), RepoPolicy::read_write());
+        policies
+    }
+
+    /// Create an empty bare repository `name` under `dir`
+    fn bare_repo(dir: &Path, name: &str) -> PathBuf {
+        let repo = dir.join(name);
+        std::fs::create_dir_all(repo.join("objects")).unwrap();
+        std::fs::write(repo.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        repo
+    }
+
+    /// Send `request` to a connection handler serving `repo_dir` under
+    /// `policies` and return the handler's result and everything it wrote back
+    async fn serve_request(
+        repo_dir: &Path,
+        policies: &HashMap<String, RepoPolicy>,
+        rejected: &HashMap<PathBuf, String>,
+        request: &str,
+    ) -> (io::Result<()>, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let packet = format!("{:04x}{}", request.len() + 4, request);
+        client.write_all(packet.as_bytes()).await.unwrap();
+
+        let push_cert = PushCertPolicy::default();
+        let limits = PackLimits::from_config(0, 0);
+        let result = handle_git_connection(server, &repo_dir, &push_cert, &limits, rejected, policies).await;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (result, response)
+    }
+
+    #[test]
+    fn test_check_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let public = bare_repo(dir.path(), "public.git");
+        let team = bare_repo(dir.path(), "team.git");
+        let secret = bare_repo(dir.path(), "secret.git");
+        assert!(check_access(&HashMap::new(), dir.path(), &secret, "secret.git", "git-receive-pack").is_ok());
+
+        let policies = policies();
+        assert!(check_access(&policies, dir.path(), &public, "public.git", "git-upload-pack").is_ok());
+        assert!(check_access(&policies, dir.path(), &team, "team.git", "git-receive-pack").is_ok());
+        assert!(check_access(&policies, dir.path(), &public, "public", "git-upload-pack").is_ok());
+        let denied = check_access(&policies, dir.path(), &public, "public.git", "git-receive-pack").unwrap_err();
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+        let unlisted = check_access(&policies, dir.path(), &secret, "secret.git", "git-upload-pack").unwrap_err();
+        assert_eq!(unlisted.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_repo_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        bare_repo(dir.path(), "secret.git");
+
+        let (result, response) = serve_request(dir.path(), &policies(), &HashMap::new(), "git-upload-pack /secret.git\0host=example.onion\0").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(response, b"0029ERR Repository not found: secret.git\n");
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_repo_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = bare_repo(dir.path(), "broken.git");
+        let mut rejected = HashMap::new();
+        rejected.insert(integrity::canonical(&repo), "pack-1234.idx is truncated".to_string());
+
+        let (result, response) = serve_request(dir.path(), &HashMap::new(), &rejected, "git-upload-pack /broken.git\0host=example.onion\0").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(&response[4..], "ERR Repository broken.git failed its integrity check\n");
+    }
+
+    #[test]
+    fn test_resolve_repo_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let bare = dir.path().join("mirror.git");
+        std::fs::create_dir_all(bare.join("objects")).unwrap();
+        std::fs::write(bare.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::create_dir_all(dir.path().join("project").join(".git")).unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+
+        assert_eq!(resolve_repo_path(dir.path(), "mirror.git"), Some(bare.clone()));
+        assert_eq!(resolve_repo_path(dir.path(), "mirror"), Some(bare));
+        assert_eq!(resolve_repo_path(dir.path(), "project.git"), Some(dir.path().join("project")));
+        assert_eq!(resolve_repo_path(dir.path(), "empty"), None);
+        assert_eq!(resolve_repo_path(dir.path(), "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_push_to_read_only_repo_is_denied() {
+        let dir = tempfile::tempdir().unwrap();
+        bare_repo(dir.path(), "public.git");
+
+        let (result, response) = serve_request(dir.path(), &policies(), &HashMap::new(), "git-receive-pack /public.git\0host=example.onion\0").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response[4..].starts_with("ERR Pushing to public.git is not allowed"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_policy_follows_the_resolved_repo() {
+        // `public` names no repository of its own, so a request for it
+        // resolves to `public.git` and gets that repository's policy
+        let dir = tempfile::tempdir().unwrap();
+        bare_repo(dir.path(), "public.git");
+        let mut policies = policies();
+        policies.insert("public".to_string(), RepoPolicy::read_write());
+
+        let (result, response) = serve_request(dir.path(), &policies, &HashMap::new(), "git-receive-pack /public\0host=example.onion\0").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response[4..].starts_with("ERR Pushing to public is not allowed"), "{}", response);
+    }
+}
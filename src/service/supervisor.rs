@@ -0,0 +1,140 @@
+//! Keep a Git onion service running
+//!
+//! Long-running hosts shouldn't go dark because a descriptor publish or an
+//! accept loop failed once. The supervisor starts the service, waits for it
+//! to stop and starts it again with exponential backoff. The onion key is
+//! persisted in the key directory, so every restart comes back on the same
+//! address.
+
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+use tor_rtcompat::Runtime;
+
+use crate::core::{GitError, Result, OnionServiceConfig};
+use super::GitOnionService;
+
+/// When and how often the supervisor restarts a failed service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Restarts in a row before giving up (0 for no limit)
+    pub max_restarts: u32,
+    /// Wait before the first restart
+    pub initial_backoff: Duration,
+    /// Longest wait between restarts
+    pub max_backoff: Duration,
+    /// A service that ran this long counts as healthy again, resetting
+    /// the restart count and backoff
+    pub stable_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self::from_config(&OnionServiceConfig::default())
+    }
+}
+
+impl RestartPolicy {
+    /// Read the restart settings of an onion service configuration
+    pub fn from_config(config: &OnionServiceConfig) -> Self {
+        let initial_backoff = Duration::from_secs(config.restart_backoff.max(1));
+        Self {
+            max_restarts: config.max_restarts,
+            initial_backoff,
+            max_backoff: Duration::from_secs(config.max_restart_backoff).max(initial_backoff),
+            stable_after: Duration::from_secs(300),
+        }
+    }
+
+    /// Wait before the given restart (1 for the first one)
+    pub fn backoff(&self, restart: u32) -> Duration {
+        let factor = 1u32.checked_shl(restart.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// Check whether another restart is allowed after `restarts` in a row
+    pub fn allows(&self, restarts: u32) -> bool {
+        self.max_restarts == 0 || restarts < self.max_restarts
+    }
+}
+
+/// Runs a [`GitOnionService`], restarting it whenever it stops
+pub struct ServiceSupervisor<R: Runtime> {
+    service: GitOnionService<R>,
+    policy: RestartPolicy,
+}
+
+impl<R: Runtime> ServiceSupervisor<R> {
+    /// Supervise `service` with the given restart policy
+    pub fn new(service: GitOnionService<R>, policy: RestartPolicy) -> Self {
+        Self { service, policy }
+    }
+
+    /// Run the service until `cancel` fires or the restart limit is reached
+    ///
+    /// Returns `Ok` after a cancellation and an error once the service has
+    /// failed more times in a row than the policy allows.
+    pub async fn run(mut self, cancel: CancellationToken) -> Result<()> {
+        let mut restarts = 0u32;
+        loop {
+            let started = Instant::now();
+            let reason = match self.service.start().await {
+                Ok(onion_addresses) => {
+                    log::info!("Onion service running at {}", onion_addresses.join(", "));
+                    let reason = tokio::select! {
+                        _ = cancel.cancelled() => {
+                            self.service.stop();
+                            return Ok(());
+                        },
+                        reason = self.service.stopped() => reason,
+                    };
+                    self.service.stop();
+                    reason
+                },
+                Err(e) => format!("failed to start: {}", e),
+            };
+
+            if started.elapsed() >= self.policy.stable_after {
+                restarts = 0;
+            }
+            if !self.policy.allows(restarts) {
+                return Err(GitError::Transport(
+                    format!("Onion service stopped ({}) after {} restarts in a row; giving up", reason, restarts),
+                    None,
+                ));
+            }
+            restarts += 1;
+
+            let backoff = self.policy.backoff(restarts);
+            log::warn!("Onion service stopped: {}; restart {} in {}s", reason, restarts, backoff.as_secs());
+            eprintln!("Onion service stopped: {}; restarting in {}s", reason, backoff.as_secs());
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(backoff) => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_limit() {
+        let mut config = OnionServiceConfig::default();
+        config.max_restarts = 3;
+        config.restart_backoff = 5;
+        config.max_restart_backoff = 30;
+        let policy = RestartPolicy::from_config(&config);
+
+        let waits: Vec<u64> = (1..=5).map(|restart| policy.backoff(restart).as_secs()).collect();
+        assert_eq!(waits, vec![5, 10, 20, 30, 30]);
+        assert_eq!(policy.backoff(100).as_secs(), 30);
+        assert!(policy.allows(2));
+        assert!(!policy.allows(3));
+
+        config.max_restarts = 0;
+        assert!(RestartPolicy::from_config(&config).allows(u32::MAX));
+    }
+}
@@ -0,0 +1,144 @@
+//! Redacted connection log for sharing diagnostics
+//!
+//! Writes one JSON object per connection-level [`TransportEvent`] to a file:
+//! timing, attempt numbers and outcomes are kept, but onion addresses (in
+//! targets and error messages alike) are replaced with salted hashes. The
+//! salt is random per log and never written out, so a log can be posted in
+//! a bug report without revealing which onion services were contacted.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+use crate::core::{Result, io_err};
+use crate::utils;
+use super::events::{TransportEvent, TransportEventHandler};
+
+/// A [`TransportEventHandler`] appending redacted events to a file
+pub struct ConnectionLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    salt: [u8; 16],
+}
+
+impl ConnectionLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| io_err(format!("Failed to open connection log: {}", e), &path))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            salt: rand::random(),
+        })
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The JSON record for an event, or `None` for per-read byte counts,
+    /// which would bloat the log without helping diagnose connections
+    fn record(&self, event: &TransportEvent) -> Option<Value> {
+        let redact = |text: &str| utils::format_repo_url_private(text, &self.salt);
+        let record = match event {
+            TransportEvent::ConnectionAttempt { target, attempt, max_failures } => json!({
+                "event": "attempt",
+                "target": redact(target),
+                "attempt": attempt,
+                "max_failures": max_failures,
+            }),
+            TransportEvent::Connected { target, attempt, elapsed, reused } => json!({
+                "event": "connected",
+                "target": redact(target),
+                "attempt": attempt,
+                "elapsed_ms": elapsed.as_millis() as u64,
+                "reused": reused,
+            }),
+            TransportEvent::ConnectionFailed { target, attempt, error } => json!({
+                "event": "failed",
+                "target": redact(target),
+                "attempt": attempt,
+                "error": redact(error),
+            }),
+            TransportEvent::Retrying { target, next_attempt, delay } => json!({
+                "event": "retrying",
+                "target": redact(target),
+                "next_attempt": next_attempt,
+                "delay_ms": delay.as_millis() as u64,
+            }),
+            TransportEvent::WaitingForOnionService { target, waited, delay } => json!({
+                "event": "waiting_for_onion_service",
+                "target": redact(target),
+                "waited_ms": waited.as_millis() as u64,
+                "delay_ms": delay.as_millis() as u64,
+            }),
+            TransportEvent::PackfileReceived { target, bytes } => json!({
+                "event": "packfile_received",
+                "target": redact(target),
+                "bytes": bytes,
+            }),
+            TransportEvent::BytesSent { .. } | TransportEvent::BytesReceived { .. } => return None,
+        };
+        Some(record)
+    }
+}
+
+impl TransportEventHandler for ConnectionLog {
+    fn on_event(&self, event: &TransportEvent) {
+        let mut record = match self.record(event) {
+            Some(record) => record,
+            None => return,
+        };
+        record["time"] = Value::String(chrono::Utc::now().to_rfc3339());
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writeln!(file, "{}", record) {
+            log::warn!("Failed to write connection log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_onion_targets_are_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connections.log");
+        let log = ConnectionLog::create(&path).unwrap();
+        let target = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion:9418";
+
+        log.on_event(&TransportEvent::ConnectionFailed {
+            target: target.to_string(),
+            attempt: 1,
+            error: format!("Failed to connect to {}: timed out", target),
+        });
+        log.on_event(&TransportEvent::BytesSent { target: target.to_string(), bytes: 10 });
+        log.on_event(&TransportEvent::Connected {
+            target: target.to_string(),
+            attempt: 2,
+            elapsed: Duration::from_millis(1500),
+            reused: false,
+        });
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("vww6ybal"));
+        let records: Vec<Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["event"], "failed");
+        assert_eq!(records[1]["elapsed_ms"], 1500);
+        // The same service keeps the same redacted name within a log
+        assert_eq!(records[0]["target"], records[1]["target"]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Structured transport events for observers such as progress UIs
+//!
+//! Transports report the same things they count in `ConnectionStats`
+//! (attempts, successes, failures, retries, transferred bytes) as
+//! [`TransportEvent`]s, so a frontend can render live progress without
+//! scraping log output.
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Something that happened on a transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A connection to `target` (`host:port`) is being attempted
+    ///
+    /// `attempt` counts every try. `max_failures` is how many failed tries
+    /// end the connection; tries that only found no onion descriptor yet
+    /// don't count towards it, as they are bounded by time instead.
+    ConnectionAttempt {
+        target: String,
+        attempt: usize,
+        max_failures: usize,
+    },
+    /// A connection to `target` was established or taken from the pool
+    Connected {
+        target: String,
+        attempt: usize,
+        elapsed: Duration,
+        reused: bool,
+    },
+    /// A single connection attempt failed
+    ConnectionFailed {
+        target: String,
+        attempt: usize,
+        error: String,
+    },
+    /// A failed attempt will be retried after `delay`
+    Retrying {
+        target: String,
+        next_attempt: usize,
+        delay: Duration,
+    },
+    /// The onion service at `target` has no published descriptor yet; the
+    /// transport waits `delay` before looking again
+    WaitingForOnionService {
+        target: String,
+        waited: Duration,
+        delay: Duration,
+    },
+    /// A request was written to `target`
+    BytesSent {
+        target: String,
+        bytes: usize,
+    },
+    /// Response data arrived from `target`; `total` counts the whole response so far
+    BytesReceived {
+        target: String,
+        bytes: usize,
+        total: usize,
+    },
+    /// A complete packfile response was received from `target`
+    PackfileReceived {
+        target: String,
+        bytes: usize,
+    },
+}
+
+/// Receives transport events
+///
+/// Handlers are called synchronously on the transport's task, so they should
+/// return quickly (e.g. forward the event over a channel).
+pub trait TransportEventHandler: Send + Sync {
+    /// Handle a single event
+    fn on_event(&self, event: &TransportEvent);
+}
+
+impl<F> TransportEventHandler for F
+where
+    F: Fn(&TransportEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &TransportEvent) {
+        self(event)
+    }
+}
+
+/// A shared list of event handlers
+///
+/// Cloning is cheap and clones share the same handlers, so a client can hand
+/// its `TransportEvents` to the transports it creates and still accept new
+/// subscribers afterwards.
+#[derive(Clone, Default)]
+pub struct TransportEvents {
+    handlers: Arc<RwLock<Vec<Arc<dyn TransportEventHandler>>>>,
+}
+
+impl TransportEvents {
+    /// Create an empty handler list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler for all future events
+    pub fn subscribe(&self, handler: impl TransportEventHandler + 'static) {
+        if let Ok(mut handlers) = self.handlers.write() {
+            handlers.push(Arc::new(handler));
+        }
+    }
+
+    /// Deliver an event to every registered handler
+    pub fn emit(&self, event: TransportEvent) {
+        if let Ok(handlers) = self.handlers.read() {
+            for handler in handlers.iter() {
+                handler.on_event(&event);
+            }
+        }
+    }
+
+    /// Check whether anyone is listening, to skip building unused events
+    pub fn has_subscribers(&self) -> bool {
+        self.handlers.read().map(|h| !h.is_empty()).unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for TransportEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.handlers.read().map(|h| h.len()).unwrap_or(0);
+        f.debug_struct("TransportEvents")
+            .field("handlers", &count)
+            .finish()
+    }
+}
@@ -1,19 +1,26 @@
 use std::io::{self, Read, Write};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
-use arti_client::{DataStream, TorClient, TorClientConfig, StreamPrefs};
+use arti_client::{DataStream, ErrorKind, HasKind, IsolationToken, TorClient, TorClientConfig, StreamPrefs};
 use bytes::Bytes;
 use futures::ready;
+use serde::{Deserialize, Serialize};
 use gix_transport::{client, Transport};
 use gix_url::Url;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tor_rtcompat::{Runtime, PreferredRuntime};
+use tor_rtcompat::{Runtime, PreferredRuntime, SleepProviderExt};
 
 use crate::core::Result as ArtiGitResult;
+use crate::crypto::Identity;
+use super::events::{TransportEvent, TransportEvents};
+use super::isolation::identity_isolation;
+use super::known_hosts::{KnownHosts, check_fingerprint};
+use super::urlmatch::{UrlConfig, UrlSettings};
 
 /// Errors specific to Tor transport
 #[derive(Error, Debug)]
@@ -241,10 +248,158 @@ impl client::Connection for TorGixConnection {
     }
 }
 
+/// Which IP version exit relays should use to reach clearnet hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitIpVersion {
+    /// Prefer IPv4, fall back to IPv6 (most exits only support IPv4)
+    Ipv4Preferred,
+    /// Prefer IPv6, fall back to IPv4
+    Ipv6Preferred,
+    /// Only connect over IPv4
+    Ipv4Only,
+    /// Only connect over IPv6
+    Ipv6Only,
+}
+
+impl Default for ExitIpVersion {
+    fn default() -> Self {
+        Self::Ipv4Preferred
+    }
+}
+
+/// Stream preferences for clearnet connections made through exit relays
+///
+/// What the linked arti-client (0.22, built without `geoip`) honors:
+///
+/// | Preference          | Honored | Notes                                         |
+/// |---------------------|---------|-----------------------------------------------|
+/// | `ip_version`        | yes     | maps to `StreamPrefs::ipv4_only()` and friends |
+/// | `optimistic`        | yes     | `StreamPrefs::optimistic()`                   |
+/// | `exit_countries`    | no      | needs arti-client's `geoip` feature           |
+/// | `exclude_countries` | no      | needs arti-client's `geoip` feature           |
+///
+/// Country lists are accepted so configs stay forward compatible, but
+/// [`validate`](Self::validate) rejects them rather than silently routing
+/// through an exit the user asked to avoid.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitPreferences {
+    /// IP version used by the exit to reach the destination
+    #[serde(default)]
+    pub ip_version: ExitIpVersion,
+    
+    /// Send data before the exit confirms the connection, saving a round trip
+    #[serde(default)]
+    pub optimistic: bool,
+    
+    /// Only use exits in these countries (ISO 3166-1 alpha-2 codes)
+    #[serde(default)]
+    pub exit_countries: Vec<String>,
+    
+    /// Never use exits in these countries (ISO 3166-1 alpha-2 codes)
+    #[serde(default)]
+    pub exclude_countries: Vec<String>,
+}
+
+impl ExitPreferences {
+    /// Check that every requested preference can be enforced by this Arti version
+    pub fn validate(&self) -> Result<(), TorTransportError> {
+        if !self.exit_countries.is_empty() || !self.exclude_countries.is_empty() {
+            return Err(TorTransportError::Connection(
+                "Exit country selection is not supported by the linked Arti version (arti-client 0.22 without `geoip`)".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    
+    /// Build the Arti stream preferences for one exit connection
+    pub fn to_stream_prefs(&self) -> StreamPrefs {
+        let mut prefs = StreamPrefs::new();
+        match self.ip_version {
+            ExitIpVersion::Ipv4Preferred => prefs.ipv4_preferred(),
+            ExitIpVersion::Ipv6Preferred => prefs.ipv6_preferred(),
+            ExitIpVersion::Ipv4Only => prefs.ipv4_only(),
+            ExitIpVersion::Ipv6Only => prefs.ipv6_only(),
+        };
+        if self.optimistic {
+            prefs.optimistic();
+        }
+        prefs
+    }
+}
+
+/// How long to keep retrying an onion service whose descriptor is missing
+///
+/// A service that has just started (or just changed introduction points) is
+/// unreachable until its descriptor reaches the hidden service directories,
+/// which can take minutes. Waits grow from `initial_delay` up to `max_delay`
+/// and stop once `max_wait` has passed in total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OnionDescriptorWait {
+    /// Total time to spend waiting for the descriptor
+    pub max_wait: Duration,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Upper bound for a single delay
+    pub max_delay: Duration,
+}
+
+impl Default for OnionDescriptorWait {
+    fn default() -> Self {
+        Self {
+            max_wait: Duration::from_secs(300),
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl OnionDescriptorWait {
+    /// Wait up to `max_wait` in total, with the default delays
+    pub fn new(max_wait: Duration) -> Self {
+        Self { max_wait, ..Self::default() }
+    }
+    
+    /// Get the delay before the next retry, given how long was already waited
+    ///
+    /// Returns `None` once the wait budget is used up.
+    pub fn next_delay(&self, waited: Duration) -> Option<Duration> {
+        let remaining = self.max_wait.checked_sub(waited).filter(|r| !r.is_zero())?;
+        // Doubling the total each round gives 5s, 5s, 10s, 20s, ... up to max_delay
+        let delay = if waited.is_zero() { self.initial_delay } else { waited.min(self.max_delay) };
+        Some(delay.min(remaining))
+    }
+}
+
+/// Check whether a connection failed because the onion service descriptor
+/// could not be found, as opposed to a generic circuit or network failure
+pub(crate) fn is_descriptor_unavailable(error: &arti_client::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::OnionServiceDescriptorFetchFailed | ErrorKind::OnionServiceNotFound
+    )
+}
+
+/// Default timeout for building a stream through an exit relay
+const DEFAULT_EXIT_CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Number of exit circuits to try before giving up on a clearnet host
+const EXIT_CONNECT_ATTEMPTS: usize = 3;
+
 /// A Tor transport for Git using gitoxide and Arti
 pub struct TorTransport {
     client: Arc<TorClient<PreferredRuntime>>,
     runtime: PreferredRuntime,
+    exit_connect_timeout: Duration,
+    exit_preferences: ExitPreferences,
+    descriptor_wait: OnionDescriptorWait,
+    url_config: UrlConfig,
+    events: TransportEvents,
+    isolation: Option<IsolationToken>,
+    known_hosts: Option<KnownHosts>,
+    accept_new_fingerprint: bool,
+    /// Set once the transport is closed; new connections are refused
+    closed: AtomicBool,
 }
 
 impl TorTransport {
@@ -268,12 +423,257 @@ impl TorTransport {
         Ok(Self {
             client,
             runtime,
+            exit_connect_timeout: DEFAULT_EXIT_CONNECT_TIMEOUT,
+            exit_preferences: ExitPreferences::default(),
+            descriptor_wait: OnionDescriptorWait::default(),
+            url_config: UrlConfig::new(),
+            events: TransportEvents::new(),
+            isolation: None,
+            known_hosts: None,
+            accept_new_fingerprint: false,
+            closed: AtomicBool::new(false),
         })
     }
+    
+    /// Set the timeout used when dialing clearnet hosts through exit relays
+    pub fn with_exit_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.exit_connect_timeout = timeout;
+        self
+    }
+    
+    /// Set the stream preferences used for clearnet connections through exits
+    pub fn with_exit_preferences(mut self, preferences: ExitPreferences) -> Result<Self, TorTransportError> {
+        preferences.validate()?;
+        self.exit_preferences = preferences;
+        Ok(self)
+    }
+    
+    /// Set how long to wait for onion services whose descriptor is not published yet
+    pub fn with_descriptor_wait(mut self, wait: OnionDescriptorWait) -> Self {
+        self.descriptor_wait = wait;
+        self
+    }
+    
+    /// Share an event handler list with this transport
+    pub fn with_events(mut self, events: TransportEvents) -> Self {
+        self.events = events;
+        self
+    }
+    
+    /// Use `tor.<url>.*` settings for the remotes they match
+    pub fn with_url_config(mut self, url_config: UrlConfig) -> Self {
+        self.url_config = url_config;
+        self
+    }
+    
+    /// Reach onion services on circuits reserved for `identity`
+    ///
+    /// Clearnet connections through exits already get a fresh circuit per
+    /// attempt and are isolated from everything.
+    pub fn with_identity(mut self, identity: &dyn Identity) -> Self {
+        self.isolation = Some(identity_isolation(identity));
+        self
+    }
+    
+    /// Trust hosts without a configured fingerprint on first use, recording
+    /// their fingerprints in `known_hosts`
+    pub fn with_known_hosts(mut self, known_hosts: KnownHosts) -> Self {
+        self.known_hosts = Some(known_hosts);
+        self
+    }
+    
+    /// Accept and record a changed fingerprint instead of refusing the host
+    pub fn with_accept_new_fingerprint(mut self, accept: bool) -> Self {
+        self.accept_new_fingerprint = accept;
+        self
+    }
+    
+    /// Get the event handlers notified by this transport
+    pub fn events(&self) -> &TransportEvents {
+        &self.events
+    }
+    
+    /// Refuse new connections from now on
+    ///
+    /// Each gitoxide connection owns its stream and closes it when dropped,
+    /// so once no operation is running nothing else is left open.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+    
+    /// Connect to an onion service, waiting for its descriptor if necessary
+    ///
+    /// Only a missing descriptor is retried here, with the longer delays of
+    /// [`OnionDescriptorWait`]; any other failure is returned immediately.
+    /// A `connect_timeout` bounds each attempt.
+    fn connect_onion(&self, addr: &str, settings: &UrlSettings) -> io::Result<DataStream> {
+        let mut prefs = StreamPrefs::default();
+        if let Some(token) = self.isolation {
+            prefs.set_isolation(token);
+        }
+        let descriptor_wait = match settings.descriptor_wait {
+            Some(max_wait) => OnionDescriptorWait { max_wait, ..self.descriptor_wait },
+            None => self.descriptor_wait,
+        };
+        let start = std::time::Instant::now();
+        let mut attempt = 0;
+        let mut waited = Duration::ZERO;
+        
+        loop {
+            attempt += 1;
+            self.events.emit(TransportEvent::ConnectionAttempt {
+                target: addr.to_string(),
+                attempt,
+                // Any failure other than a missing descriptor ends the connection
+                max_failures: 1,
+            });
+            
+            let result = match settings.connect_timeout {
+                Some(limit) => self.runtime.block_on(async {
+                    self.runtime.timeout(limit, self.client.connect_with_prefs(addr, &prefs)).await
+                }),
+                None => Ok(self.runtime.block_on(self.client.connect_with_prefs(addr, &prefs))),
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(_) => {
+                    let error = format!("timed out after {}s", settings.connect_timeout.unwrap_or_default().as_secs());
+                    self.events.emit(TransportEvent::ConnectionFailed {
+                        target: addr.to_string(),
+                        attempt,
+                        error: error.clone(),
+                    });
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Failed to connect via Tor: {}", error),
+                    ));
+                }
+            };
+            
+            let error = match result {
+                Ok(stream) => {
+                    self.events.emit(TransportEvent::Connected {
+                        target: addr.to_string(),
+                        attempt,
+                        elapsed: start.elapsed(),
+                        reused: false,
+                    });
+                    return Ok(stream);
+                },
+                Err(e) => e,
+            };
+            
+            self.events.emit(TransportEvent::ConnectionFailed {
+                target: addr.to_string(),
+                attempt,
+                error: error.to_string(),
+            });
+            
+            if !is_descriptor_unavailable(&error) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("Failed to connect via Tor: {}", error),
+                ));
+            }
+            
+            let delay = match descriptor_wait.next_delay(waited) {
+                Some(delay) => delay,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "Onion service {} did not come online within {}s: {}",
+                            addr, descriptor_wait.max_wait.as_secs(), error
+                        ),
+                    ));
+                }
+            };
+            
+            log::info!("Waiting for onion service {} to come online (retrying in {}s)", addr, delay.as_secs());
+            self.events.emit(TransportEvent::WaitingForOnionService {
+                target: addr.to_string(),
+                waited,
+                delay,
+            });
+            self.runtime.block_on(self.runtime.sleep(delay));
+            waited += delay;
+        }
+    }
+    
+    /// Connect to a clearnet host through Tor exit relays
+    ///
+    /// Each attempt uses a fresh isolation group so that a retry is built on a
+    /// new circuit, and therefore a different exit, after a refusal or timeout.
+    fn connect_via_exit(&self, host: &str, addr: &str, settings: &UrlSettings) -> io::Result<DataStream> {
+        let connect_timeout = settings.connect_timeout.unwrap_or(self.exit_connect_timeout);
+        let mut last_error = String::new();
+        
+        for attempt in 1..=EXIT_CONNECT_ATTEMPTS {
+            let mut prefs = self.exit_preferences.to_stream_prefs();
+            prefs.new_isolation_group();
+            
+            log::debug!("Connecting to {} through a Tor exit (attempt {}/{})",
+                host, attempt, EXIT_CONNECT_ATTEMPTS);
+            self.events.emit(TransportEvent::ConnectionAttempt {
+                target: addr.to_string(),
+                attempt,
+                max_failures: EXIT_CONNECT_ATTEMPTS,
+            });
+            
+            let start = std::time::Instant::now();
+            let result = self.runtime.block_on(async {
+                self.runtime
+                    .timeout(connect_timeout, self.client.connect_with_prefs(addr, &prefs))
+                    .await
+            });
+            
+            match result {
+                Ok(Ok(stream)) => {
+                    self.events.emit(TransportEvent::Connected {
+                        target: addr.to_string(),
+                        attempt,
+                        elapsed: start.elapsed(),
+                        reused: false,
+                    });
+                    return Ok(stream);
+                },
+                Ok(Err(e)) => last_error = e.to_string(),
+                Err(_) => last_error = format!("timed out after {}s", connect_timeout.as_secs()),
+            }
+            
+            log::warn!("Exit connection to {} failed: {}", host, last_error);
+            self.events.emit(TransportEvent::ConnectionFailed {
+                target: addr.to_string(),
+                attempt,
+                error: last_error.clone(),
+            });
+            if attempt < EXIT_CONNECT_ATTEMPTS {
+                self.events.emit(TransportEvent::Retrying {
+                    target: addr.to_string(),
+                    next_attempt: attempt + 1,
+                    delay: Duration::ZERO,
+                });
+            }
+        }
+        
+        // Many clearnet hosts reject or tarpit known exit relays
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!(
+                "Failed to reach {} through Tor exit relays after {} attempts ({}). \
+                 The host may be blocking Tor exits; retry later or use --no-tor",
+                host, EXIT_CONNECT_ATTEMPTS, last_error
+            ),
+        ))
+    }
 }
 
 impl Transport for TorTransport {
     fn connect(&self, url: &Url) -> std::result::Result<Box<dyn client::Connection>, gix_transport::client::Error> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Tor transport is closed").into());
+        }
+        
         // Extract host and port from the URL
         let host = url.host().ok_or_else(|| {
             gix_transport::client::Error::from(io::Error::new(
@@ -285,20 +685,28 @@ impl Transport for TorTransport {
         let port = url.port().unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
         
         // Create a Tor address string
-        let addr = format!("{}:{}", host, port);
+        let addr = crate::utils::format_host_port(host, port);
         
-        // Configure stream preferences
-        let prefs = StreamPrefs::default();
+        // The most specific `tor.<url>.*` section overrides the defaults
+        let settings = self.url_config.resolve(&url.to_bstring().to_string());
         
-        // Use runtime to perform the async connect synchronously
-        let stream = self.runtime.block_on(async {
-            self.client.connect(&addr, &prefs)
-                .await
-                .map_err(|e| io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    format!("Failed to connect via Tor: {}", e),
-                ))
-        })?;
+        let stream = if host.ends_with(".onion") {
+            self.connect_onion(&addr, &settings)?
+        } else {
+            // Clearnet hosts are reached through exit relays
+            self.connect_via_exit(host, &addr, &settings)?
+        };
+        
+        // The same check as pooled connections: `tor.<url>.fingerprint`,
+        // then the fingerprint recorded on first use
+        let presented = stream.peer_fingerprint().map(|fingerprint| hex::encode(fingerprint.as_ref()));
+        check_fingerprint(
+            host,
+            presented.as_deref(),
+            settings.fingerprint.as_deref(),
+            self.known_hosts.as_ref(),
+            self.accept_new_fingerprint,
+        ).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
         
         // Wrap the DataStream in our adapter
         let sync_stream = SyncTorStream::new(stream, self.runtime.clone());
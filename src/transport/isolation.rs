@@ -0,0 +1,80 @@
+//! Circuit isolation between signing identities
+//!
+//! Traffic of two pseudonymous identities that shares a Tor circuit can be
+//! linked by the exit or the onion service at its end. Each [`Identity`] gets
+//! an isolation token keyed by its key fingerprint: its streams may share
+//! circuits with each other, but never with another identity's streams or
+//! with traffic that runs under no identity at all.
+//!
+//! Remotes can likewise be given named tokens, so that the circuits used for
+//! one repository are never reused for another.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use arti_client::{IsolationToken, StreamPrefs};
+
+use crate::crypto::Identity;
+
+lazy_static::lazy_static! {
+    /// One token per key fingerprint, for the life of the process
+    static ref IDENTITY_TOKENS: Mutex<HashMap<String, IsolationToken>> = Mutex::new(HashMap::new());
+    /// One token per identity fingerprint and remote token name
+    static ref REMOTE_TOKENS: Mutex<HashMap<(Option<String>, String), IsolationToken>> = Mutex::new(HashMap::new());
+}
+
+/// Get the isolation token for streams opened on behalf of `identity`
+///
+/// The same key always maps to the same token, so separately created
+/// transports for one identity can still share its circuits.
+pub fn identity_isolation(identity: &dyn Identity) -> IsolationToken {
+    let mut tokens = IDENTITY_TOKENS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *tokens.entry(identity.fingerprint()).or_insert_with(IsolationToken::new)
+}
+
+/// Restrict `prefs` to circuits reserved for `identity`
+pub fn isolate_identity(prefs: &mut StreamPrefs, identity: &dyn Identity) {
+    prefs.set_isolation(identity_isolation(identity));
+}
+
+/// Get the isolation token for streams to remotes configured with `token`
+///
+/// Remotes with the same token name share circuits, others never do. The
+/// name is scoped to the identity fingerprint, if any, so a shared name
+/// doesn't link two identities either.
+pub fn remote_isolation(identity: Option<&str>, token: &str) -> IsolationToken {
+    let mut tokens = REMOTE_TOKENS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *tokens.entry((identity.map(str::to_string), token.to_string())).or_insert_with(IsolationToken::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{AnonymousIdentity, KeyPair};
+
+    #[test]
+    fn test_tokens_follow_the_key() {
+        let seed = [7u8; 32];
+        let alice = AnonymousIdentity::new(KeyPair::from_seed(&seed).unwrap(), "alice.onion");
+        let alice_elsewhere = AnonymousIdentity::new(KeyPair::from_seed(&seed).unwrap(), "mirror.onion");
+        let bob = AnonymousIdentity::generate("bob.onion");
+
+        assert_eq!(identity_isolation(&alice), identity_isolation(&alice_elsewhere));
+        assert_ne!(identity_isolation(&alice), identity_isolation(&bob));
+    }
+
+    #[test]
+    fn test_remote_tokens_do_not_share_circuits() {
+        use arti_client::isolation::Isolation;
+
+        let first = remote_isolation(None, "first");
+        let second = remote_isolation(None, "second");
+        let first_for_identity = remote_isolation(Some("abcd"), "first");
+        let first_again = remote_isolation(None, "first");
+
+        assert!(first.compatible(&first_again));
+        assert!(!first.compatible(&second));
+        assert!(!first.compatible(&first_for_identity));
+        assert!(!first.compatible(&IsolationToken::no_isolation()));
+    }
+}
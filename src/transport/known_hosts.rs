@@ -0,0 +1,215 @@
+//! Fingerprints of onion services, recorded on first use
+//!
+//! Remotes without a configured fingerprint are trusted the first time they
+//! are reached: the fingerprint they present is written to a JSON file
+//! mapping hosts to hex fingerprints, and every later connection has to
+//! present the same one. A change fails with
+//! [`GitError::FingerprintMismatch`], since it is what an attacker sitting
+//! between the client and the service would look like. A service that
+//! rotated its keys on purpose can be accepted again with
+//! `--accept-new-fingerprint`.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::core::{GitError, Result, io_err};
+
+/// Persistent host to fingerprint map, shared by clones
+#[derive(Debug, Clone)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl KnownHosts {
+    /// Load the store at `path`; a missing file is an empty store
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let hosts = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| GitError::Config(format!("Invalid known hosts file {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(io_err(format!("Failed to read known hosts: {}", e), &path)),
+        };
+        Ok(Self {
+            path,
+            hosts: Arc::new(Mutex::new(hosts)),
+        })
+    }
+
+    /// Default location, next to the configuration file
+    pub fn default_location() -> PathBuf {
+        let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("~/.config"));
+        path.push("arti-git");
+        path.push("known_hosts.json");
+        path
+    }
+
+    /// Path of the store
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The fingerprint recorded for `host`
+    pub fn get(&self, host: &str) -> Option<String> {
+        self.hosts.lock().expect("known hosts lock poisoned").get(host).cloned()
+    }
+
+    /// Check `fingerprint` against the one recorded for `host`
+    ///
+    /// An unknown host is recorded. A changed fingerprint replaces the
+    /// recorded one if `accept_new` is set, and fails otherwise.
+    pub fn verify(&self, host: &str, fingerprint: &str, accept_new: bool) -> Result<()> {
+        let mut hosts = self.hosts.lock().expect("known hosts lock poisoned");
+        match hosts.get(host) {
+            Some(recorded) if recorded == fingerprint => {
+                log::debug!("Fingerprint of {} matches the one recorded", host);
+                return Ok(());
+            },
+            Some(recorded) if !accept_new => {
+                return Err(GitError::FingerprintMismatch(host.to_string(), recorded.clone(), fingerprint.to_string()));
+            },
+            Some(recorded) => {
+                log::warn!("Fingerprint of {} changed from {} to {}; accepting the new one", host, recorded, fingerprint);
+            },
+            None => {
+                log::info!("Recording fingerprint {} for {} on first use", fingerprint, host);
+            },
+        }
+        hosts.insert(host.to_string(), fingerprint.to_string());
+        self.save(&hosts)
+    }
+
+    /// Write the store, replacing the file in one step
+    fn save(&self, hosts: &BTreeMap<String, String>) -> Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)
+            .map_err(|e| io_err(format!("Failed to create directory: {}", e), dir))?;
+        let content = serde_json::to_string_pretty(hosts)
+            .map_err(|e| GitError::Config(format!("Failed to serialize known hosts: {}", e)))?;
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .map_err(|e| io_err(format!("Failed to write known hosts: {}", e), &self.path))?;
+        file.write_all(content.as_bytes())
+            .map_err(|e| io_err(format!("Failed to write known hosts: {}", e), &self.path))?;
+        file.persist(&self.path)
+            .map_err(|e| io_err(format!("Failed to write known hosts: {}", e), &self.path))?;
+        Ok(())
+    }
+}
+
+/// Check the fingerprint `presented` by `host` before using a connection
+///
+/// `expected` is the fingerprint configured or trusted for the host. Hosts
+/// without one are checked against `known_hosts`, which records them on
+/// first use. A stream that can't report a fingerprint is refused whenever
+/// one is expected, since nothing then shows it reached the right service.
+pub fn check_fingerprint(
+    host: &str,
+    presented: Option<&str>,
+    expected: Option<&str>,
+    known_hosts: Option<&KnownHosts>,
+    accept_new: bool,
+) -> Result<()> {
+    let presented = match presented {
+        Some(presented) => presented,
+        None => {
+            let expected = expected.map(str::to_string)
+                .or_else(|| known_hosts.and_then(|known_hosts| known_hosts.get(host)));
+            return match expected {
+                Some(expected) => Err(GitError::Transport(
+                    format!("{} presented no fingerprint, but {} is expected", host, expected),
+                    Some(host.to_string()),
+                )),
+                None => Ok(()),
+            };
+        },
+    };
+
+    if let Some(expected) = expected {
+        if presented == expected {
+            log::debug!("Repository fingerprint verified for {}", host);
+            return Ok(());
+        }
+        log::warn!("Repository fingerprint verification failed for {}", host);
+        return Err(GitError::FingerprintMismatch(host.to_string(), expected.to_string(), presented.to_string()));
+    }
+
+    match known_hosts {
+        Some(known_hosts) => known_hosts.verify(host, presented, accept_new),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+
+    #[test]
+    fn test_first_use_is_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("arti-git").join("known_hosts.json");
+        let known = KnownHosts::open(&path).unwrap();
+        assert_eq!(known.get(HOST), None);
+
+        known.verify(HOST, "abcd", false).unwrap();
+        known.verify(HOST, "abcd", false).unwrap();
+
+        // Recorded on disk, not just in memory
+        let reopened = KnownHosts::open(&path).unwrap();
+        assert_eq!(reopened.get(HOST).as_deref(), Some("abcd"));
+    }
+
+    #[test]
+    fn test_changed_fingerprint_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts.json");
+        KnownHosts::open(&path).unwrap().verify(HOST, "abcd", false).unwrap();
+
+        let known = KnownHosts::open(&path).unwrap();
+        match known.verify(HOST, "ef01", false) {
+            Err(GitError::FingerprintMismatch(host, recorded, presented)) => {
+                assert_eq!(host, HOST);
+                assert_eq!(recorded, "abcd");
+                assert_eq!(presented, "ef01");
+            },
+            other => panic!("Expected a fingerprint mismatch, got {:?}", other),
+        }
+        assert_eq!(KnownHosts::open(&path).unwrap().get(HOST).as_deref(), Some("abcd"));
+
+        // Accepting the change replaces the recorded fingerprint
+        known.verify(HOST, "ef01", true).unwrap();
+        assert_eq!(KnownHosts::open(&path).unwrap().get(HOST).as_deref(), Some("ef01"));
+        known.verify(HOST, "ef01", false).unwrap();
+    }
+
+    #[test]
+    fn test_missing_fingerprint_fails_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let known = KnownHosts::open(dir.path().join("known_hosts.json")).unwrap();
+
+        // Nothing to compare with and nothing to record
+        check_fingerprint(HOST, None, None, Some(&known), false).unwrap();
+        assert_eq!(known.get(HOST), None);
+
+        assert!(matches!(check_fingerprint(HOST, None, Some("abcd"), None, false), Err(GitError::Transport(..))));
+        known.verify(HOST, "abcd", false).unwrap();
+        assert!(matches!(check_fingerprint(HOST, None, None, Some(&known), true), Err(GitError::Transport(..))));
+
+        check_fingerprint(HOST, Some("abcd"), None, Some(&known), false).unwrap();
+        assert!(matches!(check_fingerprint(HOST, Some("ef01"), Some("abcd"), None, true),
+            Err(GitError::FingerprintMismatch(..))));
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts.json");
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(KnownHosts::open(&path), Err(GitError::Config(_))));
+    }
+}
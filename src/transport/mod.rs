@@ -1,42 +1,154 @@
+mod events;
+mod connection_log;
 mod http;
 mod tor;
 mod gix_tor;
 mod registry;
+mod rewrite;
+mod urlmatch;
+mod stdio;
+mod isolation;
+mod known_hosts;
+mod resume;
 
+pub use events::{TransportEvent, TransportEventHandler, TransportEvents};
+pub use connection_log::ConnectionLog;
 pub use http::HttpConnection;
-pub use tor::{TorConnection, AsyncRemoteConnection};
-pub use gix_tor::{TorTransport, TorGixConnection, TorTransportError, create_tor_transport};
+pub use tor::{
+    TorConnection, AsyncRemoteConnection, ReadBufferSettings, ConnectionStats, PoolHostStats, extract_pack_data,
+    TorStream, TorProxySettings, TorProxyType, RetryPolicy, is_retryable_kind, FetchEstimate,
+    TorTransport as PooledTorTransport,
+};
+pub use gix_tor::{
+    TorTransport, TorGixConnection, TorTransportError, ExitPreferences, ExitIpVersion, OnionDescriptorWait,
+    create_tor_transport,
+};
 pub use registry::{ArtiGitTransportRegistry, create_transport_registry};
+pub use rewrite::UrlRewriter;
+pub use urlmatch::{UrlConfig, UrlSettings, URL_SECTION};
+pub use stdio::StdioStream;
+pub use isolation::{identity_isolation, isolate_identity};
+pub use known_hosts::{KnownHosts, check_fingerprint};
+pub use resume::{ResumeState, discard_resume_state};
 
 use crate::core::Result; // Keep Result if used elsewhere, remove ObjectId, ObjectType if not
+use crate::core::TorConfig;
 use std::sync::Arc;
+use std::time::Duration;
 use gix_transport::client::Transport as GixTransport; // Alias gitoxide's trait
 
+/// How Git URLs are routed for a single operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingMode {
+    /// Route based on the URL: `.onion` hosts and `tor+*` schemes use Tor,
+    /// everything else connects directly
+    Auto,
+    /// Always connect directly, even when Tor is enabled (`--no-tor`)
+    Direct,
+    /// Always use Tor, reaching clearnet hosts through exit relays (`--tor`)
+    Tor,
+}
+
+impl Default for RoutingMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+impl RoutingMode {
+    /// Pick a routing mode from the per-command `--tor`/`--no-tor` flags
+    pub fn from_flags(force_tor: bool, no_tor: bool) -> Self {
+        match (force_tor, no_tor) {
+            (_, true) => Self::Direct,
+            (true, false) => Self::Tor,
+            (false, false) => Self::Auto,
+        }
+    }
+    
+    /// Upgrade `Auto` to `Tor` when clearnet traffic should go through exit relays
+    ///
+    /// An explicit `--no-tor` is never overridden.
+    pub fn with_clearnet_over_tor(self, enabled: bool) -> Self {
+        match self {
+            RoutingMode::Auto if enabled => RoutingMode::Tor,
+            other => other,
+        }
+    }
+
+    /// Check whether a URL should be dialed through Tor under this mode
+    ///
+    /// file:// URLs and local paths are never dialed, so they don't go over
+    /// Tor in any mode.
+    pub fn routes_over_tor(&self, url: &gix_url::Url) -> bool {
+        if url.scheme == gix_url::Scheme::File {
+            return false;
+        }
+        match self {
+            RoutingMode::Direct => false,
+            RoutingMode::Tor => true,
+            RoutingMode::Auto => {
+                url.scheme.as_str().starts_with("tor+")
+                    || url.host().map_or(false, |host| host.ends_with(".onion"))
+            }
+        }
+    }
+}
+
 /// Registers custom transports with gitoxide.
 /// Should be called once at application startup.
-pub async fn register_transports() -> Result<()> {
+pub async fn register_transports(routing: RoutingMode, tor_config: &TorConfig) -> Result<()> {
+    if routing == RoutingMode::Direct {
+        // Nothing to override: gitoxide's built-in transports handle every URL
+        log::info!("Tor routing disabled for this operation; using direct transports.");
+        return Ok(());
+    }
+
     // Register Tor transport for .onion addresses
     // Use the existing create_tor_transport function from gix_tor.rs
-    let tor_transport = Arc::new(create_tor_transport(None).await?);
-
-    // Define the condition for using this transport
-    let tor_condition = |url: &gix_url::Url| -> bool {
-        url.host().map_or(false, |host| host.ends_with(".onion"))
-    };
+    let tor_transport = Arc::new(
+        create_tor_transport(None)
+            .await?
+            .with_exit_connect_timeout(Duration::from_secs(tor_config.exit_connect_timeout))
+            .with_descriptor_wait(OnionDescriptorWait::new(Duration::from_secs(tor_config.onion_descriptor_wait)))
+            .with_exit_preferences(tor_config.exit.clone())
+            .map_err(|e| crate::core::GitError::Config(format!("Invalid exit preferences: {}", e)))?
+            .with_url_config(UrlConfig::discover(".")),
+    );
 
-    // Register the transport with the condition
+    // Register the transport with the routing condition
     // This function overrides gitoxide's default transport resolution.
     gix_transport::client::set_required_transport_override(move |url, _remote_name| {
-        if tor_condition(url) {
+        if routing.routes_over_tor(url) {
             Some(tor_transport.clone() as Arc<dyn GixTransport + Send + Sync>)
         } else {
             None // Let gitoxide handle other protocols (like file://, http://)
         }
     });
 
-    log::info!("Registered Tor transport for .onion addresses.");
+    match routing {
+        RoutingMode::Tor => log::info!("Registered Tor transport for all remote URLs (clearnet via exit relays)."),
+        _ => log::info!("Registered Tor transport for .onion addresses."),
+    }
 
     // TODO: Register other custom transports if needed (e.g., IPFS)
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_urls_never_route_over_tor() {
+        let parse = |url: &str| gix_url::parse(url.into()).unwrap();
+        for mode in [RoutingMode::Direct, RoutingMode::Tor, RoutingMode::Auto] {
+            assert!(!mode.routes_over_tor(&parse("file:///srv/repo.git")));
+            assert!(!mode.routes_over_tor(&parse("/srv/repo.git")));
+            assert!(!mode.routes_over_tor(&parse("../repo")));
+        }
+        assert!(RoutingMode::Tor.routes_over_tor(&parse("https://example.com/repo.git")));
+        assert!(RoutingMode::Auto.routes_over_tor(&parse("git://abcdefghijklmnop.onion/repo.git")));
+        assert!(!RoutingMode::Auto.routes_over_tor(&parse("https://example.com/repo.git")));
+    }
+}
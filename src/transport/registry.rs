@@ -9,12 +9,14 @@ use gix_url::Url;
 use gix_protocol::transport;
 
 use crate::core::{GitError, Result};
-use crate::transport::TorTransport;
+use crate::transport::{TorTransport, PooledTorTransport};
 use crate::utils;
 
 /// A transport registry that handles both standard Git transports and our custom Tor transport
 pub struct ArtiGitTransportRegistry {
     tor_transport: Arc<TorTransport>,
+    /// Transport for fetches from Tor URLs, if they need the pooled one
+    upload_pack: Option<Arc<PooledTorTransport>>,
     standard_registry: client::Registry,
     custom_schemes: Arc<Mutex<HashMap<String, Arc<TorTransport>>>>,
 }
@@ -27,11 +29,21 @@ impl ArtiGitTransportRegistry {
         
         Self {
             tor_transport,
+            upload_pack: None,
             standard_registry,
             custom_schemes: Arc::new(Mutex::new(HashMap::new())),
         }
     }
     
+    /// Fetch from Tor URLs through the pooled `transport`
+    ///
+    /// Used for clones that checkpoint the pack, which only the pooled
+    /// transport can do (see [`PooledTorTransport::with_resume_target`]).
+    pub fn with_upload_pack(mut self, transport: Arc<PooledTorTransport>) -> Self {
+        self.upload_pack = Some(transport);
+        self
+    }
+    
     /// Register custom URL schemes with gitoxide
     pub fn register_schemes() -> Result<()> {
         // Register tor+http, tor+https, tor+git schemes
@@ -69,6 +81,9 @@ impl client::Transport for ArtiGitTransportRegistry {
     ) -> std::result::Result<Box<dyn client::RequestWriter>, client::Error> {
         // Check if this is a Tor URL
         if url.scheme().starts_with("tor+") || utils::is_onion_address(url.as_str()) {
+            if let (Some(upload_pack), transport::Service::UploadPack) = (&self.upload_pack, service) {
+                return upload_pack.request(url, service, args, initial_response_of_fetch);
+            }
             return self.tor_transport.request(url, service, args, initial_response_of_fetch);
         }
         
@@ -95,7 +110,10 @@ impl client::TransportFactory for ArtiGitTransportRegistry {
     fn factory(&self, url: &Url) -> std::result::Result<Box<dyn Transport>, client::Error> {
         if url.scheme().starts_with("tor+") || utils::is_onion_address(url.as_str()) {
             // For Tor URLs, use our TorTransport
-            Ok(Box::new(self.tor_transport.clone()))
+            match &self.upload_pack {
+                Some(upload_pack) => Ok(Box::new(upload_pack.clone())),
+                None => Ok(Box::new(self.tor_transport.clone())),
+            }
         } else {
             // For other URLs, use standard transports
             self.standard_registry.factory(url)
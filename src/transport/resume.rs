@@ -0,0 +1,840 @@
+//! Checkpoints for resuming interrupted pack downloads
+//!
+//! Tor circuits drop often enough that a large clone can fail near the end.
+//! While a pack arrives, its bytes are appended to a file next to the target
+//! repository, and a small JSON [`ResumeState`] records how much of it is
+//! safely on disk. A retry of the same request asks the server to continue
+//! from there (see [`PackResume`]) when it advertises `resume-pack`, over
+//! protocol v0 or v2. For servers that can't do that, the objects that
+//! arrived completely are set aside, and the commits whose history is among
+//! them are offered as haves, so the new pack leaves them out. The
+//! checkpoint lives beside the target rather than in it, because a failed
+//! clone removes the target directory.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::{Decompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::core::{GitError, Result, io_err};
+use crate::protocol::{PackBuilder, PackResume, RESUME_CAPABILITY, append_objects, apply_delta};
+
+/// Bytes received between two updates of the state file
+const CHECKPOINT_INTERVAL: u64 = 1024 * 1024;
+
+/// Progress of a pack download, persisted next to the target repository
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeState {
+    /// URL the pack is fetched from
+    pub url: String,
+    /// Hex SHA-1 of the request, so a checkpoint is only used for the same one
+    pub request: String,
+    /// Bytes of the pack kept in the checkpoint file
+    pub received: u64,
+    /// Hex IDs of set-aside commits offered as haves with the request
+    #[serde(default)]
+    pub haves: Vec<String>,
+    /// Bytes received at the last update of the state file
+    #[serde(skip)]
+    saved: u64,
+    /// Whether data of the current response has arrived yet
+    #[serde(skip)]
+    started: bool,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl ResumeState {
+    /// Directory holding the checkpoint for the repository at `target`
+    pub fn location(target: &Path) -> PathBuf {
+        let name = target.file_name().map_or_else(|| "repository".into(), |name| name.to_string_lossy());
+        target.with_file_name(format!(".{}.arti-git-resume", name))
+    }
+
+    /// Load the checkpoint of `request` to `url` for `target`, or start a new one
+    ///
+    /// A checkpoint left by another request is discarded.
+    pub fn open(target: &Path, url: &str, request: &[u8]) -> Result<Self> {
+        let dir = Self::location(target);
+        let request = hex::encode(Sha1::digest(request));
+        let state_path = dir.join("state.json");
+
+        let previous = std::fs::read_to_string(&state_path).ok()
+            .and_then(|content| serde_json::from_str::<ResumeState>(&content).ok())
+            .filter(|state| state.url == url && state.request == request);
+        let (received, haves) = match previous {
+            Some(state) => {
+                // Anything written after the last update of the state is not trusted
+                let pack = OpenOptions::new().create(true).write(true).open(dir.join("pack.partial"))
+                    .map_err(|e| io_err(format!("Failed to open checkpoint: {}", e), &dir))?;
+                let kept = pack.metadata().map(|metadata| metadata.len()).unwrap_or(0).min(state.received);
+                pack.set_len(kept)
+                    .map_err(|e| io_err(format!("Failed to truncate checkpoint: {}", e), &dir))?;
+                (kept, state.haves)
+            },
+            None => {
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir)
+                        .map_err(|e| io_err(format!("Failed to remove stale checkpoint: {}", e), &dir))?;
+                }
+                std::fs::create_dir_all(&dir)
+                    .map_err(|e| io_err(format!("Failed to create checkpoint: {}", e), &dir))?;
+                (0, Vec::new())
+            },
+        };
+        if received > 0 {
+            log::info!("Found {} bytes of an interrupted pack from {}", received, url);
+        }
+
+        let state = Self { url: url.to_string(), request, received, haves, saved: received, started: false, dir };
+        state.save()?;
+        Ok(state)
+    }
+
+    fn pack_path(&self) -> PathBuf {
+        self.dir.join("pack.partial")
+    }
+
+    fn kept_path(&self) -> PathBuf {
+        self.dir.join("kept.pack")
+    }
+
+    /// The request to send, depending on whether the server can resume
+    ///
+    /// A server that advertised `resume-pack` is asked for the rest of the
+    /// kept pack. Otherwise the complete objects of the kept pack are set
+    /// aside, and the commits whose history they hold are added as haves.
+    /// [`finish`](Self::finish) puts what the server left out back in.
+    pub fn request(&mut self, request: &[u8], can_resume: bool) -> Result<Vec<u8>> {
+        if can_resume {
+            if let Some(resume) = self.resume_request()? {
+                log::info!("Asking {} to resume the pack at byte {}", self.url, resume.offset);
+                return Ok(add_fetch_capability(&add_haves(request, &self.haves), &resume.to_capability()));
+            }
+        } else if self.received > 0 {
+            log::info!("{} can't resume packs; keeping the objects that already arrived", self.url);
+            self.set_aside()?;
+            let tips = complete_history(&self.kept_objects()?);
+            self.haves = tips.iter().map(|id| id.to_hex().to_string()).collect();
+            self.save()?;
+        }
+        Ok(add_haves(request, &self.haves))
+    }
+
+    /// Objects set aside from earlier attempts
+    fn kept_objects(&self) -> Result<Salvaged> {
+        let path = self.kept_path();
+        let mut objects = Salvaged::new();
+        match std::fs::read(&path) {
+            Ok(pack) => salvage_objects(&pack, &mut objects),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(io_err(format!("Failed to read checkpoint: {}", e), &path)),
+        }
+        Ok(objects)
+    }
+
+    /// Move the complete objects of the kept pack to the set-aside ones
+    fn set_aside(&mut self) -> Result<()> {
+        let path = self.pack_path();
+        let mut partial = Vec::with_capacity(self.received as usize);
+        File::open(&path)
+            .and_then(|file| file.take(self.received).read_to_end(&mut partial))
+            .map_err(|e| io_err(format!("Failed to read checkpoint: {}", e), &path))?;
+
+        let mut objects = self.kept_objects()?;
+        let before = objects.len();
+        salvage_objects(&partial, &mut objects);
+        log::info!("Set aside {} objects of the interrupted pack", objects.len() - before);
+
+        let mut builder = PackBuilder::new(false);
+        for (_, (kind, data)) in objects {
+            builder.add_object(data, kind);
+        }
+        let kept = builder.finish(&|_| {})?;
+        let kept_path = self.kept_path();
+        let temp_path = self.dir.join("kept.pack.tmp");
+        std::fs::write(&temp_path, &kept)
+            .and_then(|_| std::fs::rename(&temp_path, &kept_path))
+            .map_err(|e| io_err(format!("Failed to write checkpoint: {}", e), &kept_path))?;
+
+        File::create(&path)
+            .map_err(|e| io_err(format!("Failed to truncate checkpoint: {}", e), &path))?;
+        self.received = 0;
+        self.saved = 0;
+        Ok(())
+    }
+
+    /// What to ask the server for to continue, if anything was kept
+    pub fn resume_request(&self) -> Result<Option<PackResume>> {
+        if self.received == 0 {
+            return Ok(None);
+        }
+        let path = self.pack_path();
+        let mut prefix = Vec::with_capacity(self.received as usize);
+        File::open(&path)
+            .and_then(|file| file.take(self.received).read_to_end(&mut prefix))
+            .map_err(|e| io_err(format!("Failed to read checkpoint: {}", e), &path))?;
+        Ok(Some(PackResume::for_prefix(&prefix)))
+    }
+
+    /// Append pack data received from the server
+    ///
+    /// A response starting with the `PACK` signature is a whole pack, sent
+    /// by a server that couldn't resume, and replaces what was kept.
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        let path = self.pack_path();
+        let mut file = OpenOptions::new().create(true).write(true).open(&path)
+            .map_err(|e| io_err(format!("Failed to open checkpoint: {}", e), &path))?;
+        if !self.started {
+            self.started = true;
+            if self.received > 0 && data.starts_with(b"PACK") {
+                log::info!("Server sent the whole pack again; setting aside {} checkpointed bytes", self.received);
+                self.set_aside()?;
+            }
+        }
+        file.seek(SeekFrom::Start(self.received))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| io_err(format!("Failed to write checkpoint: {}", e), &path))?;
+        self.received += data.len() as u64;
+
+        if self.received - self.saved >= CHECKPOINT_INTERVAL {
+            file.sync_data()
+                .map_err(|e| io_err(format!("Failed to write checkpoint: {}", e), &path))?;
+            self.save()?;
+            self.saved = self.received;
+        }
+        Ok(())
+    }
+
+    /// Record progress, so data received so far survives a crash
+    pub fn save(&self) -> Result<()> {
+        let path = self.dir.join("state.json");
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| GitError::Config(format!("Failed to serialize resume state: {}", e)))?;
+        std::fs::write(&path, content)
+            .map_err(|e| io_err(format!("Failed to write resume state: {}", e), &path))
+    }
+
+    /// The complete pack, once the response has ended
+    ///
+    /// The pack is checked before the checkpoint is touched. A pack that
+    /// merely stops early, because the server closed the stream before the
+    /// end, is kept for the next attempt. The checkpoint is removed once
+    /// the pack is complete, and also if it is corrupt, since resuming it
+    /// could only fail again. Set-aside objects the server was told about
+    /// are appended to the complete pack.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let path = self.pack_path();
+        let pack = std::fs::read(&path)
+            .map_err(|e| io_err(format!("Failed to read checkpoint: {}", e), &path))?;
+
+        let entries_end = match scan_pack(&pack, |_| {}) {
+            Ok(entries_end) => entries_end,
+            Err(e) => {
+                self.discard()?;
+                return Err(GitError::Protocol(format!("Resumed pack is corrupt ({}); the next attempt will start over", e)));
+            },
+        };
+        match entries_end {
+            Some(end) if pack.len() == end + 20 && Sha1::digest(&pack[..end]).as_slice() == &pack[end..] => {
+                let pack = self.add_kept_history(pack)?;
+                self.discard()?;
+                Ok(pack)
+            },
+            Some(end) if pack.len() >= end + 20 => {
+                self.discard()?;
+                Err(GitError::Protocol("Resumed pack is corrupt; the next attempt will start over".to_string()))
+            },
+            _ => {
+                self.save()?;
+                Err(GitError::Transport(format!(
+                    "The pack from {} ended after {} bytes, before it was complete; the data was kept for the next attempt",
+                    self.url, pack.len()), Some(self.url.clone())))
+            },
+        }
+    }
+
+    /// Append the set-aside history of the haves, which the server left out
+    fn add_kept_history(&self, pack: Vec<u8>) -> Result<Vec<u8>> {
+        if self.haves.is_empty() {
+            return Ok(pack);
+        }
+        let mut objects = self.kept_objects()?;
+        let haves = self.haves.iter()
+            .map(|id| gix::ObjectId::from_hex(id.as_bytes()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| GitError::Protocol(format!("Invalid have in resume state: {}", e)))?;
+        let reachable = reachable_objects(&objects, &haves);
+        log::info!("Adding {} set-aside objects to the pack", reachable.len());
+        append_objects(&pack, reachable.into_iter().filter_map(|id| objects.remove(&id)))
+    }
+
+    /// Remove the checkpoint
+    pub fn discard(&self) -> Result<()> {
+        remove_checkpoint(&self.dir)
+    }
+}
+
+/// An entry of a pack whose data arrived completely
+#[derive(Debug)]
+pub(crate) struct ScannedEntry {
+    /// Offset of the entry in the pack
+    pub offset: usize,
+    /// What the data is
+    pub base: EntryBase,
+    /// Inflated data: the object, or a delta against its base
+    pub data: Vec<u8>,
+}
+
+/// What the data of a [`ScannedEntry`] stands for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EntryBase {
+    /// A whole object of this kind
+    Whole(gix::object::Kind),
+    /// A delta against the entry at this offset
+    Offset(usize),
+    /// A delta against the object with this ID
+    Id(gix::ObjectId),
+}
+
+/// Walk the entries of a pack, which may be cut short
+///
+/// `on_entry` sees every entry that arrived completely. Returns where the
+/// entries end once all of them arrived, or `None` if the pack stops
+/// before that. Data that can't be the start of a pack fails.
+pub(crate) fn scan_pack(pack: &[u8], mut on_entry: impl FnMut(ScannedEntry)) -> Result<Option<usize>> {
+    let corrupt = |what: &str| GitError::Protocol(format!("Invalid pack: {}", what));
+    if !b"PACK".starts_with(&pack[..pack.len().min(4)]) {
+        return Err(corrupt("missing PACK signature"));
+    }
+    if pack.len() < 12 {
+        return Ok(None);
+    }
+    let count = u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]);
+
+    let mut pos = 12;
+    for _ in 0..count {
+        let offset = pos;
+        let next = |pos: &mut usize| -> Option<u8> {
+            let byte = pack.get(*pos).copied();
+            *pos += 1;
+            byte
+        };
+
+        // Type and inflated size
+        let mut byte = match next(&mut pos) {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let type_code = (byte >> 4) & 7;
+        let mut size = (byte & 0x0f) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            byte = match next(&mut pos) {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            if shift > 57 {
+                return Err(corrupt("entry size too large"));
+            }
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+
+        let base = match type_code {
+            1 => EntryBase::Whole(gix::object::Kind::Commit),
+            2 => EntryBase::Whole(gix::object::Kind::Tree),
+            3 => EntryBase::Whole(gix::object::Kind::Blob),
+            4 => EntryBase::Whole(gix::object::Kind::Tag),
+            6 => {
+                let mut byte = match next(&mut pos) {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+                let mut distance = (byte & 0x7f) as usize;
+                while byte & 0x80 != 0 {
+                    byte = match next(&mut pos) {
+                        Some(byte) => byte,
+                        None => return Ok(None),
+                    };
+                    distance = distance.checked_add(1)
+                        .and_then(|distance| distance.checked_mul(128))
+                        .ok_or_else(|| corrupt("delta base offset too large"))?
+                        | (byte & 0x7f) as usize;
+                }
+                EntryBase::Offset(offset.checked_sub(distance).filter(|base| *base >= 12)
+                    .ok_or_else(|| corrupt("delta base before the start of the pack"))?)
+            },
+            7 => {
+                let id = match pack.get(pos..pos + 20) {
+                    Some(id) => id,
+                    None => return Ok(None),
+                };
+                pos += 20;
+                EntryBase::Id(gix::ObjectId::from_bytes_or_panic(id))
+            },
+            _ => return Err(corrupt("unknown entry type")),
+        };
+
+        // The compressed data ends where the zlib stream does
+        let mut inflater = Decompress::new(true);
+        let mut data = Vec::new();
+        loop {
+            let consumed = inflater.total_in() as usize;
+            let produced = data.len();
+            if data.len() == data.capacity() {
+                data.reserve((size as usize).saturating_sub(data.len()).clamp(1, 1 << 20));
+            }
+            let status = inflater.decompress_vec(&pack[pos + consumed..], &mut data, FlushDecompress::None)
+                .map_err(|e| corrupt(&format!("bad compressed data: {}", e)))?;
+            if data.len() as u64 > size {
+                return Err(corrupt("entry larger than its header says"));
+            }
+            if status == Status::StreamEnd {
+                break;
+            }
+            let stalled = inflater.total_in() as usize == consumed && data.len() == produced;
+            if pos + inflater.total_in() as usize == pack.len() {
+                return Ok(None);
+            }
+            if stalled && data.len() < data.capacity() {
+                return Err(corrupt("bad compressed data"));
+            }
+        }
+        if data.len() as u64 != size {
+            return Err(corrupt("entry smaller than its header says"));
+        }
+        pos += inflater.total_in() as usize;
+        on_entry(ScannedEntry { offset, base, data });
+    }
+    Ok(Some(pos))
+}
+
+/// Check whether an upload-pack advertisement offers `resume-pack`
+///
+/// `lines` are the packets up to the first flush: references with the
+/// capabilities after the first one for v0, capability lines for v2.
+pub fn advertises_resume(lines: &[Vec<u8>]) -> bool {
+    let has_resume = |capabilities: &[u8]| String::from_utf8_lossy(capabilities)
+        .split_whitespace()
+        .any(|capability| capability == RESUME_CAPABILITY);
+    match lines.first() {
+        Some(first) if first.starts_with(b"version 2") => lines.iter()
+            .filter_map(|line| line.strip_prefix(b"fetch="))
+            .any(|features| has_resume(features)),
+        Some(first) => first.iter().position(|&byte| byte == 0)
+            .map_or(false, |nul| has_resume(&first[nul + 1..])),
+        None => false,
+    }
+}
+
+/// Length and content of the pkt-line at the start of `data`
+fn first_packet(data: &[u8]) -> Option<(usize, &[u8])> {
+    let length = data.get(..4)
+        .and_then(|prefix| std::str::from_utf8(prefix).ok())
+        .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())?;
+    match length {
+        0..=2 => Some((4, &[])),
+        length if length > 4 && length <= data.len() => Some((length, &data[4..length])),
+        _ => None,
+    }
+}
+
+/// Whole objects recovered from packs, by ID
+type Salvaged = HashMap<gix::ObjectId, (gix::object::Kind, Vec<u8>)>;
+
+/// Add the objects of the entries of `pack` that arrived completely
+///
+/// Deltas whose base is neither in `objects` nor earlier in the pack are
+/// skipped, as is anything after data that doesn't parse.
+fn salvage_objects(pack: &[u8], objects: &mut Salvaged) {
+    let mut at_offset = HashMap::new();
+    let scanned = scan_pack(pack, |entry| {
+        let base = match entry.base {
+            EntryBase::Whole(kind) => {
+                let id = gix::objs::compute_hash(gix::hash::Kind::Sha1, kind, &entry.data);
+                at_offset.insert(entry.offset, id);
+                objects.insert(id, (kind, entry.data));
+                return;
+            },
+            EntryBase::Offset(offset) => at_offset.get(&offset).copied(),
+            EntryBase::Id(id) => Some(id),
+        };
+        let resolved = base.and_then(|base| objects.get(&base))
+            .and_then(|(kind, base)| Some((*kind, apply_delta(base, &entry.data).ok()?)));
+        if let Some((kind, data)) = resolved {
+            let id = gix::objs::compute_hash(gix::hash::Kind::Sha1, kind, &data);
+            at_offset.insert(entry.offset, id);
+            objects.insert(id, (kind, data));
+        }
+    });
+    if let Err(e) = scanned {
+        log::debug!("Stopped salvaging a pack early: {}", e);
+    }
+}
+
+/// IDs an object links to, or `None` if it doesn't parse
+fn links(kind: gix::object::Kind, data: &[u8]) -> Option<Vec<gix::ObjectId>> {
+    match kind {
+        gix::object::Kind::Commit => {
+            let commit = gix::objs::CommitRef::from_bytes(data).ok()?;
+            Some(std::iter::once(commit.tree()).chain(commit.parents()).collect())
+        },
+        gix::object::Kind::Tree => {
+            let tree = gix::objs::TreeRef::from_bytes(data).ok()?;
+            // Submodule commits live in other repositories
+            Some(tree.entries.iter().filter(|entry| !entry.mode.is_commit()).map(|entry| entry.oid.to_owned()).collect())
+        },
+        gix::object::Kind::Blob | gix::object::Kind::Tag => Some(Vec::new()),
+    }
+}
+
+/// Tips of the commits among `objects` whose whole history is there too
+///
+/// A tip is such a commit that no other one has as parent.
+fn complete_history(objects: &Salvaged) -> Vec<gix::ObjectId> {
+    let mut complete: HashMap<gix::ObjectId, bool> = HashMap::new();
+    for (&start, (kind, _)) in objects {
+        if *kind != gix::object::Kind::Commit {
+            continue;
+        }
+        // Depth first, deciding each object once its links are decided
+        let mut stack = vec![(start, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if complete.contains_key(&id) {
+                continue;
+            }
+            let object_links = objects.get(&id).and_then(|(kind, data)| links(*kind, data));
+            let object_links = match object_links {
+                Some(object_links) => object_links,
+                None => {
+                    complete.insert(id, false);
+                    continue;
+                },
+            };
+            if expanded {
+                let done = object_links.iter().all(|link| complete.get(link) == Some(&true));
+                complete.insert(id, done);
+            } else {
+                stack.push((id, true));
+                stack.extend(object_links.into_iter().filter(|link| !complete.contains_key(link)).map(|link| (link, false)));
+            }
+        }
+    }
+
+    let commits: HashSet<_> = complete.iter()
+        .filter(|(id, done)| **done && matches!(objects.get(id), Some((gix::object::Kind::Commit, _))))
+        .map(|(id, _)| *id)
+        .collect();
+    let parents: HashSet<_> = commits.iter()
+        .filter_map(|id| objects.get(id).and_then(|(kind, data)| links(*kind, data)))
+        .flat_map(|links| links.into_iter().skip(1))
+        .collect();
+    let mut tips: Vec<_> = commits.iter().filter(|id| !parents.contains(id)).copied().collect();
+    tips.sort();
+    tips
+}
+
+/// Every object among `objects` reachable from `tips`
+fn reachable_objects(objects: &Salvaged, tips: &[gix::ObjectId]) -> Vec<gix::ObjectId> {
+    let mut seen = HashSet::new();
+    let mut reachable = Vec::new();
+    let mut stack = tips.to_vec();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some((kind, data)) = objects.get(&id) {
+            reachable.push(id);
+            stack.extend(links(*kind, data).unwrap_or_default());
+        }
+    }
+    reachable
+}
+
+/// Add `have` lines before the `done` of a pkt-line encoded fetch request
+fn add_haves(request: &[u8], haves: &[String]) -> Vec<u8> {
+    let mut pos = 0;
+    while let Some((length, line)) = first_packet(&request[pos..]) {
+        if line == b"done\n" || line == b"done" {
+            let mut modified = request[..pos].to_vec();
+            for have in haves {
+                modified.extend_from_slice(format!("{:04x}have {}\n", have.len() + 10, have).as_bytes());
+            }
+            modified.extend_from_slice(&request[pos..]);
+            return modified;
+        }
+        pos += length;
+    }
+    request.to_vec()
+}
+
+/// Add `capability` to a pkt-line encoded fetch request
+///
+/// A v0 request gets it after the first want; a v2 `command=fetch` gets
+/// it as the first argument after the delimiter. Other requests are
+/// returned unchanged.
+pub fn add_fetch_capability(request: &[u8], capability: &str) -> Vec<u8> {
+    let line = match first_packet(request) {
+        Some((_, line)) => line,
+        None => return request.to_vec(),
+    };
+    if line.strip_suffix(b"\n").unwrap_or(line) == b"command=fetch" {
+        // Skip the capability lines up to the delimiter
+        let mut pos = 0;
+        while let Some((length, _)) = first_packet(&request[pos..]) {
+            let delimiter = &request[pos..pos + 4] == b"0001";
+            pos += length;
+            if delimiter {
+                let mut modified = request[..pos].to_vec();
+                modified.extend_from_slice(format!("{:04x}{}\n", capability.len() + 5, capability).as_bytes());
+                modified.extend_from_slice(&request[pos..]);
+                return modified;
+            }
+        }
+        return request.to_vec();
+    }
+    if !line.starts_with(b"want ") {
+        return request.to_vec();
+    }
+
+    let (body, newline) = match line.strip_suffix(b"\n") {
+        Some(body) => (body, true),
+        None => (line, false),
+    };
+    let mut new_line = body.to_vec();
+    new_line.push(b' ');
+    new_line.extend_from_slice(capability.as_bytes());
+    if newline {
+        new_line.push(b'\n');
+    }
+    let mut modified = format!("{:04x}", new_line.len() + 4).into_bytes();
+    modified.extend_from_slice(&new_line);
+    modified.extend_from_slice(&request[line.len() + 4..]);
+    modified
+}
+
+/// Remove any checkpoint kept for the repository at `target`
+pub fn discard_resume_state(target: &Path) -> Result<()> {
+    remove_checkpoint(&ResumeState::location(target))
+}
+
+fn remove_checkpoint(dir: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(io_err(format!("Failed to remove checkpoint: {}", e), &dir)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `size` bytes that don't compress
+    fn noise(size: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..size).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    /// A pack holding a blob of `size` bytes that don't compress
+    fn pack(size: usize) -> Vec<u8> {
+        let mut builder = PackBuilder::new(false);
+        builder.add_object(noise(size), gix::object::Kind::Blob);
+        builder.finish(&|_| {}).unwrap()
+    }
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("repo");
+        let pack = pack(3 * CHECKPOINT_INTERVAL as usize);
+        let url = "tor+git://example.onion/repo.git";
+
+        // The connection drops after two checkpoints' worth of data
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        assert_eq!(state.resume_request().unwrap(), None);
+        let cut = 2 * CHECKPOINT_INTERVAL as usize + 100;
+        state.append(&pack[..cut]).unwrap();
+        drop(state);
+
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        assert_eq!(state.received as usize, cut);
+        let resume = state.resume_request().unwrap().unwrap();
+        assert_eq!(resume.skip_in(&pack), cut);
+
+        state.append(&pack[cut..]).unwrap();
+        assert_eq!(state.finish().unwrap(), pack);
+        assert!(!ResumeState::location(&target).exists());
+    }
+
+    #[test]
+    fn test_truncated_pack_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("repo");
+        let pack = pack(1000);
+        let url = "tor+git://example.onion/repo.git";
+
+        // The server ended the response early, without an error
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        state.append(&pack[..600]).unwrap();
+        assert!(matches!(state.finish(), Err(GitError::Transport(..))));
+        let state = ResumeState::open(&target, url, b"want abc").unwrap();
+        assert_eq!(state.received, 600);
+
+        // Only the trailer missing is still a truncated pack
+        let mut state = ResumeState::open(&target, url, b"want def").unwrap();
+        state.append(&pack[..pack.len() - 5]).unwrap();
+        assert!(matches!(state.finish(), Err(GitError::Transport(..))));
+        assert!(ResumeState::location(&target).exists());
+
+        // A wrong checksum can't be fixed by resuming
+        let mut corrupt = pack.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        state.append(&corrupt).unwrap();
+        assert!(matches!(state.finish(), Err(GitError::Protocol(_))));
+        assert!(!ResumeState::location(&target).exists());
+    }
+
+    #[test]
+    fn test_scan_pack() {
+        let pack = pack(1000);
+        let mut entries = Vec::new();
+        assert_eq!(scan_pack(&pack, |entry| entries.push(entry)).unwrap(), Some(pack.len() - 20));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].base, EntryBase::Whole(gix::object::Kind::Blob));
+        assert_eq!(entries[0].data.len(), 1000);
+
+        for cut in [0, 3, 12, 13, 500, pack.len() - 21] {
+            let mut entries = 0;
+            assert_eq!(scan_pack(&pack[..cut], |_| entries += 1).unwrap(), None, "cut at {}", cut);
+            assert_eq!(entries, 0);
+        }
+        assert!(scan_pack(b"PACX", |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_kept_history_is_offered_as_haves() {
+        use gix::object::Kind;
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("repo");
+        let url = "git://example.onion/repo.git";
+        let hash = |kind, data: &[u8]| gix::objs::compute_hash(gix::hash::Kind::Sha1, kind, data);
+
+        let blob = b"hello\n".to_vec();
+        let mut tree = b"100644 hello\0".to_vec();
+        tree.extend_from_slice(hash(Kind::Blob, &blob).as_slice());
+        let commit = format!("tree {}\nauthor A <a@example.com> 0 +0000\ncommitter A <a@example.com> 0 +0000\n\nfirst\n",
+            hash(Kind::Tree, &tree)).into_bytes();
+        let commit_id = hash(Kind::Commit, &commit);
+        let large = noise(3000);
+
+        let mut builder = PackBuilder::new(false);
+        builder.add_object(commit.clone(), Kind::Commit);
+        builder.add_object(tree.clone(), Kind::Tree);
+        builder.add_object(blob.clone(), Kind::Blob);
+        builder.add_object(large.clone(), Kind::Blob);
+        let full = builder.finish(&|_| {}).unwrap();
+
+        // The first attempt stops in the middle of the large blob
+        let request = format!("0032want {}\n00000009done\n", "1".repeat(40));
+        let mut state = ResumeState::open(&target, url, request.as_bytes()).unwrap();
+        state.append(&full[..full.len() - 1000]).unwrap();
+        assert!(state.finish().is_err());
+
+        // A server without resume-pack is told about the commit that arrived
+        let mut state = ResumeState::open(&target, url, request.as_bytes()).unwrap();
+        let modified = state.request(request.as_bytes(), false).unwrap();
+        let expected = format!("0032want {}\n00000032have {}\n0009done\n", "1".repeat(40), commit_id);
+        assert_eq!(String::from_utf8(modified).unwrap(), expected);
+        assert_eq!(state.received, 0);
+
+        // It leaves out the commit's history, which is added back
+        let mut builder = PackBuilder::new(false);
+        builder.add_object(large.clone(), Kind::Blob);
+        state.append(&builder.finish(&|_| {}).unwrap()).unwrap();
+        let pack = state.finish().unwrap();
+        let mut objects = Salvaged::new();
+        salvage_objects(&pack, &mut objects);
+        assert_eq!(objects.len(), 4);
+        for (kind, data) in [(Kind::Commit, &commit), (Kind::Tree, &tree), (Kind::Blob, &blob), (Kind::Blob, &large)] {
+            assert_eq!(objects.get(&hash(kind, data)), Some(&(kind, data.clone())));
+        }
+        assert!(!ResumeState::location(&target).exists());
+
+        // A commit missing part of its tree is no have
+        objects.remove(&hash(Kind::Blob, &blob));
+        assert!(complete_history(&objects).is_empty());
+    }
+
+    #[test]
+    fn test_add_fetch_capability() {
+        let oid = "1".repeat(40);
+        let request = format!("003cwant {} ofs-delta\n0032want {}\n00000009done\n", oid, "2".repeat(40));
+        let modified = add_fetch_capability(request.as_bytes(), "resume-pack=10:ab");
+        let expected = format!("004ewant {} ofs-delta resume-pack=10:ab\n0032want {}\n00000009done\n", oid, "2".repeat(40));
+        assert_eq!(String::from_utf8(modified).unwrap(), expected);
+
+        let request = format!("0012command=fetch\n0013agent=arti-git\n00010032want {}\n0009done\n0000", oid);
+        let modified = add_fetch_capability(request.as_bytes(), "resume-pack=10:ab");
+        let expected = format!(
+            "0012command=fetch\n0013agent=arti-git\n00010016resume-pack=10:ab\n0032want {}\n0009done\n0000", oid);
+        assert_eq!(String::from_utf8(modified).unwrap(), expected);
+
+        assert_eq!(add_fetch_capability(b"0009done\n", "resume-pack=10:ab"), b"0009done\n");
+        assert_eq!(add_fetch_capability(b"00", "resume-pack=10:ab"), b"00");
+    }
+
+    #[test]
+    fn test_advertises_resume() {
+        let oid = "1".repeat(40);
+        let v0 = vec![format!("{} HEAD\0multi_ack ofs-delta resume-pack\n", oid).into_bytes()];
+        assert!(advertises_resume(&v0));
+        let v0 = vec![format!("{} HEAD\0multi_ack ofs-delta\n", oid).into_bytes()];
+        assert!(!advertises_resume(&v0));
+
+        let v2 = vec![b"version 2\n".to_vec(), b"ls-refs=unborn\n".to_vec(), b"fetch=shallow resume-pack\n".to_vec()];
+        assert!(advertises_resume(&v2));
+        let v2 = vec![b"version 2\n".to_vec(), b"fetch=shallow\n".to_vec()];
+        assert!(!advertises_resume(&v2));
+        assert!(!advertises_resume(&[]));
+    }
+
+    #[test]
+    fn test_restarted_pack_replaces_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("repo");
+        let pack = pack(100);
+        let url = "tor+git://example.onion/repo.git";
+
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        state.append(&pack[..50]).unwrap();
+        state.save().unwrap();
+
+        // Another request doesn't reuse the checkpoint
+        let other = ResumeState::open(&target, url, b"want def").unwrap();
+        assert_eq!(other.received, 0);
+        other.save().unwrap();
+
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        assert_eq!(state.received, 0);
+        state.append(&pack[..50]).unwrap();
+        state.save().unwrap();
+
+        // A server that can't resume sends everything again
+        let mut state = ResumeState::open(&target, url, b"want abc").unwrap();
+        assert_eq!(state.received, 50);
+        state.append(&pack).unwrap();
+        assert_eq!(state.finish().unwrap(), pack);
+    }
+}
@@ -0,0 +1,160 @@
+//! `url.<base>.insteadOf` and `url.<base>.pushInsteadOf` URL rewriting
+//!
+//! Lets users alias long onion URLs the same way git does:
+//!
+//! ```text
+//! [url "tor+git://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/"]
+//!     insteadOf = myhost:
+//! ```
+//!
+//! after which `arti-git clone myhost:repo` dials the full onion URL.
+
+use gix::remote::Direction;
+
+/// Rewrites URL prefixes according to `insteadOf`/`pushInsteadOf` rules
+#[derive(Debug, Clone, Default)]
+pub struct UrlRewriter {
+    /// `(prefix, base)` pairs from `url.<base>.insteadOf`
+    instead_of: Vec<(String, String)>,
+    /// `(prefix, base)` pairs from `url.<base>.pushInsteadOf`
+    push_instead_of: Vec<(String, String)>,
+}
+
+impl UrlRewriter {
+    /// Create a rewriter without any rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule replacing `prefix` with `base` for all URLs
+    pub fn with_instead_of(mut self, base: impl Into<String>, prefix: impl Into<String>) -> Self {
+        self.instead_of.push((prefix.into(), base.into()));
+        self
+    }
+
+    /// Add a rule replacing `prefix` with `base` for push URLs only
+    pub fn with_push_instead_of(mut self, base: impl Into<String>, prefix: impl Into<String>) -> Self {
+        self.push_instead_of.push((prefix.into(), base.into()));
+        self
+    }
+
+    /// Collect the rewrite rules from the `url.*` sections of a git config
+    pub fn from_config(config: &gix_config::File<'_>) -> Self {
+        let mut rewriter = Self::new();
+
+        let sections = match config.sections_by_name("url") {
+            Some(sections) => sections,
+            None => return rewriter,
+        };
+
+        for section in sections {
+            let base = match section.header().subsection_name() {
+                Some(base) => base.to_string(),
+                None => continue,
+            };
+
+            for prefix in section.values("insteadOf") {
+                rewriter = rewriter.with_instead_of(base.clone(), prefix.to_string());
+            }
+            for prefix in section.values("pushInsteadOf") {
+                rewriter = rewriter.with_push_instead_of(base.clone(), prefix.to_string());
+            }
+        }
+
+        rewriter
+    }
+
+    /// Load the rewrite rules from the user's global and system git config
+    ///
+    /// Used where no repository exists yet, such as the URL passed to clone.
+    /// A missing or unreadable config simply yields no rules.
+    pub fn from_globals() -> Self {
+        match gix_config::File::from_globals() {
+            Ok(config) => Self::from_config(&config),
+            Err(e) => {
+                log::warn!("Failed to read global git config for URL rewrites: {}", e);
+                Self::new()
+            }
+        }
+    }
+
+    /// Check whether any rules are configured
+    pub fn is_empty(&self) -> bool {
+        self.instead_of.is_empty() && self.push_instead_of.is_empty()
+    }
+
+    /// Rewrite `url` for the given direction
+    ///
+    /// The longest matching prefix wins. For pushes, `pushInsteadOf` rules
+    /// take precedence and `insteadOf` only applies when none of them match.
+    /// Returns `None` if no rule matched.
+    pub fn rewrite(&self, url: &str, direction: Direction) -> Option<String> {
+        if direction == Direction::Push {
+            if let Some(rewritten) = Self::longest_match(&self.push_instead_of, url) {
+                return Some(rewritten);
+            }
+        }
+
+        Self::longest_match(&self.instead_of, url)
+    }
+
+    /// Rewrite `url`, returning it unchanged if no rule matched
+    pub fn apply(&self, url: &str, direction: Direction) -> String {
+        match self.rewrite(url, direction) {
+            Some(rewritten) => {
+                log::debug!("Rewrote URL {} to {}", url, rewritten);
+                rewritten
+            },
+            None => url.to_string(),
+        }
+    }
+
+    /// Apply the rule with the longest prefix matching `url`
+    fn longest_match(rules: &[(String, String)], url: &str) -> Option<String> {
+        rules
+            .iter()
+            .filter(|(prefix, _)| !prefix.is_empty() && url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, base)| format!("{}{}", base, &url[prefix.len()..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION: &str = "tor+git://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/";
+
+    #[test]
+    fn test_longest_match_wins() {
+        let rewriter = UrlRewriter::new()
+            .with_instead_of("https://example.com/", "my")
+            .with_instead_of(ONION, "myhost:");
+
+        assert_eq!(rewriter.apply("myhost:repo.git", Direction::Fetch), format!("{}repo.git", ONION));
+        assert_eq!(rewriter.apply("myother/repo.git", Direction::Fetch), "https://example.com/other/repo.git");
+        assert_eq!(rewriter.rewrite("https://unrelated.org/repo.git", Direction::Fetch), None);
+    }
+
+    #[test]
+    fn test_push_instead_of() {
+        let rewriter = UrlRewriter::new()
+            .with_instead_of("https://mirror.example.com/", "myhost:")
+            .with_push_instead_of(ONION, "myhost:");
+
+        assert_eq!(rewriter.apply("myhost:repo", Direction::Fetch), "https://mirror.example.com/repo");
+        assert_eq!(rewriter.apply("myhost:repo", Direction::Push), format!("{}repo", ONION));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config: gix_config::File<'static> = format!(
+            "[url \"{}\"]\n\tinsteadOf = myhost:\n\tpushInsteadOf = push:\n", ONION
+        ).parse().unwrap();
+        let rewriter = UrlRewriter::from_config(&config);
+
+        assert_eq!(rewriter.apply("myhost:repo", Direction::Fetch), format!("{}repo", ONION));
+        assert_eq!(rewriter.apply("push:repo", Direction::Push), format!("{}repo", ONION));
+        assert_eq!(rewriter.rewrite("push:repo", Direction::Fetch), None);
+    }
+}
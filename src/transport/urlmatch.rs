@@ -0,0 +1,342 @@
+//! Per-URL transport settings from `tor.<url>.*` config sections
+//!
+//! Works like git's `http.<url>.*`: settings can be scoped to a URL
+//! pattern, and each setting is taken from the most specific section that
+//! matches the remote, falling back to the global defaults.
+//!
+//! ```text
+//! [tor "tor+git://abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion/"]
+//!     connectTimeout = 300
+//!     fingerprint = 0123abcd...
+//!     isolate = true
+//! [tor "tor+git://*.onion/mirrors/"]
+//!     descriptorWait = 600
+//!     maxHaves = 64
+//! ```
+
+use std::path::Path;
+use std::time::Duration;
+
+use url::Url;
+
+/// Config section holding URL-scoped transport settings
+pub const URL_SECTION: &str = "tor";
+
+/// Transport settings that apply to one URL
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UrlSettings {
+    /// `tor.<url>.connectTimeout`: seconds to wait for a connection
+    pub connect_timeout: Option<Duration>,
+    /// `tor.<url>.descriptorWait`: seconds to wait for an unpublished onion service
+    pub descriptor_wait: Option<Duration>,
+    /// `tor.<url>.fingerprint`: hex fingerprint the remote must present
+    pub fingerprint: Option<String>,
+    /// `tor.<url>.user`: user name for the remote
+    pub user: Option<String>,
+    /// `tor.<url>.password`: password for the remote
+    pub password: Option<String>,
+    /// `tor.<url>.maxHaves`: `have` lines to offer when fetching (0 for no limit)
+    pub max_haves: Option<usize>,
+    /// `tor.<url>.isolate`: open every stream on a circuit of its own
+    pub isolate: Option<bool>,
+}
+
+/// A parsed `tor.<url>` section name
+#[derive(Debug, Clone)]
+struct UrlPattern {
+    scheme: String,
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+/// How well a pattern matched; compared in field order, like git
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Specificity {
+    host: usize,
+    path: usize,
+    user: bool,
+}
+
+impl UrlPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        // `*` isn't valid in a URL host, so swap it for a placeholder label
+        let parsed = Url::parse(&pattern.replace("*.", "x-wildcard-x.")).ok()?;
+        let host = parsed.host_str()?.replace("x-wildcard-x.", "*.").to_ascii_lowercase();
+        Some(Self {
+            scheme: parsed.scheme().to_ascii_lowercase(),
+            user: Some(parsed.username()).filter(|user| !user.is_empty()).map(str::to_string),
+            host,
+            port: parsed.port(),
+            path: parsed.path().trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Check whether this pattern applies to `url`, and how specifically
+    fn matches(&self, url: &Url) -> Option<Specificity> {
+        if !url.scheme().eq_ignore_ascii_case(&self.scheme) {
+            return None;
+        }
+
+        let host = url.host_str()?.to_ascii_lowercase();
+        let pattern_labels: Vec<&str> = self.host.split('.').collect();
+        let host_labels: Vec<&str> = host.split('.').collect();
+        if pattern_labels.len() != host_labels.len()
+            || !pattern_labels.iter().zip(&host_labels).all(|(p, h)| *p == "*" || p == h)
+        {
+            return None;
+        }
+
+        let default_port = default_port(url.scheme());
+        if self.port.or(default_port) != url.port().or(default_port) {
+            return None;
+        }
+
+        // The pattern path must end at a path component boundary
+        let path = url.path();
+        if !self.path.is_empty() {
+            match path.strip_prefix(self.path.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {},
+                _ => return None,
+            }
+        }
+
+        let user = match &self.user {
+            Some(user) if user != url.username() => return None,
+            Some(_) => true,
+            None => false,
+        };
+
+        Some(Specificity { host: self.host.len(), path: self.path.len(), user })
+    }
+}
+
+/// Default port of a git URL scheme, with or without the `tor+` prefix
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme.trim_start_matches("tor+") {
+        "git" => Some(9418),
+        "http" => Some(80),
+        "https" => Some(443),
+        "ssh" => Some(22),
+        _ => None,
+    }
+}
+
+/// URL-scoped settings collected from config
+#[derive(Debug, Clone, Default)]
+pub struct UrlConfig {
+    /// Sections in config order, with their `(key, value)` pairs
+    sections: Vec<(UrlPattern, Vec<(String, String)>)>,
+}
+
+impl UrlConfig {
+    /// Create a config without any URL sections
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a setting for URLs matching `pattern`
+    ///
+    /// Invalid patterns are ignored with a warning, as git does.
+    pub fn with_value(mut self, pattern: &str, key: &str, value: impl Into<String>) -> Self {
+        self.push(pattern, key, value.into());
+        self
+    }
+
+    fn push(&mut self, pattern: &str, key: &str, value: String) {
+        match UrlPattern::parse(pattern) {
+            Some(parsed) => self.sections.push((parsed, vec![(key.to_ascii_lowercase(), value)])),
+            None => log::warn!("Ignoring invalid URL pattern in [{} \"{}\"]", URL_SECTION, pattern),
+        }
+    }
+
+    /// Collect the `tor.<url>.*` sections of a git config
+    pub fn from_config(config: &gix_config::File<'_>) -> Self {
+        let mut url_config = Self::new();
+
+        let sections = match config.sections_by_name(URL_SECTION) {
+            Some(sections) => sections,
+            None => return url_config,
+        };
+
+        for section in sections {
+            let pattern = match section.header().subsection_name() {
+                Some(pattern) => pattern.to_string(),
+                None => continue,
+            };
+            for key in section.value_names() {
+                let key = key.to_string();
+                if let Some(value) = section.value(&key) {
+                    url_config.push(&pattern, &key, value.to_string());
+                }
+            }
+        }
+
+        url_config
+    }
+
+    /// Load the URL sections from the user's global and system git config
+    ///
+    /// A missing or unreadable config simply yields no sections.
+    pub fn from_globals() -> Self {
+        match gix_config::File::from_globals() {
+            Ok(config) => Self::from_config(&config),
+            Err(e) => {
+                log::warn!("Failed to read global git config for URL settings: {}", e);
+                Self::new()
+            }
+        }
+    }
+
+    /// Load the URL sections seen by the repository containing `dir`
+    ///
+    /// The repository config includes the global and system files, so
+    /// repository-local sections override them. Outside a repository this
+    /// is [`UrlConfig::from_globals`].
+    pub fn discover(dir: impl AsRef<Path>) -> Self {
+        match gix::discover(dir.as_ref()) {
+            Ok(repo) => Self::from_config(repo.config_snapshot().plumbing()),
+            Err(_) => Self::from_globals(),
+        }
+    }
+
+    /// Check whether any URL sections are configured
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// Get `key` from the most specific section matching `url`
+    ///
+    /// Like `git config --get-urlmatch`: among equally specific sections
+    /// the one that comes last wins.
+    pub fn get(&self, key: &str, url: &str) -> Option<&str> {
+        let url = Url::parse(url).ok()?;
+        let key = key.to_ascii_lowercase();
+
+        let mut best: Option<(Specificity, &str)> = None;
+        for (pattern, values) in &self.sections {
+            let value = match values.iter().rev().find(|(name, _)| *name == key) {
+                Some((_, value)) => value.as_str(),
+                None => continue,
+            };
+            if let Some(specificity) = pattern.matches(&url) {
+                if best.map_or(true, |(current, _)| specificity >= current) {
+                    best = Some((specificity, value));
+                }
+            }
+        }
+        best.map(|(_, value)| value)
+    }
+
+    /// Resolve every known setting for `url`
+    pub fn resolve(&self, url: &str) -> UrlSettings {
+        if self.is_empty() {
+            return UrlSettings::default();
+        }
+
+        let seconds = |key: &str| self.get(key, url).and_then(|value| match value.trim().parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => {
+                log::warn!("Ignoring invalid {}.{} value '{}' for {}", URL_SECTION, key, value, url);
+                None
+            }
+        });
+
+        UrlSettings {
+            connect_timeout: seconds("connectTimeout"),
+            descriptor_wait: seconds("descriptorWait"),
+            fingerprint: self.get("fingerprint", url).map(|value| value.trim().to_ascii_lowercase()),
+            user: self.get("user", url).map(str::to_string),
+            password: self.get("password", url).map(str::to_string),
+            max_haves: self.get("maxHaves", url).and_then(|value| match value.trim().parse::<usize>() {
+                Ok(max) => Some(max),
+                Err(_) => {
+                    log::warn!("Ignoring invalid {}.maxHaves value '{}' for {}", URL_SECTION, value, url);
+                    None
+                }
+            }),
+            isolate: self.get("isolate", url).and_then(|value| match value.trim().to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Some(true),
+                "false" | "no" | "off" | "0" => Some(false),
+                _ => {
+                    log::warn!("Ignoring invalid {}.isolate value '{}' for {}", URL_SECTION, value, url);
+                    None
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuv.onion";
+
+    #[test]
+    fn test_most_specific_section_wins() {
+        let config = UrlConfig::new()
+            .with_value("tor+git://*.onion", "connectTimeout", "90")
+            .with_value(&format!("tor+git://{}", HOST), "connectTimeout", "300")
+            .with_value(&format!("tor+git://{}/team", HOST), "fingerprint", "ABCD")
+            .with_value(&format!("tor+git://{}:9418/team/", HOST), "connectTimeout", "600");
+
+        let repo = format!("tor+git://{}/team/project.git", HOST);
+        let settings = config.resolve(&repo);
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(settings.fingerprint.as_deref(), Some("abcd"));
+
+        let other = format!("tor+git://{}/other.git", HOST);
+        assert_eq!(config.get("connecttimeout", &other), Some("300"));
+        assert_eq!(config.get("fingerprint", &other), None);
+
+        // Path prefixes must end at a component boundary
+        assert_eq!(config.get("fingerprint", &format!("tor+git://{}/teammates.git", HOST)), None);
+        assert_eq!(config.get("connectTimeout", "tor+git://other.onion/repo.git"), Some("90"));
+        assert_eq!(config.get("connectTimeout", "tor+git://a.b.onion/repo.git"), None);
+        assert_eq!(config.get("connectTimeout", "git://other.onion/repo.git"), None);
+        assert_eq!(config.resolve("https://example.com/repo.git"), UrlSettings::default());
+    }
+
+    #[test]
+    fn test_user_and_ties() {
+        let config = UrlConfig::new()
+            .with_value("tor+http://mirror.onion/", "connectTimeout", "10")
+            .with_value("tor+http://alice@mirror.onion/", "connectTimeout", "20")
+            .with_value("tor+http://mirror.onion/", "password", "first")
+            .with_value("tor+http://mirror.onion", "password", "second");
+
+        assert_eq!(config.get("connectTimeout", "tor+http://alice@mirror.onion/r.git"), Some("20"));
+        assert_eq!(config.get("connectTimeout", "tor+http://bob@mirror.onion/r.git"), Some("10"));
+        assert_eq!(config.get("password", "tor+http://mirror.onion/r.git"), Some("second"));
+    }
+
+    #[test]
+    fn test_isolate_setting() {
+        let config = UrlConfig::new()
+            .with_value("tor+git://*.onion", "isolate", "no")
+            .with_value(&format!("tor+git://{}", HOST), "isolate", "true")
+            .with_value("tor+git://broken.onion", "isolate", "sometimes");
+
+        assert_eq!(config.resolve(&format!("tor+git://{}/r.git", HOST)).isolate, Some(true));
+        assert_eq!(config.resolve("tor+git://other.onion/r.git").isolate, Some(false));
+        assert_eq!(config.resolve("tor+git://broken.onion/r.git").isolate, None);
+    }
+
+    #[test]
+    fn test_discover_reads_repository_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git").args(args).current_dir(dir.path()).status().unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        git(&["init", "-q"]);
+        git(&["config", &format!("tor.tor+git://{}.connectTimeout", HOST), "450"]);
+
+        let nested = dir.path().join("sub");
+        std::fs::create_dir(&nested).unwrap();
+        let config = UrlConfig::discover(&nested);
+        assert_eq!(config.get("connectTimeout", &format!("tor+git://{}/r.git", HOST)), Some("450"));
+    }
+}
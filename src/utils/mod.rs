@@ -27,33 +27,120 @@ pub fn ensure_dir_exists(dir: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// Get the total size in bytes of all files below a directory
+///
+/// Symlinks are not followed. A missing directory has size zero.
+pub fn dir_size(dir: impl AsRef<Path>) -> Result<u64> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut total = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current)
+            .map_err(|e| GitError::IO(format!("Failed to read directory {}: {}", current.display(), e), Some(current.clone())))?;
+        for entry in entries.flatten() {
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                total += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+    }
+    Ok(total)
+}
+
 /// Check if a path is within a given directory
+///
+/// Both paths are normalized lexically first, so `..` components can't be
+/// used to step outside `parent`.
 pub fn is_path_within(path: impl AsRef<Path>, parent: impl AsRef<Path>) -> Result<bool> {
-    let path = absolute_path(path)?;
-    let parent = absolute_path(parent)?;
+    let path = normalize_lexically(&absolute_path(path)?);
+    let parent = normalize_lexically(&absolute_path(parent)?);
     
     Ok(path.starts_with(parent))
 }
 
+/// Resolve `.` and `..` components without touching the filesystem
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir => { result.pop(); },
+            std::path::Component::CurDir => {},
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
 /// Get a URL's host name and port
+///
+/// IPv6 literals are returned without their brackets; use
+/// [`format_host_port`] to turn the pair back into a connectable address.
+/// The port defaults from the scheme (`tor+` schemes included) and must not
+/// be zero.
 pub fn parse_host_port(url: &str) -> Result<(String, u16)> {
     let url = url::Url::parse(url)
         .map_err(|e| GitError::InvalidArgument(format!("Invalid URL: {}", e)))?;
         
-    let host = url.host_str()
-        .ok_or_else(|| GitError::InvalidArgument("Missing host in URL".to_string()))?
-        .to_string();
-        
+    let host = match url.host() {
+        Some(url::Host::Domain(domain)) if !domain.is_empty() => domain.to_string(),
+        Some(url::Host::Ipv4(addr)) => addr.to_string(),
+        Some(url::Host::Ipv6(addr)) => addr.to_string(),
+        _ => return Err(GitError::InvalidArgument("Missing host in URL".to_string())),
+    };
+    
+    check_onion_host(&host)?;
+    
+    let scheme = url.scheme().trim_start_matches("tor+");
     let port = url.port().unwrap_or_else(|| 
-        if url.scheme() == "https" { 443 }
-        else if url.scheme() == "http" { 80 }
-        else if url.scheme() == "git" { 9418 }
+        if scheme == "https" { 443 }
+        else if scheme == "http" { 80 }
+        else if scheme == "git" { 9418 }
+        else if scheme == "ssh" { 22 }
         else { 80 }
     );
     
+    if port == 0 {
+        return Err(GitError::InvalidArgument(format!("Invalid port 0 in URL: {}", url)));
+    }
+    
     Ok((host, port))
 }
 
+/// Check that an onion `host` is a v3 address label, nothing else
+///
+/// Other hosts pass.
+pub fn check_onion_host(host: &str) -> Result<()> {
+    let lower = host.to_ascii_lowercase();
+    if let Some(name) = lower.strip_suffix(".onion") {
+        let label = name.rsplit('.').next().unwrap_or_default();
+        if label.len() != 56 || !label.chars().all(|c| matches!(c, 'a'..='z' | '2'..='7')) {
+            return Err(GitError::InvalidArgument(format!("Malformed onion address: {}", host)));
+        }
+    }
+    Ok(())
+}
+
+/// Format a host and port as a connectable address, bracketing IPv6 literals
+///
+/// Hosts that are already bracketed, as `Url::host_str` returns them, are
+/// kept as they are.
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
 /// Check if a URL is an onion address
 pub fn is_onion_address(url: &str) -> bool {
     let url = match url::Url::parse(url) {
@@ -82,37 +169,273 @@ pub fn format_repo_url_safe(url: &str) -> String {
     url.to_string()
 }
 
-/// Extract the repository path from a Git URL
-pub fn get_repo_path_from_url(url: &str) -> Result<String> {
+/// Format a repository URL for logs that may be shared
+///
+/// Like [`format_repo_url_safe`], and also replaces onion hosts with a
+/// salted hash (see [`redact_onion_addresses`]).
+pub fn format_repo_url_private(url: &str, salt: &[u8]) -> String {
+    redact_onion_addresses(&format_repo_url_safe(url), salt)
+}
+
+/// Replace every onion address in `text` with `onion-<hash>.onion`
+///
+/// The hash covers the address and `salt`, so the same service maps to the
+/// same name within one log, but nobody without the salt can check whether
+/// a log mentions a service they know. Host names are case-insensitive, so
+/// `.ONION` addresses are found too and hash like their lowercase form.
+pub fn redact_onion_addresses(text: &str, salt: &[u8]) -> String {
+    use sha1::{Digest, Sha1};
+    
+    let is_base32 = |c: char| c.is_ascii_alphabetic() || ('2'..='7').contains(&c);
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    // ASCII lowercasing keeps byte offsets, so positions carry over to `rest`
+    while let Some(pos) = rest.to_ascii_lowercase().find(".onion") {
+        let label_start = rest[..pos].rfind(|c: char| !is_base32(c)).map_or(0, |i| i + 1);
+        let label = rest[label_start..pos].to_ascii_lowercase();
+        redacted.push_str(&rest[..label_start]);
+        if label.is_empty() {
+            redacted.push_str(&rest[pos..pos + ".onion".len()]);
+        } else {
+            let mut hasher = Sha1::new();
+            hasher.update(salt);
+            hasher.update(label.as_bytes());
+            redacted.push_str(&format!("onion-{}.onion", &hex::encode(hasher.finalize())[..12]));
+        }
+        rest = &rest[pos + ".onion".len()..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Longest URL accepted by the URL helpers
+///
+/// Git hosts don't produce URLs anywhere near this long; anything larger is
+/// almost certainly malformed or hostile input.
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Parse a URL, rejecting oversized input and URLs without a hierarchical path
+fn parse_git_url(url: &str) -> Result<url::Url> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err(GitError::InvalidArgument(format!(
+            "URL is too long ({} bytes, limit is {})", url.len(), MAX_URL_LENGTH
+        )));
+    }
+    
     let parsed_url = url::Url::parse(url)
         .map_err(|e| GitError::InvalidArgument(format!("Invalid URL: {}", e)))?;
     
-    // Extract the path component, removing leading and trailing slashes
-    let path = parsed_url.path().trim_start_matches('/').trim_end_matches('/');
-    
-    // Handle special case for tor+* URLs
-    if parsed_url.scheme().starts_with("tor+") {
-        // For tor+* URLs, the repository might be in a path component after the host
-        let path_segments: Vec<&str> = path.split('/').collect();
-        if path_segments.len() > 1 {
-            // Skip the first segment if it looks like a hostname
-            if path_segments[0].contains('.') || path_segments[0].ends_with(".onion") {
-                return Ok(path_segments[1..].join("/"));
-            }
+    // URLs like `mailto:` or `tor+git:host` have no path segments to work with
+    if parsed_url.cannot_be_a_base() {
+        return Err(GitError::InvalidArgument(format!("URL has no repository path: {}", url)));
+    }
+    
+    Ok(parsed_url)
+}
+
+/// Split a URL into an embedded hostname and the remaining path segments
+///
+/// `tor+*` URLs may carry the real hostname as the first path segment, as in
+/// `tor+https://proxy/example.onion/repo.git`. A lone segment is always part
+/// of the repository path, so `tor+git://host/repo.git` has no embedded host.
+fn split_url_path(parsed_url: &url::Url) -> (Option<&str>, Vec<&str>) {
+    let segments: Vec<&str> = parsed_url.path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    
+    if parsed_url.scheme().starts_with("tor+") && segments.len() > 1 {
+        let first = segments[0];
+        if first.ends_with(".onion") || (first.contains('.') && !first.ends_with(".git")) {
+            return (Some(first), segments[1..].to_vec());
         }
     }
     
+    (None, segments)
+}
+
+/// Percent-decode and normalize a slash-separated repository path
+///
+/// `.` segments and empty segments are dropped and `..` removes the previous
+/// segment. A `..` that would climb above the root is rejected, as are decoded
+/// segments containing separators or NUL bytes (`%2F`, `%5C`, `%00`).
+pub fn normalize_repo_path(path: &str) -> Result<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    
+    for segment in path.split('/') {
+        let decoded = percent_encoding::percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|e| GitError::InvalidArgument(format!("Invalid UTF-8 in repository path: {}", e)))?;
+        
+        if decoded.contains(|c| c == '/' || c == '\\' || c == '\0') {
+            return Err(GitError::InvalidArgument(format!("Invalid character in repository path: {}", path)));
+        }
+        
+        match decoded.as_ref() {
+            "" | "." => {},
+            ".." => {
+                if normalized.pop().is_none() {
+                    return Err(GitError::InvalidArgument(format!("Repository path escapes the root: {}", path)));
+                }
+            },
+            _ => normalized.push(decoded.into_owned()),
+        }
+    }
+    
+    Ok(normalized.join("/"))
+}
+
+/// Get the host to connect to for a URL, honouring hosts embedded in `tor+*` paths
+pub fn get_host_from_url(url: &str) -> Result<String> {
+    let parsed_url = parse_git_url(url)?;
+    
+    if let (Some(embedded), _) = split_url_path(&parsed_url) {
+        return Ok(embedded.to_string());
+    }
+    
+    parsed_url.host_str()
+        .filter(|host| !host.is_empty())
+        .map(|host| host.to_string())
+        .ok_or_else(|| GitError::InvalidArgument(format!("Missing host in URL: {}", url)))
+}
+
+/// Extract the repository path from a Git URL
+pub fn get_repo_path_from_url(url: &str) -> Result<String> {
+    let parsed_url = parse_git_url(url)?;
+    let (_, segments) = split_url_path(&parsed_url);
+    let path = normalize_repo_path(&segments.join("/"))?;
+    
     // Handle empty path (root repository)
     if path.is_empty() {
         return Ok(String::from("."));
     }
     
     // Remove .git extension if present
-    let repo_path = if path.ends_with(".git") {
-        &path[0..path.len() - 4]
-    } else {
-        path
-    };
+    let repo_path = path.strip_suffix(".git").unwrap_or(&path);
+    if repo_path.is_empty() {
+        return Err(GitError::InvalidArgument(format!("Invalid repository path in URL: {}", url)));
+    }
     
     Ok(repo_path.to_string())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_repo_path_plain_urls() {
+        assert_eq!(get_repo_path_from_url("https://example.com/user/repo.git").unwrap(), "user/repo");
+        assert_eq!(get_repo_path_from_url("git://example.com/repo").unwrap(), "repo");
+        assert_eq!(get_repo_path_from_url("https://example.com/user/repo.git/").unwrap(), "user/repo");
+        assert_eq!(get_repo_path_from_url("https://example.com//user//repo.git").unwrap(), "user/repo");
+    }
+    
+    #[test]
+    fn test_repo_path_empty() {
+        assert_eq!(get_repo_path_from_url("https://example.com").unwrap(), ".");
+        assert_eq!(get_repo_path_from_url("https://example.com/").unwrap(), ".");
+        assert_eq!(get_repo_path_from_url("tor+git://example.onion").unwrap(), ".");
+        assert!(get_repo_path_from_url("https://example.com/.git").is_err());
+    }
+    
+    #[test]
+    fn test_repo_path_tor_urls() {
+        assert_eq!(get_repo_path_from_url("tor+git://proxy/example.onion/repo.git").unwrap(), "repo");
+        assert_eq!(get_repo_path_from_url("tor+git://example.onion/repo.git").unwrap(), "repo");
+        assert_eq!(get_repo_path_from_url("tor+https://example.onion/group/repo.git").unwrap(), "group/repo");
+    }
+    
+    #[test]
+    fn test_repo_path_percent_encoded() {
+        assert_eq!(get_repo_path_from_url("https://example.com/my%20repo.git").unwrap(), "my repo");
+        assert_eq!(get_repo_path_from_url("https://example.com/caf%C3%A9/repo").unwrap(), "caf\u{e9}/repo");
+    }
+    
+    #[test]
+    fn test_normalize_repo_path() {
+        assert_eq!(normalize_repo_path("a/./b/../c").unwrap(), "a/c");
+        assert_eq!(normalize_repo_path("/a//b/").unwrap(), "a/b");
+        assert_eq!(normalize_repo_path("a/%2e%2e/b").unwrap(), "b");
+        assert!(normalize_repo_path("../etc/passwd").is_err());
+        assert!(normalize_repo_path("a/%2e%2e/%2e%2e/etc").is_err());
+        assert!(normalize_repo_path("a%2F..%2F..%2Fetc").is_err());
+        assert!(normalize_repo_path("a%00b").is_err());
+        assert!(normalize_repo_path("%FF").is_err());
+    }
+    
+    #[test]
+    fn test_malformed_urls() {
+        assert!(matches!(get_repo_path_from_url("not a url"), Err(GitError::InvalidArgument(_))));
+        assert!(matches!(get_repo_path_from_url("tor+git:example.onion"), Err(GitError::InvalidArgument(_))));
+        assert!(matches!(get_repo_path_from_url("mailto:someone@example.com"), Err(GitError::InvalidArgument(_))));
+        
+        let long_url = format!("https://example.com/{}", "a".repeat(MAX_URL_LENGTH));
+        assert!(matches!(get_repo_path_from_url(&long_url), Err(GitError::InvalidArgument(_))));
+    }
+    
+    const ONION_HOST: &str = "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion";
+    
+    #[test]
+    fn test_parse_host_port_defaults() {
+        assert_eq!(parse_host_port("https://example.com/repo.git").unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(parse_host_port("http://example.com/repo.git").unwrap(), ("example.com".to_string(), 80));
+        assert_eq!(parse_host_port("git://example.com/repo.git").unwrap(), ("example.com".to_string(), 9418));
+        assert_eq!(parse_host_port("ssh://example.com/repo.git").unwrap(), ("example.com".to_string(), 22));
+        assert_eq!(parse_host_port("tor+git://example.com/repo.git").unwrap(), ("example.com".to_string(), 9418));
+        assert_eq!(parse_host_port("tor+https://example.com:8443/repo.git").unwrap(), ("example.com".to_string(), 8443));
+    }
+    
+    #[test]
+    fn test_parse_host_port_onion() {
+        let url = format!("tor+git://{}/repo.git", ONION_HOST);
+        assert_eq!(parse_host_port(&url).unwrap(), (ONION_HOST.to_string(), 9418));
+        assert!(parse_host_port("git://tooshort.onion/repo.git").is_err());
+        assert!(parse_host_port(&format!("git://{}.onion/repo.git", "1".repeat(56))).is_err());
+    }
+    
+    #[test]
+    fn test_parse_host_port_ipv6() {
+        assert_eq!(parse_host_port("https://[2001:db8::1]/repo.git").unwrap(), ("2001:db8::1".to_string(), 443));
+        assert_eq!(parse_host_port("git://[::1]:9419/repo.git").unwrap(), ("::1".to_string(), 9419));
+        assert_eq!(format_host_port("2001:db8::1", 443), "[2001:db8::1]:443");
+        assert_eq!(format_host_port("[2001:db8::1]", 443), "[2001:db8::1]:443");
+        assert_eq!(format_host_port("example.com", 443), "example.com:443");
+    }
+    
+    #[test]
+    fn test_parse_host_port_invalid() {
+        assert!(matches!(parse_host_port("https://example.com:0/repo.git"), Err(GitError::InvalidArgument(_))));
+        assert!(matches!(parse_host_port("file:///tmp/repo.git"), Err(GitError::InvalidArgument(_))));
+        assert!(matches!(parse_host_port("not a url"), Err(GitError::InvalidArgument(_))));
+    }
+    
+    #[test]
+    fn test_host_from_url() {
+        assert_eq!(get_host_from_url("https://example.com/repo.git").unwrap(), "example.com");
+        assert_eq!(get_host_from_url("tor+git://proxy/example.onion/repo.git").unwrap(), "example.onion");
+        assert_eq!(get_host_from_url("tor+git://example.onion/repo.git").unwrap(), "example.onion");
+        assert!(get_host_from_url("file:///tmp/repo.git").is_err());
+    }
+    
+    #[test]
+    fn test_redact_onion_addresses() {
+        let onion = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+        let url = format!("tor+git://user:secret@{}/repo.git", onion);
+        let redacted = format_repo_url_private(&url, b"salt");
+        assert!(!redacted.contains("vww6ybal"));
+        assert!(!redacted.contains("secret"));
+        assert!(redacted.starts_with("tor+git://onion-"));
+        assert!(redacted.ends_with(".onion/repo.git"));
+        
+        // Stable within one salt, unlinkable across salts
+        let target = format!("{}:9418 refused", onion);
+        assert_eq!(redact_onion_addresses(&target, b"a"), redact_onion_addresses(&target, b"a"));
+        assert_ne!(redact_onion_addresses(&target, b"a"), redact_onion_addresses(&target, b"b"));
+        assert_eq!(redact_onion_addresses("example.com:443", b"a"), "example.com:443");
+        
+        // Host names are case-insensitive
+        let upper = onion.to_ascii_uppercase();
+        assert_eq!(redact_onion_addresses(&upper, b"a"), redact_onion_addresses(onion, b"a"));
+        assert!(!format_repo_url_private(&format!("git://{}/repo", upper), b"a").contains("VWW6YBAL"));
+    }
+}